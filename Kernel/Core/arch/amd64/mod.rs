@@ -85,6 +85,35 @@ pub mod time {
 	}
 }
 
+pub mod entropy {
+	/// RDRAND, if supported (CPUID.01H:ECX bit 30)
+	pub fn hw_random() -> Option<u64>
+	{
+		// SAFE: CPUID leaf 1 is always available on x86_64
+		if unsafe { ::core::arch::x86_64::__cpuid(1).ecx } & (1 << 30) == 0 {
+			return None;
+		}
+		// Intel recommends retrying up to 10 times on underflow
+		for _ in 0 .. 10
+		{
+			let v: u64;
+			let ok: u8;
+			// SAFE: RDRAND has no side-effects, and is supported (checked above)
+			unsafe { ::core::arch::asm!("rdrand {}; setc {}", out(reg) v, out(reg_byte) ok, options(nomem, nostack)); }
+			if ok != 0 {
+				return Some(v);
+			}
+		}
+		None
+	}
+
+	pub fn cycle_counter() -> u64
+	{
+		// SAFE: RDTSC has no side-effects
+		unsafe { ::core::arch::x86_64::_rdtsc() }
+	}
+}
+
 /// Print a backtrace, starting at the current location.
 pub fn print_backtrace()
 {
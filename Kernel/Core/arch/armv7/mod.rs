@@ -118,6 +118,17 @@ pub mod time {
 	}
 }
 
+pub mod entropy {
+	/// No architectural RNG on ARMv7
+	pub fn hw_random() -> Option<u64> {
+		None
+	}
+	pub fn cycle_counter() -> u64 {
+		// TODO: Use the PMU cycle counter (PMCCNTR) once it's enabled
+		super::time::cur_timestamp()
+	}
+}
+
 pub fn cpu_num() -> u32 {
 	0
 }
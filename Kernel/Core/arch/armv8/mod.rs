@@ -81,6 +81,37 @@ pub mod time {
 	}
 }
 
+pub mod entropy {
+	/// RNDR, if implemented (ID_AA64ISAR0_EL1.RNDR)
+	pub fn hw_random() -> Option<u64> {
+		let isar0: u64;
+		// SAFE: Reading an ID register with no side-effects
+		unsafe { ::core::arch::asm!("mrs {}, ID_AA64ISAR0_EL1", out(reg) isar0, options(nomem, nostack)); }
+		if (isar0 >> 60) & 0xF == 0 {
+			return None;
+		}
+		for _ in 0 .. 10
+		{
+			let v: u64;
+			let nzcv: u64;
+			// SAFE: RNDR has no side-effects, and is supported (checked above)
+			// - `s3_3_c2_c4_0` is RNDR, spelt out for assemblers without FEAT_RNG
+			unsafe { ::core::arch::asm!("mrs {}, s3_3_c2_c4_0; mrs {}, NZCV", out(reg) v, out(reg) nzcv, options(nomem, nostack)); }
+			// Z is set on failure
+			if nzcv & (1 << 30) == 0 {
+				return Some(v);
+			}
+		}
+		None
+	}
+	pub fn cycle_counter() -> u64 {
+		let v: u64;
+		// SAFE: Reading the virtual counter with no side-effects
+		unsafe { ::core::arch::asm!("mrs {}, CNTVCT_EL0", out(reg) v, options(nomem, nostack)); }
+		v
+	}
+}
+
 pub unsafe fn drop_to_user(entry: usize, stack: usize, args_len: usize) -> ! {
 	extern "C" {
 		fn drop_to_user(entry: usize, stack: usize, args_len: usize) -> !;
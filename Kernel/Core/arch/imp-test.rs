@@ -614,6 +614,19 @@ pub mod time {
 		(std::time::Instant::now() - ts0).as_millis() as u64
 	}
 }
+pub mod entropy {
+	pub fn hw_random() -> Option<u64> {
+		use ::std::hash::{BuildHasher,Hasher};
+		// `RandomState` is seeded from the OS on creation
+		Some( ::std::collections::hash_map::RandomState::new().build_hasher().finish() )
+	}
+	pub fn cycle_counter() -> u64 {
+		lazy_static::lazy_static! {
+			static ref TS_ZERO: std::time::Instant = std::time::Instant::now();
+		}
+		(std::time::Instant::now() - *TS_ZERO).as_nanos() as u64
+	}
+}
 pub mod x86_io {
 	pub unsafe fn inb(_p: u16) -> u8 { 0 }
 	pub unsafe fn inw(_p: u16) -> u16 { 0 }
@@ -457,6 +457,23 @@ pub mod time {
 	}
 }
 
+/// Hardware sources of entropy
+pub mod entropy {
+	use crate::arch::imp::entropy as imp;
+
+	/// Obtain a random value from a hardware RNG (if the CPU has one)
+	#[inline]
+	pub fn hw_random() -> Option<u64> {
+		imp::hw_random()
+	}
+
+	/// Read a fast-running cycle counter (used for timing jitter)
+	#[inline]
+	pub fn cycle_counter() -> u64 {
+		imp::cycle_counter()
+	}
+}
+
 #[inline]
 pub fn puts(s: &str) {
 	imp::puts(s)
@@ -314,6 +314,19 @@ pub mod time {
 	}
 }
 
+pub mod entropy {
+	/// TODO: Zkr `seed` CSR (needs the SBI to grant supervisor access)
+	pub fn hw_random() -> Option<u64> {
+		None
+	}
+	pub fn cycle_counter() -> u64 {
+		let v: u64;
+		// SAFE: Reading a CSR with no side-effects
+		unsafe { ::core::arch::asm!("rdtime {}", lateout(reg) v); }
+		v
+	}
+}
+
 pub fn drop_to_user(entry: usize, stack: usize, args_len: usize) -> ! {
 	// Create an exception frame
 	// SAFE: Validated 
@@ -158,6 +158,7 @@ impl IRQBinding
 	{
 		// The CPU owns the lock, so we don't care about ordering
 		self.has_fired.store(true, ::core::sync::atomic::Ordering::Relaxed);
+		crate::rand::add_interrupt_timing();
 		
 		// TODO: Can this force a wakeup/switch-to the IRQ worker?
 		S_IRQ_WORKER_SIGNAL.signal();
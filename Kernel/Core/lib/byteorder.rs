@@ -313,6 +313,24 @@ impl<T: EncodedLE, const N: usize> EncodedLE for [T; N] {
 	}
 }
 
+impl<T: EncodedBE, const N: usize> EncodedBE for [T; N] {
+	fn encode(&self, buf: &mut &mut [u8]) -> Result<()> {
+		for v in self.iter() {
+			EncodedBE::encode(v, buf)?;
+		}
+		Ok( () )
+	}
+	fn decode(buf: &mut &[u8]) -> Result<Self> {
+		// SAFE: Just making an array of uninit from an uninit
+		let mut rv: [::core::mem::MaybeUninit<T>; N] = unsafe { ::core::mem::MaybeUninit::uninit().assume_init() };
+		for v in rv.iter_mut() {
+			v.write(EncodedBE::decode(buf)?);
+		}
+		// SAFE: The value is now fully initialised
+		Ok( unsafe { ::core::mem::transmute_copy::<_, Self>(&rv) } )
+	}
+}
+
 impl_encoded_prim!( u8 => read_u8,write_u8 );
 impl_encoded_prim!( i8 => read_i8,write_i8 );
 impl_encoded_prim!( u16 => read_u16,write_u16 );
@@ -53,6 +53,18 @@ impl<K, V> VecMap<K,V>
 			ents: Vec::new(),
 		}
 	}
+	/// Number of items in the map
+	pub fn len(&self) -> usize {
+		self.ents.len()
+	}
+	/// Remove all items
+	pub fn clear(&mut self) {
+		self.ents.clear()
+	}
+	/// Remove all items for which `f` returns false
+	pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V)->bool) {
+		self.ents.retain_mut(|e| f(&e.0, &mut e.1))
+	}
 }
 impl<K: Ord, V> VecMap<K,V>
 {
@@ -70,8 +82,12 @@ impl<K: Ord, V> VecMap<K,V>
 		}
 	}
 	/// Remove an item from the map
-	pub fn remove(&mut self, k: &K) -> Option<V> {
-		match self.ents.binary_search_by(|e| e.0.cmp(k))
+	pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+	where
+		Q: Ord,
+		K: Borrow<Q>
+	{
+		match self.ents.binary_search_by(|e| e.0.borrow().cmp(k))
 		{
 		Ok(idx) => Some( self.ents.remove(idx).1 ),
 		Err(_) => None,
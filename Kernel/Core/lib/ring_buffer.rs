@@ -155,6 +155,12 @@ impl<T: Send> AtomicRingBuf<T>
 		}
 	}
 	
+	/// Returns true if there are no items in the buffer (the state may change immediately after this returns)
+	pub fn is_empty(&self) -> bool
+	{
+		self.start.load(Ordering::Relaxed) == self.end.load(Ordering::Relaxed)
+	}
+
 	//#[is_safe(irq)]	// Handles IRQ safety
 	/// Pop an item from the ring buffer
 	pub fn pop(&self) -> Option<T>
@@ -52,13 +52,13 @@ impl<T> SparseVec<T>
 		self.count += 1;
 		self.data.len() - 1
 	}
-	/// Remove the item at the specified location
-	pub fn remove(&mut self, idx: usize) {
-		if idx < self.data.len() && self.data[idx].is_some()
-		{
-			self.data[idx] = None;
+	/// Remove the item at the specified location (returning it)
+	pub fn remove(&mut self, idx: usize) -> Option<T> {
+		let rv = self.data.get_mut(idx).and_then(|e| e.take());
+		if rv.is_some() {
 			self.count -= 1;
 		}
+		rv
 	}
 	
 	pub fn get(&self, idx: usize) -> Option<&T> {
@@ -86,6 +86,8 @@ pub mod memory;
 pub mod threads;
 /// Timekeeping (timers and wall time)
 pub mod time;
+/// Entropy pool and random number generation
+pub mod rand;
 
 /// Module management (loading and initialisation of kernel modules)
 pub mod modules;
@@ -165,6 +165,10 @@ impl CachedPage
 			FrameHandle::from_addr( crate::memory::virt::get_phys(self.0.as_ptr()) )
 		}
 	}
+	/// Returns true if the frame is also referenced outside the cache (e.g. by a `FrameHandle` from `get_frame_handle`)
+	pub fn is_shared(&self) -> bool {
+		crate::memory::phys::is_multi_referenced( crate::memory::virt::get_phys(self.0.as_ptr()) )
+	}
 	pub fn data(&self) -> &[u8] {
 		// SAFE: Owned and valid
 		unsafe { &self.0.as_ref().0 }
@@ -30,6 +30,18 @@ static S_FREE_STACK : crate::sync::Mutex<PAddr> = mutex_init!( NOPAGE );
 /// A handle to a physical page (maintaining a reference to it, even when not mapped)
 pub struct FrameHandle(PAddr);
 
+/// A source of memory that can be given back when physical memory runs out (e.g. a cache)
+pub trait Reclaimable: Sync
+{
+	/// Release up to `count` pages, returning the number released
+	///
+	/// Called when an allocation fails, so this must not allocate memory or block on locks that an
+	/// allocating thread could be holding (use `try_lock`).
+	fn reclaim(&self, count: usize) -> usize;
+}
+const MAX_RECLAIMABLE: usize = 8;
+static S_RECLAIMABLE: crate::sync::Spinlock<[Option<&'static dyn Reclaimable>; MAX_RECLAIMABLE]> = crate::sync::Spinlock::new([None; MAX_RECLAIMABLE]);
+
 pub fn init()
 {
 	// 1. Acquire a memory map from the architecture code and save for use later
@@ -67,6 +79,10 @@ impl FrameHandle
 	pub unsafe fn from_addr_noref(addr: PAddr) -> FrameHandle {
 		FrameHandle(addr)
 	}
+	/// Returns true if other handles (or mappings) refer to this frame
+	pub fn is_shared(&self) -> bool {
+		is_multi_referenced(self.0)
+	}
 	pub fn into_addr(self) -> PAddr {
 		let rv = self.0;
 		::core::mem::forget(self);
@@ -91,6 +107,37 @@ impl Drop for FrameHandle
 	}
 }
 
+/// Register a source of reclaimable memory
+pub fn register_reclaimable(source: &'static dyn Reclaimable)
+{
+	let mut lh = S_RECLAIMABLE.lock();
+	match lh.iter_mut().find(|v| v.is_none())
+	{
+	Some(slot) => *slot = Some(source),
+	None => log_error!("Too many reclaimable memory sources registered (max {})", MAX_RECLAIMABLE),
+	}
+}
+/// Ask the registered reclaimable sources to release `count` pages, returns the number released
+///
+/// NOTE: Must not be called with memory management locks held (sources can unmap pages)
+pub fn reclaim(count: usize) -> usize
+{
+	// Copy the list out, so the sources aren't called with the spinlock held
+	let sources = *S_RECLAIMABLE.lock();
+	let mut released = 0;
+	for source in sources.iter().filter_map(|v| *v)
+	{
+		if released >= count {
+			break;
+		}
+		released += source.reclaim(count - released);
+	}
+	if released > 0 {
+		log_debug!("reclaim({}): Released {} pages", count, released);
+	}
+	released
+}
+
 fn get_memory_map() -> &'static [crate::memory::MemoryMapEnt]
 {
 	&*S_MEM_MAP
@@ -279,6 +326,15 @@ fn allocate_int( address: Option<*mut ()> ) -> Result<Option<TempHandle<u8>>, Er
 	Err( Error )
 }
 
+/// Returns true if there are other references to this frame (e.g. it's also mapped by a process)
+pub fn is_multi_referenced(paddr: PAddr) -> bool
+{
+	match phys_to_ram_frame(paddr)
+	{
+	Some(frame) => phys_track::get_multiref_count(frame) != 0,
+	None => false,
+	}
+}
 pub fn ref_frame(paddr: PAddr)
 {
 	if let Some(frame) = phys_to_ram_frame(paddr) {
@@ -138,6 +138,16 @@ pub fn allocate_user(addr: *mut (), page_count: usize) -> Result<(), MapError> {
 }
 
 fn allocate_int(addr: *mut (), page_count: usize, is_user: bool) -> Result<(), MapError>
+{
+	match allocate_int_inner(addr, page_count, is_user)
+	{
+	// Out of memory, try to release some cached memory and try again
+	// - This is done without the address space lock held, as reclaiming can unmap pages
+	Err(MapError::OutOfMemory) if crate::memory::phys::reclaim(page_count) > 0 => allocate_int_inner(addr, page_count, is_user),
+	rv => rv,
+	}
+}
+fn allocate_int_inner(addr: *mut (), page_count: usize, is_user: bool) -> Result<(), MapError>
 {
 	use crate::arch::memory::addresses::is_global;
 
@@ -220,13 +230,18 @@ impl Reservation
 		// SAFE: Unique, and owned
 		unsafe { ::core::slice::from_raw_parts_mut( (self.0 as usize + idx * PAGE_SIZE) as *mut u8, PAGE_SIZE) }
 	}
-	//pub fn map_at(&mut self, idx: usize, frame: FrameHandle) -> Result<(),FrameHandle> {
-	//	assert!(idx < self.1);
-	//	let addr = (self.0 as usize + idx * PAGE_SIZE) as *mut ();
-	//	::arch::memory::virt::unmap(addr);
-	//	::arch::memory::virt::map(addr, frame.into_addr(), ProtectionMode::KernelRW);
-	//	Ok( () )
-	//}
+	/// Replace a page of the reservation with an existing frame (e.g. a shared cache page)
+	pub fn map_at(&mut self, idx: usize, frame: crate::memory::phys::FrameHandle) {
+		assert!(idx < self.1);
+		let addr = (self.0 as usize + idx * PAGE_SIZE) as *mut ();
+		// SAFE: The reservation owns this page, the placeholder frame is released and the handle's reference moves to the mapping
+		unsafe {
+			if let Some(old) = crate::arch::memory::virt::unmap(addr) {
+				crate::memory::phys::deref_frame(old);
+			}
+			crate::arch::memory::virt::map(addr, frame.into_addr(), ProtectionMode::KernelRW);
+		}
+	}
 	pub fn finalise(self, final_mode: ProtectionMode) -> Result<(),()> {
 		log_trace!("Reservation::finalise(final_mode={:?})", final_mode);
 		for addr in Pages(self.0, self.1) {
@@ -534,7 +549,12 @@ unsafe fn map_hw(phys: PAddr, count: usize, readonly: bool, _module: &'static st
 pub fn alloc_free() -> Result<FreePage,MapError>
 {
 	log_trace!("alloc_free()");
-	let map_handle = crate::memory::phys::allocate_bare().map_err(|_| MapError::OutOfMemory)?;
+	let map_handle = match crate::memory::phys::allocate_bare()
+		{
+		Ok(v) => v,
+		Err(_) if crate::memory::phys::reclaim(1) > 0 => crate::memory::phys::allocate_bare().map_err(|_| MapError::OutOfMemory)?,
+		Err(_) => return Err(MapError::OutOfMemory),
+		};
 	log_trace!("- frame = {:#x}, map_handle = {:p}", get_phys(&map_handle[0]), &map_handle[0]);
 	Ok( FreePage(map_handle) )
 }
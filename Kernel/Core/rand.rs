@@ -0,0 +1,262 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/rand.rs
+//! Kernel entropy pool and random number generation
+//!
+//! Entropy (hardware RNG, cycle counter jitter, and interrupt timing) is mixed into a ChaCha20 key,
+//! output is generated from that key with fast key erasure (the key is replaced after every request).
+use ::core::sync::atomic::{AtomicUsize,Ordering};
+use crate::sync::Spinlock;
+
+struct Pool
+{
+	seeded: bool,
+	key: [u32; 8],
+	counter: u64,
+	/// Per-boot secret for `secret_hash`
+	hash_key: [u64; 2],
+}
+
+static S_POOL: Spinlock<Pool> = Spinlock::new(Pool { seeded: false, key: [0; 8], counter: 0, hash_key: [0; 2] });
+/// Interrupt timing samples, folded into the pool on the next request
+static S_FAST_POOL: AtomicUsize = AtomicUsize::new(0);
+static S_FAST_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Seed the pool early (otherwise done on first use)
+pub fn init()
+{
+	let mut lh = S_POOL.lock();
+	lh.seed();
+	log_log!("Entropy pool seeded (hardware RNG {})", if crate::arch::entropy::hw_random().is_some() { "present" } else { "absent" });
+}
+
+/// Called on every interrupt to add its arrival time to the pool
+pub fn add_interrupt_timing()
+{
+	let c = crate::arch::entropy::cycle_counter() as usize;
+	let v = S_FAST_POOL.load(Ordering::Relaxed);
+	S_FAST_POOL.store(v.rotate_left(7) ^ c, Ordering::Relaxed);
+	S_FAST_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Mix caller-provided data (e.g. device serial numbers, packet timing) into the pool
+pub fn add_entropy(data: &[u8])
+{
+	S_POOL.lock().mix(data);
+}
+
+/// Fill a buffer with random bytes
+pub fn get_bytes(dst: &mut [u8])
+{
+	// Take a key for this request (and replace the pool's key) under the lock, then generate outside it
+	let key = {
+		let mut lh = S_POOL.lock();
+		lh.prepare();
+		let block = lh.next_block();
+		lh.key.copy_from_slice(&block[..8]);
+		let mut rv = [0; 8];
+		rv.copy_from_slice(&block[8..]);
+		rv
+		};
+	for (i,chunk) in dst.chunks_mut(64).enumerate()
+	{
+		let block = chacha20_block(&key, [i as u32, (i >> 16 >> 16) as u32, 0, 0]);
+		for (d,w) in chunk.chunks_mut(4).zip(block.iter()) {
+			d.copy_from_slice(&w.to_le_bytes()[..d.len()]);
+		}
+	}
+}
+pub fn get_u32() -> u32
+{
+	let mut b = [0; 4];
+	get_bytes(&mut b);
+	u32::from_le_bytes(b)
+}
+pub fn get_u64() -> u64
+{
+	let mut b = [0; 8];
+	get_bytes(&mut b);
+	u64::from_le_bytes(b)
+}
+
+/// Keyed hash of `data` using a per-boot secret (for values that must be unpredictable to remote peers)
+pub fn secret_hash(data: &[u8]) -> u64
+{
+	let key = {
+		let mut lh = S_POOL.lock();
+		lh.prepare();
+		lh.hash_key
+		};
+	siphash24(&key, data)
+}
+
+impl Pool
+{
+	fn seed(&mut self)
+	{
+		if self.seeded {
+			return ;
+		}
+		for _ in 0 .. 4 {
+			if let Some(v) = crate::arch::entropy::hw_random() {
+				self.mix(&v.to_le_bytes());
+			}
+		}
+		// Jitter between back-to-back reads of the cycle counter (with a little work between them)
+		let mut jitter = [0u8; 64];
+		for (i,b) in jitter.iter_mut().enumerate()
+		{
+			let t0 = crate::arch::entropy::cycle_counter();
+			for _ in 0 .. (t0 as usize & 0xF) + i {
+				::core::hint::spin_loop();
+			}
+			*b = (crate::arch::entropy::cycle_counter().wrapping_sub(t0)) as u8;
+		}
+		self.mix(&jitter);
+		self.mix(&crate::arch::time::cur_timestamp().to_le_bytes());
+
+		let b = self.next_block();
+		self.hash_key = [
+			(b[8] as u64) << 32 | b[9] as u64,
+			(b[10] as u64) << 32 | b[11] as u64,
+			];
+		self.seeded = true;
+	}
+	/// Seed (if needed) and fold in pending interrupt timings
+	fn prepare(&mut self)
+	{
+		self.seed();
+		if S_FAST_COUNT.swap(0, Ordering::Relaxed) > 0 {
+			let v = S_FAST_POOL.swap(0, Ordering::Relaxed);
+			self.mix(&v.to_le_bytes());
+		}
+		let c = crate::arch::entropy::cycle_counter();
+		self.mix(&c.to_le_bytes());
+	}
+
+	/// Absorb data into the key (XOR 32-byte chunks into the key, then re-key)
+	fn mix(&mut self, data: &[u8])
+	{
+		for chunk in data.chunks(32)
+		{
+			for (i,b) in chunk.iter().enumerate() {
+				self.key[i / 4] ^= (*b as u32) << (8 * (i % 4));
+			}
+			let block = self.next_block();
+			self.key.copy_from_slice(&block[..8]);
+		}
+	}
+
+	fn next_block(&mut self) -> [u32; 16]
+	{
+		let c = self.counter;
+		self.counter += 1;
+		chacha20_block(&self.key, [c as u32, (c >> 32) as u32, 0, 0])
+	}
+}
+
+/// ChaCha20 block function, `input` is the counter and nonce words
+fn chacha20_block(key: &[u32; 8], input: [u32; 4]) -> [u32; 16]
+{
+	fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+		s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(16);
+		s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(12);
+		s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(8);
+		s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(7);
+	}
+	let mut init = [0u32; 16];
+	init[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);	// "expand 32-byte k"
+	init[4..12].copy_from_slice(key);
+	init[12..].copy_from_slice(&input);
+
+	let mut s = init;
+	for _ in 0 .. 10
+	{
+		quarter_round(&mut s, 0, 4,  8, 12);
+		quarter_round(&mut s, 1, 5,  9, 13);
+		quarter_round(&mut s, 2, 6, 10, 14);
+		quarter_round(&mut s, 3, 7, 11, 15);
+		quarter_round(&mut s, 0, 5, 10, 15);
+		quarter_round(&mut s, 1, 6, 11, 12);
+		quarter_round(&mut s, 2, 7,  8, 13);
+		quarter_round(&mut s, 3, 4,  9, 14);
+	}
+	for (o,i) in s.iter_mut().zip(init.iter()) {
+		*o = o.wrapping_add(*i);
+	}
+	s
+}
+
+/// SipHash-2-4
+fn siphash24(key: &[u64; 2], data: &[u8]) -> u64
+{
+	fn round(v: &mut [u64; 4]) {
+		v[0] = v[0].wrapping_add(v[1]); v[1] = v[1].rotate_left(13); v[1] ^= v[0]; v[0] = v[0].rotate_left(32);
+		v[2] = v[2].wrapping_add(v[3]); v[3] = v[3].rotate_left(16); v[3] ^= v[2];
+		v[0] = v[0].wrapping_add(v[3]); v[3] = v[3].rotate_left(21); v[3] ^= v[0];
+		v[2] = v[2].wrapping_add(v[1]); v[1] = v[1].rotate_left(17); v[1] ^= v[2]; v[2] = v[2].rotate_left(32);
+	}
+	let mut v = [
+		key[0] ^ 0x736f6d6570736575,
+		key[1] ^ 0x646f72616e646f6d,
+		key[0] ^ 0x6c7967656e657261,
+		key[1] ^ 0x7465646279746573,
+		];
+	fn compress(v: &mut [u64; 4], m: u64) {
+		v[3] ^= m;
+		round(v);
+		round(v);
+		v[0] ^= m;
+	}
+	let mut chunks = data.chunks_exact(8);
+	for c in &mut chunks {
+		let mut b = [0; 8];
+		b.copy_from_slice(c);
+		compress(&mut v, u64::from_le_bytes(b));
+	}
+	let mut last = (data.len() as u64) << 56;
+	for (i,b) in chunks.remainder().iter().enumerate() {
+		last |= (*b as u64) << (8 * i);
+	}
+	compress(&mut v, last);
+	v[2] ^= 0xFF;
+	for _ in 0 .. 4 {
+		round(&mut v);
+	}
+	v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+#[test]
+// RFC 8439 section 2.3.2
+fn chacha20_vector()
+{
+	let key = [0x03020100, 0x07060504, 0x0b0a0908, 0x0f0e0d0c, 0x13121110, 0x17161514, 0x1b1a1918, 0x1f1e1d1c];
+	let out = chacha20_block(&key, [1, 0x09000000, 0x4a000000, 0]);
+	assert_eq!(out, [
+		0xe4e7f110, 0x15593bd1, 0x1fdd0f50, 0xc47120a3, 0xc7f4d1c7, 0x0368c033, 0x9aaa2204, 0x4e6cd4c3,
+		0x466482d2, 0x09aa9f07, 0x05d7c214, 0xa2028bd9, 0xd19c12b5, 0xb94e16de, 0xe883d0cb, 0x4e3c50a2,
+		]);
+}
+
+#[test]
+// Reference implementation vector (key 00..0f, message 00..0e)
+fn siphash_vector()
+{
+	let key = [0x0706050403020100, 0x0f0e0d0c0b0a0908];
+	let msg: [u8; 15] = [0,1,2,3,4,5,6,7,8,9,10,11,12,13,14];
+	assert_eq!(siphash24(&key, &msg), 0xa129ca6149be45e5);
+	assert_eq!(siphash24(&key, &[]), 0x726fdb47dd0e0e31);
+}
+
+#[test]
+// Successive requests don't repeat
+fn distinct_output()
+{
+	let mut a = [0; 100];
+	let mut b = [0; 100];
+	get_bytes(&mut a);
+	get_bytes(&mut b);
+	assert!(a[..] != b[..]);
+	assert!(a[64..] != [0; 36][..]);
+}
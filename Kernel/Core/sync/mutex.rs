@@ -82,6 +82,21 @@ impl UnitMutex
 		::core::sync::atomic::fence(::core::sync::atomic::Ordering::Acquire);
 	}
 
+	/// Acquire the mutex only if it's not currently held
+	pub fn try_lock(&self, ty_name: &'static str) -> bool {
+		{
+			let mut lh = self.inner.lock();
+			if lh.held {
+				return false;
+			}
+			lh.held = true;
+			lh.holder = crate::threads::get_thread_id();
+		}
+		Self::trace(self, ty_name, "try_lock - acquired");
+		::core::sync::atomic::fence(::core::sync::atomic::Ordering::Acquire);
+		true
+	}
+
 	/// UNSAFE: Must only be called when the controlled resource is being released
 	#[inline(never)]	// These are nice debugging points
 	pub unsafe fn unlock(&self, ty_name: &'static str) {
@@ -130,6 +145,16 @@ impl<T: ?Sized+Send> Mutex<T>
 		return HeldMutex { lock: self };
 	}
 
+	/// Lock the mutex if it's not currently held (never blocks)
+	pub fn try_lock(&self) -> Option<HeldMutex<T>> {
+		if self.inner.try_lock(type_name!(Self)) {
+			Some( HeldMutex { lock: self } )
+		}
+		else {
+			None
+		}
+	}
+
 	/// Obtain `&mut` to the contained data
 	pub fn get_mut(&mut self) -> &mut T {
 		// SAFE: Have exclusive access (`&mut self`)
@@ -183,6 +208,13 @@ impl<T: Send> LazyMutex<T>
 		assert!(lh.is_some(), "Locking an uninitialised LazyMutex<{}>", type_name!(T));
 		HeldLazyMutex( lh )
 	}
+	/// Lock the lazy mutex if it's not currently held (never blocks)
+	pub fn try_lock(&self) -> Option<HeldLazyMutex<T>>
+	{
+		let lh = self.0.try_lock()?;
+		assert!(lh.is_some(), "Locking an uninitialised LazyMutex<{}>", type_name!(T));
+		Some( HeldLazyMutex( lh ) )
+	}
 }
 
 /// Unlock on drop of HeldMutex
@@ -12,7 +12,7 @@ mod worker_thread;
 
 mod sleep_object;
 
-pub use self::thread::{Thread,ThreadPtr,ThreadID,ProcessID};
+pub use self::thread::{Thread,ThreadPtr,ThreadID,ProcessID,Credentials};
 pub use self::thread::{ThreadHandle,ProcessHandle};
 pub use self::thread::new_idle_thread;
 
@@ -183,6 +183,24 @@ pub fn get_process_id() -> thread::ProcessID {
 	p.get_process_info().get_pid()
 }
 
+/// Credentials of the current process (root if there's no current thread, e.g. during early boot)
+pub fn get_credentials() -> thread::Credentials {
+	let p = crate::arch::threads::borrow_thread();
+	// SAFE: Checks for NULL, and the thread should be valid while executing
+	unsafe {
+		if p.is_null() {
+			thread::Credentials::ROOT
+		}
+		else {
+			(*p).get_process_info().get_credentials()
+		}
+	}
+}
+/// Change the credentials of the current process
+pub fn set_credentials(credentials: thread::Credentials) {
+	with_cur_thread( |cur| cur.get_process_info().set_credentials(credentials) )
+}
+
 fn with_cur_thread<T, F: FnOnce(&thread::Thread)->T>(fcn: F) -> T
 {
 	// SAFE: Checks for NULL, and the thread should be valid while executing
@@ -35,11 +35,29 @@ pub enum RunState
 unsafe impl Send for RunState { }
 impl Default for RunState { fn default() -> RunState { RunState::Runnable } }
 
+/// User/group identity of a process (used for filesystem permission checks)
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Credentials
+{
+	pub uid: u32,
+	pub gid: u32,
+}
+impl Credentials
+{
+	/// Superuser (bypasses permission checks), used by the kernel and the initial process
+	pub const ROOT: Credentials = Credentials { uid: 0, gid: 0 };
+
+	pub fn is_root(&self) -> bool {
+		self.uid == 0
+	}
+}
+
 pub struct Process
 {
 	name: String,
 	pid: ProcessID,
 	address_space: crate::memory::virt::AddressSpace,
+	credentials: crate::sync::Mutex<Credentials>,
 	// TODO: use of a tuple here looks a little crufty
 	exit_status: crate::sync::Mutex< (Option<u32>, Option<crate::threads::sleep_object::SleepObjectRef>) >,
 	pub proc_local_data: crate::sync::RwLock<Vec< crate::lib::mem::aref::Aref<dyn core::any::Any+Sync+Send> >>,
@@ -129,16 +147,18 @@ impl Process
 			pid: 0,
 			exit_status: Default::default(),
 			address_space: crate::memory::virt::AddressSpace::pid0(),
+			credentials: crate::sync::Mutex::new(Credentials::ROOT),
 			proc_local_data: crate::sync::RwLock::new( Vec::new() ),
 		})
 	}
-	pub fn new<S: Into<String>+::core::fmt::Debug>(name: S, addr_space: crate::memory::virt::AddressSpace) -> Arc<Process>
+	pub fn new<S: Into<String>+::core::fmt::Debug>(name: S, addr_space: crate::memory::virt::AddressSpace, credentials: Credentials) -> Arc<Process>
 	{
 		Arc::new(Process {
 			pid: allocate_pid(),
 			name: name.into(),
 			exit_status: Default::default(),
 			address_space: addr_space,
+			credentials: crate::sync::Mutex::new(credentials),
 			proc_local_data: crate::sync::RwLock::new( Vec::new() ),
 		})
 	}
@@ -149,6 +169,15 @@ impl Process
 
 	pub fn get_pid(&self) -> ProcessID { self.pid }
 
+	pub fn get_credentials(&self) -> Credentials {
+		*self.credentials.lock()
+	}
+	/// Replace the process's credentials (callers are responsible for checking that this is allowed)
+	pub fn set_credentials(&self, credentials: Credentials) {
+		log_debug!("{}: credentials {:?}", self, credentials);
+		*self.credentials.lock() = credentials;
+	}
+
 	pub fn mark_exit(&self, status: u32) -> Result<(),()> {
 		let mut lh = self.exit_status.lock();
 		if lh.0.is_some() {
@@ -168,8 +197,10 @@ impl Process
 
 impl ProcessHandle
 {
+	/// Create a new process (inheriting the current process's credentials)
 	pub fn new<S: Into<String>+::core::fmt::Debug>(name: S, clone_start: usize, clone_end: usize) -> ProcessHandle {
-		ProcessHandle( Process::new(name, crate::memory::virt::AddressSpace::new(clone_start, clone_end).expect("ProcessHandle::new - OOM")) )
+		let address_space = crate::memory::virt::AddressSpace::new(clone_start, clone_end).expect("ProcessHandle::new - OOM");
+		ProcessHandle( Process::new(name, address_space, super::get_credentials()) )
 	}
 	
 	#[cfg(not(feature="test"))]
@@ -51,6 +51,8 @@ struct CachedBlock
 	reference_count: AtomicUsize,
 	last_access: ::kernel::time::CacheTimer,
 	is_dirty: AtomicBool,
+	/// Number of holds preventing writeback (see `CachedVolume::hold_block`)
+	hold_count: AtomicUsize,
 
 	mapping: RwLock<Option<::kernel::memory::page_cache::CachedPage>>,
 }
@@ -175,7 +177,13 @@ impl CachedVolume
 				cached_block.edit(|block_data| {
 					block_data[begin ..][ .. ldata.len()].copy_from_slice( ldata );
 					});
-				cached_block.0.flush(&self.vh).await?;
+				if cached_block.0.hold_count.load(Ordering::Acquire) > 0 {
+					// Writeback of the entry is held (for other blocks in it), so just write this range
+					self.vh.write_blocks(block + cur_rel_block, ldata).await?
+				}
+				else {
+					cached_block.0.flush(&self.vh).await?;
+				}
 			}
 			else {
 				self.vh.write_blocks(block + cur_rel_block, ldata).await?
@@ -260,6 +268,34 @@ impl CachedVolume
 	}
 }
 
+/// Writeback control
+impl CachedVolume
+{
+	/// Hold off writeback of a cached block, edits stay in the cache until the matching `release_block`
+	///
+	/// Used for journalled metadata, which must not reach the disk before its transaction is committed.
+	/// NOTE: Holds apply to the whole cache entry (i.e. any other blocks in the same page are also held)
+	pub async fn hold_block(&self, block: u64) -> Result<(), IoError>
+	{
+		let cached_block = self.get_block_meta(block).await?;
+		cached_block.0.hold_count.fetch_add(1, Ordering::AcqRel);
+		Ok( () )
+	}
+	/// Release a hold taken by `hold_block`, writing the block back if this was the last hold
+	pub async fn release_block(&self, block: u64) -> Result<(), IoError>
+	{
+		let cached_block = match self.get_block_meta_opt(block)
+			{
+			Some(v) => v,
+			None => panic!("release_block({}): Block not in the cache", block),
+			};
+		if cached_block.0.hold_count.fetch_sub(1, Ordering::AcqRel) == 1 {
+			cached_block.0.flush(&self.vh).await?;
+		}
+		Ok( () )
+	}
+}
+
 fn map_cached_frame(frame: &::kernel::memory::phys::FrameHandle) -> ::kernel::memory::page_cache::CachedPage
 {
 	// TODO: If this returns that there's no free mappings, go and steal one from within the cache
@@ -286,14 +322,18 @@ impl CachedBlock
 
 			last_access: Default::default(),
 			is_dirty: AtomicBool::new(false),
+			hold_count: AtomicUsize::new(0),
 			mapping: RwLock::new(Some(mapping)),
 			})
 	}
 	
-	/// Write a modified block back to disk
+	/// Write a modified block back to disk (unless writeback is held off)
 	async fn flush(&self, vol: &VolumeHandle) -> Result<(), IoError>
 	{
 		let lh = self.mapping.read();
+		if self.hold_count.load(Ordering::Acquire) > 0 {
+			return Ok( () );
+		}
 		if self.is_dirty.swap(false, Ordering::Acquire)
 		{
 			vol.write_blocks(self.index, lh.as_ref().expect("CachedBlock::flush - None mapping").data()).await?;
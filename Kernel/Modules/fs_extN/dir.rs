@@ -3,6 +3,7 @@
 //
 // Modules/fs_extN/dir.rs
 //! Directory handling
+use kernel::prelude::*;
 use kernel::lib::byte_str::ByteStr;
 
 pub struct Dir
@@ -20,101 +21,169 @@ impl Dir
 			}
 	}
 
+	pub fn inode(&self) -> &::inodes::Inode
+	{
+		&self.inode
+	}
+
+
+	/// The directory's hash index, if it has one that can be used
+	fn index_root(&self) -> ::vfs::node::Result<Option<::htree::Root>>
+	{
+		// The index flag is ignored if the filesystem doesn't have the feature
+		if !self.inode.fs.has_feature_compat(::ondisk::FEAT_COMPAT_DIR_INDEX) {
+			return Ok(None);
+		}
+		if self.inode.lock_read().i_flags() & ::ondisk::EXT4_INDEX_FL as u32 == 0 {
+			return Ok(None);
+		}
+		::htree::Root::read(&self.inode)
+	}
 
 	/// Returns (block_index, offset)
 	fn find_name(&self, name: &ByteStr) -> ::vfs::node::Result<(usize, usize, ::vfs::node::InodeId)>
 	{
 		log_debug!("find_name({:?})", name);
-		let inode = self.inode.lock_read();
-		// TODO: Use the B+ trees if present
+		// "." and ".." aren't in the index, they're always at the start of the first block
+		if name != "." && name != ".." {
+			if let Some(root) = try!(self.index_root()) {
+				return self.find_name_indexed(&root, name);
+			}
+		}
 
 		// Linear search
+		let inode = self.inode.lock_read();
 		for (blk_index, vol_blk) in inode.blocks().enumerate()
 		{
 			log_trace!("find_name: Block {} (vol_blk={})", blk_index, vol_blk);
-			let blk_data = try!(self.inode.fs.get_block(vol_blk));
-			
-			let mut offset = 0;
-			for ent in DirEnts(&blk_data)
-			{
-				if ent.d_rec_len == 0 {
-					log_error!("find_name: Found d_rec_len=0");
-					return Err( ::vfs::Error::InconsistentFilesystem );
-				}
-				else if ent.d_inode == 0 {
-					log_debug!("find_name: Found d_inode=0");
-					return Err( :: vfs::Error::NotFound );
-				}
-				else if &ent.d_name == name.as_bytes()
-				{
-					return Ok( (blk_index, offset, ent.d_inode as ::vfs::node::InodeId) );
-				}
-				else {
-					offset += ent.u32_len() * 4;
-				}
+			if let Some((offset, ino)) = try!(self.search_block(vol_blk, name)) {
+				return Ok( (blk_index, offset, ino) );
 			}
 		}
 		Err( ::vfs::Error::NotFound )
 	}
 
-	/// Defragment a directory block, and return the offset and length of the final free entry
-	fn defragment_block(fs: &crate::instance::InstanceInner, block: u32) -> ::vfs::node::Result<(usize, u16)> {
-		fs.edit_block(block, |blk_data: &mut [u8]| {
-			// SAFE: Alignment checked, range valid
-			let blk_data: &mut [u32] = unsafe {
-				assert!(&blk_data[0] as *const _ as usize % 4 == 0);
-				::core::slice::from_raw_parts_mut(blk_data.as_mut_ptr() as *mut u32, blk_data.len() / 4)
-				};
+	/// Look up a name using the hash index, only searching the leaf block(s) that could hold its hash
+	fn find_name_indexed(&self, root: &::htree::Root, name: &ByteStr) -> ::vfs::node::Result<(usize, usize, ::vfs::node::InodeId)>
+	{
+		let hash = root.hash(name.as_bytes());
+		let mut path = try!(root.probe(&self.inode, hash));
+		loop
+		{
+			let blk_index = try!(path.last().unwrap().target(&self.inode));
+			let vol_blk = try!(self.inode.lock_read().get_block_addr(blk_index));
+			log_trace!("find_name_indexed: hash {:#x} leaf {} (vol_blk={})", hash, blk_index, vol_blk);
+			if let Some((offset, ino)) = try!(self.search_block(vol_blk, name)) {
+				return Ok( (blk_index as usize, offset, ino) );
+			}
+			if !try!(root.next_leaf(&self.inode, &mut path, hash)) {
+				return Err( ::vfs::Error::NotFound );
+			}
+		}
+	}
+
+	/// Search a single block for a name, returning its offset and inode
+	fn search_block(&self, vol_blk: u32, name: &ByteStr) -> ::vfs::node::Result<Option<(usize, ::vfs::node::InodeId)>>
+	{
+		let blk_data = try!(self.inode.fs.get_block(vol_blk));
+		let mut offset = 0;
+		for ent in DirEnts(&blk_data)
+		{
+			if ent.d_rec_len == 0 {
+				log_error!("find_name: Found d_rec_len=0");
+				return Err( ::vfs::Error::InconsistentFilesystem );
+			}
+			// Unused entries (e.g. the first in a block, after a deletion) have a zero inode
+			else if ent.d_inode != 0 && &ent.d_name == name.as_bytes()
+			{
+				return Ok( Some((offset, ent.d_inode as ::vfs::node::InodeId)) );
+			}
+			offset += ent.u32_len() * 4;
+		}
+		Ok(None)
+	}
+
+	/// Defragment a directory block (packing entries to the start), and return the offset and free space of the final entry
+	fn defragment_block(fs: &crate::instance::InstanceInner, block: u32) -> ::vfs::node::Result<(usize, usize)> {
+		fs.edit_block_u32(block, |blk_data: &mut [u32]| {
 			let mut write_u32s = 0;
 			let mut read_u32s = 0;
+			let mut last = None;
 			while read_u32s < blk_data.len() {
 				if read_u32s + 1 == blk_data.len() {
 					return Err(::vfs::Error::InconsistentFilesystem);
 				}
-				let rec_len = (u32::from_le(blk_data[read_u32s+1]) & 0xFFFF) as u16;
-				let rec_len_u32s = ((rec_len + 4-1) / 4) as usize;
-				if read_u32s + rec_len_u32s > blk_data.len() {
+				let w1 = u32::from_le(blk_data[read_u32s+1]);
+				let rec_len = (w1 & 0xFFFF) as usize;
+				let name_len = ((w1 >> 16) & 0xFF) as usize;
+				let rec_len_u32s = rec_len / 4;
+				if rec_len < ::ondisk::DIRENT_MIN_SIZE || rec_len % 4 != 0 || read_u32s + rec_len_u32s > blk_data.len() {
 					return Err(::vfs::Error::InconsistentFilesystem);
 				}
 				if blk_data[read_u32s] == 0 {
 					// Entry is free, don't update the write position
 				}
 				else {
-					if read_u32s == write_u32s {
-						// Positions are equal, no change
+					// Copy entry into the new location, shrinking it to the space it needs
+					let used_u32s = dirent_len(name_len) / 4;
+					if read_u32s != write_u32s {
+						blk_data.copy_within(read_u32s .. read_u32s + used_u32s, write_u32s);
 					}
-					else {
-						// Copy entry into the new location
-						blk_data.copy_within(read_u32s .. read_u32s + rec_len_u32s, write_u32s);
-					}
-					write_u32s += rec_len_u32s;
+					blk_data[write_u32s + 1] = ((w1 & !0xFFFF) | (used_u32s * 4) as u32).to_le();
+					last = Some(write_u32s);
+					write_u32s += used_u32s;
 				}
 				read_u32s += rec_len_u32s;
 			}
-			let tail_len = read_u32s - write_u32s;
-			if tail_len > 0 {
-				blk_data[write_u32s + 0] = 0;
-				blk_data[write_u32s + 1] = (tail_len as u32 * 4).to_le();
+			let tail_len = (read_u32s - write_u32s) * 4;
+			match last
+			{
+			Some(last) => {
+				// Extend the final entry over the free space
+				let w1 = u32::from_le(blk_data[last + 1]);
+				blk_data[last + 1] = (w1 + tail_len as u32).to_le();
+				Ok( (last * 4, tail_len) )
+				},
+			None => {
+				blk_data[0] = 0;
+				blk_data[1] = (tail_len as u32).to_le();
+				Ok( (0, tail_len) )
+				},
 			}
-			Ok( (write_u32s * 4, (tail_len * 4) as u16) )
 			})
 	}
 
-	/// Locate a entry in the directory, also checking for duplicates
-	fn find_free(&self, name: &ByteStr) -> ::vfs::node::Result<(u32, usize)>
+	/// Locate space for a new entry in the directory, also checking for duplicates
+	///
+	/// Returns the block index and offset of an entry with enough unused space (None if the directory is full)
+	fn find_free(&self, name: &ByteStr) -> ::vfs::node::Result<Option<(u32, usize)>>
 	{
 		let inode = self.inode.lock_read();
 		// Linear search
-		// TODO: ext3 and later use B+ trees (so can exit the search early, as the lookup can be faster)
-		// - These are a ReadOnly feature, so we shouldn't be here if those are present
-
-		let mut rv: Option< ((u32, usize), u16) > = None;
-		let mut total_free = 0;
+		let mut rv: Option<(u32, usize)> = None;
 		for (blk_index, vol_blk) in inode.blocks().enumerate()
 		{
-			let mut block_free = 0;
+			// Keep searching after a slot is found, to check for duplicates
+			let slot = try!(self.find_space_in_block(vol_blk, name, rv.is_none()));
+			if rv.is_none() {
+				if let Some(offset) = slot {
+					rv = Some( (blk_index as u32, offset) );
+				}
+			}
+		}
+		Ok(rv)
+	}
+
+	/// Find an entry in a block with enough unused space for `name` (defragmenting the block if allowed and needed)
+	///
+	/// Fails with `AlreadyExists` if the name is in the block
+	fn find_space_in_block(&self, vol_blk: u32, name: &ByteStr, allow_defrag: bool) -> ::vfs::node::Result<Option<usize>>
+	{
+		let needed = dirent_len(name.len());
+		let mut block_free = 0;
+		let mut slot = None;
+		{
 			let blk_data = try!(self.inode.fs.get_block(vol_blk));
-			
 			let mut offset = 0;
 			for ent in DirEnts(&blk_data)
 			{
@@ -122,93 +191,401 @@ impl Dir
 					return Err( ::vfs::Error::InconsistentFilesystem );
 				}
 				
-				if ent.d_inode == 0 {
-					if &ent.d_name == name.as_bytes() {
-						return Err( ::vfs::Error::AlreadyExists );
+				let space = if ent.d_inode == 0 {
+						ent.d_rec_len as usize
 					}
+					else {
+						if &ent.d_name == name.as_bytes() {
+							return Err( ::vfs::Error::AlreadyExists );
+						}
+						(ent.d_rec_len as usize).saturating_sub( dirent_len(ent.d_name.len()) )
+					};
+				if space >= needed && slot.is_none() {
+					slot = Some(offset);
 				}
-				else {
-					if (ent.d_rec_len - 8) >= name.len() as u16 {
-						// Free entry with sufficient space!
-						rv = Some(match rv
-							{
-							Some( (v, len) ) if len < ent.d_rec_len => (v, len),
-							_ => ( (blk_index as u32, offset), ent.d_rec_len ),
-							});
-					}
-					block_free += ent.u32_len() * 4;
-				}
+				block_free += space;
 				offset += ent.u32_len() * 4;
 			}
+		}
+
+		if slot.is_some() || !allow_defrag {
+			Ok(slot)
+		}
+		else if block_free >= needed {
+			// Defragment the block, there's enough space for this name but not in a single contiguous chunk.
+			let (offset, space) = Self::defragment_block(&self.inode.fs, vol_blk)?;
+			if space >= needed {
+				Ok( Some(offset) )
+			}
+			else {
+				log_warning!("find_free: Defragmenting block {} didn't provide the expected space ({} < {})", vol_blk, space, needed);
+				Ok(None)
+			}
+		}
+		else {
+			Ok(None)
+		}
+	}
+
+	/// Locate space for a new entry using the hash index, splitting the leaf if it's full
+	fn find_free_indexed(&self, root: &mut ::htree::Root, name: &ByteStr) -> ::vfs::node::Result<(u32, usize)>
+	{
+		// The index locates the only blocks that could already hold this name
+		match self.find_name_indexed(root, name)
+		{
+		Ok(_) => return Err( ::vfs::Error::AlreadyExists ),
+		Err(::vfs::Error::NotFound) => {},
+		Err(e) => return Err(e),
+		}
+
+		let hash = root.hash(name.as_bytes());
+		let mut path = try!(root.probe(&self.inode, hash));
+		let blk_index = try!(path.last().unwrap().target(&self.inode));
+		let vol_blk = try!(self.inode.lock_read().get_block_addr(blk_index));
+		if let Some(ofs) = try!(self.find_space_in_block(vol_blk, name, true)) {
+			return Ok( (blk_index, ofs) );
+		}
+
+		// Split the leaf, and use whichever half now covers the name's hash
+		let (new_blk, split_hash) = try!(self.split_leaf(root, &mut path, blk_index));
+		let blk_index = if hash >= split_hash { new_blk } else { blk_index };
+		let vol_blk = try!(self.inode.lock_read().get_block_addr(blk_index));
+		match try!(self.find_space_in_block(vol_blk, name, true))
+		{
+		Some(ofs) => Ok( (blk_index, ofs) ),
+		None => {
+			log_warning!("find_free_indexed: No space in block {} after splitting", blk_index);
+			Err( ::vfs::Error::OutOfSpace )
+			},
+		}
+	}
+
+	/// Move the upper half (by hash) of a full leaf into a new block, and add that block to the index
+	///
+	/// Returns the new block and the lowest hash in it
+	fn split_leaf(&self, root: &mut ::htree::Root, path: &mut Vec<::htree::Level>, blk_index: u32) -> ::vfs::node::Result<(u32, u32)>
+	{
+		let fs = &self.inode.fs;
+		// Ensure there's room for the new index entry first, so a failure leaves the leaf alone
+		let bottom = path.len() - 1;
+		try!(self.index_make_room(root, path, bottom));
 
-			// If there wasn't a good space found yet, but this block has enough space on its own - then defragment and return
-			if rv.is_none() && block_free - 8 >= name.len() {
-				// Defragment the block, there's enough space for this name but not in a single contiguous chunk.
-				drop(blk_data);
-				let (offset,rec_len) = Self::defragment_block(&self.inode.fs, vol_blk)?;
-				if rec_len - 8 >= name.len() as u16 {
-					rv = Some(( (blk_index as u32, offset), rec_len));
+		// Collect the live entries, sorted by hash
+		let vol_blk = try!(self.inode.lock_read().get_block_addr(blk_index));
+		let mut ents: Vec<(u32, u32, u8, Vec<u8>)> = Vec::new();
+		{
+			let blk_data = try!(fs.get_block(vol_blk));
+			for ent in DirEnts(&blk_data)
+			{
+				if ent.d_rec_len == 0 {
+					return Err( ::vfs::Error::InconsistentFilesystem );
 				}
-				else {
-					// BUG? It was calculated that there was enough space, but defragmentation didn't provide enough
+				if ent.d_inode != 0 {
+					ents.push( (root.hash(&ent.d_name), ent.d_inode, ent.d_type, ent.d_name.to_vec()) );
 				}
 			}
-
-			total_free += block_free - 8;	// Calculate total free space, accounting for the required dirent header
 		}
-		if let Some( (rv, _) ) = rv {
-			return Ok(rv);
+		if ents.len() < 2 {
+			return Err( ::vfs::Error::InconsistentFilesystem );
 		}
+		ents.sort_by_key(|e| e.0);
 
-		if total_free >= name.len() {
-			// There is _maybe_ enough space for this name, but not in a contiguous block
-			// - "maybe" because entries cannot span block boundaries, so there might be a few small free slots at the
-			//   end of blocks.
-			// Defragment blocks in overlapping pairs until there's enough free space
+		// Split at half of the used space
+		let total: usize = ents.iter().map(|e| dirent_len(e.3.len())).sum();
+		let mut split = 0;
+		let mut size = 0;
+		while split < ents.len() - 1 && size + dirent_len(ents[split].3.len()) <= total / 2 {
+			size += dirent_len(ents[split].3.len());
+			split += 1;
+		}
+		let split = ::core::cmp::max(split, 1);
+		let split_hash = ents[split].0;
+		// If the hash continues from the previous block, flag that in the index so lookups check both
+		let continued = if ents[split-1].0 == split_hash { 1 } else { 0 };
+
+		let (new_blk, _) = try!(self.expand());
+		let new_vol_blk = try!(self.inode.lock_read().get_block_addr(new_blk));
+		try!(write_leaf(fs, new_vol_blk, &ents[split..]));
+		try!(write_leaf(fs, vol_blk, &ents[..split]));
+		try!(path.last_mut().unwrap().insert_after_at(&self.inode, split_hash | continued, new_blk));
+		log_debug!("split_leaf: I{} block {} split at {:#x} into {}", self.inode.get_id(), blk_index, split_hash, new_blk);
+		Ok( (new_blk, split_hash) )
+	}
+
+	/// Ensure that the index node at `path[lvl]` has space for another entry, splitting nodes (or adding a level) as needed
+	///
+	/// Returns the new position of the level in the path (it moves down if a level is added)
+	fn index_make_room(&self, root: &mut ::htree::Root, path: &mut Vec<::htree::Level>, lvl: usize) -> ::vfs::node::Result<usize>
+	{
+		if path[lvl].count < path[lvl].limit {
+			return Ok(lvl);
+		}
+		let fs_block_size = self.inode.fs.fs_block_size;
+		if lvl == 0
+		{
+			// Root is full, move its entries into a new node below it
+			if root.indirect_levels >= ::htree::max_indirect_levels(&self.inode.fs) {
+				log_warning!("I{}: Hash index is full", self.inode.get_id());
+				return Err( ::vfs::Error::OutOfSpace );
+			}
+			let entries = try!(path[0].read_entries(&self.inode));
+			let (new_blk, _) = try!(self.expand());
+			let mut node = ::htree::Level::node(new_blk, fs_block_size);
+			try!(node.write_entries(&self.inode, &entries));
+			node.at = path[0].at;
+			try!(path[0].write_entries(&self.inode, &[(0, new_blk)]));
+			path[0].at = 0;
+			root.indirect_levels += 1;
+			try!(::htree::set_indirect_levels(&self.inode, root.indirect_levels));
+			path.insert(1, node);
+			Ok(1)
 		}
+		else
+		{
+			// Split this node in two, adding the new half to the parent
+			let parent = try!(self.index_make_room(root, path, lvl - 1));
+			let lvl = parent + 1;
+			let mut entries = try!(path[lvl].read_entries(&self.inode));
+			let upper = entries.split_off(entries.len() / 2);
+			let (new_blk, _) = try!(self.expand());
+			let mut node = ::htree::Level::node(new_blk, fs_block_size);
+			try!(node.write_entries(&self.inode, &upper));
+			try!(path[lvl].write_entries(&self.inode, &entries));
+			try!(path[parent].insert_after_at(&self.inode, upper[0].0, new_blk));
+			if path[lvl].at >= entries.len() {
+				node.at = path[lvl].at - entries.len();
+				path[lvl] = node;
+				path[parent].at += 1;
+			}
+			Ok(lvl)
+		}
+	}
 
-		todo!("Dir::find_free - expand directory");
+	/// Append an empty block to the directory, returning the slot at its start
+	fn expand(&self) -> ::vfs::node::Result<(u32, usize)>
+	{
+		let fs_block_size = self.inode.fs.fs_block_size;
+		let mut inode = self.inode.lock_write();
+		let blk_index = inode.max_blocks();
+		inode.ensure_blocks_allocated(blk_index, 1)?;
+		let vol_blk = inode.get_block_addr(blk_index)?;
+		self.inode.fs.edit_block(vol_blk, |blk_data| {
+			for b in blk_data.iter_mut() {
+				*b = 0;
+			}
+			write_dirent(blk_data, 0, fs_block_size, b"", 0);
+			Ok( () )
+			})?;
+		inode.set_i_size( (blk_index as u64 + 1) * fs_block_size as u64 )?;
+		log_debug!("expand: I{} now {} blocks", self.inode.get_id(), blk_index + 1);
+		Ok( (blk_index, 0) )
 	}
 
 	/// Add an entry to the directory, checking for duplicates
-	fn add_dir_ent(&self, name: &ByteStr, inode: u32/*, d_type: u8*/) -> Result<(), ::vfs::Error>
+	fn add_dir_ent(&self, name: &ByteStr, inode: u32, d_type: u8) -> Result<(), ::vfs::Error>
 	{
 		if !(name.len() <= 255) {
 			return Err(::vfs::Error::InvalidParameter);
 		}
 		let _lh_write = self.inode.lock_dir();
+		let mut index = try!(self.index_root());
 
-		// 1. Find a suitable slot
-		let (blk, ofs) = try!(self.find_free(name));
+		// 1. Find a suitable slot (growing the directory if there isn't one)
+		let (blk, ofs) = match index
+			{
+			Some(ref mut root) => try!(self.find_free_indexed(root, name)),
+			None => match try!(self.find_free(name))
+				{
+				Some(v) => v,
+				None => try!(self.expand()),
+				},
+			};
 		log_debug!("add_dir_ent: Slot found: blk {blk} ofs {ofs}");
+		let d_type = if self.inode.fs.has_feature_incompat(::ondisk::FEAT_INCOMPAT_FILETYPE) { d_type } else { 0 };
 		// 2. Fill said slot
 		let vol_blk = try!( self.inode.lock_read().blocks_from(blk as u32).next_or_err() );
 		self.inode.fs.edit_block(vol_blk, |blk_data| {
-			// SAFE: Alignment checked, range valid
-			let blk_data: &mut [u32] = unsafe {
-				assert!(&blk_data[0] as *const _ as usize % 4 == 0);
-				::core::slice::from_raw_parts_mut(blk_data.as_mut_ptr() as *mut u32, blk_data.len() / 4)
-				};
-			match ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..])
+			let (cur_inode, rec_len, cur_name_len) = read_dirent_header(&blk_data[ofs..]);
+			if cur_inode != 0 {
+				// The slot is the unused tail of an existing entry, so split it off
+				let used = dirent_len(cur_name_len);
+				blk_data[ofs+4..][..2].copy_from_slice( &(used as u16).to_le_bytes() );
+				write_dirent(&mut blk_data[ofs + used..], inode, rec_len - used, name.as_bytes(), d_type);
+			}
+			else {
+				write_dirent(&mut blk_data[ofs..], inode, rec_len, name.as_bytes(), d_type);
+			}
+			Ok( () )
+			})?;
+		// 3. An index that couldn't be used is now stale, so demote the directory to a linear one
+		if index.is_none() {
+			let i_flags = self.inode.lock_read().i_flags();
+			if i_flags & ::ondisk::EXT4_INDEX_FL as u32 != 0 {
+				log_notice!("add_dir_ent: Clearing the hash index on I{}", self.inode.get_id());
+				self.inode.lock_write().set_i_flags(i_flags & !(::ondisk::EXT4_INDEX_FL as u32));
+			}
+		}
+		Ok( () )
+	}
+
+	/// Change the inode (and type) referenced by the entry at the given location
+	fn set_dir_ent_inode(&self, blk: usize, ofs: usize, inode: u32, d_type: u8) -> ::vfs::node::Result<()>
+	{
+		let d_type = if self.inode.fs.has_feature_incompat(::ondisk::FEAT_INCOMPAT_FILETYPE) { d_type } else { 0 };
+		let vol_blk = try!( self.inode.lock_read().blocks_from(blk as u32).next_or_err() );
+		self.inode.fs.edit_block(vol_blk, |blk_data| {
+			blk_data[ofs..][..4].copy_from_slice(&inode.to_le_bytes());
+			blk_data[ofs+7] = d_type;
+			Ok( () )
+			})
+	}
+
+	/// Remove the entry at the given location (merging its space into the preceding entry)
+	fn remove_dir_ent(&self, blk: usize, ofs: usize) -> ::vfs::node::Result<()>
+	{
+		let vol_blk = try!( self.inode.lock_read().blocks_from(blk as u32).next_or_err() );
+		self.inode.fs.edit_block(vol_blk, |blk_data| {
+			let mut prev = None;
+			let mut cur = 0;
+			while cur < ofs {
+				let (_, rec_len, _) = read_dirent_header(&blk_data[cur..]);
+				if rec_len == 0 {
+					return Err(::vfs::Error::InconsistentFilesystem);
+				}
+				prev = Some(cur);
+				cur += rec_len;
+			}
+			if cur != ofs {
+				return Err(::vfs::Error::InconsistentFilesystem);
+			}
+			let (_, rec_len, _) = read_dirent_header(&blk_data[ofs..]);
+			match prev
 			{
-			None => return Err(::vfs::Error::InconsistentFilesystem),
-			Some(ent) => {
-				ent.d_name_len = name.len() as u8;
-				ent.d_inode = inode as u32;
-				//ent.d_type = d_type;
+			Some(prev) => {
+				let (_, prev_len, _) = read_dirent_header(&blk_data[prev..]);
+				blk_data[prev+4..][..2].copy_from_slice( &((prev_len + rec_len) as u16).to_le_bytes() );
 				},
+			// First entry in the block, just mark it as unused
+			None => blk_data[ofs..][..4].copy_from_slice(&[0; 4]),
 			}
-			// Re-get the entry using the new name length, and store the new name in it
-			let ent = ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..]).unwrap();
-			ent.d_name.clone_from_slice( name.as_ref() );
-			// If the entry has plenty of free space (record length is more than 16 bytes longer than needed), then split it
-			// - A dirent has 8 bytes of overhead (u32 inode, u16 len, u8 namelen, u8 type)
-			// TODO: ^
 			Ok( () )
 			})
 	}
 }
 
+/// Space needed for an entry with a name of the given length
+fn dirent_len(name_len: usize) -> usize {
+	(::ondisk::DIRENT_MIN_SIZE + name_len + 3) & !3
+}
+/// Returns (inode, rec_len, name_len) from the entry at the start of `data`
+fn read_dirent_header(data: &[u8]) -> (u32, usize, usize) {
+	(
+		u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+		u16::from_le_bytes([data[4], data[5]]) as usize,
+		data[6] as usize,
+		)
+}
+fn write_dirent(data: &mut [u8], inode: u32, rec_len: usize, name: &[u8], d_type: u8) {
+	data[0..4].copy_from_slice(&inode.to_le_bytes());
+	data[4..6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+	data[6] = name.len() as u8;
+	data[7] = d_type;
+	data[8..][..name.len()].copy_from_slice(name);
+}
+/// Replace the contents of a block with the given (hash, inode, d_type, name) entries, packed to the start
+fn write_leaf(fs: &::instance::InstanceInner, vol_blk: u32, ents: &[(u32, u32, u8, Vec<u8>)]) -> ::vfs::node::Result<()> {
+	fs.edit_block(vol_blk, |blk_data| {
+		for b in blk_data.iter_mut() {
+			*b = 0;
+		}
+		let block_size = blk_data.len();
+		let mut ofs = 0;
+		for (i,e) in ents.iter().enumerate() {
+			let len = dirent_len(e.3.len());
+			// The final entry covers the rest of the block
+			let rec_len = if i == ents.len() - 1 { block_size - ofs } else { len };
+			write_dirent(&mut blk_data[ofs..], e.1, rec_len, &e.3, e.2);
+			ofs += len;
+		}
+		Ok( () )
+		})
+}
+
+/// Populate a new directory with its "." and ".." entries
+fn init_dir(inode: &::inodes::Inode, parent: u32) -> ::vfs::node::Result<()>
+{
+	let fs = &inode.fs;
+	let d_type = if fs.has_feature_incompat(::ondisk::FEAT_INCOMPAT_FILETYPE) { ::ondisk::FT_DIR } else { 0 };
+	let mut lh = inode.lock_write();
+	lh.ensure_blocks_allocated(0, 1)?;
+	let vol_blk = lh.get_block_addr(0)?;
+	fs.edit_block(vol_blk, |blk_data| {
+		for b in blk_data.iter_mut() {
+			*b = 0;
+		}
+		let dot_len = dirent_len(1);
+		write_dirent(blk_data, inode.get_id() as u32, dot_len, b".", d_type);
+		write_dirent(&mut blk_data[dot_len..], parent, fs.fs_block_size - dot_len, b"..", d_type);
+		Ok( () )
+		})?;
+	lh.set_i_size(fs.fs_block_size as u64)?;
+	// One link from the parent, and one from "."
+	lh.set_i_links_count(2);
+	Ok( () )
+}
+
+/// Inode number of a directory's parent (from its ".." entry)
+fn parent_inode(inode: &::inodes::Inode) -> ::vfs::node::Result<u32>
+{
+	let vol_blk = try!(inode.lock_read().blocks().next_or_err());
+	let blk_data = try!(inode.fs.get_block(vol_blk));
+	for ent in DirEnts(&blk_data)
+	{
+		if ent.d_rec_len == 0 {
+			break;
+		}
+		if ent.d_inode != 0 && &ent.d_name == b".." {
+			return Ok(ent.d_inode);
+		}
+	}
+	Err( ::vfs::Error::InconsistentFilesystem )
+}
+/// Point a directory's ".." entry at a new parent
+fn set_parent_inode(inode: &::inodes::Inode, parent: u32) -> ::vfs::node::Result<()>
+{
+	let vol_blk = try!(inode.lock_read().blocks().next_or_err());
+	inode.fs.edit_block(vol_blk, |blk_data| {
+		// ".." follows "." at the start of the first block (see `init_dir`)
+		let (_, dot_len, _) = read_dirent_header(blk_data);
+		let (_, _, name_len) = read_dirent_header(&blk_data[dot_len..]);
+		if dot_len == 0 || name_len != 2 || &blk_data[dot_len+8..][..2] != b".." {
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		blk_data[dot_len..][..4].copy_from_slice(&parent.to_le_bytes());
+		Ok( () )
+		})
+}
+
+/// Check if a directory only contains "." and ".."
+fn is_empty(inode: &::inodes::Inode) -> ::vfs::node::Result<bool>
+{
+	let lh = inode.lock_read();
+	for vol_blk in lh.blocks()
+	{
+		let blk_data = try!(inode.fs.get_block(vol_blk));
+		for ent in DirEnts(&blk_data)
+		{
+			if ent.d_rec_len == 0 {
+				return Err( ::vfs::Error::InconsistentFilesystem );
+			}
+			if ent.d_inode != 0 && &ent.d_name != b"." && &ent.d_name != b".." {
+				return Ok(false);
+			}
+		}
+	}
+	Ok(true)
+}
+
 impl ::vfs::node::NodeBase for Dir
 {
 	fn get_id(&self) -> ::vfs::node::InodeId {
@@ -216,6 +593,11 @@ impl ::vfs::node::NodeBase for Dir
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
+	}	fn get_metadata(&self) -> ::vfs::node::Result<::vfs::node::Metadata> {
+		Ok( self.inode.get_metadata() )
+	}
+	fn set_metadata(&self, changes: &::vfs::node::MetadataUpdate) -> ::vfs::node::Result<()> {
+		self.inode.set_metadata(changes)
 	}
 }
 impl ::vfs::node::Dir for Dir
@@ -305,19 +687,34 @@ impl ::vfs::node::Dir for Dir
 		{
 			Err( ::vfs::Error::ReadOnlyFilesystem )
 		}
+		else if name.len() == 0
+		{
+			Err( ::vfs::Error::InvalidParameter )
+		}
 		else
 		{
+			let is_dir = match nodetype { ::vfs::node::NodeType::Dir => true, _ => false };
+			let _transaction = try!(self.inode.fs.start_transaction());
 			let ino_id = try!( self.inode.fs.allocate_inode(self.inode.get_id() as u32, nodetype) );
 
-			match self.add_dir_ent(name, ino_id)
+			let rv = if is_dir {
+					// Populate the directory before it's visible, and account for its ".." link back here
+					self.inode.fs.with_inode(ino_id, |ino| init_dir(ino, self.inode.get_id() as u32))
+						.and_then(|_| self.inode.inc_link_count())
+						.and_then(|_| self.add_dir_ent(name, ino_id, ::ondisk::FT_DIR).map_err(|e| { self.inode.dec_link_count(); e }))
+				}
+				else {
+					self.add_dir_ent(name, ino_id, ::ondisk::FT_REG_FILE)
+				};
+			match rv
 			{
 			Ok(()) => {
 				log_debug!("create: {:?} = Inode{}", name, ino_id);
 				Ok(ino_id as ::vfs::node::InodeId)
 				},
 			Err(e) => {
-				// Call with_inode to force the inode to be deallocated
-				let _ = self.inode.fs.with_inode(ino_id, |_| Ok(()));
+				// Drop the new inode's link, so it's released once the node is dropped
+				let _ = self.inode.fs.with_inode(ino_id, |ino| { ino.unlink_all(); Ok(()) });
 				Err(e)
 				},
 			}
@@ -338,16 +735,23 @@ impl ::vfs::node::Dir for Dir
 		}
 		else
 		{
-			// TODO: How can I be sure that the passed inode number is valid? (or that it stays valid)
-			let inode = node.get_id();
-			try!(self.add_dir_ent(name, inode as u32));
-			// 3. Update inode's link count
-			try!(self.inode.fs.with_inode(inode as u32, |ino| {
-				ino.inc_link_count();
-				Ok( () )
-				}));
-			
-			todo!("link(name={:?}, inode={:?})", name, inode);
+			// NOTE: The VFS only passes nodes from the same mount, and holds a reference so the inode stays valid
+			// - Directories can't be hard linked (it would form loops)
+			let file = match node.get_any().downcast_ref::<::file::File>()
+				{
+				Some(v) => v,
+				None if node.get_any().is::<Dir>() => return Err(::vfs::Error::PermissionDenied),
+				None => return Err(::vfs::Error::InvalidParameter),
+				};
+			let inode = file.inode().get_id();
+			let _transaction = try!(self.inode.fs.start_transaction());
+			// Update inode's link count first, so a failure doesn't leave a dangling entry
+			try!(file.inode().inc_link_count());
+			if let Err(e) = self.add_dir_ent(name, inode as u32, ::ondisk::FT_REG_FILE) {
+				file.inode().dec_link_count();
+				return Err(e);
+			}
+			Ok( () )
 		}
 	}
 	fn unlink(&self, name: &ByteStr) -> ::vfs::node::Result<()> {
@@ -355,39 +759,138 @@ impl ::vfs::node::Dir for Dir
 		{
 			Err( ::vfs::Error::ReadOnlyFilesystem )
 		}
-		else if name == ""
+		else if name == "" || name == "." || name == ".."
 		{
 			Err( ::vfs::Error::InvalidParameter )
 		}
 		else
 		{
-			let (blk, ofs, _) = try!(self.find_name(name));
+			let _transaction = try!(self.inode.fs.start_transaction());
+			let _lh = self.inode.lock_dir();
+			let (blk, ofs, ino_id) = try!(self.find_name(name));
+
+			let is_dir = try!(self.inode.fs.with_inode(ino_id as u32, |ino| {
+				let is_dir = ino.lock_read().i_mode_fmt() == ::ondisk::S_IFDIR;
+				if is_dir && !try!(is_empty(ino)) {
+					return Err(::vfs::Error::DirectoryNotEmpty);
+				}
+				try!(self.remove_dir_ent(blk, ofs));
+				// Decrement inode's reference count (a directory loses its "." link too)
+				if is_dir {
+					ino.unlink_all();
+				}
+				else {
+					ino.dec_link_count();
+				}
+				Ok(is_dir)
+				}));
+			if is_dir {
+				// Removed directory's ".." entry
+				self.inode.dec_link_count();
+			}
+			Ok( () )
+		}
+	}
+	fn rename(&self, old_name: &ByteStr, new_dir: &dyn (::vfs::node::NodeBase), new_name: &ByteStr) -> ::vfs::node::Result<()> {
+		if self.inode.fs.is_readonly() {
+			return Err( ::vfs::Error::ReadOnlyFilesystem );
+		}
+		if old_name == "" || old_name == "." || old_name == ".." || new_name == "" || new_name == "." || new_name == ".." {
+			return Err( ::vfs::Error::InvalidParameter );
+		}
+		// NOTE: The VFS only passes nodes from the same mount
+		let new_dir = match new_dir.get_any().downcast_ref::<Dir>()
+			{
+			Some(v) => v,
+			None => return Err(::vfs::Error::InvalidParameter),
+			};
+		let same_dir = new_dir.inode.get_id() == self.inode.get_id();
+		if same_dir && old_name == new_name {
+			return Ok( () );
+		}
 
-			let inode = self.inode.lock_read();
-			let vol_blk = try!( inode.blocks_from(blk as u32).next_or_err() );
+		let _transaction = self.inode.fs.start_transaction()?;
+		let _lh_rename = self.inode.fs.rename_lock.lock();
 
-			self.inode.fs.edit_block(vol_blk, |blk_data| {
-				// SAFE: Alignment checked, range valid
-				let blk_data: &mut [u32] = unsafe {
-					assert!(&blk_data[0] as *const _ as usize % 4 == 0);
-					::core::slice::from_raw_parts_mut(blk_data.as_mut_ptr() as *mut u32, blk_data.len() / 4)
-					};
-				match ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..])
-				{
-				None => return Err(::vfs::Error::InconsistentFilesystem),
-				Some(ent) => {
-					// Clear name length
-					ent.d_name_len = 0;
-					// Decrement inode's reference count
-					try!(self.inode.fs.with_inode(ent.d_inode, |ino| {
-						ino.dec_link_count();
-						Ok( () )
-						}));
-					Ok( () )
-					},
+		let ino_id = {
+			let _lh = self.inode.lock_dir();
+			self.find_name(old_name)?.2 as u32
+			};
+		let i_mode_fmt = self.inode.fs.with_inode(ino_id, |ino| Ok(ino.lock_read().i_mode_fmt()))?;
+		let is_dir = i_mode_fmt == ::ondisk::S_IFDIR;
+		let d_type = match i_mode_fmt
+			{
+			::ondisk::S_IFDIR => ::ondisk::FT_DIR,
+			::ondisk::S_IFLNK => ::ondisk::FT_SYMLINK,
+			_ => ::ondisk::FT_REG_FILE,
+			};
+		if is_dir && !same_dir {
+			// A directory can't be moved into itself (check every parent of the destination, stopping at the root)
+			let mut cur = new_dir.inode.get_id() as u32;
+			loop
+			{
+				if cur == ino_id {
+					return Err(::vfs::Error::InvalidParameter);
+				}
+				let parent = self.inode.fs.with_inode(cur, |ino| parent_inode(ino))?;
+				if parent == cur {
+					break;
+				}
+				cur = parent;
+			}
+		}
+
+		// Replace the destination if it exists (pointing the entry at the source, so the name is never missing)
+		let replaced_dir = {
+			let _lh = new_dir.inode.lock_dir();
+			match new_dir.find_name(new_name)
+			{
+			Ok((_, _, dst_id)) if dst_id as u32 == ino_id => return Ok( () ),
+			Ok((blk, ofs, dst_id)) => Some( self.inode.fs.with_inode(dst_id as u32, |dst| {
+				let dst_is_dir = dst.lock_read().i_mode_fmt() == ::ondisk::S_IFDIR;
+				if dst_is_dir != is_dir {
+					return Err(::vfs::Error::TypeMismatch);
+				}
+				if dst_is_dir && !is_empty(dst)? {
+					return Err(::vfs::Error::DirectoryNotEmpty);
 				}
-				})
+				new_dir.set_dir_ent_inode(blk, ofs, ino_id, d_type)?;
+				if dst_is_dir {
+					dst.unlink_all();
+				}
+				else {
+					dst.dec_link_count();
+				}
+				Ok(dst_is_dir)
+				})? ),
+			Err(::vfs::Error::NotFound) => None,
+			Err(e) => return Err(e),
+			}
+			};
+		match replaced_dir
+		{
+		// Replaced directory's ".." entry
+		Some(true) => new_dir.inode.dec_link_count(),
+		Some(false) => {},
+		None => new_dir.add_dir_ent(new_name, ino_id, d_type)?,
+		}
+
+		// Remove the old name (located again, as adding the new entry can move entries in an indexed directory)
+		{
+			let _lh = self.inode.lock_dir();
+			let (blk, ofs, id) = self.find_name(old_name)?;
+			if id as u32 != ino_id {
+				return Err(::vfs::Error::InconsistentFilesystem);
+			}
+			self.remove_dir_ent(blk, ofs)?;
+		}
+
+		if is_dir && !same_dir {
+			self.inode.fs.with_inode(ino_id, |ino| set_parent_inode(ino, new_dir.inode.get_id() as u32))?;
+			new_dir.inode.inc_link_count()?;
+			self.inode.dec_link_count();
 		}
+		Ok( () )
 	}
 }
 
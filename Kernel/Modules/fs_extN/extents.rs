@@ -0,0 +1,410 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_extN/extents.rs
+//! ext4 extent trees (FEAT_INCOMPAT_EXTENTS)
+//!
+//! Nodes are a 12-byte header followed by 12-byte entries, the root node lives in the inode's `i_block` and
+//! the rest are whole filesystem blocks. Interior nodes hold (first block, child) pairs, leaves hold extents.
+use kernel::prelude::*;
+use instance::InstanceInner;
+
+/// Longest initialised extent (longer encoded lengths mark uninitialised extents)
+const MAX_LEN: u32 = 32768;
+/// Sanity limit on the tree depth
+const MAX_DEPTH: u16 = 5;
+
+struct Extent
+{
+	block: u32,
+	len: u32,
+	start: u32,
+	uninit: bool,
+}
+
+enum Mapping
+{
+	/// Mapped to the given physical block, for `count` blocks
+	Mapped(u32, u32),
+	/// Allocated but uninitialised (reads as zero)
+	Uninit(u32),
+	/// Not allocated
+	Hole(u32),
+}
+
+/// Node header accessors (on native u32 words, matching how the rest of the driver views blocks)
+fn entries(n: &[u32]) -> usize {
+	(n[0] >> 16) as usize
+}
+fn set_entries(n: &mut [u32], v: usize) {
+	n[0] = (n[0] & 0xFFFF) | (v as u32) << 16;
+}
+fn max_entries(n: &[u32]) -> usize {
+	(n[1] & 0xFFFF) as usize
+}
+fn depth(n: &[u32]) -> u16 {
+	(n[1] >> 16) as u16
+}
+fn set_header(n: &mut [u32], count: usize, max: usize, depth: u16) {
+	n[0] = ::ondisk::EXT4_EXTENT_MAGIC as u32 | (count as u32) << 16;
+	n[1] = max as u32 | (depth as u32) << 16;
+	n[2] = 0;
+}
+fn key(n: &[u32], i: usize) -> u32 {
+	n[3 + 3*i]
+}
+fn entry_mut(n: &mut [u32], i: usize) -> &mut [u32] {
+	&mut n[3 + 3*i..][..3]
+}
+
+fn check_node(n: &[u32]) -> ::vfs::node::Result<()> {
+	if n[0] as u16 != ::ondisk::EXT4_EXTENT_MAGIC || entries(n) > max_entries(n) || 3 + 3 * max_entries(n) > n.len() || depth(n) > MAX_DEPTH {
+		log_error!("Malformed extent node header {:#x} {:#x}", n[0], n[1]);
+		return Err(::vfs::Error::InconsistentFilesystem);
+	}
+	Ok( () )
+}
+fn get_extent(n: &[u32], i: usize) -> ::vfs::node::Result<Extent> {
+	let w = &n[3 + 3*i..][..3];
+	if w[1] >> 16 != 0 {
+		return Err(::vfs::Error::Unknown("extN: 48-bit block numbers unsupported"));
+	}
+	let raw_len = w[1] & 0xFFFF;
+	Ok(Extent {
+		block: w[0],
+		len: if raw_len > MAX_LEN { raw_len - MAX_LEN } else { raw_len },
+		start: w[2],
+		uninit: raw_len > MAX_LEN,
+		})
+}
+fn set_extent(n: &mut [u32], i: usize, e: &Extent) {
+	let w = entry_mut(n, i);
+	w[0] = e.block;
+	w[1] = if e.uninit { e.len + MAX_LEN } else { e.len };
+	w[2] = e.start;
+}
+fn get_child(n: &[u32], i: usize) -> ::vfs::node::Result<u32> {
+	let w = &n[3 + 3*i..][..3];
+	if w[2] & 0xFFFF != 0 {
+		return Err(::vfs::Error::Unknown("extN: 48-bit block numbers unsupported"));
+	}
+	Ok(w[1])
+}
+fn set_child(n: &mut [u32], i: usize, key: u32, child: u32) {
+	let w = entry_mut(n, i);
+	w[0] = key;
+	w[1] = child;
+	w[2] = 0;
+}
+/// Open a gap for a new entry at `pos`
+fn insert_gap(n: &mut [u32], pos: usize) {
+	let count = entries(n);
+	n.copy_within(3 + 3*pos .. 3 + 3*count, 3 + 3*(pos+1));
+	set_entries(n, count + 1);
+}
+
+/// Initialise an empty tree in an inode's `i_block`
+pub fn init_root(root: &mut [u32]) {
+	for v in root.iter_mut() {
+		*v = 0;
+	}
+	let max = (root.len() - 3) / 3;
+	set_header(root, 0, max, 0);
+}
+
+/// A node on the path from the root to a leaf
+struct Level
+{
+	/// Tree block holding this node (0 for the root)
+	block: u32,
+	data: Vec<u32>,
+	/// Index of the last entry starting at or before the search block
+	idx: Option<usize>,
+}
+
+fn find_path(fs: &InstanceInner, root: &[u32], block: u32) -> ::vfs::node::Result<Vec<Level>>
+{
+	let mut rv: Vec<Level> = Vec::new();
+	let mut node_block = 0;
+	let mut data = root.to_vec();
+	loop
+	{
+		check_node(&data)?;
+		if let Some(p) = rv.last() {
+			if depth(&data) + 1 != depth(&p.data) {
+				log_error!("Extent tree depth mismatch in block {}", node_block);
+				return Err(::vfs::Error::InconsistentFilesystem);
+			}
+		}
+		let count = entries(&data);
+		let idx = (0 .. count).rev().find(|&i| key(&data, i) <= block);
+		if depth(&data) == 0 {
+			rv.push(Level { block: node_block, data, idx });
+			return Ok(rv);
+		}
+		if count == 0 {
+			log_error!("Empty extent index node in block {}", node_block);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		// Blocks before the first key are still searched for in the first child
+		let idx = idx.unwrap_or(0);
+		let child = get_child(&data, idx)?;
+		rv.push(Level { block: node_block, data, idx: Some(idx) });
+		data = fs.get_block(child)?.to_vec();
+		node_block = child;
+	}
+}
+
+/// Write a (modified) node back to its location
+fn store(fs: &InstanceInner, root: &mut [u32], block: u32, data: &[u32]) -> ::vfs::node::Result<()>
+{
+	if block == 0 {
+		let len = root.len();
+		root.copy_from_slice(&data[..len]);
+		Ok( () )
+	}
+	else {
+		fs.edit_block_u32(block, |d| { d.copy_from_slice(data); Ok( () ) })
+	}
+}
+
+fn find(fs: &InstanceInner, root: &[u32], block: u32, max: u32) -> ::vfs::node::Result<Mapping>
+{
+	let path = find_path(fs, root, block)?;
+	let leaf = path.last().unwrap();
+	if let Some(i) = leaf.idx {
+		let e = get_extent(&leaf.data, i)?;
+		if block < e.block + e.len {
+			let ofs = block - e.block;
+			let count = u32::min(e.len - ofs, max);
+			return Ok(if e.uninit { Mapping::Uninit(count) } else { Mapping::Mapped(e.start + ofs, count) });
+		}
+	}
+	// A hole, which ends at the next key at any level
+	let mut limit = block as u64 + max as u64;
+	for l in &path {
+		let next = l.idx.map(|i| i + 1).unwrap_or(0);
+		if next < entries(&l.data) {
+			limit = u64::min(limit, key(&l.data, next) as u64);
+		}
+	}
+	Ok(Mapping::Hole( (limit - block as u64) as u32 ))
+}
+
+/// Look up the physical location of `block`, returning the start and length of the contiguous run
+///
+/// Holes (and uninitialised extents) are returned as physical block zero.
+pub fn lookup(fs: &InstanceInner, root: &[u32], block: u32, max: u32) -> ::vfs::node::Result<(u32, u32)>
+{
+	Ok(match find(fs, root, block, max)?
+		{
+		Mapping::Mapped(b, n) => (b, n),
+		Mapping::Uninit(n) => (0, n),
+		Mapping::Hole(n) => (0, n),
+		})
+}
+
+/// Allocate blocks for all holes in the range, zeroing any new blocks below `zero_below`
+///
+/// `n_alloc` is incremented for every block allocated (including tree blocks)
+pub fn ensure_allocated(fs: &InstanceInner, inode_num: u32, root: &mut [u32], mut block: u32, count: u32, zero_below: u32, n_alloc: &mut u32) -> ::vfs::node::Result<()>
+{
+	let end = block + count;
+	let mut prev = if block > 0 { lookup(fs, root, block - 1, 1)?.0 } else { 0 };
+	while block < end
+	{
+		match find(fs, root, block, end - block)?
+		{
+		Mapping::Mapped(b, n) => {
+			prev = b + n - 1;
+			block += n;
+			},
+		Mapping::Uninit(_) => initialise(fs, root, block)?,
+		Mapping::Hole(n) => {
+			for _ in 0 .. n {
+				let new = fs.allocate_data_block(inode_num, prev)?;
+				*n_alloc += 1;
+				if block < zero_below {
+					fs.zero_blocks(new, 1)?;
+				}
+				insert(fs, inode_num, root, Extent { block, len: 1, start: new, uninit: false }, n_alloc)?;
+				prev = new;
+				block += 1;
+			}
+			},
+		}
+	}
+	Ok( () )
+}
+
+/// Convert the uninitialised extent containing `block` into a normal (zeroed) extent
+fn initialise(fs: &InstanceInner, root: &mut [u32], block: u32) -> ::vfs::node::Result<()>
+{
+	let mut path = find_path(fs, root, block)?;
+	let leaf = path.last_mut().unwrap();
+	let i = leaf.idx.expect("extents::initialise - no extent");
+	let mut e = get_extent(&leaf.data, i)?;
+	fs.zero_blocks(e.start, e.len)?;
+	e.uninit = false;
+	set_extent(&mut leaf.data, i, &e);
+	store(fs, root, leaf.block, &leaf.data)
+}
+
+/// Insert a mapping (which must not overlap an existing one)
+fn insert(fs: &InstanceInner, inode_num: u32, root: &mut [u32], e: Extent, n_alloc: &mut u32) -> ::vfs::node::Result<()>
+{
+	loop
+	{
+		let mut path = find_path(fs, root, e.block)?;
+		let leaf_level = path.len() - 1;
+		{
+			let leaf = &mut path[leaf_level];
+			// Extend the preceding extent if this directly follows it
+			if let Some(i) = leaf.idx {
+				let mut p = get_extent(&leaf.data, i)?;
+				if !p.uninit && p.block + p.len == e.block && p.start + p.len == e.start && p.len + e.len <= MAX_LEN {
+					p.len += e.len;
+					set_extent(&mut leaf.data, i, &p);
+					return store(fs, root, leaf.block, &leaf.data);
+				}
+			}
+			// Add a new entry if there's space
+			if entries(&leaf.data) < max_entries(&leaf.data) {
+				let pos = leaf.idx.map(|i| i + 1).unwrap_or(0);
+				insert_gap(&mut leaf.data, pos);
+				set_extent(&mut leaf.data, pos, &e);
+				store(fs, root, leaf.block, &leaf.data)?;
+				if pos == 0 {
+					// New first entry, so the keys leading to this leaf might need lowering
+					for l in (0 .. leaf_level).rev() {
+						let i = path[l].idx.unwrap();
+						if key(&path[l].data, i) > e.block {
+							entry_mut(&mut path[l].data, i)[0] = e.block;
+							store(fs, root, path[l].block, &path[l].data)?;
+						}
+						if i != 0 {
+							break;
+						}
+					}
+				}
+				return Ok( () );
+			}
+		}
+		// The leaf is full: split the lowest full node below one with space, or add a level at the root
+		match (0 .. leaf_level).rev().find(|&l| entries(&path[l].data) < max_entries(&path[l].data))
+		{
+		Some(l) => split(fs, inode_num, root, &mut path, l + 1, n_alloc)?,
+		None => grow(fs, inode_num, root, n_alloc)?,
+		}
+	}
+}
+
+/// Move the upper half of a full (non-root) node into a new block, and add it to the parent
+fn split(fs: &InstanceInner, inode_num: u32, root: &mut [u32], path: &mut [Level], level: usize, n_alloc: &mut u32) -> ::vfs::node::Result<()>
+{
+	let (parents, rest) = path.split_at_mut(level);
+	let parent = parents.last_mut().unwrap();
+	let node = &mut rest[0];
+
+	let new_block = fs.allocate_data_block(inode_num, node.block)?;
+	*n_alloc += 1;
+	let count = entries(&node.data);
+	let mid = count / 2;
+	let mut new_data = vec![0u32; fs.fs_block_size / 4];
+	set_header(&mut new_data, count - mid, (fs.fs_block_size - 12) / 12, depth(&node.data));
+	new_data[3 ..][.. 3*(count - mid)].copy_from_slice(&node.data[3 + 3*mid ..][.. 3*(count - mid)]);
+	set_entries(&mut node.data, mid);
+	log_debug!("Extent split: B{} -> B{} ({} entries)", node.block, new_block, count - mid);
+
+	store(fs, root, new_block, &new_data)?;
+	store(fs, root, node.block, &node.data)?;
+	let pos = parent.idx.unwrap() + 1;
+	insert_gap(&mut parent.data, pos);
+	set_child(&mut parent.data, pos, key(&new_data, 0), new_block);
+	store(fs, root, parent.block, &parent.data)
+}
+
+/// Move the root's contents into a new block, increasing the depth of the tree
+fn grow(fs: &InstanceInner, inode_num: u32, root: &mut [u32], n_alloc: &mut u32) -> ::vfs::node::Result<()>
+{
+	let new_block = fs.allocate_data_block(inode_num, 0)?;
+	*n_alloc += 1;
+	let count = entries(root);
+	let mut new_data = vec![0u32; fs.fs_block_size / 4];
+	new_data[.. 3 + 3*count].copy_from_slice(&root[.. 3 + 3*count]);
+	set_header(&mut new_data, count, (fs.fs_block_size - 12) / 12, depth(root));
+	store(fs, root, new_block, &new_data)?;
+
+	let first_key = if count > 0 { key(root, 0) } else { 0 };
+	let (max, d) = (max_entries(root), depth(root));
+	set_header(root, 1, max, d + 1);
+	set_child(root, 0, first_key, new_block);
+	log_debug!("Extent tree grown to depth {} (B{})", d + 1, new_block);
+	Ok( () )
+}
+
+/// Release all blocks at or after `keep`, incrementing `n_freed` for each block (including tree blocks)
+pub fn truncate(fs: &InstanceInner, root: &mut [u32], keep: u32, n_freed: &mut u32) -> ::vfs::node::Result<()>
+{
+	let mut data = root.to_vec();
+	truncate_node(fs, &mut data, keep, n_freed)?;
+	if entries(&data) == 0 {
+		// An empty tree is just an empty leaf
+		let max = max_entries(&data);
+		set_header(&mut data, 0, max, 0);
+	}
+	root.copy_from_slice(&data);
+	Ok( () )
+}
+
+fn truncate_node(fs: &InstanceInner, data: &mut [u32], keep: u32, n_freed: &mut u32) -> ::vfs::node::Result<()>
+{
+	check_node(data)?;
+	let count = entries(data);
+	let mut new_count = count;
+	if depth(data) == 0
+	{
+		for i in (0 .. count).rev()
+		{
+			let mut e = get_extent(data, i)?;
+			if e.block >= keep {
+				fs.free_blocks(e.start, e.len)?;
+				*n_freed += e.len;
+				new_count = i;
+			}
+			else {
+				if e.block + e.len > keep {
+					let n_keep = keep - e.block;
+					fs.free_blocks(e.start + n_keep, e.len - n_keep)?;
+					*n_freed += e.len - n_keep;
+					e.len = n_keep;
+					set_extent(data, i, &e);
+				}
+				break;
+			}
+		}
+	}
+	else
+	{
+		for i in (0 .. count).rev()
+		{
+			let child = get_child(data, i)?;
+			let mut child_data = fs.get_block(child)?.to_vec();
+			truncate_node(fs, &mut child_data, keep, n_freed)?;
+			if entries(&child_data) == 0 {
+				fs.free_data_block(child)?;
+				*n_freed += 1;
+				new_count = i;
+			}
+			else {
+				fs.edit_block_u32(child, |d| { d.copy_from_slice(&child_data); Ok( () ) })?;
+			}
+			// Earlier children end before this one starts
+			if key(data, i) < keep {
+				break;
+			}
+		}
+	}
+	set_entries(data, new_count);
+	Ok( () )
+}
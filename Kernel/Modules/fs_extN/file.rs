@@ -17,6 +17,11 @@ impl File
 			inode: inode,
 			}
 	}
+
+	pub fn inode(&self) -> &::inodes::Inode
+	{
+		&self.inode
+	}
 }
 
 impl vfs::node::NodeBase for File
@@ -26,6 +31,11 @@ impl vfs::node::NodeBase for File
 	}
 	fn get_any(&self) -> &dyn (::core::any::Any) {
 		self
+	}	fn get_metadata(&self) -> vfs::node::Result<vfs::node::Metadata> {
+		Ok( self.inode.get_metadata() )
+	}
+	fn set_metadata(&self, changes: &vfs::node::MetadataUpdate) -> vfs::node::Result<()> {
+		self.inode.set_metadata(changes)
 	}
 }
 impl vfs::node::File for File
@@ -54,6 +64,12 @@ impl vfs::node::File for File
 		iter_blocks_range(&inode, ofs, buf.len(), &mut |block_range, data_range| {
 			match block_range
 			{
+			// Sparse (unallocated) blocks read as zero
+			BlockRef::Sub(0, _) | BlockRef::Range(0, _) => {
+				for b in &mut buf[data_range] {
+					*b = 0;
+				}
+				},
 			BlockRef::Sub(blkid, sub_range) => {
 				let blk_data = try!(self.inode.fs.get_block_uncached(blkid));
 				buf[data_range].copy_from_slice(&blk_data[sub_range]);
@@ -67,30 +83,30 @@ impl vfs::node::File for File
 	}
 
 	fn truncate(&self, new_size: u64) -> vfs::node::Result<u64> {
+		if self.inode.fs.is_readonly() {
+			return Err( vfs::Error::ReadOnlyFilesystem );
+		}
+		let fs_block_size = self.inode.fs.fs_block_size as u64;
+		let _transaction = self.inode.fs.start_transaction()?;
 		let mut inode = self.inode.lock_write();
 		let old_size = inode.i_size();
-		if new_size == 0
+		if new_size < old_size
 		{
-			todo!("truncate - 0");
-		}
-		else if new_size == old_size
-		{
-			Ok( new_size )
+			let keep_blocks = ::kernel::lib::num::div_up(new_size, fs_block_size);
+			// Zero the rest of the final block, so a later extension reads as zero
+			let tail_end = u64::min(old_size, keep_blocks * fs_block_size);
+			zero_range(&inode, new_size, tail_end)?;
+			inode.truncate_blocks(keep_blocks as u32)?;
+			inode.set_i_size(new_size)?;
 		}
-		else if new_size < old_size
+		else if new_size > old_size
 		{
-			todo!("truncate - shrink");
-		}
-		else {
-			ensure_blocks_present(&self.inode.fs, &mut inode, new_size)?;
+			// Zero the remainder of the current final block, the rest is left sparse
+			let tail_end = u64::min(new_size, ::kernel::lib::num::div_up(old_size, fs_block_size) * fs_block_size);
+			zero_range(&inode, old_size, tail_end)?;
 			inode.set_i_size(new_size)?;
-			// TODO: Risky cast? If truncating to a very large size
-			iter_blocks_range(&inode, old_size, (new_size - old_size) as usize, &mut |_block_range, _data_range| {
-				// TODO: Zero the blocks?
-				Ok( () )
-				})?;
-			Ok( new_size )
 		}
+		Ok( new_size )
 	}
 	fn clear(&self, ofs: u64, size: u64) -> vfs::node::Result<()> {
 		let inode = self.inode.lock_read();
@@ -103,49 +119,73 @@ impl vfs::node::File for File
 			Err( vfs::Error::InvalidParameter )
 		}
 		else {
-			// 1. Leading partial
-			// 2. Inner
-			// 3. Trailing partial
-			todo!("clear");
+			let _transaction = self.inode.fs.start_transaction()?;
+			zero_range(&inode, ofs, ofs + size)
 		}
 	}
 	fn write(&self, ofs: u64, buf: &[u8]) -> vfs::Result<usize> {
-		let inode = self.inode.lock_read();
+		if self.inode.fs.is_readonly() {
+			return Err( vfs::Error::ReadOnlyFilesystem );
+		}
+		// NOTE: In this function, we're free to read-modify-write blocks without fear, as the VFS itself handles
+		//       the file "borrow checking". A file race is the userland's problem (if a SharedRW handle is used)
+		let fs_block_size = self.inode.fs.fs_block_size as u64;
+		let _transaction = self.inode.fs.start_transaction()?;
+		let mut inode = self.inode.lock_write();
 		let size = inode.i_size();
-		if self.inode.fs.is_readonly()
-		{
-			Err( vfs::Error::ReadOnlyFilesystem )
+		if ofs > size {
+			return Err( vfs::Error::InvalidParameter );
 		}
-		else if ofs == size
-		{
-			drop(inode);
-			let mut inode = self.inode.lock_write();
-			if ofs == inode.i_size() {
-			}
-			else {
-				// Race! Should this be possible? (shouldn't the vfs layer protect that?)
-			}
-			let new_size = ofs + buf.len() as u64;
-			// Ensure that there are blocks allocated
-			ensure_blocks_present(&self.inode.fs, &mut inode, new_size)?;
-			// Extend the size
-			inode.set_i_size(new_size)?;
-			// Write data
-			let rv = write_inner(&inode, ofs, buf)?;
-			inode.set_i_size(ofs + rv as u64)?;
-			Ok(rv)
+		if buf.len() == 0 {
+			return Ok(0);
 		}
-		else if ofs > size || buf.len() as u64 > size || ofs + buf.len() as u64 > size {
-			Err( vfs::Error::InvalidParameter )
+		let end = ofs + buf.len() as u64;
+		let first_block = ofs / fs_block_size;
+		let end_block = ::kernel::lib::num::div_up(end, fs_block_size);
+		if end_block > u32::MAX as u64 {
+			return Err( vfs::Error::InvalidParameter );
 		}
-		else {
-			// NOTE: In this section, we're free to read-modify-write blocks without fear, as the VFS itself handles
-			//       the file "borrow checking". A file race is the userland's problem (if a SharedRW handle is used)
-			write_inner(&inode, ofs, buf)
+		// Ensure that there are blocks allocated (filling holes, and extending the file)
+		if let Err(e) = inode.ensure_blocks_allocated(first_block as u32, (end_block - first_block) as u32) {
+			// Release anything allocated past the current end
+			let _ = inode.truncate_blocks(::kernel::lib::num::div_up(size, fs_block_size) as u32);
+			return Err(e);
+		}
+		// Extend the size
+		if end > size {
+			inode.set_i_size(end)?;
 		}
+		// Write data
+		write_inner(&inode, ofs, buf)
 	}
 }
 
+/// Zero a byte range (within the file), skipping sparse blocks
+fn zero_range(inode: &dyn super::inodes::InodeHandleTrait, start: u64, end: u64) -> vfs::Result<()> {
+	if start >= end {
+		return Ok( () );
+	}
+	iter_blocks_range(inode, start, (end - start) as usize, &mut |block_range, _data_range| {
+		match block_range
+		{
+		BlockRef::Sub(0, _) | BlockRef::Range(0, _) => {},
+		BlockRef::Sub(blkid, sub_range) => {
+			inode.fs().edit_block(blkid, |data| {
+				for b in &mut data[sub_range] {
+					*b = 0;
+				}
+				Ok( () )
+				})?;
+			},
+		BlockRef::Range(blkid, count) => {
+			inode.fs().zero_blocks(blkid, count)?;
+			},
+		}
+		Ok( () )
+		})?;
+	Ok( () )
+}
+
 fn write_inner(inode: &dyn super::inodes::InodeHandleTrait, ofs: u64, buf: &[u8]) -> vfs::Result<usize> {
 	iter_blocks_range(inode, ofs, buf.len(), &mut |block_range, data_range| {
 		match block_range
@@ -200,15 +240,8 @@ fn iter_blocks_range(inode: &dyn super::inodes::InodeHandleTrait, ofs: u64, len:
 	{
 		let b = blocks.next_or_err()?;
 		log_trace!("iter_blocks_range: Suffix B{} 0+{}", b, trailing_bytes);
-		cb( BlockRef::Sub(b, 0..trailing_bytes), written..written+len )?;
+		cb( BlockRef::Sub(b, 0..trailing_bytes), written..written+trailing_bytes )?;
 		written += trailing_bytes;
 	}
 	Ok( written )
 }
-
-fn ensure_blocks_present(fs: &super::instance::InstanceInner, lh: &mut super::inodes::InodeHandleWrite, size: u64) -> vfs::Result<()> {
-	let nblocks = ::kernel::lib::num::div_up(size, fs.fs_block_size as u64);
-
-	lh.ensure_blocks_allocated(0, nblocks as u32)
-}
-
@@ -0,0 +1,372 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_extN/htree.rs
+//! Hashed directory indexes (HTree)
+//!
+//! The index maps name hashes to directory blocks (leaves), which are otherwise normal linear directory blocks.
+use kernel::prelude::*;
+
+/// Parameters of a directory's index (from the root block)
+pub struct Root
+{
+	/// Hash version (with `DX_HASH_UNSIGNED_OFS` applied)
+	hash_version: u8,
+	seed: [u32; 4],
+	/// Number of interior node levels between the root and the leaves
+	pub indirect_levels: u8,
+	/// Entry limit in the root block
+	root_limit: usize,
+}
+
+/// A node visited when walking from the root to a leaf
+#[derive(Copy,Clone,Debug)]
+pub struct Level
+{
+	/// Directory block containing the node
+	pub blk_index: u32,
+	/// Offset of the node's entries within the block
+	entries_ofs: usize,
+	pub count: usize,
+	pub limit: usize,
+	/// The entry that was followed
+	pub at: usize,
+}
+
+impl Root
+{
+	/// Read the index parameters, returning `None` if the index can't be used (unknown version or layout)
+	pub fn read(inode: &::inodes::Inode) -> ::vfs::node::Result<Option<Root>>
+	{
+		let fs = &inode.fs;
+		let vol_blk = inode.lock_read().get_block_addr(0)?;
+		let data = fs.get_block(vol_blk)?;
+		let info = u32::from_le(data[::ondisk::DX_ROOT_INFO_OFS / 4 + 1]);
+		let hash_version = (info & 0xFF) as u8;
+		let info_length = ((info >> 8) & 0xFF) as usize;
+		let indirect_levels = ((info >> 16) & 0xFF) as u8;
+		if data[::ondisk::DX_ROOT_INFO_OFS / 4] != 0 || info_length != ::ondisk::DX_ROOT_INFO_LEN {
+			log_warning!("I{}: Unsupported hash index (info length {})", inode.get_id(), info_length);
+			return Ok(None);
+		}
+		if hash_version > ::ondisk::DX_HASH_TEA {
+			log_warning!("I{}: Unsupported hash version {}", inode.get_id(), hash_version);
+			return Ok(None);
+		}
+		if indirect_levels > max_indirect_levels(fs) {
+			log_error!("I{}: Hash index too deep ({} levels)", inode.get_id(), indirect_levels);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		let (seed, unsigned) = fs.dir_hash_params();
+		let entries_ofs = ::ondisk::DX_ROOT_INFO_OFS + info_length;
+		Ok(Some(Root {
+			hash_version: hash_version + if unsigned { ::ondisk::DX_HASH_UNSIGNED_OFS } else { 0 },
+			seed: seed,
+			indirect_levels: indirect_levels,
+			root_limit: (fs.fs_block_size - entries_ofs) / ::ondisk::DX_ENTRY_SIZE,
+			}))
+	}
+
+	/// Major hash of a name (the low bit is clear, it's used to flag collisions in index entries)
+	pub fn hash(&self, name: &[u8]) -> u32
+	{
+		let mut buf = if self.seed == [0; 4] { [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476] } else { self.seed };
+		let is_unsigned = self.hash_version >= ::ondisk::DX_HASH_UNSIGNED_OFS;
+		let hash = match self.hash_version % ::ondisk::DX_HASH_UNSIGNED_OFS
+			{
+			::ondisk::DX_HASH_LEGACY => hash_legacy(name, is_unsigned),
+			::ondisk::DX_HASH_HALF_MD4 => {
+				for (i,chunk) in name.chunks(32).enumerate() {
+					let mut input = [0; 8];
+					str2hashbuf(chunk, name.len() - i * 32, &mut input, is_unsigned);
+					half_md4_transform(&mut buf, &input);
+				}
+				buf[1]
+				},
+			_ => {
+				for (i,chunk) in name.chunks(16).enumerate() {
+					let mut input = [0; 4];
+					str2hashbuf(chunk, name.len() - i * 16, &mut input, is_unsigned);
+					tea_transform(&mut buf, &input);
+				}
+				buf[0]
+				},
+			};
+		let hash = hash & !1;
+		// The final hash value is reserved as an end-of-directory marker for readdir cookies
+		if hash == 0xFFFF_FFFE { 0xFFFF_FFFC } else { hash }
+	}
+
+	/// Root node (with no entry selected)
+	pub fn root_level(&self, inode: &::inodes::Inode) -> ::vfs::node::Result<Level>
+	{
+		let mut rv = Level {
+			blk_index: 0,
+			entries_ofs: ::ondisk::DX_ROOT_INFO_OFS + ::ondisk::DX_ROOT_INFO_LEN,
+			count: 0,
+			limit: self.root_limit,
+			at: 0,
+			};
+		rv.load(inode)?;
+		Ok(rv)
+	}
+
+	/// Walk from the root to the leaf that would hold `hash`
+	pub fn probe(&self, inode: &::inodes::Inode, hash: u32) -> ::vfs::node::Result<Vec<Level>>
+	{
+		let mut path = Vec::with_capacity(self.indirect_levels as usize + 1);
+		let mut level = self.root_level(inode)?;
+		loop
+		{
+			let entries = level.read_entries(inode)?;
+			// Find the last entry with a hash at or below the target (the first entry covers everything below the second)
+			level.at = match entries[1..].binary_search_by(|e| if e.0 <= hash { ::core::cmp::Ordering::Less } else { ::core::cmp::Ordering::Greater })
+				{
+				Ok(_) => unreachable!(),
+				Err(i) => i,
+				};
+			let child = entries[level.at].1;
+			path.push(level);
+			if path.len() > self.indirect_levels as usize {
+				return Ok(path);
+			}
+			level = Level::node(child, inode.fs.fs_block_size);
+			level.load(inode)?;
+		}
+	}
+
+	/// Advance the path to the next leaf if it could also contain entries for `hash` (due to a collision)
+	pub fn next_leaf(&self, inode: &::inodes::Inode, path: &mut Vec<Level>, hash: u32) -> ::vfs::node::Result<bool>
+	{
+		// Find the lowest level that has a following entry
+		let mut depth = path.len();
+		loop
+		{
+			if depth == 0 {
+				return Ok(false);
+			}
+			depth -= 1;
+			if path[depth].at + 1 < path[depth].count {
+				break;
+			}
+		}
+		path[depth].at += 1;
+		let entries = path[depth].read_entries(inode)?;
+		// The next leaf only continues this hash if its first hash matches (the low bit flags a continuation)
+		if entries[path[depth].at].0 & !1 != hash {
+			return Ok(false);
+		}
+		// Descend to the start of the next subtree
+		let mut child = entries[path[depth].at].1;
+		for level in path[depth+1..].iter_mut()
+		{
+			*level = Level::node(child, inode.fs.fs_block_size);
+			level.load(inode)?;
+			child = level.read_entries(inode)?[0].1;
+		}
+		Ok(true)
+	}
+}
+
+impl Level
+{
+	/// An interior node in the given block (not yet read)
+	pub fn node(blk_index: u32, fs_block_size: usize) -> Level
+	{
+		Level {
+			blk_index: blk_index,
+			entries_ofs: ::ondisk::DX_NODE_ENTRIES_OFS,
+			count: 0,
+			limit: (fs_block_size - ::ondisk::DX_NODE_ENTRIES_OFS) / ::ondisk::DX_ENTRY_SIZE,
+			at: 0,
+			}
+	}
+
+	/// Read the node's count and limit
+	fn load(&mut self, inode: &::inodes::Inode) -> ::vfs::node::Result<()>
+	{
+		let vol_blk = inode.lock_read().get_block_addr(self.blk_index)?;
+		let data = inode.fs.get_block(vol_blk)?;
+		let countlimit = u32::from_le(data[self.entries_ofs / 4]);
+		let limit = (countlimit & 0xFFFF) as usize;
+		let count = (countlimit >> 16) as usize;
+		// NOTE: The on-disk limit can be lower than the computed one (space reserved for a checksum)
+		if count == 0 || limit > self.limit || count > limit {
+			log_error!("I{}: Bad hash index node in block {} (count={}, limit={})", inode.get_id(), self.blk_index, count, limit);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		self.count = count;
+		self.limit = limit;
+		Ok( () )
+	}
+
+	/// Read the (hash, block) pairs from this node (the first hash is always zero)
+	pub fn read_entries(&self, inode: &::inodes::Inode) -> ::vfs::node::Result<Vec<(u32, u32)>>
+	{
+		let vol_blk = inode.lock_read().get_block_addr(self.blk_index)?;
+		let data = inode.fs.get_block(vol_blk)?;
+		let words = &data[self.entries_ofs / 4 ..][.. self.count * 2];
+		Ok(words.chunks(2).enumerate().map(|(i,e)| (if i == 0 { 0 } else { u32::from_le(e[0]) }, u32::from_le(e[1]))).collect())
+	}
+
+	/// Replace the node's entries (updating the count)
+	pub fn write_entries(&mut self, inode: &::inodes::Inode, entries: &[(u32, u32)]) -> ::vfs::node::Result<()>
+	{
+		assert!(entries.len() > 0 && entries.len() <= self.limit);
+		let vol_blk = inode.lock_read().get_block_addr(self.blk_index)?;
+		let entries_ofs = self.entries_ofs;
+		let limit = self.limit;
+		inode.fs.edit_block_u32(vol_blk, |data| {
+			let words = &mut data[entries_ofs / 4 ..];
+			for (i,e) in entries.iter().enumerate() {
+				if i > 0 {
+					words[i*2] = e.0.to_le();
+				}
+				words[i*2+1] = e.1.to_le();
+			}
+			words[0] = (limit as u32 | (entries.len() as u32) << 16).to_le();
+			Ok( () )
+			})?;
+		self.count = entries.len();
+		Ok( () )
+	}
+
+	/// Block referenced by the current entry
+	pub fn target(&self, inode: &::inodes::Inode) -> ::vfs::node::Result<u32>
+	{
+		Ok( self.read_entries(inode)?[self.at].1 )
+	}
+
+	/// Insert an entry after the current one
+	pub fn insert_after_at(&mut self, inode: &::inodes::Inode, hash: u32, block: u32) -> ::vfs::node::Result<()>
+	{
+		let mut entries = self.read_entries(inode)?;
+		entries.insert(self.at + 1, (hash, block));
+		self.write_entries(inode, &entries)
+	}
+}
+
+/// Update the number of interior levels recorded in the root
+pub fn set_indirect_levels(inode: &::inodes::Inode, levels: u8) -> ::vfs::node::Result<()>
+{
+	let vol_blk = inode.lock_read().get_block_addr(0)?;
+	inode.fs.edit_block(vol_blk, |data| {
+		data[::ondisk::DX_ROOT_INFO_OFS + 6] = levels;
+		Ok( () )
+		})
+}
+
+/// Maximum number of interior node levels (more are allowed with FEAT_INCOMPAT_LARGEDIR)
+pub fn max_indirect_levels(fs: &::instance::InstanceInner) -> u8
+{
+	if fs.has_feature_incompat(::ondisk::FEAT_INCOMPAT_LARGEDIR) { 2 } else { 1 }
+}
+
+/// The original ext3 hash
+fn hash_legacy(name: &[u8], is_unsigned: bool) -> u32
+{
+	let mut hash0: u32 = 0x12a3fe2d;
+	let mut hash1: u32 = 0x37abe8f9;
+	for &c in name
+	{
+		let c = if is_unsigned { c as u32 } else { c as i8 as i32 as u32 };
+		let mut hash = hash1.wrapping_add(hash0 ^ c.wrapping_mul(7152373));
+		if hash & 0x8000_0000 != 0 {
+			hash = hash.wrapping_sub(0x7fff_ffff);
+		}
+		hash1 = hash0;
+		hash0 = hash;
+	}
+	hash0 << 1
+}
+
+/// Pack a chunk of a name into the hash input, padding with a value derived from the remaining length
+fn str2hashbuf(chunk: &[u8], rem_len: usize, out: &mut [u32], is_unsigned: bool)
+{
+	let pad = {
+		let v = rem_len as u32 | (rem_len as u32) << 8;
+		v | v << 16
+		};
+	let mut val = pad;
+	let mut o = 0;
+	for (i,&c) in chunk.iter().take(out.len() * 4).enumerate()
+	{
+		let c = if is_unsigned { c as u32 } else { c as i8 as i32 as u32 };
+		val = c.wrapping_add(val << 8);
+		if i % 4 == 3 {
+			out[o] = val;
+			o += 1;
+			val = pad;
+		}
+	}
+	if o < out.len() {
+		out[o] = val;
+		o += 1;
+	}
+	for v in &mut out[o..] {
+		*v = pad;
+	}
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8])
+{
+	fn f(x: u32, y: u32, z: u32) -> u32 { z ^ (x & (y ^ z)) }
+	fn g(x: u32, y: u32, z: u32) -> u32 { (x & y).wrapping_add((x ^ y) & z) }
+	fn h(x: u32, y: u32, z: u32) -> u32 { x ^ y ^ z }
+	const K1: u32 = 0;
+	const K2: u32 = 0o13240474631;
+	const K3: u32 = 0o15666365641;
+	let [mut a, mut b, mut c, mut d] = *buf;
+	macro_rules! round {
+		($f:ident, $a:ident, $b:ident, $c:ident, $d:ident, $x:expr, $s:expr) => {
+			$a = $a.wrapping_add($f($b, $c, $d)).wrapping_add($x).rotate_left($s);
+		};
+	}
+	round!(f, a, b, c, d, input[0].wrapping_add(K1),  3);
+	round!(f, d, a, b, c, input[1].wrapping_add(K1),  7);
+	round!(f, c, d, a, b, input[2].wrapping_add(K1), 11);
+	round!(f, b, c, d, a, input[3].wrapping_add(K1), 19);
+	round!(f, a, b, c, d, input[4].wrapping_add(K1),  3);
+	round!(f, d, a, b, c, input[5].wrapping_add(K1),  7);
+	round!(f, c, d, a, b, input[6].wrapping_add(K1), 11);
+	round!(f, b, c, d, a, input[7].wrapping_add(K1), 19);
+
+	round!(g, a, b, c, d, input[1].wrapping_add(K2),  3);
+	round!(g, d, a, b, c, input[3].wrapping_add(K2),  5);
+	round!(g, c, d, a, b, input[5].wrapping_add(K2),  9);
+	round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+	round!(g, a, b, c, d, input[0].wrapping_add(K2),  3);
+	round!(g, d, a, b, c, input[2].wrapping_add(K2),  5);
+	round!(g, c, d, a, b, input[4].wrapping_add(K2),  9);
+	round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+	round!(h, a, b, c, d, input[3].wrapping_add(K3),  3);
+	round!(h, d, a, b, c, input[7].wrapping_add(K3),  9);
+	round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+	round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+	round!(h, a, b, c, d, input[1].wrapping_add(K3),  3);
+	round!(h, d, a, b, c, input[5].wrapping_add(K3),  9);
+	round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+	round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+	buf[0] = buf[0].wrapping_add(a);
+	buf[1] = buf[1].wrapping_add(b);
+	buf[2] = buf[2].wrapping_add(c);
+	buf[3] = buf[3].wrapping_add(d);
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4])
+{
+	const DELTA: u32 = 0x9E3779B9;
+	let mut sum: u32 = 0;
+	let (mut b0, mut b1) = (buf[0], buf[1]);
+	let [a, b, c, d] = *input;
+	for _ in 0 .. 16
+	{
+		sum = sum.wrapping_add(DELTA);
+		b0 = b0.wrapping_add( (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b) );
+		b1 = b1.wrapping_add( (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d) );
+	}
+	buf[0] = buf[0].wrapping_add(b0);
+	buf[1] = buf[1].wrapping_add(b1);
+}
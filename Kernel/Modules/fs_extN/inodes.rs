@@ -11,6 +11,8 @@ pub struct Inode
 	inode_idx: u32,
 
 	is_dirty: AtomicBool,
+	/// Set when the last link is removed, the inode is released when the node is dropped (i.e. closed)
+	is_released: AtomicBool,
 	/// Inode data, with a lock
 	on_disk: ::kernel::sync::RwLock<crate::ondisk::Inode>,
 	/// Lock used for directories to maintain internal consistency
@@ -26,6 +28,7 @@ impl Inode
 			fs: fs,
 			inode_idx: id,
 			is_dirty: AtomicBool::new(false),
+			is_released: AtomicBool::new(false),
 			on_disk: ::kernel::sync::RwLock::new(od),
 			dir_lock: Default::default(),
 			})
@@ -39,6 +42,11 @@ impl Drop for Inode
 			log_warning!("Inode::drop - Dirty node being dropped, writing back and ignoring errors");
 			let _ = self.flush();
 		}
+		if self.is_released.load(Ordering::Relaxed) {
+			if let Err(e) = self.release() {
+				log_error!("Inode::drop - Error releasing I{}: {:?}", self.inode_idx, e);
+			}
+		}
 	}
 }
 
@@ -54,17 +62,115 @@ impl Inode
 		Ok( () )
 	}
 
+	/// Remove a link to this inode (releasing it once closed, if this was the last)
 	pub fn dec_link_count(&self) {
-		todo!("Inode::dec_link_count");
+		let mut lh = self.lock_write();
+		let is_dir = lh.i_mode_fmt() == ::ondisk::S_IFDIR;
+		match lh.lock.i_links_count
+		{
+		0 => log_warning!("Inode::dec_link_count - I{} already has no links", self.inode_idx),
+		// FEAT_RO_COMPAT_DIR_NLINK: A directory with a link count of 1 has too many subdirectories to count
+		1 if is_dir => {},
+		1 => {
+			lh.lock.i_links_count = 0;
+			self.is_released.store(true, Ordering::Relaxed);
+			},
+		_ => lh.lock.i_links_count -= 1,
+		}
+	}
+	/// Add a link to this inode
+	pub fn inc_link_count(&self) -> vfs::node::Result<()> {
+		const MAX_LINKS: u16 = 65000;
+		let mut lh = self.lock_write();
+		let is_dir = lh.i_mode_fmt() == ::ondisk::S_IFDIR;
+		if is_dir && lh.lock.i_links_count == 1 {
+			// Already saturated (see `dec_link_count`)
+		}
+		else if lh.lock.i_links_count >= MAX_LINKS {
+			if is_dir && self.fs.has_feature_ro_compat(::ondisk::FEAT_RO_COMPAT_DIR_NLINK) {
+				lh.lock.i_links_count = 1;
+			}
+			else {
+				return Err(vfs::Error::Unknown("Too many links"));
+			}
+		}
+		else {
+			lh.lock.i_links_count += 1;
+		}
+		Ok( () )
+	}
+	/// Remove all links (e.g. for a removed directory, or a failed creation), releasing the inode once closed
+	pub fn unlink_all(&self) {
+		self.lock_write().lock.i_links_count = 0;
+		self.is_released.store(true, Ordering::Relaxed);
 	}
-	pub fn inc_link_count(&self) {
-		todo!("Inode::inc_link_count");
+
+	/// Free the inode's data blocks and the inode itself
+	fn release(&self) -> vfs::Result<()>
+	{
+		log_debug!("Releasing I{}", self.inode_idx);
+		let _transaction = self.fs.start_transaction()?;
+		let is_dir = {
+			let mut lh = self.lock_write();
+			let is_dir = lh.i_mode_fmt() == ::ondisk::S_IFDIR;
+			lh.truncate_blocks(0)?;
+			lh.set_i_size(0)?;
+			lh.lock.i_mode = 0;
+			lh.lock.i_links_count = 0;
+			is_dir
+			};
+		self.fs.free_inode(self.inode_idx, is_dir)
 	}
 
 	/// Obtain the inode ID
 	pub fn get_id(&self) -> vfs::node::InodeId {
 		self.inode_idx as vfs::node::InodeId
 	}
+	/// Obtain the VFS metadata for this inode
+	pub fn get_metadata(&self) -> vfs::node::Metadata {
+		let lh = self.lock_read();
+		let od = &*lh.lock;
+		let ts = |v: u32| Some(vfs::node::Timestamp::from_unix(v as i32 as i64));
+		vfs::node::Metadata {
+			size: lh.i_size(),
+			links: od.i_links_count as u32,
+			mode: od.i_mode & 0o7777,
+			// High 16 bits of the IDs are in `osd2` (Linux layout)
+			uid: od.i_uid as u32 | (od._osd2[1] & 0xFFFF) << 16,
+			gid: od.i_gid as u32 | (od._osd2[1] >> 16) << 16,
+			atime: ts(od.i_atime),
+			mtime: ts(od.i_mtime),
+			ctime: ts(od.i_ctime),
+			crtime: None,
+		}
+	}
+	/// Update mode/ownership/times
+	pub fn set_metadata(&self, changes: &vfs::node::MetadataUpdate) -> vfs::node::Result<()> {
+		if self.fs.is_readonly() {
+			return Err( vfs::Error::ReadOnlyFilesystem );
+		}
+		let _transaction = self.fs.start_transaction()?;
+		let mut lh = self.lock_write();
+		let od = &mut *lh.lock;
+		if let Some(v) = changes.mode {
+			od.i_mode = (od.i_mode & ::ondisk::S_IFMT) | (v & 0o7777);
+		}
+		if let Some(v) = changes.uid {
+			od.i_uid = v as u16;
+			od._osd2[1] = (od._osd2[1] & !0xFFFF) | (v >> 16);
+		}
+		if let Some(v) = changes.gid {
+			od.i_gid = v as u16;
+			od._osd2[1] = (od._osd2[1] & 0xFFFF) | (v >> 16) << 16;
+		}
+		if let Some(v) = changes.atime {
+			od.i_atime = v.secs as u32;
+		}
+		if let Some(v) = changes.mtime {
+			od.i_mtime = v.secs as u32;
+		}
+		Ok( () )
+	}
 	/// Obtain the node contents consistency lock (used for directories)
 	pub fn lock_dir(&self) -> ::kernel::sync::mutex::HeldMutex<'_, ()> {
 		self.dir_lock.lock()
@@ -93,6 +199,17 @@ pub struct InodeHandleWrite<'a> {
 	parent: &'a Inode,
 	lock: ::kernel::sync::rwlock::Write<'a, ::ondisk::Inode>,
 }
+impl<'a> Drop for InodeHandleWrite<'a>
+{
+	fn drop(&mut self)
+	{
+		// Write-through, leaving it dirty (to be retried later) if that fails
+		if let Err(e) = self.parent.fs.write_inode(self.parent.inode_idx, &self.lock) {
+			log_error!("Error writing back I{}: {:?}", self.parent.inode_idx, e);
+			self.parent.is_dirty.store(true, Ordering::Relaxed);
+		}
+	}
+}
 macro_rules! common_methods {
 	($($(#[$attr:meta])* pub fn $name:ident(&$self:ident$(, $a:ident : $t:ty)*) -> $rv:ty $b:block)+) => {
 		pub trait InodeHandleTrait<'a> {
@@ -145,6 +262,12 @@ common_methods! {
 	pub fn i_size(&self) -> u64 {
 		self.lock.i_size(&self.parent.fs)
 	}
+	pub fn i_links_count(&self) -> u16 {
+		self.lock.i_links_count
+	}
+	pub fn i_flags(&self) -> u32 {
+		self.lock.i_flags
+	}
 	pub fn get_extent_from_block(&self, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u32, u32)> {
 		self.lock.get_extent_from_block(&self.parent.fs, block_idx, max_blocks)
 	}
@@ -174,9 +297,20 @@ impl<'a> InodeHandleWrite<'a> {
 	pub fn set_i_size(&mut self, new_size: u64) -> vfs::node::Result<()> {
 		self.lock.set_i_size(&self.parent.fs, new_size)
 	}
+	pub fn set_i_flags(&mut self, flags: u32) {
+		self.lock.i_flags = flags;
+	}
+	pub fn set_i_links_count(&mut self, count: u16) {
+		self.lock.i_links_count = count;
+	}
+	/// Allocate any missing blocks in the given range (blocks within the current size are zeroed, blocks past it aren't)
 	pub fn ensure_blocks_allocated(&mut self, block_idx: u32, num_blocks: u32) -> vfs::node::Result<()> {
 		self.lock.ensure_blocks_allocated(&self.parent.fs, self.parent.inode_idx, block_idx, num_blocks)
 	}
+	/// Release all blocks from `keep` onwards
+	pub fn truncate_blocks(&mut self, keep: u32) -> vfs::node::Result<()> {
+		self.lock.truncate_blocks(&self.parent.fs, keep)
+	}
 }
 
 
@@ -210,8 +344,23 @@ impl crate::ondisk::Inode
 		self.i_size = s as u32;
 		Ok( () )
 	}
+	fn uses_extents(&self) -> bool {
+		self.i_flags & ::ondisk::EXT4_EXTENTS_FL != 0
+	}
+	/// Adjust `i_blocks` by a number of filesystem blocks
+	fn add_i_blocks(&mut self, fs: &InstanceInner, delta: i64) {
+		// FEAT_RO_COMPAT_HUGE_FILE: 48-bit count (high bits in `osd2`), and optionally in filesystem blocks
+		let is_huge = fs.has_feature_ro_compat(crate::ondisk::FEAT_RO_COMPAT_HUGE_FILE);
+		let units = if is_huge && self.i_flags & ::ondisk::EXT4_HUGE_FILE_FL != 0 { 1 } else { (fs.fs_block_size / 512) as i64 };
+		let cur = self.i_blocks as u64 | if is_huge { ((self._osd2[0] & 0xFFFF) as u64) << 32 } else { 0 };
+		let new = i64::max(0, cur as i64 + delta * units) as u64;
+		self.i_blocks = new as u32;
+		if is_huge {
+			self._osd2[0] = (self._osd2[0] & !0xFFFF) | (new >> 32) as u32 & 0xFFFF;
+		}
+	}
 
-	fn max_blocks(&self, fs: &InstanceInner) -> u32 {
+	pub fn max_blocks(&self, fs: &InstanceInner) -> u32 {
 		let n_blocks = (self.i_size(fs) + fs.fs_block_size as u64 - 1) / fs.fs_block_size as u64;
 		if n_blocks > ::core::u32::MAX as u64 {
 			::core::u32::MAX
@@ -254,113 +403,210 @@ impl crate::ondisk::Inode
 			panic!("");
 		}
 	}
-	fn get_extent_from_block(&self, fs: &InstanceInner, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u32, u32)>
+	/// Locate the block pointer table for an indirect address (returns 0 if a table along the way is missing)
+	fn get_table(&self, fs: &InstanceInner, addrs: &BlockAddrs) -> vfs::node::Result<(u32, usize)> {
+		// TODO: Have locally a mutex-protected cached filesystem block (linked to a global cache manager)
+		Ok(match *addrs
+		{
+		BlockAddrs::Direct { .. } => panic!("get_table - direct"),
+		BlockAddrs::Single { idx } => (self.i_block[SI_BLOCK], idx),
+		BlockAddrs::Double { blk, idx } => (read_table(fs, self.i_block[DI_BLOCK], blk)?, idx),
+		BlockAddrs::Triple { blk_o, blk_i, idx } => {
+			let l1 = read_table(fs, self.i_block[TI_BLOCK], blk_o)?;
+			(read_table(fs, l1, blk_i)?, idx)
+			},
+		})
+	}
+	pub fn get_extent_from_block(&self, fs: &InstanceInner, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u32, u32)>
 	{
+		if self.uses_extents() {
+			return ::extents::lookup(fs, &self.i_block, block_idx, max_blocks);
+		}
 		match Self::get_block_addr_extent(fs, block_idx, max_blocks)
 		{
-		(BlockAddrs::Direct { direct_idx: idx }, max_blocks) => {
-			let fs_start = self.i_block[idx];
-			for num in 1 .. max_blocks {
-				if fs_start + num != self.i_block[idx + num as usize] {
-					return Ok( (fs_start, num) );
-				}
+		(BlockAddrs::Direct { direct_idx: idx }, max_blocks) => Ok( run_length(&self.i_block[idx..], max_blocks) ),
+		(addrs, max_blocks) => {
+			let (table, idx) = self.get_table(fs, &addrs)?;
+			if table == 0 {
+				// The whole table is missing (sparse)
+				Ok( (0, max_blocks) )
 			}
-			Ok( (fs_start, max_blocks) )
-			},
-		(BlockAddrs::Single { idx }, max_blocks) => {
-			// TODO: Have locally a mutex-protected cached filesystem block (linked to a global cache manager)
-			let si_block = try!( fs.get_block( self.i_block[SI_BLOCK] ) );
-			
-			let fs_start = si_block[idx];
-			for num in 1 .. max_blocks {
-				if fs_start + num != si_block[ idx + num as usize ] {
-					return Ok( (fs_start, num) );
-				}
+			else {
+				let table = try!( fs.get_block(table) );
+				Ok( run_length(&table[idx..], max_blocks) )
 			}
-			Ok( (fs_start, max_blocks) )
-			},
-		(BlockAddrs::Double { blk, idx }, max_blocks) => {
-			let di_block = try!( fs.get_block( self.i_block[DI_BLOCK] ) );
-			let di_block = try!( fs.get_block( di_block[blk] ) );
-
-			let fs_start = di_block[idx as usize];
-			for num in 1 .. max_blocks {
-				if fs_start + num != di_block[ idx + num as usize ] {
-					return Ok( (fs_start, num) );
-				}
-			}
-			Ok( (fs_start, max_blocks) )
-			},
-		(BlockAddrs::Triple { blk_o, blk_i, idx }, max_blocks) => {
-			// Triple-indirect block
-			let ti_block = try!( fs.get_block( self.i_block[TI_BLOCK] ) );
-			let ti_block = try!( fs.get_block( ti_block[blk_o] ) );
-			let ti_block = try!( fs.get_block( ti_block[blk_i] ) );
-
-
-			let fs_start = ti_block[idx as usize];
-			for num in 1 .. max_blocks {
-				if fs_start + num != ti_block[ idx + num as usize ] {
-					return Ok( (fs_start, num) );
-				}
-			}
-			Ok( (fs_start, max_blocks) )
 			},
 		}
 	}
 
 	pub fn get_block_addr(&self, fs: &InstanceInner, block_idx: u32) -> vfs::node::Result<u32>
 	{
+		if self.uses_extents() {
+			return Ok( ::extents::lookup(fs, &self.i_block, block_idx, 1)?.0 );
+		}
 		match Self::get_block_addr_extent(fs, block_idx, 1).0
 		{
 		BlockAddrs::Direct { direct_idx: idx } => {
 			Ok( self.i_block[idx] )
 			},
-		BlockAddrs::Single { idx } => {
-			// TODO: Have locally a mutex-protected cached filesystem block (linked to a global cache manager)
-			let si_block = try!( fs.get_block( self.i_block[SI_BLOCK] ) );
-			Ok( si_block[ idx] )
-			},
-		BlockAddrs::Double { blk, idx } => {
-			let di_block = try!( fs.get_block( self.i_block[DI_BLOCK] ) );
-			let di_block = try!( fs.get_block( di_block[blk] ) );
-			Ok( di_block[idx] )
-			},
-		BlockAddrs::Triple { blk_o, blk_i, idx } => {
-			let ti_block = try!( fs.get_block( self.i_block[TI_BLOCK] ) );
-			let ti_block = try!( fs.get_block( ti_block[blk_o] ) );
-			let ti_block = try!( fs.get_block( ti_block[blk_i] ) );
-			Ok( ti_block[idx] )
+		addrs => {
+			let (table, idx) = self.get_table(fs, &addrs)?;
+			read_table(fs, table, idx)
 			},
 		}
 	}
 
-	fn ensure_blocks_allocated(&mut self, fs: &InstanceInner, inode_num: u32, mut block_idx: u32, mut count: u32) -> vfs::node::Result<()> {
-		let mut prev_block = 0;	// track the previous block to allow efficient allocation
-		if block_idx > 0 {
-			// TODO: Populate the previous block? What if the file is sparse?
-		}
-		while count > 0
+	/// Allocate blocks for any holes in the range, zeroing new blocks that are within the current file size
+	fn ensure_blocks_allocated(&mut self, fs: &InstanceInner, inode_num: u32, block_idx: u32, count: u32) -> vfs::node::Result<()> {
+		let zero_below = self.max_blocks(fs);
+		let mut n_alloc = 0;
+		let rv = if self.uses_extents() {
+				::extents::ensure_allocated(fs, inode_num, &mut self.i_block, block_idx, count, zero_below, &mut n_alloc)
+			}
+			else {
+				self.ensure_blocks_allocated_indirect(fs, inode_num, block_idx, count, zero_below, &mut n_alloc)
+			};
+		// NOTE: Updated even on error, as some blocks might have been allocated
+		self.add_i_blocks(fs, n_alloc as i64);
+		rv
+	}
+	fn ensure_blocks_allocated_indirect(&mut self, fs: &InstanceInner, inode_num: u32, block_idx: u32, count: u32, zero_below: u32, n_alloc: &mut u32) -> vfs::node::Result<()> {
+		// Track the previous block to allow efficient allocation
+		let mut prev_block = if block_idx > 0 { self.get_block_addr(fs, block_idx - 1)? } else { 0 };
+		for block in block_idx .. block_idx + count
 		{
-			let (addrs, span_count) = Self::get_block_addr_extent(fs, block_idx, count);
-			block_idx += span_count;
-			count -= span_count;
-			match addrs
+			// Root slot, then the index within each level of table
+			let (slot, path, depth) = match Self::get_block_addr_extent(fs, block, 1).0
+				{
+				BlockAddrs::Direct { direct_idx } => (direct_idx, [0; 3], 0),
+				BlockAddrs::Single { idx } => (SI_BLOCK, [idx, 0, 0], 1),
+				BlockAddrs::Double { blk, idx } => (DI_BLOCK, [blk, idx, 0], 2),
+				BlockAddrs::Triple { blk_o, blk_i, idx } => (TI_BLOCK, [blk_o, blk_i, idx], 3),
+				};
+			// Tables must always be zeroed, data only if it's filling a hole
+			let is_hole = block < zero_below;
+			if self.i_block[slot] == 0 {
+				let new = fs.allocate_data_block(inode_num, prev_block)?;
+				*n_alloc += 1;
+				if depth > 0 || is_hole {
+					fs.zero_blocks(new, 1)?;
+				}
+				self.i_block[slot] = new;
+			}
+			let mut cur = self.i_block[slot];
+			for (level, &idx) in path[..depth].iter().enumerate()
 			{
-			BlockAddrs::Direct { direct_idx } => {
-				for s in &mut self.i_block[direct_idx..][.. span_count as usize] {
-					if *s == 0 {
-						*s = fs.allocate_data_block(inode_num, prev_block)?;
+				let table = cur;
+				cur = fs.get_block(table)?[idx];
+				if cur == 0 {
+					let is_table = level + 1 < depth;
+					let new = fs.allocate_data_block(inode_num, if is_table { table } else { prev_block })?;
+					*n_alloc += 1;
+					if is_table || is_hole {
+						fs.zero_blocks(new, 1)?;
 					}
-					prev_block = *s;
+					fs.edit_block_u32(table, |d| { d[idx] = new; Ok( () ) })?;
+					cur = new;
 				}
-				},
-			// TODO: Indirect requires editing the result of `get_block`
-			_ => todo!("ensure_blocks_allocated - indirect"), 
 			}
+			prev_block = cur;
 		}
 		Ok( () )
 	}
+
+	/// Release all blocks from `keep` onwards
+	fn truncate_blocks(&mut self, fs: &InstanceInner, keep: u32) -> vfs::node::Result<()> {
+		let mut n_freed = 0;
+		let rv = if self.uses_extents() {
+				::extents::truncate(fs, &mut self.i_block, keep, &mut n_freed)
+			}
+			else {
+				self.truncate_blocks_indirect(fs, keep, &mut n_freed)
+			};
+		self.add_i_blocks(fs, -(n_freed as i64));
+		rv
+	}
+	fn truncate_blocks_indirect(&mut self, fs: &InstanceInner, keep: u32, n_freed: &mut u32) -> vfs::node::Result<()> {
+		for s in &mut self.i_block[usize::min(keep as usize, SI_BLOCK) .. SI_BLOCK] {
+			if *s != 0 {
+				fs.free_data_block(*s)?;
+				*n_freed += 1;
+				*s = 0;
+			}
+		}
+		let u32_per_fs_block = (fs.fs_block_size / ::core::mem::size_of::<u32>()) as u64;
+		let mut base = SI_BLOCK as u64;
+		for &(slot, level) in &[(SI_BLOCK, 1), (DI_BLOCK, 2), (TI_BLOCK, 3)]
+		{
+			let span = u32_per_fs_block.pow(level);
+			if self.i_block[slot] != 0 && base + span > keep as u64 {
+				let rel_keep = (keep as u64).saturating_sub(base);
+				if truncate_table(fs, self.i_block[slot], level, rel_keep, n_freed)? {
+					self.i_block[slot] = 0;
+				}
+			}
+			base += span;
+		}
+		Ok( () )
+	}
+}
+
+/// Read an entry from a block pointer table (a missing table reads as zero)
+fn read_table(fs: &InstanceInner, table: u32, idx: usize) -> vfs::node::Result<u32> {
+	if table == 0 {
+		Ok(0)
+	}
+	else {
+		Ok( fs.get_block(table)?[idx] )
+	}
+}
+/// Length of the contiguous run at the start of a pointer list (a run of zeroes is a hole)
+fn run_length(ptrs: &[u32], max_blocks: u32) -> (u32, u32) {
+	let fs_start = ptrs[0];
+	for num in 1 .. max_blocks {
+		let expected = if fs_start == 0 { 0 } else { fs_start + num };
+		if ptrs[num as usize] != expected {
+			return (fs_start, num);
+		}
+	}
+	(fs_start, max_blocks)
+}
+/// Free everything at or after `keep` (relative to the start of this table), returning true if the table itself was freed
+fn truncate_table(fs: &InstanceInner, table: u32, level: u32, keep: u64, n_freed: &mut u32) -> vfs::node::Result<bool> {
+	let u32_per_fs_block = (fs.fs_block_size / ::core::mem::size_of::<u32>()) as u64;
+	let span = u32_per_fs_block.pow(level - 1);
+	let mut ptrs = fs.get_block(table)?.to_vec();
+	let mut changed = false;
+	for (i, p) in ptrs.iter_mut().enumerate()
+	{
+		let start = i as u64 * span;
+		if *p == 0 || start + span <= keep {
+			continue ;
+		}
+		let freed = if level == 1 {
+				fs.free_data_block(*p)?;
+				*n_freed += 1;
+				true
+			}
+			else {
+				truncate_table(fs, *p, level - 1, keep.saturating_sub(start), n_freed)?
+			};
+		if freed {
+			*p = 0;
+			changed = true;
+		}
+	}
+	if ptrs.iter().all(|&p| p == 0) {
+		fs.free_data_block(table)?;
+		*n_freed += 1;
+		Ok(true)
+	}
+	else {
+		if changed {
+			fs.edit_block_u32(table, |d| { d.copy_from_slice(&ptrs); Ok( () ) })?;
+		}
+		Ok(false)
+	}
 }
 
 /// Iterator over block numbers owned by an inode
@@ -17,9 +17,15 @@ pub struct InstanceInner
 	pub vol: ::block_cache::CachedVolume,
 	superblock: ::kernel::sync::RwLock<crate::ondisk::Superblock>,
 	pub fs_block_size: usize,
+	/// Byte offset of the group descriptor table
+	gdt_offset: usize,
 
 	mount_handle: ::vfs::mount::SelfHandle,
 	group_descriptors: ::kernel::sync::RwLock< Vec<::ondisk::GroupDesc> >,
+	/// Metadata journal (FEAT_COMPAT_HAS_JOURNAL), only present if the filesystem is writable
+	journal: Option<::journal::Journal>,
+	/// Held while renaming, so a directory can't be moved while checking that a move doesn't form a loop
+	pub rename_lock: ::kernel::sync::Mutex<()>,
 }
 
 pub enum FeatureState
@@ -64,23 +70,10 @@ impl Instance
 		}
 	}
 
-	pub fn new_boxed(vol: VolumeHandle, mount_handle: ::vfs::mount::SelfHandle) -> ::vfs::Result<Box<Instance>>
+	pub fn new_boxed(vol: VolumeHandle, read_only: bool, mount_handle: ::vfs::mount::SelfHandle) -> ::vfs::Result<Box<Instance>>
 	{
-		let vol_bs = vol.block_size();
-
-		// The superblock exists at offset 1024 in the volume, no matter the on-disk block size
-		let superblock_idx = (1024 / vol_bs) as u64;
-		let superblock_ofs = (1024 % vol_bs) as usize;
-
-		let (superblock, first_block) = {
-			let mut first_block: Vec<u8> = vec![0; ::core::cmp::max(1024, vol_bs)];
-			::kernel::futures::block_on(vol.read_blocks(superblock_idx, &mut first_block[..]))?;
-			assert!(superblock_ofs % 4 == 0);
-			(
-				::ondisk::Superblock::from_slice(&first_block[superblock_ofs ..][..1024]),
-				first_block,
-				)
-			};
+		let vol = ::block_cache::CachedVolume::new(vol);
+		let (superblock, first_block) = Self::read_superblock(&vol)?;
 
 		if superblock.data.s_magic != 0xEF53 {
 			return Err(::vfs::Error::TypeMismatch);
@@ -91,7 +84,7 @@ impl Instance
 			{
 			FeatureState::Incompatible(_) => return Err(::vfs::Error::TypeMismatch),
 			FeatureState::ReadOnly(_) => true,
-			_ => false,
+			_ => read_only,
 			};
 
 		// Limit filesystem block size to 1MB each, as a sanity check
@@ -100,108 +93,135 @@ impl Instance
 		}
 
 		let fs_block_size = 1024 << superblock.data.s_log_block_size as usize;
-		if fs_block_size % vol_bs != 0 {
+		if fs_block_size % vol.block_size() != 0 {
 			log_warning!("ExtN TODO: Handle filesystem block size smaller than disk block size?");
 			return Err(::vfs::Error::InconsistentFilesystem);
 		}
-		let num_groups = ::kernel::lib::num::div_up(superblock.data.s_blocks_count, superblock.data.s_blocks_per_group);
-
-		// Read group descriptor table
-		// - This resides in the first FS block after the superblock
-		let group_descs = {
-			const GROUP_DESC_SIZE: usize = ::core::mem::size_of::<::ondisk::GroupDesc>();
-			if GROUP_DESC_SIZE != superblock.s_group_desc_size() {
-				return Err(::vfs::Error::Unknown("Superblock size mismatch vs expected"));
-			}
-
-			let groups_per_vol_block = vol_bs / GROUP_DESC_SIZE;
-			// Group descriptors are in the first filesystem block after the superblock
-			// - So either immediately right after the superblock, or the second block (whichever is larger)
-			let byte_offset = usize::max(2*1024, fs_block_size);
-			log_trace!("Group Descs: {} groups @ byte {}, {} per volume block (vol_bs={})",
-				num_groups, byte_offset, groups_per_vol_block, vol_bs);
-
-			let mut gds: Vec<::ondisk::GroupDesc> = (0..num_groups).map(|_| Default::default()).collect();
-
-			// The superblock is 1024 bytes at offset 1024
-			// - If the volume block size is 2K or larger, then there are some group descriptors in the first block
-			let (n_skip, mut vol_block) = if vol_bs > byte_offset {
-					// Volume block size is larger than the offset
-					// - This means that at least 2048 bytes of the group descriptors are in the same block as the superblock
-					let n_shared = (vol_bs - byte_offset) / GROUP_DESC_SIZE;
-
-					let mut src = &first_block[byte_offset..];
-					let count = ::core::cmp::min(n_shared, gds.len());
-					assert_eq!(src.len(), count * GROUP_DESC_SIZE);
-					for s in &mut gds[..count] {
-						*s = ::kernel::lib::byteorder::EncodedLE::decode(&mut src).unwrap();
-						log_debug!("GROUP DESC: {:?}", s);
-					}
-					(count, 1)
-				}
-				else {
-					// Volume BS <= superblock
-					// - Offset of byte 2048 in the disk
-					(0, (byte_offset / vol_bs) as u64)
-				};
-
-			// Determine how many descriptors are in the subsequent volume blocks
-			let rem_count = gds.len() - n_skip;
-			let tail_count = rem_count % groups_per_vol_block;
-			let body_count = rem_count - tail_count;
-			log_trace!("vol_block={} n_skip={} => rem_count={} (tail_count={}, body_count={})",
-				vol_block, n_skip, rem_count,  tail_count, body_count);
-
-			let mut buf: Vec<u8> = vec![0; vol_bs];
-			if body_count > 0
-			{
-				for gds in gds[n_skip..][..body_count].chunks_mut(groups_per_vol_block) {
-					::kernel::futures::block_on(vol.read_blocks(vol_block, &mut buf))?;
-					let mut src = &buf[..];
-					for s in gds {
-						*s = ::kernel::lib::byteorder::EncodedLE::decode(&mut src).unwrap();
-						log_debug!("GROUP DESC: {:?}", s);
-					}
-					vol_block += 1;
-				}
-			}
-
-			if tail_count > 0
-			{
-				let ofs = n_skip + body_count;
-				// Read a single volume block into a buffer, then populate from that
-				::kernel::futures::block_on(vol.read_blocks(vol_block, &mut buf))?;
-				let n_bytes = (gds.len() - ofs) * GROUP_DESC_SIZE;
-				let mut src = &buf[..n_bytes];
-				for s in &mut gds[ofs..] {
-					*s = ::kernel::lib::byteorder::EncodedLE::decode(&mut src).unwrap();
-					log_debug!("GROUP DESC: {:?}", s);
-				}
-			}
-
-			gds
-			};
-
 
+		let group_descs = Self::read_group_descs(&vol, &superblock, fs_block_size, &first_block)?;
 		for (i, gd) in group_descs.iter().enumerate()
 		{
 			log_debug!("{}: Group #{}: {:?}", vol.name(), i, gd);
 		}
 
-		let inner = InstanceInner {
+		let has_journal = superblock.data.s_rev_level > 0 && superblock.ext.s_feature_compat & ::ondisk::FEAT_COMPAT_HAS_JOURNAL != 0;
+		let mut inner = InstanceInner {
 			is_readonly: is_readonly,
 			fs_block_size: fs_block_size,
+			gdt_offset: usize::max(2*1024, fs_block_size),
 			superblock: ::kernel::sync::RwLock::new(superblock),
 			group_descriptors: ::kernel::sync::RwLock::new(group_descs),
 			mount_handle: mount_handle,
-			vol: ::block_cache::CachedVolume::new(vol),
+			vol: vol,
+			journal: None,
+			rename_lock: Default::default(),
 			};
+		if has_journal {
+			inner.load_journal()?;
+		}
 
 		// SAFE: Boxed instantly
 		unsafe {
 			Ok(Box::new(Instance(ArefInner::new( inner ))))
 		}
 	}
+
+	/// Read the superblock, returning it and the volume block(s) containing it
+	fn read_superblock(vol: &::block_cache::CachedVolume) -> ::vfs::Result<(::ondisk::Superblock, Vec<u8>)>
+	{
+		let vol_bs = vol.block_size();
+
+		// The superblock exists at offset 1024 in the volume, no matter the on-disk block size
+		let superblock_idx = (1024 / vol_bs) as u64;
+		let superblock_ofs = (1024 % vol_bs) as usize;
+
+		let mut first_block: Vec<u8> = vec![0; ::core::cmp::max(1024, vol_bs)];
+		::kernel::futures::block_on(vol.read_blocks(superblock_idx, &mut first_block[..]))?;
+		assert!(superblock_ofs % 4 == 0);
+		Ok((
+			::ondisk::Superblock::from_slice(&first_block[superblock_ofs ..][..1024]),
+			first_block,
+			))
+	}
+
+	/// Read the group descriptor table
+	fn read_group_descs(vol: &::block_cache::CachedVolume, superblock: &::ondisk::Superblock, fs_block_size: usize, first_block: &[u8]) -> ::vfs::Result<Vec<::ondisk::GroupDesc>>
+	{
+		let vol_bs = vol.block_size();
+		let num_groups = ::kernel::lib::num::div_up(superblock.data.s_blocks_count, superblock.data.s_blocks_per_group);
+
+		const GROUP_DESC_SIZE: usize = ::core::mem::size_of::<::ondisk::GroupDesc>();
+		if GROUP_DESC_SIZE != superblock.s_group_desc_size() {
+			return Err(::vfs::Error::Unknown("Superblock size mismatch vs expected"));
+		}
+
+		let groups_per_vol_block = vol_bs / GROUP_DESC_SIZE;
+		// Group descriptors are in the first filesystem block after the superblock
+		// - So either immediately right after the superblock, or the second block (whichever is larger)
+		let byte_offset = usize::max(2*1024, fs_block_size);
+		log_trace!("Group Descs: {} groups @ byte {}, {} per volume block (vol_bs={})",
+			num_groups, byte_offset, groups_per_vol_block, vol_bs);
+
+		let mut gds: Vec<::ondisk::GroupDesc> = (0..num_groups).map(|_| Default::default()).collect();
+
+		// The superblock is 1024 bytes at offset 1024
+		// - If the volume block size is 2K or larger, then there are some group descriptors in the first block
+		let (n_skip, mut vol_block) = if vol_bs > byte_offset {
+				// Volume block size is larger than the offset
+				// - This means that at least 2048 bytes of the group descriptors are in the same block as the superblock
+				let n_shared = (vol_bs - byte_offset) / GROUP_DESC_SIZE;
+
+				let mut src = &first_block[byte_offset..];
+				let count = ::core::cmp::min(n_shared, gds.len());
+				assert_eq!(src.len(), count * GROUP_DESC_SIZE);
+				for s in &mut gds[..count] {
+					*s = ::kernel::lib::byteorder::EncodedLE::decode(&mut src).unwrap();
+					log_debug!("GROUP DESC: {:?}", s);
+				}
+				(count, 1)
+			}
+			else {
+				// Volume BS <= superblock
+				// - Offset of byte 2048 in the disk
+				(0, (byte_offset / vol_bs) as u64)
+			};
+
+		// Determine how many descriptors are in the subsequent volume blocks
+		let rem_count = gds.len() - n_skip;
+		let tail_count = rem_count % groups_per_vol_block;
+		let body_count = rem_count - tail_count;
+		log_trace!("vol_block={} n_skip={} => rem_count={} (tail_count={}, body_count={})",
+			vol_block, n_skip, rem_count,  tail_count, body_count);
+
+		let mut buf: Vec<u8> = vec![0; vol_bs];
+		if body_count > 0
+		{
+			for gds in gds[n_skip..][..body_count].chunks_mut(groups_per_vol_block) {
+				::kernel::futures::block_on(vol.read_blocks(vol_block, &mut buf))?;
+				let mut src = &buf[..];
+				for s in gds {
+					*s = ::kernel::lib::byteorder::EncodedLE::decode(&mut src).unwrap();
+					log_debug!("GROUP DESC: {:?}", s);
+				}
+				vol_block += 1;
+			}
+		}
+
+		if tail_count > 0
+		{
+			let ofs = n_skip + body_count;
+			// Read a single volume block into a buffer, then populate from that
+			::kernel::futures::block_on(vol.read_blocks(vol_block, &mut buf))?;
+			let n_bytes = (gds.len() - ofs) * GROUP_DESC_SIZE;
+			let mut src = &buf[..n_bytes];
+			for s in &mut gds[ofs..] {
+				*s = ::kernel::lib::byteorder::EncodedLE::decode(&mut src).unwrap();
+				log_debug!("GROUP DESC: {:?}", s);
+			}
+		}
+
+		Ok(gds)
+	}
 }
 
 impl ::vfs::mount::Filesystem for Instance
@@ -244,6 +264,117 @@ impl InstanceInner
 	}
 }
 
+/// Journal
+impl InstanceInner
+{
+	/// Load the journal, replaying it if the filesystem wasn't cleanly unmounted
+	fn load_journal(&mut self) -> ::vfs::Result<()>
+	{
+		let (inode_num, needs_recovery) = {
+			let sb = self.superblock.read();
+			(sb.ext.s_journal_inum, sb.has_feature_incompat(::ondisk::FEAT_INCOMPAT_RECOVER))
+			};
+		if inode_num == 0 {
+			if needs_recovery {
+				return Err(::vfs::Error::Unknown("extN: External journal needs recovery (not supported)"));
+			}
+			log_warning!("{}: External journals are not supported, mounting read-only", self.vol.name());
+			self.is_readonly = true;
+			return Ok( () );
+		}
+
+		let journal = ::journal::Journal::load(self, inode_num)?;
+		let replayed = journal.needs_recovery();
+		if replayed {
+			if !needs_recovery {
+				log_notice!("{}: Journal has transactions, but the filesystem isn't marked as needing recovery", self.vol.name());
+			}
+			if self.is_readonly {
+				log_notice!("{}: Recovering journal on a read-only filesystem", self.vol.name());
+			}
+			journal.replay(self)?;
+
+			// The replayed blocks can include the superblock and group descriptors
+			let (superblock, first_block) = Instance::read_superblock(&self.vol)?;
+			if superblock.data.s_magic != 0xEF53 || superblock.data.s_log_block_size != self.superblock.read().data.s_log_block_size {
+				log_error!("{}: Superblock is invalid after journal replay", self.vol.name());
+				return Err(::vfs::Error::InconsistentFilesystem);
+			}
+			let group_descs = Instance::read_group_descs(&self.vol, &superblock, self.fs_block_size, &first_block)?;
+			*self.superblock.write() = superblock;
+			*self.group_descriptors.write() = group_descs;
+		}
+
+		let use_journal = if self.is_readonly {
+				false
+			}
+			else if !journal.supports_writes() {
+				log_warning!("{}: Journal uses unsupported features, mounting read-only", self.vol.name());
+				self.is_readonly = true;
+				false
+			}
+			else {
+				true
+			};
+		// While the journal is in use the filesystem is marked as needing recovery (it's otherwise clean now)
+		if use_journal {
+			self.edit_superblock(|sb| sb.ext.s_feature_incompat |= ::ondisk::FEAT_INCOMPAT_RECOVER)?;
+			self.journal = Some(journal);
+		}
+		else if replayed {
+			// Read-only mounts leave the superblock alone, unless the journal had to be replayed
+			self.edit_superblock(|sb| sb.ext.s_feature_incompat &= !::ondisk::FEAT_INCOMPAT_RECOVER)?;
+		}
+		Ok( () )
+	}
+
+	/// Start (or join) a journal transaction, metadata edits made while the handle exists are committed together
+	pub fn start_transaction(&self) -> ::vfs::node::Result<::journal::Handle<'_>>
+	{
+		match self.journal
+		{
+		Some(ref j) => j.start(self),
+		None => Ok(::journal::Handle::none()),
+		}
+	}
+	/// Hold off writeback of a (journalled) block
+	pub fn hold_block(&self, block: u32) -> ::vfs::node::Result<()>
+	{
+		::kernel::futures::block_on(self.vol.hold_block(block as u64 * self.vol_blocks_per_fs_block()))?;
+		Ok( () )
+	}
+	/// Allow a block held by `hold_block` to be written back
+	pub fn release_block(&self, block: u32) -> ::vfs::node::Result<()>
+	{
+		::kernel::futures::block_on(self.vol.release_block(block as u64 * self.vol_blocks_per_fs_block()))?;
+		Ok( () )
+	}
+
+	/// Edit metadata on the volume (within a journal transaction, if there's a journal)
+	fn edit_metadata<F,R>(&self, vol_block: u64, count: usize, f: F) -> ::vfs::node::Result<R>
+	where
+		F: FnOnce(&mut [u8]) -> R
+	{
+		let _h = self.start_transaction()?;
+		if let Some(ref j) = self.journal {
+			j.add_block(self, (vol_block / self.vol_blocks_per_fs_block()) as u32)?;
+		}
+		Ok( ::kernel::futures::block_on(self.vol.edit(vol_block, count, f))? )
+	}
+}
+impl Drop for InstanceInner
+{
+	fn drop(&mut self)
+	{
+		// The journal is empty between transactions, so the filesystem is clean once it's no longer in use
+		if self.journal.take().is_some() {
+			if let Err(e) = self.edit_superblock(|sb| sb.ext.s_feature_incompat &= !::ondisk::FEAT_INCOMPAT_RECOVER) {
+				log_error!("{}: Unable to mark the filesystem as clean: {:?}", self.vol.name(), e);
+			}
+		}
+	}
+}
+
 /// Structure representing a view into a BlockCache entry
 pub struct Block<'a>(::block_cache::BlockHandleRead<'a>, u16,u16);
 impl<'a> ::core::ops::Deref for Block<'a>
@@ -292,9 +423,38 @@ impl InstanceInner
 		log_trace!("get_block({})", block);
 		let sector = block as u64 * self.vol_blocks_per_fs_block();
 
-		::kernel::futures::block_on(self.vol.edit(sector, self.vol_blocks_per_fs_block() as usize, |data| {
+		self.edit_metadata(sector, self.vol_blocks_per_fs_block() as usize, |data| {
 			f(data)
-			}))?
+			})?
+	}
+
+	/// Edit a block as a sequence of native-endian words (e.g. block pointer tables)
+	pub fn edit_block_u32<F,R>(&self, block: u32, f: F) -> ::vfs::node::Result<R>
+	where
+		F: FnOnce(&mut [u32]) -> ::vfs::node::Result<R>
+	{
+		self.edit_block(block, |blk_data| {
+			// SAFE: Alignment checked, range valid
+			let blk_data: &mut [u32] = unsafe {
+				assert!(&blk_data[0] as *const _ as usize % 4 == 0);
+				::core::slice::from_raw_parts_mut(blk_data.as_mut_ptr() as *mut u32, blk_data.len() / 4)
+				};
+			f(blk_data)
+			})
+	}
+
+	/// Fill a run of blocks with zeroes
+	pub fn zero_blocks(&self, first_block: u32, count: u32) -> ::vfs::node::Result<()>
+	{
+		const MAX_CHUNK: u32 = 16;
+		let zeroes = vec![0; self.fs_block_size * u32::min(count, MAX_CHUNK) as usize];
+		let mut done = 0;
+		while done < count {
+			let n = u32::min(count - done, MAX_CHUNK);
+			self.write_blocks(first_block + done, &zeroes[.. n as usize * self.fs_block_size])?;
+			done += n;
+		}
+		Ok( () )
 	}
 
 	#[cfg(any())]	// TODO
@@ -321,82 +481,82 @@ impl InstanceInner
 	/// Read a sequence of blocks into a user-provided buffer
 	pub fn read_blocks(&self, first_block: u32, data: &mut [u8]) -> ::vfs::node::Result<()>
 	{
-		::kernel::futures::block_on( self.vol.read_blocks_uncached( first_block as u64 * self.vol_blocks_per_fs_block(), data) )?;
+		::kernel::futures::block_on( self.vol.read_blocks( first_block as u64 * self.vol_blocks_per_fs_block(), data) )?;
 		Ok( () )
 	}
 
 	/// Write a sequence of blocks from a user-provided buffer
 	pub fn write_blocks(&self, first_block: u32, data: &[u8]) -> ::vfs::node::Result<()>
 	{
-		// TODO: Requires maybe interfacing with the cache used by get_block?
-		::kernel::futures::block_on( self.vol.write_blocks_uncached( first_block as u64 * self.vol_blocks_per_fs_block(), data) )?;
+		// NOTE: Goes via the cache-checking path, as blocks are reused between metadata (cached) and file data
+		::kernel::futures::block_on( self.vol.write_blocks( first_block as u64 * self.vol_blocks_per_fs_block(), data) )?;
 		Ok( () )
 	}
 }
 
 impl InstanceInner
 {
+	/// Returns (grp_idx, inner_idx)
 	fn get_block_grp_id(&self, block_idx: u32) -> (u32, u32) {
-		let s_blocks_per_group = self.superblock.read().data.s_blocks_per_group;
-		(block_idx / s_blocks_per_group, block_idx % s_blocks_per_group)
+		let sb = self.superblock.read();
+		// Block groups start at the first data block (block 1 for 1KiB blocks)
+		let rel_block = block_idx - sb.data.s_first_data_block;
+		(rel_block / sb.data.s_blocks_per_group, rel_block % sb.data.s_blocks_per_group)
+	}
+	fn num_groups(&self) -> u32 {
+		self.group_descriptors.read().len() as u32
+	}
+
+	/// Adjust the superblock's free block count, returns `false` (and leaves it unchanged) if it would go negative
+	fn update_sb_free_blocks(&self, delta: i64) -> ::vfs::node::Result<bool> {
+		self.edit_superblock(|sb| {
+			let is_64bit = sb.has_feature_incompat(crate::ondisk::FEAT_INCOMPAT_64BIT);
+			let cur = sb.data.s_free_blocks_count as u64 | if is_64bit { (sb.ext.s_free_blocks_count_hi as u64) << 32 } else { 0 };
+			if delta < 0 && cur < (-delta) as u64 {
+				return false;
+			}
+			let new = (cur as i64 + delta) as u64;
+			sb.data.s_free_blocks_count = new as u32;
+			if is_64bit {
+				sb.ext.s_free_blocks_count_hi = (new >> 32) as u32;
+			}
+			true
+			})
 	}
+
 	/// Allocate a new data block
 	pub fn allocate_data_block(&self, inode_num: u32, prev_block: u32) -> ::vfs::node::Result<u32> {
 		log_debug!("allocate_data_block(inode_num=I{}, prev_block=B{})", inode_num, prev_block);
-		let has_blocks = self.edit_superblock(|sb| {
-			if sb.has_feature_incompat(crate::ondisk::FEAT_INCOMPAT_64BIT) {
-				if sb.data.s_free_blocks_count == 0 && sb.ext.s_free_blocks_count_hi == 0 {
-					false
-				}
-				else {
-					let (new, overflow) = sb.data.s_free_blocks_count.overflowing_sub(1);
-					if overflow {
-						sb.ext.s_free_blocks_count_hi -= 1;
-					}
-					sb.data.s_free_blocks_count = new;
-					true
-				}
-			}
-			else {
-				if sb.data.s_free_blocks_count == 0 {
-					false
-				}
-				else {
-					sb.data.s_free_blocks_count -= 1;
-					true
-				}
-			}
-			})?;
-		if !has_blocks {
+		if !self.update_sb_free_blocks(-1)? {
 			return Err(::vfs::Error::OutOfSpace);
 		}
+		// 1. Check within the same BG as the previous block (telling it the previous block, so it can pick one near that)
 		if prev_block != 0 {
 			let (block_bg, _) = self.get_block_grp_id(prev_block);
-			let inode_bg = self.get_inode_grp_id(inode_num).0;
-			// 1. Check within the same BG (telling it the previous block, so it can pick one near that)
 			if let Some(rv) = self.allocate_block_in_group(block_bg, prev_block)? {
 				return Ok(rv);
 			}
-			// 2. If the inode BG is different, then check in the inode's BG
-			if block_bg != inode_bg {
-				if let Some(rv) = self.allocate_block_in_group(inode_bg, 0)? {
-					return Ok(rv);
-				}
-			}
 		}
-		else {
-			let inode_bg = self.get_inode_grp_id(inode_num).0;
-			if let Some(rv) = self.allocate_block_in_group(inode_bg, 0)? {
+		// 2. First available in the inode's BG, then each following BG
+		let inode_bg = self.get_inode_grp_id(inode_num).0;
+		let num_groups = self.num_groups();
+		for i in 0 .. num_groups {
+			if let Some(rv) = self.allocate_block_in_group((inode_bg + i) % num_groups, 0)? {
 				return Ok(rv);
 			}
 		}
-		// Fallback: Otherwise, first available within the inodes BG
-		// Or, from a random block group
-		todo!("allocate_data_block(inode={}, prev_block={})", inode_num, prev_block);
+		log_warning!("allocate_data_block: Superblock said that there were free blocks, but no group has any");
+		self.update_sb_free_blocks(1)?;
+		Err(::vfs::Error::OutOfSpace)
 	}
 
 	fn allocate_block_in_group(&self, group: u32, prev_block: u32) -> ::vfs::node::Result<Option<u32>> {
 		let first_bmp_block = self.group_descriptors.read()[group as usize].bg_block_bitmap;
+		let (s_first_data_block, s_blocks_per_group) = {
+			let sb = self.superblock.read();
+			(sb.data.s_first_data_block, sb.data.s_blocks_per_group)
+			};
+		let blocks_per_bmpblock = self.fs_block_size as u32 * 8;
 		// Prefer allocating within a few blocks of the previous (ideally right after) - if non zero
 		if prev_block != 0 {
 			let next_block = prev_block + 1;
@@ -404,9 +564,8 @@ impl InstanceInner
 			if block_bg == group {
 				// Edit the bitmap, check if this bit is clear
 				let bmp_mask = 1 << (block_subidx % 8);
-				let bmp_byte = block_subidx / 8;
-				let bmp_block = bmp_byte / self.fs_block_size as u32;
-				let bmp_byte = bmp_byte % self.fs_block_size as u32;
+				let bmp_block = block_subidx / blocks_per_bmpblock;
+				let bmp_byte = (block_subidx % blocks_per_bmpblock) / 8;
 				// If it is, then set it and decrement the (non-zero) free block count
 				if self.edit_block(first_bmp_block + bmp_block, |blk_data| {
 					if blk_data[bmp_byte as usize] & bmp_mask == 0 {
@@ -427,19 +586,21 @@ impl InstanceInner
 		}
 
 		// Decrement the block count, and then find an entry
-		if !self.edit_block_group_header(group, |bg| if bg.bg_free_inodes_count == 0 { false } else { bg.bg_free_blocks_count -= 1; true })?
+		// NOTE: Check with read-only first, and only read-modify-write if the read-only check passed
+		if self.group_descriptors.read()[group as usize].bg_free_blocks_count == 0 {
+			return Ok(None);
+		}
+		if !self.edit_block_group_header(group, |bg| if bg.bg_free_blocks_count == 0 { false } else { bg.bg_free_blocks_count -= 1; true })?
 		{
 			return Ok(None);
 		}
 
 		// Iterate the bitmap
-		let blocks_per_bmpblock = self.fs_block_size * 8;
-		let s_blocks_per_group = self.superblock.read().data.s_blocks_per_group;
-		for base in (0 .. s_blocks_per_group).step_by(blocks_per_bmpblock) {
-			// Number of inodes in this bitmap block (might be fewer, if the group size is small)
-			let n_blocks = (s_blocks_per_group - base).min(blocks_per_bmpblock as u32);
+		for base in (0 .. s_blocks_per_group).step_by(blocks_per_bmpblock as usize) {
+			// Number of blocks in this bitmap block (might be fewer, if the group size is small)
+			let n_blocks = (s_blocks_per_group - base).min(blocks_per_bmpblock);
 			let n_bytes = ::kernel::lib::num::div_up(n_blocks, 8) as usize;
-			let rv = self.edit_block(first_bmp_block + base / blocks_per_bmpblock as u32, |blk_data| {
+			let rv = self.edit_block(first_bmp_block + base / blocks_per_bmpblock, |blk_data| {
 				Ok(match blk_data[..n_bytes].iter().position(|&v| v != !0)
 				{
 				None => None,
@@ -455,7 +616,7 @@ impl InstanceInner
 				if rel_block_id >= n_blocks {
 					break
 				}
-				let rv = group * s_blocks_per_group + base + rel_block_id;
+				let rv = s_first_data_block + group * s_blocks_per_group + base + rel_block_id;
 				log_debug!("allocate_block_in_bg({}) Allocate B{}", group, rv);
 				return Ok(Some(rv));
 			}
@@ -463,6 +624,56 @@ impl InstanceInner
 		log_error!("allocate_block_in_group: Descriptor said that there were free blocks, but bitmap was full.");
 		Err(::vfs::Error::InconsistentFilesystem)
 	}
+
+	/// Release a single data block
+	pub fn free_data_block(&self, block: u32) -> ::vfs::node::Result<()> {
+		self.free_blocks(block, 1)
+	}
+	/// Release a run of data blocks
+	pub fn free_blocks(&self, first_block: u32, count: u32) -> ::vfs::node::Result<()> {
+		log_debug!("free_blocks(B{}+{})", first_block, count);
+		let s_blocks_per_group = self.superblock.read().data.s_blocks_per_group;
+		let end = first_block + count;
+		let mut block = first_block;
+		while block < end
+		{
+			let (group, idx) = self.get_block_grp_id(block);
+			let n = u32::min(end - block, s_blocks_per_group - idx);
+			let first_bmp_block = self.group_descriptors.read()[group as usize].bg_block_bitmap;
+			let n_freed = self.clear_bitmap_range(first_bmp_block, idx, n)?;
+			if n_freed != n {
+				log_warning!("free_blocks: {} of B{}+{} were already free", n - n_freed, block, n);
+			}
+			self.edit_block_group_header(group, |bg| bg.bg_free_blocks_count += n_freed as u16)?;
+			self.update_sb_free_blocks(n_freed as i64)?;
+			block += n;
+		}
+		Ok( () )
+	}
+
+	/// Clear a range of bits in an allocation bitmap, returning the number that were set
+	fn clear_bitmap_range(&self, first_bmp_block: u32, first: u32, count: u32) -> ::vfs::node::Result<u32> {
+		let bits_per_bmpblock = self.fs_block_size as u32 * 8;
+		let mut rv = 0;
+		let mut bit = first;
+		while bit < first + count
+		{
+			let n = u32::min(first + count - bit, bits_per_bmpblock - bit % bits_per_bmpblock);
+			rv += self.edit_block(first_bmp_block + bit / bits_per_bmpblock, |blk_data| {
+				let mut n_cleared = 0;
+				for i in bit % bits_per_bmpblock .. bit % bits_per_bmpblock + n {
+					let mask = 1 << (i % 8);
+					if blk_data[(i / 8) as usize] & mask != 0 {
+						blk_data[(i / 8) as usize] &= !mask;
+						n_cleared += 1;
+					}
+				}
+				Ok(n_cleared)
+				})?;
+			bit += n;
+		}
+		Ok(rv)
+	}
 }
 
 impl InstanceInner
@@ -471,26 +682,26 @@ impl InstanceInner
 		let mut lh = self.superblock.write();
 		let rv = cb(&mut lh);
 		if self.vol.block_size() > 1024 {
-			::kernel::futures::block_on(self.vol.edit(0, 1, |data| {
+			self.edit_metadata(0, 1, |data| {
 				let data = &mut data[1024..][..1024];
 				lh.write_to_slice(data);
-				}))?;
+				})?;
 		}
 		else {
-			::kernel::futures::block_on(self.vol.edit(1024 / self.vol.block_size() as u64, 1024 / self.vol.block_size(), |data| {
+			self.edit_metadata(1024 / self.vol.block_size() as u64, 1024 / self.vol.block_size(), |data| {
 				lh.write_to_slice(data);
-				}))?;
+				})?;
 		}
 		Ok(rv)
 	}
 	fn edit_block_group_header<R>(&self, idx: u32, cb: impl FnOnce(&mut crate::ondisk::GroupDesc)->R) -> ::vfs::node::Result<R> {
 		let mut lh = self.group_descriptors.write();
 		let rv = cb(&mut lh[idx as usize]);
-		let ofs = 1024 + idx as usize * ::core::mem::size_of::<::ondisk::GroupDesc>();
-		::kernel::futures::block_on(self.vol.edit( (ofs / self.vol.block_size()) as u64, 1, |data| {
+		let ofs = self.gdt_offset + idx as usize * ::core::mem::size_of::<::ondisk::GroupDesc>();
+		self.edit_metadata( (ofs / self.vol.block_size()) as u64, 1, |data| {
 			let buf = &mut data[ofs % self.vol.block_size()..][..::core::mem::size_of::<::ondisk::GroupDesc>()];
 			lh[idx as usize].write_to_slice(buf);
-			}))?;
+			})?;
 		Ok(rv)
 	}
 }
@@ -523,22 +734,34 @@ impl InstanceInner
 	where
 		F: FnOnce(&::inodes::Inode) -> ::vfs::node::Result<R>
 	{
-		// TODO: Hook into the VFS's node cache somehow (we'd need to know our mount ID) and
-		//       obtain a reference to a cached inode.
-		// - This prevents us from having to maintain our own node cache
+		// NOTE: Goes via the VFS's node cache, so the inode is shared with any open handles
 
 		let node = try!(self.mount_handle.get_node(inode_num as ::vfs::node::InodeId));
-		match node.get_node_any().downcast_ref()
-		{
-		Some(our_in) => fcn(our_in),
-		None => Err(::vfs::Error::Unknown("BUG: Node wasn't an extN inode")),
+		let any = node.get_node_any();
+		if let Some(f) = any.downcast_ref::<::file::File>() {
+			fcn(f.inode())
+		}
+		else if let Some(d) = any.downcast_ref::<::dir::Dir>() {
+			fcn(d.inode())
+		}
+		else {
+			Err(::vfs::Error::Unknown("BUG: Node wasn't an extN inode"))
 		}
 	}
 
 	/// Allocate a new inode number, possibly in the same block group as `parent_inode_num`.
+	///
+	/// The new inode has a link count of one (the caller is expected to link it into a directory)
 	pub fn allocate_inode(&self, parent_inode_num: u32, nodetype: ::vfs::node::NodeType) -> ::vfs::node::Result< u32 >
 	{
-		// TODO: Update the superblock
+		let i_mode = match nodetype
+			{
+			::vfs::node::NodeType::File => ::ondisk::S_IFREG | 0o644,
+			::vfs::node::NodeType::Dir => ::ondisk::S_IFDIR | 0o755,
+			::vfs::node::NodeType::Symlink(_) => return Err(::vfs::Error::Unknown("extN: Symbolic links not yet supported")),
+			};
+		let is_dir = i_mode & ::ondisk::S_IFMT == ::ondisk::S_IFDIR;
+
 		let has_inodes = self.edit_superblock(|sb| {
 			if sb.data.s_free_inodes_count == 0 {
 				false
@@ -555,25 +778,39 @@ impl InstanceInner
 		assert!(parent_inode_num != 0);	// Has to be a parent - root exists
 		let (grp, _idx) = self.get_inode_grp_id(parent_inode_num);
 
-		let rv = if let Some(rv) = self.allocate_inode_in_bg(grp, nodetype)? {
-				rv
+		// Prefer the parent's group, then search the following groups
+		let num_groups = self.num_groups();
+		let mut rv = None;
+		for i in 0 .. num_groups {
+			if let Some(v) = self.allocate_inode_in_bg((grp + i) % num_groups, is_dir)? {
+				rv = Some(v);
+				break;
 			}
-			else {
-				todo!("InstanceInner::allocate_inode - Search for any BG");
+		}
+		let rv = match rv
+			{
+			Some(v) => v,
+			None => {
+				log_warning!("allocate_inode: Superblock said that there were free inodes, but no group has any");
+				self.edit_superblock(|sb| sb.data.s_free_inodes_count += 1)?;
+				return Err(::vfs::Error::OutOfSpace);
+				},
 			};
 
-		self.write_inode(rv, &crate::ondisk::Inode {
-			i_mode: match nodetype
-				{
-				::vfs::node::NodeType::File => ::ondisk::S_IFREG,
-				_ => todo!(""),
-				},
+		let mut inode = crate::ondisk::Inode {
+			i_mode: i_mode,
+			i_links_count: 1,
 			..Default::default()
-			})?;
+			};
+		if self.has_feature_incompat(crate::ondisk::FEAT_INCOMPAT_EXTENTS) {
+			inode.i_flags |= crate::ondisk::EXT4_EXTENTS_FL;
+			::extents::init_root(&mut inode.i_block);
+		}
+		self.init_inode(rv, &inode)?;
 
 		Ok(rv)
 	}
-	fn allocate_inode_in_bg(&self, grp: u32, _nodetype: ::vfs::node::NodeType) -> ::vfs::node::Result< Option<u32> > {
+	fn allocate_inode_in_bg(&self, grp: u32, is_dir: bool) -> ::vfs::node::Result< Option<u32> > {
 		// NOTE: Check with read-only first, and only read-modify-write if the read-only check passed
 		if self.group_descriptors.read()[grp as usize].bg_free_inodes_count == 0 {
 			return Ok(None);
@@ -585,6 +822,9 @@ impl InstanceInner
 			}
 			else {
 				gd.bg_free_inodes_count -= 1;
+				if is_dir {
+					gd.bg_used_dirs_count += 1;
+				}
 				Some(gd.bg_inode_bitmap)
 			})? else {
 			return Ok(None);
@@ -651,13 +891,56 @@ impl InstanceInner
 		let (vol_block, blk_ofs) = self.get_inode_pos(inode_num);
 
 		let s_inode_size = self.superblock.read().s_inode_size();
-		::kernel::futures::block_on(self.vol.edit(vol_block, 1, |data| {
+		self.edit_metadata(vol_block, 1, |data| {
 			let mut slice = &mut data[blk_ofs..][..s_inode_size];
 			let _ = ::kernel::lib::byteorder::EncodedLE::encode(inode_data, &mut slice);
-			}))?;
+			})?;
+
+		Ok( () )
+	}
+	/// Write a freshly allocated inode, clearing the rest of the on-disk slot
+	fn init_inode(&self, inode_num: u32, inode_data: &::ondisk::Inode) -> ::vfs::Result< () >
+	{
+		let (vol_block, blk_ofs) = self.get_inode_pos(inode_num);
+
+		let s_inode_size = self.superblock.read().s_inode_size();
+		self.edit_metadata(vol_block, 1, |data| {
+			let slot = &mut data[blk_ofs..][..s_inode_size];
+			for b in slot.iter_mut() {
+				*b = 0;
+			}
+			// Large inodes: `i_extra_isize` covers the (zeroed) standard extra fields
+			const BASE_SIZE: usize = ::core::mem::size_of::<::ondisk::Inode>();
+			const EXTRA_SIZE: usize = ::core::mem::size_of::<::ondisk::InodeExtra>();
+			if s_inode_size >= BASE_SIZE + EXTRA_SIZE {
+				slot[BASE_SIZE..][..2].copy_from_slice(&(EXTRA_SIZE as u16).to_le_bytes());
+			}
+			let mut slice = &mut slot[..];
+			let _ = ::kernel::lib::byteorder::EncodedLE::encode(inode_data, &mut slice);
+			})?;
 
 		Ok( () )
 	}
+
+	/// Release an inode number (once its data blocks have been released)
+	pub fn free_inode(&self, inode_num: u32, is_dir: bool) -> ::vfs::node::Result<()>
+	{
+		log_debug!("free_inode(I{})", inode_num);
+		let (grp, idx) = self.get_inode_grp_id(inode_num);
+		let first_bmp_block = self.group_descriptors.read()[grp as usize].bg_inode_bitmap;
+		if self.clear_bitmap_range(first_bmp_block, idx, 1)? == 0 {
+			log_warning!("free_inode: I{} was already free", inode_num);
+			return Ok( () );
+		}
+		self.edit_block_group_header(grp, |gd| {
+			gd.bg_free_inodes_count += 1;
+			if is_dir {
+				gd.bg_used_dirs_count = gd.bg_used_dirs_count.saturating_sub(1);
+			}
+			})?;
+		self.edit_superblock(|sb| sb.data.s_free_inodes_count += 1)?;
+		Ok( () )
+	}
 }
 
 /// Superblock parameters
@@ -667,13 +950,21 @@ impl InstanceInner
 		(self.fs_block_size / self.vol.block_size()) as u64
 	}
 
-	#[allow(dead_code)]
+	pub fn has_feature_compat(&self, feat: u32) -> bool {
+		self.superblock.read().has_feature_compat(feat)
+	}
 	pub fn has_feature_incompat(&self, feat: u32) -> bool {
 		self.superblock.read().has_feature_incompat(feat)
 	}
 	pub fn has_feature_ro_compat(&self, feat: u32) -> bool {
 		self.superblock.read().has_feature_ro_compat(feat)
 	}
+
+	/// Directory hash seed, and if names are hashed as unsigned characters
+	pub fn dir_hash_params(&self) -> ([u32; 4], bool) {
+		let sb = self.superblock.read();
+		(sb.ext.s_hash_seed, sb.ext.s_flags & ::ondisk::EXT2_FLAGS_UNSIGNED_HASH != 0)
+	}
 }
 
 
@@ -0,0 +1,532 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_extN/journal.rs
+//! JBD2 journal (ext3/ext4 FEAT_COMPAT_HAS_JOURNAL)
+//!
+//! A dirty journal is replayed at mount. After that, metadata edits made while a transaction handle is open are
+//! held in the block cache, and are logged as whole-block images when the last handle is closed. Each transaction
+//! is checkpointed (written to its home location) straight after its commit block, so the log never holds more
+//! than one transaction and is marked empty between transactions.
+use kernel::prelude::*;
+use kernel::lib::VecMap;
+use instance::InstanceInner;
+use ondisk::{JournalHeader,JournalSuperblock};
+
+/// Incompatible features that don't stop the log from being replayed (checksums aren't verified)
+const REPLAY_INCOMPAT_FEATURES: u32 = 0
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_REVOKE
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_64BIT
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V2
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V3
+	;
+/// Incompatible features that can be used when writing transactions
+const WRITE_INCOMPAT_FEATURES: u32 = 0
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_REVOKE
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_64BIT
+	| ::ondisk::JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT
+	;
+const HEADER_SIZE: usize = 12;
+const UUID_SIZE: usize = 16;
+
+pub struct Journal
+{
+	/// Location of the journal inode's data: (first log block, first filesystem block, block count)
+	extents: Vec<(u32, u32, u32)>,
+	block_size: usize,
+	/// First log block (after the journal superblock)
+	first: u32,
+	/// Number of blocks in the journal (log blocks wrap from here back to `first`)
+	maxlen: u32,
+	feature_compat: u32,
+	feature_incompat: u32,
+	/// Largest number of blocks that fit in a single transaction
+	max_transaction: usize,
+
+	state: ::kernel::sync::Mutex<State>,
+}
+struct State
+{
+	sb: JournalSuperblock,
+	/// Number of open handles on the running transaction
+	handles: usize,
+	/// Filesystem blocks changed by the running transaction (sorted, each holds off writeback of the block)
+	blocks: Vec<u32>,
+	/// Set if a commit failed, no further transactions are started
+	is_aborted: bool,
+}
+
+/// Handle on the running transaction, the transaction is committed once all handles are dropped
+pub struct Handle<'a>(Option<(&'a Journal, &'a InstanceInner)>);
+
+struct Tag
+{
+	block: u64,
+	flags: u32,
+}
+
+#[derive(PartialEq,Debug)]
+enum Pass
+{
+	/// Locate the end of the log
+	Scan,
+	/// Collect revoke records
+	Revoke,
+	/// Write logged blocks to their home locations
+	Replay,
+}
+
+impl Journal
+{
+	/// Load the journal superblock and location from the journal inode
+	pub fn load(fs: &InstanceInner, inode_num: u32) -> ::vfs::Result<Journal>
+	{
+		let inode = fs.read_inode(inode_num)?;
+		let n_blocks = inode.max_blocks(fs);
+
+		let mut extents = Vec::new();
+		let mut block = 0;
+		while block < n_blocks
+		{
+			let (phys, count) = inode.get_extent_from_block(fs, block, n_blocks - block)?;
+			if phys == 0 || count == 0 {
+				log_error!("Journal inode I{} has a hole at block {}", inode_num, block);
+				return Err(::vfs::Error::InconsistentFilesystem);
+			}
+			extents.push( (block, phys, count) );
+			block += count;
+		}
+		if extents.is_empty() {
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+
+		let mut buf = vec![0; fs.fs_block_size];
+		fs.read_blocks(extents[0].1, &mut buf)?;
+		let sb = JournalSuperblock::from_slice(&buf[..1024]);
+		log_debug!("Journal superblock: {:?}", sb);
+		if sb.s_header.h_magic != ::ondisk::JBD2_MAGIC {
+			log_error!("Journal superblock has a bad magic number ({:#x})", sb.s_header.h_magic);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		let (feature_compat, feature_incompat) = match sb.s_header.h_blocktype
+			{
+			::ondisk::JBD2_SUPERBLOCK_V1 => (0, 0),
+			::ondisk::JBD2_SUPERBLOCK_V2 => (sb.s_feature_compat, sb.s_feature_incompat),
+			v => {
+				log_error!("Journal superblock has an unknown type {}", v);
+				return Err(::vfs::Error::InconsistentFilesystem);
+				},
+			};
+		if sb.s_blocksize as usize != fs.fs_block_size || sb.s_maxlen > n_blocks || sb.s_first == 0 || sb.s_first >= sb.s_maxlen {
+			log_error!("Journal superblock is inconsistent (blocksize={}, first={}, maxlen={}, inode has {} blocks)",
+				sb.s_blocksize, sb.s_first, sb.s_maxlen, n_blocks);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+
+		let mut rv = Journal {
+			extents: extents,
+			block_size: fs.fs_block_size,
+			first: sb.s_first,
+			maxlen: sb.s_maxlen,
+			feature_compat: feature_compat,
+			feature_incompat: feature_incompat,
+			max_transaction: 0,
+			state: ::kernel::sync::Mutex::new(State {
+				sb: sb,
+				handles: 0,
+				blocks: Vec::new(),
+				is_aborted: false,
+				}),
+			};
+		// Each transaction needs its block images, descriptor blocks, and a commit block
+		let tags_per_desc = rv.tags_per_descriptor();
+		let log_len = (rv.maxlen - rv.first) as usize;
+		rv.max_transaction = ((log_len - 1) * tags_per_desc / (tags_per_desc + 1)).saturating_sub(1);
+		Ok(rv)
+	}
+
+	/// Returns true if the log contains transactions that haven't been checkpointed
+	pub fn needs_recovery(&self) -> bool
+	{
+		self.state.lock().sb.s_start != 0
+	}
+	/// Returns true if this driver can write transactions to this journal
+	pub fn supports_writes(&self) -> bool
+	{
+		// Checksums (CRC32 commit checksums, or CRC32C checksums) aren't generated
+		self.feature_compat & ::ondisk::JBD2_FEATURE_COMPAT_CHECKSUM == 0
+			&& self.feature_incompat & !WRITE_INCOMPAT_FEATURES == 0
+	}
+
+	/// Replay all committed transactions in the log, then mark the journal as empty
+	pub fn replay(&self, fs: &InstanceInner) -> ::vfs::Result<()>
+	{
+		let mut lh = self.state.lock();
+		if lh.sb.s_start == 0 {
+			return Ok( () );
+		}
+		if self.feature_incompat & !REPLAY_INCOMPAT_FEATURES != 0 {
+			log_error!("Journal needs recovery, but uses unsupported features {:#x}", self.feature_incompat & !REPLAY_INCOMPAT_FEATURES);
+			return Err(::vfs::Error::Unknown("extN: Journal needs recovery, but uses unsupported features"));
+		}
+		if self.feature_incompat & (::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V2|::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V3) != 0 {
+			log_notice!("Journal uses checksums, these are not verified during replay");
+		}
+
+		// Three passes (as with other implementations), so revoke records apply to all earlier transactions
+		let mut revoked = VecMap::new();
+		let (end_seq, _) = self.do_pass(fs, &lh.sb, Pass::Scan, 0, &mut revoked)?;
+		if end_seq == lh.sb.s_sequence {
+			log_notice!("Journal recovery: no complete transactions in the log");
+		}
+		else {
+			self.do_pass(fs, &lh.sb, Pass::Revoke, end_seq, &mut revoked)?;
+			let (_, n_blocks) = self.do_pass(fs, &lh.sb, Pass::Replay, end_seq, &mut revoked)?;
+			log_notice!("Journal recovery: replayed transactions {}-{} ({} blocks, {} revoked)",
+				lh.sb.s_sequence, end_seq.wrapping_sub(1), n_blocks, revoked.iter().count());
+		}
+
+		// Mark the journal empty, starting after any (uncommitted) sequence numbers in the log
+		lh.sb.s_start = 0;
+		lh.sb.s_sequence = end_seq.wrapping_add(1);
+		self.write_superblock(fs, &lh.sb)?;
+		Ok( () )
+	}
+
+	/// Start (or join) the running transaction
+	pub fn start<'a>(&'a self, fs: &'a InstanceInner) -> ::vfs::node::Result<Handle<'a>>
+	{
+		let mut lh = self.state.lock();
+		if lh.is_aborted {
+			return Err(::vfs::Error::ReadOnlyFilesystem);
+		}
+		lh.handles += 1;
+		Ok(Handle(Some( (self, fs) )))
+	}
+
+	/// Add a filesystem block to the running transaction (called before the block is edited)
+	pub fn add_block(&self, fs: &InstanceInner, block: u32) -> ::vfs::node::Result<()>
+	{
+		let mut lh = self.state.lock();
+		assert!(lh.handles > 0, "Journal::add_block with no open handles");
+		if lh.blocks.binary_search(&block).is_ok() {
+			return Ok( () );
+		}
+		if lh.blocks.len() >= self.max_transaction {
+			// The operation is too large for a single transaction, commit what's there so far (losing atomicity)
+			log_notice!("Journal transaction {} is full ({} blocks), committing early", lh.sb.s_sequence, lh.blocks.len());
+			self.commit(fs, &mut lh)?;
+		}
+		fs.hold_block(block)?;
+		let pos = lh.blocks.binary_search(&block).unwrap_err();
+		lh.blocks.insert(pos, block);
+		Ok( () )
+	}
+
+	/// Write the running transaction to the log, then checkpoint it
+	fn commit(&self, fs: &InstanceInner, st: &mut State) -> ::vfs::node::Result<()>
+	{
+		let blocks = ::core::mem::replace(&mut st.blocks, Vec::new());
+		if blocks.is_empty() {
+			return Ok( () );
+		}
+		let seq = st.sb.s_sequence;
+		log_debug!("Journal commit: transaction {}, {} blocks", seq, blocks.len());
+		let rv = self.write_transaction(fs, &mut st.sb, seq, &blocks);
+
+		// Checkpoint: allow the edited blocks to reach their home locations
+		// - Even if the commit failed, as the edits have already been made in the cache
+		for &b in &blocks {
+			if let Err(e) = fs.release_block(b) {
+				log_error!("Journal checkpoint: error writing B{}: {:?}", b, e);
+				st.is_aborted = true;
+			}
+		}
+		if let Err(e) = rv {
+			log_error!("Journal commit of transaction {} failed: {:?}", seq, e);
+			st.is_aborted = true;
+			return Err(e);
+		}
+		if st.is_aborted {
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+
+		// The transaction is now on disk, so the log is empty again
+		st.sb.s_start = 0;
+		st.sb.s_sequence = seq.wrapping_add(1);
+		self.write_superblock(fs, &st.sb)
+	}
+
+	fn write_transaction(&self, fs: &InstanceInner, sb: &mut JournalSuperblock, seq: u32, blocks: &[u32]) -> ::vfs::node::Result<()>
+	{
+		// Point the journal at this transaction, so it's replayed if the checkpoint doesn't complete
+		sb.s_start = self.first;
+		sb.s_sequence = seq;
+		self.write_superblock(fs, sb)?;
+
+		let mut desc = vec![0u8; self.block_size];
+		let mut image = vec![0u8; self.block_size];
+		let mut pos = self.first;
+		for chunk in blocks.chunks(self.tags_per_descriptor())
+		{
+			for b in desc.iter_mut() {
+				*b = 0;
+			}
+			JournalHeader { h_magic: ::ondisk::JBD2_MAGIC, h_blocktype: ::ondisk::JBD2_DESCRIPTOR_BLOCK, h_sequence: seq }.write_to_slice(&mut desc);
+			let desc_pos = pos;
+			pos = self.next_log(pos);
+
+			let mut ofs = HEADER_SIZE;
+			for (i, &block) in chunk.iter().enumerate()
+			{
+				fs.read_blocks(block, &mut image)?;
+				let mut flags = 0;
+				// Blocks that look like journal blocks are escaped (the magic is restored on replay)
+				if be32(&image, 0) == ::ondisk::JBD2_MAGIC {
+					flags |= ::ondisk::JBD2_FLAG_ESCAPE;
+					image[..4].copy_from_slice(&[0; 4]);
+				}
+				if i > 0 {
+					flags |= ::ondisk::JBD2_FLAG_SAME_UUID;
+				}
+				if i == chunk.len() - 1 {
+					flags |= ::ondisk::JBD2_FLAG_LAST_TAG;
+				}
+				self.write_tag(&mut desc[ofs..], block, flags);
+				ofs += self.tag_size();
+				if i == 0 {
+					desc[ofs..][..UUID_SIZE].copy_from_slice(&sb.s_uuid);
+					ofs += UUID_SIZE;
+				}
+				self.write_log(fs, pos, &image)?;
+				pos = self.next_log(pos);
+			}
+			self.write_log(fs, desc_pos, &desc)?;
+		}
+
+		// Commit block, written once everything else is in the log
+		for b in desc.iter_mut() {
+			*b = 0;
+		}
+		JournalHeader { h_magic: ::ondisk::JBD2_MAGIC, h_blocktype: ::ondisk::JBD2_COMMIT_BLOCK, h_sequence: seq }.write_to_slice(&mut desc);
+		self.write_log(fs, pos, &desc)
+	}
+
+	/// Walk the log from its start, returning the sequence number after the last complete transaction and the number of blocks replayed
+	fn do_pass(&self, fs: &InstanceInner, sb: &JournalSuperblock, pass: Pass, end_seq: u32, revoked: &mut VecMap<u64, u32>) -> ::vfs::Result<(u32, usize)>
+	{
+		let mut buf = vec![0u8; self.block_size];
+		let mut data = vec![0u8; self.block_size];
+		let mut seq = sb.s_sequence;
+		let mut pos = sb.s_start;
+		let mut n_replayed = 0;
+		// NOTE: Bounded by the log size, in case of a corrupted log
+		for _ in 0 .. self.maxlen
+		{
+			if pass != Pass::Scan && seq == end_seq {
+				break;
+			}
+			self.read_log(fs, pos, &mut buf)?;
+			let hdr = JournalHeader::from_slice(&buf);
+			if hdr.h_magic != ::ondisk::JBD2_MAGIC || hdr.h_sequence != seq {
+				if pass != Pass::Scan {
+					log_error!("Journal {:?} pass: log ended early at block {} (transaction {})", pass, pos, seq);
+					return Err(::vfs::Error::InconsistentFilesystem);
+				}
+				break;
+			}
+			pos = self.next_log(pos);
+			match hdr.h_blocktype
+			{
+			::ondisk::JBD2_DESCRIPTOR_BLOCK => {
+				for tag in self.parse_tags(&buf)
+				{
+					if pass == Pass::Replay && !is_revoked(revoked, tag.block, seq) {
+						if tag.block > u32::MAX as u64 {
+							log_error!("Journal replay: block {:#x} is out of range", tag.block);
+							return Err(::vfs::Error::InconsistentFilesystem);
+						}
+						self.read_log(fs, pos, &mut data)?;
+						if tag.flags & ::ondisk::JBD2_FLAG_ESCAPE != 0 {
+							data[..4].copy_from_slice(&::ondisk::JBD2_MAGIC.to_be_bytes());
+						}
+						log_trace!("Journal replay: transaction {}, B{}", seq, tag.block);
+						fs.write_blocks(tag.block as u32, &data)?;
+						n_replayed += 1;
+					}
+					pos = self.next_log(pos);
+				}
+				},
+			::ondisk::JBD2_COMMIT_BLOCK => {
+				seq = seq.wrapping_add(1);
+				},
+			::ondisk::JBD2_REVOKE_BLOCK => {
+				if pass == Pass::Revoke {
+					self.parse_revoke(&buf, seq, revoked);
+				}
+				},
+			v => {
+				log_notice!("Journal {:?} pass: unexpected block type {} at block {}", pass, v, pos);
+				if pass != Pass::Scan {
+					return Err(::vfs::Error::InconsistentFilesystem);
+				}
+				break;
+				},
+			}
+		}
+		Ok( (seq, n_replayed) )
+	}
+
+	fn parse_tags(&self, buf: &[u8]) -> Vec<Tag>
+	{
+		let is_64bit = self.feature_incompat & ::ondisk::JBD2_FEATURE_INCOMPAT_64BIT != 0;
+		let is_csum_v3 = self.feature_incompat & ::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V3 != 0;
+		let end = self.block_size - self.tail_size();
+		let mut rv = Vec::new();
+		let mut ofs = HEADER_SIZE;
+		while ofs + self.tag_size() <= end
+		{
+			let t = &buf[ofs..];
+			let (block_lo, flags) = if is_csum_v3 {
+					(be32(t, 0), be32(t, 4))
+				}
+				else {
+					(be32(t, 0), be16(t, 6) as u32)
+				};
+			let block_hi = if is_64bit { be32(t, 8) } else { 0 };
+			rv.push(Tag { block: (block_hi as u64) << 32 | block_lo as u64, flags: flags });
+			ofs += self.tag_size();
+			if flags & ::ondisk::JBD2_FLAG_SAME_UUID == 0 {
+				ofs += UUID_SIZE;
+			}
+			if flags & ::ondisk::JBD2_FLAG_LAST_TAG != 0 {
+				break;
+			}
+		}
+		rv
+	}
+	fn write_tag(&self, dst: &mut [u8], block: u32, flags: u32)
+	{
+		// NOTE: Only the un-checksummed formats are written (see `supports_writes`)
+		dst[0..4].copy_from_slice(&block.to_be_bytes());
+		dst[6..8].copy_from_slice(&(flags as u16).to_be_bytes());
+	}
+	fn parse_revoke(&self, buf: &[u8], seq: u32, revoked: &mut VecMap<u64, u32>)
+	{
+		let rec_size = if self.feature_incompat & ::ondisk::JBD2_FEATURE_INCOMPAT_64BIT != 0 { 8 } else { 4 };
+		let count = usize::min(be32(buf, HEADER_SIZE) as usize, self.block_size - self.tail_size());
+		let mut ofs = HEADER_SIZE + 4;
+		while ofs + rec_size <= count
+		{
+			let block = if rec_size == 8 {
+					(be32(buf, ofs) as u64) << 32 | be32(buf, ofs+4) as u64
+				}
+				else {
+					be32(buf, ofs) as u64
+				};
+			let e = revoked.entry(block).or_insert(seq);
+			if tid_gt(seq, *e) {
+				*e = seq;
+			}
+			ofs += rec_size;
+		}
+	}
+
+	fn tag_size(&self) -> usize
+	{
+		if self.feature_incompat & ::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V3 != 0 {
+			16
+		}
+		else {
+			// Block number, checksum (16-bit), and flags (16-bit), plus the high block number bits
+			let sz = if self.feature_incompat & ::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V2 != 0 { 14 } else { 12 };
+			if self.feature_incompat & ::ondisk::JBD2_FEATURE_INCOMPAT_64BIT != 0 { sz } else { sz - 4 }
+		}
+	}
+	/// Size of the checksum tail on descriptor and revoke blocks
+	fn tail_size(&self) -> usize
+	{
+		if self.feature_incompat & (::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V2|::ondisk::JBD2_FEATURE_INCOMPAT_CSUM_V3) != 0 { 4 } else { 0 }
+	}
+	fn tags_per_descriptor(&self) -> usize
+	{
+		(self.block_size - HEADER_SIZE - UUID_SIZE - self.tail_size()) / self.tag_size()
+	}
+
+	fn next_log(&self, pos: u32) -> u32
+	{
+		if pos + 1 >= self.maxlen { self.first } else { pos + 1 }
+	}
+	fn log_to_fs(&self, pos: u32) -> ::vfs::node::Result<u32>
+	{
+		for &(log, phys, count) in &self.extents
+		{
+			if log <= pos && pos < log + count {
+				return Ok(phys + (pos - log));
+			}
+		}
+		log_error!("Journal block {} is outside the journal inode", pos);
+		Err(::vfs::Error::InconsistentFilesystem)
+	}
+	fn read_log(&self, fs: &InstanceInner, pos: u32, buf: &mut [u8]) -> ::vfs::node::Result<()>
+	{
+		fs.read_blocks(self.log_to_fs(pos)?, buf)
+	}
+	fn write_log(&self, fs: &InstanceInner, pos: u32, buf: &[u8]) -> ::vfs::node::Result<()>
+	{
+		fs.write_blocks(self.log_to_fs(pos)?, buf)
+	}
+	fn write_superblock(&self, fs: &InstanceInner, sb: &JournalSuperblock) -> ::vfs::node::Result<()>
+	{
+		let mut buf = vec![0; self.block_size];
+		self.read_log(fs, 0, &mut buf)?;
+		sb.write_to_slice(&mut buf[..1024]);
+		self.write_log(fs, 0, &buf)
+	}
+}
+
+impl<'a> Handle<'a>
+{
+	/// A handle for a filesystem without a journal
+	pub fn none() -> Handle<'a> {
+		Handle(None)
+	}
+}
+impl<'a> Drop for Handle<'a>
+{
+	fn drop(&mut self)
+	{
+		if let Some((journal, fs)) = self.0
+		{
+			let mut lh = journal.state.lock();
+			lh.handles -= 1;
+			if lh.handles == 0 {
+				// Errors are logged (and abort the journal) within `commit`
+				let _ = journal.commit(fs, &mut lh);
+			}
+		}
+	}
+}
+
+fn is_revoked(revoked: &VecMap<u64, u32>, block: u64, seq: u32) -> bool
+{
+	match revoked.get(&block)
+	{
+	Some(&rseq) => !tid_gt(seq, rseq),
+	None => false,
+	}
+}
+/// Compare transaction IDs (allowing for wraparound)
+fn tid_gt(a: u32, b: u32) -> bool
+{
+	(a.wrapping_sub(b) as i32) > 0
+}
+fn be32(d: &[u8], ofs: usize) -> u32
+{
+	u32::from_be_bytes([d[ofs], d[ofs+1], d[ofs+2], d[ofs+3]])
+}
+fn be16(d: &[u8], ofs: usize) -> u16
+{
+	u16::from_be_bytes([d[ofs], d[ofs+1]])
+}
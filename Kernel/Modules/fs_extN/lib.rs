@@ -21,6 +21,9 @@ module_define!{FS_EXTN, [VFS], init}
 
 mod ondisk;
 mod inodes;
+mod extents;
+mod journal;
+mod htree;
 
 mod dir;
 mod file;
@@ -33,17 +36,25 @@ fn init()
 
 /// Optional Features: Doesn't stop Read+Write, but might confuse other systems or be inefficient
 const SUPPORTED_OPT_FEATURES: u32 = 0
+	| ::ondisk::FEAT_COMPAT_HAS_JOURNAL	// Metadata journal (replayed at mount, and used for metadata writes)
 	| ::ondisk::FEAT_COMPAT_EXT_ATTR	// Extended attributes
 	| ::ondisk::FEAT_COMPAT_RESIZE_INODE	// Extra space was allocated for resizing the filesystem
+	| ::ondisk::FEAT_COMPAT_DIR_INDEX	// Hashed directories (B-tree index on name hashes)
 	;
 /// Read-only features: Missing features stop write support
 const SUPPORTED_RDO_FEATURES: u32 = 0
 	| ::ondisk::FEAT_RO_COMPAT_SPARSE_SUPER	// Enables storing SB backups at group 0, 3^n, 5^n, and 7^n
 	| ::ondisk::FEAT_RO_COMPAT_LARGE_FILE	// 64-bit file sizes (in a separate inode field)
+	| ::ondisk::FEAT_RO_COMPAT_HUGE_FILE	// 48-bit block counts
+	| ::ondisk::FEAT_RO_COMPAT_DIR_NLINK	// Directory link counts saturate at 1
+	| ::ondisk::FEAT_RO_COMPAT_EXTRA_ISIZE	// Large inodes reserve space for extra fields
 	;
 /// Required Features: Missing features prevent mounting
 const SUPPORTED_REQ_FEATURES: u32 = 0
 	| ::ondisk::FEAT_INCOMPAT_FILETYPE	// DirEnt.d_name_len restricted to 1 byte and extra byte used for file type
+	| ::ondisk::FEAT_INCOMPAT_RECOVER	// Journal needs recovery (replayed at mount)
+	| ::ondisk::FEAT_INCOMPAT_EXTENTS	// Files mapped using extent trees (ext4)
+	| ::ondisk::FEAT_INCOMPAT_FLEX_BG	// Group metadata may be outside its group
 	;
 
 static S_DRIVER: Driver = Driver;
@@ -80,8 +91,8 @@ impl vfs::mount::Driver for Driver
 			Ok(0)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, mounthandle: vfs::mount::SelfHandle) -> vfs::Result<Box<dyn vfs::mount::Filesystem>> {
-		Ok( try!(instance::Instance::new_boxed(vol, mounthandle)) )
+	fn mount(&self, vol: VolumeHandle, read_only: bool, mounthandle: vfs::mount::SelfHandle) -> vfs::Result<Box<dyn vfs::mount::Filesystem>> {
+		Ok( try!(instance::Instance::new_boxed(vol, read_only, mounthandle)) )
 	}
 }
 
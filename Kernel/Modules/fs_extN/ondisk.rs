@@ -44,6 +44,9 @@ impl Superblock
 		}
 	}
 
+	pub fn has_feature_compat(&self, feat: u32) -> bool {
+		self.data.s_rev_level > 0 && self.ext.s_feature_compat & feat != 0
+	}
 	pub fn has_feature_incompat(&self, feat: u32) -> bool {
 		self.data.s_rev_level > 0 && self.ext.s_feature_incompat & feat != 0
 	}
@@ -260,6 +263,10 @@ pub const FEAT_COMPAT_EXCLUDE_INODE: u32 = 1 << 7;
 pub const FEAT_COMPAT_EXCLUDE_BITMAP:u32 = 1 << 8;
 pub const FEAT_COMPAT_SPARSE_SUPER2: u32 = 1 << 9;
 
+// Superblock.s_flags values
+pub const EXT2_FLAGS_SIGNED_HASH: u32 = 0x1;	// Directory hashes treat names as signed chars
+pub const EXT2_FLAGS_UNSIGNED_HASH: u32 = 0x2;	// Directory hashes treat names as unsigned chars
+
 #[repr(C)]
 #[derive(Debug,Default,::kernel_derives::EncodedLE)]
 pub struct Inode
@@ -326,6 +333,10 @@ pub const S_IWOTH: u16 =  0o002;	// Global Write
 pub const S_IXOTH: u16 =  0o001;	// Global Execute
 
 pub const EXT4_INDEX_FL: u16 = 0x1000;	// i_flags: Directory uses a hashed btree
+pub const EXT4_HUGE_FILE_FL: u32 = 0x40000;	// i_flags: `i_blocks` is in filesystem blocks (not sectors)
+pub const EXT4_EXTENTS_FL: u32 = 0x80000;	// i_flags: `i_block` holds an extent tree
+
+pub const EXT4_EXTENT_MAGIC: u16 = 0xF30A;	// Extent tree node header magic
 
 #[repr(C)]
 #[derive(Default,::kernel_derives::EncodedLE)]
@@ -370,6 +381,33 @@ pub struct DirEnt
 }
 pub const DIRENT_MIN_SIZE: usize = 8;
 
+// DirEnt.d_type values (FEAT_INCOMPAT_FILETYPE)
+pub const FT_UNKNOWN: u8 = 0;
+pub const FT_REG_FILE: u8 = 1;
+pub const FT_DIR: u8 = 2;
+pub const FT_SYMLINK: u8 = 7;
+
+// --------------------------------------------------------------------
+// Hashed directory index (HTree, EXT4_INDEX_FL)
+// --------------------------------------------------------------------
+// Block 0 of an indexed directory holds "." and ".." (the latter covering the rest of the block), followed by `dx_root_info`
+// and the root's entries. Interior nodes are a single empty entry covering the block, followed by their entries.
+// Entries are (hash: u32, block: u32) pairs, with the first entry's hash replaced by (limit: u16, count: u16).
+
+pub const DX_HASH_LEGACY: u8 = 0;
+pub const DX_HASH_HALF_MD4: u8 = 1;
+pub const DX_HASH_TEA: u8 = 2;
+/// Added to the hash version if the filesystem uses unsigned name hashes
+pub const DX_HASH_UNSIGNED_OFS: u8 = 3;
+
+/// Offset of `dx_root_info` in the root block (after "." and "..")
+pub const DX_ROOT_INFO_OFS: usize = 24;
+// dx_root_info: reserved_zero: u32, hash_version: u8, info_length: u8, indirect_levels: u8, unused_flags: u8
+pub const DX_ROOT_INFO_LEN: usize = 8;
+/// Offset of the entries in an interior node (after the empty entry header)
+pub const DX_NODE_ENTRIES_OFS: usize = 8;
+pub const DX_ENTRY_SIZE: usize = 8;
+
 impl DirEnt
 {
 	pub fn new_raw(buf: *mut [u32], name_len: usize) -> *mut DirEnt
@@ -427,3 +465,89 @@ impl_fmt! {
 			)
 	}
 }
+
+// --------------------------------------------------------------------
+// JBD2 journal structures (big-endian, unlike the rest of the filesystem)
+// --------------------------------------------------------------------
+
+pub const JBD2_MAGIC: u32 = 0xC03B3998;
+
+pub const JBD2_DESCRIPTOR_BLOCK: u32 = 1;
+pub const JBD2_COMMIT_BLOCK: u32 = 2;
+pub const JBD2_SUPERBLOCK_V1: u32 = 3;
+pub const JBD2_SUPERBLOCK_V2: u32 = 4;
+pub const JBD2_REVOKE_BLOCK: u32 = 5;
+
+pub const JBD2_FEATURE_COMPAT_CHECKSUM: u32 = 1 << 0;	// Commit blocks have a (CRC32) checksum
+pub const JBD2_FEATURE_INCOMPAT_REVOKE: u32 = 1 << 0;	// Revoke blocks present
+pub const JBD2_FEATURE_INCOMPAT_64BIT: u32 = 1 << 1;	// Block tags have 64-bit block numbers
+pub const JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT: u32 = 1 << 2;	// Commit blocks may be written before the data
+pub const JBD2_FEATURE_INCOMPAT_CSUM_V2: u32 = 1 << 3;	// CRC32C metadata checksums (16-bit tag checksums)
+pub const JBD2_FEATURE_INCOMPAT_CSUM_V3: u32 = 1 << 4;	// CRC32C metadata checksums (32-bit tag checksums)
+pub const JBD2_FEATURE_INCOMPAT_FAST_COMMIT: u32 = 1 << 5;	// Fast commit area after the main log
+
+/// Block tag flags
+pub const JBD2_FLAG_ESCAPE: u32 = 1;	// Block had the magic number in its first word (which was zeroed in the log)
+pub const JBD2_FLAG_SAME_UUID: u32 = 2;	// UUID omitted (same as previous tag)
+pub const JBD2_FLAG_DELETED: u32 = 4;
+pub const JBD2_FLAG_LAST_TAG: u32 = 8;	// Last tag in this descriptor block
+
+#[derive(Debug,Default,::kernel_derives::EncodedBE)]
+pub struct JournalHeader
+{
+	pub h_magic: u32,
+	pub h_blocktype: u32,
+	pub h_sequence: u32,
+}
+impl JournalHeader
+{
+	pub fn from_slice(mut r: &[u8]) -> Self {
+		::kernel::lib::byteorder::EncodedBE::decode(&mut r).unwrap()
+	}
+	pub fn write_to_slice(&self, mut r: &mut [u8]) {
+		::kernel::lib::byteorder::EncodedBE::encode(self, &mut r).unwrap()
+	}
+}
+
+#[derive(Debug,::kernel_derives::EncodedBE)]
+pub struct JournalSuperblock
+{
+	pub s_header: JournalHeader,
+	/// Journal device block size
+	pub s_blocksize: u32,
+	/// Total number of blocks in the journal
+	pub s_maxlen: u32,
+	/// First block of log information
+	pub s_first: u32,
+	/// First expected commit ID
+	pub s_sequence: u32,
+	/// Block number of the start of the log (zero if the journal is empty)
+	pub s_start: u32,
+	pub s_errno: i32,
+
+	// Version 2 only
+	pub s_feature_compat: u32,
+	pub s_feature_incompat: u32,
+	pub s_feature_ro_compat: u32,
+	pub s_uuid: [u8; 16],
+	pub s_nr_users: u32,
+	pub s_dynsuper: u32,
+	pub s_max_transaction: u32,
+	pub s_max_trans_data: u32,
+	pub s_checksum_type: u8,
+	pub _s_padding2: [u8; 3],
+	pub s_num_fc_blks: u32,
+	pub s_head: u32,
+	pub _s_padding: [u32; 40],
+	pub s_checksum: u32,
+	pub s_users: [u8; 16*48],
+}
+impl JournalSuperblock
+{
+	pub fn from_slice(mut r: &[u8]) -> Self {
+		::kernel::lib::byteorder::EncodedBE::decode(&mut r).unwrap()
+	}
+	pub fn write_to_slice(&self, mut r: &mut [u8]) {
+		::kernel::lib::byteorder::EncodedBE::encode(self, &mut r).unwrap()
+	}
+}
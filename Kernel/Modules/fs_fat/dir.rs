@@ -17,6 +17,8 @@ pub struct DirNode
 {
 	fs: ArefBorrow<crate::FilesystemInner>,
 	start_cluster: ClusterNum,
+	/// Parent directory (`None` for the root, or temporary nodes)
+	parent: Option<ClusterNum>,
 }
 impl_fmt! {
 	Debug(self, f) for DirNode {
@@ -29,20 +31,56 @@ impl DirNode {
 		DirNode {
 			fs: fs,
 			start_cluster: start_cluster,
+			parent: None,
 		}
 	}
-	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, start_cluster: ClusterNum) -> Box<DirNode> {
-		Box::new(Self::new(fs, start_cluster))
+	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, start_cluster: ClusterNum, parent: Option<ClusterNum>) -> Box<DirNode> {
+		Box::new(DirNode {
+			parent,
+			..Self::new(fs, start_cluster)
+			})
 	}
 }
 
 impl node::NodeBase for DirNode {
 	fn get_id(&self) -> node::InodeId {
-		todo!("DirNode::get_id")
+		match self.parent
+		{
+		Some(p) => super::InodeRef::new(self.start_cluster, p).to_id(),
+		None => super::InodeRef::root(self.start_cluster).to_id(),
+		}
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		let Some(parent) = self.parent else {
+			// The root directory has no entry
+			let p = &self.fs.perms;
+			return Ok(node::Metadata { links: 1, mode: p.mode(false), uid: p.uid, gid: p.gid, ..Default::default() });
+			};
+		let dir = DirNode::new(self.fs.reborrow(), parent);
+		let dir_info = self.fs.get_dir_info(parent);
+		let _lh_dir = dir_info.info.lock.read();
+		match dir.find_ent_by_cluster(self.start_cluster)?
+		{
+		Some(e) => Ok(e.metadata(&self.fs.perms)),
+		None => Err(::vfs::Error::Unknown("FAT: DirNode::get_metadata didn't find entry")),
+		}
+	}
+	fn set_metadata(&self, changes: &node::MetadataUpdate) -> node::Result<()> {
+		match self.parent
+		{
+		Some(parent) => {
+			if !edit_ents_in_dir(&self.fs, parent, self.start_cluster, &mut |e| e.apply_update(changes))? {
+				return Err(::vfs::Error::Unknown("FAT: DirNode::set_metadata didn't find entry"));
+			}
+			Ok( () )
+			},
+		// Nowhere to store root metadata
+		None => Err(::vfs::Error::InvalidParameter),
+		}
+	}
 }
 
 #[derive(Debug)]
@@ -50,12 +88,18 @@ pub struct OpenFileInfo
 {
 	dir_cluster: ClusterNum,
 	reference_count: u32,
+	/// Current file size (shared by all nodes for this file)
+	size: super::Arc<::kernel::sync::RwLock<u32>>,
+	/// All names for the file have been removed, the clusters are released on last close
+	unlinked: bool,
 }
 impl OpenFileInfo {
-	pub fn new(dir_cluster: ClusterNum) -> OpenFileInfo {
+	pub fn new(dir_cluster: ClusterNum, size: u32) -> OpenFileInfo {
 		OpenFileInfo {
 			dir_cluster,
 			reference_count: 0,
+			size: super::Arc::new(::kernel::sync::RwLock::new(size)),
+			unlinked: false,
 		}
 	}
 	pub fn add_ref(&mut self) {
@@ -79,8 +123,16 @@ impl super::FilesystemInner {
 
 	pub fn close_file(&self, file_cluster: ClusterNum) {
 		let mut lh_files = self.open_files.write();
-		if lh_files.get_mut(&file_cluster).expect("close_file but not open?").sub_ref() {
+		let info = lh_files.get_mut(&file_cluster).expect("close_file but not open?");
+		if info.sub_ref() {
+			let unlinked = info.unlinked;
 			lh_files.remove(&file_cluster);
+			if unlinked {
+				// The last name was removed while this was open, release the data now
+				if let Err(e) = self.release_chain(file_cluster) {
+					log_error!("close_file({}): Error releasing clusters - {:?}", file_cluster, e);
+				}
+			}
 		}
 	}
 }
@@ -111,6 +163,11 @@ impl<'a> ::core::ops::Drop for DirInfoHandle<'a> {
 }
 
 pub fn update_file_size(fs: &FilesystemInner, file_cluster: ClusterNum, new_size: u32) -> Result<(), ::vfs::Error> {
+	edit_file_ents(fs, file_cluster, &mut |e| e.size = new_size)
+}
+
+/// Apply `cb` to every directory entry naming the (open) file starting at `file_cluster`
+fn edit_file_ents(fs: &FilesystemInner, file_cluster: ClusterNum, cb: &mut dyn FnMut(&mut DirEntShort)) -> Result<(), ::vfs::Error> {
 	// Get the dir info, lock it, iterate the directory looking for this file
 
 	// Challenges:
@@ -124,42 +181,86 @@ pub fn update_file_size(fs: &FilesystemInner, file_cluster: ClusterNum, new_size
 	
 	// Lock the file list and get the current file
 	let lh_files = fs.open_files.read();
-	let file_info = lh_files.get(&file_cluster).ok_or(::vfs::Error::Unknown("FAT: update_file_size called with file not recorded open"))?;
+	let file_info = lh_files.get(&file_cluster).ok_or(::vfs::Error::Unknown("FAT: edit_file_ents called with file not recorded open"))?;
+	if file_info.unlinked {
+		// No entries left to update
+		return Ok( () );
+	}
+	if !edit_ents_in_dir(fs, file_info.dir_cluster, file_cluster, cb)? {
+		return Err(::vfs::Error::Unknown("FAT: edit_file_ents didn't find entry"));
+	}
+	Ok( () )
+}
+
+/// Update every entry for `file_cluster` within a directory, returns `false` if none were found
+fn edit_ents_in_dir(fs: &FilesystemInner, dir_cluster: ClusterNum, file_cluster: ClusterNum, cb: &mut dyn FnMut(&mut DirEntShort)) -> Result<bool, ::vfs::Error> {
 	// Get/create the current directory info (shared ownership)
-	let dir_info = fs.get_dir_info(file_info.dir_cluster);
-	let _lh_dir = dir_info.info.lock.read();	// Entry count isn't changing, so can be a read lock
+	let dir_info = fs.get_dir_info(dir_cluster);
+	// Write lock, as the entry could be updated using a read-modify-write of the cluster
+	let _lh_dir = dir_info.info.lock.write();
 
 	// Iterate the dir, find the file, update
-	for c in dir_clusters(fs, file_info.dir_cluster)
+	let mut found = false;
+	for c in dir_clusters(fs, dir_cluster)
 	{
-		if let Some(found) = ::kernel::futures::block_on(fs.with_cluster(c, |cluster| {
+		let (idxs, is_end) = ::kernel::futures::block_on(fs.with_cluster(c, |cluster| {
+			let mut idxs = vec![];
 			for (i,ent) in DirEnts::new(&cluster).enumerate()
 			{
 				match ent {
-				DirEnt::End => return Some(None),
-				DirEnt::Short(e) if e.cluster == file_cluster => return Some(Some(i)),
+				DirEnt::End => return (idxs, true),
+				// Skip the `.` and `..` entries of subdirectories
+				DirEnt::Short(e) if e.cluster == file_cluster && e.name[0] != b'.' => idxs.push(i),
 				_ => {},
 				}
 			}
-			None
-		}))? {
-			match found
-			{
-			None => break,
-			Some(idx) => 
-				return Ok( ::kernel::futures::block_on(fs.edit_cluster(c, |cluster| {
+			(idxs, false)
+		}))?;
+		if !idxs.is_empty() {
+			::kernel::futures::block_on(fs.edit_cluster(c, |cluster| {
+				for idx in idxs {
 					let data = &mut cluster[idx*32..][..32];
 					let mut ent = DirEnt::from_raw(&data[..]);
 					match ent {
-					DirEnt::Short(ref mut e) => e.size = new_size,
+					DirEnt::Short(ref mut e) => cb(e),
 					_ => unreachable!()
 					}
 					ent.to_raw(data);
-					})).map(|_| ())? )
-			}
+				}
+				}))?;
+			found = true;
+		}
+		if is_end {
+			break;
 		}
 	}
-	Err(::vfs::Error::Unknown("FAT: update_file_size didn't find entry"))
+	Ok(found)
+}
+
+/// Read the metadata of the (open) file starting at `file_cluster`
+pub fn get_file_metadata(fs: &ArefBorrow<FilesystemInner>, file_cluster: ClusterNum, size: u32) -> Result<node::Metadata, ::vfs::Error> {
+	let lh_files = fs.open_files.read();
+	let file_info = lh_files.get(&file_cluster).ok_or(::vfs::Error::Unknown("FAT: get_file_metadata called with file not recorded open"))?;
+	if file_info.unlinked {
+		let p = &fs.perms;
+		return Ok(node::Metadata { size: size as u64, mode: p.mode(false), uid: p.uid, gid: p.gid, ..Default::default() });
+	}
+	let dir = DirNode::new(fs.reborrow(), file_info.dir_cluster);
+	let dir_info = fs.get_dir_info(file_info.dir_cluster);
+	let _lh_dir = dir_info.info.lock.read();
+	match dir.find_ent_by_cluster(file_cluster)?
+	{
+	Some(e) => {
+		let mut rv = e.metadata(&fs.perms);
+		rv.size = size as u64;
+		Ok(rv)
+		},
+	None => Err(::vfs::Error::Unknown("FAT: get_file_metadata didn't find entry")),
+	}
+}
+/// Update the metadata of the (open) file starting at `file_cluster`
+pub fn set_file_metadata(fs: &FilesystemInner, file_cluster: ClusterNum, changes: &node::MetadataUpdate) -> Result<(), ::vfs::Error> {
+	edit_file_ents(fs, file_cluster, &mut |e| e.apply_update(changes))
 }
 
 fn dir_clusters(fs: &super::FilesystemInner, start_cluster: ClusterNum) -> ClusterList<'_> {
@@ -213,19 +314,24 @@ impl DirNode {
 		let dir_info = self.fs.get_dir_info(self.start_cluster);
 		let _lh_dir = dir_info.info.lock.read();
 		log_debug!("DirNode::find_node({})", ent_cluster);
+		// Empty files without a cluster can't be told apart (`lookup` assigns them a cluster before they're opened)
+		if ent_cluster == cluster_none() {
+			return Ok(None);
+		}
 		Ok(match self.find_ent_by_cluster(ent_cluster)?
 		{
 		None => None,
 		Some(e) =>
 			if e.attributes & on_disk::ATTR_DIRECTORY != 0 {
-				Some(node::Node::Dir(DirNode::new_boxed(self.fs.reborrow(), ent_cluster)))
+				Some(node::Node::Dir(DirNode::new_boxed(self.fs.reborrow(), ent_cluster, Some(self.start_cluster))))
 			}
 			else if e.attributes & on_disk::ATTR_VOLUMEID != 0 {
 				None
 			}
 			else {
-				lh_files.entry(ent_cluster).or_insert(OpenFileInfo::new(self.start_cluster)).add_ref();
-				Some(node::Node::File(FileNode::new_boxed( self.fs.reborrow(), ent_cluster, e.size )))
+				let info = lh_files.entry(ent_cluster).or_insert_with(|| OpenFileInfo::new(self.start_cluster, e.size));
+				info.add_ref();
+				Some(node::Node::File(FileNode::new_boxed( self.fs.reborrow(), self.start_cluster, ent_cluster, info.size.clone() )))
 			},
 		})
 	}
@@ -240,6 +346,370 @@ impl DirNode {
 			}
 		})
 	}
+
+	/// Locate an entry by name
+	///
+	/// Returns the index of the first entry used by this name (the start of the LFN entries), the index of the short entry,
+	/// and the short entry itself.
+	fn find_ent_by_name(&self, name: &ByteStr) -> Result<Option<(usize, usize, DirEntShort)>, super::storage::IoError> {
+		let mut lfn = LFN::new();
+		let mut lfn_start = 0;
+		self.iterate_ents(0, |i, ent| {
+			match ent {
+			DirEnt::End => {},
+			DirEnt::Short(e) => {
+				if e.name() == name || lfn.name() == name {
+					let first = if lfn.is_valid() { lfn_start } else { i };
+					return Some( (first, i, e) );
+				}
+				lfn.clear();
+				},
+			DirEnt::Long(e) => {
+				if e.id & 0x40 != 0 {
+					lfn_start = i;
+				}
+				lfn.add(&e)
+				},
+			DirEnt::Empty => lfn.clear(),
+			DirEnt::Invalid(_) => lfn.clear(),
+			}
+			None
+			})
+	}
+
+	/// Returns `true` if the directory only contains the `.` and `..` entries
+	fn is_empty(&self) -> Result<bool, super::storage::IoError> {
+		Ok(self.iterate_ents(0, |_i, ent| {
+			match ent {
+			DirEnt::Short(e) if e.name().as_bytes() != b"." && e.name().as_bytes() != b".." => Some(()),
+			_ => None,
+			}
+			})?.is_none())
+	}
+
+	/// Locate an entry by name (with the directory locked)
+	fn find_ent_by_name_locked(&self, name: &ByteStr) -> Result<Option<(usize, usize, DirEntShort)>, super::storage::IoError> {
+		let dir_info = self.fs.get_dir_info(self.start_cluster);
+		let _lh_dir = dir_info.info.lock.read();
+		self.find_ent_by_name(name)
+	}
+
+	/// First cluster of the parent directory (from the `..` entry)
+	fn parent_cluster(&self) -> node::Result<ClusterNum> {
+		match self.find_ent_by_name_locked(ByteStr::new(".."))?
+		{
+		// `..` is zero if the parent is the root
+		Some((_, _, e)) if e.cluster == cluster_none() => Ok(self.fs.root_first_cluster),
+		Some((_, _, e)) => Ok(e.cluster),
+		None => Err(::vfs::Error::InconsistentFilesystem),
+		}
+	}
+
+	/// Release the node named by a removed entry `e` (which was in this directory)
+	///
+	/// The caller must hold the open file list lock (passed as `open_files`)
+	fn release_name(&self, open_files: &mut ::kernel::lib::collections::VecMap<ClusterNum,OpenFileInfo>, e: &DirEntShort) -> node::Result<()> {
+		let is_dir = e.attributes & on_disk::ATTR_DIRECTORY != 0;
+		if e.cluster == cluster_none() {
+			// Empty file with no data
+		}
+		else if is_dir {
+			self.fs.release_chain(e.cluster)?;
+		}
+		else {
+			match open_files.get_mut(&e.cluster)
+			{
+			// The file is open - release on close
+			Some(info) => info.unlinked = true,
+			None => self.fs.release_chain(e.cluster)?,
+			}
+		}
+		Ok( () )
+	}
+
+	/// Overwrite directory entries starting at index `first_idx` (the directory must already be large enough)
+	fn write_ents(&self, first_idx: usize, ents: impl Iterator<Item=DirEnt>) -> node::Result<()> {
+		let ents_per_cluster = self.fs.cluster_size / 32;
+		let mut ents = ents.peekable();
+		let mut idx = first_idx % ents_per_cluster;
+		for c in self.clusters().skip(first_idx / ents_per_cluster)
+		{
+			if ents.peek().is_none() {
+				break;
+			}
+			::kernel::futures::block_on(self.fs.edit_cluster(c, |data| {
+				while idx < ents_per_cluster {
+					let Some(ent) = ents.next() else { break; };
+					log_trace!("write_ents: {} @ {} {:?}", c, idx*32, ent);
+					ent.to_raw(&mut data[idx*32..][..32]);
+					idx += 1;
+				}
+				}))?;
+			idx = 0;
+		}
+		if ents.peek().is_some() {
+			log_error!("write_ents: Directory {} ended before all entries were written", self.start_cluster);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		Ok( () )
+	}
+
+	/// Add a new name to this directory
+	///
+	/// `make_target` is called once the name has been checked, and returns the target cluster, attributes, and size.
+	fn add_entry(&self, name: &ByteStr, times: DirEntTimes, make_target: impl FnOnce()->node::Result<(ClusterNum, u8, u32)>) -> node::Result<ClusterNum> {
+		if name.len() == 0 || name == "." || name == ".." || name.as_bytes().iter().any(|&b| !is_valid_long_char(b)) {
+			return Err(::vfs::Error::InvalidParameter);
+		}
+		// Determine if this file can be encoded as a short filename, and if not - how many entries it will need
+		let valid_short_name = is_valid_short_name(name);
+		let num_entries = if valid_short_name.is_some() {
+				1
+			}
+			else {
+				let name_len = ::utf16::wtf8_to_utf16(name.as_bytes()).count();
+				if name_len > 255 {
+					return Err(::vfs::Error::InvalidParameter);
+				}
+				1 + (name_len + 13-1) / 13
+			};
+
+		let dir_info = self.fs.get_dir_info(self.start_cluster);
+		let _lh_dir = dir_info.info.lock.write();
+		// - Lock the directory, then start seeking clusters looking for a sequence of slots large enough
+		let ents_per_cluster = self.fs.cluster_size / 32;
+		let mut end_idx = None;
+		let mut found_slot = None;
+		let mut short_names = vec![];
+		let mut last_cluster = self.start_cluster;
+		let mut total_ents = 0;
+		{
+			let mut n_free_run = 0;	// Number of free entries in a row currently seen
+			let mut lfn = LFN::new();
+			// TODO: `iterate_ent` doesn't return the cluster - so reimplemented here
+			for c in self.clusters()
+			{
+				last_cluster = c;
+				if end_idx.is_none()
+				{
+					if let Some(rv) = ::kernel::futures::block_on(self.fs.with_cluster(c, |cluster| {
+						for (i,ent) in DirEnts::new(&cluster).enumerate()
+						{
+							let idx = total_ents + i;
+							match ent {
+							DirEnt::End => {
+								end_idx = Some(idx);
+								break ;
+								},
+							DirEnt::Short(ref e) => {
+								if e.name() == name || lfn.name() == name {
+									return Some(::vfs::Error::AlreadyExists);
+								}
+								short_names.push(e.name);
+								lfn.clear();
+								},
+							DirEnt::Long(ref e) => lfn.add(e),
+							DirEnt::Empty => lfn.clear(),
+							DirEnt::Invalid(_) => lfn.clear(),
+							}
+							if let DirEnt::Empty = ent {
+								n_free_run += 1;
+								if found_slot.is_none() && n_free_run == num_entries {
+									found_slot = Some(idx + 1 - num_entries);
+								}
+							}
+							else {
+								n_free_run = 0;
+							}
+						}
+						None
+						}))? {
+						return Err(rv);
+					}
+				}
+				total_ents += ents_per_cluster;
+			}
+		}
+
+		// Pick a short name that doesn't collide with an existing one
+		// - Short names are case-insensitive
+		let short_exists = |sn: &[u8; 8+1+3]| short_names.iter().any(|n| trim_nul(n).eq_ignore_ascii_case(trim_nul(sn)));
+		let short_name = match valid_short_name
+			{
+			Some(sn) => {
+				if short_exists(&sn) {
+					return Err(::vfs::Error::AlreadyExists);
+				}
+				sn
+				},
+			None => (1 ..= 999_999).map(|i| make_short_name(name, i)).find(|sn| !short_exists(sn)).ok_or(::vfs::Error::OutOfSpace)?,
+			};
+
+		// The FAT12/16 root directory is a fixed number of sectors (which might not be a multiple of the cluster size)
+		let is_fixed_root = !is!(self.fs.ty, super::Size::Fat32) && self.start_cluster == self.fs.root_first_cluster;
+		if is_fixed_root {
+			total_ents = usize::min(total_ents, self.fs.root_sector_count as usize * (self.fs.vh.block_size() / 32));
+		}
+
+		// Determine where the new entries go, extending the directory if there's not enough space
+		let start_idx = found_slot.or(end_idx).unwrap_or(total_ents);
+		let needed = start_idx + num_entries;
+		if needed > total_ents {
+			if is_fixed_root {
+				return Err(::vfs::Error::OutOfSpace);
+			}
+			// NOTE: No need to update the size, as the size of a directory is always zero.
+			while total_ents < needed {
+				last_cluster = self.fs.alloc_cluster_zeroed(last_cluster, true)?.ok_or(::vfs::Error::OutOfSpace)?;
+				total_ents += ents_per_cluster;
+			}
+		}
+
+		let (target_cluster, attributes, size) = make_target()?;
+		log_debug!("DirNode::add_entry('{:?}'): {} short={:?} @{}+{}",
+			name, target_cluster, ByteStr::new(trim_nul(&short_name)), start_idx, num_entries);
+		let ents = CreateDirents::new(attributes, target_cluster, size, times, short_name, if num_entries == 1 { None } else { Some(name) });
+		// If appending, make sure the entry after the new ones is an end marker
+		let end = if found_slot.is_none() && needed < total_ents { Some(DirEnt::End) } else { None };
+		self.write_ents(start_idx, ents.chain(end))?;
+		Ok(target_cluster)
+	}
+}
+
+/// Strip the NUL padding from a decoded short name
+fn trim_nul(name: &[u8; 8+1+3]) -> &[u8] {
+	name.split(|&b| b == 0).next().unwrap()
+}
+fn is_valid_short_char(b: u8) -> bool {
+	b.is_ascii_uppercase() || b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_'
+}
+/// Characters allowed in long file names
+fn is_valid_long_char(b: u8) -> bool {
+	b >= 0x20 && !b"\\/:*?\"<>|".contains(&b)
+}
+/// Check if the passed string is a valid short file name, and return the encoded version if it is
+fn is_valid_short_name(name: &ByteStr) -> Option<[u8; 8+1+3]> {
+	let mut rv = [0; 8+1+3];
+	let mut dotpos = None;
+	let mut has_upper = false;
+	let mut has_lower = false;
+	for (i,&b) in name.as_bytes().iter().enumerate() {
+		if b == b'.' && i > 0 {	// leading dot isn't valid
+			dotpos = Some(i);
+			break;
+		}
+		else if i == 8 {
+			return None;
+		}
+		else if !is_valid_short_char(b) {
+			return None;
+		}
+		else {
+			rv[i] = b;
+			has_lower |= b.is_ascii_lowercase();
+			has_upper |= b.is_ascii_uppercase();
+		}
+	}
+	if has_upper && has_lower {
+		return None;
+	}
+	if let Some(dotpos) = dotpos {
+		let mut has_upper = false;
+		let mut has_lower = false;
+		rv[dotpos] = b'.';
+		for (i,&b) in name.as_bytes()[dotpos+1..].iter().enumerate() {
+			if i == 3 {
+				return None;
+			}
+			else if !is_valid_short_char(b) {
+				return None;
+			}
+			else {
+				rv[dotpos+1+i] = b;
+				has_lower |= b.is_ascii_lowercase();
+				has_upper |= b.is_ascii_uppercase();
+			}
+		}
+		if has_upper && has_lower {
+			return None;
+		}
+		// A trailing dot isn't valid
+		if name.len() == dotpos+1 {
+			return None;
+		}
+	}
+	Some(rv)
+}
+/// Generate a `BASIS~N.EXT` short name for a name that can't be stored as one
+fn make_short_name(name: &ByteStr, index: u32) -> [u8; 8+1+3] {
+	let mut bytes = name.as_bytes();
+	// Leading dots are dropped, and the extension is after the last dot
+	while let [b'.', rest @ ..] = bytes {
+		bytes = rest;
+	}
+	let (base, ext) = match bytes.iter().rposition(|&b| b == b'.')
+		{
+		Some(p) => (&bytes[..p], &bytes[p+1..]),
+		None => (bytes, &[][..]),
+		};
+	// Spaces and dots are removed, other invalid characters become `_`
+	let conv = |&b: &u8| {
+		let b = b.to_ascii_uppercase();
+		if is_valid_short_char(b) { Some(b) } else if b == b' ' || b == b'.' { None } else { Some(b'_') }
+		};
+
+	let mut tail = [b'~'; 1+6];
+	let mut tail_len = 1;
+	let mut digits = index;
+	let mut div = 1;
+	while digits / div >= 10 {
+		div *= 10;
+	}
+	while div > 0 {
+		tail[tail_len] = b'0' + (digits / div) as u8;
+		digits %= div;
+		div /= 10;
+		tail_len += 1;
+	}
+
+	let mut rv = [0; 8+1+3];
+	let mut i = 0;
+	for b in base.iter().filter_map(conv).take(8 - tail_len) {
+		rv[i] = b;
+		i += 1;
+	}
+	rv[i..][..tail_len].copy_from_slice(&tail[..tail_len]);
+	i += tail_len;
+	let mut ext = ext.iter().filter_map(conv).take(3).peekable();
+	if ext.peek().is_some() {
+		rv[i] = b'.';
+		i += 1;
+		for b in ext {
+			rv[i] = b;
+			i += 1;
+		}
+	}
+	rv
+}
+/// Write the `.` and `..` entries into a new directory's first cluster
+fn init_dir_cluster(data: &mut [u8], self_cluster: ClusterNum, parent_cluster: u32) {
+	for (i, &(name, cluster)) in [(b".          ", self_cluster.get()), (b"..         ", parent_cluster)].iter().enumerate()
+	{
+		on_disk::DirEnt {
+			name: *name,
+			attribs: on_disk::ATTR_DIRECTORY,
+			lcase: 0,
+			size: 0,
+			cluster: cluster as u16,
+			cluster_hi: (cluster >> 16) as u16,
+			creation_ds: 0,
+			creation_date: 0,
+			creation_time: 0,
+			accessed_date: 0,
+			modified_date: 0,
+			modified_time: 0,
+			}.write(&mut &mut data[i*32..][..32]);
+	}
 }
 
 /// Iterator over directory entries
@@ -325,9 +795,21 @@ impl DirEnt {
 			// 3. Cluster, Size, Attribs
 			DirEnt::Short(DirEntShort{
 				name: outname,
-				cluster: ClusterNum::new( (ent.cluster as u32) | (ent.cluster_hi as u32) << 16 ).unwrap_or( ClusterNum::new(0xFF_FFFF).unwrap() ),
+				cluster: match (ent.cluster as u32) | (ent.cluster_hi as u32) << 16
+					{
+					0 => cluster_none(),
+					c => ClusterNum::new(c).unwrap_or( cluster_none() ),
+					},
 				size: ent.size,
 				attributes: ent.attribs,
+				times: DirEntTimes {
+					creation_ds: ent.creation_ds,
+					creation_time: ent.creation_time,
+					creation_date: ent.creation_date,
+					accessed_date: ent.accessed_date,
+					modified_time: ent.modified_time,
+					modified_date: ent.modified_date,
+					},
 				})
 		}
 	}
@@ -336,21 +818,24 @@ impl DirEnt {
 		match self
 		{
 		DirEnt::End => dst.copy_from_slice(&[0; 32]),
+		// Deleted entries only have the first byte changed
+		DirEnt::Empty => dst[0] = 0xE5,
 		DirEnt::Short(v) => {
 			let (lcase, name) = v.get_encoded_name();
+			let cluster = if v.cluster == cluster_none() { 0 } else { v.cluster.get() };
 			on_disk::DirEnt {
 				name,
 				attribs: v.attributes,
 				lcase,
 				size: v.size,
-				cluster: v.cluster.get() as u16,
-				cluster_hi: (v.cluster.get() >> 16) as u16,
-				creation_ds: 0,
-				creation_date: 0,
-				creation_time: 0,
-				accessed_date: 0,
-				modified_date: 0,
-				modified_time: 0,
+				cluster: cluster as u16,
+				cluster_hi: (cluster >> 16) as u16,
+				creation_ds: v.times.creation_ds,
+				creation_date: v.times.creation_date,
+				creation_time: v.times.creation_time,
+				accessed_date: v.times.accessed_date,
+				modified_date: v.times.modified_date,
+				modified_time: v.times.modified_time,
 				}.write(&mut dst);
 			},
 		DirEnt::Long(e) => {
@@ -370,15 +855,30 @@ impl DirEnt {
 		}
 	}
 }
+/// Placeholder cluster for entries without data (empty files, and `..` pointing to the root)
+fn cluster_none() -> ClusterNum {
+	ClusterNum::new(0xFF_FFFF).unwrap()
+}
+
+#[derive(Clone)]
 struct DirEntShort {
 	/// NUL-padded string with extension joined
 	name: [u8; 8+1+3],
 	cluster: ClusterNum,
 	size: u32,
 	attributes: u8,
-	//creation_time: ::kernel::time::Timestamp,
-	//modified_time: ::kernel::time::Timestamp,
-	//accessed_time: ::kernel::time::Timestamp,
+	times: DirEntTimes,
+}
+/// Raw timestamps from a short entry (zero for unset)
+#[derive(Clone,Copy,Default,Debug)]
+struct DirEntTimes {
+	/// Fine resolution for `creation_time` (10ms units, 0-199)
+	creation_ds: u8,
+	creation_time: u16,
+	creation_date: u16,
+	accessed_date: u16,
+	modified_time: u16,
+	modified_date: u16,
 }
 impl_fmt! {
 	Debug(self,f) for DirEntShort {
@@ -456,6 +956,69 @@ impl DirEntShort {
 	fn inode(&self, parent_dir: ClusterNum) -> node::InodeId {
 		super::InodeRef::new(self.cluster, parent_dir).to_id()
 	}
+
+	/// Metadata from the entry (ownership and permissions come from the mount options)
+	fn metadata(&self, perms: &::vfs::mount::PermissionDefaults) -> node::Metadata {
+		let t = &self.times;
+		let mtime = DirEntTimes::decode(t.modified_date, t.modified_time);
+		node::Metadata {
+			size: if self.attributes & on_disk::ATTR_DIRECTORY != 0 { 0 } else { self.size as u64 },
+			links: 1,
+			mode: perms.mode(self.attributes & on_disk::ATTR_READONLY != 0),
+			uid: perms.uid,
+			gid: perms.gid,
+			atime: DirEntTimes::decode(t.accessed_date, 0),
+			mtime,
+			// FAT doesn't track metadata changes separately
+			ctime: mtime,
+			crtime: DirEntTimes::decode(t.creation_date, t.creation_time).map(|v| node::Timestamp {
+				secs: v.secs + t.creation_ds as i64 / 100,
+				nsecs: (t.creation_ds as u32 % 100) * 10_000_000,
+				}),
+		}
+	}
+	/// Apply a metadata update (only the read-only flag and times can be stored)
+	fn apply_update(&mut self, changes: &node::MetadataUpdate) {
+		if let Some(mode) = changes.mode {
+			if mode & 0o222 == 0 {
+				self.attributes |= on_disk::ATTR_READONLY;
+			}
+			else {
+				self.attributes &= !on_disk::ATTR_READONLY;
+			}
+		}
+		if let Some(ts) = changes.mtime {
+			(self.times.modified_date, self.times.modified_time) = DirEntTimes::encode(ts);
+		}
+		if let Some(ts) = changes.atime {
+			self.times.accessed_date = DirEntTimes::encode(ts).0;
+		}
+	}
+}
+impl DirEntTimes {
+	/// Decode a FAT date/time pair (`None` if the date is unset)
+	fn decode(date: u16, time: u16) -> Option<node::Timestamp> {
+		if date == 0 {
+			return None;
+		}
+		Some(node::Timestamp::from_civil(
+			1980 + (date >> 9) as i64, (date >> 5 & 0xF) as u8, (date & 0x1F) as u8,
+			(time >> 11) as u8, (time >> 5 & 0x3F) as u8, (time & 0x1F) as u8 * 2
+			))
+	}
+	/// Encode a timestamp as a FAT date/time pair (clamped to the representable range)
+	fn encode(ts: node::Timestamp) -> (u16, u16) {
+		let (year, month, day, hour, minute, second) = ts.to_civil();
+		if year < 1980 {
+			return (1 << 5 | 1, 0);
+		}
+		if year > 1980 + 127 {
+			return (127 << 9 | 12 << 5 | 31, 23 << 11 | 59 << 5 | 29);
+		}
+		let date = ((year - 1980) as u16) << 9 | (month as u16) << 5 | day as u16;
+		let time = (hour as u16) << 11 | (minute as u16) << 5 | (second / 2) as u16;
+		(date, time)
+	}
 }
 
 /// Decoded long file name
@@ -510,31 +1073,35 @@ impl LFN {
 impl node::Dir for DirNode {
 	fn lookup(&self, name: &ByteStr) -> node::Result<node::InodeId> {
 		log_trace!("DirNode::lookup({:?})", name);
-		let dir_info = self.fs.get_dir_info(self.start_cluster);
-		let _lh_dir = dir_info.info.lock.read();
-		// For each cluster in the directory, iterate
-		let mut lfn = LFN::new();
-		match self.iterate_ents(0, |_i, ent| {
-			match ent {
-			DirEnt::End => {},
-			DirEnt::Short(e) => {
-				if e.name() == name || lfn.name() == name {
-					return Some( e.inode(self.start_cluster) );
-				}
-				lfn.clear();
-				},
-			DirEnt::Long(e) => lfn.add(&e),
-			DirEnt::Empty => {
-				lfn.clear();
+		{
+			let dir_info = self.fs.get_dir_info(self.start_cluster);
+			let _lh_dir = dir_info.info.lock.read();
+			match self.find_ent_by_name(name)?
+			{
+			None => return Err(::vfs::Error::NotFound),
+			Some((_, _, e)) if e.cluster != cluster_none() || e.attributes & on_disk::ATTR_DIRECTORY != 0 => {
+				return Ok( e.inode(self.start_cluster) );
 				},
-			DirEnt::Invalid(_) => lfn.clear(),
+			Some(_) => {},
 			}
-			None
-			})?
-		{
-		Some(v) => Ok(v),
-		None => Err(::vfs::Error::NotFound)
 		}
+
+		// Empty files created by other systems have no cluster (and so no unique inode number), allocate one
+		let dir_info = self.fs.get_dir_info(self.start_cluster);
+		let _lh_dir = dir_info.info.lock.write();
+		let Some((_, idx, mut e)) = self.find_ent_by_name(name)? else {
+			return Err(::vfs::Error::NotFound);
+			};
+		if e.cluster == cluster_none() {
+			if self.fs.read_only {
+				log_notice!("DirNode::lookup({:?}): Empty file has no cluster, and one can't be assigned on a read-only mount", name);
+				return Err(::vfs::Error::ReadOnlyFilesystem);
+			}
+			e.cluster = self.fs.alloc_cluster_unchained(self.start_cluster)?.ok_or(::vfs::Error::OutOfSpace)?;
+			log_debug!("DirNode::lookup({:?}): Assigned {} to empty file", name, e.cluster);
+			self.write_ents(idx, ::core::iter::once(DirEnt::Short(e.clone())))?;
+		}
+		Ok( e.inode(self.start_cluster) )
 	}
 	fn read(&self, ofs: usize, callback: &mut node::ReadDirCallback) -> node::Result<usize> {
 		log_trace!("DirNode::read(ofs={})", ofs);
@@ -577,270 +1144,163 @@ impl node::Dir for DirNode {
 		}
 	}
 	fn create(&self, name: &ByteStr, nodetype: node::NodeType) -> node::Result<node::InodeId> {
-		// File cluster for the dir's data
-		let Some(new_cluster) = self.fs.alloc_cluster_unchained(self.start_cluster)? else {
-			return Err(::vfs::Error::OutOfSpace);
+		log_debug!("DirNode::create('{:?}', {:?})", name, nodetype);
+		let attributes = match nodetype
+			{
+			node::NodeType::File => on_disk::ATTR_ARCHIVE,
+			node::NodeType::Dir => on_disk::ATTR_DIRECTORY,
+			node::NodeType::Symlink(_) => return Err(::vfs::Error::Unknown("FAT doesn't support symbolic links")),
 			};
-		log_debug!("DirNode::create('{:?}', {:?}): new_cluster={}", name, nodetype, new_cluster);
-		fn is_valid_short_char(b: u8) -> bool {
-			b.is_ascii_uppercase() || b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_'
-		}
-		/// Check if the passed string is a valid short file name, and return the encoded version if it is
-		fn is_valid_short_name(name: &ByteStr) -> Option<[u8; 8+1+3]> {
-			let mut rv = [0; 8+1+3];
-			let mut dotpos = None;
-			let mut has_upper = false;
-			let mut has_lower = false;
-			for (i,&b) in name.as_bytes().iter().enumerate() {
-				if b == b'.' && i > 0 {	// leading dot isn't valid
-					dotpos = Some(i);
-					break;
-				}
-				else if i == 8 {
-					return None;
-				}
-				else if !is_valid_short_char(b) {
-					return None;
-				}
-				else {
-					rv[i] = b;
-					has_lower |= b.is_ascii_lowercase();
-					has_upper |= b.is_ascii_uppercase();
-				}
-			}
-			if has_upper && has_lower {
-				return None;
-			}
-			if let Some(dotpos) = dotpos {
-				let mut has_upper = false;
-				let mut has_lower = false;
-				rv[dotpos] = b'.';
-				for (i,&b) in name.as_bytes()[dotpos+1..].iter().enumerate() {
-					if i == 3 {
-						return None;
-					}
-					else if !is_valid_short_char(b) {
-						return None;
-					}
-					else {
-						rv[dotpos+1+i] = b;
-						has_lower |= b.is_ascii_lowercase();
-						has_upper |= b.is_ascii_uppercase();
-					}
-				}
-				if has_upper && has_lower {
-					return None;
-				}
-			}
-			Some(rv)
-		}
-		fn make_short_name(name: &ByteStr, index: u16) -> [u8; 8+1+3] {
-			let mut rv = [0; 8+1+3];
-			let mut i = 0;
-			// Strip leading invalid characters
-			let mut iter = name.as_bytes().iter().copied().peekable();
-			if iter.peek() == Some(&b'.') {
-				iter.next();
-			}
-			let mut has_ext = false;
-			while let Some(v) = iter.next() {
-				if i > 0 && v == b'.' {
-					has_ext = true;
-					break;
-				}
-				if i == 8 {
-					rv[6] = b'~';
-					rv[7] = b'1';
-					// Advance until the extension
-					while let Some(v) = iter.next() {
-						if v == b'.' {
-							has_ext = true;
-							break;
-						}
-					}
-					break;
-				}
-				if is_valid_short_char(v.to_ascii_uppercase()) {
-					rv[i] = v.to_ascii_uppercase();
-					i += 1;
-				}
+		// Allocate the new node's first cluster once the name is known to be usable
+		// - Directories get `.` and `..` entries
+		let allocated = ::core::cell::Cell::new(None);
+		let rv = self.add_entry(name, Default::default(), || {
+			let Some(new_cluster) = self.fs.alloc_cluster_zeroed(self.start_cluster, false)? else {
+				return Err(::vfs::Error::OutOfSpace);
+				};
+			allocated.set(Some(new_cluster));
+			if attributes & on_disk::ATTR_DIRECTORY != 0 {
+				// `..` is zero if the parent is the root
+				let parent = if self.start_cluster == self.fs.root_first_cluster { 0 } else { self.start_cluster.get() };
+				::kernel::futures::block_on(self.fs.edit_cluster(new_cluster, |data| init_dir_cluster(data, new_cluster, parent)))?;
 			}
-			if i == 0 {
-				rv[i] = b'_';
-				i += 1;
-			}
-			if has_ext && iter.peek().is_some() {
-				rv[i] = b'.';
-				i += 1;
-				while let Some(v) = iter.next() {
-					if is_valid_short_char(v.to_ascii_uppercase()) {
-						rv[i] = v.to_ascii_uppercase();
-						i += 1;
-					}
-				}
-				if rv[i-1] == b'.' {
-					rv[i] = b'_';
-				}
+			Ok( (new_cluster, attributes, 0) )
+			});
+		match rv
+		{
+		Ok(new_cluster) => Ok( super::InodeRef::new(new_cluster, self.start_cluster).to_id() ),
+		Err(e) => {
+			if let Some(c) = allocated.get() {
+				let _ = self.fs.release_chain(c);
 			}
+			Err(e)
+			},
+		}
+	}
+	fn link(&self, name: &ByteStr, node: &dyn node::NodeBase) -> node::Result<()> {
+		log_debug!("DirNode::link('{:?}', {:#x})", name, node.get_id());
+		// FAT has no link count, so a second entry would be a cross-linked file (freed when either name is removed)
+		Err(::vfs::Error::Unsupported)
+	}
+	fn unlink(&self, name: &ByteStr) -> node::Result<()> {
+		log_debug!("DirNode::unlink('{:?}')", name);
+		if name == "." || name == ".." {
+			return Err(::vfs::Error::InvalidParameter);
+		}
+		// Lock the file list, then the directory
+		let mut lh_files = self.fs.open_files.write();
+		let dir_info = self.fs.get_dir_info(self.start_cluster);
+		let _lh_dir = dir_info.info.lock.write();
 
-			if index > 0 {
-				todo!("Mangle short name for de-duplication");
-			}
+		let Some((first_idx, short_idx, e)) = self.find_ent_by_name(name)? else {
+			return Err(::vfs::Error::NotFound);
+			};
+		let is_dir = e.attributes & on_disk::ATTR_DIRECTORY != 0;
+		if is_dir && !DirNode::new(self.fs.reborrow(), e.cluster).is_empty()? {
+			return Err(::vfs::Error::DirectoryNotEmpty);
+		}
 
-			rv
+		// Mark the short entry and its long name entries as free
+		self.write_ents(first_idx, (first_idx ..= short_idx).map(|_| DirEnt::Empty))?;
+		self.release_name(&mut lh_files, &e)
+	}
+	fn rename(&self, old_name: &ByteStr, new_dir: &dyn node::NodeBase, new_name: &ByteStr) -> node::Result<()> {
+		log_debug!("DirNode::rename('{:?}', {:#x}, '{:?}')", old_name, new_dir.get_id(), new_name);
+		if old_name == "." || old_name == ".." || new_name == "." || new_name == ".." {
+			return Err(::vfs::Error::InvalidParameter);
 		}
-		let short_name;
-		// Add entries to the end of the dir
-		// - Determine if this file can be encoded as a short filename, and if not - how many entries it will need
-		let num_entries = if let Some(sn) = is_valid_short_name(name) {
-			short_name = sn;
-			1
-		} else {
-			short_name = make_short_name(name, 0);
-			1 + (::utf16::wtf8_to_utf16(name.as_bytes()).count() + 1 + 13-1) / 13
-		};
+		let Some(new_dir) = new_dir.get_any().downcast_ref::<DirNode>() else {
+			return Err(::vfs::Error::InvalidParameter);
+			};
+		if !::core::ptr::eq(&*self.fs, &*new_dir.fs) {
+			return Err(::vfs::Error::InvalidParameter);
+		}
+		let same_dir = self.start_cluster == new_dir.start_cluster;
+		if same_dir && old_name == new_name {
+			return Ok( () );
+		}
+		// Lock the file list (so names can't be removed while the move is in progress)
+		let mut lh_files = self.fs.open_files.write();
 
-		let dir_info = self.fs.get_dir_info(self.start_cluster);
-		let _lh_dir = dir_info.info.lock.write();
-		// - Lock the directory, then start seeking clusters looking for a sequence of slots large enough
-		let mut end_entry = None;
-		let (found_slot, short_collision, total_free_slots) = {
-			let mut found_slot = None;
-			let mut short_collision = false;
-			let mut n_free_total = 0;
-			let mut n_free_run = 0;	// Number of free entries in a row currently seen
-			let mut lfn = LFN::new();
-			let mut idx = 0;	// Index of the directory entry
-			// TODO: `iterate_ent` doesn't return the cluster - so reimplemented here
-			for c in self.clusters()
-			{
-				if let Some(rv) = ::kernel::futures::block_on(self.fs.with_cluster(c, |cluster| {
-					for (i,ent) in DirEnts::new(&cluster).enumerate()
-					{
-						match ent {
-						DirEnt::End => {
-							end_entry = Some( (c,i) );
-							break ;
-							},
-						DirEnt::Short(ref e) => {
-							if e.name() == name || lfn.name() == name {
-								return Some(Err(::vfs::Error::AlreadyExists));
-							}
-							if e.name().as_bytes() == &short_name {
-								short_collision = true;
-							}
-							lfn.clear();
-							},
-						DirEnt::Long(ref e) => lfn.add(e),
-						DirEnt::Empty => {
-							lfn.clear();
-							},
-						DirEnt::Invalid(_) => lfn.clear(),
-						}
-						if let DirEnt::Empty = ent {
-							n_free_run += 1;
-							n_free_total += 1;
-							if found_slot.is_none() && n_free_run == num_entries {
-								found_slot = Some(idx);
-							}
-						}
-						else {
-							n_free_run = 0;
-						}
-						idx += 1;
-					}
-					None
-					}))? {
-					return rv;
-				}
-				if end_entry.is_some() {
-					break;
+		let Some((_, _, src)) = self.find_ent_by_name_locked(old_name)? else {
+			return Err(::vfs::Error::NotFound);
+			};
+		let is_dir = src.attributes & on_disk::ATTR_DIRECTORY != 0;
+		if is_dir && !same_dir {
+			// A directory can't be moved into itself (check every parent of the destination)
+			let mut cur = new_dir.start_cluster;
+			while cur != self.fs.root_first_cluster {
+				if cur == src.cluster {
+					return Err(::vfs::Error::InvalidParameter);
 				}
+				cur = DirNode::new(self.fs.reborrow(), cur).parent_cluster()?;
 			}
-			(found_slot, short_collision, n_free_total)
-		};
+		}
 
-		// If there was a short name collision (... which can only be flagged if LFN is used, otherwise it's an error)
-		// then re-iterate and create a list of short names
-		if short_collision
+		match new_dir.find_ent_by_name_locked(new_name)?
 		{
-			let mut names = vec![];
-			self.iterate_ents(0, |_i, ent| {
-				if let DirEnt::Short(e) = ent {
-					// TODO: Only push if it prefix matches the preferred name (until the `~`)
-					names.push( e.name );
-				}
-				None::<()>
-			})?;
-			todo!("DirNode::create(): Handle short name collision");
+		// Both names already refer to the same file
+		Some((_, _, dst)) if dst.cluster == src.cluster && src.cluster != cluster_none() => return Ok( () ),
+		Some((_, _, dst)) => {
+			let dst_is_dir = dst.attributes & on_disk::ATTR_DIRECTORY != 0;
+			if dst_is_dir != is_dir {
+				return Err(::vfs::Error::TypeMismatch);
+			}
+			if dst_is_dir && !DirNode::new(self.fs.reborrow(), dst.cluster).is_empty()? {
+				return Err(::vfs::Error::DirectoryNotEmpty);
+			}
+			// Point the existing name at the source (a single entry write, so the name is never missing)
+			{
+				let dir_info = self.fs.get_dir_info(new_dir.start_cluster);
+				let _lh_dir = dir_info.info.lock.write();
+				let Some((_, idx, mut e)) = new_dir.find_ent_by_name(new_name)? else {
+					return Err(::vfs::Error::InconsistentFilesystem);
+					};
+				e.cluster = src.cluster;
+				e.size = src.size;
+				e.attributes = src.attributes;
+				e.times = src.times;
+				new_dir.write_ents(idx, ::core::iter::once(DirEnt::Short(e)))?;
+			}
+			new_dir.release_name(&mut lh_files, &dst)?;
+			},
+		None => {
+			new_dir.add_entry(new_name, src.times, || Ok( (src.cluster, src.attributes, src.size) ))?;
+			},
 		}
 
-		let mut ents_it = CreateDirents::new(nodetype, new_cluster, short_name, if num_entries == 1 { None } else {Some(name) })
-			.chain(::core::iter::once(DirEnt::End))
-			.peekable()
-			;
-
-		if let Some(pos) = found_slot {
-			// Can freely insert
-			todo!("DirNode::create('{:?}', {:?}): new_cluster={}, num_entries={} - found_slot={:?}", name, nodetype, new_cluster, num_entries, pos);
-		}
-		else {
-			// Extend the directory
-			// - Should this also defragment?
-			if total_free_slots >= num_entries {
-				// There were enough entries - need to defragment the directory
-				todo!("DirNode::create(): Defragment directory ({} total free, needed {} contig)", total_free_slots, num_entries);
+		// Remove the old name
+		{
+			let dir_info = self.fs.get_dir_info(self.start_cluster);
+			let _lh_dir = dir_info.info.lock.write();
+			match self.find_ent_by_name(old_name)?
+			{
+			Some((first_idx, short_idx, e)) if e.cluster == src.cluster => {
+				self.write_ents(first_idx, (first_idx ..= short_idx).map(|_| DirEnt::Empty))?;
+				},
+			_ => return Err(::vfs::Error::InconsistentFilesystem),
 			}
-			else {
-				// Don't bother defragmenting, just extend the length
-				// - Maybe the existing last cluster can be extended...
-
-				// NOTE: No need to update the size, as the size of a directory is always zero.
-
-				let mut clusters_it = self.clusters();
-				let mut cluster = self.start_cluster;
-				let mut idx = self.fs.cluster_size / 32;	// Default to past the end
-				while let Some(c) = clusters_it.next() {
-					cluster = c;
-					if let Some((end_cluster, end_idx)) = end_entry {
-						if c == end_cluster {
-							idx = end_idx;
-							break;
-						}
-					}
-				}
+		}
 
-				while ents_it.peek().is_some()
-				{
-					assert!(idx <= self.fs.cluster_size / 32);
-					if idx == self.fs.cluster_size / 32 {
-						if let Some(next_cluster) = clusters_it.next() {
-							cluster = next_cluster;
-						}
-						else {
-							todo!("Allocate a new cluster?");
-						}
-						idx = 0;
-					}
-					::kernel::futures::block_on(self.fs.edit_cluster(cluster, |data| {
-						while idx < self.fs.cluster_size / 32 {
-							let Some(ent) = ents_it.next() else { break; };
-							log_debug!("WRITE: {} @ {} {:?}", cluster, idx*32, ent);
-							ent.to_raw(&mut data[idx*32..][..32]);
-							log_debug!("- {:x?}", &data[idx*32..][..32]);
-							idx += 1;
-						}
-						}))?;
+		if !same_dir {
+			if is_dir {
+				// Update the `..` entry (zero if the new parent is the root)
+				let parent = if new_dir.start_cluster == self.fs.root_first_cluster { 0 } else { new_dir.start_cluster.get() };
+				::kernel::futures::block_on(self.fs.edit_cluster(src.cluster, |data| {
+					let mut ent = on_disk::DirEnt::read(&mut &data[32..][..32]);
+					ent.cluster = parent as u16;
+					ent.cluster_hi = (parent >> 16) as u16;
+					ent.write(&mut &mut data[32..][..32]);
+					}))?;
+			}
+			else if src.cluster != cluster_none() {
+				// The name moved directories, so update the record of where the file's entry is
+				if let Some(info) = lh_files.get_mut(&src.cluster) {
+					info.dir_cluster = new_dir.start_cluster;
 				}
 			}
 		}
-		Ok( super::InodeRef::new(new_cluster, self.start_cluster).to_id() )
-	}
-	fn link(&self, name: &ByteStr, node: &dyn node::NodeBase) -> node::Result<()> {
-		todo!("DirNode::link('{:?}', {:#x})", name, node.get_id());
-	}
-	fn unlink(&self, name: &ByteStr) -> node::Result<()> {
-		todo!("DirNode::unlink('{:?}')", name);
+		Ok( () )
 	}
 }
 
@@ -853,17 +1313,13 @@ struct CreateDirents {
 	long_name: ::core::iter::Rev<::kernel::lib::vec::IntoIter<DirEntLong>>,
 }
 impl CreateDirents {
-	fn new(node_type: node::NodeType, target_cluster: ClusterNum, name: [u8; 8+1+3], long_name: Option<&'_ ByteStr>) -> Self {
+	fn new(attributes: u8, target_cluster: ClusterNum, size: u32, times: DirEntTimes, name: [u8; 8+1+3], long_name: Option<&'_ ByteStr>) -> Self {
 		let short_ent = DirEntShort {
 			name,
 			cluster: target_cluster,
-			size: 0,
-			attributes: match node_type
-				{
-				node::NodeType::File => on_disk::ATTR_ARCHIVE,
-				node::NodeType::Dir => on_disk::ATTR_DIRECTORY,
-				node::NodeType::Symlink(_) => todo!("Symlink"),
-				},
+			size,
+			attributes,
+			times,
 			};
 		let short_name_checksum = short_ent.get_encoded_name().1.iter().copied().fold(0, |sum, b| {
 			u8::wrapping_add((sum >> 1) + (sum << 7), b)
@@ -874,10 +1330,14 @@ impl CreateDirents {
 				let mut it = ::utf16::wtf8_to_utf16(long_name.map(|v| v.as_bytes()).unwrap_or(&[]));
 				let mut rv = vec![];
 				while let Some(cp) = it.next() {
-					let mut seg = [0u16; 13];
+					// Unused characters are a NUL terminator then 0xFFFF padding
+					let mut seg = [0xFFFFu16; 13];
 					seg[0] = cp;
 					for i in 1 .. 13 {
-						let Some(cp) = it.next() else { break; };
+						let Some(cp) = it.next() else {
+							seg[i] = 0;
+							break;
+							};
 						seg[i] = cp;
 					}
 					rv.push(DirEntLong {
@@ -5,10 +5,48 @@ use kernel::lib::byteorder::{ReadBytesExt,LittleEndian};
 use super::Size;
 use super::ClusterNum;
 
-// End-of-chain marker values
+// End-of-chain marker values (anything at or above the `_MIN` value is also end-of-chain)
 const FAT12_EOC: u16 = 0x0FFF;
+const FAT12_EOC_MIN: u16 = 0x0FF8;
 const FAT16_EOC: u16 = 0xFFFF;
-const FAT32_EOC: u32 = 0x00FFFFFF;	// FAT32 is actually FAT24...
+const FAT16_EOC_MIN: u16 = 0xFFF8;
+const FAT32_EOC: u32 = 0x0FFFFFFF;	// FAT32 is actually FAT28, the top four bits are reserved
+const FAT32_EOC_MIN: u32 = 0x0FFFFFF8;
+const FAT32_MASK: u32 = 0x0FFFFFFF;
+
+const FSINFO_SIG_LEAD: u32 = 0x41615252;
+const FSINFO_SIG_STRUCT: u32 = 0x61417272;
+const FSINFO_UNKNOWN: u32 = 0xFFFFFFFF;
+
+/// FAT32 FSInfo sector (cached free cluster count)
+pub struct FsInfo
+{
+	sector: u64,
+	free_count: ::kernel::sync::Mutex<u32>,
+}
+impl FsInfo
+{
+	/// Load and validate the FSInfo sector
+	pub fn load(vh: &::block_cache::CachedVolume, sector: u64) -> Result<Option<FsInfo>, storage::IoError>
+	{
+		let mut buf = [0; 4*3];
+		::kernel::futures::block_on( vh.read_inner(sector, 0, &mut buf[..4]) )?;
+		::kernel::futures::block_on( vh.read_inner(sector, 484, &mut buf[4..]) )?;
+		let mut buf = &buf[..];
+		let lead = buf.read_u32::<LittleEndian>().unwrap();
+		let sig = buf.read_u32::<LittleEndian>().unwrap();
+		let free_count = buf.read_u32::<LittleEndian>().unwrap();
+		if lead != FSINFO_SIG_LEAD || sig != FSINFO_SIG_STRUCT {
+			log_notice!("FSInfo sector {} has bad signatures ({:#x},{:#x}), ignoring", sector, lead, sig);
+			return Ok(None);
+		}
+		log_debug!("FSInfo: {} free clusters", free_count as i32);
+		Ok(Some(FsInfo {
+			sector,
+			free_count: ::kernel::sync::Mutex::new(free_count),
+			}))
+	}
+}
 
 /// FAT management methods
 impl super::FilesystemInner
@@ -35,15 +73,36 @@ impl super::FilesystemInner
 		Ok( Some(cluster_idx) )
 	}
 
+	/// Allocate a new cluster (unchained) and fill it with zeroes
+	pub fn alloc_cluster_zeroed(&self, prev_cluster: ClusterNum, chain: bool) -> Result< Option<ClusterNum>, storage::IoError > {
+		let rv = if chain {
+				self.alloc_cluster_chained(prev_cluster)?
+			}
+			else {
+				self.alloc_cluster_unchained(prev_cluster)?
+			};
+		if let Some(c) = rv {
+			::kernel::futures::block_on(self.edit_cluster(c, |data| data.fill(0)))?;
+		}
+		Ok(rv)
+	}
+
 	/// Allocate a new cluster as the start of a new chain (use the previous cluster to maybe reduce fragmentation)
 	pub fn alloc_cluster_unchained(&self, prev_cluster: ClusterNum) -> Result< Option<ClusterNum>, storage::IoError > {
+		let rv = self.alloc_cluster_unchained_inner(prev_cluster)?;
+		if rv.is_some() {
+			self.update_free_count(-1)?;
+		}
+		Ok(rv)
+	}
+	fn alloc_cluster_unchained_inner(&self, prev_cluster: ClusterNum) -> Result< Option<ClusterNum>, storage::IoError > {
 		let prev_cluster = if prev_cluster.get() == super::FATL_ROOT_CLUSTER { 2 } else { prev_cluster.get() };
 		// Search for an unallocated cluster in the FAT, starting from `prev_cluster`
 		// - May need to use a pre-allocated bitmap to speed up allocation?
 		// - Or just iterate the FAT
 		let cps = match self.ty
 			{
-			Size::Fat12 => (self.vh.block_size() / 3 * 2) as u32,	// 2 per 3 bytes
+			Size::Fat12 => (self.vh.block_size() / 3 * 2) as u32,	// Not sector aligned, but a reasonable search chunk
 			Size::Fat16 => (self.vh.block_size() / 2) as u32,
 			Size::Fat32 => (self.vh.block_size() / 4) as u32,
 			};
@@ -59,7 +118,7 @@ impl super::FilesystemInner
 				prev_cluster + 1
 			};
 		// Iterate until the end of the list
-		for base in (aligned .. self.cluster_count as u32).step_by(cps as usize)
+		for base in (aligned .. self.cluster_count as u32 + 2).step_by(cps as usize)
 		{
 			if let Some(rv) = self.find_and_alloc_cluster_in_sector(base, 0, cps)? {
 				assert_eq!(self.get_fat_entry(rv).unwrap(), FatEntry::EndOfChain);
@@ -98,10 +157,75 @@ impl super::FilesystemInner
 		}
 		// Set this cluster to 0 (must have been EOC)
 		self.set_fat_entry(cluster, FatEntry::EndOfChain, FatEntry::Unallocated)?;
+		self.update_free_count(1)?;
+		Ok( () )
+	}
+
+	/// Release every cluster in a chain (starting at `first`)
+	pub fn release_chain(&self, first: ClusterNum) -> Result<(), ::vfs::Error> {
+		let mut cur = Some(first);
+		while let Some(cluster) = cur
+		{
+			cur = self.get_next_cluster(cluster)?;
+			let prev = match cur
+				{
+				Some(next) => FatEntry::Chain(next.get()),
+				None => FatEntry::EndOfChain,
+				};
+			self.set_fat_entry(cluster, prev, FatEntry::Unallocated)?;
+			self.update_free_count(1)?;
+		}
+		Ok( () )
+	}
+	/// Shorten a chain to `keep` clusters (at least one is always kept)
+	pub fn truncate_chain(&self, first: ClusterNum, keep: usize) -> Result<(), ::vfs::Error> {
+		let mut last = first;
+		for _ in 1 .. keep
+		{
+			match self.get_next_cluster(last)?
+			{
+			Some(c) => last = c,
+			None => return Ok( () ),
+			}
+		}
+		if let Some(next) = self.get_next_cluster(last)?
+		{
+			self.set_fat_entry(last, FatEntry::Chain(next.get()), FatEntry::EndOfChain)?;
+			self.release_chain(next)?;
+		}
 		Ok( () )
 	}
 
-	// TODO: A deallocation that chains?
+	/// Update the FSInfo free cluster count (if present and known)
+	fn update_free_count(&self, delta: i32) -> Result<(), storage::IoError> {
+		let Some(ref fsinfo) = self.fsinfo else { return Ok( () ) };
+		let mut lh = fsinfo.free_count.lock();
+		if *lh == FSINFO_UNKNOWN {
+			return Ok( () );
+		}
+		*lh = (*lh as i32 + delta) as u32;
+		let v = *lh;
+		::kernel::futures::block_on(self.vh.edit(fsinfo.sector, 1, |data| {
+			write_u32_le(&mut data[488..][..4], v);
+			}))
+	}
+	/// Copy a modified FAT sector to the other FAT copies
+	fn mirror_fat_sector(&self, sector_idx: u64) -> Result<(), storage::IoError> {
+		if self.fat_count < 2 {
+			return Ok( () );
+		}
+		let data = {
+			let blk = ::kernel::futures::block_on(self.vh.get_block(sector_idx))?;
+			let ofs = (sector_idx - blk.index()) as usize * self.vh.block_size();
+			blk.data()[ofs..][..self.vh.block_size()].to_vec()
+			};
+		for i in 1 .. self.fat_count
+		{
+			let mirror_sector = sector_idx + (i * self.fat_size) as u64;
+			::kernel::futures::block_on(self.vh.edit(mirror_sector, 1, |d| d.copy_from_slice(&data)))?;
+		}
+		Ok( () )
+	}
 }
 
 #[derive(Copy,Clone,Debug, PartialEq)]
@@ -111,27 +235,27 @@ enum FatEntry {
 	Chain(u32),
 }
 impl FatEntry {
-	fn from_fat12_outer(val: u32, is_second: bool) -> Self {
-		Self::from_fat12(if is_second { (val >> 12) as u16 } else { (val & 0xFFF) as u16 })
+	fn from_fat12_pair(val: u16, is_odd: bool) -> Self {
+		Self::from_fat12(if is_odd { val >> 4 } else { val & 0xFFF })
 	}
 	fn from_fat12(val: u16) -> Self {
 		match val {
 		0 => FatEntry::Unallocated,
-		FAT12_EOC => FatEntry::EndOfChain,
+		FAT12_EOC_MIN ..= FAT12_EOC => FatEntry::EndOfChain,
 		val => FatEntry::Chain(val as u32),
 		}
 	}
 	fn from_fat16(val: u16) -> Self {
 		match val {
 		0 => FatEntry::Unallocated,
-		FAT16_EOC => FatEntry::EndOfChain,
+		FAT16_EOC_MIN ..= FAT16_EOC => FatEntry::EndOfChain,
 		val => FatEntry::Chain(val as u32),
 		}
 	}
 	fn from_fat32(val: u32) -> Self {
-		match val {
+		match val & FAT32_MASK {
 		0 => FatEntry::Unallocated,
-		FAT32_EOC => FatEntry::EndOfChain,
+		FAT32_EOC_MIN ..= FAT32_EOC => FatEntry::EndOfChain,
 		val => FatEntry::Chain(val),
 		}
 	}
@@ -143,12 +267,12 @@ impl FatEntry {
 		FatEntry::Chain(val) => val as u16,
 		}
 	}
-	fn to_fat12_outer(self, prev: u32, is_second: bool) -> u32 {
-		if is_second {
-			(prev & 0xFFF) | (self.to_fat12() as u32) << 12
+	fn to_fat12_pair(self, prev: u16, is_odd: bool) -> u16 {
+		if is_odd {
+			(prev & 0x000F) | self.to_fat12() << 4
 		}
 		else {
-			(prev & 0xFFF_000) | (self.to_fat12() as u32) << 0
+			(prev & 0xF000) | self.to_fat12()
 		}
 	}
 	fn to_fat16(self) -> u16 {
@@ -169,34 +293,61 @@ impl FatEntry {
 
 impl super::FilesystemInner
 {
+	/// Get the location of a FAT entry: (sector, byte offset in sector, entry length, entries per sector)
+	///
+	/// FAT12 entries are 1.5 bytes long and are packed across sector boundaries, so their "length" is the two
+	/// bytes that contain them (and the entries per sector is meaningless)
 	fn get_fat_addr(&self, cluster: u32) -> (u64, usize, usize, u32) {
 		// - Determine what sector contains the requested FAT entry
 		let bs = self.vh.block_size();
-		let cps;
-		let (ofs, ent_len) = match self.ty
+		let (byte_ofs, ent_len, cps) = match self.ty
 			{
-			Size::Fat12 => {
-				cps = bs as u32 / 3 * 2;	// 2 per 3 bytes
-				( (cluster % cps) / 2 * 3, 3 )
-				},
-			Size::Fat16 => {
-				cps = bs as u32 / 2;
-				( (cluster % cps) * 2, 2, )
-				},
-			Size::Fat32 => {
-				cps = bs as u32 / 4;
-				( (cluster % cps) * 4, 4, )
-				},
+			Size::Fat12 => (cluster + cluster / 2, 2, 0),
+			Size::Fat16 => (cluster * 2, 2, bs as u32 / 2),
+			Size::Fat32 => (cluster * 4, 4, bs as u32 / 4),
 			};
-		let sector_idx = self.first_fat_sector as u64 + (cluster / cps) as u64;
+		let sector_idx = self.first_fat_sector as u64 + (byte_ofs as usize / bs) as u64;
 		//log_trace!("get_fat_addr({}): S {} ofs={} ent_len={} cps={}", cluster, sector_idx, ofs, ent_len, cps);
-		(sector_idx, ofs as usize, ent_len, cps)
+		(sector_idx, byte_ofs as usize % bs, ent_len, cps)
+	}
+
+	/// Read the two bytes holding a FAT12 entry (which may span two sectors)
+	fn read_fat12_pair(&self, sector_idx: u64, ofs: usize) -> Result<u16, storage::IoError> {
+		let mut buf = [0; 2];
+		if ofs + 2 > self.vh.block_size() {
+			::kernel::futures::block_on( self.vh.read_inner(sector_idx, ofs, &mut buf[..1]) )?;
+			::kernel::futures::block_on( self.vh.read_inner(sector_idx+1, 0, &mut buf[1..]) )?;
+		}
+		else {
+			::kernel::futures::block_on( self.vh.read_inner(sector_idx, ofs, &mut buf) )?;
+		}
+		Ok( u16::from_le_bytes(buf) )
+	}
+	/// Write the two bytes holding a FAT12 entry
+	fn write_fat12_pair(&self, sector_idx: u64, ofs: usize, val: u16) -> Result<(), storage::IoError> {
+		let bytes = val.to_le_bytes();
+		if ofs + 2 > self.vh.block_size() {
+			::kernel::futures::block_on( self.vh.edit(sector_idx, 1, |d| d[ofs] = bytes[0]) )?;
+			::kernel::futures::block_on( self.vh.edit(sector_idx+1, 1, |d| d[0] = bytes[1]) )?;
+			self.mirror_fat_sector(sector_idx)?;
+			self.mirror_fat_sector(sector_idx+1)?;
+		}
+		else {
+			::kernel::futures::block_on( self.vh.edit(sector_idx, 1, |d| d[ofs..][..2].copy_from_slice(&bytes)) )?;
+			self.mirror_fat_sector(sector_idx)?;
+		}
+		Ok( () )
 	}
 
 	/// Read a FAT entry
 	fn get_fat_entry(&self, cluster: ClusterNum) -> Result<FatEntry, storage::IoError> {
 		let (sector_idx, ofs, ent_len, _cps) = self.get_fat_addr(cluster.get());
 
+		if let Size::Fat12 = self.ty {
+			let val = self.read_fat12_pair(sector_idx, ofs)?;
+			return Ok( FatEntry::from_fat12_pair(val, cluster.get() % 2 == 1) );
+		}
+
 		// - Read entry from the FAT
 		let mut buf = [0; 4];
 		::kernel::futures::block_on( self.vh.read_inner(sector_idx, ofs, &mut buf[..ent_len]) )?;
@@ -205,48 +356,41 @@ impl super::FilesystemInner
 		// - Extract the entry
 		Ok(match self.ty
 		{
-		// FAT12 has special handling because it packs 2 entries into 24 bytes
-		Size::Fat12 => FatEntry::from_fat12_outer(buf.read_uint::<LittleEndian>(3).unwrap() as u32, cluster.get() % 2 == 1),
+		Size::Fat12 => unreachable!(),
 		Size::Fat16 => FatEntry::from_fat16(buf.read_u16::<LittleEndian>().unwrap()),
 		Size::Fat32 => FatEntry::from_fat32(buf.read_u32::<LittleEndian>().unwrap()),
 		})
 	}
 	/// Update a FAT entry, checking the previous value
 	fn set_fat_entry(&self, cluster: ClusterNum, exp_prev: FatEntry, new: FatEntry) -> Result< (), storage::IoError > {
+		let _lh = self.fat_lock.lock();
+		self.set_fat_entry_locked(cluster, exp_prev, new)
+	}
+	fn set_fat_entry_locked(&self, cluster: ClusterNum, exp_prev: FatEntry, new: FatEntry) -> Result< (), storage::IoError > {
 		let (sector_idx, ofs, ent_len, _cps) = self.get_fat_addr(cluster.get());
 
+		// FAT12 has special handling because it packs 2 entries into 3 bytes (and they can span sectors)
+		if let Size::Fat12 = self.ty {
+			let is_odd = cluster.get() % 2 == 1;
+			let val = self.read_fat12_pair(sector_idx, ofs)?;
+			let cur = FatEntry::from_fat12_pair(val, is_odd);
+			if cur != exp_prev {
+				log_error!("FAT Check failure: {} expected {:?} got {:?}", cluster, exp_prev, cur);
+				return Err(storage::IoError::Unknown("FAT: Internal assertion failure"));
+			}
+			return self.write_fat12_pair(sector_idx, ofs, new.to_fat12_pair(val, is_odd));
+		}
+
 		// Use `block_cache`'s read/write locks
 		let changed = ::kernel::futures::block_on(self.vh.edit(sector_idx, 1, |buf| {
 			let buf = &mut buf[ofs..][..ent_len];
 			match self.ty
 			{
-			// FAT12 has special handling because it packs 2 entries into 24 bytes
-			Size::Fat12 => {
-				let val = (&buf[..]).read_uint::<LittleEndian>(3).unwrap() as u32;
-				let newval = if cluster.get() % 2 == 0 {
-						let cur = (val & 0xFFF) as u16;
-						if cur != exp_prev.to_fat12() {
-							log_error!("FAT Check failure: {} expected {:?} got {:?}",
-								cluster, exp_prev, FatEntry::from_fat12(cur));
-							return false;
-						}
-						(val & 0xFFF_000) | (new.to_fat12() as u32) << 0
-					}
-					else {
-						let cur = (val >> 12) as u16;
-						if cur != exp_prev.to_fat12() {
-							log_error!("FAT Check failure: {} expected {:?} got {:?}",
-								cluster, exp_prev, FatEntry::from_fat12(cur));
-							return false;
-						}
-						(val & 0x000_FFF) | (new.to_fat12() as u32) << 12
-					};
-				write_u24_le(buf, newval);
-				},
+			Size::Fat12 => unreachable!(),
 			// Simple read+check and write for FAT16/FAT32
 			Size::Fat16 => {
 				let val = (&buf[..]).read_u16::<LittleEndian>().unwrap();
-				if val != exp_prev.to_fat16() {
+				if FatEntry::from_fat16(val) != exp_prev {
 					log_error!("FAT Check failure: {} expected {:?} got {:?}",
 						cluster, exp_prev, FatEntry::from_fat16(val));
 					return false;
@@ -255,12 +399,13 @@ impl super::FilesystemInner
 				},
 			Size::Fat32 => {
 				let val = (&buf[..]).read_u32::<LittleEndian>().unwrap();
-				if val != exp_prev.to_fat32() {
+				if FatEntry::from_fat32(val) != exp_prev {
 					log_error!("FAT Check failure: {} expected {:?} got {:?}",
 						cluster, exp_prev, FatEntry::from_fat32(val));
 					return false;
 				}
-				write_u32_le(buf, new.to_fat32());
+				// Preserve the reserved upper bits
+				write_u32_le(buf, (val & !FAT32_MASK) | new.to_fat32());
 				},
 			}
 			true
@@ -270,7 +415,7 @@ impl super::FilesystemInner
 			Err(storage::IoError::Unknown("FAT: Internal assertion failure"))
 		}
 		else {
-			Ok( () )
+			self.mirror_fat_sector(sector_idx)
 		}
 	}
 
@@ -280,24 +425,29 @@ impl super::FilesystemInner
 	/// * `end`: Sector-internal index of the past-end cluster to consider
 	fn find_and_alloc_cluster_in_sector(&self, base: u32, start: u32, end: u32) -> Result<Option<ClusterNum>, storage::IoError>
 	{
-		assert!(base < self.cluster_count as u32, "find_and_alloc_cluster_in_sector: base {} >= count {}", base, self.cluster_count);
+		let max_cluster = self.cluster_count as u32 + 2;
+		assert!(base < max_cluster, "find_and_alloc_cluster_in_sector: base {} >= count {}", base, max_cluster);
+		let _lh = self.fat_lock.lock();
+		if let Size::Fat12 = self.ty {
+			// Entries don't align to sectors, so just check each one
+			for idx in (base + start).max(2) .. (base + end).min(max_cluster) {
+				let c = ClusterNum::new(idx).unwrap();
+				if self.get_fat_entry(c)? == FatEntry::Unallocated {
+					self.set_fat_entry_locked(c, FatEntry::Unallocated, FatEntry::EndOfChain)?;
+					return Ok(Some(c));
+				}
+			}
+			return Ok(None);
+		}
 		let (sector_idx, _ofs, _ent_size, cps) = self.get_fat_addr(base);
 		assert!(base % cps == 0);
 		// Clamp the end to CPS (should be already), and to the last cluster in the volume (may not be)
-		let end = end.min(cps).min(self.cluster_count as u32 - base);
-		::kernel::futures::block_on( self.vh.edit(sector_idx, 1, |data| {
+		// - Clusters 0 and 1 are reserved (and never unallocated)
+		let end = end.min(cps).min(max_cluster - base);
+		let rv = ::kernel::futures::block_on( self.vh.edit(sector_idx, 1, |data| {
 			match self.ty
 			{
-			Size::Fat12 => {
-				for sub_idx in start .. end {
-					let buf = &mut data[sub_idx as usize / 2 * 3..][..3];
-					let val = {&buf[..]}.read_uint::<LittleEndian>(3).unwrap() as u32;
-					if let FatEntry::Unallocated = FatEntry::from_fat12_outer(val, sub_idx % 2 == 1) {
-						write_u24_le(buf, FatEntry::EndOfChain.to_fat12_outer(val, sub_idx % 2 == 1));
-						return Some(ClusterNum::new(base + sub_idx).unwrap());
-					}
-				}
-				},
+			Size::Fat12 => unreachable!(),
 			Size::Fat16 => {
 				for sub_idx in start .. end {
 					let buf = &mut data[sub_idx as usize * 2..][..2];
@@ -313,22 +463,21 @@ impl super::FilesystemInner
 					let buf = &mut data[sub_idx as usize * 4..][..4];
 					let val = {&buf[..]}.read_u32::<LittleEndian>().unwrap();
 					if let FatEntry::Unallocated = FatEntry::from_fat32(val) {
-						write_u32_le(buf, FatEntry::EndOfChain.to_fat32());
+						write_u32_le(buf, (val & !FAT32_MASK) | FatEntry::EndOfChain.to_fat32());
 						return Some(ClusterNum::new(base + sub_idx).unwrap());
 					}
 				}
 				},
 			}
 			None
-			}) )
+			}) )?;
+		if rv.is_some() {
+			self.mirror_fat_sector(sector_idx)?;
+		}
+		Ok(rv)
 	}
 }
 
-fn write_u24_le(dst: &mut [u8], val: u32) {
-	dst[0] = (val >>  0) as u8;
-	dst[1] = (val >>  8) as u8;
-	dst[2] = (val >> 16) as u8;
-}
 fn write_u16_le(dst: &mut [u8], val: u16) {
 	dst.copy_from_slice(&val.to_le_bytes());
 }
@@ -7,38 +7,121 @@ use kernel::lib::mem::aref::ArefBorrow;
 use ::vfs::node;
 use super::FilesystemInner;
 use super::ClusterNum;
+use kernel::lib::mem::Arc;
 
 const ERROR_SHORTCHAIN: ::vfs::Error = ::vfs::Error::Unknown("Cluster chain terminated early");
 
 pub struct FileNode
 {
 	fs: ArefBorrow<FilesystemInner>,
+	/// Directory this node was opened from (used for the inode number)
+	dir_cluster: ClusterNum,
 	first_cluster: ClusterNum,
-	size: ::kernel::sync::RwLock<u32>,
+	/// Shared with all other nodes for this file (see `dir::OpenFileInfo`)
+	size: Arc<::kernel::sync::RwLock<u32>>,
 }
 
 impl FileNode
 {
-	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, first_cluster: ClusterNum, size: u32) -> Box<FileNode> {
+	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, dir_cluster: ClusterNum, first_cluster: ClusterNum, size: Arc<::kernel::sync::RwLock<u32>>) -> Box<FileNode> {
 		Box::new(FileNode {
 			fs: fs,
+			dir_cluster,
 			first_cluster,
-			size: ::kernel::sync::RwLock::new(size),
+			size,
 			})
 	}
+
+	/// Number of clusters needed to hold `size` bytes (the first cluster is always allocated, see `DirNode::lookup`)
+	fn clusters_for(&self, size: u32) -> usize {
+		usize::max(1, (size as usize + self.fs.cluster_size - 1) / self.fs.cluster_size)
+	}
+	/// Ensure that the cluster chain is long enough to hold `size` bytes
+	fn ensure_allocated(&self, size: u32) -> node::Result<()> {
+		let mut count = 1;
+		let mut last = self.first_cluster;
+		let needed = self.clusters_for(size);
+		while count < needed
+		{
+			last = match self.fs.get_next_cluster(last)?
+				{
+				Some(c) => c,
+				None => self.fs.alloc_cluster_chained(last)?.ok_or(::vfs::Error::OutOfSpace)?,
+				};
+			count += 1;
+		}
+		Ok( () )
+	}
+
+	/// Write data into already-allocated clusters
+	fn write_inner(&self, ofs: u64, buf: &[u8]) -> node::Result<()> {
+		let cs = self.fs.cluster_size;
+		let mut clusters = super::ClusterList::chained(&self.fs, self.first_cluster);
+		for _ in 0 .. (ofs / cs as u64) {
+			clusters.next();
+		}
+		let ofs = (ofs % cs as u64) as usize;
+
+		let mut cur_ofs = 0;
+		// Leading partial cluster
+		if ofs != 0 {
+			let Some(cluster) = clusters.next() else { return Err(ERROR_SHORTCHAIN); };
+			let len = usize::min(cs - ofs, buf.len());
+			::kernel::futures::block_on(self.fs.edit_cluster(cluster, |c| {
+				c[ofs..][..len].copy_from_slice(&buf[..len]);
+				}))?;
+			cur_ofs += len;
+		}
+		// Whole clusters
+		while buf.len() - cur_ofs >= cs
+		{
+			let src = &buf[cur_ofs..];
+			let Some((cluster, count)) = clusters.next_extent(src.len() / cs) else { return Err(ERROR_SHORTCHAIN); };
+			let bytes = count * cs;
+			::kernel::futures::block_on(self.fs.write_clusters(cluster, &src[..bytes]))?;
+			cur_ofs += bytes;
+		}
+		// Trailing partial cluster
+		if buf.len() > cur_ofs {
+			let src = &buf[cur_ofs..];
+			let Some(cluster) = clusters.next() else { return Err(ERROR_SHORTCHAIN); };
+			::kernel::futures::block_on(self.fs.edit_cluster(cluster, |c| {
+				c[..src.len()].copy_from_slice(src);
+				}))?;
+		}
+		Ok( () )
+	}
+	/// Fill a range of (allocated) clusters with zeroes
+	fn zero_range(&self, mut ofs: u64, end: u64) -> node::Result<()> {
+		let zeroes = vec![0; self.fs.cluster_size];
+		while ofs < end
+		{
+			let len = u64::min(end - ofs, (self.fs.cluster_size as u64) - ofs % self.fs.cluster_size as u64);
+			self.write_inner(ofs, &zeroes[..len as usize])?;
+			ofs += len;
+		}
+		Ok( () )
+	}
 }
 impl ::core::ops::Drop for FileNode {
 	fn drop(&mut self) {
-		//super::dir::close_file(&self.fs, self.first_cluster);
+		self.fs.close_file(self.first_cluster);
 	}
 }
 impl node::NodeBase for FileNode {
 	fn get_id(&self) -> node::InodeId {
-		todo!("FileNode::get_id")
+		super::InodeRef::new(self.first_cluster, self.dir_cluster).to_id()
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		let size_lh = self.size.read();
+		super::dir::get_file_metadata(&self.fs, self.first_cluster, *size_lh)
+	}
+	fn set_metadata(&self, changes: &node::MetadataUpdate) -> node::Result<()> {
+		super::dir::set_file_metadata(&self.fs, self.first_cluster, changes)
+	}
 }
 impl node::File for FileNode {
 	fn size(&self) -> u64 {
@@ -49,19 +132,26 @@ impl node::File for FileNode {
 		let mut size_lh = self.size.write();
 		if newsize < *size_lh {
 			// Update size, and then deallocate clusters
-			let old_size = *size_lh;
 			super::dir::update_file_size(&self.fs, self.first_cluster, newsize)?;
 			*size_lh = newsize;
-			todo!("FileNode::truncate({:#x}): old_size={:#x}", newsize, old_size);
+			self.fs.truncate_chain(self.first_cluster, self.clusters_for(newsize))?;
 		}
-		else {
-			// Allocate new clusters, then update the size
-			// Update size iteratively, allocating clusters as needed
-			todo!("FileNode::truncate({:#x})", newsize);
+		else if newsize > *size_lh {
+			// Allocate and clear new clusters, then update the size
+			self.ensure_allocated(newsize)?;
+			self.zero_range(*size_lh as u64, newsize as u64)?;
+			super::dir::update_file_size(&self.fs, self.first_cluster, newsize)?;
+			*size_lh = newsize;
 		}
+		Ok( newsize as u64 )
 	}
 	fn clear(&self, ofs: u64, size: u64) -> node::Result<()> {
-		todo!("FileNode::clear({:#x}+{:#x}", ofs, size);
+		let size_lh = self.size.read();
+		if ofs > *size_lh as u64 {
+			return Err( ::vfs::Error::InvalidParameter );
+		}
+		let end = u64::min(ofs.saturating_add(size), *size_lh as u64);
+		self.zero_range(ofs, end)
 	}
 	fn read(&self, ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
 		let maxread = {
@@ -143,50 +233,27 @@ impl node::File for FileNode {
 		log_trace!("read(): Complete {}", read_length);
 		Ok( read_length )
 	}
-	/// Write data to the file, can only grow the file if ofs==size
-	fn write(&self, ofs: u64, mut buf: &[u8]) -> node::Result<usize> {
+	/// Write data to the file, growing it if the write extends past the end
+	fn write(&self, ofs: u64, buf: &[u8]) -> node::Result<usize> {
 		let mut size_lh = self.size.write();
-		if ofs == *size_lh as u64 {
-			let rv = buf.len();
-			// Write data, allocating new clusters if needed
-			let mut clusters = super::ClusterList::chained(&self.fs, self.first_cluster);
-			let mut prev_cluster = self.first_cluster;
-			for _ in 0 .. (ofs/self.fs.cluster_size as u64) {
-				prev_cluster = clusters.next().ok_or(vfs::Error::InconsistentFilesystem)?;
-			}
-			let ofs = (ofs % self.fs.cluster_size as u64) as usize;
-			while buf.len() > 0
-			{
-				let cluster = if let Some(c) = clusters.next() {
-						c
-					} else {
-						// Need to allocate a new one!
-						self.fs.alloc_cluster_chained(prev_cluster)?.ok_or(vfs::Error::OutOfSpace)?
-					};
-				let len = usize::min(self.fs.cluster_size - ofs, buf.len());
-				if len == self.fs.cluster_size {
-					assert!(ofs == 0);
-					::kernel::futures::block_on(self.fs.write_clusters(cluster, &buf[..len]))?;
-				}
-				else {
-					::kernel::futures::block_on(self.fs.edit_cluster(cluster, |c| {
-						c[ofs..][..len].copy_from_slice(&buf[..len]);
-						}))?;
-				}
-				buf = &buf[len..];
-				*size_lh += len as u32;
-			}
-			// Update the size
-			super::dir::update_file_size(&self.fs, self.first_cluster, *size_lh)?;
-
-			Ok(rv)
+		if ofs > *size_lh as u64 {
+			// Writes can't leave a hole (the caller can `truncate` first)
+			return Err( ::vfs::Error::InvalidParameter );
 		}
-		else {
-			// Don't want the size to reduce while this is happening...
-			// - But it _could_ increase due to append?
-			// - or just prevent a change
-			todo!("FileNode::write({:#x}, {:p}) - inplace", ofs, ::kernel::lib::SlicePtr(buf));
+		let end = ofs + buf.len() as u64;
+		// FAT file sizes are 32-bit
+		let end: u32 = ::core::convert::TryFrom::try_from(end).map_err(|_| ::vfs::Error::OutOfSpace)?;
+
+		// Allocate clusters to cover the new end (if needed), then write data
+		self.ensure_allocated(end)?;
+		self.write_inner(ofs, buf)?;
+
+		// Update the size
+		if end > *size_lh {
+			super::dir::update_file_size(&self.fs, self.first_cluster, end)?;
+			*size_lh = end;
 		}
+		Ok(buf.len())
 	}
 }
 
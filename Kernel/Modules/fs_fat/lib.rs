@@ -69,7 +69,15 @@ pub struct FilesystemInner
 	/// Total number of data clusters
 	cluster_count: usize,
 	first_fat_sector: usize,
+	/// Number of copies of the FAT (all are kept in sync)
+	fat_count: usize,
+	/// Size of one FAT in sectors
+	fat_size: usize,
 	first_data_sector: usize,
+	/// FAT32 free cluster count
+	fsinfo: Option<fat::FsInfo>,
+	/// Serialises FAT updates (FAT12 entries span sectors, and updates are mirrored)
+	fat_lock: ::kernel::sync::Mutex<()>,
 	
 	root_first_cluster: ClusterNum,
 	root_sector_count: u32,
@@ -77,6 +85,10 @@ pub struct FilesystemInner
 	// TODO: Directory handles (with the dir's lock, and the number of open handles/files)
 	dir_info: ::kernel::sync::RwLock<::kernel::lib::collections::VecMap<ClusterNum,Arc<dir::DirInfo>>>,
 	open_files: ::kernel::sync::RwLock<::kernel::lib::collections::VecMap<ClusterNum,dir::OpenFileInfo>>,
+	/// Ownership/permissions reported for all nodes (FAT only has a read-only flag)
+	perms: ::vfs::mount::PermissionDefaults,
+	/// Mounted read-only, nothing may be written (not even the clusters `DirNode::lookup` assigns to empty files)
+	read_only: bool,
 }
 
 
@@ -109,7 +121,7 @@ impl mount::Driver for Driver
 			Ok(1)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle) -> ::vfs::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, read_only: bool, mounthandle: mount::SelfHandle) -> ::vfs::Result<Box<dyn mount::Filesystem>> {
 		let vol = ::block_cache::CachedVolume::new(vol);
 
 		// Read the bootsector
@@ -149,7 +161,7 @@ impl mount::Driver for Driver
 		let first_data_sector = bs_c.reserved_sect_count as usize
 			+ fat_size + spare_fat_sectors
 			+ root_dir_sectors;
-		let cluster_count = (total_sectors - first_data_sector - spare_fat_sectors) / spc;
+		let cluster_count = (total_sectors - first_data_sector) / spc;
 		
 		// Determine the FAT type
 		let fat_type = if cluster_count < FAT16_MIN_CLUSTERS {
@@ -163,6 +175,12 @@ impl mount::Driver for Driver
 			};
 		log_debug!("{:?} {} sectors, Size {}", fat_type, total_sectors,
 			SizePrinter((total_sectors*bs_c.bps as usize) as u64));
+
+		let fsinfo = match bs.info32()
+			{
+			Some(i) if is!(fat_type, Size::Fat32) && i.fs_info != 0 && i.fs_info != 0xFFFF => fat::FsInfo::load(&vol, i.fs_info as u64)?,
+			_ => None,
+			};
 		
 		Ok(Box::new(Filesystem {
 			// SAFE: Saving to a Box, so won't move
@@ -172,7 +190,11 @@ impl mount::Driver for Driver
 				cluster_size: spc * vol.block_size(),
 				cluster_count: cluster_count,
 				first_fat_sector: bs_c.reserved_sect_count as usize,
+				fat_count: bs_c.fat_count as usize,
+				fat_size: fat_size,
 				first_data_sector: first_data_sector,
+				fsinfo: fsinfo,
+				fat_lock: Default::default(),
 				root_first_cluster: match fat_type {
 					Size::Fat32 => ClusterNum::new(bs.info32().unwrap().root_cluster)
 						.map_err(|()| {
@@ -184,6 +206,8 @@ impl mount::Driver for Driver
 				root_sector_count: root_dir_sectors as u32,
 				dir_info: Default::default(),
 				open_files: Default::default(),
+				perms: mounthandle.permission_defaults(),
+				read_only: read_only,
 
 				vh: vol,
 				}) },
@@ -231,16 +255,32 @@ impl FilesystemInner
 		Ok( () )
 	}
 
+	/// Returns true if the cluster is entirely within one cache block
+	fn cluster_in_one_block(&self, sector: u64) -> bool {
+		(sector % self.vh.blocks_per_page()) as usize + self.spc <= self.vh.blocks_per_page() as usize
+	}
 	/// Cached cluster access
 	async fn with_cluster<T>(&self, cluster: ClusterNum, callback: impl FnOnce(&[u8])->T) -> Result<T, storage::IoError> {
 		let sector = self.get_sector_for_cluster(cluster);
+		if !self.cluster_in_one_block(sector) {
+			let mut buf = vec![0; self.cluster_size];
+			self.read_clusters(cluster, &mut buf).await?;
+			return Ok( callback(&buf) );
+		}
 		let block = self.vh.get_block(sector).await?;
 		let ofs = sector - block.index();
-		Ok( callback(&block.data()[ofs as usize * self.vh.block_size()..]) )
+		Ok( callback(&block.data()[ofs as usize * self.vh.block_size()..][..self.cluster_size]) )
 	}
 	async fn edit_cluster(&self, cluster: ClusterNum, callback: impl FnOnce(&mut [u8])) -> Result<(), storage::IoError> {
 		let sector = self.get_sector_for_cluster(cluster);
-		// TODO: What if a cluster is larger than a block?
+		if !self.cluster_in_one_block(sector) {
+			// Spans cache blocks (or is larger than one), so read-modify-write
+			// - Callers hold the relevant directory/file lock
+			let mut buf = vec![0; self.cluster_size];
+			self.read_clusters(cluster, &mut buf).await?;
+			callback(&mut buf);
+			return self.write_clusters(cluster, &buf).await;
+		}
 		self.vh.edit(sector, /*::block_cache::CacheType::Metadata,*/ self.spc, callback).await
 	}
 }
@@ -261,7 +301,7 @@ impl mount::Filesystem for Filesystem
 		}
 		else {
 			assert!(r.first_cluster == self.root_first_cluster);
-			Some(node::Node::Dir(dir::DirNode::new_boxed(self.inner.borrow(), r.first_cluster)))
+			Some(node::Node::Dir(dir::DirNode::new_boxed(self.inner.borrow(), r.first_cluster, None)))
 		}
 	}
 }
@@ -10,13 +10,14 @@ use vfs::{mount, node};
 use kernel::metadevs::storage::{self,VolumeHandle};
 use kernel::lib::mem::aref::{ArefInner,ArefBorrow};
 use kernel::lib::byteorder::{ByteOrder,LittleEndian};
-use kernel::lib::byte_str::ByteStr;
+use kernel::lib::byte_str::{ByteStr,ByteString};
 
 #[macro_use]
 extern crate kernel;
 
 extern crate vfs;
 extern crate block_cache;
+extern crate utf16;
 
 module_define!{FS_ISO9660, [VFS], init}
 
@@ -38,7 +39,10 @@ struct InstanceInner
 	root_lba: u32,
 	root_size: u32,
 
+	/// Rock Ridge is in use (entries have SUSP fields, after this many bytes)
 	susp_len_skip: Option<u8>,
+	/// Names are UCS-2 (the tree from the Joliet supplementary volume descriptor is in use)
+	is_joliet: bool,
 }
 
 fn init()
@@ -64,41 +68,45 @@ impl mount::Driver for Driver
 			Ok(0)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle) -> vfs::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _read_only: bool, _mounthandle: mount::SelfHandle) -> vfs::Result<Box<dyn mount::Filesystem>> {
 		// For this to work properly, the block size must evenly divide 2048
 		if 2048 % vol.block_size() != 0 {
 			return Err( vfs::Error::Unknown("Can't mount ISO9660 with sector size not a factor of 2048"/*, vol.block_size()*/) );
 		}
 		let scale = 2048 / vol.block_size();
 		
-		// Search the start of the disk for the primary volume descriptor
+		// Search the start of the disk for the primary volume descriptor (and a Joliet supplementary descriptor)
 		// - TODO: Limit the number of sectors searched.
 		let mut block = vec![0u8; 2048];
+		let mut primary = None;
+		let mut joliet = None;
 		for sector in 16 .. 
 		{
 			::kernel::futures::block_on(vol.read_blocks((sector*scale) as u64, &mut block))?;
 			if &block[1..6] != b"CD001" {
 				return Err( vfs::Error::Unknown("Invalid volume descriptor present") );
 			}
-			else if block[0] == 255 {
-				return Err( vfs::Error::Unknown("Can't find ISO9660 primary volume descriptor") );
-			}
-			else if block[0] == 0x01 {
-				// Found it!
-				break ;
-			}
-			else {
-				// Try the next one
+			match block[0]
+			{
+			255 => break,
+			0x01 if primary.is_none() => primary = Some(block.clone()),
+			// Supplementary volume descriptor, with a UCS-2 escape sequence (levels 1-3)
+			0x02 if joliet.is_none() && block[88..91].starts_with(b"%/") && b"@CE".contains(&block[90]) => joliet = Some(block.clone()),
+			_ => {},	// Try the next one
 			}
 		}
+		let block = match primary
+			{
+			Some(v) => v,
+			None => return Err( vfs::Error::Unknown("Can't find ISO9660 primary volume descriptor") ),
+			};
 		//::kernel::logging::hex_dump("ISO966 PVD", &block);
 		
 		// Obtain the logical block size (different from medium sector size)
 		let lb_size = LittleEndian::read_u16(&block[128..]);
 		// Extract the root directory entry
 		// - We want the LBA and byte length
-		let root_lba  = LittleEndian::read_u32(&block[156+ 2..]);
-		let root_size = LittleEndian::read_u32(&block[156+10..]);
+		let (root_lba, root_size) = get_root_extent(&block);
 		
 		log_debug!("lb_size = {}, root = {:#x} + {:#x} bytes", lb_size, root_lba, root_size);
 	
@@ -109,11 +117,12 @@ impl mount::Driver for Driver
 			root_lba: root_lba,
 			root_size: root_size,
 			susp_len_skip: None,
+			is_joliet: false,
 			};
 
 		// Determine if SUSP is in use (used for RockRidge extensions)
 		inner.susp_len_skip = {
-			let mut it = DirSector::new(&inner, ::kernel::futures::block_on(inner.get_sector(root_lba))?, 0 );
+			let mut it = DirSector::new(::kernel::futures::block_on(inner.get_sector(root_lba))?, 0 );
 			let first_ent = match it.next()?
 				{
 				None => return Err(vfs::Error::InconsistentFilesystem),
@@ -126,53 +135,94 @@ impl mount::Driver for Driver
 				None
 			}
 			};
+		// - SUSP on its own isn't enough, the root entry must also indicate Rock Ridge
+		if inner.susp_len_skip.is_some() {
+			let is_rock_ridge = {
+				let mut it = DirSector::new(::kernel::futures::block_on(inner.get_sector(root_lba))?, 0 );
+				let first_ent = it.next()?.ok_or(vfs::Error::InconsistentFilesystem)?;
+				::kernel::futures::block_on(inner.entry_info(&first_ent))?.is_rock_ridge
+				};
+			if !is_rock_ridge {
+				inner.susp_len_skip = None;
+			}
+		}
+
+		// Without Rock Ridge, prefer the Joliet tree (it has long names)
+		if inner.susp_len_skip.is_some() {
+			log_debug!("Using Rock Ridge names");
+		}
+		else if let Some(svd) = joliet {
+			log_debug!("Using Joliet names");
+			let (root_lba, root_size) = get_root_extent(&svd);
+			inner.root_lba = root_lba;
+			inner.root_size = root_size;
+			inner.is_joliet = true;
+		}
 		
 		// SAFE: Stored in a box, and not moved out.
 		Ok( Box::new( Instance(unsafe { ArefInner::new( inner ) }) ) )
 	}
 }
 
+/// Get the root directory extent from a volume descriptor
+fn get_root_extent(vd: &[u8]) -> (u32, u32) {
+	(LittleEndian::read_u32(&vd[156+ 2..]), LittleEndian::read_u32(&vd[156+10..]))
+}
+
 impl mount::Filesystem for Instance
 {
 	fn root_inode(&self) -> node::InodeId {
 		0 as node::InodeId
 	}
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
-		if id == 0 {
-			Some(Dir::new_node(self.0.borrow(), self.root_lba, self.root_size) )
+		// Look up (or read) parent directory to obtain the info
+		// - The root's metadata comes from its `.` entry
+		let (sector, ofs) = if id == 0 {
+				(self.root_lba as u64, 0)
+			}
+			else {
+				::kernel::lib::num::div_rem(id as u64, self.lb_size as u64)
+			};
+		let blk = match ::kernel::futures::block_on(self.get_sector(sector as u32))
+			{
+			Ok(v) => v,
+			Err(_) => return None,
+			};
+		let mut it = DirSector::new(blk, ofs as usize);
+		let ent = match it.next()
+			{
+			Ok(Some(v)) => v,
+			Ok(None) => return None,
+			Err(_) => return None,
+			};
+		if ent.name.len() == 0 {
+			None
 		}
 		else {
-			// Look up (or read) parent directory to obtain the info
-			let (sector, ofs) = ::kernel::lib::num::div_rem(id as u64, self.lb_size as u64);
-			let blk = match ::kernel::futures::block_on(self.get_sector(sector as u32))
+			let info = match ::kernel::futures::block_on(self.entry_info(&ent))
 				{
 				Ok(v) => v,
 				Err(_) => return None,
 				};
-			let mut it = DirSector::new(&self.0, blk, ofs as usize);
-			let ent = match it.next()
-				{
-				Ok(Some(v)) => v,
-				Ok(None) => return None,
-				Err(_) => return None,
-				};
-			if ent.name.len() == 0 {
+			let meta = entry_metadata(&ent, &info);
+			if id == 0 {
+				Some(Dir::new_node(self.0.borrow(), id, self.root_lba, self.root_size, meta))
+			}
+			else if let Some(target) = info.symlink {
+				Some(Symlink::new_node(id, target, meta))
+			}
+			else if ent.flags & (1 << 7) != 0 {
+				// Multi-extent file!
+				None
+			}
+			else if ent.flags & (1 << 1) != 0 {
+				Some(Dir::new_node(self.0.borrow(), id, ent.start, ent.size, meta))
+			}
+			else if ent.flags & 0x64 != 0 {
 				None
 			}
 			else {
-				if ent.flags & (1 << 7) != 0 {
-					// Multi-extent file!
-					None
-				}
-				else if ent.flags & (1 << 1) != 0 {
-					Some(Dir::new_node(self.0.borrow(), ent.start, ent.size))
-				}
-				else if ent.flags & 0x64 != 0 {
-					None
-				}
-				else {
-					Some(File::new_node(self.0.borrow(), ent.start, ent.size))
-				}
+				Some(File::new_node(self.0.borrow(), id, ent.start, ent.size, meta))
 			}
 		}
 	}
@@ -196,13 +246,92 @@ impl InstanceInner
 		Ok( () )
 		
 	}
+	/// Decode the name (and any Rock Ridge information) for a directory entry
+	async fn entry_info(&self, ent: &DirEnt<'_>) -> vfs::Result<EntryInfo> {
+		let mut rv = EntryInfo::default();
+		if let Some(skip) = self.susp_len_skip {
+			let skip = skip as usize;
+			if ent.sys_use.len() < skip {
+				log_warning!("System use area smaller than SUSP skip value");
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			self.parse_rock_ridge(&ent.sys_use[skip..], &mut rv).await?;
+		}
+		else if self.is_joliet && ent.name != b"\0" && ent.name != b"\x01" {
+			rv.name = decode_joliet(ent.name);
+		}
+		if rv.name.is_empty() {
+			rv.name = ent.name.to_vec();
+		}
+		Ok(rv)
+	}
+	/// Parse Rock Ridge entries from a system use area (and any continuation areas)
+	async fn parse_rock_ridge(&self, sys_use: &[u8], info: &mut EntryInfo) -> vfs::Result<()> {
+		// Limit on continuation areas, to avoid looping forever on a malformed image
+		const MAX_CONTINUATIONS: usize = 16;
+		let mut continuation: Option<Vec<u8>> = None;
+		let mut component_continues = false;
+		for _ in 0 .. MAX_CONTINUATIONS
+		{
+			let area = match continuation { Some(ref v) => &v[..], None => sys_use };
+			let mut next = None;
+			for ent in SuspIterator(area)
+			{
+				match ent
+				{
+				SuspItem::ContinuationEntry(block, ofs, len) => next = Some( (block, ofs as usize, len as usize) ),
+				SuspItem::RockRidge(_) => info.is_rock_ridge = true,
+				SuspItem::ExtensionReference(id) => {
+					if id == b"RRIP_1991A" || id == b"IEEE_P1282" || id == b"IEEE_1282" {
+						info.is_rock_ridge = true;
+					}
+					},
+				SuspItem::PosixMode { mode, n_links, uid, gid, .. } => {
+					info.is_rock_ridge = true;
+					info.posix = Some( (mode, n_links, uid, gid) );
+					},
+				SuspItem::Timestamps { flags, data } => parse_timestamps(flags, data, &mut info.times),
+				// Names for `.` and `..` are ignored
+				SuspItem::AlternateName(flags, name) => {
+					if flags & (NM_CURRENT|NM_PARENT) == 0 {
+						info.name.extend_from_slice(name);
+					}
+					},
+				SuspItem::Symlink(_flags, components) => {
+					push_symlink_components(info.symlink.get_or_insert_with(Vec::new), components, &mut component_continues);
+					},
+				_ => {},
+				}
+			}
+
+			let (block, ofs, len) = match next
+				{
+				Some(v) => v,
+				None => return Ok( () ),
+				};
+			if ofs + len > self.lb_size {
+				log_warning!("Continuation area {:#x}+{:#x} overruns block {:#x}", ofs, len, block);
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			let mut buf = vec![0; self.lb_size];
+			self.read_sector(block, &mut buf).await?;
+			buf.truncate(ofs + len);
+			buf.drain(.. ofs);
+			continuation = Some(buf);
+		}
+		log_warning!("Too many SUSP continuation areas");
+		Err(vfs::Error::InconsistentFilesystem)
+	}
+
 	/// Read a metadata sector via a cache
 	async fn get_sector(&self, sector: u32) -> Result<Sector<'_>, storage::IoError> {
 		assert!(sector > 0);
 		
-		let blk = self.vh.get_block(sector as u64).await?;
-		let ofs = (sector as u64 - blk.index()) as usize * self.vh.block_size();
-		Ok( Sector(blk, ofs as u16, self.vh.block_size() as u16) )
+		// - Will be round, Driver::mount() ensures this
+		let hwsector = sector as u64 * (self.lb_size / self.vh.block_size()) as u64;
+		let blk = self.vh.get_block(hwsector).await?;
+		let ofs = (hwsector - blk.index()) as usize * self.vh.block_size();
+		Ok( Sector(blk, ofs as u16, self.lb_size as u16) )
 	}
 }
 
@@ -210,27 +339,34 @@ impl InstanceInner
 struct File
 {
 	fs: ArefBorrow<InstanceInner>,
+	inode: node::InodeId,
 	first_lba: u32,
 	size: u32,
+	meta: node::Metadata,
 }
 impl File
 {
-	fn new_node(fs: ArefBorrow<InstanceInner>, first_lba: u32, size: u32) -> node::Node {
+	fn new_node(fs: ArefBorrow<InstanceInner>, inode: node::InodeId, first_lba: u32, size: u32, meta: node::Metadata) -> node::Node {
 		node::Node::File( Box::new( File {
 			fs: fs,
+			inode: inode,
 			first_lba: first_lba,
 			size: size,
+			meta: meta,
 			} ) )
 	}
 }
 impl node::NodeBase for File
 {
 	fn get_id(&self) -> node::InodeId {
-		todo!("File::get_id")
+		self.inode
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.meta.clone() )
+	}
 }
 impl node::File for File
 {
@@ -263,8 +399,8 @@ impl node::File for File
 
 				assert!(ofs < self.fs.lb_size);
 				sector += 1;
-				buf[..len].clone_from_slice(&tmp[ofs..]);
-				read = len;
+				read = ::core::cmp::min(len, self.fs.lb_size - ofs);
+				buf[..read].clone_from_slice(&tmp[ofs..][..read]);
 			}
 
 			// 2. Inner
@@ -283,7 +419,7 @@ impl node::File for File
 				let mut tmp = vec![0; self.fs.lb_size];
 				::kernel::futures::block_on(self.fs.read_sector(self.first_lba + sector, &mut tmp))?;
 
-				buf[read..].clone_from_slice(&tmp);
+				buf[read..len].clone_from_slice(&tmp[..len - read]);
 			}
 
 			Ok( len )
@@ -299,27 +435,34 @@ impl node::File for File
 struct Dir
 {
 	fs: ArefBorrow<InstanceInner>,
+	inode: node::InodeId,
 	first_lba: u32,
 	size: u32,
+	meta: node::Metadata,
 }
 impl Dir
 {
-	fn new_node(fs: ArefBorrow<InstanceInner>, first_lba: u32, size: u32) -> node::Node {
+	fn new_node(fs: ArefBorrow<InstanceInner>, inode: node::InodeId, first_lba: u32, size: u32, meta: node::Metadata) -> node::Node {
 		node::Node::Dir( Box::new( Dir {
 			fs: fs,
+			inode: inode,
 			first_lba: first_lba,
 			size: size,
+			meta: meta,
 			} ) )
 	}
 }
 impl node::NodeBase for Dir
 {
 	fn get_id(&self) -> node::InodeId {
-		todo!("Dir::get_id")
+		self.inode
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.meta.clone() )
+	}
 }
 impl node::Dir for Dir
 {
@@ -327,11 +470,11 @@ impl node::Dir for Dir
 	{
 		for sector in 0 .. ::kernel::lib::num::div_up(self.size, self.fs.lb_size as u32)
 		{
-			let mut it = DirSector::new(&self.fs, ::kernel::futures::block_on(self.fs.get_sector(self.first_lba + sector))?, 0); 
+			let mut it = DirSector::new(::kernel::futures::block_on(self.fs.get_sector(self.first_lba + sector))?, 0); 
 
 			while let Some(ent) = it.next()?
 			{
-				if ent.name == name.as_bytes()
+				if ent.name.len() > 0 && ::kernel::futures::block_on(self.fs.entry_info(&ent))?.name == name.as_bytes()
 				{
 					let inode = (self.first_lba + sector) as u64 * self.fs.lb_size as u64 + ent.this_ofs as u64;
 					return Ok( inode );
@@ -350,7 +493,7 @@ impl node::Dir for Dir
 		
 		for sector in sector as u32 .. max_sectors
 		{
-			let mut it = DirSector::new(&self.fs, ::kernel::futures::block_on(self.fs.get_sector(self.first_lba + sector))?,  ofs );
+			let mut it = DirSector::new(::kernel::futures::block_on(self.fs.get_sector(self.first_lba + sector))?,  ofs );
 			ofs = 0;
 
 			while let Some(ent) = it.next()?
@@ -358,8 +501,9 @@ impl node::Dir for Dir
 				if ent.name.len() > 0 && ent.name != b"\0" && ent.name != b"\x01"
 				{
 					log_debug!("ent = {:?}", ent);
+					let info = ::kernel::futures::block_on(self.fs.entry_info(&ent))?;
 					let inode = (self.first_lba + sector) as u64 * self.fs.lb_size as u64 + ent.this_ofs as u64;
-					if ! callback(inode, &mut ent.name.iter().cloned()) {
+					if ! callback(inode, &mut info.name.iter().cloned()) {
 						return Ok( sector as usize * self.fs.lb_size + ent.next_ofs );
 					}
 				}
@@ -384,6 +528,48 @@ impl node::Dir for Dir
 		// ISO9660 is readonly
 		Err( vfs::Error::ReadOnlyFilesystem )
 	}
+	fn rename(&self, _old_name: &ByteStr, _new_dir: &dyn node::NodeBase, _new_name: &ByteStr) -> node::Result<()> {
+		// ISO9660 is readonly
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
+}
+
+
+// --------------------------------------------------------------------
+/// Rock Ridge symbolic link
+struct Symlink
+{
+	inode: node::InodeId,
+	target: Vec<u8>,
+	meta: node::Metadata,
+}
+impl Symlink
+{
+	fn new_node(inode: node::InodeId, target: Vec<u8>, meta: node::Metadata) -> node::Node {
+		node::Node::Symlink( Box::new( Symlink {
+			inode: inode,
+			target: target,
+			meta: meta,
+			} ) )
+	}
+}
+impl node::NodeBase for Symlink
+{
+	fn get_id(&self) -> node::InodeId {
+		self.inode
+	}
+	fn get_any(&self) -> &dyn core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.meta.clone() )
+	}
+}
+impl node::Symlink for Symlink
+{
+	fn read(&self) -> ByteString {
+		ByteString::from(&self.target[..])
+	}
 }
 
 
@@ -396,6 +582,8 @@ struct DirEnt<'a>
 	flags: u8,
 	start: u32,
 	size: u32,
+	/// Recording date and time (7-byte format)
+	recorded: [u8; 7],
 	name: &'a [u8],
 	sys_use: &'a [u8],
 }
@@ -411,18 +599,142 @@ impl<'a> DirEnt<'a>
 {
 }
 
+/// Decoded name and Rock Ridge information for a directory entry
+#[derive(Default)]
+struct EntryInfo
+{
+	name: Vec<u8>,
+	/// Rock Ridge symbolic link target
+	symlink: Option<Vec<u8>>,
+	/// The entry has Rock Ridge fields (only checked on the root)
+	is_rock_ridge: bool,
+	/// Rock Ridge POSIX attributes (`PX`): mode, link count, uid, gid
+	posix: Option<(u32,u32,u32,u32)>,
+	/// Rock Ridge timestamps (`TF`): creation, modification, access, attribute change
+	times: [Option<node::Timestamp>; 4],
+}
+
+/// Build the VFS metadata for a directory entry
+fn entry_metadata(ent: &DirEnt<'_>, info: &EntryInfo) -> node::Metadata {
+	let recorded = decode_date_short(&ent.recorded);
+	let mut rv = node::Metadata {
+		size: match info.symlink { Some(ref t) => t.len() as u64, None => ent.size as u64 },
+		links: 1,
+		mode: if info.symlink.is_some() { 0o777 } else if ent.flags & (1 << 1) != 0 { 0o555 } else { 0o444 },
+		uid: 0,
+		gid: 0,
+		atime: None,
+		mtime: recorded,
+		ctime: recorded,
+		crtime: None,
+		};
+	if let Some( (mode, links, uid, gid) ) = info.posix {
+		rv.mode = (mode & 0o7777) as u16;
+		rv.links = links;
+		rv.uid = uid;
+		rv.gid = gid;
+	}
+	let [creation, modify, access, attributes] = info.times;
+	rv.crtime = creation;
+	rv.atime = access;
+	rv.mtime = modify.or(rv.mtime);
+	rv.ctime = attributes.or(rv.ctime);
+	rv
+}
+/// Decode a 7-byte date (directory records and short-form Rock Ridge `TF`)
+fn decode_date_short(d: &[u8]) -> Option<node::Timestamp> {
+	if d[..6].iter().all(|&v| v == 0) {
+		return None;
+	}
+	let ts = node::Timestamp::from_civil(1900 + d[0] as i64, d[1], d[2], d[3], d[4], d[5]);
+	// Offset from GMT in 15 minute units
+	Some(node::Timestamp::from_unix(ts.secs - d[6] as i8 as i64 * 15 * 60))
+}
+/// Decode a 17-byte date (`YYYYMMDDHHMMSScc` in ASCII then the GMT offset, used by volume descriptors and long-form `TF`)
+fn decode_date_long(d: &[u8]) -> Option<node::Timestamp> {
+	let num = |r: ::core::ops::Range<usize>| d[r].iter().try_fold(0u32, |acc, &c| if c.is_ascii_digit() { Some(acc * 10 + (c - b'0') as u32) } else { None });
+	let year = num(0..4)?;
+	if year == 0 {
+		return None;
+	}
+	let ts = node::Timestamp::from_civil(year as i64, num(4..6)? as u8, num(6..8)? as u8, num(8..10)? as u8, num(10..12)? as u8, num(12..14)? as u8);
+	Some(node::Timestamp {
+		secs: ts.secs - d[16] as i8 as i64 * 15 * 60,
+		nsecs: num(14..16)? * 10_000_000,
+		})
+}
+/// Parse the contents of a Rock Ridge `TF` entry (only the first four times are used)
+fn parse_timestamps(flags: u8, mut data: &[u8], times: &mut [Option<node::Timestamp>; 4]) {
+	let len = if flags & TF_LONG_FORM != 0 { 17 } else { 7 };
+	for (i, dst) in times.iter_mut().enumerate()
+	{
+		if flags & (1 << i) != 0 {
+			if data.len() < len {
+				log_warning!("Truncated TF entry");
+				return ;
+			}
+			*dst = if len == 17 { decode_date_long(&data[..len]) } else { decode_date_short(&data[..len]) };
+			data = &data[len..];
+		}
+	}
+}
+
+/// Decode a Joliet name (UCS-2, big endian) to WTF-8, removing the version suffix
+fn decode_joliet(raw: &[u8]) -> Vec<u8> {
+	let mut units: Vec<u16> = raw.chunks_exact(2).map(|v| u16::from_be_bytes([v[0], v[1]])).collect();
+	if let Some(p) = units.iter().position(|&v| v == b';' as u16) {
+		units.truncate(p);
+	}
+	if ::utf16::Str16::new(&units).is_none() {
+		// Joliet is nominally UCS-2, so replace unpaired surrogates
+		for v in units.iter_mut() {
+			if 0xD800 <= *v && *v < 0xE000 {
+				*v = 0xFFFD;
+			}
+		}
+	}
+	// SAFE: Surrogates have been removed (if they were invalid)
+	unsafe { ::utf16::Str16::new_unchecked(&units) }.wtf8().collect()
+}
+
+/// Append the components from a Rock Ridge `SL` entry to a symbolic link target
+fn push_symlink_components(dst: &mut Vec<u8>, mut data: &[u8], component_continues: &mut bool) {
+	while data.len() >= 2
+	{
+		let flags = data[0];
+		let len = usize::min(data[1] as usize, data.len() - 2);
+		let content = &data[2..][..len];
+		data = &data[2 + len..];
+
+		if !*component_continues && !dst.is_empty() && !dst.ends_with(b"/") {
+			dst.push(b'/');
+		}
+		if flags & SL_ROOT != 0 {
+			dst.push(b'/');
+		}
+		else if flags & SL_PARENT != 0 {
+			dst.extend_from_slice(b"..");
+		}
+		else if flags & SL_CURRENT != 0 {
+			dst.push(b'.');
+		}
+		else {
+			dst.extend_from_slice(content);
+		}
+		*component_continues = flags & SL_CONTINUE != 0;
+	}
+}
+
 struct DirSector<'a> {
-	fs: &'a InstanceInner,
 	data: Sector<'a>,
 	ofs: usize
 }
 
 impl<'a> DirSector<'a>
 {
-	pub fn new<'b>(fs: &'b InstanceInner, data: Sector<'b>, start_ofs: usize) -> DirSector<'b>
+	pub fn new<'b>(data: Sector<'b>, start_ofs: usize) -> DirSector<'b>
 	{
 		DirSector {
-			fs: fs,
 			data: data,
 			ofs: start_ofs,
 		}
@@ -460,26 +772,9 @@ impl<'a> DirSector<'a>
 					log_warning!("Name overruns end of entry");
 					return Err(vfs::Error::InconsistentFilesystem);
 				}
-				let su = &ent[33 + namelen ..];
-
-				let mut name = &ent[33..][..namelen];
-
-				if let Some(skip) = self.fs.susp_len_skip {
-					let skip = skip as usize;
-					if su.len() < skip {
-						log_warning!("System use area smaller than SUSP skip value");
-						return Err(vfs::Error::InconsistentFilesystem);
-					}
-
-					for ent in SuspIterator(&su[skip..])
-					{
-						//log_trace!("ent={:?}", ent);
-						// TODO: Need to handle this _FAR_ better
-						if let SuspItem::AlternateName(0, new_name) = ent {
-							name = new_name;
-						}
-					}
-				}
+				// The system use area starts on an even offset
+				let name = &ent[33..][..namelen];
+				let su = &ent[usize::min(len, 33 + namelen + (namelen + 1) % 2) ..];
 
 				Ok(Some(DirEnt {
 					this_ofs: cur_ofs,
@@ -487,6 +782,7 @@ impl<'a> DirSector<'a>
 					flags: ent[25],
 					start: LittleEndian::read_u32(&ent[2..]),
 					size: LittleEndian::read_u32(&ent[10..]),
+					recorded: { let mut v = [0; 7]; v.copy_from_slice(&ent[18..][..7]); v },
 					name: name,
 					sys_use: su,
 					}))
@@ -495,6 +791,17 @@ impl<'a> DirSector<'a>
 	}
 }
 
+// Rock Ridge `NM` flags
+const NM_CURRENT: u8 = 1 << 1;
+const NM_PARENT: u8 = 1 << 2;
+// Rock Ridge `SL` component flags
+const SL_CONTINUE: u8 = 1 << 0;
+const SL_CURRENT: u8 = 1 << 1;
+const SL_PARENT: u8 = 1 << 2;
+const SL_ROOT: u8 = 1 << 3;
+// Rock Ridge `TF` flags (bits 0-6 select which times are present)
+const TF_LONG_FORM: u8 = 1 << 7;
+
 struct SuspIterator<'a>(&'a [u8]);
 
 #[derive(Debug)]
@@ -505,6 +812,7 @@ enum SuspItem<'a>
 	ContinuationEntry(u32, u32, u32),
 	Pad(&'a [u8]),
 	Identifier,
+	ExtensionReference(&'a [u8]),
 	//End,
 	
 	// RockRidge
@@ -517,6 +825,7 @@ enum SuspItem<'a>
 		serial_number: u32,
 		},
 	AlternateName(u8, &'a [u8]),
+	Symlink(u8, &'a [u8]),
 	Timestamps {
 		flags: u8,
 		data: &'a [u8],
@@ -556,6 +865,12 @@ impl<'a> Iterator for SuspIterator<'a>
 				b"ST" => return None,	// Terminated
 				b"SP" => SuspItem::Identifier,
 				b"PD" => SuspItem::Pad(data),
+				b"ER" => {
+					if data.len() < 4 { return None; }
+					let id_len = data[0] as usize;
+					if data.len() < 4 + id_len { return None; }
+					SuspItem::ExtensionReference(&data[4..][..id_len])
+					},
 				b"CE" => {
 					if data.len() < 3*8 { return None; }
 					SuspItem::ContinuationEntry(
@@ -585,7 +900,10 @@ impl<'a> Iterator for SuspIterator<'a>
 						data: &data[1..],
 						}
 					},
-				//b"SL" => SuspItem::Symlink(),
+				b"SL" => {
+					if data.len() < 1 { return None; }
+					SuspItem::Symlink(data[0], &data[1..])
+					},
 				b"NM" => {
 					if data.len() < 1 { return None; }
 					SuspItem::AlternateName(data[0], &data[1..])
@@ -0,0 +1,130 @@
+// "Tifflin" Kernel - NTFS Driver
+// - By John Hodge (Mutabah/thePowersGang)
+//
+// Modules/fs_ntfs/alloc.rs
+//! Cluster allocation (using the `$Bitmap` file)
+use ::kernel::prelude::*;
+use crate::instance::{Instance,CachedMft};
+use crate::ondisk;
+
+/// Number of bitmap bytes handled at a time
+const CHUNK_BYTES: usize = 0x1000;
+
+pub struct Allocator
+{
+	bitmap_ent: CachedMft,
+	bitmap_attr: ondisk::AttrHandle,
+	/// Where to start searching when the caller doesn't have a better hint
+	next_search: u64,
+}
+
+impl Allocator
+{
+	pub fn new(bitmap: (CachedMft, ondisk::AttrHandle)) -> Allocator {
+		Allocator {
+			bitmap_ent: bitmap.0,
+			bitmap_attr: bitmap.1,
+			next_search: 0,
+		}
+	}
+
+	/// Allocate `count` clusters, starting the search at `hint` (the result may be fragmented)
+	pub async fn alloc(&mut self, inst: &Instance, hint: u64, count: u64) -> ::vfs::Result<Vec<ondisk::DataRun>> {
+		let total = inst.total_clusters();
+		let start = if hint > 0 && hint < total { hint } else if self.next_search < total { self.next_search } else { 0 };
+
+		let mut rv: Vec<ondisk::DataRun> = Vec::new();
+		let mut remaining = count;
+		let mut buf = vec![0u8; CHUNK_BYTES];
+		// Search from `start` to the end of the volume, then wrap around to `start`
+		let mut cluster = start;
+		let mut end = total;
+		let mut wrapped = false;
+		while remaining > 0
+		{
+			if cluster >= end {
+				if wrapped || start == 0 {
+					break;
+				}
+				wrapped = true;
+				cluster = 0;
+				end = start;
+				continue;
+			}
+			let chunk_base = cluster / 8 / CHUNK_BYTES as u64 * CHUNK_BYTES as u64;
+			let len = inst.attr_read(&self.bitmap_ent, &self.bitmap_attr, chunk_base, &mut buf).await?;
+			if len == 0 {
+				log_warning!("alloc: $Bitmap is shorter than the volume ({} clusters)", total);
+				cluster = end;
+				continue;
+			}
+			let limit = u64::min(end, (chunk_base + len as u64) * 8);
+
+			let mut changed: Option<(usize,usize)> = None;
+			while cluster < limit && remaining > 0
+			{
+				let bit = (cluster - chunk_base * 8) as usize;
+				let (byte, mask) = (bit / 8, 1 << (bit % 8));
+				// Quickly skip over fully used bytes
+				if mask == 1 && buf[byte] == 0xFF {
+					cluster += 8;
+					continue;
+				}
+				if buf[byte] & mask == 0 {
+					buf[byte] |= mask;
+					changed = Some(match changed { Some((s,_)) => (s, byte+1), None => (byte, byte+1) });
+					match rv.last_mut()
+					{
+					Some(r) if r.lcn.unwrap() + r.cluster_count == cluster => r.cluster_count += 1,
+					_ => rv.push(ondisk::DataRun { lcn: Some(cluster), cluster_count: 1 }),
+					}
+					remaining -= 1;
+				}
+				cluster += 1;
+			}
+			if let Some((s,e)) = changed {
+				inst.attr_write_raw(&self.bitmap_ent, &self.bitmap_attr, chunk_base + s as u64, &buf[s..e]).await?;
+			}
+			cluster = u64::max(cluster, limit);
+		}
+
+		if remaining > 0 {
+			self.free(inst, &rv).await?;
+			return Err(::vfs::Error::OutOfSpace);
+		}
+		if let Some(r) = rv.last() {
+			self.next_search = r.lcn.unwrap() + r.cluster_count;
+		}
+		Ok(rv)
+	}
+
+	/// Release clusters back to the free pool
+	pub async fn free(&mut self, inst: &Instance, runs: &[ondisk::DataRun]) -> ::vfs::Result<()> {
+		let mut buf = vec![0u8; CHUNK_BYTES];
+		for r in runs
+		{
+			let Some(lcn) = r.lcn else { continue };
+			let end = lcn + r.cluster_count;
+			let mut cluster = lcn;
+			while cluster < end
+			{
+				let byte_ofs = cluster / 8;
+				let nbytes = usize::min(((end - 1) / 8 - byte_ofs + 1) as usize, CHUNK_BYTES);
+				let b = &mut buf[..nbytes];
+				if inst.attr_read(&self.bitmap_ent, &self.bitmap_attr, byte_ofs, b).await? != nbytes {
+					log_error!("free: Clusters {:#x}+{} are past the end of $Bitmap", lcn, r.cluster_count);
+					return Err(::vfs::Error::InconsistentFilesystem);
+				}
+				let chunk_end = u64::min(end, (byte_ofs + nbytes as u64) * 8);
+				while cluster < chunk_end
+				{
+					let bit = (cluster - byte_ofs * 8) as usize;
+					b[bit / 8] &= !(1 << (bit % 8));
+					cluster += 1;
+				}
+				inst.attr_write_raw(&self.bitmap_ent, &self.bitmap_attr, byte_ofs, b).await?;
+			}
+		}
+		Ok( () )
+	}
+}
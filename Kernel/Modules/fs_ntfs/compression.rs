@@ -1,5 +1,57 @@
-
 // Takes a compression unit worth of data, and yields 4096 byte blocks from it
+
+/// Uncompressed size of a LZNT1 chunk
+pub const CHUNK_SIZE: usize = 0x1000;
+
+/// Decompress a compression unit into `dst`, starting `skip` bytes into the unit's uncompressed data
+///
+/// Every chunk stands for `CHUNK_SIZE` bytes (a short chunk is zero padded), and the unit is zero past the end of the
+/// compressed data. Returns `Err` if the compressed data is malformed.
+pub fn decompress_unit(src: &[u8], skip: usize, mut dst: &mut [u8]) -> Result<(),()>
+{
+	let mut decomp = Decompressor::new(src);
+	// Consume complete chunks until the offset is reached
+	for _ in 0 .. skip / CHUNK_SIZE
+	{
+		if decomp.get_block(None)?.is_none() {
+			dst.fill(0);
+			return Ok( () );
+		}
+	}
+
+	let mut skip = skip % CHUNK_SIZE;
+	while dst.len() > 0
+	{
+		if skip == 0 && dst.len() >= CHUNK_SIZE {
+			// Complete chunk, decompress directly into the output
+			let b = ::kernel::lib::split_off_front_mut(&mut dst, CHUNK_SIZE).unwrap();
+			match decomp.get_block(Some(b))?
+			{
+			Some(len) => b[usize::min(len, CHUNK_SIZE)..].fill(0),
+			None => {
+				b.fill(0);
+				dst.fill(0);
+				return Ok( () );
+				},
+			}
+		}
+		else {
+			// Partial chunk (either the start or the end of the read), use a bounce buffer
+			let mut chunk = vec![0u8; CHUNK_SIZE];
+			let is_end = decomp.get_block(Some(&mut chunk))?.is_none();
+			let len = usize::min(CHUNK_SIZE - skip, dst.len());
+			let b = ::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
+			b.copy_from_slice(&chunk[skip..][..len]);
+			skip = 0;
+			if is_end {
+				dst.fill(0);
+				return Ok( () );
+			}
+		}
+	}
+	Ok( () )
+}
+
 pub struct Decompressor<'a>(&'a [u8]);
 impl<'a> Decompressor<'a>
 {
@@ -7,40 +59,46 @@ impl<'a> Decompressor<'a>
 		//log_debug!("{:?}", ::kernel::logging::HexDump(v));
 		Decompressor(v)
 	}
-	/// Decompress a block out of the stream
-	pub fn get_block(&mut self, dst: Option<&mut [u8]>) -> Option<usize>
+	/// Decompress a chunk out of the stream, returning the number of bytes it expands to
+	///
+	/// Returns `Ok(None)` at the end of the compressed data, and `Err` if the data is malformed
+	pub fn get_block(&mut self, dst: Option<&mut [u8]>) -> Result<Option<usize>,()>
 	{
 		let dst = dst.unwrap_or(&mut []);
 		let Some(hdr) = ::kernel::lib::split_off_front(&mut self.0, 2) else {
-			return None;
+			return Ok(None);
 			};
 		let hdr = u16::from_le_bytes([hdr[0], hdr[1]]);
 		if hdr == 0 {
 			// This seems to indicate the end of the compressed data
-			return None;
+			return Ok(None);
 		}
 		let compressed_len = (hdr & 0xFFF) as usize + 1;
 		let Some(src) = ::kernel::lib::split_off_front(&mut self.0, compressed_len) else {
 			log_error!("Decompressor::get_block: MALFORMED: Unable to obtain all of compressed data ({compressed_len} > {})", self.0.len());
-			return None;
+			return Err(());
 			};
 		if hdr & 0x8000 == 0 {
 			//log_debug!("Uncompressed block {:#x}", compressed_len);
 			// Uncompressed data, hopefully the length is 0x1000
 			let len = usize::min(src.len(), dst.len());
 			dst[..len].copy_from_slice(&src[..len]);
-			Some(compressed_len)
+			Ok(Some(compressed_len))
 		}
 		else {
 			let mut ofs = 0;
 			//log_debug!("{} Compressed block {:?}", self.0.len(), ::kernel::logging::HexDump(src));
 			// Compressed data, a sequence of token-groups preceded by a bitmap indicating the token classes
 			let mut it = Tokens::new(src);
-			while let Some(t) = it.next().expect("Malformed compressed data?")
+			while let Some(t) = it.next().map_err(|()| log_error!("Decompressor::get_block: MALFORMED: Truncated lookback token"))?
 			{
 				match t
 				{
 				Token::Literal(b) => {
+					if ofs >= CHUNK_SIZE {
+						log_error!("Decompressor::get_block: MALFORMED: Literal past end of chunk");
+						return Err(());
+					}
 					if ofs < dst.len() {
 						dst[ofs] = b;
 					}
@@ -48,10 +106,10 @@ impl<'a> Decompressor<'a>
 					},
 				Token::Lookback(dist_back, length) => {
 					//log_debug!("Token::Lookback(-{}+{})", dist_back, length);
-					if dist_back > ofs || ofs + length > 4096 {
+					if dist_back > ofs || ofs + length > CHUNK_SIZE {
 						log_error!("Decompressor::get_block: MALFORMED: Lookback bad (-{}+{}, ofs={})",
 							dist_back, length, ofs);
-						return None;
+						return Err(());
 					}
 					if ofs < dst.len() {
 						//log_debug!("{} += {}..{} - {:?}", ofs, ofs-dist_back, ofs-dist_back+length, ::kernel::logging::HexDump(&dst[ofs-dist_back..usize::min(ofs-dist_back+length,ofs)]));
@@ -69,7 +127,7 @@ impl<'a> Decompressor<'a>
 					},
 				}
 			}
-			Some(ofs)
+			Ok(Some(ofs))
 		}
 	}
 }
@@ -168,7 +226,7 @@ impl Iterator for BitsIter {
 
 #[cfg(test)]
 mod tests {
-	use super::Decompressor;
+	use super::{Decompressor,decompress_unit,CHUNK_SIZE};
 	#[test]
 	fn decomp_0()
 	{
@@ -176,7 +234,7 @@ mod tests {
 		let cdat = &[ 3,0xb0,  0x2, b' ', 0xFC,0x0F, ];
 		let mut d = Decompressor::new(cdat);
 		let mut dst = [0xCC; 4096];
-		assert_eq!(d.get_block(Some(&mut dst)), Some(4096));
+		assert_eq!(d.get_block(Some(&mut dst)), Ok(Some(4096)));
 		assert_eq!(dst, [b' '; 4096]);
 	}
 
@@ -195,7 +253,49 @@ mod tests {
 		let exp = b"#include <ntfs.h>\n#include <stdio";
 		let mut d = Decompressor::new(cdat);
 		let mut dst = [0xCC; 4096];
-		assert_eq!(d.get_block(Some(&mut dst)), Some(exp.len()));
+		assert_eq!(d.get_block(Some(&mut dst)), Ok(Some(exp.len())));
 		assert_eq!(&dst[..exp.len()], exp);
 	}
+
+	#[test]
+	fn decomp_truncated()
+	{
+		// Chunk header claims more data than is present
+		let mut d = Decompressor::new(&[ 9,0xB0, 0x00, b'a' ]);
+		assert_eq!(d.get_block(None), Err(()));
+		// Lookback token missing its second byte
+		let mut d = Decompressor::new(&[ 2,0xB0, 0x02, b'a', 0xFC ]);
+		assert_eq!(d.get_block(None), Err(()));
+		// Lookback before the start of the chunk
+		let mut d = Decompressor::new(&[ 2,0xB0, 0x01, 0x00,0x00 ]);
+		assert_eq!(d.get_block(None), Err(()));
+	}
+
+	#[test]
+	fn unit_padding()
+	{
+		// Two chunks: A short compressed chunk ("ab" then spaces to 100 bytes), then a stored chunk of 0x55
+		let mut cdat = vec![ 5,0xB0,  0x08, b'a',b'b',b' ', 0x5e,0x00, ];
+		cdat.extend_from_slice(&[ 0xFF,0x3F ]);
+		cdat.extend_from_slice(&[ 0x55; CHUNK_SIZE ]);
+		// Followed by the end marker and garbage
+		cdat.extend_from_slice(&[ 0,0, 0xAA,0xAA ]);
+
+		let mut exp = vec![0u8; 4*CHUNK_SIZE];
+		exp[..2].copy_from_slice(b"ab");
+		exp[2..100].fill(b' ');
+		exp[CHUNK_SIZE..][..CHUNK_SIZE].fill(0x55);
+
+		let mut dst = vec![0xCC; 4*CHUNK_SIZE];
+		assert_eq!(decompress_unit(&cdat, 0, &mut dst), Ok(()));
+		assert!(dst == exp);
+
+		// Unaligned reads spanning the chunk boundaries
+		for &(skip,len) in &[ (1,10), (50,CHUNK_SIZE), (CHUNK_SIZE-1,CHUNK_SIZE+2), (CHUNK_SIZE+7,3*CHUNK_SIZE-7), (3*CHUNK_SIZE,CHUNK_SIZE) ]
+		{
+			let mut dst = vec![0xCC; len];
+			assert_eq!(decompress_unit(&cdat, skip, &mut dst), Ok(()));
+			assert!(dst[..] == exp[skip..][..len], "skip={} len={}", skip, len);
+		}
+	}
 }
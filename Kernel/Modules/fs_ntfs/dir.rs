@@ -8,6 +8,7 @@ pub struct Dir {
 	
 	i30_root: Option<super::ondisk::AttrHandle>,
 	i30_allocation: Option<super::ondisk::AttrHandle>,
+	i30_bitmap: Option<super::ondisk::AttrHandle>,
 }
 impl Dir
 {
@@ -15,6 +16,7 @@ impl Dir
 		Dir {
 			i30_root: instance.get_attr_inner(&mft_ent, crate::ondisk::FileAttr::IndexRoot, "$I30", 0),
 			i30_allocation: instance.get_attr_inner(&mft_ent, crate::ondisk::FileAttr::IndexAllocation, "$I30", 0),
+			i30_bitmap: instance.get_attr_inner(&mft_ent, crate::ondisk::FileAttr::Bitmap, "$I30", 0),
 			instance,
 			mft_idx,
 			mft_ent,
@@ -50,6 +52,15 @@ impl ::vfs::node::NodeBase for Dir
 	fn get_any(&self) -> &(dyn ::core::any::Any + 'static) {
 		self
 	}
+	fn get_metadata(&self) -> Result<::vfs::node::Metadata, ::vfs::Error> {
+		self.instance.get_metadata(&self.mft_ent, 0)
+	}
+	fn set_metadata(&self, changes: &::vfs::node::MetadataUpdate) -> Result<(), ::vfs::Error> {
+		if self.instance.is_readonly() {
+			return Err(::vfs::Error::ReadOnlyFilesystem);
+		}
+		::kernel::futures::block_on(self.instance.set_metadata(crate::MftEntryIdx(self.mft_idx as _), &self.mft_ent, changes))
+	}
 }
 impl ::vfs::node::Dir for Dir
 {
@@ -100,7 +111,8 @@ impl ::vfs::node::Dir for Dir
 		let mut buf = vec![ 0; i30_root.index_block_size() as usize];
 		loop
 		{
-			let l = ::kernel::futures::block_on(self.instance.attr_read(&self.mft_ent, i30_alloc, vcn * buf.len() as u64, &mut buf))?;
+			let ofs = vcn * self.instance.index_vcn_size(buf.len());
+			let l = ::kernel::futures::block_on(self.instance.attr_read(&self.mft_ent, i30_alloc, ofs, &mut buf))?;
 			if l == 0 {
 				// Inconsistent? Off the end
 				return Err(::vfs::Error::NotFound);
@@ -161,10 +173,28 @@ impl ::vfs::node::Dir for Dir
 		{
 			let i30_alloc = self.i30_allocation.as_ref().ok_or(::vfs::Error::InconsistentFilesystem)?;
 
+			// Only blocks marked in the bitmap are in use, the rest can contain stale entries
+			let bitmap = match self.i30_bitmap
+				{
+				Some(ref h) => {
+					let mut bitmap = vec![ 0u8; self.instance.attr_size(&self.mft_ent, h) as usize ];
+					let l = ::kernel::futures::block_on(self.instance.attr_read(&self.mft_ent, h, 0, &mut bitmap))?;
+					bitmap.truncate(l);
+					Some(bitmap)
+					},
+				None => None,
+				};
+
 			let mut buf = vec![ 0; i30_root.index_block_size() as usize];
 			let mut ipos = pos;
-			for read_ofs in (0 ..).step_by(buf.len())
+			let alloc_size = self.instance.attr_size(&self.mft_ent, i30_alloc);
+			for (block_idx, read_ofs) in (0 .. alloc_size).step_by(buf.len()).enumerate()
 			{
+				if let Some(ref bitmap) = bitmap {
+					if bitmap.get(block_idx / 8).map_or(true, |b| b & (1 << (block_idx % 8)) == 0) {
+						continue;
+					}
+				}
 				let l = ::kernel::futures::block_on(self.instance.attr_read(&self.mft_ent, i30_alloc, read_ofs, &mut buf))?;
 				if l == 0 {
 					break;
@@ -198,11 +228,17 @@ impl ::vfs::node::Dir for Dir
 	fn unlink(&self, _name: &ByteStr) -> Result<(), ::vfs::Error> {
 		Err(::vfs::Error::ReadOnlyFilesystem)
 	}
+	fn rename(&self, _old_name: &ByteStr, _new_dir: &dyn ::vfs::node::NodeBase, _new_name: &ByteStr) -> Result<(), ::vfs::Error> {
+		Err(::vfs::Error::ReadOnlyFilesystem)
+	}
 }
 
 fn get_index_block<'a>(instance: &super::instance::Instance, buf: &'a mut [u8]) -> Result<&'a crate::ondisk::Attrib_IndexBlockHeader, ::vfs::Error>
 {
-	instance.apply_sequence_fixups(buf, &|buf1| crate::ondisk::Attrib_IndexBlockHeader::from_slice(buf1).map(|ent| ent.update_sequence()))?;
+	if crate::ondisk::Attrib_IndexBlockHeader::fixup(buf).is_none() {
+		log_error!("Index block is corrupted");
+		return Err(::vfs::Error::InconsistentFilesystem);
+	}
 	crate::ondisk::Attrib_IndexBlockHeader::from_slice(buf).ok_or(::vfs::Error::InconsistentFilesystem)
 }
 
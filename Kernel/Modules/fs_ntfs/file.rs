@@ -20,6 +20,14 @@ impl File
 			mft_ent,
 		}
 	}
+
+	/// Get the data attribute for a modification
+	fn attr_data_w(&self) -> Result<&super::ondisk::AttrHandle, ::vfs::Error> {
+		if self.instance.is_readonly() {
+			return Err(::vfs::Error::ReadOnlyFilesystem);
+		}
+		self.attr_data.as_ref().ok_or(::vfs::Error::Unknown("NTFS: File has no $DATA attribute"))
+	}
 }
 
 impl ::vfs::node::NodeBase for File
@@ -30,6 +38,15 @@ impl ::vfs::node::NodeBase for File
 	fn get_any(&self) -> &(dyn ::core::any::Any + 'static) {
 		self
 	}
+	fn get_metadata(&self) -> Result<::vfs::node::Metadata, ::vfs::Error> {
+		self.instance.get_metadata(&self.mft_ent, ::vfs::node::File::size(self))
+	}
+	fn set_metadata(&self, changes: &::vfs::node::MetadataUpdate) -> Result<(), ::vfs::Error> {
+		if self.instance.is_readonly() {
+			return Err(::vfs::Error::ReadOnlyFilesystem);
+		}
+		::kernel::futures::block_on(self.instance.set_metadata(crate::MftEntryIdx(self.mft_idx as _), &self.mft_ent, changes))
+	}
 }
 impl ::vfs::node::File for File
 {
@@ -39,11 +56,17 @@ impl ::vfs::node::File for File
 			};
 		self.instance.attr_size(&self.mft_ent, attr_data)
 	}
-	fn truncate(&self, _new_size: u64) -> Result<u64, ::vfs::Error> {
-		Err(::vfs::Error::ReadOnlyFilesystem)
+	fn truncate(&self, new_size: u64) -> Result<u64, ::vfs::Error> {
+		let attr_data = self.attr_data_w()?;
+		::kernel::futures::block_on(self.instance.attr_set_size(crate::MftEntryIdx(self.mft_idx as _), &self.mft_ent, attr_data, new_size))?;
+		Ok(new_size)
 	}
-	fn clear(&self, _ofs: u64, _size: u64) -> Result<(), ::vfs::Error> {
-		Err(::vfs::Error::ReadOnlyFilesystem)
+	fn clear(&self, ofs: u64, size: u64) -> Result<(), ::vfs::Error> {
+		let attr_data = self.attr_data_w()?;
+		if ofs + size > self.size() {
+			return Err(::vfs::Error::InvalidParameter);
+		}
+		Ok( ::kernel::futures::block_on(self.instance.attr_clear(crate::MftEntryIdx(self.mft_idx as _), &self.mft_ent, attr_data, ofs, size))? )
 	}
 	fn read(&self, ofs: u64, dst: &mut [u8]) -> Result<usize, ::vfs::Error> {
 		let Some(ref attr_data) = self.attr_data else {
@@ -51,7 +74,8 @@ impl ::vfs::node::File for File
 			};
 		Ok( ::kernel::futures::block_on(self.instance.attr_read(&self.mft_ent, attr_data, ofs, dst))? )
 	}
-	fn write(&self, _ofs: u64, _src: &[u8]) -> Result<usize, ::vfs::Error> {
-		Err(::vfs::Error::ReadOnlyFilesystem)
+	fn write(&self, ofs: u64, src: &[u8]) -> Result<usize, ::vfs::Error> {
+		let attr_data = self.attr_data_w()?;
+		Ok( ::kernel::futures::block_on(self.instance.attr_write(crate::MftEntryIdx(self.mft_idx as _), &self.mft_ent, attr_data, ofs, src))? )
 	}
 }
@@ -16,13 +16,19 @@ pub type InstanceRef = aref::ArefBorrow<Instance>;
 pub struct Instance
 {
 	vol: ::block_cache::CachedVolume,
-	_mount_handle: ::vfs::mount::SelfHandle,
+	mount_handle: ::vfs::mount::SelfHandle,
 	cluster_size_blocks: usize,
 	mft_record_size: usize,
 	mft_data_attr: Option<(CachedMft,ondisk::AttrHandle)>,
 	upcase_table: Vec<u16>,
 	bs: ondisk::Bootsector,
 
+	is_readonly: bool,
+	/// Set once the volume has been flagged as dirty (on the first write), cleared when synced or unmounted
+	is_dirty: ::core::sync::atomic::AtomicBool,
+	/// Cluster allocator, `None` if read-only
+	allocator: ::kernel::futures::Mutex<Option<crate::alloc::Allocator>>,
+
 	// A cache of loaded (open and just in-cache) MFT entries
 	mft_cache: ::kernel::sync::RwLock<::kernel::lib::VecMap<u32, CachedMft>>,
 }
@@ -30,7 +36,9 @@ pub struct Instance
 impl Instance
 {
 	/// Construct a new instance using a bootsector (`bs`) read from a volume (`vol`)
-	pub fn new(vol: VolumeHandle, bs: ondisk::Bootsector, mount_handle: ::vfs::mount::SelfHandle) -> ::vfs::Result<Box<InstanceWrapper>> {
+	///
+	/// If `read_only` is set the volume is never written (not even to set the dirty flag)
+	pub fn new(vol: VolumeHandle, bs: ondisk::Bootsector, read_only: bool, mount_handle: ::vfs::mount::SelfHandle) -> ::vfs::Result<Box<InstanceWrapper>> {
 
 		let cluster_size_bytes = bs.bytes_per_sector as usize * bs.sectors_per_cluster as usize;
 		let cluster_size_blocks = cluster_size_bytes / vol.block_size();
@@ -41,12 +49,15 @@ impl Instance
 		// Pre-calculate some useful values (cluster size, mft entry, ...)
 		let mut instance = Instance {
 			vol: ::block_cache::CachedVolume::new(vol),
-			_mount_handle: mount_handle,
+			mount_handle: mount_handle,
 			mft_data_attr: None,
 			cluster_size_blocks,
 			mft_record_size: bs.mft_record_size.get().to_bytes(cluster_size_bytes),
 			bs,
 			upcase_table: Vec::new(),
+			is_readonly: true,
+			is_dirty: Default::default(),
+			allocator: ::kernel::futures::Mutex::new(None),
 			mft_cache: Default::default(),
 			};
 		log_debug!("cluster_size_blocks = {:#x}, mft_record_size = {:#x}", instance.cluster_size_blocks, instance.mft_record_size);
@@ -60,18 +71,35 @@ impl Instance
 
 		if let Some( (upcase_ent,upcase_data) ) = ::kernel::futures::block_on(instance.get_attr(ondisk::MFT_ENTRY_UPCASE, ondisk::FileAttr::Data, ondisk::ATTRNAME_DATA, 0))?
 		{
-			instance.upcase_table = {
-				let mut upcase_table = vec![0u16; 0x10000];
-				let len = ::kernel::futures::block_on(instance.attr_read(&upcase_ent, &upcase_data, 0, ::kernel::lib::as_byte_slice_mut(&mut upcase_table[..])))?;
-				if len != 0x1_0000 * 2 {
-					log_warning!("$UpCase not large enough - Read {:#x} bytes, expected {:#x}",
-						len, upcase_table.len() * 2);
-				}
+			let mut upcase_table = vec![0u16; 0x10000];
+			let len = ::kernel::futures::block_on(instance.attr_read(&upcase_ent, &upcase_data, 0, ::kernel::lib::as_byte_slice_mut(&mut upcase_table[..])))?;
+			if len != upcase_table.len() * 2 {
+				// A partial table would give an inconsistent collation, so don't use it at all
+				log_warning!("$UpCase not large enough - Read {:#x} bytes, expected {:#x}. Falling back to ASCII case folding",
+					len, upcase_table.len() * 2);
+			}
+			else {
 				for e in upcase_table.iter_mut() {
-					*e = e.to_le();
+					*e = u16::from_le(*e);
 				}
-				upcase_table
-				};
+				instance.upcase_table = upcase_table;
+			}
+		}
+		else {
+			log_warning!("No $UpCase file, falling back to ASCII case folding");
+		}
+
+		if read_only {
+			log_debug!("{}: Mounted read-only", instance.vol.name());
+		}
+		else if ::kernel::futures::block_on(instance.check_writable())? {
+			if let Some(bitmap) = ::kernel::futures::block_on(instance.get_attr(ondisk::MFT_ENTRY_BITMAP, ondisk::FileAttr::Data, ondisk::ATTRNAME_DATA, 0))? {
+				instance.allocator = ::kernel::futures::Mutex::new(Some(crate::alloc::Allocator::new(bitmap)));
+				instance.is_readonly = false;
+			}
+			else {
+				log_warning!("{}: No $Bitmap, mounting read-only", instance.vol.name());
+			}
 		}
 
 		// SAFE: ArefInner::new requires a stable pointer, and the immediate boxing does that
@@ -81,59 +109,23 @@ impl Instance
 	fn cluster_size_bytes(&self) -> usize {
 		self.cluster_size_blocks * self.vol.block_size()
 	}
-
-	/// Apply `update_sequence` fixups to a loaded metadata block
-	///
-	/// All metadata blocks (e.g. MFT entries, or index blocks) have an "update sequence" that catches sectors (volume
-	/// blocks) that didn't get written to disk correctly. Within the first sector there's a sequence number that's
-	/// incremented on every change to the block and a copy of the original/correct last two bytes of each sector.
-	///
-	/// This function takes that update sequence information, checks that the last word of each non-first sector matches
-	/// the expectation and then restores the original value.
-	///
-	/// `get_usa` is a function that gets an `UpdateSequence` from the passed first sector
-	pub fn apply_sequence_fixups(&self, buf: &mut [u8], get_usa: &dyn Fn(&[u8])->Option<&crate::ondisk::UpdateSequence>) -> Result<(),::vfs::Error> {
-		let block_size = self.vol.block_size();
-		if buf.len() >= block_size
-		{
-			assert!(buf.len() % block_size == 0, "apply_sequence_fixups: Passed a buffer not a multiple of volume blocks long"); 
-			let (buf1, buf2) = buf.split_at_mut(block_size);
-			let usa = (get_usa)(buf1).ok_or(::vfs::Error::InconsistentFilesystem)?;
-			let exp_val = usa.sequence_number();
-			let mut usa_it = usa.array();
-			let s0_last_word = usa_it.next().ok_or(::vfs::Error::InconsistentFilesystem)?;
-			//log_debug!("apply_sequence_fixups: {} sectors, seq={}", 1+buf2.len()/block_size, exp_val);
-
-			fn apply_fixup(sector_idx: usize, sector: &mut [u8], last_word: u16, exp_val: u16) -> Result<(),::vfs::Error> {
-				let block_size = sector.len();
-				let slot = &mut sector[ block_size - 2 ..];
-				let cur_val = u16::from_le_bytes([slot[0], slot[1]]);
-				//log_debug!("apply_sequence_fixups: +{}: 0x{:04x} -> 0x{:04x}", 1+sector_idx, cur_val, last_word);
-				if cur_val != exp_val {
-					log_error!("apply_sequence_fixups: Sequence number mismatch in sector +{}: 0x{:04x} != exp 0x{:04x}",
-						1+sector_idx, cur_val, exp_val
-						);
-					return Err(::vfs::Error::InconsistentFilesystem);
-				}
-				slot.copy_from_slice(&last_word.to_le_bytes());
-				Ok( () )
-			}
-
-			for (rel_sector_idx, (sector, last_word)) in Iterator::zip( buf2.chunks_mut(block_size), usa_it ).enumerate()
-			{
-				apply_fixup(1+rel_sector_idx, sector, last_word, exp_val)?;
-			}
-
-			apply_fixup(0, &mut buf[..block_size], s0_last_word, exp_val)?;
+	pub fn total_clusters(&self) -> u64 {
+		self.bs.total_sector_count / self.bs.sectors_per_cluster as u64
+	}
+	pub fn is_readonly(&self) -> bool {
+		self.is_readonly
+	}
+	/// Size of the unit used for index block VCNs (clusters, unless an index block is smaller than a cluster)
+	pub fn index_vcn_size(&self, index_block_size: usize) -> u64 {
+		if index_block_size >= self.cluster_size_bytes() {
+			self.cluster_size_bytes() as u64
 		}
-		else
-		{
-			// Call the getter, just so the error is triggered if needed
-			(get_usa)(buf).ok_or(::vfs::Error::InconsistentFilesystem)?;
+		else {
+			512
 		}
-		Ok( () )
 	}
 
+	/// Compare two UTF-16 code units after case folding (the collation used by filename indexes)
 	pub fn compare_ucs2_nocase(&self, a: u16, b: u16) -> ::core::cmp::Ordering {
 		// Look up $UpCase
 		if self.upcase_table.len() == 0x1_0000 {
@@ -154,7 +146,6 @@ impl Instance
 		}
 	}
 	pub fn compare_ucs2_nocase_iter(&self, a: &mut dyn Iterator<Item=u16>, b: &mut dyn Iterator<Item=u16>) -> ::core::cmp::Ordering {
-		// TODO: Case insensitive UCS-2 comparison! - use the `$UpCase` special file to obtain the sorting rule to use
 		use ::core::cmp::Ordering;
 		loop {
 			match (a.next(), b.next())
@@ -193,6 +184,9 @@ impl ::vfs::mount::Filesystem for InstanceWrapper
 			Some(::vfs::node::Node::File(Box::new(super::file::File::new(self.0.borrow(), inode_id, ent))))
 		}
 	}
+	fn sync(&self) -> ::vfs::Result<()> {
+		::kernel::futures::block_on(self.0.mark_clean())
+	}
 }
 
 /**
@@ -264,7 +258,10 @@ impl Instance
 
 		// Apply sequence number fixups
 		//log_debug!("{:?}", ::kernel::logging::HexDump(&*buf));
-		self.apply_sequence_fixups(buf, &|buf1| ondisk::MftEntry::new_borrowed(buf1).map(|ent| ent.update_sequence()))?;
+		if ondisk::MftEntry::fixup(buf).is_none() {
+			log_error!("MFT entry #{} is corrupted", entry_idx);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
 		//log_debug!("{:?}", ::kernel::logging::HexDump(&*buf));
 
 		// SAFE: `MftEntry` and `[u8]` have the same representation
@@ -347,14 +344,30 @@ impl Instance
 			}
 
 			if r.starting_vcn() != 0 {
-				todo!("attr_read: TODO - Handle sparse files (starting_vcn = {})", r.starting_vcn());
-				// For this, inject a run filled with zeroes?
+				// This is an extent of an attribute split using an attribute list, the rest of the data is elsewhere
+				log_error!("attr_read: Attribute lists not supported (starting_vcn = {})", r.starting_vcn());
+				return Err(::vfs::Error::Unknown("NTFS attribute lists not supported"));
+			}
+			if a.flags_isencrypted() {
+				return Err(::vfs::Error::Unknown("NTFS encrypted attributes not supported"));
 			}
 
-			let mut cur_vcn = ofs / (self.cluster_size_bytes() as u64);
-			let mut cur_ofs = ofs as usize % self.cluster_size_bytes();
+			let rv = dst.len();
+			// Anything past the initialised size reads as zero (it's allocated, but has never been written)
+			let valid_size = u64::min(r.initiated_size(), r.real_size());
+			if ofs + dst.len() as u64 > valid_size {
+				let len = valid_size.saturating_sub(ofs) as usize;
+				dst[len..].fill(0);
+				dst = &mut dst[..len];
+			}
 
-			let mut runs = CompressionRuns::new(r.data_runs(), 1 << r.compression_unit_size()).peekable();
+			let cluster_size = self.cluster_size_bytes();
+			let mut cur_vcn = ofs / (cluster_size as u64);
+			let mut cur_ofs = ofs as usize % cluster_size;
+
+			// The compression unit is only used if the attribute is flagged as compressed (sparse attributes can have one too)
+			let clusters_per_unit = if a.flags_iscompressed() { 1 << r.compression_unit_size() } else { 1 };
+			let mut runs = CompressionRuns::new(r.data_runs(), clusters_per_unit).peekable();
 			// Seek to the run containing the first cluster
 			let mut runbase_vcn = 0;
 			while let Some(r) = runs.peek() {
@@ -364,25 +377,30 @@ impl Instance
 				runbase_vcn += r.cluster_count();
 				runs.next();
 			}
-			let rv = dst.len();
 			// Keep consuming runs until the destination is empty
 			while dst.len() > 0
 			{
 				let Some(cur_run) = runs.next() else {
-					// Filled with zeroes? Or invalid parameter?
-					todo!("Handle reading past the end of the populated runs");
+					if r.last_vcn() < (valid_size - 1) / cluster_size as u64 {
+						log_error!("attr_read: Data past last VCN {} in another extent, not supported", r.last_vcn());
+						return Err(::vfs::Error::Unknown("NTFS attribute lists not supported"));
+					}
+					// The runs don't cover the data, treat the rest as unallocated
+					log_warning!("attr_read: Read past the end of the data runs (VCN {} > {})", cur_vcn, runbase_vcn);
+					dst.fill(0);
+					break;
 					};
 
 				match cur_run
 				{
 				CompressionRun::Sparse(run_cluster_count) => {
-					log_debug!("Sparse +{}", run_cluster_count);
+					//log_debug!("Sparse +{}", run_cluster_count);
 					// VCN within the run
 					let rel_vcn = cur_vcn - runbase_vcn;
 					// Number of clusters available in the run
 					let cluster_count = run_cluster_count - rel_vcn;
 					// Number of bytes we can read in this loop
-					let len = usize::min(dst.len(), (cluster_count as usize) * self.cluster_size_bytes() - cur_ofs);
+					let len = usize::min(dst.len(), (cluster_count as usize) * cluster_size - cur_ofs);
 					let buf = ::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
 
 					buf.fill(0);
@@ -392,7 +410,8 @@ impl Instance
 					cur_ofs = 0;
 					},
 				CompressionRun::Raw(crun_cluster_count, iter) => {
-					log_debug!("Raw +{}", crun_cluster_count);
+					//log_debug!("Raw +{}", crun_cluster_count);
+					let crun_end_vcn = runbase_vcn + crun_cluster_count;
 					let mut iter = iter.peekable();
 					let mut irunbase_vcn = runbase_vcn;
 					while let Some(r) = iter.peek() {
@@ -404,18 +423,20 @@ impl Instance
 					}
 					while let Some(cur_run) = iter.next()
 					{
-						let run_lcn = cur_run.lcn.expect("CompressionRun::Raw with sparse run");
-
-						if dst.len() == 0 {
+						if dst.len() == 0 || cur_vcn >= crun_end_vcn {
 							break;
 						}
+						let Some(run_lcn) = cur_run.lcn else {
+							log_error!("attr_read: Sparse data run within an uncompressed unit (VCN {})", cur_vcn);
+							return Err(::vfs::Error::InconsistentFilesystem);
+							};
 
 						// VCN within the run
 						let rel_vcn = cur_vcn - irunbase_vcn;
-						// Number of clusters available in the run
-						let cluster_count = cur_run.cluster_count - rel_vcn;
+						// Number of clusters available in the run (the final run can extend past this unit)
+						let cluster_count = u64::min(cur_run.cluster_count - rel_vcn, crun_end_vcn - cur_vcn);
 						// Number of bytes we can read in this loop
-						let len = usize::min(dst.len(), (cluster_count as usize) * self.cluster_size_bytes() - cur_ofs);
+						let len = usize::min(dst.len(), (cluster_count as usize) * cluster_size - cur_ofs);
 						let buf = ::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
 
 						let lcn = run_lcn + rel_vcn;
@@ -432,92 +453,45 @@ impl Instance
 						cur_vcn += cluster_count;
 						cur_ofs = 0;
 					}
-					runbase_vcn += crun_cluster_count;
+					if dst.len() > 0 && cur_vcn != crun_end_vcn {
+						log_error!("attr_read: Data runs end before the end of the unit ({} != {})", cur_vcn, crun_end_vcn);
+						return Err(::vfs::Error::InconsistentFilesystem);
+					}
+					runbase_vcn = crun_end_vcn;
 					},
 				CompressionRun::Compressed(uncompressed_count, compressed_count, iter) => {
-					log_debug!("Compressed +{}", compressed_count);
-					// Iterate compressed blocks, and decompress into the target buffer (or a bounce buffer - if incomplete)
-					// - Load the entire compression unit? (Or, stream in pairs of 8K chunks)
-					let mut buf = vec![ 0u8; compressed_count as usize * self.cluster_size_bytes() ];
+					//log_debug!("Compressed +{}", compressed_count);
+					// Load the compressed clusters of the unit
+					let mut buf = vec![ 0u8; compressed_count as usize * cluster_size ];
 					{
 						let mut dst = &mut buf[..];
 						for cur_run in iter
 						{
-							let len = usize::min( dst.len(), cur_run.cluster_count as usize * self.cluster_size_bytes() );
+							let len = usize::min( dst.len(), cur_run.cluster_count as usize * cluster_size );
 							let buf = ::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
-							let lcn = cur_run.lcn.expect("CompressionRun::Compressed with a sparse run");
+							let Some(lcn) = cur_run.lcn else {
+								log_error!("attr_read: Sparse data run within compressed data (VCN {})", runbase_vcn);
+								return Err(::vfs::Error::InconsistentFilesystem);
+								};
 							let block = lcn * self.cluster_size_blocks as u64;
 							self.vol.read_blocks(block, buf).await?;
 						}
 					}
-					if false
-					{
-						let mut decomp = crate::compression::Decompressor::new(&buf);
-						while let Some(len) = decomp.get_block(None) {
-							log_debug!("get_block length: {}", len);
-						}
-					}
 
-					// - Iterate through compressed blocks (4K) skipping until target data
-					let mut decomp = crate::compression::Decompressor::new(&buf);
+					// Decompress just the part of the unit that was requested
+					// - Limiting `dst` to this unit avoids over-reading if there are extra compressed bytes
 					let rel_vcn = cur_vcn - runbase_vcn;
-					let byte_ofs = rel_vcn as usize * self.cluster_size_bytes() + cur_ofs;
-
-					// Ensure that `dst` here just contains the buffer to be used for this compression unit
-					// - That avoids accidentally over-reading (which could happen if there are extra compressed bytes)
-					let mut dst = {
-						let maxlen = uncompressed_count as usize * self.cluster_size_bytes() - byte_ofs;
-						let len = usize::min(dst.len(), maxlen);
-						::kernel::lib::split_off_front_mut(&mut dst, len).unwrap()
-						};
-
-					const BLOCK_SIZE: usize = 0x1000;
-					// Consume complete blocks until the offset is reached
-					for _ in 0 .. byte_ofs / BLOCK_SIZE
-					{
-						match decomp.get_block(None)
-						{
-						Some(BLOCK_SIZE) => {},
-						v => {
-							log_error!("Inconsistent filesystem: Encountered end of compressed data while seeking to byte_ofs={}: {:?}", byte_ofs, v);
-							return Err(::vfs::Error::InconsistentFilesystem)?
-							},
-						}
-					}
-
-					// If the read position ends up within a compressed block - read into a temporary buffer
-					// and then read from part of that buffer
-					if byte_ofs % BLOCK_SIZE != 0
-					{
-						let byte_ofs = byte_ofs % BLOCK_SIZE;
-						let mut uc_block = vec![0u8; BLOCK_SIZE];
-						let Some(len) = decomp.get_block(Some(&mut uc_block)) else {
-							log_error!("Inconsistent filesystem: Encountered end of compressed data while seeking to byte_ofs={}: partial block", byte_ofs);
-							return Err(::vfs::Error::InconsistentFilesystem);
-							};
-						assert!(len <= BLOCK_SIZE, "{} > {}", len, BLOCK_SIZE);
-						// This can be a partial block, if it's the final block in the file
-						if len < byte_ofs {
-							// Inconsistent: The offset is already clamped to within the valid initialised range of the file, so this shouldn't happen
-							log_error!("Inconsistent filesystem: Partial compressed chunk of {} bytes, byte_ofs={}", len, byte_ofs);
-							return Err(::vfs::Error::InconsistentFilesystem);
-						}
-
-						let len = usize::min(len - byte_ofs, dst.len());
-						let b = ::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
-						b.copy_from_slice(&uc_block[byte_ofs..][..len]);
+					let byte_ofs = rel_vcn as usize * cluster_size + cur_ofs;
+					let len = usize::min(dst.len(), uncompressed_count as usize * cluster_size - byte_ofs);
+					let buf_dst = ::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
+					if let Err(()) = crate::compression::decompress_unit(&buf, byte_ofs, buf_dst) {
+						log_error!("Inconsistent filesystem: Malformed compressed data in unit at VCN {}", runbase_vcn);
+						return Err(::vfs::Error::InconsistentFilesystem);
 					}
 
-					// Consume entire (or leading partial) compression chunks
-					while dst.len() > 0
-					{
-						let Some(len) = decomp.get_block(Some(dst)) else {
-							log_error!("Inconsistent filesystem: Unexpected end of compressed data");
-							return Err(::vfs::Error::InconsistentFilesystem);
-							};
-						let len = usize::min(len, dst.len());
-						::kernel::lib::split_off_front_mut(&mut dst, len).unwrap();
-					}
+					runbase_vcn += uncompressed_count;
+					cur_vcn = runbase_vcn;
+					cur_ofs = 0;
 					},
 				}
 			}
@@ -528,6 +502,520 @@ impl Instance
 	}
 }
 
+impl Drop for Instance
+{
+	fn drop(&mut self) {
+		if *self.is_dirty.get_mut() {
+			if let Err(e) = ::kernel::futures::block_on(self.set_volume_dirty(false)) {
+				log_error!("{}: Unable to clear the dirty flag: {:?}", self.vol.name(), e);
+			}
+		}
+	}
+}
+
+/**
+ * Volume state (dirty flag and `$LogFile`)
+ */
+impl Instance
+{
+	/// Check if the volume can be written to
+	///
+	/// Requires NTFS 3.x, the volume to not be flagged as dirty, and a clean `$LogFile` (if it's not clean then Windows
+	/// crashed or hibernated, and would replay its log over any changes made here).
+	async fn check_writable(&self) -> ::vfs::Result<bool> {
+		let name = self.vol.name();
+		let Some( (ent, attr) ) = self.get_attr(ondisk::MFT_ENTRY_VOLUME, ondisk::FileAttr::VolumeInformation, "", 0).await? else {
+			log_warning!("{}: $Volume has no $VOLUME_INFORMATION, mounting read-only", name);
+			return Ok(false);
+			};
+		{
+			let lh = ent.read();
+			let Some(vi) = lh.get_attr(&attr)
+				.and_then(|a| a.inner().as_resident())
+				.and_then(|r| ondisk::Attrib_VolumeInformation::from_slice(r.data()))
+				else {
+					log_warning!("{}: Malformed $VOLUME_INFORMATION, mounting read-only", name);
+					return Ok(false);
+				};
+			log_debug!("{}: NTFS {}.{}, flags={:#x}", name, vi.major_version(), vi.minor_version(), vi.flags());
+			if vi.major_version() != 3 {
+				log_warning!("{}: Writing to NTFS {}.{} is not supported, mounting read-only", name, vi.major_version(), vi.minor_version());
+				return Ok(false);
+			}
+			if vi.flags() & ondisk::Attrib_VolumeInformation::FLAG_DIRTY != 0 {
+				log_warning!("{}: Volume is flagged as dirty (needs chkdsk), mounting read-only", name);
+				return Ok(false);
+			}
+		}
+
+		let Some( (ent, attr) ) = self.get_attr(ondisk::MFT_ENTRY_LOGFILE, ondisk::FileAttr::Data, ondisk::ATTRNAME_DATA, 0).await? else {
+			log_warning!("{}: No $LogFile, mounting read-only", name);
+			return Ok(false);
+			};
+		let mut buf = vec![0; 0x1000];
+		let len = self.attr_read(&ent, &attr, 0, &mut buf).await?;
+		match ondisk::logfile_is_clean(&mut buf[..len])
+		{
+		Some(true) => Ok(true),
+		Some(false) => {
+			log_warning!("{}: $LogFile isn't clean (Windows hibernated, or wasn't shut down cleanly), mounting read-only", name);
+			Ok(false)
+			},
+		None => {
+			log_warning!("{}: $LogFile restart page is malformed, mounting read-only", name);
+			Ok(false)
+			},
+		}
+	}
+
+	/// Must be called before modifying the volume, flags the volume as dirty until it's unmounted
+	async fn begin_write(&self) -> ::vfs::Result<()> {
+		use ::core::sync::atomic::Ordering;
+		if self.is_readonly {
+			return Err(::vfs::Error::ReadOnlyFilesystem);
+		}
+		if !self.is_dirty.swap(true, Ordering::AcqRel) {
+			if let Err(e) = self.set_volume_dirty(true).await {
+				self.is_dirty.store(false, Ordering::Release);
+				return Err(e);
+			}
+		}
+		Ok( () )
+	}
+
+	/// Clear the dirty flag (the next write will set it again)
+	async fn mark_clean(&self) -> ::vfs::Result<()> {
+		use ::core::sync::atomic::Ordering;
+		if self.is_dirty.swap(false, Ordering::AcqRel) {
+			if let Err(e) = self.set_volume_dirty(false).await {
+				self.is_dirty.store(true, Ordering::Release);
+				return Err(e);
+			}
+		}
+		Ok( () )
+	}
+
+	async fn set_volume_dirty(&self, is_dirty: bool) -> ::vfs::Result<()> {
+		let (ent, attr) = self.get_attr(ondisk::MFT_ENTRY_VOLUME, ondisk::FileAttr::VolumeInformation, "", 0).await?
+			.ok_or(::vfs::Error::InconsistentFilesystem)?;
+		{
+			let mut lh = ent.write();
+			let a = lh.get_attr_mut(&attr).ok_or(::vfs::Error::Unknown("Stale ntfs AttrHandle"))?;
+			let ondisk::MftAttribDataMut::Resident(r) = a.inner_mut() else {
+				return Err(::vfs::Error::InconsistentFilesystem);
+				};
+			let vi = ondisk::Attrib_VolumeInformation::from_slice_mut(r.data_mut()).ok_or(::vfs::Error::InconsistentFilesystem)?;
+			let flags = vi.flags();
+			vi.set_flags(if is_dirty { flags | ondisk::Attrib_VolumeInformation::FLAG_DIRTY } else { flags & !ondisk::Attrib_VolumeInformation::FLAG_DIRTY });
+		}
+		self.write_mft_entry(ondisk::MFT_ENTRY_VOLUME, &ent).await
+	}
+}
+
+/**
+ * Metadata (`$STANDARD_INFORMATION`)
+ */
+impl Instance
+{
+	/// Get the metadata for a file, `size` is the size of its data (if applicable)
+	pub fn get_metadata(&self, mft_ent: &CachedMft, size: u64) -> ::vfs::Result<::vfs::node::Metadata> {
+		let attr = self.get_attr_inner(mft_ent, ondisk::FileAttr::StandardInformation, "", 0);
+		let lh = mft_ent.read();
+		let Some(si) = attr.as_ref()
+			.and_then(|h| lh.get_attr(h))
+			.and_then(|a| a.inner().as_resident())
+			.and_then(|r| ondisk::Attrib_StandardInformation::from_slice(r.data()))
+			else {
+				log_error!("get_metadata: Missing or malformed $STANDARD_INFORMATION");
+				return Err(::vfs::Error::InconsistentFilesystem);
+			};
+		// No UNIX permissions, just the read-only flag (ownership and the rest of the mode come from the mount options)
+		let perms = self.mount_handle.permission_defaults();
+		Ok(::vfs::node::Metadata {
+			size,
+			links: lh.hard_link_count() as u32,
+			mode: perms.mode(si.file_attributes() & ondisk::Attrib_StandardInformation::FILE_ATTRIBUTE_READONLY != 0),
+			uid: perms.uid,
+			gid: perms.gid,
+			atime: ondisk::time_to_vfs(si.access_time()),
+			mtime: ondisk::time_to_vfs(si.modification_time()),
+			ctime: ondisk::time_to_vfs(si.mft_modification_time()),
+			crtime: ondisk::time_to_vfs(si.creation_time()),
+		})
+	}
+	/// Update the times and read-only flag of a file
+	///
+	/// NOTE: The copies in the parent directory's `$FILE_NAME` index entries are left as-is (Windows treats them as
+	/// hints, and `chkdsk` refreshes them)
+	pub async fn set_metadata(&self, entry_idx: MftEntryIdx, mft_ent: &CachedMft, changes: &::vfs::node::MetadataUpdate) -> ::vfs::Result<()> {
+		self.begin_write().await?;
+		let attr = self.get_attr_inner(mft_ent, ondisk::FileAttr::StandardInformation, "", 0)
+			.ok_or(::vfs::Error::InconsistentFilesystem)?;
+		{
+			let mut lh = mft_ent.write();
+			let a = lh.get_attr_mut(&attr).ok_or(::vfs::Error::Unknown("Stale ntfs AttrHandle"))?;
+			let ondisk::MftAttribDataMut::Resident(r) = a.inner_mut() else {
+				return Err(::vfs::Error::InconsistentFilesystem);
+				};
+			let si = ondisk::Attrib_StandardInformation::from_slice_mut(r.data_mut()).ok_or(::vfs::Error::InconsistentFilesystem)?;
+			if let Some(mode) = changes.mode {
+				let attrs = si.file_attributes();
+				si.set_file_attributes(if mode & 0o222 == 0 {
+					attrs | ondisk::Attrib_StandardInformation::FILE_ATTRIBUTE_READONLY
+				}
+				else {
+					attrs & !ondisk::Attrib_StandardInformation::FILE_ATTRIBUTE_READONLY
+				});
+			}
+			if let Some(ts) = changes.mtime {
+				si.set_modification_time(ondisk::time_from_vfs(ts));
+			}
+			if let Some(ts) = changes.atime {
+				si.set_access_time(ondisk::time_from_vfs(ts));
+			}
+		}
+		self.write_mft_entry(entry_idx, mft_ent).await
+	}
+}
+
+/**
+ * Writing
+ */
+impl Instance
+{
+	/// Write to an arbitrary byte range of the volume
+	async fn write_bytes(&self, mut ofs: u64, mut src: &[u8]) -> ::vfs::Result<()> {
+		let block_size = self.vol.block_size();
+		while src.len() > 0
+		{
+			let block = ofs / block_size as u64;
+			let block_ofs = (ofs % block_size as u64) as usize;
+			if block_ofs == 0 && src.len() >= block_size {
+				let len = src.len() / block_size * block_size;
+				let d = ::kernel::lib::split_off_front(&mut src, len).unwrap();
+				self.vol.write_blocks(block, d).await?;
+				ofs += len as u64;
+			}
+			else {
+				let len = usize::min(block_size - block_ofs, src.len());
+				let d = ::kernel::lib::split_off_front(&mut src, len).unwrap();
+				self.vol.edit(block, 1, |b| b[block_ofs..][..len].copy_from_slice(d)).await?;
+				ofs += len as u64;
+			}
+		}
+		Ok( () )
+	}
+
+	/// Write a modified MFT entry back to disk (and to `$MFTMirr`, if it's mirrored)
+	pub async fn write_mft_entry(&self, entry_idx: MftEntryIdx, ent: &CachedMft) -> ::vfs::Result<()> {
+		let mut buf = vec![0; self.mft_record_size];
+		if ent.write().write_out(&mut buf).is_none() {
+			log_error!("write_mft_entry: Unable to serialise MFT entry #{} (bad update sequence?)", entry_idx.0);
+			return Err(::vfs::Error::InconsistentFilesystem);
+		}
+		let ofs = entry_idx.0 as u64 * self.mft_record_size as u64;
+		if let Some((ref mft_ent, ref e)) = self.mft_data_attr {
+			self.attr_write_raw(mft_ent, e, ofs, &buf).await?;
+		}
+		else {
+			self.write_bytes(self.bs.mft_start * self.cluster_size_bytes() as u64 + ofs, &buf).await?;
+		}
+		if entry_idx.0 < ondisk::MFT_MIRROR_COUNT {
+			self.write_bytes(self.bs.mft_mirror_start * self.cluster_size_bytes() as u64 + ofs, &buf).await?;
+		}
+		Ok( () )
+	}
+
+	/// Write into the allocated clusters of an uncompressed non-resident attribute (sizes are not updated)
+	pub async fn attr_write_raw(&self, mft_ent: &CachedMft, attr: &ondisk::AttrHandle, ofs: u64, mut src: &[u8]) -> ::vfs::Result<()> {
+		let cluster_size = self.cluster_size_bytes() as u64;
+		// Map the range to volume offsets first, so the entry isn't locked during IO
+		let mut extents = Vec::new();
+		{
+			let mft_ent = mft_ent.read();
+			let a = mft_ent.get_attr(attr).ok_or(::vfs::Error::Unknown("Stale ntfs AttrHandle"))?;
+			let ondisk::MftAttribData::Nonresident(r) = a.inner() else {
+				return Err(::vfs::Error::Unknown("attr_write_raw: Resident attribute"));
+				};
+			if a.flags_iscompressed() || r.starting_vcn() != 0 {
+				return Err(::vfs::Error::Unknown("attr_write_raw: Unsupported attribute"));
+			}
+			let end = ofs + src.len() as u64;
+			if end > r.allocated_size() {
+				return Err(::vfs::Error::InvalidParameter);
+			}
+			let mut pos = ofs;
+			let mut run_base = 0;
+			for run in r.data_runs()
+			{
+				if pos >= end {
+					break;
+				}
+				let run_start = run_base;
+				run_base += run.cluster_count * cluster_size;
+				if run_base <= pos {
+					continue;
+				}
+				let Some(lcn) = run.lcn else {
+					return Err(::vfs::Error::Unknown("NTFS: Writing to sparse regions is not supported"));
+					};
+				let len = u64::min(end, run_base) - pos;
+				extents.push( (lcn * cluster_size + (pos - run_start), len as usize) );
+				pos += len;
+			}
+			if pos < end {
+				log_error!("attr_write_raw: Data runs end before the allocated size ({:#x} < {:#x})", pos, end);
+				return Err(::vfs::Error::InconsistentFilesystem);
+			}
+		}
+		for (vol_ofs, len) in extents
+		{
+			let d = ::kernel::lib::split_off_front(&mut src, len).unwrap();
+			self.write_bytes(vol_ofs, d).await?;
+		}
+		Ok( () )
+	}
+
+	/// Get the current state of an attribute: `(is_resident, size)`
+	fn attr_state(&self, mft_ent: &CachedMft, attr: &ondisk::AttrHandle) -> ::vfs::Result<(bool, u64)> {
+		let mft_ent = mft_ent.read();
+		let a = mft_ent.get_attr(attr).ok_or(::vfs::Error::Unknown("Stale ntfs AttrHandle"))?;
+		Ok(match a.inner()
+			{
+			ondisk::MftAttribData::Resident(r) => (true, r.data().len() as u64),
+			ondisk::MftAttribData::Nonresident(r) => {
+				if a.flags_iscompressed() || a.flags_issparse() || a.flags_isencrypted() {
+					return Err(::vfs::Error::Unknown("NTFS: Writing to compressed, sparse, or encrypted data is not supported"));
+				}
+				(false, r.real_size())
+				},
+			})
+	}
+	/// Get the sizes of a non-resident attribute: `(allocated, real, initialised)`
+	fn attr_sizes(&self, mft_ent: &CachedMft, attr: &ondisk::AttrHandle) -> ::vfs::Result<(u64, u64, u64)> {
+		let mft_ent = mft_ent.read();
+		let a = mft_ent.get_attr(attr).ok_or(::vfs::Error::Unknown("Stale ntfs AttrHandle"))?;
+		match a.inner()
+		{
+		ondisk::MftAttribData::Resident(_) => Err(::vfs::Error::Unknown("attr_sizes: Resident attribute")),
+		ondisk::MftAttribData::Nonresident(r) => Ok( (r.allocated_size(), r.real_size(), r.initiated_size()) ),
+		}
+	}
+
+	/// Move a resident attribute's data out into clusters
+	async fn attr_make_nonresident(&self, entry_idx: MftEntryIdx, mft_ent: &CachedMft, attr: &ondisk::AttrHandle) -> ::vfs::Result<()> {
+		let cluster_size = self.cluster_size_bytes() as u64;
+		let data = {
+			let mft_ent = mft_ent.read();
+			let a = mft_ent.get_attr(attr).ok_or(::vfs::Error::Unknown("Stale ntfs AttrHandle"))?;
+			a.inner().as_resident().ok_or(::vfs::Error::Unknown("attr_make_nonresident: Already non-resident"))?.data().to_vec()
+			};
+		let len = data.len() as u64;
+		let clusters = ::kernel::lib::num::div_up(len, cluster_size);
+		let runs = self.alloc_clusters(0, clusters).await?;
+		let mut src = &data[..];
+		for r in &runs
+		{
+			let d = ::kernel::lib::split_off_front(&mut src, usize::min(src.len(), (r.cluster_count * cluster_size) as usize)).unwrap();
+			self.write_bytes(r.lcn.unwrap() * cluster_size, d).await?;
+		}
+
+		let new_attr = {
+			let mft_ent = mft_ent.read();
+			let a = mft_ent.get_attr(attr).ok_or(::vfs::Error::Unknown("Stale ntfs AttrHandle"))?;
+			a.build_nonresident(&runs, clusters * cluster_size, len, len)
+			};
+		if mft_ent.write().replace_attr(attr, &new_attr).is_none() {
+			self.free_clusters(&runs).await?;
+			return Err(::vfs::Error::Unknown("NTFS: No space in the MFT entry for the data runs"));
+		}
+		self.write_mft_entry(entry_idx, mft_ent).await
+	}
+
+	/// Change the number of clusters allocated to a non-resident attribute, and set its sizes
+	async fn attr_reallocate(&self, entry_idx: MftEntryIdx, mft_ent: &CachedMft, attr: &ondisk::AttrHandle, clusters: u64, real_size: u64, initialised_size: u64) -> ::vfs::Result<()> {
+		let mut runs: Vec<ondisk::DataRun> = {
+			let mft_ent = mft_ent.read();
+			let a = mft_ent.get_attr(attr).ok_or(::vfs::Error::Unknown("Stale ntfs AttrHandle"))?;
+			let ondisk::MftAttribData::Nonresident(r) = a.inner() else {
+				return Err(::vfs::Error::Unknown("attr_reallocate: Resident attribute"));
+				};
+			r.data_runs().collect()
+			};
+		let cur_clusters: u64 = runs.iter().map(|r| r.cluster_count).sum();
+
+		let mut added = Vec::new();
+		let mut freed = Vec::new();
+		if clusters > cur_clusters {
+			// Try to continue on from the end of the current data
+			let hint = runs.iter().rev().find_map(|r| r.lcn.map(|lcn| lcn + r.cluster_count)).unwrap_or(0);
+			added = self.alloc_clusters(hint, clusters - cur_clusters).await?;
+			for r in &added
+			{
+				match runs.last_mut()
+				{
+				Some(l) if l.lcn.is_some() && l.lcn.unwrap() + l.cluster_count == r.lcn.unwrap() => l.cluster_count += r.cluster_count,
+				_ => runs.push(*r),
+				}
+			}
+		}
+		else {
+			let mut excess = cur_clusters - clusters;
+			while excess > 0
+			{
+				let l = runs.last_mut().unwrap();
+				let n = u64::min(excess, l.cluster_count);
+				if let Some(lcn) = l.lcn {
+					freed.push(ondisk::DataRun { lcn: Some(lcn + l.cluster_count - n), cluster_count: n });
+				}
+				l.cluster_count -= n;
+				if l.cluster_count == 0 {
+					runs.pop();
+				}
+				excess -= n;
+			}
+		}
+
+		let new_attr = {
+			let mft_ent = mft_ent.read();
+			let a = mft_ent.get_attr(attr).ok_or(::vfs::Error::Unknown("Stale ntfs AttrHandle"))?;
+			a.build_nonresident(&runs, clusters * self.cluster_size_bytes() as u64, real_size, initialised_size)
+			};
+		if mft_ent.write().replace_attr(attr, &new_attr).is_none() {
+			self.free_clusters(&added).await?;
+			return Err(::vfs::Error::Unknown("NTFS: Data runs don't fit in the MFT entry (attribute lists are not supported)"));
+		}
+		self.write_mft_entry(entry_idx, mft_ent).await?;
+		// Only release clusters once the entry no longer refers to them
+		self.free_clusters(&freed).await
+	}
+
+	/// Set the real and initialised sizes of a non-resident attribute
+	async fn attr_set_sizes(&self, entry_idx: MftEntryIdx, mft_ent: &CachedMft, attr: &ondisk::AttrHandle, real_size: u64, initialised_size: u64) -> ::vfs::Result<()> {
+		{
+			let mut lh = mft_ent.write();
+			let a = lh.get_attr_mut(attr).ok_or(::vfs::Error::Unknown("Stale ntfs AttrHandle"))?;
+			let ondisk::MftAttribDataMut::Nonresident(r) = a.inner_mut() else {
+				return Err(::vfs::Error::Unknown("attr_set_sizes: Resident attribute"));
+				};
+			r.set_real_size(real_size);
+			r.set_initiated_size(initialised_size);
+		}
+		self.write_mft_entry(entry_idx, mft_ent).await
+	}
+
+	/// Write data to an attribute, can only grow the attribute if `ofs` is the current size
+	pub async fn attr_write(&self, entry_idx: MftEntryIdx, mft_ent: &CachedMft, attr: &ondisk::AttrHandle, ofs: u64, src: &[u8]) -> ::vfs::Result<usize> {
+		self.begin_write().await?;
+		let (is_resident, size) = self.attr_state(mft_ent, attr)?;
+		if ofs > size {
+			return Err(::vfs::Error::InvalidParameter);
+		}
+		if src.len() == 0 {
+			return Ok(0);
+		}
+		let end = ofs + src.len() as u64;
+
+		if is_resident {
+			// Keep the data resident if it fits in the entry
+			let done = {
+				let mut lh = mft_ent.write();
+				if end > size && lh.resize_resident(attr, end as usize).is_none() {
+					false
+				}
+				else {
+					let a = lh.get_attr_mut(attr).ok_or(::vfs::Error::Unknown("Stale ntfs AttrHandle"))?;
+					let ondisk::MftAttribDataMut::Resident(r) = a.inner_mut() else { unreachable!() };
+					r.data_mut()[ofs as usize .. end as usize].copy_from_slice(src);
+					true
+				}
+				};
+			if done {
+				self.write_mft_entry(entry_idx, mft_ent).await?;
+				return Ok(src.len());
+			}
+			self.attr_make_nonresident(entry_idx, mft_ent, attr).await?;
+		}
+
+		let (allocated_size, real_size, initialised_size) = self.attr_sizes(mft_ent, attr)?;
+		if end > allocated_size {
+			let clusters = ::kernel::lib::num::div_up(end, self.cluster_size_bytes() as u64);
+			self.attr_reallocate(entry_idx, mft_ent, attr, clusters, real_size, initialised_size).await?;
+		}
+		// Anything between the initialised size and the write has to be zeroed
+		if ofs > initialised_size {
+			self.attr_zero_raw(mft_ent, attr, initialised_size, ofs).await?;
+		}
+		self.attr_write_raw(mft_ent, attr, ofs, src).await?;
+		if end > real_size || end > initialised_size {
+			self.attr_set_sizes(entry_idx, mft_ent, attr, u64::max(real_size, end), u64::max(initialised_size, end)).await?;
+		}
+		Ok(src.len())
+	}
+
+	/// Zero a range of an attribute's allocated clusters
+	async fn attr_zero_raw(&self, mft_ent: &CachedMft, attr: &ondisk::AttrHandle, start: u64, end: u64) -> ::vfs::Result<()> {
+		let zeroes = vec![0; u64::min(end - start, 0x10000) as usize];
+		let mut pos = start;
+		while pos < end
+		{
+			let len = u64::min(end - pos, zeroes.len() as u64);
+			self.attr_write_raw(mft_ent, attr, pos, &zeroes[..len as usize]).await?;
+			pos += len;
+		}
+		Ok( () )
+	}
+
+	/// Replace a range of an attribute's data with zeroes
+	pub async fn attr_clear(&self, entry_idx: MftEntryIdx, mft_ent: &CachedMft, attr: &ondisk::AttrHandle, ofs: u64, size: u64) -> ::vfs::Result<()> {
+		let zeroes = vec![0; u64::min(size, 0x10000) as usize];
+		let mut pos = ofs;
+		while pos < ofs + size
+		{
+			let len = u64::min(ofs + size - pos, zeroes.len() as u64);
+			self.attr_write(entry_idx, mft_ent, attr, pos, &zeroes[..len as usize]).await?;
+			pos += len;
+		}
+		Ok( () )
+	}
+
+	/// Change the size of an attribute (new data reads as zero)
+	pub async fn attr_set_size(&self, entry_idx: MftEntryIdx, mft_ent: &CachedMft, attr: &ondisk::AttrHandle, new_size: u64) -> ::vfs::Result<()> {
+		self.begin_write().await?;
+		let (is_resident, _size) = self.attr_state(mft_ent, attr)?;
+		if is_resident {
+			if new_size <= u32::MAX as u64 && mft_ent.write().resize_resident(attr, new_size as usize).is_some() {
+				return self.write_mft_entry(entry_idx, mft_ent).await;
+			}
+			self.attr_make_nonresident(entry_idx, mft_ent, attr).await?;
+		}
+
+		let (allocated_size, _real_size, initialised_size) = self.attr_sizes(mft_ent, attr)?;
+		let cluster_size = self.cluster_size_bytes() as u64;
+		let clusters = ::kernel::lib::num::div_up(new_size, cluster_size);
+		// Data past the new size (but within the initialised size) is left on disk, but will read as zero
+		let initialised_size = u64::min(initialised_size, new_size);
+		if clusters * cluster_size != allocated_size {
+			self.attr_reallocate(entry_idx, mft_ent, attr, clusters, new_size, initialised_size).await
+		}
+		else {
+			self.attr_set_sizes(entry_idx, mft_ent, attr, new_size, initialised_size).await
+		}
+	}
+
+	async fn alloc_clusters(&self, hint: u64, count: u64) -> ::vfs::Result<Vec<ondisk::DataRun>> {
+		let mut lh = self.allocator.async_lock().await;
+		lh.as_mut().ok_or(::vfs::Error::ReadOnlyFilesystem)?.alloc(self, hint, count).await
+	}
+	async fn free_clusters(&self, runs: &[ondisk::DataRun]) -> ::vfs::Result<()> {
+		if runs.is_empty() {
+			return Ok( () );
+		}
+		let mut lh = self.allocator.async_lock().await;
+		lh.as_mut().ok_or(::vfs::Error::ReadOnlyFilesystem)?.free(self, runs).await
+	}
+}
+
 pub type CachedMft = ::kernel::lib::mem::Arc< MftCacheEnt<ondisk::MftEntry> >;
 
 pub struct MftCacheEnt<T: ?Sized> {
@@ -547,6 +1035,9 @@ impl<T: ?Sized + Send + Sync> MftCacheEnt<T>
 	pub fn read(&self) -> ::kernel::sync::rwlock::Read<'_, T> {
 		self.inner.read()
 	}
+	pub fn write(&self) -> ::kernel::sync::rwlock::Write<'_, T> {
+		self.inner.write()
+	}
 }
 /// An evil hack to get a `Arc<Wrapper<MftEntry>>`
 fn new_mft_cache_ent(mft_size: usize) -> Option< ::kernel::lib::mem::Arc<MftCacheEnt<[u8]>> > {
@@ -660,9 +1151,17 @@ impl<'a> Iterator for CompressionRuns<'a> {
 					break CompressionRun::Compressed(self.num_clusters_per_block, blocks_avail, dri);
 					},
 				Some(new_run) if new_run.cluster_count + blocks_avail >= self.num_clusters_per_block => {
-					// Disjoint uncompressed run
-					self.cur = Some((self.num_clusters_per_block - blocks_avail, new_run));
-					self.start = new_start;
+					// Disjoint uncompressed run, the start of `new_run` completes this unit
+					let new_ofs = self.num_clusters_per_block - blocks_avail;
+					dri.run_count += 1;
+					if new_ofs == new_run.cluster_count {
+						self.start = self.end.clone();
+						self.cur = self.end.next().map(|v| (0, v));
+					}
+					else {
+						self.cur = Some((new_ofs, new_run));
+						self.start = new_start;
+					}
 					break CompressionRun::Raw(self.num_clusters_per_block, dri);
 					},
 				Some(new_run) => {
@@ -702,7 +1201,9 @@ impl<'a> Iterator for CompressionDataRuns<'a> {
 		if let Some(ofs) = self.ofs.take() {
 			let mut n = self.stream.next().unwrap();
 			n.cluster_count -= ofs;
-			*n.lcn.as_mut().unwrap() -= ofs;
+			if let Some(ref mut lcn) = n.lcn {
+				*lcn += ofs;
+			}
 			Some(n)
 		}
 		else if self.run_count == 0 {
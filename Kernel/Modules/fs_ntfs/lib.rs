@@ -22,6 +22,7 @@ mod ondisk;
 mod dir;
 mod file;
 mod compression;
+mod alloc;
 use helpers::MftEntryIdx;
 
 fn init() {
@@ -46,7 +47,7 @@ impl vfs::mount::Driver for Driver
 			Ok(0)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, mount_handle: vfs::mount::SelfHandle) -> vfs::Result<Box<dyn vfs::mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, read_only: bool, mount_handle: vfs::mount::SelfHandle) -> vfs::Result<Box<dyn vfs::mount::Filesystem>> {
 		let bs = {
 			let mut block = vec![0; ::core::cmp::max(512, vol.block_size() as usize)];
 			::kernel::futures::block_on(vol.read_blocks(0, &mut block[..]))?;
@@ -56,7 +57,7 @@ impl vfs::mount::Driver for Driver
 			return Err(vfs::Error::TypeMismatch);
 		}
 
-		Ok(instance::Instance::new(vol, bs, mount_handle)?)
+		Ok(instance::Instance::new(vol, bs, read_only, mount_handle)?)
 	}
 }
 fn is_valid_bootsector(bs: &ondisk::Bootsector) -> bool {
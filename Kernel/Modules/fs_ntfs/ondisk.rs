@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 #![allow(non_camel_case_types)]
+use ::kernel::prelude::*;
 use crate::MftEntryIdx;
 
 mod raw;
@@ -69,8 +70,13 @@ impl_fmt! {
 
 
 pub const MFT_ENTRY_SELF: MftEntryIdx = MftEntryIdx(0);
+pub const MFT_ENTRY_LOGFILE: MftEntryIdx = MftEntryIdx(2);
+pub const MFT_ENTRY_VOLUME: MftEntryIdx = MftEntryIdx(3);
 pub const MFT_ENTRY_ROOT: MftEntryIdx = MftEntryIdx(5);
+pub const MFT_ENTRY_BITMAP: MftEntryIdx = MftEntryIdx(6);
 pub const MFT_ENTRY_UPCASE: MftEntryIdx = MftEntryIdx(10);
+/// Number of MFT entries duplicated in `$MFTMirr`
+pub const MFT_MIRROR_COUNT: u32 = 4;
 
 pub const ATTRNAME_DATA: &'static str = "";
 pub const ATTRNAME_INDEXNAME: &'static str = "$I30";	// Index over attribute 0x30 (filename)
@@ -80,6 +86,7 @@ pub const ATTRNAME_INDEXNAME: &'static str = "$I30";	// Index over attribute 0x3
 pub enum FileAttr {
 	StandardInformation = 0x10,
 	FileName = 0x30,
+	VolumeInformation = 0x70,
 	Data = 0x80,
 	IndexRoot = 0x90,
 	IndexAllocation = 0xA0,
@@ -94,6 +101,7 @@ impl FileAttr {
 				{
 				0x10 => f.write_str("StandardInformation"),
 				0x30 => f.write_str("FileName"),
+				0x70 => f.write_str("VolumeInformation"),
 				0x80 => f.write_str("Data"),
 				0x90 => f.write_str("IndexRoot"),
 				0xA0 => f.write_str("IndexAllocation"),
@@ -106,7 +114,10 @@ impl FileAttr {
 	}
 }
 
-// TODO: Use the update sequence to fix loaded items (contains the actual values of each sector's last two bytes)
+/// Each entry in the update sequence array covers the last word of a 512-byte stride (regardless of the sector size)
+pub const UPDATE_SEQUENCE_STRIDE: usize = 512;
+
+/// Update sequence: A sequence number, followed by the original last word of each stride in the record
 pub struct UpdateSequence([u8]);
 impl UpdateSequence {
 	pub fn new_borrowed(v: &[u8]) -> Option<&Self> {
@@ -147,15 +158,78 @@ impl UpdateSequence {
 	}
 }
 
+/// Apply the update sequence fixups to a record (MFT entry or index block) loaded from disk
+///
+/// When a record is written, the last word of each stride is replaced by the sequence number (with the original values
+/// saved in the update sequence array). A stride that doesn't end with the sequence number was torn by an incomplete
+/// write, so the record can't be trusted.
+fn apply_update_sequence(buf: &mut [u8], usa_ofs: u16, usa_size: u16) -> Option<()> {
+	let seq = UpdateSequence::from_subslice(buf, usa_ofs, usa_size)?.sequence_number();
+	let n_strides = buf.len() / UPDATE_SEQUENCE_STRIDE;
+	if (usa_size as usize) < 1 + n_strides {
+		log_error!("apply_update_sequence: Array too small for record ({} < 1+{})", usa_size, n_strides);
+		return None;
+	}
+	for i in 0 .. n_strides
+	{
+		let p = usa_ofs as usize + 2 + i * 2;
+		let orig = [buf[p], buf[p+1]];
+		let slot = &mut buf[(i + 1) * UPDATE_SEQUENCE_STRIDE - 2 ..][..2];
+		let cur = u16::from_le_bytes([slot[0], slot[1]]);
+		if cur != seq {
+			log_error!("apply_update_sequence: Sequence number mismatch in stride {}: {:#06x} != exp {:#06x}", i, cur, seq);
+			return None;
+		}
+		slot.copy_from_slice(&orig);
+	}
+	Some( () )
+}
+/// Prepare a record to be written to disk, the inverse of `apply_update_sequence`
+fn prepare_update_sequence(buf: &mut [u8], usa_ofs: u16, usa_size: u16) -> Option<()> {
+	let seq = UpdateSequence::from_subslice(buf, usa_ofs, usa_size)?.sequence_number();
+	let n_strides = buf.len() / UPDATE_SEQUENCE_STRIDE;
+	if (usa_size as usize) < 1 + n_strides {
+		return None;
+	}
+	for i in 0 .. n_strides
+	{
+		let end = (i + 1) * UPDATE_SEQUENCE_STRIDE - 2;
+		let p = usa_ofs as usize + 2 + i * 2;
+		let orig = [buf[end], buf[end+1]];
+		buf[p..][..2].copy_from_slice(&orig);
+		buf[end..][..2].copy_from_slice(&seq.to_le_bytes());
+	}
+	Some( () )
+}
+
+fn set_u16(buf: &mut [u8], ofs: usize, v: u16) {
+	buf[ofs..][..2].copy_from_slice(&v.to_le_bytes());
+}
+fn set_u32(buf: &mut [u8], ofs: usize, v: u32) {
+	buf[ofs..][..4].copy_from_slice(&v.to_le_bytes());
+}
+fn set_u64(buf: &mut [u8], ofs: usize, v: u64) {
+	buf[ofs..][..8].copy_from_slice(&v.to_le_bytes());
+}
+
 pub struct MftEntry([u8]);
 delegate!{ MftEntry -> MftEntryHeader =>
+	magic: [u8; 4],
 	first_attrib_ofs: u16,
 	flags: u16,
 
 	update_sequence_ofs: u16,
 	update_sequence_size: u16,
+	/// Number of `$FILE_NAME` attributes that are in an index
+	pub hard_link_count: u16,
+	/// Number of bytes used by the record
+	record_size: u32,
+	/// Space available for the record
+	record_space: u32,
 }
 impl MftEntry {
+	const OFS_RECORD_SIZE: usize = 0x18;
+
 	pub fn new_borrowed(v: &[u8]) -> Option<&Self> {
 		if v.len() < ::core::mem::size_of::<raw::MftEntryHeader>() {
 			return None;
@@ -168,6 +242,34 @@ impl MftEntry {
 		UpdateSequence::from_subslice(v, rv.update_sequence_ofs(), rv.update_sequence_size())?;
 		Some(rv)
 	}
+	/// Check a record freshly loaded from disk, and apply its update sequence fixups
+	pub fn fixup(buf: &mut [u8]) -> Option<()> {
+		let (usa_ofs, usa_size) = {
+			let rv = Self::new_borrowed(buf)?;
+			if rv.magic() != *b"FILE" {
+				log_error!("MftEntry::fixup: Bad magic {:?}", ::kernel::lib::byte_str::ByteStr::new(&rv.magic()));
+				return None;
+			}
+			(rv.update_sequence_ofs(), rv.update_sequence_size())
+			};
+		apply_update_sequence(buf, usa_ofs, usa_size)
+	}
+	/// Prepare a copy of this record to be written to disk (bumping the sequence number, and saving each stride's last word)
+	pub fn write_out(&mut self, out: &mut [u8]) -> Option<()> {
+		if out.len() != self.0.len() {
+			return None;
+		}
+		let usa_ofs = self.update_sequence_ofs();
+		let p = usa_ofs as usize;
+		let seq = match u16::from_le_bytes([self.0[p], self.0[p+1]]).wrapping_add(1)
+			{
+			0 | 0xFFFF => 1,
+			v => v,
+			};
+		set_u16(&mut self.0, p, seq);
+		out.copy_from_slice(&self.0);
+		prepare_update_sequence(out, usa_ofs, self.update_sequence_size())
+	}
 
 	pub fn flags_isused(&self) -> bool {
 		self.flags() & 0x1 != 0
@@ -188,19 +290,75 @@ impl MftEntry {
 		let s_e = s_s + self.0.len();
 		assert!(s_s <= a_s && a_s < s_e);
 		assert!(s_s <= a_e && a_e <= s_e);
-		AttrHandle(a_s - s_s, a.0.len())
+		AttrHandle(a_s - s_s, a.ty())
 	}
 
 	pub fn get_attr(&self, handle: &AttrHandle) -> Option<&MftAttrib> {
-		MftAttrib::new_borrowed(self.0.get(handle.0..)?.get(..handle.1)?)
+		let v = self.0.get(handle.0..)?;
+		let size = u32::from_le_bytes(v.get(4..8)?.try_into().unwrap());
+		let rv = MftAttrib::new_borrowed(v.get(..size as usize)?)?;
+		if rv.ty() != handle.1 {
+			return None;
+		}
+		Some(rv)
 	}
-
-	pub fn update_sequence(&self) -> &UpdateSequence {
-		UpdateSequence::from_subslice(&self.0, self.update_sequence_ofs(), self.update_sequence_size()).unwrap()
+	pub fn get_attr_mut(&mut self, handle: &AttrHandle) -> Option<&mut MftAttrib> {
+		let len = self.get_attr(handle)?.0.len();
+		// SAFE: Same repr, and has been checked by `get_attr`
+		Some(unsafe { ::core::mem::transmute(&mut self.0[handle.0..][..len]) })
+	}
+
+	/// Change the size of an attribute, moving the attributes that follow it
+	///
+	/// NOTE: This invalidates handles to the following attributes
+	pub fn resize_attr(&mut self, handle: &AttrHandle, new_size: usize) -> Option<()> {
+		assert!(new_size % 8 == 0, "resize_attr: Unaligned size {}", new_size);
+		let old_size = self.get_attr(handle)?.0.len();
+		let in_use = self.record_size() as usize;
+		let space = usize::min(self.record_space() as usize, self.0.len());
+		let old_end = handle.0 + old_size;
+		let new_end = handle.0 + new_size;
+		if in_use < old_end || in_use > space {
+			return None;
+		}
+		let new_in_use = in_use - old_size + new_size;
+		if new_in_use > space {
+			return None;
+		}
+		self.0.copy_within(old_end .. in_use, new_end);
+		if new_end > old_end {
+			self.0[old_end .. new_end].fill(0);
+		}
+		else {
+			self.0[new_in_use .. in_use].fill(0);
+		}
+		set_u32(&mut self.0, handle.0 + 4, new_size as u32);
+		set_u32(&mut self.0, Self::OFS_RECORD_SIZE, new_in_use as u32);
+		Some( () )
+	}
+	/// Replace an attribute with a new one (of the same type)
+	pub fn replace_attr(&mut self, handle: &AttrHandle, data: &[u8]) -> Option<()> {
+		self.resize_attr(handle, data.len())?;
+		self.0[handle.0..][..data.len()].copy_from_slice(data);
+		Some( () )
+	}
+	/// Change the length of a resident attribute's data (new space is zeroed)
+	pub fn resize_resident(&mut self, handle: &AttrHandle, new_len: usize) -> Option<()> {
+		let (data_ofs, old_len) = {
+			let r = self.get_attr(handle)?.inner().as_resident()?;
+			(r.attrib_ofs() as usize, r.attrib_len() as usize)
+			};
+		self.resize_attr(handle, (data_ofs + new_len + 7) & !7)?;
+		let a = &mut self.0[handle.0..];
+		if new_len > old_len {
+			a[data_ofs + old_len .. data_ofs + new_len].fill(0);
+		}
+		set_u32(a, MftAttrib::size_of() + MftAttrHeader_Resident::OFS_ATTRIB_LEN, new_len as u32);
+		Some( () )
 	}
 }
-/// Saved handle (offset+size) to an attribute
-pub struct AttrHandle(usize, usize);
+/// Saved handle (offset and type) to an attribute, the size is re-read (so this stays valid when the attribute is resized)
+pub struct AttrHandle(usize, u32);
 
 /// Iterator over attributes in a MFT entry
 struct MftEntryAttribs<'a>(&'a [u8]);
@@ -277,6 +435,22 @@ impl MftAttrib {
 		let l = self.name_length() as usize * 2;	// Number of u16s
 		Utf16Le::new(&self.0[o..][..l])
 	}
+	fn name_bytes(&self) -> &[u8] {
+		let o = self.name_ofs() as usize;
+		let l = self.name_length() as usize * 2;
+		&self.0[o..][..l]
+	}
+
+	/// Data is LZNT1 compressed (in units of `compression_unit_size` clusters)
+	pub fn flags_iscompressed(&self) -> bool {
+		self.flags() & 0x00FF != 0
+	}
+	pub fn flags_isencrypted(&self) -> bool {
+		self.flags() & 0x4000 != 0
+	}
+	pub fn flags_issparse(&self) -> bool {
+		self.flags() & 0x8000 != 0
+	}
 
 	fn raw_data(&self) -> &[u8] {
 		&self.0[ Self::size_of() .. ]
@@ -290,11 +464,58 @@ impl MftAttrib {
 			MftAttribData::Resident(MftAttrHeader_Resident::from_slice(self.raw_data()).unwrap())
 		}
 	}
+	pub fn inner_mut(&mut self) -> MftAttribDataMut<'_> {
+		let is_nonresident = self.nonresident_flag() != 0;
+		let d = &mut self.0[Self::size_of()..];
+		// SAFE: Same repr, and the header was checked by `new_borrowed`
+		unsafe {
+			if is_nonresident {
+				MftAttribDataMut::Nonresident(::core::mem::transmute(d))
+			}
+			else {
+				MftAttribDataMut::Resident(::core::mem::transmute(d))
+			}
+		}
+	}
+
+	/// Build an uncompressed non-resident version of this attribute (with the same type, name, flags, and ID)
+	pub fn build_nonresident(&self, runs: &[DataRun], allocated_size: u64, real_size: u64, initialised_size: u64) -> Vec<u8> {
+		const HDR_SIZE: usize = 0x40;
+		let name = self.name_bytes();
+		let data_run_ofs = (HDR_SIZE + name.len() + 7) & !7;
+		let mut rv = vec![0; data_run_ofs];
+		encode_data_runs(runs, &mut rv);
+		rv.resize((rv.len() + 7) & !7, 0);
+
+		let cluster_count: u64 = runs.iter().map(|r| r.cluster_count).sum();
+		let size = rv.len() as u32;
+		set_u32(&mut rv, 0, self.ty());
+		set_u32(&mut rv, 4, size);
+		rv[8] = 1;
+		rv[9] = self.name_length();
+		set_u16(&mut rv, 10, HDR_SIZE as u16);
+		set_u16(&mut rv, 12, self.flags());
+		set_u16(&mut rv, 14, self.attribute_id());
+		let nr = &mut rv[Self::size_of()..];
+		// - Starting VCN is zero
+		set_u64(nr, MftAttrHeader_NonResident::OFS_LAST_VCN, cluster_count.wrapping_sub(1));
+		set_u16(nr, MftAttrHeader_NonResident::OFS_DATA_RUN_OFS, data_run_ofs as u16);
+		// - Compression unit is zero
+		set_u64(nr, MftAttrHeader_NonResident::OFS_ALLOCATED_SIZE, allocated_size);
+		set_u64(nr, MftAttrHeader_NonResident::OFS_REAL_SIZE, real_size);
+		set_u64(nr, MftAttrHeader_NonResident::OFS_INITIATED_SIZE, initialised_size);
+		rv[HDR_SIZE..][..name.len()].copy_from_slice(name);
+		rv
+	}
 }
 pub enum MftAttribData<'a> {
 	Nonresident(&'a MftAttrHeader_NonResident),
 	Resident(&'a MftAttrHeader_Resident),
 }
+pub enum MftAttribDataMut<'a> {
+	Nonresident(&'a mut MftAttrHeader_NonResident),
+	Resident(&'a mut MftAttrHeader_Resident),
+}
 impl<'a> MftAttribData<'a> {
 	pub fn as_resident(&self) -> Option<&'a MftAttrHeader_Resident> {
 		match *self {
@@ -314,10 +535,15 @@ delegate!{ MftAttrHeader_NonResident =>
 	pub allocated_size: u64,
 	/// Size of the user-facing data (bytes)
 	pub real_size: u64,
-	/// Size of data on-disk (taking into account compression)
+	/// Size of the initialised data (bytes), anything after this reads as zero
 	pub initiated_size: u64,
 }
 impl MftAttrHeader_NonResident {
+	const OFS_LAST_VCN: usize = 8;
+	const OFS_DATA_RUN_OFS: usize = 16;
+	const OFS_ALLOCATED_SIZE: usize = 24;
+	const OFS_REAL_SIZE: usize = 32;
+	const OFS_INITIATED_SIZE: usize = 40;
 	fn size_of() -> usize {
 		::core::mem::size_of::<raw::MftAttrHeader_NonResident>()
 	}
@@ -343,6 +569,40 @@ impl MftAttrHeader_NonResident {
 		let Some(ofs) = ofs.checked_sub(4*4) else { return DataRunsIt(&[], 0); };
 		DataRunsIt(&self.0[ofs..], 0)
 	}
+
+	pub fn set_real_size(&mut self, v: u64) {
+		set_u64(&mut self.0, Self::OFS_REAL_SIZE, v);
+	}
+	pub fn set_initiated_size(&mut self, v: u64) {
+		set_u64(&mut self.0, Self::OFS_INITIATED_SIZE, v);
+	}
+}
+
+/// Encode a list of data runs (the inverse of `DataRunsIt`), including the terminator
+pub fn encode_data_runs(runs: &[DataRun], out: &mut Vec<u8>) {
+	// Number of bytes needed to store a value as a signed integer
+	fn signed_len(v: i64) -> usize {
+		(1 ..= 8).find(|&n| n == 8 || { let bits = n * 8 - 1; v >= -(1 << bits) && v < (1 << bits) }).unwrap()
+	}
+	let mut prev_lcn = 0;
+	for r in runs
+	{
+		// The count is unsigned, but is encoded like a signed value
+		let len_len = signed_len(r.cluster_count as i64);
+		let (ofs, ofs_len) = match r.lcn
+			{
+			Some(lcn) => {
+				let ofs = lcn.wrapping_sub(prev_lcn) as i64;
+				prev_lcn = lcn;
+				(ofs, signed_len(ofs))
+				},
+			None => (0, 0),
+			};
+		out.push( (ofs_len << 4 | len_len) as u8 );
+		out.extend_from_slice(&r.cluster_count.to_le_bytes()[..len_len]);
+		out.extend_from_slice(&ofs.to_le_bytes()[..ofs_len]);
+	}
+	out.push(0);
 }
 #[derive(Clone)]
 pub struct DataRunsIt<'a>(&'a [u8], u64);
@@ -421,6 +681,7 @@ delegate!{ MftAttrHeader_Resident =>
 	indexed_flag: u8,
 }
 impl MftAttrHeader_Resident {
+	const OFS_ATTRIB_LEN: usize = 0;
 	fn size_of() -> usize {
 		::core::mem::size_of::<raw::MftAttrHeader_Resident>()
 	}
@@ -458,6 +719,120 @@ impl MftAttrHeader_Resident {
 		let len = self.attrib_len() as usize;
 		&self.0[ofs..][..len]
 	}
+	pub fn data_mut(&mut self) -> &mut [u8] {
+		let ofs = self.adj_attrib_ofs();
+		let len = self.attrib_len() as usize;
+		&mut self.0[ofs..][..len]
+	}
+}
+
+/// `$VOLUME_INFORMATION` attribute (on `$Volume`)
+pub struct Attrib_VolumeInformation([u8]);
+delegate!{ Attrib_VolumeInformation =>
+	pub major_version: u8,
+	pub minor_version: u8,
+	pub flags: u16,
+}
+impl Attrib_VolumeInformation {
+	/// Volume is marked as dirty (`chkdsk` will run on next boot)
+	pub const FLAG_DIRTY: u16 = 0x0001;
+	const OFS_FLAGS: usize = 10;
+	pub fn from_slice(v: &[u8]) -> Option<&Self> {
+		if v.len() < ::core::mem::size_of::<raw::Attrib_VolumeInformation>() {
+			return None;
+		}
+		// SAFE: Same repr
+		Some(unsafe { ::core::mem::transmute(v) })
+	}
+	pub fn from_slice_mut(v: &mut [u8]) -> Option<&mut Self> {
+		Self::from_slice(v)?;
+		// SAFE: Same repr
+		Some(unsafe { ::core::mem::transmute(v) })
+	}
+	pub fn set_flags(&mut self, v: u16) {
+		set_u16(&mut self.0, Self::OFS_FLAGS, v);
+	}
+}
+
+/// `$STANDARD_INFORMATION` attribute (present on every file)
+pub struct Attrib_StandardInformation([u8]);
+delegate!{ Attrib_StandardInformation =>
+	pub creation_time: u64,
+	pub modification_time: u64,
+	pub mft_modification_time: u64,
+	pub access_time: u64,
+	pub file_attributes: u32,
+}
+impl Attrib_StandardInformation {
+	pub const FILE_ATTRIBUTE_READONLY: u32 = 0x0001;
+	const OFS_MODIFICATION_TIME: usize = 8;
+	const OFS_ACCESS_TIME: usize = 24;
+	const OFS_FILE_ATTRIBUTES: usize = 32;
+	pub fn from_slice(v: &[u8]) -> Option<&Self> {
+		if v.len() < ::core::mem::size_of::<raw::Attrib_StandardInformation>() {
+			return None;
+		}
+		// SAFE: Same repr
+		Some(unsafe { ::core::mem::transmute(v) })
+	}
+	pub fn from_slice_mut(v: &mut [u8]) -> Option<&mut Self> {
+		Self::from_slice(v)?;
+		// SAFE: Same repr
+		Some(unsafe { ::core::mem::transmute(v) })
+	}
+	pub fn set_modification_time(&mut self, v: u64) {
+		set_u64(&mut self.0, Self::OFS_MODIFICATION_TIME, v);
+	}
+	pub fn set_access_time(&mut self, v: u64) {
+		set_u64(&mut self.0, Self::OFS_ACCESS_TIME, v);
+	}
+	pub fn set_file_attributes(&mut self, v: u32) {
+		set_u32(&mut self.0, Self::OFS_FILE_ATTRIBUTES, v);
+	}
+}
+
+/// Convert a NTFS time (100ns units since 1601) to a VFS timestamp (`None` for zero)
+pub fn time_to_vfs(v: u64) -> Option<::vfs::node::Timestamp> {
+	if v == 0 {
+		return None;
+	}
+	Some(::vfs::node::Timestamp {
+		secs: (v / 10_000_000) as i64 - EPOCH_OFFSET_SECS,
+		nsecs: (v % 10_000_000) as u32 * 100,
+	})
+}
+/// Convert a VFS timestamp into a NTFS time
+pub fn time_from_vfs(v: ::vfs::node::Timestamp) -> u64 {
+	let secs = i64::max(0, v.secs + EPOCH_OFFSET_SECS) as u64;
+	secs * 10_000_000 + (v.nsecs / 100) as u64
+}
+/// Seconds between 1601-01-01 and 1970-01-01
+const EPOCH_OFFSET_SECS: i64 = 11_644_473_600;
+
+/// Check if the restart page at the start of `$LogFile` indicates that the volume was cleanly unmounted
+///
+/// If not, Windows will replay the log (or resume from hibernation) when it next mounts the volume.
+pub fn logfile_is_clean(buf: &mut [u8]) -> Option<bool> {
+	// `$LogFile` is emptied (filled with 0xFF) when formatted, and by some other drivers when mounting
+	if buf.iter().all(|&v| v == 0xFF) {
+		return Some(true);
+	}
+	let magic = raw::LogFileRestartPage::magic(buf).ok()?;
+	if magic != *b"RSTR" {
+		// Could also be "CHKD" (modified by chkdsk)
+		log_notice!("logfile_is_clean: Unexpected restart page magic {:?}", ::kernel::lib::byte_str::ByteStr::new(&magic));
+		return Some(false);
+	}
+	let page_size = raw::LogFileRestartPage::system_page_size(buf).ok()? as usize;
+	let usa_ofs = raw::LogFileRestartPage::update_sequence_ofs(buf).ok()?;
+	let usa_size = raw::LogFileRestartPage::update_sequence_size(buf).ok()?;
+	let buf = &mut buf[..usize::min(page_size, buf.len())];
+	apply_update_sequence(buf, usa_ofs, usa_size)?;
+	let area = buf.get(raw::LogFileRestartPage::restart_area_offset(buf).ok()? as usize ..)?;
+	let in_use = raw::LogFileRestartArea::client_in_use_list(area).ok()?;
+	let flags = raw::LogFileRestartArea::flags(area).ok()?;
+	// Clean if there are no clients with outstanding log records, or if the volume is explicitly flagged as clean
+	Some(in_use == 0xFFFF || flags & 0x0002 != 0)
 }
 
 pub struct Attrib_IndexRoot([u8]);
@@ -523,9 +898,18 @@ impl Attrib_IndexBlockHeader {
 		if !(rv.magic() == 0x58_44_4e_49) {
 			return None;
 		}
+		UpdateSequence::from_subslice(v, rv.update_sequence_ofs(), rv.update_sequence_size())?;
 		Attrib_IndexHeader::from_slice(&rv.index_header_bytes())?;
 		Some(rv)
 	}
+	/// Check a block freshly loaded from disk, and apply its update sequence fixups
+	pub fn fixup(buf: &mut [u8]) -> Option<()> {
+		let (usa_ofs, usa_size) = {
+			let rv = Self::from_slice(buf)?;
+			(rv.update_sequence_ofs(), rv.update_sequence_size())
+			};
+		apply_update_sequence(buf, usa_ofs, usa_size)
+	}
 
 	fn index_header_bytes(&self) -> &[u8] {
 		&self.0[0x18..]
@@ -533,10 +917,6 @@ impl Attrib_IndexBlockHeader {
 	pub fn index_header(&self) -> &Attrib_IndexHeader {
 		Attrib_IndexHeader::from_slice(self.index_header_bytes()).unwrap()
 	}
-	// TODO: the update sequence handles spotting badly-written sectors
-	pub fn update_sequence(&self) -> &UpdateSequence {
-		UpdateSequence::from_subslice(&self.0, self.update_sequence_ofs(), self.update_sequence_size()).unwrap()
-	}
 }
 delegate!{ Attrib_IndexBlockHeader =>
 	magic: u32,
@@ -174,7 +174,7 @@ pub struct MftAttrHeader_NonResident {
 	allocated_size: u64,
 	/// User-facing byte count
 	real_size: u64,
-	/// Size of the data, after compression
+	/// Byte count that has been written (the rest reads as zero)
 	initiated_size: u64,
 	// name: [u16],
 }
@@ -248,7 +248,7 @@ pub struct Attrib_IndexBlockHeader {
 	/// 'INDX' as little endian
 	magic: u32,
 
-	/// Offset of the "Update Sequence" (todo)
+	/// Offset of the "Update Sequence"
 	update_sequence_ofs: u16,
 	/// Size of the update sequence (word count)
 	update_sequence_size: u16,
@@ -272,3 +272,54 @@ pub struct Attrib_IndexEntry {
 	index_flags: u16,
 	_resvd: u16,
 }
+
+/// `$STANDARD_INFORMATION` (only the fields common to all versions)
+#[derive(::kernel_derives::FieldsLE)]
+#[repr(C)]
+pub struct Attrib_StandardInformation {
+	/// Times are in 100ns units since 1601-01-01 (UTC)
+	creation_time: u64,
+	modification_time: u64,
+	mft_modification_time: u64,
+	access_time: u64,
+	/// DOS-style attributes (`FILE_ATTRIBUTE_*`)
+	file_attributes: u32,
+}
+
+#[derive(::kernel_derives::FieldsLE)]
+#[repr(C)]
+pub struct Attrib_VolumeInformation {
+	_reserved: u64,
+	major_version: u8,
+	minor_version: u8,
+	/// [0]: Dirty
+	flags: u16,
+}
+
+/// Header of a `$LogFile` restart page
+#[derive(::kernel_derives::FieldsLE)]
+#[repr(C)]
+pub struct LogFileRestartPage {
+	/// 'RSTR' (or 'CHKD')
+	magic: [u8; 4],
+	update_sequence_ofs: u16,
+	update_sequence_size: u16,
+	chkdsk_lsn: u64,
+	system_page_size: u32,
+	log_page_size: u32,
+	/// Offset of the restart area (relative to the page)
+	restart_area_offset: u16,
+	minor_version: u16,
+	major_version: u16,
+}
+#[derive(::kernel_derives::FieldsLE)]
+#[repr(C)]
+pub struct LogFileRestartArea {
+	current_lsn: u64,
+	log_clients: u16,
+	client_free_list: u16,
+	/// 0xFFFF if there are no clients in use
+	client_in_use_list: u16,
+	/// [1]: Volume is clean
+	flags: u16,
+}
@@ -0,0 +1,569 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/dhcp.rs
+//! Dynamic Host Configuration Protocol client (IPv4, RFC 2131)
+//!
+//! A client is started for each NIC that is registered without a statically configured address. It obtains an
+//! address (and mask, router, and DNS servers), installs it as an IPv4 interface and default route, and keeps
+//! the lease renewed until the NIC is removed.
+use kernel::prelude::*;
+use kernel::sync::Mutex;
+use kernel::lib::mem::Arc;
+use shared_map::SharedMap;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::nic::{MacAddr, SparsePacket, PacketReader};
+use crate::ipv4::Address;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OP_BOOTREQUEST: u8 = 1;
+const OP_BOOTREPLY: u8 = 2;
+
+const MSG_DISCOVER: u8 = 1;
+const MSG_OFFER: u8 = 2;
+const MSG_REQUEST: u8 = 3;
+const MSG_ACK: u8 = 5;
+const MSG_NAK: u8 = 6;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS_SERVERS: u8 = 6;
+const OPT_REQUESTED_ADDRESS: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETER_LIST: u8 = 55;
+const OPT_RENEWAL_TIME: u8 = 58;
+const OPT_REBINDING_TIME: u8 = 59;
+const OPT_END: u8 = 255;
+
+/// Lease time value indicating that the lease never expires
+const INFINITE_LEASE: u32 = 0xFFFF_FFFF;
+/// Initial retransmission timeout (doubled after each attempt)
+const INITIAL_TIMEOUT_MS: u64 = 4_000;
+/// Upper limit on the retransmission timeout
+const MAX_TIMEOUT_MS: u64 = 64_000;
+/// Minimum retransmission interval while renewing/rebinding
+const MIN_RENEW_INTERVAL_MS: u64 = 60_000;
+/// Number of REQUEST attempts before returning to discovery
+const REQUEST_ATTEMPTS: usize = 4;
+/// Limit on the number of unprocessed replies held for a client
+const INBOX_LIMIT: usize = 8;
+/// Minimum size of a BOOTP message (some servers drop smaller messages)
+const MIN_MESSAGE_SIZE: usize = 300;
+
+static CLIENTS: SharedMap<MacAddr, Arc<Client>> = SharedMap::new();
+/// Poked when a client receives a message or is stopped
+static CLIENTS_CV: ::kernel::futures::Condvar = ::kernel::futures::Condvar::new();
+
+struct Client
+{
+	inbox: Mutex<Vec<Vec<u8>>>,
+	lease: Mutex<Option<Lease>>,
+	stop: AtomicBool,
+}
+
+/// Configuration obtained from a DHCP server
+#[derive(Clone,Debug)]
+pub struct Lease
+{
+	pub address: Address,
+	pub mask_bits: u8,
+	pub router: Option<Address>,
+	pub dns_servers: Vec<Address>,
+	/// Address of the server that granted the lease
+	pub server: Address,
+	/// Lease duration in seconds (`None` for an infinite lease)
+	pub lease_time: Option<u32>,
+	renewal_time: u32,
+	rebinding_time: u32,
+	/// Time (in ticks) that the lease was obtained
+	obtained: ::kernel::time::TickCount,
+}
+
+/// Start a client on the specified NIC
+pub fn start(mac: MacAddr)
+{
+	let client = Arc::new(Client {
+		inbox: Mutex::new(Vec::new()),
+		lease: Mutex::new(None),
+		stop: AtomicBool::new(false),
+		});
+	if CLIENTS.insert(mac, client.clone()).is_err() {
+		log_warning!("DHCP {:x?}: Client already running", mac);
+		return ;
+	}
+	log_notice!("DHCP {:x?}: Starting client", mac);
+	::core::mem::forget(::kernel::threads::WorkerThread::new("DHCP Client", move || worker(mac, client)));
+}
+/// Stop the client on the specified NIC (releasing its address)
+pub fn stop(mac: MacAddr)
+{
+	if let Some(client) = CLIENTS.take(&mac)
+	{
+		client.stop.store(true, Ordering::SeqCst);
+		CLIENTS_CV.wake_all();
+	}
+}
+/// Get the current lease for the specified NIC
+pub fn get_lease(mac: MacAddr) -> Option<Lease>
+{
+	CLIENTS.get(&mac)?.lease.lock().clone()
+}
+/// Get the DNS servers provided by all current leases
+pub fn dns_servers() -> Vec<Address>
+{
+	let mut rv = Vec::new();
+	for (_, client) in CLIENTS.iter()
+	{
+		if let Some(ref lease) = *client.lease.lock()
+		{
+			for a in lease.dns_servers.iter()
+			{
+				if !rv.contains(a) {
+					rv.push(*a);
+				}
+			}
+		}
+	}
+	rv
+}
+
+/// Check an incoming (unfragmented) UDP datagram for a DHCP reply, returning `true` if it was consumed
+///
+/// Called before the destination address is checked, as replies can be sent before the interface is configured.
+pub fn handle_rx(local_mac: MacAddr, source: Address, dest: Address, mut reader: PacketReader) -> bool
+{
+	// Quick check of the destination port before looking up the client
+	match reader.clone().read_bytes([0; 4])
+	{
+	Ok(b) if u16::from_be_bytes([b[2], b[3]]) == CLIENT_PORT => {},
+	_ => return false,
+	}
+	let client = match CLIENTS.get(&local_mac)
+		{
+		Some(c) => c,
+		None => return false,
+		};
+	match crate::udp::read_ports(crate::Address::Ipv4(source), crate::Address::Ipv4(dest), &mut reader)
+	{
+	Some( (SERVER_PORT, CLIENT_PORT) ) => {},
+	Some(_) => return false,
+	None => return true,
+	}
+	let mut data = vec![0; reader.remain()];
+	reader.read(&mut data).unwrap();
+	{
+		let mut lh = client.inbox.lock();
+		if lh.len() >= INBOX_LIMIT {
+			log_notice!("DHCP {:x?}: Inbox full, dropping message from {:?}", local_mac, source);
+			return true;
+		}
+		lh.push(data);
+	}
+	CLIENTS_CV.wake_all();
+	true
+}
+
+/// Returned when the client has been asked to stop
+struct Stopped;
+
+enum Reply
+{
+	Ack(Message),
+	Nak,
+	Timeout,
+}
+
+fn worker(mac: MacAddr, client: Arc<Client>)
+{
+	let mut w = Worker {
+		mac,
+		xid: ::kernel::rand::get_u32(),
+		client,
+		bound: None,
+		};
+	let _ = w.run();
+	w.unbind();
+	log_notice!("DHCP {:x?}: Stopped", mac);
+}
+
+struct Worker
+{
+	mac: MacAddr,
+	xid: u32,
+	client: Arc<Client>,
+	/// Currently configured lease, and if the default route was added by this client
+	bound: Option<(Lease, bool)>,
+}
+impl Worker
+{
+	fn run(&mut self) -> Result<(), Stopped>
+	{
+		loop
+		{
+			// INIT/SELECTING: Broadcast a DISCOVER until an OFFER is received
+			self.new_transaction();
+			let offer = self.discover()?;
+			let server = offer.server_id.unwrap();
+			log_debug!("DHCP {:x?}: Offered {:?} by {:?}", self.mac, offer.yiaddr, server);
+
+			// REQUESTING: Request the offered address
+			let ack = match self.request_offered(offer.yiaddr, server)?
+				{
+				Reply::Ack(m) => m,
+				Reply::Nak => { log_notice!("DHCP {:x?}: Request for {:?} refused", self.mac, offer.yiaddr); continue },
+				Reply::Timeout => { log_notice!("DHCP {:x?}: No reply to request for {:?}", self.mac, offer.yiaddr); continue },
+				};
+			self.bind(Lease::from_message(&ack, server));
+
+			// BOUND/RENEWING/REBINDING: Keep the lease until it expires or is refused
+			self.maintain()?;
+			self.unbind();
+		}
+	}
+
+	/// Renew the current lease until it's lost
+	fn maintain(&mut self) -> Result<(), Stopped>
+	{
+		loop
+		{
+			let lease = self.bound.as_ref().expect("maintain - Not bound").0.clone();
+			let lease_time = match lease.lease_time
+				{
+				Some(v) => v,
+				None => {
+					// Infinite lease, just wait until stopped
+					return self.wait_reply(None, |_| false).map(|_| ());
+					},
+				};
+			let t1 = lease.obtained + lease.renewal_time as u64 * 1000;
+			let t2 = lease.obtained + lease.rebinding_time as u64 * 1000;
+			let expiry = lease.obtained + lease_time as u64 * 1000;
+			self.wait_reply(Some(t1), |_| false)?;
+
+			// RENEWING: Unicast to the server that granted the lease
+			log_debug!("DHCP {:x?}: Renewing {:?}", self.mac, lease.address);
+			self.new_transaction();
+			let reply = match self.renew(&lease, Some(lease.server), t2)?
+				{
+				Reply::Timeout => {
+					// REBINDING: Broadcast to any server
+					log_debug!("DHCP {:x?}: Rebinding {:?}", self.mac, lease.address);
+					self.renew(&lease, None, expiry)?
+					},
+				r => r,
+				};
+			match reply
+			{
+			Reply::Ack(m) => {
+				let server = m.server_id.unwrap_or(lease.server);
+				self.bind(Lease::from_message(&m, server));
+				},
+			Reply::Nak => {
+				log_notice!("DHCP {:x?}: Lease on {:?} refused", self.mac, lease.address);
+				return Ok( () );
+				},
+			Reply::Timeout => {
+				log_notice!("DHCP {:x?}: Lease on {:?} expired", self.mac, lease.address);
+				return Ok( () );
+				},
+			}
+		}
+	}
+
+	fn discover(&mut self) -> Result<Message, Stopped>
+	{
+		let mut timeout = INITIAL_TIMEOUT_MS;
+		loop
+		{
+			self.send(MSG_DISCOVER, Address::zero(), None, &[]);
+			let deadline = ::kernel::time::ticks() + timeout;
+			let r = self.wait_reply(Some(deadline), |m| m.msg_type == Some(MSG_OFFER) && m.server_id.is_some() && !m.yiaddr.is_zero())?;
+			if let Some(m) = r {
+				return Ok(m);
+			}
+			timeout = u64::min(timeout * 2, MAX_TIMEOUT_MS);
+		}
+	}
+	fn request_offered(&mut self, addr: Address, server: Address) -> Result<Reply, Stopped>
+	{
+		let mut timeout = INITIAL_TIMEOUT_MS;
+		for _ in 0 .. REQUEST_ATTEMPTS
+		{
+			self.send(MSG_REQUEST, Address::zero(), None, &[ (OPT_REQUESTED_ADDRESS, &addr.0), (OPT_SERVER_ID, &server.0) ]);
+			let deadline = ::kernel::time::ticks() + timeout;
+			let r = self.wait_reply(Some(deadline), |m| m.msg_type == Some(MSG_NAK) || (m.msg_type == Some(MSG_ACK) && m.yiaddr == addr))?;
+			match r
+			{
+			Some(m) if m.msg_type == Some(MSG_ACK) => return Ok(Reply::Ack(m)),
+			Some(_) => return Ok(Reply::Nak),
+			None => {},
+			}
+			timeout = u64::min(timeout * 2, MAX_TIMEOUT_MS);
+		}
+		Ok(Reply::Timeout)
+	}
+	/// Request an extension of the current lease (unicast to `server` if known, otherwise broadcast), giving up at `deadline`
+	fn renew(&mut self, lease: &Lease, server: Option<Address>, deadline: ::kernel::time::TickCount) -> Result<Reply, Stopped>
+	{
+		loop
+		{
+			let now = ::kernel::time::ticks();
+			if now >= deadline {
+				return Ok(Reply::Timeout);
+			}
+			self.send(MSG_REQUEST, lease.address, server, &[]);
+			// Retransmit after half of the remaining time (RFC 2131 4.4.5)
+			let wait = u64::max((deadline - now) / 2, MIN_RENEW_INTERVAL_MS);
+			let r = self.wait_reply(Some(u64::min(now + wait, deadline)), |m| m.msg_type == Some(MSG_NAK) || (m.msg_type == Some(MSG_ACK) && m.yiaddr == lease.address))?;
+			match r
+			{
+			Some(m) if m.msg_type == Some(MSG_ACK) => return Ok(Reply::Ack(m)),
+			Some(_) => return Ok(Reply::Nak),
+			None => {},
+			}
+		}
+	}
+
+	fn new_transaction(&mut self)
+	{
+		self.xid = ::kernel::rand::get_u32();
+	}
+
+	/// Wait (until `deadline`, or forever) for a reply to the current transaction that matches `filter`
+	fn wait_reply(&self, deadline: Option<::kernel::time::TickCount>, filter: impl Fn(&Message)->bool) -> Result<Option<Message>, Stopped>
+	{
+		loop
+		{
+			// Get the key before checking, so a message pushed after the check wakes the wait
+			let key = CLIENTS_CV.get_key();
+			if self.client.stop.load(Ordering::SeqCst) {
+				return Err(Stopped);
+			}
+			let messages = ::core::mem::replace(&mut *self.client.inbox.lock(), Vec::new());
+			for m in messages.iter().filter_map(|d| Message::parse(d))
+			{
+				if m.op != OP_BOOTREPLY || m.xid != self.xid || m.chaddr != self.mac {
+					continue ;
+				}
+				if filter(&m) {
+					return Ok(Some(m));
+				}
+				log_debug!("DHCP {:x?}: Ignoring message type {:?}", self.mac, m.msg_type);
+			}
+
+			match deadline
+			{
+			Some(deadline) => {
+				let now = ::kernel::time::ticks();
+				if now >= deadline {
+					return Ok(None);
+				}
+				::kernel::futures::block_on(::kernel::futures::join_one(
+					CLIENTS_CV.wait(key),
+					::kernel::futures::msleep( (deadline - now) as usize )
+					));
+				},
+			None => ::kernel::futures::block_on(CLIENTS_CV.wait(key)),
+			}
+		}
+	}
+
+	/// Send a message from `ciaddr` to `server` (or broadcast)
+	fn send(&self, msg_type: u8, ciaddr: Address, server: Option<Address>, options: &[(u8, &[u8])])
+	{
+		let mut data = vec![0u8; 240];
+		data[0] = OP_BOOTREQUEST;
+		data[1] = 1;	// Hardware type: Ethernet
+		data[2] = 6;	// Hardware address length
+		data[4..8].copy_from_slice(&self.xid.to_be_bytes());
+		// Request broadcast replies until the address is configured
+		if ciaddr.is_zero() {
+			data[10] = 0x80;
+		}
+		data[12..16].copy_from_slice(&ciaddr.0);
+		data[28..34].copy_from_slice(&self.mac);
+		data[236..240].copy_from_slice(&MAGIC_COOKIE);
+		data.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, msg_type]);
+		data.extend_from_slice(&[OPT_PARAMETER_LIST, 6, OPT_SUBNET_MASK, OPT_ROUTER, OPT_DNS_SERVERS, OPT_LEASE_TIME, OPT_RENEWAL_TIME, OPT_REBINDING_TIME]);
+		for &(code, value) in options
+		{
+			data.push(code);
+			data.push(value.len() as u8);
+			data.extend_from_slice(value);
+		}
+		data.push(OPT_END);
+		if data.len() < MIN_MESSAGE_SIZE {
+			data.resize(MIN_MESSAGE_SIZE, OPT_PAD);
+		}
+
+		let dest = server.unwrap_or(Address::new(255,255,255,255));
+		let hdr = crate::udp::encode_header((crate::Address::Ipv4(ciaddr), CLIENT_PORT), (crate::Address::Ipv4(dest), SERVER_PORT), &data);
+		let data_pkt = SparsePacket::new_root(&data);
+		let pkt = SparsePacket::new_chained(&hdr, &data_pkt);
+		match server
+		{
+		Some(server) => ::kernel::futures::block_on(crate::ipv4::send_packet(ciaddr, server, crate::udp::IPV4_PROTO_UDP, pkt)),
+		None => crate::ipv4::send_broadcast(self.mac, ciaddr, crate::udp::IPV4_PROTO_UDP, pkt),
+		}
+	}
+
+	/// Configure the interface (and default route) for a new or renewed lease
+	fn bind(&mut self, lease: Lease)
+	{
+		let own_route = match self.bound.take()
+			{
+			Some((old, own_route)) if old.address == lease.address && old.mask_bits == lease.mask_bits && old.router == lease.router => own_route,
+			Some(old) => {
+				self.bound = Some(old);
+				self.unbind();
+				self.configure(&lease)
+				},
+			None => self.configure(&lease),
+			};
+		log_notice!("DHCP {:x?}: Bound to {:?}/{} (router {:?}, DNS {:?}) for {:?}s",
+			self.mac, lease.address, lease.mask_bits, lease.router, lease.dns_servers, lease.lease_time);
+		*self.client.lease.lock() = Some(lease.clone());
+		self.bound = Some( (lease, own_route) );
+	}
+	fn configure(&self, lease: &Lease) -> bool
+	{
+		crate::ipv4::add_interface(self.mac, lease.address, lease.mask_bits);
+		match lease.router
+		{
+		Some(router) => match crate::ipv4::add_route(crate::ipv4::Route { network: Address::zero(), mask: 0, next_hop: router })
+			{
+			Ok(()) => true,
+			Err(e) => {
+				log_notice!("DHCP {:x?}: Not adding default route via {:?}: {:?}", self.mac, router, e);
+				false
+				},
+			},
+		None => false,
+		}
+	}
+	/// Remove the configuration for the current lease (if any)
+	fn unbind(&mut self)
+	{
+		if let Some((lease, own_route)) = self.bound.take()
+		{
+			log_notice!("DHCP {:x?}: Releasing {:?}", self.mac, lease.address);
+			if own_route {
+				let _ = crate::ipv4::del_route(Address::zero(), 0);
+			}
+			crate::ipv4::remove_interface(self.mac, lease.address);
+			*self.client.lease.lock() = None;
+		}
+	}
+}
+
+impl Lease
+{
+	fn from_message(m: &Message, server: Address) -> Lease
+	{
+		let lease_time = match m.lease_time
+			{
+			Some(INFINITE_LEASE) => None,
+			Some(v) => Some(v),
+			None => {
+				log_warning!("DHCP: No lease time in ACK from {:?}, assuming infinite", server);
+				None
+				},
+			};
+		let t = lease_time.unwrap_or(0);
+		Lease {
+			address: m.yiaddr,
+			mask_bits: match m.mask
+				{
+				Some(mask) => mask.as_u32().leading_ones() as u8,
+				None => 24,
+				},
+			router: m.router,
+			dns_servers: m.dns_servers.clone(),
+			server,
+			lease_time,
+			// Defaults are 0.5 and 0.875 of the lease time (RFC 2131 4.4.5)
+			renewal_time: m.renewal_time.unwrap_or(t / 2).min(t),
+			rebinding_time: m.rebinding_time.unwrap_or(t / 8 * 7).min(t),
+			obtained: ::kernel::time::ticks(),
+			}
+	}
+}
+
+/// A parsed BOOTP/DHCP message
+struct Message
+{
+	op: u8,
+	xid: u32,
+	yiaddr: Address,
+	chaddr: MacAddr,
+	msg_type: Option<u8>,
+	server_id: Option<Address>,
+	mask: Option<Address>,
+	router: Option<Address>,
+	dns_servers: Vec<Address>,
+	lease_time: Option<u32>,
+	renewal_time: Option<u32>,
+	rebinding_time: Option<u32>,
+}
+impl Message
+{
+	fn parse(d: &[u8]) -> Option<Message>
+	{
+		fn addr(d: &[u8]) -> Address {
+			Address::new(d[0], d[1], d[2], d[3])
+		}
+		fn u32_be(d: &[u8]) -> u32 {
+			u32::from_be_bytes([d[0], d[1], d[2], d[3]])
+		}
+		if d.len() < 240 || d[236..240] != MAGIC_COOKIE {
+			return None;
+		}
+		let mut rv = Message {
+			op: d[0],
+			xid: u32_be(&d[4..]),
+			yiaddr: addr(&d[16..]),
+			chaddr: [d[28], d[29], d[30], d[31], d[32], d[33]],
+			msg_type: None,
+			server_id: None,
+			mask: None,
+			router: None,
+			dns_servers: Vec::new(),
+			lease_time: None,
+			renewal_time: None,
+			rebinding_time: None,
+			};
+		let mut opts = &d[240..];
+		while let Some(&code) = opts.first()
+		{
+			match code
+			{
+			OPT_PAD => { opts = &opts[1..]; continue },
+			OPT_END => break,
+			_ => {},
+			}
+			let len = *opts.get(1)? as usize;
+			let value = opts.get(2 .. 2 + len)?;
+			match (code, len)
+			{
+			(OPT_MESSAGE_TYPE, 1) => rv.msg_type = Some(value[0]),
+			(OPT_SERVER_ID, 4) => rv.server_id = Some(addr(value)),
+			(OPT_SUBNET_MASK, 4) => rv.mask = Some(addr(value)),
+			// Only the first router is used
+			(OPT_ROUTER, _) if len >= 4 => rv.router = Some(addr(value)),
+			(OPT_DNS_SERVERS, _) => rv.dns_servers = value.chunks_exact(4).map(addr).collect(),
+			(OPT_LEASE_TIME, 4) => rv.lease_time = Some(u32_be(value)),
+			(OPT_RENEWAL_TIME, 4) => rv.renewal_time = Some(u32_be(value)),
+			(OPT_REBINDING_TIME, 4) => rv.rebinding_time = Some(u32_be(value)),
+			_ => {},
+			}
+			opts = &opts[2 + len..];
+		}
+		Some(rv)
+	}
+}
@@ -0,0 +1,424 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/icmp.rs
+//! Internet Control Message Protocol (IPv4)
+use kernel::prelude::*;
+use kernel::sync::Mutex;
+use shared_map::SharedMap;
+use crate::nic::SparsePacket;
+use crate::Address;
+use crate::port_pool::PortPool;
+
+const IPV4_PROTO_ICMP: u8 = 1;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_DEST_UNREACHABLE: u8 = 3;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const TYPE_TIME_EXCEEDED: u8 = 11;
+const TYPE_PARAMETER_PROBLEM: u8 = 12;
+
+/// Destination unreachable code: Protocol unreachable
+pub const UNREACH_PROTOCOL: u8 = 2;
+/// Destination unreachable code: Port unreachable
+pub const UNREACH_PORT: u8 = 3;
+
+/// Maximum number of error messages generated per second
+const ERROR_RATE_LIMIT: u32 = 100;
+/// Maximum amount of the original datagram (after its header) included in an error message
+/// - Keeps the error within the minimum IPv4 reassembly size of 576 bytes
+const ERROR_MAX_DATA: usize = 576 - 20 - 8 - 20;
+/// Limit on the number of queued echo replies per ping socket
+const PING_QUEUE_LIMIT: usize = 64;
+
+pub fn init()
+{
+	crate::ipv4::register_handler(IPV4_PROTO_ICMP, rx_handler_v4).unwrap();
+}
+
+/// Ping sockets, indexed by echo identifier
+static PING_SOCKETS: SharedMap<u16, PingSocket> = SharedMap::new();
+static S_IDENTIFIERS: Mutex<PortPool> = Mutex::new(PortPool::new());
+/// Error rate limiting state: (start of the current second, errors sent in that second)
+static ERROR_RATE: Mutex<(::kernel::time::TickCount, u32)> = Mutex::new( (0, 0) );
+
+/// Error reported by a remote host (or router) about a packet that was sent
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum ErrorKind
+{
+	NetUnreachable,
+	HostUnreachable,
+	ProtocolUnreachable,
+	PortUnreachable,
+	/// Fragmentation was required but not allowed, with the next-hop MTU (zero if not reported)
+	FragmentationNeeded(u16),
+	/// Communication was administratively prohibited
+	Prohibited,
+	/// Other destination unreachable code
+	Unreachable(u8),
+	/// TTL expired in transit, or fragment reassembly timed out
+	TimeExceeded,
+	ParameterProblem,
+}
+impl ErrorKind
+{
+	fn from_type_code(ty: u8, code: u8, rest: [u8; 4]) -> Option<ErrorKind>
+	{
+		Some(match ty
+		{
+		TYPE_DEST_UNREACHABLE => match code
+			{
+			0 | 6 | 9 => ErrorKind::NetUnreachable,
+			1 | 7 | 10 => ErrorKind::HostUnreachable,
+			UNREACH_PROTOCOL => ErrorKind::ProtocolUnreachable,
+			UNREACH_PORT => ErrorKind::PortUnreachable,
+			4 => ErrorKind::FragmentationNeeded( (rest[2] as u16) << 8 | rest[3] as u16 ),
+			13 => ErrorKind::Prohibited,
+			_ => ErrorKind::Unreachable(code),
+			},
+		TYPE_TIME_EXCEEDED => ErrorKind::TimeExceeded,
+		TYPE_PARAMETER_PROBLEM => ErrorKind::ParameterProblem,
+		_ => return None,
+		})
+	}
+	/// Returns true if this error indicates that the remote isn't accepting this protocol/port (instead of a possibly-transient routing problem)
+	pub fn is_hard(&self) -> bool
+	{
+		match *self
+		{
+		ErrorKind::ProtocolUnreachable | ErrorKind::PortUnreachable => true,
+		_ => false,
+		}
+	}
+}
+
+fn rx_handler_v4(int: &crate::ipv4::Interface, src_addr: crate::ipv4::Address, mut pkt: crate::nic::PacketReader)
+{
+	let len = pkt.remain();
+	if len < 8 {
+		log_error!("Undersized packet: {} bytes", len);
+		return ;
+	}
+	// Validate checksum (over the entire message)
+	{
+		let mut reader = pkt.clone();
+		let words = (0 .. len / 2).map(|_| reader.read_u16n().unwrap());
+		let sum_whole = !crate::ipv4::calculate_checksum(words);
+		let sum_partial = if len % 2 == 1 { (reader.read_u8().unwrap() as u16) << 8 } else { 0 };
+		let sum = crate::ipv4::calculate_checksum([sum_whole, sum_partial].iter().copied());
+		if sum != 0 {
+			log_error!("Incorrect checksum: 0x{:04x} != 0", sum);
+			return ;
+		}
+	}
+
+	let ty = pkt.read_u8().unwrap();
+	let code = pkt.read_u8().unwrap();
+	let _checksum = pkt.read_u16n().unwrap();
+	let rest: [u8; 4] = pkt.read_bytes([0; 4]).unwrap();
+	log_debug!("ICMP {:?} -> {:?} type={} code={}", src_addr, int.addr(), ty, code);
+
+	match ty
+	{
+	TYPE_ECHO_REQUEST => {
+		let mut data = vec![0; pkt.remain()];
+		pkt.read(&mut data).unwrap();
+		send_message_v4(int.addr(), src_addr, TYPE_ECHO_REPLY, 0, rest, &data);
+		},
+	TYPE_ECHO_REPLY => handle_echo_reply(Address::Ipv4(src_addr), rest, pkt),
+	_ => match ErrorKind::from_type_code(ty, code, rest)
+		{
+		Some(kind) => handle_error_v4(src_addr, kind, pkt),
+		None => log_debug!("Unhandled ICMP type {} code {} from {:?}", ty, code, src_addr),
+		},
+	}
+}
+
+/// Deliver an echo reply (IPv4 or IPv6) to the ping socket with the matching identifier
+pub(crate) fn handle_echo_reply(source: Address, rest: [u8; 4], mut pkt: crate::nic::PacketReader)
+{
+	let identifier = (rest[0] as u16) << 8 | rest[1] as u16;
+	let sequence = (rest[2] as u16) << 8 | rest[3] as u16;
+	match PING_SOCKETS.get(&identifier)
+	{
+	Some(s) => {
+		let mut data = vec![0; pkt.remain()];
+		pkt.read(&mut data).unwrap();
+		s.push(source, sequence, data);
+		},
+	None => log_debug!("Echo reply from {:?} with unknown identifier {:#x}", source, identifier),
+	}
+}
+/// Report an error for an echo request sent by the ping socket with the given identifier
+pub(crate) fn handle_echo_error(identifier: u16, kind: ErrorKind)
+{
+	if let Some(s) = PING_SOCKETS.get(&identifier) {
+		s.set_error(kind);
+	}
+}
+
+/// Handle a received error message, passing it to the protocol that sent the original packet
+fn handle_error_v4(reporter: crate::ipv4::Address, kind: ErrorKind, mut pkt: crate::nic::PacketReader)
+{
+	// Error messages contain the original IP header and at least the first 8 bytes of its data
+	let orig_hdr: [u8; 20] = match pkt.read_bytes([0; 20])
+		{
+		Ok(v) => v,
+		Err(_) => { log_warning!("ICMP error from {:?} too short for an IPv4 header", reporter); return },
+		};
+	if orig_hdr[0] >> 4 != 4 {
+		log_warning!("ICMP error from {:?} contains a non-IPv4 header", reporter);
+		return ;
+	}
+	for _ in 20 .. (orig_hdr[0] & 0xF) as usize * 4 {
+		if pkt.read_u8().is_err() {
+			return ;
+		}
+	}
+	let orig_data: [u8; 8] = match pkt.read_bytes([0; 8])
+		{
+		Ok(v) => v,
+		Err(_) => { log_warning!("ICMP error from {:?} has too little of the original packet", reporter); return },
+		};
+	let proto = orig_hdr[9];
+	let local = Address::Ipv4(crate::ipv4::Address::new(orig_hdr[12], orig_hdr[13], orig_hdr[14], orig_hdr[15]));
+	let remote = Address::Ipv4(crate::ipv4::Address::new(orig_hdr[16], orig_hdr[17], orig_hdr[18], orig_hdr[19]));
+	log_debug!("{:?} reported by {:?} for protocol {} {:?} -> {:?}", kind, reporter, proto, local, remote);
+
+	let u16n = |i: usize| (orig_data[i] as u16) << 8 | orig_data[i+1] as u16;
+	match proto
+	{
+	IPV4_PROTO_ICMP => if orig_data[0] == TYPE_ECHO_REQUEST {
+		handle_echo_error(u16n(4), kind);
+		},
+	crate::tcp::IPV4_PROTO_TCP => {
+		let seq = (u16n(4) as u32) << 16 | u16n(6) as u32;
+		crate::tcp::handle_icmp_error(local, u16n(0), remote, u16n(2), seq, kind);
+		},
+	crate::udp::IPV4_PROTO_UDP => crate::udp::handle_icmp_error(local, u16n(0), remote, u16n(2), kind),
+	_ => {},
+	}
+}
+
+/// Send a "destination unreachable" error in response to a received packet
+///
+/// `data` is the received packet's payload. The original IP header isn't available to the protocol handlers, so a
+/// minimal header is reconstructed from the addresses and protocol.
+pub(crate) fn send_unreachable_v4(local: crate::ipv4::Address, remote: crate::ipv4::Address, proto: u8, code: u8, mut data: crate::nic::PacketReader)
+{
+	if !error_rate_check() {
+		log_debug!("Not sending unreachable to {:?}, rate limited", remote);
+		return ;
+	}
+	let total_length = (20 + data.remain()).min(0xFFFF) as u16;
+	let mut msg = [0u8; 20 + ERROR_MAX_DATA];
+	msg[0] = 0x45;
+	msg[2] = (total_length >> 8) as u8;
+	msg[3] = total_length as u8;
+	msg[8] = 64;
+	msg[9] = proto;
+	msg[12..16].copy_from_slice(&remote.0);
+	msg[16..20].copy_from_slice(&local.0);
+	let hdr_sum = crate::ipv4::calculate_checksum(msg[..20].chunks(2).map(|v| (v[0] as u16) << 8 | v[1] as u16));
+	msg[10] = (hdr_sum >> 8) as u8;
+	msg[11] = hdr_sum as u8;
+	let data_len = data.read(&mut msg[20..]).unwrap_or(0);
+	send_message_v4(local, remote, TYPE_DEST_UNREACHABLE, code, [0; 4], &msg[..20 + data_len]);
+}
+
+/// Check (and update) the error rate limit, shared by ICMP and ICMPv6
+pub(crate) fn error_rate_check() -> bool
+{
+	let now = ::kernel::time::ticks();
+	let mut lh = ERROR_RATE.lock();
+	if now - lh.0 >= 1000 {
+		*lh = (now, 0);
+	}
+	if lh.1 >= ERROR_RATE_LIMIT {
+		false
+	}
+	else {
+		lh.1 += 1;
+		true
+	}
+}
+
+fn send_message_v4(local: crate::ipv4::Address, remote: crate::ipv4::Address, ty: u8, code: u8, rest: [u8; 4], data: &[u8])
+{
+	let mut hdr = [ty, code, 0, 0, rest[0], rest[1], rest[2], rest[3]];
+	let sum = {
+		let it = hdr.iter().chain(data.iter()).copied();
+		let words = it.clone().step_by(2).zip(it.skip(1).step_by(2)).map(|(a,b)| (a as u16) << 8 | b as u16);
+		let sum_whole = !crate::ipv4::calculate_checksum(words);
+		let sum_partial = if data.len() % 2 == 1 { (*data.last().unwrap() as u16) << 8 } else { 0 };
+		crate::ipv4::calculate_checksum([sum_whole, sum_partial].iter().copied())
+		};
+	hdr[2] = (sum >> 8) as u8;
+	hdr[3] = sum as u8;
+	let data_pkt = SparsePacket::new_root(data);
+	let hdr_pkt = SparsePacket::new_chained(&hdr, &data_pkt);
+	::kernel::futures::block_on(crate::ipv4::send_packet(local, remote, IPV4_PROTO_ICMP, hdr_pkt));
+}
+
+struct EchoReply
+{
+	source: Address,
+	sequence: u16,
+	data: Vec<u8>,
+}
+struct PingSocket
+{
+	rx_queue: Mutex<PingQueue>,
+	/// Userland waiters for an echo reply
+	waiters: ::kernel::user_async::Queue,
+}
+struct PingQueue
+{
+	count: usize,
+	queue: ::kernel::lib::Queue<EchoReply>,
+	error: Option<ErrorKind>,
+}
+impl PingSocket
+{
+	fn push(&self, source: Address, sequence: u16, data: Vec<u8>)
+	{
+		let mut lh = self.rx_queue.lock();
+		if lh.count >= PING_QUEUE_LIMIT {
+			log_debug!("Dropping echo reply from {:?}, queue full", source);
+			return ;
+		}
+		lh.count += 1;
+		lh.queue.push(EchoReply { source, sequence, data });
+		self.waiters.wake_all();
+	}
+	fn set_error(&self, kind: ErrorKind)
+	{
+		self.rx_queue.lock().error = Some(kind);
+		self.waiters.wake_all();
+	}
+	fn has_event(&self) -> bool
+	{
+		let lh = self.rx_queue.lock();
+		!lh.queue.is_empty() || lh.error.is_some()
+	}
+}
+
+#[derive(Debug)]
+pub enum PingError
+{
+	/// No identifiers are available
+	NoIdentifierAvailable,
+}
+
+/// Handle to an ICMP echo ("ping") socket
+///
+/// Each socket has a unique echo identifier, replies with that identifier are queued on the socket.
+pub struct PingHandle
+{
+	identifier: u16,
+}
+impl PingHandle
+{
+	pub fn open() -> Result<PingHandle, PingError>
+	{
+		let identifier = S_IDENTIFIERS.lock().allocate().ok_or(PingError::NoIdentifierAvailable)?;
+		let sock = PingSocket {
+			rx_queue: Mutex::new(PingQueue { count: 0, queue: ::kernel::lib::Queue::new(), error: None }),
+			waiters: ::kernel::user_async::Queue::new(),
+			};
+		if PING_SOCKETS.insert(identifier, sock).is_err() {
+			panic!("Allocated echo identifier {:#x} already in use", identifier);
+		}
+		Ok(PingHandle { identifier })
+	}
+
+	/// Echo identifier used by this socket
+	pub fn identifier(&self) -> u16
+	{
+		self.identifier
+	}
+
+	/// Send an echo request, returning `Err(())` if there's no route to the destination
+	pub fn send_echo(&self, addr: Address, sequence: u16, data: &[u8]) -> Result<usize, ()>
+	{
+		match addr
+		{
+		Address::Ipv4(a) => {
+			let local = match crate::ipv4::route_lookup(crate::ipv4::Address::zero(), a)
+				{
+				Some((laddr, _, _)) => laddr,
+				None => return Err( () ),
+				};
+			let rest = [(self.identifier >> 8) as u8, self.identifier as u8, (sequence >> 8) as u8, sequence as u8];
+			send_message_v4(local, a, TYPE_ECHO_REQUEST, 0, rest, data);
+			},
+		Address::Ipv6(a) => {
+			let local = match crate::ipv6::route_lookup(crate::ipv6::Address::zero(), a)
+				{
+				Some((laddr, _, _)) => laddr,
+				None => return Err( () ),
+				};
+			crate::icmpv6::send_echo_request(local, a, self.identifier, sequence, data);
+			},
+		}
+		Ok(data.len())
+	}
+
+	/// Receive an echo reply (non-blocking), returning the data length, source, and sequence number
+	///
+	/// An error reported for an earlier request is returned (once) before any further replies.
+	pub fn recv_reply(&self, buf: &mut [u8]) -> Result<Option<(usize, Address, u16)>, ErrorKind>
+	{
+		self.with_socket(|s| {
+			let mut lh = s.rx_queue.lock();
+			if let Some(e) = lh.error.take() {
+				return Err(e);
+			}
+			let r = match lh.queue.pop()
+				{
+				Some(v) => v,
+				None => return Ok(None),
+				};
+			lh.count -= 1;
+			let len = usize::min(buf.len(), r.data.len());
+			buf[..len].copy_from_slice(&r.data[..len]);
+			Ok(Some( (len, r.source, r.sequence) ))
+			})
+	}
+
+	/// Register a sleep object to be woken when a reply (or error) is available
+	pub fn bind_wait_recv(&self, obj: &mut ::kernel::threads::SleepObject)
+	{
+		self.with_socket(|s| {
+			s.waiters.wait_upon(obj);
+			if s.has_event() {
+				obj.signal();
+			}
+			})
+	}
+	/// Unregister a sleep object, returning `true` if there's a reply (or error) waiting
+	pub fn clear_wait_recv(&self, obj: &mut ::kernel::threads::SleepObject) -> bool
+	{
+		self.with_socket(|s| {
+			s.waiters.clear_wait(obj);
+			s.has_event()
+			})
+	}
+
+	fn with_socket<R>(&self, f: impl FnOnce(&PingSocket)->R) -> R
+	{
+		match PING_SOCKETS.get(&self.identifier)
+		{
+		None => panic!("Ping socket {:#x} removed before handle dropped", self.identifier),
+		Some(v) => f(&v),
+		}
+	}
+}
+impl ::core::ops::Drop for PingHandle
+{
+	fn drop(&mut self)
+	{
+		PING_SOCKETS.take(&self.identifier);
+		S_IDENTIFIERS.lock().release(self.identifier);
+	}
+}
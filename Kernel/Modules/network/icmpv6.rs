@@ -0,0 +1,200 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/icmpv6.rs
+//! Internet Control Message Protocol for IPv6 (RFC 4443)
+use kernel::prelude::*;
+use crate::nic::SparsePacket;
+use crate::Address;
+use crate::icmp::ErrorKind;
+
+pub(crate) const IPV6_NEXT_ICMPV6: u8 = 58;
+
+const TYPE_DEST_UNREACHABLE: u8 = 1;
+const TYPE_PACKET_TOO_BIG: u8 = 2;
+const TYPE_TIME_EXCEEDED: u8 = 3;
+const TYPE_PARAMETER_PROBLEM: u8 = 4;
+const TYPE_ECHO_REQUEST: u8 = 128;
+const TYPE_ECHO_REPLY: u8 = 129;
+
+/// Maximum amount of the original packet included in an error message
+/// - Keeps the error within the minimum IPv6 MTU of 1280 bytes
+const ERROR_MAX_DATA: usize = 1280 - 40 - 8;
+
+/// Reason for an error sent in response to a received packet
+pub(crate) enum Unreachable
+{
+	/// No socket on the destination port
+	Port,
+	/// Unrecognised next header type
+	NextHeader,
+}
+
+pub fn init()
+{
+	crate::ipv6::register_handler(IPV6_NEXT_ICMPV6, rx_handler).unwrap();
+}
+
+fn rx_handler(int: &crate::ipv6::Interface, info: &crate::ipv6::PacketInfo, mut pkt: crate::nic::PacketReader)
+{
+	let len = pkt.remain();
+	if len < 8 {
+		log_error!("Undersized packet: {} bytes", len);
+		return ;
+	}
+	// Validate checksum (over the pseudo-header and the entire message)
+	{
+		let mut reader = pkt.clone();
+		let words = (0 .. len / 2).map(|_| reader.read_u16n().unwrap());
+		let sum_pseudo = crate::pseudo_header_checksum(Address::Ipv6(info.source), Address::Ipv6(info.destination), IPV6_NEXT_ICMPV6, len);
+		let sum_whole = crate::ipv4::calculate_checksum(words);
+		let sum_partial = if len % 2 == 1 { (reader.read_u8().unwrap() as u16) << 8 } else { 0 };
+		let sum = crate::ipv4::calculate_checksum([!sum_pseudo, !sum_whole, sum_partial].iter().copied());
+		if sum != 0 {
+			log_error!("Incorrect checksum: 0x{:04x} != 0", sum);
+			return ;
+		}
+	}
+
+	let ty = pkt.read_u8().unwrap();
+	let code = pkt.read_u8().unwrap();
+	let _checksum = pkt.read_u16n().unwrap();
+	let rest: [u8; 4] = pkt.read_bytes([0; 4]).unwrap();
+	log_debug!("ICMPv6 {:?} -> {:?} type={} code={}", info.source, info.destination, ty, code);
+
+	match ty
+	{
+	TYPE_ECHO_REQUEST => {
+		// Replies to multicast requests come from the interface's unicast address
+		let local = if info.destination.is_multicast() { int.addr() } else { info.destination };
+		let mut data = vec![0; pkt.remain()];
+		pkt.read(&mut data).unwrap();
+		send_message(local, info.source, TYPE_ECHO_REPLY, 0, rest, &data);
+		},
+	TYPE_ECHO_REPLY => crate::icmp::handle_echo_reply(Address::Ipv6(info.source), rest, pkt),
+	crate::ndp::TYPE_NEIGHBOR_SOLICITATION
+	| crate::ndp::TYPE_NEIGHBOR_ADVERTISEMENT => crate::ndp::handle_packet(int, info, ty, pkt),
+	_ => match error_kind(ty, code, rest)
+		{
+		Some(kind) => handle_error(info.source, kind, pkt),
+		None => log_debug!("Unhandled ICMPv6 type {} code {} from {:?}", ty, code, info.source),
+		},
+	}
+}
+
+fn error_kind(ty: u8, code: u8, rest: [u8; 4]) -> Option<ErrorKind>
+{
+	Some(match ty
+	{
+	TYPE_DEST_UNREACHABLE => match code
+		{
+		0 => ErrorKind::NetUnreachable,
+		1 | 5 | 6 => ErrorKind::Prohibited,
+		3 => ErrorKind::HostUnreachable,
+		4 => ErrorKind::PortUnreachable,
+		_ => ErrorKind::Unreachable(code),
+		},
+	TYPE_PACKET_TOO_BIG => ErrorKind::FragmentationNeeded( u32::from_be_bytes(rest).min(0xFFFF) as u16 ),
+	TYPE_TIME_EXCEEDED => ErrorKind::TimeExceeded,
+	TYPE_PARAMETER_PROBLEM if code == 1 => ErrorKind::ProtocolUnreachable,
+	TYPE_PARAMETER_PROBLEM => ErrorKind::ParameterProblem,
+	_ => return None,
+	})
+}
+
+/// Handle a received error message, passing it to the protocol that sent the original packet
+fn handle_error(reporter: crate::ipv6::Address, kind: ErrorKind, mut pkt: crate::nic::PacketReader)
+{
+	let orig_hdr: [u8; 40] = match pkt.read_bytes([0; 40])
+		{
+		Ok(v) => v,
+		Err(_) => { log_warning!("ICMPv6 error from {:?} too short for an IPv6 header", reporter); return },
+		};
+	if orig_hdr[0] >> 4 != 6 {
+		log_warning!("ICMPv6 error from {:?} contains a non-IPv6 header", reporter);
+		return ;
+	}
+	let orig_data: [u8; 8] = match pkt.read_bytes([0; 8])
+		{
+		Ok(v) => v,
+		Err(_) => { log_warning!("ICMPv6 error from {:?} has too little of the original packet", reporter); return },
+		};
+	// TODO: Skip extension headers in the original packet
+	let next_header = orig_hdr[6];
+	let addr = |ofs: usize| {
+		let mut a = [0; 16];
+		a.copy_from_slice(&orig_hdr[ofs..][..16]);
+		Address::Ipv6(crate::ipv6::Address(a))
+		};
+	let local = addr(8);
+	let remote = addr(24);
+	log_debug!("{:?} reported by {:?} for next header {} {:?} -> {:?}", kind, reporter, next_header, local, remote);
+
+	let u16n = |i: usize| (orig_data[i] as u16) << 8 | orig_data[i+1] as u16;
+	match next_header
+	{
+	IPV6_NEXT_ICMPV6 => if orig_data[0] == TYPE_ECHO_REQUEST {
+		crate::icmp::handle_echo_error(u16n(4), kind);
+		},
+	crate::tcp::IPV6_NEXT_TCP => {
+		let seq = (u16n(4) as u32) << 16 | u16n(6) as u32;
+		crate::tcp::handle_icmp_error(local, u16n(0), remote, u16n(2), seq, kind);
+		},
+	crate::udp::IPV6_NEXT_UDP => crate::udp::handle_icmp_error(local, u16n(0), remote, u16n(2), kind),
+	_ => {},
+	}
+}
+
+/// Send an error in response to a received packet
+///
+/// `data` is the received packet's payload (after the IPv6 header), the header is reconstructed from the
+/// addresses and next header value.
+pub(crate) fn send_unreachable(local: crate::ipv6::Address, remote: crate::ipv6::Address, next_header: u8, reason: Unreachable, mut data: crate::nic::PacketReader)
+{
+	if !crate::icmp::error_rate_check() {
+		log_debug!("Not sending unreachable to {:?}, rate limited", remote);
+		return ;
+	}
+	let (ty, code, rest) = match reason
+		{
+		Unreachable::Port => (TYPE_DEST_UNREACHABLE, 4, [0; 4]),
+		// Pointer to the next header field of the (reconstructed) header
+		Unreachable::NextHeader => (TYPE_PARAMETER_PROBLEM, 1, 6u32.to_be_bytes()),
+		};
+	let mut msg = [0u8; ERROR_MAX_DATA];
+	msg[..40].copy_from_slice(&crate::ipv6::reconstruct_header(remote, local, next_header, data.remain()));
+	let data_len = data.read(&mut msg[40..]).unwrap_or(0);
+	send_message(local, remote, ty, code, rest, &msg[..40 + data_len]);
+}
+
+/// Send an echo request (used by ping sockets)
+pub(crate) fn send_echo_request(local: crate::ipv6::Address, remote: crate::ipv6::Address, identifier: u16, sequence: u16, data: &[u8])
+{
+	let rest = [(identifier >> 8) as u8, identifier as u8, (sequence >> 8) as u8, sequence as u8];
+	send_message(local, remote, TYPE_ECHO_REQUEST, 0, rest, data);
+}
+
+/// Build an ICMPv6 header (including the checksum) for a message
+pub(crate) fn encode_header(local: crate::ipv6::Address, remote: crate::ipv6::Address, ty: u8, code: u8, rest: [u8; 4], data: &[u8]) -> [u8; 8]
+{
+	let mut hdr = [ty, code, 0, 0, rest[0], rest[1], rest[2], rest[3]];
+	let sum = {
+		let it = hdr.iter().chain(data.iter()).copied();
+		let words = it.clone().step_by(2).zip(it.skip(1).step_by(2)).map(|(a,b)| (a as u16) << 8 | b as u16);
+		let sum_pseudo = crate::pseudo_header_checksum(Address::Ipv6(local), Address::Ipv6(remote), IPV6_NEXT_ICMPV6, 8 + data.len());
+		let sum_whole = !crate::ipv4::calculate_checksum(words);
+		let sum_partial = if data.len() % 2 == 1 { (*data.last().unwrap() as u16) << 8 } else { 0 };
+		crate::ipv4::calculate_checksum([!sum_pseudo, sum_whole, sum_partial].iter().copied())
+		};
+	hdr[2] = (sum >> 8) as u8;
+	hdr[3] = sum as u8;
+	hdr
+}
+
+fn send_message(local: crate::ipv6::Address, remote: crate::ipv6::Address, ty: u8, code: u8, rest: [u8; 4], data: &[u8])
+{
+	let hdr = encode_header(local, remote, ty, code, rest, data);
+	let data_pkt = SparsePacket::new_root(data);
+	let hdr_pkt = SparsePacket::new_chained(&hdr, &data_pkt);
+	::kernel::futures::block_on(crate::ipv6::send_packet(local, remote, IPV6_NEXT_ICMPV6, hdr_pkt));
+}
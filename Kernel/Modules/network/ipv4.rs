@@ -8,9 +8,15 @@ use kernel::sync::RwLock;
 use crate::nic::MacAddr;
 use crate::nic::PacketReader;
 
+mod fragment;
+
 // List of protocol numbers and handlers
 static PROTOCOLS: RwLock<Vec<(u8, ProtoHandler)>> = RwLock::new(Vec::new());
 static INTERFACES: RwLock<Vec<Interface>> = RwLock::new(Vec::new());
+/// Static routes (interface subnets are implicit on-link routes)
+static ROUTES: RwLock<Vec<Route>> = RwLock::new(Vec::new());
+/// Identification value for the next outgoing packet
+static NEXT_IDENTIFICATION: ::core::sync::atomic::AtomicU16 = ::core::sync::atomic::AtomicU16::new(0);
 
 // NOTE: uses mac address to identify interface
 pub fn add_interface(local_mac: [u8; 6], addr: Address, mask_bits: u8)
@@ -31,6 +37,16 @@ pub fn add_interface(local_mac: [u8; 6], addr: Address, mask_bits: u8)
 		mask: mask_bits,
 		});
 }
+/// Remove an interface address (e.g. when a DHCP lease expires)
+pub fn remove_interface(local_mac: [u8; 6], addr: Address)
+{
+	INTERFACES.write().retain(|i| !(i.local_mac == local_mac && i.address == addr));
+}
+/// Check if the specified NIC has any addresses assigned
+pub fn has_interface(local_mac: [u8; 6]) -> bool
+{
+	INTERFACES.read().iter().any(|i| i.local_mac == local_mac)
+}
 
 pub fn register_handler(proto: u8, handler: fn(&Interface, Address, PacketReader)) -> Result<(), ()>
 {
@@ -44,7 +60,7 @@ pub fn register_handler(proto: u8, handler: fn(&Interface, Address, PacketReader
 	lh.push( (proto, ProtoHandler::DirectKernel(handler),) );
 	Ok( () )
 }
-pub fn handle_rx_ethernet(_physical_interface: &dyn crate::nic::Interface, source_mac: [u8; 6], mut reader: PacketReader) -> Result<(), ()>
+pub fn handle_rx_ethernet(_physical_interface: &dyn crate::nic::Interface, local_mac: MacAddr, source_mac: [u8; 6], mut reader: PacketReader) -> Result<(), ()>
 {
 	let pre_header_reader = reader.clone();
 	let hdr = match Ipv4Header::read(&mut reader)
@@ -93,20 +109,22 @@ pub fn handle_rx_ethernet(_physical_interface: &dyn crate::nic::Interface, sourc
 		}
 	}
 	
-	// Check for IP-level fragmentation
-	if hdr.get_has_more_fragments() || hdr.get_fragment_ofs() != 0 {
-		// TODO: Handle fragmented packets
-		log_error!("TODO: Handle fragmented packets");
-		return Ok( () );
-	}
-	
 	// Sanity check that we have enough bytes for the body.
-	if reader.remain() < hdr.total_length as usize - hdr_len {
-		log_warning!("Undersized packet: {} bytes after header, body length is {}", reader.remain(), hdr.total_length as usize - hdr_len);
+	if hdr.total_length as usize <= hdr_len || reader.remain() < hdr.total_length as usize - hdr_len {
+		log_warning!("Undersized packet: {} bytes after header, body length is {}", reader.remain(), (hdr.total_length as usize).wrapping_sub(hdr_len));
 		return Err( () );
 	}
+	// Drop any link-layer padding
+	reader.truncate(hdr.total_length as usize - hdr_len);
+
+	// DHCP replies are accepted before the NIC has an address (and can be sent to the offered address or broadcast)
+	if hdr.protocol == crate::udp::IPV4_PROTO_UDP && !hdr.get_has_more_fragments() && hdr.get_fragment_ofs() == 0
+	{
+		if crate::dhcp::handle_rx(local_mac, hdr.source, hdr.destination, reader.clone()) {
+			return Ok( () );
+		}
+	}
 
-	
 	// Check destination IP against known interfaces.
 	// - Could also be doing routing.
 	for interface in INTERFACES.read().iter()
@@ -119,31 +137,57 @@ pub fn handle_rx_ethernet(_physical_interface: &dyn crate::nic::Interface, sourc
 			// TODO: Check if the source address is from the same subnet, and only cache in ARP if it is
 			crate::arp::snoop_v4(source_mac, hdr.source);
 
-			// Figure out which sub-protocol to send this packet to
-			// - Should there be alternate handlers for 
-			for &(id,ref handler) in PROTOCOLS.read().iter()
+			// Check for IP-level fragmentation
+			if hdr.get_has_more_fragments() || hdr.get_fragment_ofs() != 0
 			{
-				if id == hdr.protocol
+				let key = fragment::Key {
+					source: hdr.source,
+					destination: hdr.destination,
+					protocol: hdr.protocol,
+					identification: hdr.identification,
+					};
+				if let Some(data) = fragment::add_fragment(key, hdr.get_fragment_ofs(), hdr.get_has_more_fragments(), reader)
 				{
-					handler.dispatch(interface, hdr.source, hdr.destination, reader);
-					return Ok( () );
+					log_debug!("Reassembled {:?} ({} bytes)", key, data.len());
+					let pkt = match crate::nic::PacketHandle::new(fragment::OwnedPacket(data))
+						{
+						Ok(v) => v,
+						Err(_) => panic!("OwnedPacket doesn't fit in a PacketHandle"),
+						};
+					dispatch_to_protocol(interface, &hdr, PacketReader::new(&pkt));
 				}
+				return Ok( () );
 			}
-			log_debug!("Unknown protocol {}", hdr.protocol);
-			// No handler, but the interface is known
+
+			dispatch_to_protocol(interface, &hdr, reader);
 			return Ok( () );
 		}
 	}
 	//else
 	{
-		// Routing.
-		// For now, just drop it
-		log_debug!("TODO: Packet didn't match any interfaces (A={:?}), try routing?", hdr.destination);
+		// This is a host, not a router - drop packets for other addresses
+		log_debug!("Packet didn't match any interfaces (A={:?}), dropping", hdr.destination);
 	}
 	
 	Ok( () )
 }
 
+fn dispatch_to_protocol(interface: &Interface, hdr: &Ipv4Header, reader: PacketReader)
+{
+	// Figure out which sub-protocol to send this packet to
+	for &(id,ref handler) in PROTOCOLS.read().iter()
+	{
+		if id == hdr.protocol
+		{
+			handler.dispatch(interface, hdr.source, hdr.destination, reader);
+			return ;
+		}
+	}
+	log_debug!("Unknown protocol {}", hdr.protocol);
+	// No handler, but the interface is known
+	crate::icmp::send_unreachable_v4(interface.address, hdr.source, hdr.protocol, crate::icmp::UNREACH_PROTOCOL, reader);
+}
+
 // Calculate a checksum of a sequence of NATIVE ENDIAN (not network) 16-bit words
 pub fn calculate_checksum(words: impl Iterator<Item=u16>) -> u16
 {
@@ -159,18 +203,97 @@ pub fn calculate_checksum(words: impl Iterator<Item=u16>) -> u16
 	!sum as u16
 }
 
-// 
+/// A static route
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Route
+{
+	/// Destination network
+	pub network: Address,
+	/// Prefix length of the destination network (zero for a default route)
+	pub mask: u8,
+	/// Router to send to (must be reachable through an interface's subnet)
+	pub next_hop: Address,
+}
+#[derive(Debug)]
+pub enum RouteError
+{
+	/// The mask is longer than 32 bits, or the network has bits set outside the mask
+	InvalidNetwork,
+	/// A route for this network already exists
+	Exists,
+	/// No route for this network exists
+	NotFound,
+}
+
+/// Add a static route
+pub fn add_route(route: Route) -> Result<(), RouteError>
+{
+	if route.mask > 32 || route.network.mask(route.mask) != route.network {
+		return Err(RouteError::InvalidNetwork);
+	}
+	let mut lh = ROUTES.write();
+	if lh.iter().any(|r| r.network == route.network && r.mask == route.mask) {
+		return Err(RouteError::Exists);
+	}
+	log_notice!("Route added: {:?}/{} via {:?}", route.network, route.mask, route.next_hop);
+	lh.push(route);
+	Ok( () )
+}
+/// Remove the static route for the specified network
+pub fn del_route(network: Address, mask: u8) -> Result<Route, RouteError>
+{
+	let mut lh = ROUTES.write();
+	match lh.iter().position(|r| r.network == network && r.mask == mask)
+	{
+	Some(i) => {
+		let r = lh.remove(i);
+		log_notice!("Route removed: {:?}/{} via {:?}", r.network, r.mask, r.next_hop);
+		Ok(r)
+		},
+	None => Err(RouteError::NotFound),
+	}
+}
+/// Get a static route by index (for enumeration)
+pub fn get_route(index: usize) -> Option<Route>
+{
+	ROUTES.read().get(index).copied()
+}
+
+/// Find the interface that has `dest` on-link (longest prefix first), returning the interface address, MAC, and prefix length
+fn lookup_on_link(interfaces: &[Interface], source: Address, dest: Address) -> Option<(Address, MacAddr, u8)>
+{
+	interfaces.iter()
+		.filter(|i| source.is_zero() || i.address == source)
+		.filter(|i| i.address.mask(i.mask) == dest.mask(i.mask))
+		.max_by_key(|i| i.mask)
+		.map(|i| (i.address, i.local_mac, i.mask))
+}
+
+/// Determine how to reach `dest`, returning the local address, interface MAC, and next-hop address
+///
+/// Uses a longest-prefix match over the interface subnets and the static routes (interface subnets win ties).
 pub fn route_lookup(source: Address, dest: Address) -> Option<(Address, MacAddr, Address)>
 {
-	for interface in INTERFACES.read().iter()
+	let interfaces = INTERFACES.read();
+	let on_link = lookup_on_link(&interfaces, source, dest);
+	let route = ROUTES.read().iter()
+		.filter(|r| r.network == dest.mask(r.mask))
+		.max_by_key(|r| r.mask)
+		.copied();
+	match (on_link, route)
 	{
-		// On-link?
-		if (source.is_zero() || interface.address == source) && interface.address.mask(interface.mask) == dest.mask(interface.mask)
+	(Some((addr, mac, mask)), Some(r)) if mask >= r.mask => Some( (addr, mac, dest) ),
+	(Some((addr, mac, _)), None) => Some( (addr, mac, dest) ),
+	(_, Some(r)) => match lookup_on_link(&interfaces, source, r.next_hop)
 		{
-			return Some( (interface.address, interface.local_mac, dest) );
-		}
+		Some((addr, mac, _)) => Some( (addr, mac, r.next_hop) ),
+		None => {
+			log_notice!("Route {:?}/{} has an unreachable next hop {:?}", r.network, r.mask, r.next_hop);
+			None
+			},
+		},
+	(None, None) => None,
 	}
-	None
 }
 
 pub async fn send_packet(source: Address, dest: Address, proto: u8, pkt: crate::nic::SparsePacket<'_>)
@@ -195,22 +318,63 @@ pub async fn send_packet(source: Address, dest: Address, proto: u8, pkt: crate::
 			},	// TODO: Error - No route to host
 		};
 	// 3. Send
+	send_on_interface(interface_mac, dest_mac, source, dest, proto, pkt);
+}
+
+/// Send a packet to the limited broadcast address (255.255.255.255) from a specific NIC
+pub fn send_broadcast(interface_mac: MacAddr, source: Address, proto: u8, pkt: crate::nic::SparsePacket<'_>)
+{
+	log_trace!("send_broadcast({:?} {:x?} 0x{:02x})", source, interface_mac, proto);
+	send_on_interface(interface_mac, [0xFF; 6], source, Address::new(255,255,255,255), proto, pkt);
+}
+
+fn send_on_interface(interface_mac: MacAddr, dest_mac: MacAddr, source: Address, dest: Address, proto: u8, pkt: crate::nic::SparsePacket<'_>)
+{
+	let mtu = crate::nic::get_mtu(interface_mac).unwrap_or(crate::nic::DEFAULT_MTU);
 	let mut hdr = Ipv4Header {
 		ver_and_len: 0x40 | 20/4,
 		diff_services: 0,
 		total_length: (20 + pkt.total_len()) as u16,
-		identification: 0,
+		identification: NEXT_IDENTIFICATION.fetch_add(1, ::core::sync::atomic::Ordering::Relaxed),
 		flags: 0,
-		frag_ofs_high: 0,
+		frag_ofs_low: 0,
 		ttl: 255,
 		protocol: proto,
 		hdr_checksum: 0,
 		source: source,
 		destination: dest,
 		};
-	hdr.set_checksum();
-	let hdr_bytes = hdr.encode();
-	crate::nic::send_from(interface_mac, dest_mac, 0x0800, crate::nic::SparsePacket::new_chained(&hdr_bytes, &pkt));
+	if 20 + pkt.total_len() <= mtu
+	{
+		hdr.set_checksum();
+		let hdr_bytes = hdr.encode();
+		crate::nic::send_from(interface_mac, dest_mac, 0x0800, crate::nic::SparsePacket::new_chained(&hdr_bytes, &pkt));
+	}
+	else
+	{
+		// Fragment: each fragment (except the last) carries a multiple of 8 bytes
+		let data: Vec<u8> = pkt.into_iter().flat_map(|v| v.iter().copied()).collect();
+		let max_payload = (mtu - 20) & !7;
+		log_debug!("send_packet: Fragmenting {} bytes into {} byte fragments", data.len(), max_payload);
+		for (i,chunk) in data.chunks(max_payload).enumerate()
+		{
+			let ofs = i * max_payload;
+			let mut frag_hdr = Ipv4Header {
+				total_length: (20 + chunk.len()) as u16,
+				flags: 0,
+				frag_ofs_low: 0,
+				hdr_checksum: 0,
+				..hdr
+				};
+			frag_hdr.set_fragment_ofs(ofs);
+			if ofs + chunk.len() < data.len() {
+				frag_hdr.set_has_more_fragments();
+			}
+			frag_hdr.set_checksum();
+			let hdr_bytes = frag_hdr.encode();
+			crate::nic::send_from(interface_mac, dest_mac, 0x0800, crate::nic::SparsePacket::new_chained(&hdr_bytes, &crate::nic::SparsePacket::new_root(chunk)));
+		}
+	}
 }
 
 #[allow(dead_code)]
@@ -221,7 +385,7 @@ struct Ipv4Header
 	total_length: u16,
 	identification: u16,
 	flags: u8,
-	frag_ofs_high: u8,
+	frag_ofs_low: u8,
 	ttl: u8,
 	protocol: u8,
 	hdr_checksum: u16,
@@ -237,7 +401,7 @@ impl Ipv4Header
 			(self.total_length >> 8) as u8, self.total_length as u8,
 			(self.identification >> 8) as u8, self.identification as u8,
 			self.flags,
-			self.frag_ofs_high,
+			self.frag_ofs_low,
 			self.ttl,
 			self.protocol,
 			(self.hdr_checksum >> 8) as u8, self.hdr_checksum as u8,
@@ -258,7 +422,7 @@ impl Ipv4Header
 			total_length: reader.read_u16n()?,
 			identification: reader.read_u16n()?,
 			flags: reader.read_u8()?,
-			frag_ofs_high: reader.read_u8()?,	// low bits in the `flags` field
+			frag_ofs_low: reader.read_u8()?,	// high bits in the `flags` field
 			ttl: reader.read_u8()?,
 			protocol: reader.read_u8()?,
 			hdr_checksum: reader.read_u16n()?,
@@ -273,12 +437,20 @@ impl Ipv4Header
 	fn get_has_more_fragments(&self) -> bool {
 		self.flags & 1 << 5 != 0
 	}
-	//fn set_has_more_fragments(&mut self) {
-	//	self.flags |= 1 << 5;
-	//}
+	fn set_has_more_fragments(&mut self) {
+		self.flags |= 1 << 5;
+	}
 
+	/// Fragment offset in bytes
 	fn get_fragment_ofs(&self) -> usize {
-		((self.frag_ofs_high as usize) << 5) | (self.flags & 0x1F) as usize
+		((((self.flags & 0x1F) as usize) << 8) | self.frag_ofs_low as usize) * 8
+	}
+	fn set_fragment_ofs(&mut self, ofs: usize) {
+		assert!(ofs % 8 == 0);
+		let v = ofs / 8;
+		assert!(v < 1 << 13);
+		self.flags = (self.flags & !0x1F) | (v >> 8) as u8;
+		self.frag_ofs_low = v as u8;
 	}
 }
 
@@ -332,26 +504,12 @@ impl Address
 		| (self.0[2] as u32) << 8
 		| (self.0[3] as u32) << 0
 	}
+	/// Clear all but the first `bits` bits of the address
 	pub fn mask(&self, bits: u8) -> Address {
-		let mask = (1 << (bits % 8)) - 1;
-		if bits < 8 {
-			Address([ self.0[0] & mask, 0, 0, 0 ])
-		}
-		else if bits < 16 {
-			Address([ self.0[0], self.0[1] & mask, 0, 0 ])
-		}
-		else if bits < 24 {
-			Address([ self.0[0], self.0[1], self.0[2] & mask, 0 ])
-		}
-		else if bits < 32 {
-			Address([ self.0[0], self.0[1], self.0[2], self.0[3] & mask ])
-		}
-		else if bits == 32 {
-			Address(self.0)
-		}
-		else {
-			unreachable!()
-		}
+		assert!(bits <= 32);
+		let mask = if bits == 0 { 0 } else { !0u32 << (32 - bits) };
+		let v = self.as_u32() & mask;
+		Address([ (v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8 ])
 	}
 	pub fn is_zero(&self) -> bool {
 		self.0 == [0,0,0,0]
@@ -0,0 +1,203 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/ipv4/fragment.rs
+//! IPv4 fragment reassembly
+use kernel::prelude::*;
+use kernel::sync::Mutex;
+use kernel::lib::VecMap;
+use super::Address;
+
+/// Time after the first fragment is seen before an incomplete datagram is discarded
+const REASSEMBLY_TIMEOUT_MS: u64 = 30_000;
+/// Maximum number of bytes held in the reassembly table
+const MAX_TOTAL_BYTES: usize = 0x40000;	// 256KiB
+/// Maximum size of a reassembled datagram (the maximum value of the total length field)
+const MAX_DATAGRAM_SIZE: usize = 0xFFFF;
+
+static TABLE: Mutex<Table> = Mutex::new(Table { total_bytes: 0, entries: VecMap::new() });
+
+#[derive(Copy,Clone,PartialEq,PartialOrd,Eq,Ord,Debug)]
+pub struct Key
+{
+	pub source: Address,
+	pub destination: Address,
+	pub protocol: u8,
+	pub identification: u16,
+}
+
+struct Table
+{
+	/// Sum of the buffer sizes of all entries
+	total_bytes: usize,
+	entries: VecMap<Key, Entry>,
+}
+struct Entry
+{
+	expiry: ::kernel::time::TickCount,
+	data: Vec<u8>,
+	/// Received byte ranges (sorted, non-overlapping and not adjacent)
+	ranges: Vec<(usize, usize)>,
+	/// Length of the complete datagram (known once the final fragment arrives)
+	total_len: Option<usize>,
+}
+impl Entry
+{
+	fn add_range(&mut self, start: usize, end: usize)
+	{
+		self.ranges.push( (start, end) );
+		self.ranges.sort();
+		let mut out: Vec<(usize,usize)> = Vec::with_capacity(self.ranges.len());
+		for &(s, e) in self.ranges.iter()
+		{
+			match out.last_mut()
+			{
+			Some(l) if s <= l.1 => { l.1 = usize::max(l.1, e); },
+			_ => out.push( (s, e) ),
+			}
+		}
+		self.ranges = out;
+	}
+	fn is_complete(&self) -> bool
+	{
+		match self.total_len
+		{
+		Some(len) => self.ranges.len() == 1 && self.ranges[0] == (0, len),
+		None => false,
+		}
+	}
+}
+
+/// Add a fragment to the reassembly table, returning the complete datagram once all fragments have been seen
+///
+/// `ofs` is the byte offset of this fragment, and `reader` must be limited to the fragment's data.
+pub fn add_fragment(key: Key, ofs: usize, more_fragments: bool, mut reader: crate::nic::PacketReader) -> Option<Vec<u8>>
+{
+	let len = reader.remain();
+	let end = ofs + len;
+	if end > MAX_DATAGRAM_SIZE {
+		log_warning!("Fragment {:?} extends past the maximum datagram size ({}+{})", key, ofs, len);
+		return None;
+	}
+	if more_fragments && len % 8 != 0 {
+		log_warning!("Fragment {:?} isn't a multiple of 8 bytes ({}) and isn't the last", key, len);
+		return None;
+	}
+
+	let now = ::kernel::time::ticks();
+	let mut lh = TABLE.lock();
+	lh.purge_expired(now);
+
+	if !lh.entries.get(&key).is_some() {
+		lh.entries.insert(key, Entry {
+			expiry: now + REASSEMBLY_TIMEOUT_MS,
+			data: Vec::new(),
+			ranges: Vec::new(),
+			total_len: None,
+			});
+	}
+	// Grow the buffer to fit this fragment (evicting other datagrams if the cap would be exceeded)
+	let cur_size = lh.entries.get(&key).unwrap().data.len();
+	if end > cur_size
+	{
+		let growth = end - cur_size;
+		if !lh.make_space(growth, &key) {
+			log_notice!("Reassembly table full, dropping {:?}", key);
+			let e = lh.entries.remove(&key).unwrap();
+			lh.total_bytes -= e.data.len();
+			return None;
+		}
+		lh.total_bytes += growth;
+		lh.entries.get_mut(&key).unwrap().data.resize(end, 0);
+	}
+
+	let rv = {
+		let e = lh.entries.get_mut(&key).unwrap();
+		if !more_fragments
+		{
+			if e.total_len.map(|l| l != end).unwrap_or(false) {
+				log_warning!("Fragment {:?} has inconsistent final length ({:?} != {})", key, e.total_len, end);
+			}
+			e.total_len = Some(end);
+		}
+		match e.total_len
+		{
+		Some(total) if end > total => {
+			log_warning!("Fragment {:?} extends past the final fragment ({} > {})", key, end, total);
+			return None;
+			},
+		_ => {},
+		}
+		reader.read(&mut e.data[ofs..end]).ok()?;
+		e.add_range(ofs, end);
+		e.is_complete()
+		};
+	if rv
+	{
+		let mut e = lh.entries.remove(&key).unwrap();
+		lh.total_bytes -= e.data.len();
+		e.data.truncate(e.total_len.unwrap());
+		Some(e.data)
+	}
+	else
+	{
+		None
+	}
+}
+
+impl Table
+{
+	fn purge_expired(&mut self, now: ::kernel::time::TickCount)
+	{
+		loop
+		{
+			let k = match self.entries.iter().find(|(_,e)| e.expiry <= now)
+				{
+				Some((k,_)) => *k,
+				None => break,
+				};
+			log_debug!("Reassembly of {:?} timed out", k);
+			// TODO: Send an ICMP "time exceeded" if the first fragment was seen
+			let e = self.entries.remove(&k).unwrap();
+			self.total_bytes -= e.data.len();
+		}
+	}
+	/// Evict the oldest entries (other than `keep`) until `size` more bytes can be held
+	fn make_space(&mut self, size: usize, keep: &Key) -> bool
+	{
+		if size > MAX_TOTAL_BYTES {
+			return false;
+		}
+		while self.total_bytes + size > MAX_TOTAL_BYTES
+		{
+			let k = match self.entries.iter().filter(|(k,_)| *k != keep).min_by_key(|(_,e)| e.expiry)
+				{
+				Some((k,_)) => *k,
+				None => return false,
+				};
+			log_debug!("Evicting {:?} from the reassembly table", k);
+			let e = self.entries.remove(&k).unwrap();
+			self.total_bytes -= e.data.len();
+		}
+		true
+	}
+}
+
+/// A reassembled datagram, in a form that can be passed to the protocol handlers
+pub struct OwnedPacket(pub Vec<u8>);
+impl crate::nic::RxPacket for OwnedPacket
+{
+	fn len(&self) -> usize {
+		self.0.len()
+	}
+	fn num_regions(&self) -> usize {
+		1
+	}
+	fn get_region(&self, idx: usize) -> &[u8] {
+		assert!(idx == 0);
+		&self.0
+	}
+	fn get_slice(&self, range: ::core::ops::Range<usize>) -> Option<&[u8]> {
+		self.0.get(range)
+	}
+}
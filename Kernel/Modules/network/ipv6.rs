@@ -0,0 +1,429 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/ipv6.rs
+//! IPv6 (Layer 3)
+use kernel::lib::Vec;
+use kernel::sync::RwLock;
+use crate::nic::MacAddr;
+use crate::nic::PacketReader;
+
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+const NEXT_HOP_BY_HOP: u8 = 0;
+const NEXT_ROUTING: u8 = 43;
+const NEXT_FRAGMENT: u8 = 44;
+const NEXT_NONE: u8 = 59;
+const NEXT_DEST_OPTIONS: u8 = 60;
+
+/// Default hop limit for outgoing packets
+const DEFAULT_HOP_LIMIT: u8 = 64;
+
+static PROTOCOLS: RwLock<Vec<(u8, fn(&Interface, &PacketInfo, PacketReader))>> = RwLock::new(Vec::new());
+static INTERFACES: RwLock<Vec<Interface>> = RwLock::new(Vec::new());
+
+/// Add an address to a NIC
+pub fn add_interface(local_mac: MacAddr, addr: Address, prefix_len: u8)
+{
+	log_notice!("Adding IPv6 interface {:?}/{} on {:x?}", addr, prefix_len, local_mac);
+	INTERFACES.write().push(Interface {
+		local_mac: local_mac,
+		address: addr,
+		prefix_len: prefix_len,
+		});
+}
+/// Remove an address from a NIC
+pub fn remove_interface(local_mac: MacAddr, addr: Address)
+{
+	INTERFACES.write().retain(|i| !(i.local_mac == local_mac && i.address == addr));
+}
+/// Assign the link-local address (derived from the MAC address) to a NIC
+///
+/// TODO: Duplicate address detection
+pub fn add_link_local(local_mac: MacAddr)
+{
+	add_interface(local_mac, Address::link_local_from_mac(local_mac), 64);
+}
+/// Remove all addresses assigned to a NIC
+pub fn remove_all(local_mac: MacAddr)
+{
+	INTERFACES.write().retain(|i| i.local_mac != local_mac);
+}
+/// Check if an address is assigned to the specified NIC
+pub fn has_address(local_mac: MacAddr, addr: Address) -> bool
+{
+	INTERFACES.read().iter().any(|i| i.local_mac == local_mac && i.address == addr)
+}
+
+pub fn register_handler(next_header: u8, handler: fn(&Interface, &PacketInfo, PacketReader)) -> Result<(), ()>
+{
+	let mut lh = PROTOCOLS.write();
+	for &(p, _) in lh.iter()
+	{
+		if p == next_header {
+			return Err( () );
+		}
+	}
+	lh.push( (next_header, handler) );
+	Ok( () )
+}
+
+/// Addressing information for a received packet
+pub struct PacketInfo
+{
+	pub source: Address,
+	/// Destination address (can be a multicast address)
+	pub destination: Address,
+	pub hop_limit: u8,
+}
+
+pub fn handle_rx_ethernet(_physical_interface: &dyn crate::nic::Interface, local_mac: MacAddr, _source_mac: MacAddr, mut reader: PacketReader) -> Result<(), ()>
+{
+	let hdr = match Ipv6Header::read(&mut reader)
+		{
+		Ok(v) => v,
+		Err(_) => {
+			log_warning!("Undersized packet: Ran out of data reading header");
+			return Err( () );
+			},
+		};
+	if hdr.ver_tc_fl >> 28 != 6 {
+		log_warning!("Malformed packet: version isn't 6 - {:08x}", hdr.ver_tc_fl);
+		return Err( () );
+	}
+	if reader.remain() < hdr.payload_length as usize {
+		log_warning!("Undersized packet: {} bytes after header, payload length is {}", reader.remain(), hdr.payload_length);
+		return Err( () );
+	}
+	// Drop any link-layer padding
+	reader.truncate(hdr.payload_length as usize);
+
+	// Skip extension headers
+	let mut next_header = hdr.next_header;
+	loop
+	{
+		match next_header
+		{
+		NEXT_HOP_BY_HOP | NEXT_DEST_OPTIONS | NEXT_ROUTING => {
+			let nh = reader.read_u8()?;
+			let len = (reader.read_u8()? as usize + 1) * 8;
+			if next_header == NEXT_ROUTING {
+				// Routing headers with segments left would need forwarding, which a host doesn't do
+				let _ty = reader.read_u8()?;
+				let segments_left = reader.read_u8()?;
+				if segments_left != 0 {
+					log_debug!("Routing header with {} segments left, dropping", segments_left);
+					return Ok( () );
+				}
+				for _ in 4 .. len { reader.read_u8()?; }
+			}
+			else {
+				// TODO: Handle options that require a response (the high bits of the option type)
+				for _ in 2 .. len { reader.read_u8()?; }
+			}
+			next_header = nh;
+			},
+		NEXT_FRAGMENT => {
+			// TODO: Fragment reassembly
+			log_notice!("Fragmented IPv6 packet from {:?}, dropping", hdr.source);
+			return Ok( () );
+			},
+		NEXT_NONE => return Ok( () ),
+		_ => break,
+		}
+	}
+
+	let info = PacketInfo {
+		source: hdr.source,
+		destination: hdr.destination,
+		hop_limit: hdr.hop_limit,
+		};
+	// Check destination against the addresses on this NIC (including the multicast groups that they imply)
+	let interfaces = INTERFACES.read();
+	let is_multicast = hdr.destination.is_multicast();
+	let interface = if !is_multicast {
+			interfaces.iter().find(|i| i.local_mac == local_mac && i.address == hdr.destination)
+		}
+		else {
+			let is_member = |i: &&Interface| i.local_mac == local_mac
+				&& (hdr.destination == Address::ALL_NODES || hdr.destination == i.address.solicited_node());
+			// Prefer an interface with the same scope as the sender (so replies come from a usable address)
+			interfaces.iter().filter(is_member).find(|i| i.address.is_link_local() == hdr.source.is_link_local())
+				.or_else(|| interfaces.iter().filter(is_member).next())
+		};
+	match interface
+	{
+	Some(interface) => {
+		for &(id, handler) in PROTOCOLS.read().iter()
+		{
+			if id == next_header
+			{
+				handler(interface, &info, reader);
+				return Ok( () );
+			}
+		}
+		log_debug!("Unknown next header {}", next_header);
+		if !is_multicast {
+			crate::icmpv6::send_unreachable(interface.address, hdr.source, next_header, crate::icmpv6::Unreachable::NextHeader, reader);
+		}
+		},
+	None => {
+		// This is a host, not a router - drop packets for other addresses
+		log_debug!("Packet didn't match any interfaces (A={:?}), dropping", hdr.destination);
+		},
+	}
+	Ok( () )
+}
+
+/// Determine how to reach `dest`, returning the local address, interface MAC, and next-hop address
+///
+/// Only on-link destinations (link-local, or within an interface's prefix) are reachable.
+// TODO: Default routers from router advertisements
+pub fn route_lookup(source: Address, dest: Address) -> Option<(Address, MacAddr, Address)>
+{
+	let interfaces = INTERFACES.read();
+	let candidates = interfaces.iter()
+		.filter(|i| source.is_zero() || i.address == source);
+	if dest.is_link_local() || dest.is_multicast() {
+		// TODO: Link-local addresses are ambiguous with multiple NICs, should take a scope/NIC
+		candidates.filter(|i| i.address.is_link_local())
+			.next()
+			.map(|i| (i.address, i.local_mac, dest))
+	}
+	else {
+		candidates.filter(|i| !i.address.is_link_local())
+			.filter(|i| i.address.mask(i.prefix_len) == dest.mask(i.prefix_len))
+			.max_by_key(|i| i.prefix_len)
+			.map(|i| (i.address, i.local_mac, dest))
+	}
+}
+
+pub async fn send_packet(source: Address, dest: Address, next_header: u8, pkt: crate::nic::SparsePacket<'_>)
+{
+	log_trace!("send_packet({:?} -> {:?} {})", source, dest, next_header);
+	// 1. Look up routing table for destination IP and interface
+	let (source, interface_mac, next_hop) = match route_lookup(source, dest)
+		{
+		Some(v) => v,
+		None => {
+			log_notice!("Unable to send to {:?}: No route", dest);
+			return	// TODO: Error - No route to host
+			},
+		};
+	// 2. Neighbour discovery (multicast addresses map directly to a MAC)
+	let dest_mac = if dest.is_multicast() {
+			dest.multicast_mac()
+		}
+		else {
+			match crate::ndp::resolve(interface_mac, source, next_hop).await
+			{
+			Some(v) => v,
+			None => {
+				log_notice!("Unable to send to {:?}: No neighbour entry", dest);
+				return
+				},
+			}
+		};
+	// 3. Send
+	send_on_interface(interface_mac, dest_mac, source, dest, next_header, DEFAULT_HOP_LIMIT, pkt);
+}
+
+/// Send a packet with an explicit link-layer destination and hop limit (used by neighbour discovery)
+pub(crate) fn send_on_interface(interface_mac: MacAddr, dest_mac: MacAddr, source: Address, dest: Address, next_header: u8, hop_limit: u8, pkt: crate::nic::SparsePacket<'_>)
+{
+	let mtu = crate::nic::get_mtu(interface_mac).unwrap_or(crate::nic::DEFAULT_MTU);
+	if 40 + pkt.total_len() > mtu {
+		// TODO: Fragmentation (only done by the source in IPv6)
+		log_notice!("Unable to send to {:?}: {} bytes exceeds the MTU ({})", dest, 40 + pkt.total_len(), mtu);
+		return ;
+	}
+	let hdr = Ipv6Header {
+		ver_tc_fl: 6 << 28,
+		payload_length: pkt.total_len() as u16,
+		next_header: next_header,
+		hop_limit: hop_limit,
+		source: source,
+		destination: dest,
+		};
+	let hdr_bytes = hdr.encode();
+	crate::nic::send_from(interface_mac, dest_mac, ETHERTYPE_IPV6, crate::nic::SparsePacket::new_chained(&hdr_bytes, &pkt));
+}
+
+/// Build a header describing a received packet (for inclusion in ICMPv6 errors)
+pub(crate) fn reconstruct_header(source: Address, dest: Address, next_header: u8, payload_length: usize) -> [u8; 40]
+{
+	Ipv6Header {
+		ver_tc_fl: 6 << 28,
+		payload_length: payload_length.min(0xFFFF) as u16,
+		next_header: next_header,
+		hop_limit: 0,
+		source: source,
+		destination: dest,
+		}.encode()
+}
+
+struct Ipv6Header
+{
+	/// Version (4 bits), traffic class (8 bits), flow label (20 bits)
+	ver_tc_fl: u32,
+	payload_length: u16,
+	next_header: u8,
+	hop_limit: u8,
+	source: Address,
+	destination: Address,
+}
+impl Ipv6Header
+{
+	fn read(reader: &mut PacketReader) -> Result<Self, ()>
+	{
+		Ok(Ipv6Header {
+			ver_tc_fl: u32::from_be_bytes(reader.read_bytes([0; 4])?),
+			payload_length: reader.read_u16n()?,
+			next_header: reader.read_u8()?,
+			hop_limit: reader.read_u8()?,
+			source: Address(reader.read_bytes([0; 16])?),
+			destination: Address(reader.read_bytes([0; 16])?),
+			})
+	}
+	fn encode(&self) -> [u8; 40]
+	{
+		let mut rv = [0; 40];
+		rv[0..4].copy_from_slice(&self.ver_tc_fl.to_be_bytes());
+		rv[4..6].copy_from_slice(&self.payload_length.to_be_bytes());
+		rv[6] = self.next_header;
+		rv[7] = self.hop_limit;
+		rv[8..24].copy_from_slice(&self.source.0);
+		rv[24..40].copy_from_slice(&self.destination.0);
+		rv
+	}
+}
+
+#[derive(Copy,Clone,Default,PartialEq,PartialOrd,Eq,Ord)]
+pub struct Address(pub [u8; 16]);
+impl ::core::fmt::Display for Address
+{
+	/// Formats using the RFC 5952 canonical form (lower-case, longest run of zero groups replaced by `::`)
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result
+	{
+		let words = self.words();
+		// Find the longest run (of at least two) zero groups
+		let mut best = (0, 0);
+		let mut i = 0;
+		while i < 8
+		{
+			if words[i] == 0 {
+				let start = i;
+				while i < 8 && words[i] == 0 { i += 1; }
+				if i - start > best.1 - best.0 && i - start > 1 {
+					best = (start, i);
+				}
+			}
+			else {
+				i += 1;
+			}
+		}
+		let write_groups = |f: &mut ::core::fmt::Formatter, groups: &[u16]| -> ::core::fmt::Result {
+			for (i,w) in groups.iter().enumerate()
+			{
+				if i > 0 {
+					f.write_str(":")?;
+				}
+				write!(f, "{:x}", w)?;
+			}
+			Ok( () )
+			};
+		if best.1 > best.0 {
+			write_groups(f, &words[..best.0])?;
+			f.write_str("::")?;
+			write_groups(f, &words[best.1..])
+		}
+		else {
+			write_groups(f, &words)
+		}
+	}
+}
+impl ::core::fmt::Debug for Address {
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		::core::fmt::Display::fmt(self, f)
+	}
+}
+impl Address
+{
+	/// All-nodes link-local multicast address (ff02::1)
+	pub const ALL_NODES: Address = Address([0xFF,0x02, 0,0, 0,0, 0,0, 0,0, 0,0, 0,0, 0,1]);
+
+	pub fn zero() -> Self {
+		Address([0; 16])
+	}
+	pub fn from_words(w: [u16; 8]) -> Self {
+		let mut rv = [0; 16];
+		for i in 0 .. 8 {
+			rv[i*2..][..2].copy_from_slice(&w[i].to_be_bytes());
+		}
+		Address(rv)
+	}
+	/// Big-endian 16-bit groups
+	pub fn words(&self) -> [u16; 8] {
+		let mut rv = [0; 8];
+		for i in 0 .. 8 {
+			rv[i] = u16::from_be_bytes([self.0[i*2], self.0[i*2+1]]);
+		}
+		rv
+	}
+	/// Link-local address with an interface identifier derived from the MAC address (modified EUI-64)
+	pub fn link_local_from_mac(mac: MacAddr) -> Self {
+		Address([
+			0xFE,0x80, 0,0, 0,0, 0,0,
+			mac[0] ^ 0x02, mac[1], mac[2], 0xFF, 0xFE, mac[3], mac[4], mac[5],
+			])
+	}
+	/// Clear all but the first `bits` bits of the address
+	pub fn mask(&self, bits: u8) -> Address {
+		assert!(bits <= 128);
+		let mut rv = *self;
+		for (i,b) in rv.0.iter_mut().enumerate()
+		{
+			let start = i as u8 * 8;
+			if bits <= start {
+				*b = 0;
+			}
+			else if bits < start + 8 {
+				*b &= !(0xFF >> (bits - start));
+			}
+		}
+		rv
+	}
+	pub fn is_zero(&self) -> bool {
+		self.0 == [0; 16]
+	}
+	pub fn is_multicast(&self) -> bool {
+		self.0[0] == 0xFF
+	}
+	/// Link-local unicast (fe80::/10)
+	pub fn is_link_local(&self) -> bool {
+		self.0[0] == 0xFE && self.0[1] & 0xC0 == 0x80
+	}
+	/// Solicited-node multicast address for this address (ff02::1:ffXX:XXXX)
+	pub fn solicited_node(&self) -> Address {
+		Address([0xFF,0x02, 0,0, 0,0, 0,0, 0,0, 0,1, 0xFF, self.0[13], self.0[14], self.0[15]])
+	}
+	/// Ethernet address for a multicast address (33:33 followed by the low 32 bits)
+	pub fn multicast_mac(&self) -> MacAddr {
+		[0x33, 0x33, self.0[12], self.0[13], self.0[14], self.0[15]]
+	}
+}
+pub struct Interface
+{
+	local_mac: MacAddr,
+	address: Address,
+	prefix_len: u8,
+}
+impl Interface
+{
+	pub fn addr(&self) -> Address {
+		self.address
+	}
+	pub fn local_mac(&self) -> MacAddr {
+		self.local_mac
+	}
+}
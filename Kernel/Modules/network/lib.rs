@@ -17,26 +17,68 @@ module_define!{Network, [], init}
 
 pub mod nic;
 pub mod tcp;
+pub mod udp;
 pub mod arp;
+pub mod icmp;
 pub mod ipv4;
-//pub mod ipv6;
+pub mod dhcp;
+pub mod ipv6;
+pub mod ndp;
+pub mod icmpv6;
+
+mod port_pool;
 
 fn init()
 {
 	crate::tcp::init();
+	crate::udp::init();
+	crate::icmp::init();
+	crate::icmpv6::init();
 }
 
 #[derive(Copy,Clone,PartialOrd,PartialEq,Ord,Eq,Debug)]
 pub enum Address
 {
 	Ipv4(crate::ipv4::Address),
+	Ipv6(crate::ipv6::Address),
 }
 impl Address
 {
-	fn unwrap_ipv4(&self) -> crate::ipv4::Address {
+	/// Returns true for the unspecified ("any") address
+	pub fn is_zero(&self) -> bool {
+		match self {
+		&Address::Ipv4(v) => v.is_zero(),
+		&Address::Ipv6(v) => v.is_zero(),
+		}
+	}
+	/// Raw address bytes (network order)
+	pub fn as_bytes(&self) -> &[u8] {
 		match self {
-		&Address::Ipv4(v) => v,
+		Address::Ipv4(v) => &v.0,
+		Address::Ipv6(v) => &v.0,
 		}
 	}
 }
 
+/// Checksum of the pseudo-header used by the TCP/UDP/ICMPv6 checksums (inverted, as with `ipv4::calculate_checksum`)
+fn pseudo_header_checksum(src: Address, dst: Address, proto: u8, len: usize) -> u16
+{
+	use crate::ipv4::calculate_checksum;
+	match (src, dst)
+	{
+	(Address::Ipv4(s), Address::Ipv4(d)) =>
+		calculate_checksum([
+			// Big endian stores MSB first, so write the high word first
+			(s.as_u32() >> 16) as u16, (s.as_u32() >> 0) as u16,
+			(d.as_u32() >> 16) as u16, (d.as_u32() >> 0) as u16,
+			proto as u16, len as u16,
+			].iter().copied()),
+	(Address::Ipv6(s), Address::Ipv6(d)) =>
+		calculate_checksum(
+			s.words().iter().chain(d.words().iter()).copied()
+				.chain([ (len >> 16) as u16, len as u16, 0, proto as u16 ].iter().copied())
+			),
+	_ => panic!("pseudo_header_checksum: Mismatched address families {:?} and {:?}", src, dst),
+	}
+}
+
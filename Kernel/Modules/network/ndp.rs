@@ -0,0 +1,172 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/ndp.rs
+//! Neighbor Discovery Protocol (IPv6 address resolution, RFC 4861)
+use kernel::sync::RwLock;
+use kernel::lib::VecMap;
+use crate::nic::MacAddr;
+use crate::ipv6::Address;
+
+pub(crate) const TYPE_NEIGHBOR_SOLICITATION: u8 = 135;
+pub(crate) const TYPE_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+const OPT_SOURCE_LINK_ADDR: u8 = 1;
+const OPT_TARGET_LINK_ADDR: u8 = 2;
+
+const FLAG_SOLICITED: u8 = 0x40;
+const FLAG_OVERRIDE: u8 = 0x20;
+
+/// Number of solicitations sent before giving up (MAX_MULTICAST_SOLICIT)
+const MAX_SOLICIT: usize = 3;
+/// Time between solicitations (RETRANS_TIMER)
+const RETRANS_TIMER_MS: u64 = 1000;
+
+static CACHE: RwLock<VecMap<Address, MacAddr>> = RwLock::new(VecMap::new());
+static SLEEPERS: ::kernel::futures::Condvar = ::kernel::futures::Condvar::new();
+
+/// Handle a neighbour solicitation or advertisement (the ICMPv6 header has been consumed)
+pub(crate) fn handle_packet(interface: &crate::ipv6::Interface, info: &crate::ipv6::PacketInfo, ty: u8, mut r: crate::nic::PacketReader)
+{
+	// Messages that could have been forwarded are invalid
+	if info.hop_limit != 255 {
+		log_debug!("NDP from {:?} with hop limit {}, ignoring", info.source, info.hop_limit);
+		return ;
+	}
+	let target = match r.read_bytes([0; 16])
+		{
+		Ok(v) => Address(v),
+		Err(_) => { log_debug!("NDP from {:?} truncated", info.source); return },
+		};
+	if target.is_multicast() {
+		return ;
+	}
+	let mut link_addr = None;
+	while r.remain() >= 8
+	{
+		let opt_ty = r.read_u8().unwrap();
+		let opt_len = r.read_u8().unwrap() as usize * 8;
+		if opt_len == 0 {
+			log_debug!("NDP option with zero length from {:?}", info.source);
+			return ;
+		}
+		match opt_ty
+		{
+		OPT_SOURCE_LINK_ADDR | OPT_TARGET_LINK_ADDR if opt_len == 8 => {
+			link_addr = Some( (opt_ty, r.read_bytes([0; 6]).unwrap()) );
+			},
+		_ => {
+			for _ in 2 .. opt_len {
+				if r.read_u8().is_err() { return ; }
+			}
+			},
+		}
+	}
+
+	match ty
+	{
+	TYPE_NEIGHBOR_SOLICITATION => {
+		if !crate::ipv6::has_address(interface.local_mac(), target) {
+			return ;
+		}
+		log_debug!("NDP solicitation for {:?} from {:?}", target, info.source);
+		let reply_to = if info.source.is_zero() {
+				// Duplicate address detection, reply to all nodes
+				Address::ALL_NODES
+			}
+			else {
+				if let Some( (OPT_SOURCE_LINK_ADDR, mac) ) = link_addr {
+					snoop(info.source, mac);
+				}
+				info.source
+			};
+		let flags = if reply_to.is_multicast() { FLAG_OVERRIDE } else { FLAG_SOLICITED|FLAG_OVERRIDE };
+		let mac = interface.local_mac();
+		let mut body = [0u8; 16 + 8];
+		body[..16].copy_from_slice(&target.0);
+		body[16] = OPT_TARGET_LINK_ADDR;
+		body[17] = 1;
+		body[18..24].copy_from_slice(&mac);
+		send(interface.local_mac(), target, reply_to, TYPE_NEIGHBOR_ADVERTISEMENT, [flags, 0, 0, 0], &body);
+		},
+	TYPE_NEIGHBOR_ADVERTISEMENT => {
+		if let Some( (OPT_TARGET_LINK_ADDR, mac) ) = link_addr {
+			log_debug!("NDP advertisement: {:?} = {:x?}", target, mac);
+			snoop(target, mac);
+		}
+		},
+	_ => {},
+	}
+}
+
+/// Inform the neighbour cache of an observed mapping
+fn snoop(addr: Address, mac: MacAddr)
+{
+	let mut lh = CACHE.write();
+	if lh.get(&addr) != Some(&mac)
+	{
+		lh.insert(addr, mac);
+		SLEEPERS.wake_all();
+	}
+}
+
+/// Acquire a MAC address for the given (on-link) address
+pub async fn resolve(interface_mac: MacAddr, source: Address, addr: Address) -> Option<MacAddr>
+{
+	if let Some(v) = CACHE.read().get(&addr) {
+		return Some(*v);
+	}
+	for _ in 0 .. MAX_SOLICIT
+	{
+		log_debug!("Sending neighbour solicitation for {:?} from {:?}", addr, interface_mac);
+		let mut body = [0u8; 16 + 8];
+		body[..16].copy_from_slice(&addr.0);
+		body[16] = OPT_SOURCE_LINK_ADDR;
+		body[17] = 1;
+		body[18..24].copy_from_slice(&interface_mac);
+		send(interface_mac, source, addr.solicited_node(), TYPE_NEIGHBOR_SOLICITATION, [0; 4], &body);
+
+		// - Wait until the cache has the requested host in it (with timeout)
+		let timeout_time = ::kernel::time::ticks() + RETRANS_TIMER_MS;
+		loop
+		{
+			// Get condvar key, then check if the address is present, THEN wait until the key changes
+			let key = SLEEPERS.get_key();
+			if let Some(v) = CACHE.read().get(&addr) {
+				return Some(*v);
+			}
+			let sleep_duration = match timeout_time.checked_sub(::kernel::time::ticks())
+				{
+				None | Some(0) => break,
+				Some(v) => v,
+				};
+			::kernel::futures::join_one(
+				SLEEPERS.wait(key),
+				::kernel::futures::msleep(sleep_duration as usize)
+				).await;
+		}
+	}
+	None
+}
+
+fn send(interface_mac: MacAddr, source: Address, dest: Address, ty: u8, rest: [u8; 4], body: &[u8])
+{
+	let hdr = crate::icmpv6::encode_header(source, dest, ty, 0, rest, body);
+	let body_pkt = crate::nic::SparsePacket::new_root(body);
+	let pkt = crate::nic::SparsePacket::new_chained(&hdr, &body_pkt);
+	let dest_mac = if dest.is_multicast() {
+			dest.multicast_mac()
+		}
+		else {
+			match CACHE.read().get(&dest)
+			{
+			Some(v) => *v,
+			None => {
+				// Solicitations carry the sender's link address, so this only happens for unsolicited traffic
+				log_notice!("NDP: No link address for {:?}, not sending", dest);
+				return ;
+				},
+			}
+		};
+	crate::ipv6::send_on_interface(interface_mac, dest_mac, source, dest, crate::icmpv6::IPV6_NEXT_ICMPV6, 255, pkt);
+}
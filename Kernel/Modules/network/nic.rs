@@ -98,18 +98,30 @@ pub trait RxPacket
 pub struct PacketReader<'a> {
 	pkt: &'a PacketHandle<'a>,
 	ofs: usize,
+	/// Offset of the end of the readable region (can be less than the packet length, e.g. for padding)
+	end: usize,
 }
 impl<'a> PacketReader<'a> {
-	fn new(pkt: &'a PacketHandle<'a>) -> PacketReader<'a> {
+	pub(crate) fn new(pkt: &'a PacketHandle<'a>) -> PacketReader<'a> {
 		PacketReader {
 			pkt: pkt,
 			ofs: 0,
+			end: pkt.len(),
 			}
 	}
 	pub fn remain(&self) -> usize {
-		self.pkt.len() - self.ofs
+		self.end - self.ofs
+	}
+	/// Restrict the reader to the next `len` bytes (e.g. to exclude link-layer padding)
+	pub fn truncate(&mut self, len: usize) {
+		if len < self.remain() {
+			self.end = self.ofs + len;
+		}
 	}
 	pub fn read(&mut self, dst: &mut [u8]) -> Result<usize, ()> {
+		if self.ofs >= self.end && dst.len() > 0 {
+			return Err( () );
+		}
 		// TODO: Should this be cached?
 		let mut ofs = self.ofs;
 		let mut r = 0;
@@ -122,11 +134,11 @@ impl<'a> PacketReader<'a> {
 		}
 
 		let mut wofs = 0;
-		while wofs < dst.len() && self.ofs + wofs < self.pkt.len()
+		while wofs < dst.len() && self.ofs + wofs < self.end
 		{
 			let rgn = self.pkt.get_region(r);
 			let alen = rgn.len() - ofs;
-			let rlen = dst.len() - wofs;
+			let rlen = ::core::cmp::min(dst.len() - wofs, self.end - (self.ofs + wofs));
 			let len = ::core::cmp::min(alen, rlen);
 
 			dst[wofs..][..len].copy_from_slice( &rgn[ofs..][..len] );
@@ -157,13 +169,19 @@ impl<'a> PacketReader<'a> {
 	pub fn read_u32n(&mut self) -> Result<u32, ()> {
 		let mut b = [0,0,0,0];
 		self.read(&mut b)?;
-		Ok( (b[0] as u32) << 24 + (b[1] as u32) << 16 | (b[2] as u32) << 8 | (b[3] as u32) )
+		Ok( (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | (b[3] as u32) )
 	}
 }
 
+/// Default MTU for an interface (the ethernet payload size)
+pub const DEFAULT_MTU: usize = 1500;
+
 /// Network interface API
 pub trait Interface: 'static + Send + Sync
 {
+	/// Maximum size of a frame's payload (excluding the ethernet header)
+	fn mtu(&self) -> usize { DEFAULT_MTU }
+
 	/// Transmit a raw packet (blocking)
 	fn tx_raw(&self, pkt: SparsePacket);
 
@@ -220,6 +238,22 @@ pub fn send_from(local_addr: MacAddr, dest_addr: MacAddr, ether_ty: u16, pkt: Sp
 	}
 }
 
+/// Get the MTU of the interface with the specified MAC address
+pub fn get_mtu(local_addr: MacAddr) -> Option<usize>
+{
+	for i in INTERFACES_LIST.lock().iter()
+	{
+		if let Some(v) = i
+		{
+			if v.data.addr == local_addr
+			{
+				return Some(v.data.base_interface.mtu());
+			}
+		}
+	}
+	None
+}
+
 /// Handle to a registered interface
 pub struct Registration<T> {
 	// Logically owns the `T`
@@ -233,6 +267,8 @@ impl<T> Drop for Registration<T> {
 		let mut lh = INTERFACES_LIST.lock();
 		assert!( self.index < lh.len() );
 		if let Some(ref mut int_ent) = lh[self.index] {
+			crate::dhcp::stop(int_ent.data.addr);
+			crate::ipv6::remove_all(int_ent.data.addr);
 			int_ent.data.stop_flag.store(true, Ordering::SeqCst);
 			int_ent.data.sleep_object_ref.lock().take().unwrap().signal();
 			int_ent.thread.wait().expect("Couldn't wait for NIC worker to terminate");
@@ -307,7 +343,13 @@ pub fn register<T: Interface>(mac_addr: [u8; 6], int: T) -> Registration<T> {
 		return list.len() - 1;
 	}
 	let idx = insert_opt(&mut INTERFACES_LIST.lock(), reg);
-	
+
+	// Interfaces without a static address are configured using DHCP
+	if !crate::ipv4::has_interface(mac_addr) {
+		crate::dhcp::start(mac_addr);
+	}
+	crate::ipv6::add_link_local(mac_addr);
+
 	Registration {
 		pd: ::core::marker::PhantomData,
 		index: idx,
@@ -351,13 +393,20 @@ fn rx_thread(int_data: &InterfaceData)
 				let ether_ty = r.read_u16n().unwrap();
 				match ether_ty
 				{
-				0x0800 => match crate::ipv4::handle_rx_ethernet(&*int_data.base_interface, src_mac, r)
+				0x0800 => match crate::ipv4::handle_rx_ethernet(&*int_data.base_interface, int_data.addr, src_mac, r)
 					{
 					Ok( () ) => {},
 					Err(e) => {
 						log_warning!("TODO: Unable to handle IPv4 packet - {:?}", e);
 						},
 					}
+				0x86DD => match crate::ipv6::handle_rx_ethernet(&*int_data.base_interface, int_data.addr, src_mac, r)
+					{
+					Ok( () ) => {},
+					Err(e) => {
+						log_warning!("TODO: Unable to handle IPv6 packet - {:?}", e);
+						},
+					}
 				// ARP
 				0x0806 => {
 					crate::arp::handle_packet(&*int_data.base_interface, src_mac, r);
@@ -0,0 +1,77 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/port_pool.rs
+//! Dynamic (ephemeral) port allocation, shared by the layer 4 protocols
+
+const MIN_DYN_PORT: u16 = 0xC000;
+const N_DYN_PORTS: usize = (1<<16) - MIN_DYN_PORT as usize;
+pub struct PortPool {
+	bitmap: [u32; N_DYN_PORTS / 32],
+}
+impl PortPool
+{
+	pub const fn new() -> PortPool
+	{
+		PortPool {
+			bitmap: [0; N_DYN_PORTS / 32],
+			}
+	}
+
+	fn ofs_mask(idx: u16) -> Option<(usize, u32)>
+	{
+		if idx >= MIN_DYN_PORT
+		{
+			let ofs = (idx - MIN_DYN_PORT) as usize / 32;
+			let mask  = 1 << (idx % 32);
+			Some( (ofs, mask) )
+		}
+		else
+		{
+			None
+		}
+	}
+	pub fn take(&mut self, idx: u16) -> Result<(),()>
+	{
+		let (ofs,mask) = match Self::ofs_mask(idx)
+			{
+			Some(v) => v,
+			None => return Ok(()),
+			};
+		if self.bitmap[ofs] & mask != 0 {
+			Err( () )
+		}
+		else {
+			self.bitmap[ofs] |= mask;
+			Ok( () )
+		}
+	}
+	pub fn release(&mut self, idx: u16)
+	{
+		let (ofs,mask) = match Self::ofs_mask(idx)
+			{
+			Some(v) => v,
+			None => return,
+			};
+		self.bitmap[ofs] &= !mask;
+	}
+	/// Returns true if no dynamic ports are in use
+	pub fn is_empty(&self) -> bool
+	{
+		self.bitmap.iter().all(|&w| w == 0)
+	}
+	/// Allocate a free port, searching from a random start point (RFC 6056 "Simple Port Randomization")
+	pub fn allocate(&mut self) -> Option<u16>
+	{
+		let start = ::kernel::rand::get_u32() as usize % N_DYN_PORTS;
+		for i in 0 .. N_DYN_PORTS
+		{
+			let idx = MIN_DYN_PORT + ((start + i) % N_DYN_PORTS) as u16;
+			if self.take(idx).is_ok() {
+				return Some(idx);
+			}
+		}
+		None
+	}
+}
+
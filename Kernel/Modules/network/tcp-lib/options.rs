@@ -0,0 +1,186 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/tcp-lib/options.rs
+//! TCP header options (MSS, window scaling, SACK and timestamps)
+
+const KIND_END: u8 = 0;
+const KIND_NOP: u8 = 1;
+const KIND_MSS: u8 = 2;
+const KIND_WINDOW_SCALE: u8 = 3;
+const KIND_SACK_PERMITTED: u8 = 4;
+const KIND_SACK: u8 = 5;
+const KIND_TIMESTAMP: u8 = 8;
+
+/// Maximum length of the options in a TCP header
+pub const MAX_LEN: usize = 40;
+/// Largest window scale shift allowed (RFC 7323 section 2.3)
+pub const MAX_WINDOW_SCALE: u8 = 14;
+/// Most SACK blocks that can be carried in a header
+pub const MAX_SACK_BLOCKS: usize = 4;
+
+/// Options from a received segment
+#[derive(Default,Debug)]
+pub struct Options
+{
+	pub mss: Option<u16>,
+	pub window_scale: Option<u8>,
+	pub sack_permitted: bool,
+	sack_blocks: [(u32, u32); MAX_SACK_BLOCKS],
+	sack_count: usize,
+	/// Timestamp value and echo reply
+	pub timestamp: Option<(u32, u32)>,
+}
+impl Options
+{
+	/// Parse the options area of a header (stops at the first malformed option)
+	pub fn parse(mut data: &[u8]) -> Options
+	{
+		let mut rv = Options::default();
+		while let Some(&kind) = data.first()
+		{
+			match kind
+			{
+			KIND_END => break,
+			KIND_NOP => { data = &data[1..]; continue },
+			_ => {},
+			}
+			let len = match data.get(1)
+				{
+				Some(&l) if l >= 2 && l as usize <= data.len() => l as usize,
+				_ => {
+					log_debug!("Malformed TCP option {} (len {:?})", kind, data.get(1));
+					break
+					},
+				};
+			let value = &data[2..len];
+			let u16n = |o: usize| (value[o] as u16) << 8 | value[o+1] as u16;
+			let u32n = |o: usize| (u16n(o) as u32) << 16 | u16n(o+2) as u32;
+			match (kind, value.len())
+			{
+			(KIND_MSS, 2) => rv.mss = Some(u16n(0)),
+			(KIND_WINDOW_SCALE, 1) => rv.window_scale = Some(u8::min(value[0], MAX_WINDOW_SCALE)),
+			(KIND_SACK_PERMITTED, 0) => rv.sack_permitted = true,
+			(KIND_SACK, l) if l % 8 == 0 => {
+				for i in 0 .. usize::min(l / 8, MAX_SACK_BLOCKS) {
+					rv.sack_blocks[i] = (u32n(i*8), u32n(i*8+4));
+				}
+				rv.sack_count = usize::min(l / 8, MAX_SACK_BLOCKS);
+				},
+			(KIND_TIMESTAMP, 8) => rv.timestamp = Some( (u32n(0), u32n(4)) ),
+			_ => log_trace!("Ignoring TCP option {} ({} bytes)", kind, value.len()),
+			}
+			data = &data[len..];
+		}
+		rv
+	}
+
+	/// SACK blocks (start and end sequence numbers)
+	pub fn sack_blocks(&self) -> &[(u32, u32)]
+	{
+		&self.sack_blocks[..self.sack_count]
+	}
+}
+
+/// Options for an outgoing segment
+pub struct OptionsBuf
+{
+	data: [u8; MAX_LEN],
+	len: usize,
+}
+impl OptionsBuf
+{
+	pub fn new() -> OptionsBuf
+	{
+		OptionsBuf { data: [0; MAX_LEN], len: 0 }
+	}
+	pub fn as_slice(&self) -> &[u8]
+	{
+		&self.data[..self.len]
+	}
+	pub fn len(&self) -> usize
+	{
+		self.len
+	}
+
+	fn push(&mut self, bytes: &[u8])
+	{
+		self.data[self.len..][..bytes.len()].copy_from_slice(bytes);
+		self.len += bytes.len();
+	}
+
+	pub fn push_mss(&mut self, mss: u16)
+	{
+		let [a, b] = mss.to_be_bytes();
+		self.push(&[KIND_MSS, 4, a, b]);
+	}
+	pub fn push_window_scale(&mut self, shift: u8)
+	{
+		self.push(&[KIND_NOP, KIND_WINDOW_SCALE, 3, shift]);
+	}
+	pub fn push_sack_permitted(&mut self)
+	{
+		self.push(&[KIND_NOP, KIND_NOP, KIND_SACK_PERMITTED, 2]);
+	}
+	pub fn push_timestamp(&mut self, value: u32, echo: u32)
+	{
+		self.push(&[KIND_NOP, KIND_NOP, KIND_TIMESTAMP, 10]);
+		self.push(&value.to_be_bytes());
+		self.push(&echo.to_be_bytes());
+	}
+	/// Add as many of the SACK blocks as will fit, returns the number added
+	pub fn push_sack(&mut self, blocks: &[(u32, u32)]) -> usize
+	{
+		let count = usize::min(blocks.len(), (MAX_LEN - self.len).saturating_sub(4) / 8);
+		if count == 0 {
+			return 0;
+		}
+		self.push(&[KIND_NOP, KIND_NOP, KIND_SACK, (2 + count * 8) as u8]);
+		for &(start, end) in &blocks[..count] {
+			self.push(&start.to_be_bytes());
+			self.push(&end.to_be_bytes());
+		}
+		count
+	}
+}
+
+#[test]
+// Options written by `OptionsBuf` can be read back
+fn round_trip()
+{
+	let mut buf = OptionsBuf::new();
+	buf.push_mss(1400);
+	buf.push_window_scale(7);
+	buf.push_sack_permitted();
+	buf.push_timestamp(0x12345678, 0x9abcdef0);
+	assert_eq!(buf.len() % 4, 0);
+	let o = Options::parse(buf.as_slice());
+	assert_eq!(o.mss, Some(1400));
+	assert_eq!(o.window_scale, Some(7));
+	assert!(o.sack_permitted);
+	assert_eq!(o.timestamp, Some((0x12345678, 0x9abcdef0)));
+}
+
+#[test]
+// Only as many SACK blocks as fit are added (three with timestamps)
+fn sack_limit()
+{
+	let blocks = [(1,2), (3,4), (5,6), (7,8)];
+	let mut buf = OptionsBuf::new();
+	buf.push_timestamp(1, 2);
+	assert_eq!(buf.push_sack(&blocks), 3);
+	assert!(buf.len() <= MAX_LEN);
+	let o = Options::parse(buf.as_slice());
+	assert_eq!(o.sack_blocks(), &blocks[..3]);
+}
+
+#[test]
+// A truncated option stops parsing without panicking
+fn malformed()
+{
+	let o = Options::parse(&[KIND_MSS, 4, 0x05, 0xb4, KIND_WINDOW_SCALE, 10, 1]);
+	assert_eq!(o.mss, Some(1460));
+	assert_eq!(o.window_scale, None);
+	let o = Options::parse(&[KIND_NOP, KIND_MSS, 0]);
+	assert_eq!(o.mss, None);
+}
@@ -78,7 +78,8 @@ impl RxBuffer
 			let v = self.data[self.size..][self.read_pos/8] >> ofs;
 			(!v).trailing_zeros()
 			};
-		if len > 0
+		// Only continue into the following bitmap entries if the first one was valid to the end
+		if len > 0 && len as usize == 8 - self.read_pos % 8
 		{
 			for i in 1 .. self.size / 8
 			{
@@ -98,6 +99,25 @@ impl RxBuffer
 		}
 		len as usize
 	}
+	/// Total size of the buffer (valid data plus free space)
+	pub fn size(&self) -> usize
+	{
+		self.size
+	}
+	/// Find the first run of valid data starting at or after `offset` and before `limit`
+	///
+	/// Returns the start and end offsets of the run
+	pub fn next_valid_range(&self, offset: usize, limit: usize) -> Option<(usize, usize)>
+	{
+		let limit = usize::min(limit, self.size);
+		let is_valid = |i: usize| {
+			let ofs = (self.read_pos + i) % self.size;
+			self.data[self.size..][ofs / 8] & 1 << (ofs % 8) != 0
+			};
+		let start = (offset .. limit).find(|&i| is_valid(i))?;
+		let end = (start .. limit).find(|&i| !is_valid(i)).unwrap_or(limit);
+		Some( (start, end) )
+	}
 	/// Resize the buffer
 	pub fn resize(&mut self, new_size: usize) {
 		self.compact();
@@ -105,12 +125,12 @@ impl RxBuffer
 		if new_size > self.size {
 			// Resize underlying vector
 			self.data.resize(new_size + (new_size + 7) / 8, 0u8);
-			// Copy/move the bitmap up
-			self.data[self.size ..].rotate_right( (new_size - self.size) / 8 );
+			// Copy/move the bitmap up (to just after the enlarged data area)
+			self.data[self.size ..].rotate_right( new_size - self.size );
 		}
 		else {
 			// Move the bitmap down
-			self.data[new_size ..].rotate_left( (self.size - new_size) / 8 );
+			self.data[new_size ..].rotate_left( self.size - new_size );
 			self.data.truncate( new_size + (new_size + 7) / 8 );
 		}
 		self.size = new_size;
@@ -146,6 +166,7 @@ impl RxBuffer
 			}
 			// Step 2: shift bytes down
 			self.data[self.size ..].rotate_left( self.read_pos / 8 );
+			self.read_pos = 0;
 		}
 	}
 }
@@ -200,6 +221,54 @@ fn merging()
 	}
 }
 
+#[test]
+// Sparse data after a partially-filled bitmap entry isn't counted as valid
+fn sparse_gap()
+{
+	let mut buf = RxBuffer::new(32);
+	buf.insert(0, b"abc").expect("Insert 1");
+	buf.insert(8, b"01234567").expect("Insert 2");
+	assert_eq!(buf.valid_len(), 3);
+	buf.insert(3, b"defgh").expect("Insert 3");
+	assert_eq!(buf.valid_len(), 16);
+}
+
+#[test]
+// Out-of-order runs are reported (for SACK)
+fn valid_ranges()
+{
+	let mut buf = RxBuffer::new(32);
+	buf.insert(0, b"ab").expect("Insert 1");
+	buf.insert(5, b"cde").expect("Insert 2");
+	buf.insert(20, b"f").expect("Insert 3");
+	assert_eq!(buf.next_valid_range(2, 32), Some((5, 8)));
+	assert_eq!(buf.next_valid_range(8, 32), Some((20, 21)));
+	assert_eq!(buf.next_valid_range(8, 20), None);
+	assert_eq!(buf.next_valid_range(6, 7), Some((6, 7)));
+}
+
+#[test]
+// Growing and shrinking keeps the data (and the validity bitmap)
+fn resize()
+{
+	let mut buf = RxBuffer::new(16);
+	buf.insert(0, b"0123").expect("Insert 1");
+	{ let mut b = [0; 2]; buf.take(&mut b); }
+	buf.insert(4, b"z").expect("Insert 2");
+	buf.resize(64);
+	assert_eq!(buf.size(), 64);
+	assert_eq!(buf.valid_len(), 2);
+	assert_eq!(buf.next_valid_range(2, 64), Some((4, 5)));
+	buf.insert(40, b"far").expect("Insert 3");
+	buf.resize(48);
+	assert_eq!(buf.next_valid_range(5, 48), Some((40, 43)));
+	{
+		let mut b = [0; 2];
+		assert_eq!(buf.take(&mut b), 2);
+		assert_eq!(&b, b"23");
+	}
+}
+
 #[test]
 // Try to insert some over-sized data
 fn oversize()
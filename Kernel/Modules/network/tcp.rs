@@ -3,21 +3,26 @@
 //
 // Modules/network/tcp.rs
 //! Transmission Control Protocol (Layer 4)
+use kernel::prelude::*;
 use shared_map::SharedMap;
 use kernel::sync::Mutex;
+use kernel::lib::VecMap;
 use kernel::lib::ring_buffer::{AtomicRingBuf};
 use core::sync::atomic::{AtomicUsize, Ordering};
 use crate::nic::SparsePacket;
 use crate::Address;
+use crate::port_pool::PortPool;
 use kernel::futures::block_on;
 
-const IPV4_PROTO_TCP: u8 = 6;
+pub(crate) const IPV4_PROTO_TCP: u8 = 6;
+pub(crate) const IPV6_NEXT_TCP: u8 = 6;
 
 
 #[path="tcp-lib/"]
 /// Library types just for TCP
 mod lib {
 	pub mod rx_buffer;
+	pub mod options;
 }
 
 mod connection;
@@ -38,24 +43,39 @@ fn earliest_timestamp(dst: &mut Option<::kernel::time::TickCount>, src: Option<:
 pub fn init()
 {
 	crate::ipv4::register_handler(IPV4_PROTO_TCP, rx_handler_v4).unwrap();
+	crate::ipv6::register_handler(IPV6_NEXT_TCP, rx_handler_v6).unwrap();
 
-	// TODO: Spawn a worker that waits on all the TX timers and handles sending packets
+	// Worker that handles all transmission (and the timers) for connections
 	::core::mem::forget(::kernel::threads::WorkerThread::new("TCP Worker", || {
 		// Check/advance all connections, also getting the timeout for the sleep
 		loop
 		{
 			let key = WORKER_CV.get_key();
 			let mut wakeup_time = None;
+			let mut finished = Vec::new();
 			for (quad, conn) in CONNECTIONS.iter()
 			{
-				earliest_timestamp(&mut wakeup_time, conn.lock().run_tasks(quad));
+				let mut conn = conn.lock();
+				earliest_timestamp(&mut wakeup_time, conn.run_tasks(quad));
+				if let Some(is_client) = conn.is_removable() {
+					finished.push( (*quad, is_client) );
+				}
+			}
+			// Remove connections that are closed and no longer referenced by the user
+			for (quad, is_client) in finished
+			{
+				log_debug!("{:?} Removed", quad);
+				CONNECTIONS.take(&quad);
+				if is_client {
+					release_port(&quad.local_addr, quad.local_port);
+				}
 			}
 			// Wait on a condvar with a timeout (based)
 			// - This condvar will be poked when an incoming packet wants to trigger an action
 			if let Some(wakeup_time) = wakeup_time {
 				::kernel::futures::block_on(::kernel::futures::join_one(
 					WORKER_CV.wait(key),
-					::kernel::futures::msleep( (wakeup_time - ::kernel::time::ticks()) as usize )
+					::kernel::futures::msleep( wakeup_time.saturating_sub(::kernel::time::ticks()) as usize )
 					));
 			}
 			else {
@@ -70,7 +90,8 @@ static PROTO_CONNECTIONS: SharedMap<Quad, ProtoConnection> = SharedMap::new();
 static SERVERS: SharedMap<ListenPair, Server> = SharedMap::new();
 static WORKER_CV: ::kernel::futures::Condvar = ::kernel::futures::Condvar::new();
 
-static S_PORTS: Mutex<PortPool> = Mutex::new(PortPool::new());
+/// Ephemeral port bitmaps, one for each local address
+static S_PORTS: Mutex<VecMap<Address, Box<PortPool>>> = Mutex::new(VecMap::new());
 
 /// Find the local source address for the given remote address
 // TODO: Shouldn't this get an interface handle instead?
@@ -79,23 +100,39 @@ fn get_outbound_ip_for(addr: &Address) -> Option<Address>
 	match addr
 	{
 	Address::Ipv4(addr) => crate::ipv4::route_lookup(crate::ipv4::Address::zero(), *addr).map(|(laddr, _, _)| Address::Ipv4(laddr)),
+	Address::Ipv6(addr) => crate::ipv6::route_lookup(crate::ipv6::Address::zero(), *addr).map(|(laddr, _, _)| Address::Ipv6(laddr)),
 	}
 }
 /// Allocate a port for the given local address
-fn allocate_port(_addr: &Address) -> Option<u16>
+fn allocate_port(addr: &Address) -> Option<u16>
 {
-	// TODO: Could store bitmap against the interface (having a separate bitmap for each interface)
-	S_PORTS.lock().allocate()
+	S_PORTS.lock().entry(*addr).or_insert_with(|| Box::new(PortPool::new())).allocate()
 }
-fn release_port(_addr: &Address, idx: u16)
+fn release_port(addr: &Address, idx: u16)
 {
-	S_PORTS.lock().release(idx)
+	let mut lh = S_PORTS.lock();
+	if let Some(pool) = lh.get_mut(addr)
+	{
+		pool.release(idx);
+		// Drop the bitmap once unused (the address may have been a transient one)
+		if pool.is_empty() {
+			lh.remove(addr);
+		}
+	}
 }
 
 fn rx_handler_v4(int: &crate::ipv4::Interface, src_addr: crate::ipv4::Address, pkt: crate::nic::PacketReader)
 {
 	rx_handler(Address::Ipv4(src_addr), Address::Ipv4(int.addr()), pkt)
 }
+fn rx_handler_v6(_int: &crate::ipv6::Interface, info: &crate::ipv6::PacketInfo, pkt: crate::nic::PacketReader)
+{
+	if info.destination.is_multicast() {
+		return ;
+	}
+	// Interfaces can have several addresses (e.g. link-local and global), so use the one the packet was sent to
+	rx_handler(Address::Ipv6(info.source), Address::Ipv6(info.destination), pkt)
+}
 fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::PacketReader)
 {
 	let pre_header_reader = pkt.clone();
@@ -120,20 +157,12 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 
 		let packet_len = pre_header_reader.remain();
 		// Pseudo header for checksum
-		let sum_pseudo = match (src_addr,dest_addr)
-			{
-			(Address::Ipv4(s), Address::Ipv4(d)) =>
-				calculate_checksum([
-					// Big endian stores MSB first, so write the high word first
-					(s.as_u32() >> 16) as u16, (s.as_u32() >> 0) as u16,
-					(d.as_u32() >> 16) as u16, (d.as_u32() >> 0) as u16,
-					IPV4_PROTO_TCP as u16, packet_len as u16,
-					].iter().copied()),
-			};
+		let sum_pseudo = crate::pseudo_header_checksum(src_addr, dest_addr, IPV4_PROTO_TCP, packet_len);
 		let sum_header = hdr.checksum();
 		let sum_options_and_data = {
 			let mut pkt = pkt.clone();
-			let psum_whole = !calculate_checksum( (0 .. (pre_header_reader.remain() - hdr_len) / 2).map(|_| pkt.read_u16n().unwrap()) );
+			// Everything after the fixed header (options and data)
+			let psum_whole = !calculate_checksum( (0 .. (packet_len - 5*4) / 2).map(|_| pkt.read_u16n().unwrap()) );
 			// Final byte is decoded as if there was a zero after it (so as 0x??00)
 			let psum_partial = if pkt.remain() > 0 { (pkt.read_u8().unwrap() as u16) << 8} else { 0 };
 			calculate_checksum([psum_whole, psum_partial].iter().copied())
@@ -141,18 +170,19 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 		let sum_total = calculate_checksum([ !sum_pseudo, !sum_header, !sum_options_and_data ].iter().copied());
 		if sum_total != 0 {
 			log_error!("Incorrect checksum: 0x{:04x} != 0", sum_total);
-			// TODO: Discard the packet.
+			return ;
 		}
 	}
 
 	// Options
-	while pkt.remain() > pre_header_reader.remain() - hdr_len
-	{
-		match pkt.read_u8().unwrap()
-		{
-		_ => {},
+	let options = {
+		let mut buf = [0; lib::options::MAX_LEN];
+		let len = hdr_len.saturating_sub(5*4);
+		for b in &mut buf[..len] {
+			*b = pkt.read_u8().unwrap();
 		}
-	}
+		lib::options::Options::parse(&buf[..len])
+		};
 	
 	let get_server = ||->Option<_> {
 		Option::or( SERVERS.get( &ListenPair::fixed(dest_addr, hdr.dest_port) ), SERVERS.get( &ListenPair::any(hdr.dest_port) ) )
@@ -162,7 +192,7 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 	// Search for active connections with this quad
 	if let Some(c) = CONNECTIONS.get(&quad)
 	{
-		c.lock().handle(&quad, &hdr, pkt);
+		c.lock().handle(&quad, &hdr, &options, pkt);
 	}
 	// Search for proto-connections
 	// - Proto-connections are lighter weight than full-blown connections, reducing the impact of a SYN flood
@@ -171,17 +201,17 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 		if let Some(c) = PROTO_CONNECTIONS.take(&quad)
 		{
 			// Check the SEQ/ACK numbers, and create the actual connection
-			if hdr.sequence_number == c.seen_seq + 1 && hdr.acknowledgement_number == c.sent_seq
+			if hdr.sequence_number == c.seen_seq.wrapping_add(1) && hdr.acknowledgement_number == c.sent_seq.wrapping_add(1)
 			{
 				// Make the full connection struct
-				match CONNECTIONS.insert(quad, Mutex::new(Connection::new_inbound(&hdr)))
+				match CONNECTIONS.insert(quad, Mutex::new(Connection::new_inbound(&hdr, &c.options, &options)))
 				{
 				Ok(()) => {
 					log_debug!("Final ACK of a handshake: {:?}", quad);
 					// Add the connection onto the server's accept queue
 					let server = get_server().expect("Can't find server for proto connection");
 					server.accept_queue.push(quad).expect("Acceped connection with full accept queue");
-					// TODO: Signal a waiter too
+					server.waiters.wake_all();
 					},
 				Err(_) => log_warning!("Conflicting connection?"),	// TODO: What do to if there's a second connection for the quad?
 				}
@@ -189,8 +219,8 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 			else
 			{
 				log_debug!("Bad ACK of a handshake: {:?} - SEQ {} != {} || ACK {} != {}", quad,
-					hdr.sequence_number, c.seen_seq + 1,
-					hdr.acknowledgement_number, c.sent_seq,
+					hdr.sequence_number, c.seen_seq.wrapping_add(1),
+					hdr.acknowledgement_number, c.sent_seq.wrapping_add(1),
 					);
 				// - Bad ACK, put the proto connection back into the list
 				let _ = PROTO_CONNECTIONS.insert(quad, c);
@@ -199,7 +229,7 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 		else {
 			// No proto connection - RST?
 			log_debug!("Unexpected ACK: {:?}", quad);
-			block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_RST, 0, &[], &[]));
+			block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_RST, 0, &[], &[], &[]));
 		}
 	}
 	// If none found, look for servers on the destination (if SYN)
@@ -213,13 +243,15 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 				// Reject if no space
 				// - Send a RST
 				// TODO: Queue a packet instead of blocking here
-				block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_RST, 0, &[], &[]));
+				block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_RST, 0, &[], &[], &[]));
 			}
 			else {
 				log_debug!("Start of incoming handshake: {:?}", quad);
 				// - Add the quad as a proto-connection and send the SYN-ACK
-				let pc = ProtoConnection::new(hdr.sequence_number);
-				block_on(quad.send_packet(pc.sent_seq, pc.seen_seq, FLAG_SYN|FLAG_ACK, hdr.window_size, &[], &[]));
+				let pc = ProtoConnection::new(&quad, hdr.sequence_number, connection::SynOptions::from_syn(&options));
+				// The SYN consumes a sequence number
+				let syn_options = connection::syn_options(Some(&pc.options));
+				block_on(quad.send_packet(pc.sent_seq, pc.seen_seq.wrapping_add(1), FLAG_SYN|FLAG_ACK, connection::SYN_WINDOW_SIZE, syn_options.as_slice(), &[], &[]));
 				let _ = PROTO_CONNECTIONS.replace(quad, pc);	// Insert without replacing
 			}
 		}
@@ -227,12 +259,26 @@ fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::Packet
 		{
 			// Send a RST
 			log_debug!("SYN to closed port: {:?}", quad);
-			block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_RST|(!hdr.flags & FLAG_ACK), 0, &[], &[]));
+			block_on(quad.send_packet(hdr.acknowledgement_number, hdr.sequence_number, FLAG_RST|(!hdr.flags & FLAG_ACK), 0, &[], &[], &[]));
 		}
 	}
 	// Otherwise, drop
 }
 
+/// Handle an ICMP error reported for a segment sent from `local`
+pub(crate) fn handle_icmp_error(local_addr: Address, local_port: u16, remote_addr: Address, remote_port: u16, seq: u32, kind: crate::icmp::ErrorKind)
+{
+	let quad = Quad::new(local_addr, local_port, remote_addr, remote_port);
+	if let Some(c) = CONNECTIONS.get(&quad)
+	{
+		c.lock().handle_icmp_error(&quad, seq, kind);
+	}
+	else if kind.is_hard() && PROTO_CONNECTIONS.take(&quad).is_some()
+	{
+		log_debug!("{:?} SYN-ACK rejected by ICMP {:?}", quad, kind);
+	}
+}
+
 #[derive(Copy,Clone,PartialEq,PartialOrd,Eq,Ord)]
 struct ListenPair(Option<Address>, u16);
 impl ::core::fmt::Debug for ListenPair
@@ -280,13 +326,27 @@ impl Quad
 			local_addr, local_port, remote_addr, remote_port
 			}
 	}
-	async fn send_packet(&self, seq: u32, ack: u32, flags: u8, window_size: u16, data1: &[u8], data2: &[u8])
+	/// Initial sequence number for a connection on this quad (RFC 6528)
+	///
+	/// A 4us clock plus a keyed hash of the quad, so the ISN is not predictable by an off-path
+	/// attacker but still increases across reuses of the same quad.
+	fn initial_sequence_number(&self) -> u32
+	{
+		let mut buf = [0; 2*16 + 2*2];
+		let mut len = 0;
+		for v in [self.local_addr.as_bytes(), &self.local_port.to_be_bytes(), self.remote_addr.as_bytes(), &self.remote_port.to_be_bytes()].iter()
+		{
+			buf[len..][..v.len()].copy_from_slice(v);
+			len += v.len();
+		}
+		let clock = ::kernel::time::ticks().wrapping_mul(250) as u32;
+		clock.wrapping_add( ::kernel::rand::secret_hash(&buf[..len]) as u32 )
+	}
+	async fn send_packet(&self, seq: u32, ack: u32, flags: u8, window_size: u16, options_bytes: &[u8], data1: &[u8], data2: &[u8])
 	{
 		// Make a header
-		// TODO: Any options required?
-		let options_bytes = &[];
 		let opts_len_rounded = ((options_bytes.len() + 3) / 4) * 4;
-		let hdr = PktHeader {
+		let mut hdr = PktHeader {
 			source_port: self.local_port,
 			dest_port: self.remote_port,
 			sequence_number: seq,
@@ -296,8 +356,20 @@ impl Quad
 			window_size: window_size,
 			checksum: 0,	// To be filled afterwards
 			urgent_pointer: 0,
-			}.as_bytes();
-		// Calculate checksum
+			};
+		// Calculate checksum (over the pseudo-header, header, options, and data)
+		hdr.checksum = {
+			use crate::ipv4::calculate_checksum;
+			let total_len = 20 + opts_len_rounded + data1.len() + data2.len();
+			let it = options_bytes.iter().chain(&[0; 3][.. opts_len_rounded - options_bytes.len()]).chain(data1).chain(data2).copied();
+			// Final byte is summed as if there was a zero after it
+			let words = it.clone().step_by(2).zip(it.skip(1).step_by(2).map(Some).chain(::core::iter::once(None)))
+				.map(|(a,b)| (a as u16) << 8 | b.unwrap_or(0) as u16);
+			let sum_pseudo = crate::pseudo_header_checksum(self.local_addr, self.remote_addr, IPV4_PROTO_TCP, total_len);
+			let sum_rest = calculate_checksum(hdr.as_u16s().iter().copied().chain(words));
+			calculate_checksum([!sum_pseudo, !sum_rest].iter().copied())
+			};
+		let hdr = hdr.as_bytes();
 
 		// Create sparse packet chain
 		let data_pkt = SparsePacket::new_root(data2);
@@ -308,9 +380,11 @@ impl Quad
 		let hdr_pkt = SparsePacket::new_chained(&hdr, &opt_pkt);
 
 		// Pass packet downstream
-		match self.local_addr
+		match (self.local_addr, self.remote_addr)
 		{
-		Address::Ipv4(a) => crate::ipv4::send_packet(a, self.remote_addr.unwrap_ipv4(), IPV4_PROTO_TCP, hdr_pkt).await,
+		(Address::Ipv4(s), Address::Ipv4(d)) => crate::ipv4::send_packet(s, d, IPV4_PROTO_TCP, hdr_pkt).await,
+		(Address::Ipv6(s), Address::Ipv6(d)) => crate::ipv6::send_packet(s, d, IPV6_NEXT_TCP, hdr_pkt).await,
+		_ => unreachable!("Quad with mismatched address families"),
 		}
 	}
 }
@@ -339,7 +413,7 @@ struct PktHeader
 	checksum: u16,
 	urgent_pointer: u16,
 
-	//options: [u8],
+	// Options are parsed separately (see `lib::options`)
 }
 const FLAG_FIN: u8 = 1 << 0;
 const FLAG_SYN: u8 = 1 << 1;
@@ -416,14 +490,17 @@ struct ProtoConnection
 {
 	seen_seq: u32,
 	sent_seq: u32,
+	/// Options from the remote's SYN
+	options: connection::SynOptions,
 }
 impl ProtoConnection
 {
-	fn new(seen_seq: u32) -> ProtoConnection
+	fn new(quad: &Quad, seen_seq: u32, options: connection::SynOptions) -> ProtoConnection
 	{
 		ProtoConnection {
 			seen_seq: seen_seq,
-			sent_seq: 1,	// TODO: Random
+			sent_seq: quad.initial_sequence_number(),
+			options,
 			}
 	}
 }
@@ -434,6 +511,19 @@ struct Server
 	accept_space: AtomicUsize,
 	// Established connections waiting for the user to accept
 	accept_queue: AtomicRingBuf<Quad>,
+	/// Userland waiters for a new connection
+	waiters: ::kernel::user_async::Queue,
+}
+impl Server
+{
+	fn new() -> Server
+	{
+		Server {
+			accept_space: AtomicUsize::new(10),
+			accept_queue: AtomicRingBuf::new(10),
+			waiters: ::kernel::user_async::Queue::new(),
+			}
+	}
 }
 
 #[derive(Debug)]
@@ -446,25 +536,62 @@ pub enum ListenError
 pub struct ServerHandle(ListenPair);
 impl ServerHandle
 {
+	/// Listen on the specified port on all local addresses
 	pub fn listen(port: u16) -> Result<ServerHandle,ListenError>
 	{
-		let p = ListenPair::any(port);
-		SERVERS.insert(p, Server {
-			accept_space: AtomicUsize::new(10),
-			accept_queue: AtomicRingBuf::new(10),
-			}).map_err(|_| ListenError::SocketInUse)?;
+		Self::listen_inner(ListenPair::any(port))
+	}
+	/// Listen on the specified port of a single local address
+	pub fn listen_on(addr: Address, port: u16) -> Result<ServerHandle,ListenError>
+	{
+		Self::listen_inner(ListenPair::fixed(addr, port))
+	}
+	fn listen_inner(p: ListenPair) -> Result<ServerHandle,ListenError>
+	{
+		SERVERS.insert(p, Server::new()).map_err(|_| ListenError::SocketInUse)?;
 		Ok( ServerHandle(p) )
 	}
 
 	/// Accept a new incoming connection
-	pub fn accept(&mut self) -> Option<ConnectionHandle>
+	pub fn accept(&self) -> Option<ConnectionHandle>
 	{
 		let s = SERVERS.get(&self.0).expect("Server entry missing while handle still exists");
 		let rv_quad = s.accept_queue.pop()?;
+		// Release the slot reserved when the SYN arrived
+		s.accept_space.fetch_add(1, Ordering::SeqCst);
 		Some( ConnectionHandle(rv_quad) )
 	}
 
-	//pub fn wait_accept(&mut self)
+	/// Register a sleep object to be woken when a connection is waiting to be accepted
+	pub fn bind_wait_accept(&self, obj: &mut ::kernel::threads::SleepObject)
+	{
+		let s = SERVERS.get(&self.0).expect("Server entry missing while handle still exists");
+		s.waiters.wait_upon(obj);
+		if !s.accept_queue.is_empty() {
+			obj.signal();
+		}
+	}
+	/// Unregister a sleep object, returning `true` if there's a connection waiting
+	pub fn clear_wait_accept(&self, obj: &mut ::kernel::threads::SleepObject) -> bool
+	{
+		let s = SERVERS.get(&self.0).expect("Server entry missing while handle still exists");
+		s.waiters.clear_wait(obj);
+		!s.accept_queue.is_empty()
+	}
+}
+impl ::core::ops::Drop for ServerHandle
+{
+	fn drop(&mut self)
+	{
+		// Remove the server, any connections left in the accept queue are closed
+		if let Some(s) = SERVERS.take(&self.0)
+		{
+			while let Some(quad) = s.accept_queue.pop()
+			{
+				drop(ConnectionHandle(quad));
+			}
+		}
+	}
 }
 
 /// Handle to an open (or partially-open) connection
@@ -472,7 +599,7 @@ impl ServerHandle
 /// Can be directly constructed (for an outgoing/client connection), or returned from a server
 pub struct ConnectionHandle(Quad);
 
-#[derive(Debug)]
+#[derive(Copy,Clone,Debug)]
 pub enum ConnError
 {
 	NoRoute,
@@ -480,9 +607,21 @@ pub enum ConnError
 	RemoteRefused,
 	RemoteClosed,
 	RemoteReset,
+	/// The remote stopped acknowledging data
+	TimedOut,
 	NoPortAvailable,
 }
 
+/// Side of a connection to shut down
+#[derive(Debug)]
+pub enum ShutdownSide
+{
+	/// Stop sending data (sends a FIN)
+	Transmit,
+	/// Stop receiving data (further received data is discarded)
+	Receive,
+}
+
 impl ConnectionHandle
 {
 	pub fn connect(addr: Address, port: u16) -> Result<ConnectionHandle, ConnError>
@@ -503,9 +642,10 @@ impl ConnectionHandle
 		// 3. Create the quad and allocate the connection structure
 		let quad = Quad::new(local_addr, local_port,  addr, port, );
 		log_trace!("ConnectionHandle::connect: quad={:?}", quad);
-		// 4. Send the opening SYN (by creating the outbound connection structure)
-		let conn = Connection::new_outbound(&quad, 0x10000u32);
+		// 4. Create the outbound connection structure (the worker sends the opening SYN)
+		let conn = Connection::new_outbound(&quad, quad.initial_sequence_number());
 		CONNECTIONS.insert(quad, Mutex::new(conn)).map_err(|_| ()).expect("Our unique port wasn't unique");
+		WORKER_CV.wake_one();
 		Ok( ConnectionHandle(quad) )
 	}
 	pub fn send_data(&self, buf: &[u8]) -> Result<usize, ConnError>
@@ -525,101 +665,59 @@ impl ConnectionHandle
 		Some(v) => v.lock().recv_data(&self.0, buf),
 		}
 	}
-	//pub fn wait_recv()
 
-	pub fn close(&mut self) -> Result<(), ConnError>
+	/// Remote address and port of the connection
+	pub fn remote(&self) -> (Address, u16)
+	{
+		(self.0.remote_addr, self.0.remote_port)
+	}
+
+	/// Register a sleep object to be woken when data (or a state change) is available
+	pub fn bind_wait_recv(&self, obj: &mut ::kernel::threads::SleepObject)
 	{
 		match CONNECTIONS.get(&self.0)
 		{
 		None => panic!("Connection {:?} removed before handle dropped", self.0),
-		Some(v) => v.lock().close(&self.0),
+		Some(v) => v.lock().bind_wait_recv(obj),
 		}
 	}
-}
-impl ::core::ops::Drop for ConnectionHandle
-{
-	fn drop(&mut self)
-	{
-		// Mark the connection to close
-	}
-}
-
-
-const MIN_DYN_PORT: u16 = 0xC000;
-const N_DYN_PORTS: usize = (1<<16) - MIN_DYN_PORT as usize;
-struct PortPool {
-	bitmap: [u32; N_DYN_PORTS / 32],
-	//n_free_ports: u16,
-	next_port: u16,
-}
-impl PortPool
-{
-	const fn new() -> PortPool
+	/// Unregister a sleep object, returning `true` if a `recv_data` call would not return zero bytes
+	pub fn clear_wait_recv(&self, obj: &mut ::kernel::threads::SleepObject) -> bool
 	{
-		PortPool {
-			bitmap: [0; N_DYN_PORTS / 32],
-			//n_free_ports: N_DYN_PORTS as u16,
-			next_port: MIN_DYN_PORT,
-			}
+		match CONNECTIONS.get(&self.0)
+		{
+		None => panic!("Connection {:?} removed before handle dropped", self.0),
+		Some(v) => v.lock().clear_wait_recv(obj),
+		}
 	}
 
-	fn ofs_mask(idx: u16) -> Option<(usize, u32)>
+	pub fn shutdown(&self, side: ShutdownSide) -> Result<(), ConnError>
 	{
-		if idx >= MIN_DYN_PORT
-		{
-			let ofs = (idx - MIN_DYN_PORT) as usize / 32;
-			let mask  = 1 << (idx % 32);
-			Some( (ofs, mask) )
-		}
-		else
+		match CONNECTIONS.get(&self.0)
 		{
-			None
+		None => panic!("Connection {:?} removed before handle dropped", self.0),
+		Some(v) => v.lock().shutdown(&self.0, side),
 		}
 	}
-	fn take(&mut self, idx: u16) -> Result<(),()>
+
+	pub fn close(&mut self) -> Result<(), ConnError>
 	{
-		let (ofs,mask) = match Self::ofs_mask(idx)
-			{
-			Some(v) => v,
-			None => return Ok(()),
-			};
-		if self.bitmap[ofs] & mask != 0 {
-			Err( () )
-		}
-		else {
-			self.bitmap[ofs] |= mask;
-			Ok( () )
+		match CONNECTIONS.get(&self.0)
+		{
+		None => panic!("Connection {:?} removed before handle dropped", self.0),
+		Some(v) => v.lock().close(&self.0),
 		}
 	}
-	fn release(&mut self, idx: u16)
-	{
-		let (ofs,mask) = match Self::ofs_mask(idx)
-			{
-			Some(v) => v,
-			None => return,
-			};
-		self.bitmap[ofs] &= !mask;
-	}
-	fn allocate(&mut self) -> Option<u16>
+}
+impl ::core::ops::Drop for ConnectionHandle
+{
+	fn drop(&mut self)
 	{
-		// Strategy: Linear ('cos it's easy)
-		for idx in self.next_port ..= 0xFFFF
+		// Mark the connection to close, it's removed by the worker once finished
+		if let Some(v) = CONNECTIONS.get(&self.0)
 		{
-			match self.take(idx)
-			{
-			Ok(_) => { self.next_port = idx; return Some(idx); },
-			_ => {},
-			}
-		}
-		for idx in MIN_DYN_PORT .. self.next_port
-		{
-			match self.take(idx)
-			{
-			Ok(_) => { self.next_port = idx; return Some(idx); },
-			_ => {},
-			}
+			v.lock().handle_dropped(&self.0);
 		}
-		None
 	}
 }
 
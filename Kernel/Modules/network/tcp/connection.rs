@@ -1,18 +1,116 @@
 //! TCP connection logic
+//!
+//! Segment processing follows RFC 793 (with the RFC 1122 corrections), the retransmit timer is RFC 6298, and
+//! congestion control is NewReno (RFC 5681 and RFC 6582).
+//! Options negotiated in the handshake add window scaling and timestamps (RFC 7323), and selective
+//! acknowledgements (RFC 2018) which are used to skip already-received data during loss recovery.
 
+use ::kernel::prelude::*;
 use ::kernel::lib::ring_buffer::RingBuf;
+use ::kernel::time::{Timer,TickCount};
+use ::kernel::futures::block_on;
 use super::lib::rx_buffer::RxBuffer;
+use super::lib::options::{Options,OptionsBuf,MAX_SACK_BLOCKS};
 use super::{Quad,WORKER_CV};
 use super::ConnError;
 use super::{FLAG_SYN,FLAG_ACK,FLAG_PSH,FLAG_RST,FLAG_FIN};
 
+/// Size of the outbound buffer (unacknowledged and unsent data)
+const TX_BUFFER_SIZE: usize = 0x4000;	// 16KiB
+/// Assumed TX window until the remote advertises one
 const DEF_TX_WINDOW_SIZE: u32 = 0x1000;
 const DEF_RX_WINDOW_SIZE: u32 = 0x4000;	// 16KiB
-const MAX_WINDOW_SIZE: u32 = 0x100000;	// 4MiB
-/// Base timeout between attempting to send a packet and the first retransmit attempt
-const RETRANSMIT_TIMEOUT_MS: usize = 200;
-/// Maximum segment size (i.e. the largest amount of data in a single IP frame)
+const MAX_WINDOW_SIZE: u32 = 0x100000;	// 1MiB
+/// Window scale advertised in our SYN, enough to advertise `MAX_WINDOW_SIZE`
+const RX_WINDOW_SHIFT: u8 = (MAX_WINDOW_SIZE.trailing_zeros() - 15) as u8;
+/// Window advertised in a SYN-ACK (windows in SYN segments are never scaled)
+pub(super) const SYN_WINDOW_SIZE: u16 = DEF_RX_WINDOW_SIZE as u16;
+/// Retransmit timeout used before the first round-trip measurement
+const INITIAL_RTO_MS: u64 = 1000;
+/// Lower bound on the retransmit timeout (RFC 6298 section 2.4)
+const MIN_RTO_MS: u64 = 1000;
+/// Upper bound on the retransmit timeout (after backoff)
+const MAX_RTO_MS: u64 = 60_000;
+/// Number of timeouts of the same segment before the connection is dropped
+const MAX_RETRANSMITS: u32 = 8;
+/// Number of duplicate ACKs that trigger a fast retransmit
+const DUP_ACK_THRESHOLD: u32 = 3;
+/// Longest time an ACK is delayed (RFC 1122 requires less than 500ms)
+const DELAYED_ACK_MS: u64 = 200;
+/// Maximum segment lifetime, TIME_WAIT lasts for twice this
+const MSL_MS: u64 = 30_000;
+/// Default maximum segment size (i.e. the largest amount of data in a single IP frame)
 const MSS: usize = 1400;
+/// Remote MSS assumed when the SYN has no MSS option (RFC 9293 section 3.7.1)
+const DEFAULT_MSS: usize = 536;
+/// Size of the IPv4 and TCP headers (without options), subtracted from a path MTU to get the MSS
+const HEADERS_SIZE: usize = 20 + 20;
+/// Smallest MSS accepted from a "fragmentation needed" error (from the minimum IPv4 MTU of 68)
+const MIN_MSS: usize = 68 - HEADERS_SIZE;
+
+/// `a` is before `b` in sequence space
+fn seq_lt(a: u32, b: u32) -> bool {
+	(a.wrapping_sub(b) as i32) < 0
+}
+/// `a` is before or equal to `b` in sequence space
+fn seq_le(a: u32, b: u32) -> bool {
+	!seq_lt(b, a)
+}
+
+/// Current value of the timestamp clock (milliseconds)
+fn timestamp_now() -> u32 {
+	::kernel::time::ticks() as u32
+}
+/// Initial congestion window for a given MSS (RFC 5681 section 3.1)
+fn initial_window(mss: usize) -> u32 {
+	usize::min(4*mss, usize::max(2*mss, 4380)) as u32
+}
+
+/// Options from the remote's SYN, used to set up the connection
+#[derive(Copy,Clone,Debug)]
+pub(super) struct SynOptions
+{
+	/// Remote's maximum segment size
+	mss: usize,
+	/// Remote's window scale (`None` if it doesn't support scaling)
+	window_scale: Option<u8>,
+	sack_permitted: bool,
+	/// Remote's timestamp value (`None` if it doesn't support timestamps)
+	timestamp: Option<u32>,
+}
+impl SynOptions
+{
+	pub(super) fn from_syn(options: &Options) -> SynOptions
+	{
+		SynOptions {
+			mss: options.mss.map(|v| v as usize).unwrap_or(DEFAULT_MSS),
+			window_scale: options.window_scale,
+			sack_permitted: options.sack_permitted,
+			timestamp: options.timestamp.map(|(val, _)| val),
+			}
+	}
+}
+/// Options for an outgoing SYN, `peer` is the remote's SYN when replying with a SYN-ACK
+///
+/// Options other than MSS are only included in a SYN-ACK if the remote offered them.
+pub(super) fn syn_options(peer: Option<&SynOptions>) -> OptionsBuf
+{
+	let mut rv = OptionsBuf::new();
+	rv.push_mss(MSS as u16);
+	if peer.map_or(true, |p| p.window_scale.is_some()) {
+		rv.push_window_scale(RX_WINDOW_SHIFT);
+	}
+	if peer.map_or(true, |p| p.sack_permitted) {
+		rv.push_sack_permitted();
+	}
+	match peer
+	{
+	None => rv.push_timestamp(timestamp_now(), 0),
+	Some(&SynOptions { timestamp: Some(ts), .. }) => rv.push_timestamp(timestamp_now(), ts),
+	Some(_) => {},
+	}
+	rv
+}
 
 pub struct Connection
 {
@@ -29,46 +127,239 @@ pub struct Connection
 
 	rx_window_size_max: u32,
 	rx_window_size: u32,
+	/// Shift applied to the advertised window (zero unless window scaling was negotiated)
+	rx_window_shift: u8,
+	/// Right edge of the last advertised window
+	rx_window_edge: u32,
+	/// Sequence number of the most recent out-of-order segment (`Some` while there's out-of-order data)
+	rx_last_ooo: Option<u32>,
+	/// Both sides support selective acknowledgements
+	sack_permitted: bool,
+	/// Timestamp to echo to the remote (`None` if timestamps aren't in use)
+	ts_recent: Option<u32>,
+	/// The user has requested that the receive side be shut down
+	rx_shutdown: bool,
+	/// Userland waiters for received data
+	rx_waiters: ::kernel::user_async::Queue,
+
+	/// Error that caused the connection to be aborted (reported instead of the default for the state)
+	error: Option<ConnError>,
+
+	/// The local port was allocated for this connection (and must be released when it's removed)
+	is_client: bool,
+	/// The user's handle has been dropped, the connection is removed once `Finished`
+	handle_dropped: bool,
+	/// Timer for TIME_WAIT (and for FIN_WAIT_2 once the user has let go of the connection)
+	close_timer: Timer,
 
 	tx_state: ConnectionTxState,
 }
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum FinState
+{
+	/// Not closed locally
+	Idle,
+	/// Close requested, FIN is sent after the buffered data
+	Pending,
+	/// FIN sent, waiting for it to be ACKed
+	Sent,
+	Acked,
+}
 struct ConnectionTxState {
-	/// Buffer of outbound bytes (data pending an incoming ACK)
+	/// Buffer of outbound bytes (data pending an incoming ACK), the first byte is at `una_seq`
 	buffer: RingBuf<u8>,
-	/// Sequence number of the next byte to be sent
+	/// Sequence number of the oldest unacknowledged byte (SND.UNA)
+	una_seq: u32,
+	/// Sequence number of the next byte to be sent (SND.NXT)
 	next_tx_seq: u32,
-	
+
 	/// Number of bytes that have been sent, but not ACKed
 	sent_bytes: usize,
+	/// Maximum segment size (reduced if an ICMP "fragmentation needed" is received)
+	mss: usize,
+	fin: FinState,
 
 	/// Last received TX window size
 	max_tx_window_size: u32,
-	/// Current TX window size (can be reduced if packet loss is seen)
-	cur_tx_window_size: u32,
+	/// Shift applied to the remote's advertised window
+	window_shift: u8,
+	/// Congestion window
+	cwnd: u32,
+	/// Slow start threshold
+	ssthresh: u32,
+	/// Number of consecutive duplicate ACKs
+	dup_acks: u32,
+	/// End of the current loss recovery (NewReno's `recover`), set after a fast retransmit or a timeout
+	recover_seq: Option<u32>,
+	/// In fast recovery (the congestion window is inflated by duplicate ACKs)
+	fast_recovery: bool,
+	/// Ranges above `una_seq` that the remote has SACKed (sorted, non-overlapping)
+	sacked: Vec<(u32, u32)>,
+	/// End of the last retransmitted segment in the current recovery (RFC 6675's `HighRxt`)
+	high_rxt: u32,
+	/// Congestion state to restore if the last retransmit turns out to be spurious
+	undo: Option<CongestionUndo>,
 
-	// TODO: Pending flags?
+	rtt: RttEstimator,
+	/// End sequence number and send time of the segment being timed for a round-trip measurement
+	rtt_sample: Option<(u32, TickCount)>,
 
 	// -- Timers and state for transmit
 	/// Timer use to ensure that we get ACKs in a suitable time.
 	retransmit_timer: ::kernel::time::Timer,
-	/// Flag that forces a TX on the next opportunity (e.g. the buffer has a packet worth of data, or a flush was requested)
-	force_tx: bool,
-	/// Send an ACK in the next opportunity
+	/// Number of timeouts since data was last ACKed
+	retransmit_count: u32,
+	/// Re-send the oldest unacknowledged segment on the next opportunity (fast retransmit or a lowered MSS)
+	retransmit_now: bool,
+	/// An ACK is owed to the remote, it is sent when `ack_timer` expires (or with any other segment)
 	pending_ack: bool,
+	/// Delayed ACK timer
+	ack_timer: Timer,
+	/// Send the owed ACK on the next opportunity, without waiting for the timer
+	ack_now: bool,
+	/// Send a RST on the next opportunity (handshake aborted)
+	pending_rst: bool,
 }
 impl ConnectionTxState {
 	fn new(tx_seq: u32, init_window_size: u32) -> Self {
 		ConnectionTxState {
-			buffer: RingBuf::new(DEF_TX_WINDOW_SIZE as usize),
+			buffer: RingBuf::new(TX_BUFFER_SIZE),
+			una_seq: tx_seq,
 			next_tx_seq: tx_seq,
 
 			sent_bytes: 0,
+			mss: MSS,
+			fin: FinState::Idle,
 			max_tx_window_size: init_window_size,
-			cur_tx_window_size: init_window_size,
+			window_shift: 0,
+			cwnd: initial_window(MSS),
+			ssthresh: u32::MAX,
+			dup_acks: 0,
+			recover_seq: None,
+			fast_recovery: false,
+			sacked: Vec::new(),
+			high_rxt: tx_seq,
+			undo: None,
+			rtt: RttEstimator::new(),
+			rtt_sample: None,
 			retransmit_timer: ::kernel::time::Timer::new(),
-			force_tx: false,
+			retransmit_count: 0,
+			retransmit_now: false,
 			pending_ack: false,
+			ack_timer: Timer::new(),
+			ack_now: false,
+			pending_rst: false,
+		}
+	}
+	/// Number of sequence numbers sent but not yet ACKed
+	fn flight_size(&self) -> usize {
+		self.next_tx_seq.wrapping_sub(self.una_seq) as usize
+	}
+	/// Start the retransmit timer, if it's not already running
+	fn start_retransmit_timer(&mut self) {
+		if self.retransmit_timer.get_expiry().is_none() {
+			self.retransmit_timer.reset(self.rtt.rto);
+		}
+	}
+	/// Largest amount of data that fits in a segment with the given options
+	fn max_data(&self, options: &OptionsBuf) -> usize {
+		usize::max(1, self.mss.saturating_sub(options.len()))
+	}
+	/// Save the congestion state before a retransmit (for Eifel detection, RFC 3522)
+	fn save_undo(&mut self) {
+		self.undo = Some(CongestionUndo { tsval: timestamp_now(), cwnd: self.cwnd, ssthresh: self.ssthresh });
+	}
+
+	/// Record SACK blocks from the remote, ignoring any that don't cover outstanding data
+	fn add_sack_blocks(&mut self, blocks: &[(u32, u32)]) {
+		for &(start, end) in blocks
+		{
+			if !(seq_lt(start, end) && seq_lt(self.una_seq, end) && seq_le(end, self.next_tx_seq)) {
+				continue ;
+			}
+			// Merge with any overlapping or adjacent ranges
+			let mut new = (if seq_lt(start, self.una_seq) { self.una_seq } else { start }, end);
+			self.sacked.retain(|&(s, e)| {
+				if seq_le(s, new.1) && seq_le(new.0, e) {
+					if seq_lt(s, new.0) { new.0 = s; }
+					if seq_lt(new.1, e) { new.1 = e; }
+					false
+				}
+				else {
+					true
+				}
+				});
+			let pos = self.sacked.iter().position(|&(s, _)| seq_lt(new.0, s)).unwrap_or(self.sacked.len());
+			self.sacked.insert(pos, new);
+		}
+	}
+	/// Drop SACKed ranges that are now cumulatively acknowledged
+	fn prune_sacked(&mut self) {
+		let una = self.una_seq;
+		self.sacked.retain(|&(_, e)| seq_lt(una, e));
+		if let Some(first) = self.sacked.first_mut() {
+			if seq_lt(first.0, una) {
+				first.0 = una;
+			}
+		}
+	}
+	/// Next range (start and length) that hasn't been retransmitted in this recovery and has SACKed data after it
+	fn next_hole(&self) -> Option<(u32, u32)> {
+		let mut start = if seq_lt(self.una_seq, self.high_rxt) { self.high_rxt } else { self.una_seq };
+		for &(s, e) in &self.sacked
+		{
+			if seq_lt(start, s) {
+				return Some( (start, s.wrapping_sub(start)) );
+			}
+			if seq_lt(start, e) {
+				start = e;
+			}
 		}
+		None
+	}
+}
+/// Congestion state saved when retransmitting
+struct CongestionUndo
+{
+	/// Timestamp of the retransmit, an ACK echoing an earlier value was for the original transmission
+	tsval: u32,
+	cwnd: u32,
+	ssthresh: u32,
+}
+/// Round-trip time estimation (RFC 6298)
+struct RttEstimator
+{
+	/// Smoothed round-trip time, `None` until the first measurement
+	srtt: Option<u64>,
+	/// Round-trip time variation
+	rttvar: u64,
+	/// Current retransmit timeout (including any backoff)
+	rto: u64,
+}
+impl RttEstimator
+{
+	fn new() -> Self {
+		RttEstimator { srtt: None, rttvar: 0, rto: INITIAL_RTO_MS }
+	}
+	fn add_sample(&mut self, rtt: u64) {
+		let srtt = match self.srtt
+			{
+			None => {
+				self.rttvar = rtt / 2;
+				rtt
+				},
+			Some(srtt) => {
+				self.rttvar = (3 * self.rttvar + u64::abs_diff(srtt, rtt)) / 4;
+				(7 * srtt + rtt) / 8
+				},
+			};
+		self.srtt = Some(srtt);
+		// Clock granularity is one tick (1ms)
+		self.rto = (srtt + u64::max(1, 4 * self.rttvar)).max(MIN_RTO_MS).min(MAX_RTO_MS);
+	}
+	/// Double the timeout after a retransmit (cleared by the next measurement)
+	fn backoff(&mut self) {
+		self.rto = (self.rto * 2).min(MAX_RTO_MS);
 	}
 }
 #[derive(Copy,Clone,Debug,PartialEq)]
@@ -82,7 +373,7 @@ enum ConnectionState
 	Established,
 
 	FinWait1,	// FIN sent, waiting for reply (ACK or FIN)
-	FinWait2,	// sent FIN acked, waiting for FIN from peer 
+	FinWait2,	// sent FIN acked, waiting for FIN from peer
 	Closing,	// Waiting for ACK of FIN (FIN sent and received)
 	TimeWait,	// Waiting for timeout after local close
 
@@ -95,9 +386,9 @@ enum ConnectionState
 impl Connection
 {
 	/// Create a new connection from the ACK in a SYN-SYN,ACK-ACK
-	pub(super) fn new_inbound(hdr: &super::PktHeader) -> Self
+	pub(super) fn new_inbound(hdr: &super::PktHeader, syn_options: &SynOptions, options: &Options) -> Self
 	{
-		Connection {
+		let mut rv = Connection {
 			state: ConnectionState::Established,
 			next_rx_seq: hdr.sequence_number,
 			last_rx_ack: hdr.sequence_number,
@@ -106,15 +397,36 @@ impl Connection
 
 			rx_window_size_max: MAX_WINDOW_SIZE,	// Can be updated by the user
 			rx_window_size: DEF_RX_WINDOW_SIZE,
+			rx_window_shift: 0,
+			rx_window_edge: hdr.sequence_number.wrapping_add(DEF_RX_WINDOW_SIZE),
+			rx_last_ooo: None,
+			sack_permitted: false,
+			ts_recent: None,
+			rx_shutdown: false,
+			rx_waiters: ::kernel::user_async::Queue::new(),
 
-			tx_state: ConnectionTxState::new(hdr.acknowledgement_number, hdr.window_size as u32),
-			}
+			error: None,
+
+			is_client: false,
+			handle_dropped: false,
+			close_timer: Timer::new(),
+
+			tx_state: ConnectionTxState::new(hdr.acknowledgement_number, 0),
+			};
+		rv.apply_syn_options(syn_options);
+		// The ACK's window is scaled, unlike the one in the SYN
+		rv.tx_state.max_tx_window_size = (hdr.window_size as u32) << rv.tx_state.window_shift;
+		if let (Some(_), Some((tsval, _))) = (rv.ts_recent, options.timestamp) {
+			rv.ts_recent = Some(tsval);
+		}
+		rv
 	}
 
+	/// Create an outbound connection, the SYN is sent by the worker
 	pub(super) fn new_outbound(quad: &Quad, sequence_number: u32) -> Self
 	{
 		log_trace!("Connection::new_outbound({:?}, {:#x})", quad, sequence_number);
-		let mut rv = Connection {
+		Connection {
 			state: ConnectionState::SynSent,
 			next_rx_seq: 0,
 			last_rx_ack: 0,
@@ -123,224 +435,548 @@ impl Connection
 
 			rx_window_size_max: MAX_WINDOW_SIZE,	// Can be updated by the user
 			rx_window_size: DEF_RX_WINDOW_SIZE,
+			rx_window_shift: 0,
+			rx_window_edge: 0,
+			rx_last_ooo: None,
+			sack_permitted: false,
+			ts_recent: None,
+			rx_shutdown: false,
+			rx_waiters: ::kernel::user_async::Queue::new(),
+
+			error: None,
+
+			is_client: true,
+			handle_dropped: false,
+			close_timer: Timer::new(),
 
 			tx_state: ConnectionTxState::new(sequence_number, DEF_TX_WINDOW_SIZE),
-			};
-		rv.send_empty_packet(quad, FLAG_SYN);
-		rv
+			}
+	}
+
+	/// Use the options from the remote's SYN (only the ones that both sides sent are enabled)
+	fn apply_syn_options(&mut self, peer: &SynOptions)
+	{
+		let tx = &mut self.tx_state;
+		tx.mss = peer.mss.max(MIN_MSS).min(MSS);
+		tx.cwnd = initial_window(tx.mss);
+		if let Some(shift) = peer.window_scale {
+			tx.window_shift = shift;
+			self.rx_window_shift = RX_WINDOW_SHIFT;
+		}
+		self.sack_permitted = peer.sack_permitted;
+		self.ts_recent = peer.timestamp;
 	}
 
 	/// Handle an inbound packet
-	pub(super) fn handle(&mut self, quad: &Quad, hdr: &super::PktHeader, mut pkt: crate::nic::PacketReader)
+	pub(super) fn handle(&mut self, quad: &Quad, hdr: &super::PktHeader, options: &Options, mut pkt: crate::nic::PacketReader)
 	{
 		match self.state
 		{
 		//ConnectionState::Closed => return,
-		ConnectionState::Finished => return,
+		// Ignore all packets once reset or closed
+		ConnectionState::Finished | ConnectionState::ForceClose => return,
+		ConnectionState::SynSent => return self.handle_syn_sent(quad, hdr, options),
 		_ => {},
 		}
 
-		// Synchronisation request
-		if hdr.flags & FLAG_SYN != 0 {
-			// TODO: Send an ACK of the last received byte (should this be conditional?)
-			if self.last_rx_ack != self.next_rx_seq {
+		let data_len = pkt.remain() as u32;
+		let seg_len = data_len + if hdr.flags & FLAG_FIN != 0 { 1 } else { 0 };
+
+		// 0. Protection against wrapped sequence numbers, drop segments with an old timestamp (RFC 7323 section 5.3)
+		if let (Some(ts_recent), Some((tsval, _))) = (self.ts_recent, options.timestamp)
+		{
+			if hdr.flags & FLAG_RST == 0 && seq_lt(tsval, ts_recent) {
+				log_debug!("{:?} PAWS: Old timestamp {:#x} (recent {:#x})", quad, tsval, ts_recent);
+				self.send_ack(quad, "PAWS");
+				return ;
 			}
-			//self.next_rx_seq = hdr.sequence_number;
 		}
-		// ACK of sent data
-		if hdr.flags & FLAG_ACK != 0 {
-			let in_flight = self.tx_state.next_tx_seq.wrapping_sub(1).wrapping_sub(hdr.acknowledgement_number) as usize;
-			if in_flight > self.tx_state.buffer.len() {
-				// TODO: Error, something funky has happened
-			}
-			else {
-				let n_bytes = self.tx_state.buffer.len() - in_flight;
-				log_debug!("{:?} ACQ {} bytes", quad, n_bytes);
-				for _ in 0 .. n_bytes {
-					self.tx_state.buffer.pop_front();
-					self.tx_state.sent_bytes -= 1;
-				}
-				// If there are no un-acked bytes, and there's pending bytes. Trigger a re-send
-				if self.tx_state.sent_bytes == 0 && self.tx_state.buffer.len() > 0 {
-					self.tx_state.force_tx = true;
-					super::WORKER_CV.wake_one();
-				}
-				else {
-					// Since we've seen an ACK, reset the retransmit time
-					if self.tx_state.sent_bytes == 0 {
-						self.tx_state.retransmit_timer.clear();
-					}
-					else {
-						// TODO: Maintain a retransmit timer and double it each time we need to retransmit
-						self.tx_state.retransmit_timer.reset(RETRANSMIT_TIMEOUT_MS as u64);
-					}
+
+		// 1. Check that the segment is within the receive window
+		if !self.is_acceptable(hdr.sequence_number, seg_len)
+		{
+			if hdr.flags & FLAG_RST == 0 {
+				log_debug!("{:?} Unacceptable segment {:#x}+{} (expected {:#x})", quad, hdr.sequence_number, seg_len, self.next_rx_seq);
+				// A retransmitted FIN restarts TIME_WAIT
+				if self.state == ConnectionState::TimeWait && hdr.flags & FLAG_FIN != 0 {
+					self.close_timer.reset(2 * MSL_MS);
 				}
+				self.send_ack(quad, "Unacceptable");
+			}
+			return ;
+		}
+		// Update the timestamp to echo, if this segment doesn't leave a gap (RFC 7323 section 4.3)
+		if let (Some(_), Some((tsval, _))) = (self.ts_recent, options.timestamp)
+		{
+			if seq_le(hdr.sequence_number, self.last_rx_ack) {
+				self.ts_recent = Some(tsval);
 			}
 		}
 
-		// Update the window size if it changes
-		if self.tx_state.max_tx_window_size != hdr.window_size as u32 {
-			log_debug!("{:?} Max TX window changed: {} -> {}", quad, self.tx_state.max_tx_window_size, hdr.window_size);
-			self.tx_state.max_tx_window_size = hdr.window_size as u32;
+		// 2. Reset
+		if hdr.flags & FLAG_RST != 0
+		{
+			log_debug!("{:?} Reset by peer", quad);
+			let new_state = match self.state
+				{
+				ConnectionState::Established
+				| ConnectionState::FinWait1
+				| ConnectionState::FinWait2
+				| ConnectionState::CloseWait => ConnectionState::ForceClose,
+				_ => ConnectionState::Finished,
+				};
+			self.state_update(quad, new_state);
+			return ;
 		}
-		
-		let new_state = match self.state
+
+		// 3. SYN in a synchronised state, send a challenge ACK (RFC 5961 section 4)
+		if hdr.flags & FLAG_SYN != 0
 		{
-		//ConnectionState::Closed => return,
+			self.send_ack(quad, "SYN in synchronised state");
+			return ;
+		}
 
-		// SYN sent by local, waiting for SYN-ACK
-		ConnectionState::SynSent => {	
-			if hdr.flags & FLAG_SYN != 0 {
-				self.next_rx_seq += 1;
-				if hdr.flags & FLAG_ACK != 0 {
-					// Now established
-					// TODO: Send ACK back
-					self.send_ack(quad, "SYN-ACK");
-					ConnectionState::Established
-				}
-				else {
-					// Why did we get a plain SYN in this state?
-					self.state
+		// 4. Acknowledgement
+		if hdr.flags & FLAG_ACK == 0
+		{
+			return ;
+		}
+		if !self.handle_ack(quad, hdr, options, seg_len)
+		{
+			return ;
+		}
+		if self.tx_state.fin == FinState::Acked
+		{
+			match self.state
+			{
+			ConnectionState::FinWait1 => self.state_update(quad, ConnectionState::FinWait2),
+			ConnectionState::Closing => self.state_update(quad, ConnectionState::TimeWait),
+			ConnectionState::LastAck => {
+				self.state_update(quad, ConnectionState::Finished);
+				return ;
+				},
+			_ => {},
+			}
+		}
+
+		// 5. Data
+		if data_len > 0
+		{
+			match self.state
+			{
+			ConnectionState::Established
+			| ConnectionState::FinWait1
+			| ConnectionState::FinWait2 => self.handle_data(quad, hdr.sequence_number, &mut pkt),
+			// Data after the remote's FIN, ignore
+			_ => {},
+			}
+		}
+
+		// 6. FIN (only once all data before it has been received)
+		if hdr.flags & FLAG_FIN != 0
+		{
+			if hdr.sequence_number.wrapping_add(data_len) != self.next_rx_seq {
+				log_debug!("{:?} Out of order FIN {:#x}, expected {:#x}", quad, hdr.sequence_number.wrapping_add(data_len), self.next_rx_seq);
+				return ;
+			}
+			let new_state = match self.state
+				{
+				ConnectionState::Established => ConnectionState::CloseWait,
+				ConnectionState::FinWait1 => ConnectionState::Closing,
+				ConnectionState::FinWait2 => ConnectionState::TimeWait,
+				_ => return,
+				};
+			// The FIN consumes a sequence number
+			self.next_rx_seq = self.next_rx_seq.wrapping_add(1);
+			self.send_ack(quad, "FIN");
+			self.state_update(quad, new_state);
+		}
+	}
+
+	/// Handle a packet while waiting for the SYN,ACK of an outbound connection
+	fn handle_syn_sent(&mut self, quad: &Quad, hdr: &super::PktHeader, options: &Options)
+	{
+		if hdr.flags & FLAG_ACK != 0 && hdr.acknowledgement_number != self.tx_state.next_tx_seq
+		{
+			log_debug!("{:?} Bad ACK {:#x} of SYN, expected {:#x}", quad, hdr.acknowledgement_number, self.tx_state.next_tx_seq);
+			if hdr.flags & FLAG_RST == 0 {
+				block_on(quad.send_packet(hdr.acknowledgement_number, 0, FLAG_RST, 0, &[], &[], &[]));
+			}
+			return ;
+		}
+		if hdr.flags & FLAG_RST != 0
+		{
+			if hdr.flags & FLAG_ACK != 0 {
+				log_debug!("{:?} Connection refused", quad);
+				self.error = Some(ConnError::RemoteRefused);
+				self.state_update(quad, ConnectionState::ForceClose);
+			}
+			return ;
+		}
+		if hdr.flags & FLAG_SYN != 0
+		{
+			// The SYN consumes one sequence number, the first data byte is the one after
+			self.next_rx_seq = hdr.sequence_number.wrapping_add(1);
+			self.last_rx_ack = self.next_rx_seq;
+			self.rx_buffer_seq = self.next_rx_seq;
+			self.rx_window_edge = self.next_rx_seq.wrapping_add(self.rx_window_size);
+			if hdr.flags & FLAG_ACK != 0 {
+				self.apply_syn_options(&SynOptions::from_syn(options));
+				let tx = &mut self.tx_state;
+				tx.una_seq = hdr.acknowledgement_number;
+				tx.high_rxt = tx.una_seq;
+				tx.max_tx_window_size = hdr.window_size as u32;
+				if let Some((_, sent_time)) = tx.rtt_sample.take() {
+					tx.rtt.add_sample(::kernel::time::ticks() - sent_time);
 				}
+				tx.retransmit_timer.clear();
+				tx.retransmit_count = 0;
+				// Now established
+				self.send_ack(quad, "SYN-ACK");
+				self.state_update(quad, ConnectionState::Established);
 			}
 			else {
-				// Ignore non-SYN
-				self.state
+				// Simultaneous open isn't supported, wait for the SYN,ACK
+				log_debug!("{:?} Plain SYN while in SYN-SENT", quad);
 			}
-			},
+		}
+	}
 
-		ConnectionState::Established =>
-			if hdr.flags & FLAG_RST != 0 {
-				// RST received, do an unclean close (reset by peer)
-				// TODO: Signal to user that the connection is closing (error)
-				ConnectionState::ForceClose
+	/// Check that a segment overlaps the receive window (RFC 793 page 69)
+	fn is_acceptable(&self, seq: u32, seg_len: u32) -> bool
+	{
+		let window = self.rx_window_size;
+		let start = seq.wrapping_sub(self.next_rx_seq);
+		match (seg_len, window)
+		{
+		(0, 0) => start == 0,
+		(0, _) => start < window,
+		(_, 0) => false,
+		(_, _) => start < window || seq.wrapping_add(seg_len - 1).wrapping_sub(self.next_rx_seq) < window,
+		}
+	}
+
+	/// Process the acknowledgement and window fields, returns `false` if the segment should be dropped
+	fn handle_ack(&mut self, quad: &Quad, hdr: &super::PktHeader, options: &Options, seg_len: u32) -> bool
+	{
+		let ack = hdr.acknowledgement_number;
+		if seq_lt(self.tx_state.next_tx_seq, ack)
+		{
+			log_debug!("{:?} ACK {:#x} of unsent data (next {:#x})", quad, ack, self.tx_state.next_tx_seq);
+			self.send_ack(quad, "ACK of unsent data");
+			return false;
+		}
+		let window = (hdr.window_size as u32) << self.tx_state.window_shift;
+		let window_changed = self.tx_state.max_tx_window_size != window;
+		// Echoed timestamp (only if timestamps were negotiated)
+		let ts_echo = match (self.ts_recent, options.timestamp)
+			{
+			(Some(_), Some((_, ecr))) => Some(ecr),
+			_ => None,
+			};
+		let sack_permitted = self.sack_permitted;
+
+		let tx = &mut self.tx_state;
+		if sack_permitted {
+			tx.add_sack_blocks(options.sack_blocks());
+		}
+		if seq_lt(tx.una_seq, ack)
+		{
+			// New data acknowledged
+			let acked = ack.wrapping_sub(tx.una_seq) as usize;
+			let n_bytes = usize::min(acked, tx.sent_bytes);
+			log_debug!("{:?} ACQ {} bytes", quad, n_bytes);
+			for _ in 0 .. n_bytes {
+				tx.buffer.pop_front();
 			}
-			else if hdr.flags & FLAG_FIN != 0 {
-				// FIN received, start a clean shutdown
-				self.next_rx_seq += 1;
-				// TODO: Signal to user that the connection is closing (EOF)
-				ConnectionState::CloseWait
+			tx.sent_bytes -= n_bytes;
+			tx.una_seq = ack;
+			if acked > n_bytes && tx.fin == FinState::Sent {
+				tx.fin = FinState::Acked;
 			}
-			else {
-				if pkt.remain() == 0 {
-					// Pure ACK, no change
-					if hdr.flags == FLAG_ACK {
-						log_trace!("{:?} ACK only", quad);
-					}
-					else if self.next_rx_seq != hdr.sequence_number {
-						log_trace!("{:?} Empty packet, unexpected sequence number {:x} != {:x}", quad, hdr.sequence_number, self.next_rx_seq);
-					}
-					else {
-						// Counts as one byte
-						self.next_rx_seq += 1;
-						self.send_ack(quad, "Empty");
-					}
-				}
-				else if hdr.sequence_number - self.next_rx_seq + pkt.remain() as u32 > MAX_WINDOW_SIZE {
-					// Completely out of sequence
+			tx.prune_sacked();
+			if seq_lt(tx.high_rxt, ack) {
+				tx.high_rxt = ack;
+			}
+
+			if let Some(ecr) = ts_echo {
+				// Timestamps give a measurement from every ACK, including for retransmits (RFC 7323 section 4)
+				tx.rtt.add_sample(timestamp_now().wrapping_sub(ecr) as u64);
+				tx.rtt_sample = None;
+			}
+			else if let Some((end_seq, sent_time)) = tx.rtt_sample {
+				if seq_le(end_seq, ack) {
+					tx.rtt.add_sample(::kernel::time::ticks() - sent_time);
+					tx.rtt_sample = None;
 				}
-				else {
-					// In sequence.
-					let mut start_ofs = (hdr.sequence_number - self.next_rx_seq) as i32;
-					while start_ofs < 0 {
-						pkt.read_u8().unwrap();
-						start_ofs += 1;
-					}
-					let mut ofs = start_ofs as usize;
-					while let Ok(b) = pkt.read_u8() {
-						match self.rx_buffer.insert( (self.next_rx_seq - self.rx_buffer_seq) as usize + ofs, &[b])
-						{
-						Ok(_) => {},
-						Err(e) => {
-							log_error!("{:?} RX buffer push {:?}", quad, e);
-							break;
-							},
-						}
-						ofs += 1;
-					}
-					// Better idea: Have an ACQ point, and a window point. Buffer is double the window
-					// Once the window point reaches 25% of the window from the ACK point
-					if start_ofs == 0 {
-						self.next_rx_seq += ofs as u32;
-
-						// Calculate a maximum window size based on how much space is left in the buffer
-						let buffered_len = self.next_rx_seq - self.rx_buffer_seq;	// How much data the user has buffered
-						let cur_max_window = 2*self.rx_window_size_max - buffered_len;	// NOTE: 2* for some flex so the window can stay at max size
-						if cur_max_window < self.rx_window_size {
-							// Reduce the window size and send an ACQ (with the updated size)
-							while cur_max_window < self.rx_window_size {
-								self.rx_window_size /= 2;
-							}
-							self.send_ack(quad, "Constrain window");
-						}
-						else if self.next_rx_seq - self.last_rx_ack > self.rx_window_size/2 {
-							// Send an ACK now, we've received a burst of data
-							self.send_ack(quad, "Data burst");
-						}
-						else {
-							// TODO: Schedule an ACK in a few hundred milliseconds
-							// - Just set a flag so the next outbound packet ACKs
-							self.tx_state.pending_ack = true;
-						}
-					}
-
-					if hdr.flags & FLAG_PSH != 0 {
-						// TODO: Prod the user that there's new data?
-					}
+			}
+			tx.retransmit_count = 0;
+			tx.dup_acks = 0;
+
+			// Eifel detection (RFC 3522): if the echoed timestamp is older than the retransmit, the original got through
+			if let (Some(undo), Some(ecr)) = (tx.undo.take(), ts_echo) {
+				if seq_lt(ecr, undo.tsval) {
+					log_debug!("{:?} Spurious retransmit, restoring cwnd={} ssthresh={}", quad, undo.cwnd, undo.ssthresh);
+					tx.cwnd = undo.cwnd;
+					tx.ssthresh = undo.ssthresh;
+					tx.recover_seq = None;
+					tx.fast_recovery = false;
+					tx.retransmit_now = false;
 				}
+			}
 
-				self.state
-			},
+			let mss = tx.mss as u32;
+			match tx.recover_seq
+			{
+			Some(recover) if seq_lt(ack, recover) => {
+				// Partial ACK during recovery, the next segment was lost too (RFC 6582 section 3.2 step 3)
+				log_debug!("{:?} Partial ACK {:#x} (recovering to {:#x})", quad, ack, recover);
+				if tx.fast_recovery {
+					tx.cwnd = u32::max(tx.cwnd.saturating_sub(acked as u32) + mss, mss);
+				}
+				tx.retransmit_now = true;
+				},
+			Some(_) => {
+				// Full ACK, recovery complete
+				if tx.fast_recovery {
+					tx.cwnd = u32::min(tx.ssthresh, u32::max(tx.flight_size() as u32, mss) + mss);
+				}
+				tx.recover_seq = None;
+				tx.fast_recovery = false;
+				},
+			None =>
+				if tx.cwnd < tx.ssthresh {
+					// Slow start
+					tx.cwnd += u32::min(acked as u32, mss);
+				}
+				else {
+					// Congestion avoidance, approximately one MSS per round trip
+					tx.cwnd += u32::max(1, mss * mss / tx.cwnd);
+				},
+			}
 
-		ConnectionState::CloseWait => {
-			// Ignore all packets while waiting for the user to complete teardown
-			self.state
-			},
-		ConnectionState::LastAck =>	// Waiting for ACK in FIN,FIN/ACK,ACK
-			if hdr.flags & FLAG_ACK != 0 {
-				ConnectionState::Finished
+			// Restart the retransmit timer if there's still data in flight (RFC 6298 section 5.3)
+			if tx.flight_size() > 0 {
+				tx.retransmit_timer.reset(tx.rtt.rto);
 			}
 			else {
-				self.state
-			},
+				tx.retransmit_timer.clear();
+			}
+			WORKER_CV.wake_one();
+		}
+		else if ack == tx.una_seq && seg_len == 0 && !window_changed && tx.flight_size() > 0
+		{
+			// Duplicate ACK (RFC 5681 section 2)
+			tx.dup_acks += 1;
+			if tx.dup_acks == DUP_ACK_THRESHOLD && tx.recover_seq.is_none()
+			{
+				let mss = tx.mss as u32;
+				log_debug!("{:?} Fast retransmit of {:#x}", quad, ack);
+				tx.ssthresh = u32::max(tx.flight_size() as u32 / 2, 2 * mss);
+				tx.cwnd = tx.ssthresh + DUP_ACK_THRESHOLD * mss;
+				tx.recover_seq = Some(tx.next_tx_seq);
+				tx.fast_recovery = true;
+				tx.rtt_sample = None;
+				tx.high_rxt = tx.una_seq;
+				if ts_echo.is_some() {
+					tx.save_undo();
+				}
+				tx.retransmit_now = true;
+				tx.retransmit_timer.reset(tx.rtt.rto);
+				WORKER_CV.wake_one();
+			}
+			else if tx.fast_recovery
+			{
+				// Each further duplicate means a segment has left the network
+				tx.cwnd += tx.mss as u32;
+				// With SACK, also fill the next hole without waiting for a partial ACK
+				if sack_permitted && tx.next_hole().is_some() {
+					tx.retransmit_now = true;
+				}
+				WORKER_CV.wake_one();
+			}
+		}
 
-		ConnectionState::FinWait1 =>	// FIN sent, waiting for reply (ACK or FIN)
-			if hdr.flags & FLAG_FIN != 0 {
-				// TODO: Check the sequence number vs the sequence for the FIN
-				self.send_ack(quad, "SYN-ACK");
-				ConnectionState::Closing
+		if window_changed {
+			log_debug!("{:?} Max TX window changed: {} -> {}", quad, self.tx_state.max_tx_window_size, window);
+			self.tx_state.max_tx_window_size = window;
+			WORKER_CV.wake_one();
+		}
+		true
+	}
+
+	/// Add received data to the RX buffer
+	fn handle_data(&mut self, quad: &Quad, seq: u32, pkt: &mut crate::nic::PacketReader)
+	{
+		let mut start_ofs = seq.wrapping_sub(self.next_rx_seq) as i32;
+		// Skip data that has already been received
+		while start_ofs < 0 {
+			if pkt.read_u8().is_err() {
+				return ;
 			}
-			else if hdr.flags & FLAG_ACK != 0 {
-				// TODO: Check the sequence number vs the sequence for the FIN
-				ConnectionState::FinWait2
+			start_ofs += 1;
+		}
+		let start_ofs = start_ofs as usize;
+		let buffer_ofs = self.next_rx_seq.wrapping_sub(self.rx_buffer_seq) as usize;
+		let mut ofs = start_ofs;
+		while ofs < self.rx_window_size as usize
+		{
+			let b = match pkt.read_u8()
+				{
+				Ok(b) => b,
+				Err(_) => break,
+				};
+			match self.rx_buffer.insert(buffer_ofs + ofs, &[b])
+			{
+			Ok(_) => {},
+			Err(e) => {
+				log_error!("{:?} RX buffer push {:?}", quad, e);
+				break;
+				},
 			}
-			else {
-				self.state
-			},
-		ConnectionState::FinWait2 =>
-			if hdr.flags & FLAG_FIN != 0 {	// Got a FIN after the ACK, close
-				ConnectionState::TimeWait
+			ofs += 1;
+		}
+
+		if start_ofs != 0 {
+			// Out of order, send a duplicate ACK immediately (RFC 5681 section 4.2)
+			if ofs > start_ofs {
+				self.rx_last_ooo = Some(self.next_rx_seq.wrapping_add(start_ofs as u32));
 			}
-			else {
-				self.state
-			},
+			self.send_ack(quad, "Out of order");
+			return ;
+		}
 
-		ConnectionState::Closing =>
-			if hdr.flags & FLAG_ACK != 0 {
-				// TODO: Check the sequence number vs the sequence for the FIN
-				ConnectionState::TimeWait
+		// In order: advance over this segment, and any out-of-order data that it joined up with
+		let new_next = self.rx_buffer_seq.wrapping_add(self.rx_buffer.valid_len() as u32);
+		let filled_gap = new_next.wrapping_sub(self.next_rx_seq) as usize > ofs;
+		self.next_rx_seq = new_next;
+		self.rx_waiters.wake_all();
+		if self.rx_last_ooo.is_some() {
+			let next_ofs = self.next_rx_seq.wrapping_sub(self.rx_buffer_seq) as usize;
+			if self.rx_buffer.next_valid_range(next_ofs, next_ofs + self.rx_window_size as usize).is_none() {
+				self.rx_last_ooo = None;
 			}
-			else {
-				self.state
-			},
+		}
 
-		ConnectionState::ForceClose => self.state,
-		ConnectionState::TimeWait => self.state,
+		// Limit the window to the space left in the buffer
+		let space = self.rx_space();
+		if space < self.rx_window_size {
+			// Reduce the window size and send an ACQ (with the updated size)
+			while space < self.rx_window_size {
+				self.rx_window_size /= 2;
+			}
+			self.send_ack(quad, "Constrain window");
+		}
+		else if filled_gap {
+			self.send_ack(quad, "Filled gap");
+		}
+		else if !seq_lt(self.next_rx_seq, self.rx_window_edge) {
+			// The remote has filled the advertised window, let it send more per round trip
+			self.grow_rx_window(quad);
+			self.send_ack(quad, "Window filled");
+		}
+		else if self.next_rx_seq.wrapping_sub(self.last_rx_ack) >= 2 * MSS as u32 {
+			// Send an ACK now, we've received a burst of data
+			self.send_ack(quad, "Data burst");
+		}
+		else {
+			self.delay_ack();
+		}
+	}
 
-		ConnectionState::Finished => return,
-		};
+	/// Space in the RX buffer after the data waiting for the user
+	fn rx_space(&self) -> u32
+	{
+		let buffered_len = self.next_rx_seq.wrapping_sub(self.rx_buffer_seq);
+		(self.rx_buffer.size() as u32).saturating_sub(buffered_len)
+	}
+	/// Double the receive window (up to the largest that can be advertised)
+	fn grow_rx_window(&mut self, quad: &Quad)
+	{
+		let limit = if self.rx_window_shift > 0 { self.rx_window_size_max } else { 0xFFFF };
+		let new_size = u32::min(self.rx_window_size * 2, limit);
+		if new_size > self.rx_window_size
+		{
+			log_debug!("{:?} RX window grown {} -> {}", quad, self.rx_window_size, new_size);
+			// Keep the buffer at twice the window, so the window can stay open while the user reads
+			if (self.rx_buffer.size() as u32) < 2 * new_size {
+				self.rx_buffer.resize(2 * new_size as usize);
+			}
+			self.rx_window_size = new_size;
+		}
+	}
+	/// SACK blocks for the out-of-order data in the RX buffer
+	///
+	/// The first block contains the most recently received segment (RFC 2018 section 4)
+	fn sack_blocks(&self) -> ([(u32, u32); MAX_SACK_BLOCKS], usize)
+	{
+		let mut rv = [(0, 0); MAX_SACK_BLOCKS];
+		let mut count = 0;
+		let base = self.rx_buffer_seq;
+		let mut ofs = self.next_rx_seq.wrapping_sub(base) as usize;
+		let limit = ofs + self.rx_window_size as usize;
+		while count < rv.len()
+		{
+			let (start, end) = match self.rx_buffer.next_valid_range(ofs, limit)
+				{
+				Some(v) => v,
+				None => break,
+				};
+			rv[count] = (base.wrapping_add(start as u32), base.wrapping_add(end as u32));
+			count += 1;
+			ofs = end;
+		}
+		if let Some(latest) = self.rx_last_ooo {
+			if let Some(i) = rv[..count].iter().position(|&(s, e)| seq_le(s, latest) && seq_lt(latest, e)) {
+				rv[..=i].rotate_right(1);
+			}
+		}
+		(rv, count)
+	}
 
-		self.state_update(quad, new_state);
+	/// Handle an ICMP error reported for a sent segment (with sequence number `seq`)
+	pub(super) fn handle_icmp_error(&mut self, quad: &Quad, seq: u32, kind: crate::icmp::ErrorKind)
+	{
+		use crate::icmp::ErrorKind;
+		// Ignore errors for segments that aren't outstanding (could be stale or forged)
+		if seq.wrapping_sub(self.tx_state.una_seq) as usize > self.tx_state.flight_size() {
+			log_debug!("{:?} ICMP {:?} for non-outstanding sequence {:#x}", quad, kind, seq);
+			return ;
+		}
+		match kind
+		{
+		ErrorKind::FragmentationNeeded(mtu) => {
+			let mtu = mtu as usize;
+			if mtu > HEADERS_SIZE && mtu - HEADERS_SIZE >= MIN_MSS && mtu - HEADERS_SIZE < self.tx_state.mss {
+				log_debug!("{:?} MSS reduced {} -> {}", quad, self.tx_state.mss, mtu - HEADERS_SIZE);
+				self.tx_state.mss = mtu - HEADERS_SIZE;
+				// Re-send the segment that was too large
+				self.tx_state.retransmit_now = true;
+				WORKER_CV.wake_one();
+			}
+			},
+		// Port/protocol unreachable is a definite rejection, and any error during the handshake aborts the connect.
+		_ if kind.is_hard() || self.state == ConnectionState::SynSent => {
+			let err = match kind
+				{
+				ErrorKind::ProtocolUnreachable | ErrorKind::PortUnreachable => ConnError::RemoteRefused,
+				_ => ConnError::NoRoute,
+				};
+			match self.state
+			{
+			ConnectionState::SynSent
+			| ConnectionState::Established
+			| ConnectionState::CloseWait => {
+				log_debug!("{:?} aborted by ICMP {:?}", quad, kind);
+				self.error = Some(err);
+				self.state_update(quad, ConnectionState::ForceClose);
+				},
+			_ => {},
+			}
+			},
+		// Soft errors (e.g. a transient routing problem) are left to the retransmit logic
+		_ => log_debug!("{:?} ignoring ICMP {:?}", quad, kind),
+		}
 	}
 
 	fn state_update(&mut self, quad: &Quad, new_state: ConnectionState)
@@ -349,13 +985,27 @@ impl Connection
 		{
 			log_trace!("{:?} {:?} -> {:?}", quad, self.state, new_state);
 			self.state = new_state;
+			// Any state change can change the result of `recv_data`
+			self.rx_waiters.wake_all();
 
-			// TODO: If transitioning to `Finished`, release the local port?
-			// - Only for client connections.
-			if let ConnectionState::Finished = self.state
+			match self.state
 			{
-				super::release_port(&quad.local_addr, quad.local_port);
+			ConnectionState::TimeWait => {
+				self.tx_state.retransmit_timer.clear();
+				self.close_timer.reset(2 * MSL_MS);
+				},
+			// Don't wait forever for the remote's FIN if the user has finished with the connection
+			ConnectionState::FinWait2 if self.handle_dropped => {
+				self.close_timer.reset(2 * MSL_MS);
+				},
+			ConnectionState::ForceClose | ConnectionState::Finished => {
+				self.tx_state.retransmit_timer.clear();
+				self.tx_state.pending_ack = false;
+				self.close_timer.clear();
+				},
+			_ => {},
 			}
+			WORKER_CV.wake_one();
 		}
 	}
 
@@ -363,128 +1013,388 @@ impl Connection
 	{
 		match self.state
 		{
-		ConnectionState::SynSent => {
-			todo!("(quad=?) send/recv before established");
-			},
+		// Not yet established, but not an error (no data can be sent/received yet)
+		ConnectionState::SynSent => Ok( () ),
 		ConnectionState::Established => Ok( () ),
 		ConnectionState::FinWait1
 		| ConnectionState::FinWait2
 		| ConnectionState::Closing
 		| ConnectionState::TimeWait => Err( ConnError::LocalClosed ),
 
-		ConnectionState::ForceClose => Err( ConnError::RemoteReset ),
+		ConnectionState::ForceClose => Err( self.error.unwrap_or(ConnError::RemoteReset) ),
 		ConnectionState::CloseWait | ConnectionState::LastAck => Err( ConnError::RemoteClosed ),
 
-		ConnectionState::Finished => Err( ConnError::LocalClosed ),
+		ConnectionState::Finished => Err( self.error.unwrap_or(ConnError::LocalClosed) ),
 		}
 	}
 	/// Enqueue data to be sent
 	pub(super) fn send_data(&mut self, _quad: &Quad, buf: &[u8]) -> Result<usize, ConnError>
 	{
-		// TODO: Is it valid to send before the connection is fully established?
 		self.state_to_error()?;
-		// 1. Determine how much data we can send (based on the TX window)
-		let max_len = usize::saturating_sub(self.tx_state.cur_tx_window_size as usize, self.tx_state.buffer.len());
+		// Data isn't queued until the handshake completes, the user can retry once established
+		if self.state == ConnectionState::SynSent {
+			return Ok(0);
+		}
+		// 1. Determine how much data we can queue (based on the space in the TX buffer)
+		let max_len = TX_BUFFER_SIZE - self.tx_state.buffer.len();
 		let rv = ::core::cmp::min(buf.len(), max_len);
 		log_debug!("{:?} send_data({}/{})", _quad, rv, buf.len());
 		// Add the data to the TX buffer
 		for &b in &buf[..rv] {
 			self.tx_state.buffer.push_back(b).expect("Incorrectly calculated `max_len` in tcp::Connection::send_data");
 		}
-		
-		// Nagle algorithm!
-		// Only send if:
-		// - There's no unsent data in the buffer, OR
-		// - There's more than 1MSS unsent in the buffer
-		if self.tx_state.sent_bytes == 0 || self.tx_state.buffer.len() - self.tx_state.sent_bytes >= MSS
-		{
-			log_trace!("{:?} forcing a send", _quad);
-			// Force a TX
-			self.tx_state.force_tx = true;
-			WORKER_CV.wake_one();
-			//self.flush_send(quad);
-		}
-		else
-		{
-			// Just enqueue the data, the RX logic will trigger a re-send on ACK
-			log_trace!("{:?} waiting for nagle", _quad);
-		}
+		// The worker sends it once the windows allow
+		WORKER_CV.wake_one();
 		Ok(rv)
 	}
 	/// Pull data from the received buffer
-	pub(super) fn recv_data(&mut self, _quad: &Quad, buf: &mut [u8]) -> Result<usize, ConnError>
+	pub(super) fn recv_data(&mut self, quad: &Quad, buf: &mut [u8]) -> Result<usize, ConnError>
 	{
-		self.state_to_error()?;
-		//let valid_len = self.rx_buffer.valid_len();
-		//let acked_len = u32::wrapping_sub(self.next_rx_seq, self.rx_buffer_seq);
-		//let len = usize::min(valid_len, buf.len());
-		Ok( self.rx_buffer.take(buf) )
+		if self.rx_shutdown {
+			return Err( ConnError::LocalClosed );
+		}
+		// Data that was received before a FIN/RST can still be read
+		let rv = self.rx_buffer.take(buf);
+		self.rx_buffer_seq = self.rx_buffer_seq.wrapping_add(rv as u32);
+		// Re-open a window that was reduced while the user wasn't reading
+		if rv > 0 && self.rx_window_size < DEF_RX_WINDOW_SIZE && self.rx_space() >= DEF_RX_WINDOW_SIZE {
+			self.rx_window_size = DEF_RX_WINDOW_SIZE;
+			self.send_ack(quad, "Window update");
+		}
+		if rv == 0 && buf.len() > 0 {
+			self.state_to_error()?;
+		}
+		Ok( rv )
 	}
 
-	/// Run TX tasks (from the TX worker)
-	pub(super) fn run_tasks(&mut self, quad: &Quad) -> Option<::kernel::time::TickCount>
+	/// Register a waiter for received data
+	pub(super) fn bind_wait_recv(&mut self, obj: &mut ::kernel::threads::SleepObject)
 	{
-		use ::kernel::futures::block_on;
-
-		let flags = {
-			let mut flags = 0u8;
-			if ::core::mem::replace(&mut self.tx_state.pending_ack, false) {
-				flags |= FLAG_ACK;
+		self.rx_waiters.wait_upon(obj);
+		if self.has_rx_event() {
+			obj.signal();
+		}
+	}
+	/// Unregister a waiter for received data, returning `true` if there's data (or an error) to read
+	pub(super) fn clear_wait_recv(&mut self, obj: &mut ::kernel::threads::SleepObject) -> bool
+	{
+		self.rx_waiters.clear_wait(obj);
+		self.has_rx_event()
+	}
+	fn has_rx_event(&self) -> bool
+	{
+		self.rx_buffer.valid_len() > 0 || self.rx_shutdown || match self.state
+			{
+			ConnectionState::SynSent | ConnectionState::Established => false,
+			_ => true,
 			}
-			// TODO: Use a better method of picking when to PSH
-			if false && self.tx_state.force_tx {
-				flags |= FLAG_PSH;
+	}
+
+	/// User requests that one side of the connection be shut down
+	pub(super) fn shutdown(&mut self, quad: &Quad, side: super::ShutdownSide) -> Result<(), ConnError>
+	{
+		match side
+		{
+		super::ShutdownSide::Transmit => self.close(quad),
+		super::ShutdownSide::Receive => {
+			self.rx_shutdown = true;
+			self.rx_waiters.wake_all();
+			Ok( () )
+			},
+		}
+	}
+
+	/// Run TX tasks (from the TX worker), returning the time of the next timer
+	pub(super) fn run_tasks(&mut self, quad: &Quad) -> Option<TickCount>
+	{
+		if ::core::mem::replace(&mut self.tx_state.pending_rst, false) {
+			log_debug!("{:?} Sending RST", quad);
+			block_on(quad.send_packet(self.tx_state.next_tx_seq, 0, FLAG_RST, 0, &[], &[], &[]));
+		}
+		if self.close_timer.is_expired() {
+			self.close_timer.clear();
+			match self.state
+			{
+			ConnectionState::TimeWait
+			| ConnectionState::FinWait2 => {
+				log_debug!("{:?} Close timer expired in {:?}", quad, self.state);
+				self.state_update(quad, ConnectionState::Finished);
+				},
+			_ => {},
 			}
-			flags
-			};
+		}
+		match self.state
+		{
+		ConnectionState::ForceClose | ConnectionState::Finished => return None,
+		_ => {},
+		}
 
 		if self.tx_state.retransmit_timer.is_expired() {
-			// Re-send any pending data (and reduce our TX window size?)
-			let len = self.tx_state.buffer.len().min(MSS);
-			log_trace!("{:?} Retransmit {:#x} {} bytes", quad, flags, len);
-			let data = self.tx_state.buffer.get_slices(0..len);
-			// `next_tx_seq` is the sequence number of the next new byte to be sent
-			// - I.e. the byte at `buffer[sent_bytes]`
-			// - So, we want to subtract the number of bytes between `data.len()` and `sent_bytes`
-			let seq_ofs = (self.tx_state.sent_bytes as u32).wrapping_sub(len as u32);
-			let seq = self.tx_state.next_tx_seq.wrapping_sub(seq_ofs);
-			block_on(quad.send_packet(seq, self.next_rx_seq, flags, self.rx_window_size as u16, data.0, data.1));
-			// TODO: Double this timer each time we need to resend (and halve it on successful reception)
-			self.tx_state.retransmit_timer.reset(RETRANSMIT_TIMEOUT_MS as u64);
-		}
-		else if ::core::mem::replace(&mut self.tx_state.force_tx, false) {
-			// Send the new data
-			let nbytes = self.tx_state.buffer.len() - self.tx_state.sent_bytes;
-			let nbytes = nbytes.min(MSS);
-			let data = self.tx_state.buffer.get_slices(self.tx_state.sent_bytes .. self.tx_state.sent_bytes + nbytes);
-			let seq = self.tx_state.next_tx_seq;
-			log_trace!("{:?} TX forced {:#x} {} bytes", quad, flags, nbytes);
-			block_on(quad.send_packet(seq, self.next_rx_seq, flags, self.rx_window_size as u16, data.0, data.1));
-			// TODO: Some flags act as a pseudo-byte if in an empty packet
-			self.tx_state.next_tx_seq = self.tx_state.next_tx_seq.wrapping_add( nbytes as u32 );
+			self.tx_state.retransmit_timer.clear();
+			self.retransmit_timeout(quad);
+			if self.state == ConnectionState::ForceClose || self.state == ConnectionState::Finished {
+				return None;
+			}
 		}
-		else {
-			// Nothing to do.
+		else if ::core::mem::replace(&mut self.tx_state.retransmit_now, false) {
+			// Once the first segment has been resent, SACK information picks what else to resend
+			if self.sack_permitted && seq_lt(self.tx_state.una_seq, self.tx_state.high_rxt) {
+				self.retransmit_hole(quad);
+			}
+			else {
+				self.retransmit_first(quad);
+			}
+		}
+
+		self.send_pending(quad);
+
+		// Any segment sent above carries the ACK, so this only sends if nothing else was sent
+		if self.tx_state.pending_ack && (self.tx_state.ack_now || self.tx_state.ack_timer.is_expired()) {
+			let options = self.segment_options();
+			let (flags, ack, window) = self.prep_segment(0);
+			block_on(quad.send_packet(self.tx_state.next_tx_seq, ack, flags, window, options.as_slice(), &[], &[]));
 		}
 
 		let mut rv = None;
 		super::earliest_timestamp(&mut rv, self.tx_state.retransmit_timer.get_expiry());
+		if self.tx_state.pending_ack {
+			super::earliest_timestamp(&mut rv, self.tx_state.ack_timer.get_expiry());
+		}
+		super::earliest_timestamp(&mut rv, self.close_timer.get_expiry());
 		rv
 	}
 
-	fn send_empty_packet(&mut self, quad: &Quad, flags: u8)
+	/// Send new data (and the SYN/FIN) as allowed by the send and congestion windows
+	fn send_pending(&mut self, quad: &Quad)
 	{
-		log_debug!("{:?} send_packet({:02x})", quad, flags);
-		// TODO: Enqueue instead of blocking?
-		::kernel::futures::block_on(quad.send_packet(self.tx_state.next_tx_seq, self.next_rx_seq, flags, self.rx_window_size as u16, &[], &[]));
+		if self.state == ConnectionState::SynSent {
+			if self.tx_state.next_tx_seq == self.tx_state.una_seq {
+				log_debug!("{:?} Sending SYN", quad);
+				let seq = self.tx_state.una_seq;
+				let options = syn_options(None);
+				let (flags, ack, window) = self.prep_segment(FLAG_SYN);
+				block_on(quad.send_packet(seq, ack, flags, window, options.as_slice(), &[], &[]));
+				// The SYN consumes a sequence number
+				self.tx_state.next_tx_seq = seq.wrapping_add(1);
+				self.tx_state.rtt_sample = Some( (self.tx_state.next_tx_seq, ::kernel::time::ticks()) );
+				self.tx_state.start_retransmit_timer();
+			}
+			return ;
+		}
+		match self.tx_state.fin
+		{
+		FinState::Idle | FinState::Pending => {},
+		FinState::Sent | FinState::Acked => return,
+		}
+
+		loop
+		{
+			let options = self.segment_options();
+			let tx = &self.tx_state;
+			let max_data = tx.max_data(&options);
+			let unsent = tx.buffer.len() - tx.sent_bytes;
+			let window = u32::min(tx.cwnd, tx.max_tx_window_size) as usize;
+			if unsent == 0 || tx.sent_bytes >= window {
+				break;
+			}
+			let len = usize::min( usize::min(unsent, max_data), window - tx.sent_bytes );
+			// Nagle algorithm (RFC 896): only send a partial segment if nothing is in flight, or if closing
+			if len < max_data && tx.sent_bytes > 0 && tx.fin == FinState::Idle {
+				log_trace!("{:?} waiting for nagle ({} bytes)", quad, len);
+				break;
+			}
+
+			let seq = tx.next_tx_seq;
+			let start = tx.sent_bytes;
+			// Push when this empties the buffer
+			let flags = if len == unsent { FLAG_PSH } else { 0 };
+			log_trace!("{:?} TX {:#x}+{}", quad, seq, len);
+			let (flags, ack, window) = self.prep_segment(flags);
+			let data = self.tx_state.buffer.get_slices(start .. start + len);
+			block_on(quad.send_packet(seq, ack, flags, window, options.as_slice(), data.0, data.1));
+
+			let tx = &mut self.tx_state;
+			tx.sent_bytes += len;
+			tx.next_tx_seq = seq.wrapping_add(len as u32);
+			if tx.rtt_sample.is_none() && tx.recover_seq.is_none() {
+				tx.rtt_sample = Some( (tx.next_tx_seq, ::kernel::time::ticks()) );
+			}
+			tx.start_retransmit_timer();
+		}
+
+		let tx = &mut self.tx_state;
+		if tx.fin == FinState::Pending && tx.sent_bytes == tx.buffer.len()
+		{
+			log_debug!("{:?} Sending FIN", quad);
+			let seq = tx.next_tx_seq;
+			let options = self.segment_options();
+			let (flags, ack, window) = self.prep_segment(FLAG_FIN);
+			block_on(quad.send_packet(seq, ack, flags, window, options.as_slice(), &[], &[]));
+			let tx = &mut self.tx_state;
+			tx.fin = FinState::Sent;
+			tx.next_tx_seq = seq.wrapping_add(1);
+			tx.start_retransmit_timer();
+		}
+		else if tx.sent_bytes == 0 && tx.buffer.len() > 0 && tx.max_tx_window_size == 0
+		{
+			// Zero window, the retransmit timer sends probes
+			tx.start_retransmit_timer();
+		}
 	}
+
+	/// Handle expiry of the retransmit timer
+	fn retransmit_timeout(&mut self, quad: &Quad)
+	{
+		let tx = &mut self.tx_state;
+		// Probes of a zero window aren't retransmits, the remote is still ACKing them
+		let is_probe = self.state != ConnectionState::SynSent && tx.flight_size() == 0;
+		if !is_probe
+		{
+			tx.retransmit_count += 1;
+			if tx.retransmit_count > MAX_RETRANSMITS
+			{
+				log_notice!("{:?} No response after {} retransmits, dropping connection", quad, MAX_RETRANSMITS);
+				self.error = Some(ConnError::TimedOut);
+				let new_state = match self.state
+					{
+					ConnectionState::SynSent
+					| ConnectionState::Established
+					| ConnectionState::CloseWait => ConnectionState::ForceClose,
+					_ => ConnectionState::Finished,
+					};
+				self.state_update(quad, new_state);
+				return ;
+			}
+			if self.state != ConnectionState::SynSent
+			{
+				if tx.retransmit_count == 1 && self.ts_recent.is_some() {
+					tx.save_undo();
+				}
+				// The remote can discard SACKed data, so resend everything from the oldest unacknowledged byte (RFC 2018 section 8)
+				tx.sacked.clear();
+				tx.high_rxt = tx.una_seq;
+				// Collapse to the loss window (RFC 5681 section 3.1)
+				let mss = tx.mss as u32;
+				tx.ssthresh = u32::max(tx.flight_size() as u32 / 2, 2 * mss);
+				tx.cwnd = mss;
+				tx.dup_acks = 0;
+				tx.fast_recovery = false;
+				tx.recover_seq = Some(tx.next_tx_seq);
+			}
+		}
+		tx.rtt.backoff();
+		// Karn's algorithm: don't measure round-trips of retransmitted segments
+		tx.rtt_sample = None;
+		log_debug!("{:?} Retransmit timeout #{}, RTO now {}ms", quad, tx.retransmit_count, tx.rtt.rto);
+		self.retransmit_first(quad);
+		self.tx_state.retransmit_timer.reset(self.tx_state.rtt.rto);
+	}
+
+	/// (Re-)send the oldest unacknowledged segment
+	fn retransmit_first(&mut self, quad: &Quad)
+	{
+		let seq = self.tx_state.una_seq;
+		if self.state == ConnectionState::SynSent {
+			log_debug!("{:?} Retransmit SYN", quad);
+			let options = syn_options(None);
+			let (flags, ack, window) = self.prep_segment(FLAG_SYN);
+			block_on(quad.send_packet(seq, ack, flags, window, options.as_slice(), &[], &[]));
+			return ;
+		}
+		let options = self.segment_options();
+		let tx = &mut self.tx_state;
+		if tx.flight_size() == 0 && tx.buffer.len() > 0 && tx.fin == FinState::Idle {
+			// Nothing outstanding, but data is waiting on a zero window - send a single byte to probe it
+			tx.sent_bytes = 1;
+			tx.next_tx_seq = seq.wrapping_add(1);
+		}
+		let mut len = usize::min(tx.sent_bytes, tx.max_data(&options));
+		// Don't resend data that the remote has SACKed
+		if let Some(&(sack_start, _)) = tx.sacked.first() {
+			len = usize::min(len, sack_start.wrapping_sub(seq) as usize);
+		}
+		let with_fin = tx.fin == FinState::Sent && len == tx.sent_bytes;
+		if len == 0 && !with_fin {
+			return ;
+		}
+		log_debug!("{:?} Retransmit {:#x}+{}{}", quad, seq, len, if with_fin { " FIN" } else { "" });
+		tx.high_rxt = seq.wrapping_add(len as u32);
+		let flags = (if with_fin { FLAG_FIN } else { 0 }) | (if len > 0 && len == tx.buffer.len() { FLAG_PSH } else { 0 });
+		let (flags, ack, window) = self.prep_segment(flags);
+		let data = self.tx_state.buffer.get_slices(0 .. len);
+		block_on(quad.send_packet(seq, ack, flags, window, options.as_slice(), data.0, data.1));
+		self.tx_state.start_retransmit_timer();
+	}
+
+	/// Resend the next segment that the SACK information shows as missing
+	fn retransmit_hole(&mut self, quad: &Quad)
+	{
+		let (seq, hole_len) = match self.tx_state.next_hole()
+			{
+			Some(v) => v,
+			None => return,
+			};
+		let options = self.segment_options();
+		let tx = &mut self.tx_state;
+		let ofs = seq.wrapping_sub(tx.una_seq) as usize;
+		let len = usize::min(hole_len as usize, tx.max_data(&options)).min(tx.sent_bytes.saturating_sub(ofs));
+		if len == 0 {
+			return ;
+		}
+		log_debug!("{:?} Retransmit hole {:#x}+{}", quad, seq, len);
+		tx.high_rxt = seq.wrapping_add(len as u32);
+		let (flags, ack, window) = self.prep_segment(0);
+		let data = self.tx_state.buffer.get_slices(ofs .. ofs + len);
+		block_on(quad.send_packet(seq, ack, flags, window, options.as_slice(), data.0, data.1));
+	}
+
+	/// Get the flags, ACK number and window for an outgoing segment (marking any pending ACK as sent)
+	fn prep_segment(&mut self, flags: u8) -> (u8, u32, u16)
+	{
+		// Everything after the SYN carries an ACK
+		let flags = if self.state == ConnectionState::SynSent { flags } else { flags | FLAG_ACK };
+		let window = u32::min(self.rx_window_size >> self.rx_window_shift, 0xFFFF);
+		if flags & FLAG_ACK != 0 {
+			self.tx_state.pending_ack = false;
+			self.tx_state.ack_now = false;
+			self.tx_state.ack_timer.clear();
+			self.last_rx_ack = self.next_rx_seq;
+			self.rx_window_edge = self.next_rx_seq.wrapping_add(window << self.rx_window_shift);
+		}
+		(flags, self.next_rx_seq, window as u16)
+	}
+	/// Options for a segment after the handshake (timestamps and SACK blocks)
+	fn segment_options(&self) -> OptionsBuf
+	{
+		let mut rv = OptionsBuf::new();
+		if let Some(ts_recent) = self.ts_recent {
+			rv.push_timestamp(timestamp_now(), ts_recent);
+		}
+		if self.sack_permitted && self.rx_last_ooo.is_some() {
+			let (blocks, count) = self.sack_blocks();
+			rv.push_sack(&blocks[..count]);
+		}
+		rv
+	}
+	/// Send an ACK as soon as possible
 	fn send_ack(&mut self, quad: &Quad, msg: &str)
 	{
 		log_debug!("{:?} send_ack({:?})", quad, msg);
-		// - TODO: Cancel any pending ACK
-		// - Send a new ACK
 		self.tx_state.pending_ack = true;
-		self.tx_state.force_tx = true;
+		self.tx_state.ack_now = true;
+		WORKER_CV.wake_one();
+	}
+	/// Schedule an ACK, sent with the next outgoing segment or when the delayed ACK timer expires
+	fn delay_ack(&mut self)
+	{
+		if self.tx_state.pending_ack {
+			// Don't delay for more than one segment
+			self.tx_state.ack_now = true;
+		}
+		else {
+			self.tx_state.pending_ack = true;
+			self.tx_state.ack_timer.reset(DELAYED_ACK_MS);
+		}
 		WORKER_CV.wake_one();
 	}
 
@@ -494,7 +1404,9 @@ impl Connection
 		let new_state = match self.state
 			{
 			ConnectionState::SynSent => {
-				todo!("{:?} close before established", quad);
+				// Abort the handshake
+				self.tx_state.pending_rst = self.tx_state.next_tx_seq != self.tx_state.una_seq;
+				ConnectionState::Finished
 				},
 			ConnectionState::FinWait1
 			| ConnectionState::FinWait2
@@ -506,18 +1418,40 @@ impl Connection
 			ConnectionState::Finished => return Err( ConnError::LocalClosed ),
 
 			ConnectionState::CloseWait => {
-				self.send_empty_packet(quad, FLAG_FIN|FLAG_ACK);
+				self.tx_state.fin = FinState::Pending;
 				ConnectionState::LastAck
 				},
 			ConnectionState::ForceClose => {
 				ConnectionState::Finished
 				},
 			ConnectionState::Established => {
-				self.send_empty_packet(quad, FLAG_FIN);
+				self.tx_state.fin = FinState::Pending;
 				ConnectionState::FinWait1
 				},
 			};
 		self.state_update(quad, new_state);
 		Ok( () )
 	}
-}
\ No newline at end of file
+
+	/// The user's handle was dropped, close the connection and let it be removed once finished
+	pub(super) fn handle_dropped(&mut self, quad: &Quad)
+	{
+		self.handle_dropped = true;
+		// Errors just indicate that it's already closing
+		let _ = self.close(quad);
+		if self.state == ConnectionState::FinWait2 {
+			self.close_timer.reset(2 * MSL_MS);
+			WORKER_CV.wake_one();
+		}
+	}
+	/// Returns `Some(is_client)` if the connection can be removed
+	pub(super) fn is_removable(&self) -> Option<bool>
+	{
+		if self.handle_dropped && self.state == ConnectionState::Finished {
+			Some(self.is_client)
+		}
+		else {
+			None
+		}
+	}
+}
@@ -0,0 +1,486 @@
+// "Tifflin" Kernel - Networking Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/network/udp.rs
+//! User Datagram Protocol (Layer 4)
+use kernel::prelude::*;
+use kernel::sync::Mutex;
+use kernel::lib::VecMap;
+use shared_map::SharedMap;
+use crate::nic::SparsePacket;
+use crate::Address;
+use crate::port_pool::PortPool;
+
+pub(crate) const IPV4_PROTO_UDP: u8 = 17;
+pub(crate) const IPV6_NEXT_UDP: u8 = 17;
+
+/// Default limit on the number of bytes queued on a socket
+const DEF_RX_QUEUE_LIMIT: usize = 0x10000;	// 64KiB
+
+pub fn init()
+{
+	crate::ipv4::register_handler(IPV4_PROTO_UDP, rx_handler_v4).unwrap();
+	crate::ipv6::register_handler(IPV6_NEXT_UDP, rx_handler_v6).unwrap();
+}
+
+static SOCKETS: SharedMap<LocalPair, Socket> = SharedMap::new();
+/// Dynamic port bitmaps, one for each local address (`None` for sockets bound to all addresses)
+static S_PORTS: Mutex<VecMap<Option<Address>, Box<PortPool>>> = Mutex::new(VecMap::new());
+
+/// Allocate a dynamic port for the given local address
+fn allocate_port(addr: &Option<Address>) -> Option<u16>
+{
+	S_PORTS.lock().entry(*addr).or_insert_with(|| Box::new(PortPool::new())).allocate()
+}
+/// Reserve a specific port on the given local address (if it's in the dynamic range), so it's not handed out later
+fn take_port(addr: &Option<Address>, idx: u16) -> Result<(),()>
+{
+	let mut lh = S_PORTS.lock();
+	let pool = lh.entry(*addr).or_insert_with(|| Box::new(PortPool::new()));
+	let rv = pool.take(idx);
+	// Ports outside the dynamic range aren't tracked, so don't leave an empty bitmap behind
+	if pool.is_empty() {
+		lh.remove(addr);
+	}
+	rv
+}
+fn release_port(addr: &Option<Address>, idx: u16)
+{
+	let mut lh = S_PORTS.lock();
+	if let Some(pool) = lh.get_mut(addr)
+	{
+		// NOTE: Ports outside the dynamic range are ignored by the pool
+		pool.release(idx);
+		// Drop the bitmap once unused (the address may have been a transient one)
+		if pool.is_empty() {
+			lh.remove(addr);
+		}
+	}
+}
+
+fn rx_handler_v4(int: &crate::ipv4::Interface, src_addr: crate::ipv4::Address, pkt: crate::nic::PacketReader)
+{
+	rx_handler(Address::Ipv4(src_addr), Address::Ipv4(int.addr()), pkt)
+}
+fn rx_handler_v6(_int: &crate::ipv6::Interface, info: &crate::ipv6::PacketInfo, pkt: crate::nic::PacketReader)
+{
+	rx_handler(Address::Ipv6(info.source), Address::Ipv6(info.destination), pkt)
+}
+fn rx_handler(src_addr: Address, dest_addr: Address, mut pkt: crate::nic::PacketReader)
+{
+	let pre_header_reader = pkt.clone();
+	let hdr = match read_header(src_addr, dest_addr, &mut pkt)
+		{
+		Some(v) => v,
+		None => return,
+		};
+	let data_len = pkt.remain();
+	let src = (src_addr, hdr.source_port);
+	let deliver = |sock: &Socket| -> bool {
+		if !sock.remote.matches(&src_addr, hdr.source_port) {
+			return false;
+		}
+		let mut data = vec![0; data_len];
+		pkt.clone().read(&mut data).unwrap();
+		sock.push(src, data);
+		true
+		};
+	// Search for a socket bound to this specific address, then for a wildcard socket
+	if let Some(s) = SOCKETS.get(&LocalPair::fixed(dest_addr, hdr.dest_port)) {
+		if deliver(&s) {
+			return ;
+		}
+	}
+	if let Some(s) = SOCKETS.get(&LocalPair::any(hdr.dest_port)) {
+		if deliver(&s) {
+			return ;
+		}
+	}
+	log_debug!("Datagram to closed port: {:?}:{} from {:?}:{}", dest_addr, hdr.dest_port, src_addr, hdr.source_port);
+	match (dest_addr, src_addr)
+	{
+	(Address::Ipv4(l), Address::Ipv4(r)) => crate::icmp::send_unreachable_v4(l, r, IPV4_PROTO_UDP, crate::icmp::UNREACH_PORT, pre_header_reader),
+	// No errors in response to multicast
+	(Address::Ipv6(l), _) if l.is_multicast() => {},
+	(Address::Ipv6(l), Address::Ipv6(r)) => crate::icmpv6::send_unreachable(l, r, IPV6_NEXT_UDP, crate::icmpv6::Unreachable::Port, pre_header_reader),
+	_ => {},
+	}
+}
+
+/// Read and validate (length and checksum) a datagram's header, limiting `pkt` to the datagram's data
+fn read_header(src_addr: Address, dest_addr: Address, pkt: &mut crate::nic::PacketReader) -> Option<PktHeader>
+{
+	let pre_header_reader = pkt.clone();
+	let hdr = match PktHeader::read(pkt)
+		{
+		Ok(v) => v,
+		Err(_) => {
+			log_error!("Undersized packet: Ran out of data reading header");
+			return None;
+			},
+		};
+	log_debug!("hdr = {:?}", hdr);
+	let total_len = hdr.length as usize;
+	if total_len < 8 || total_len > pre_header_reader.remain() {
+		log_error!("Undersized or invalid packet: Length is {} but packet length is {}", total_len, pre_header_reader.remain());
+		return None;
+	}
+
+	// Validate checksum (a checksum of zero means that the sender didn't calculate one)
+	if hdr.checksum != 0
+	{
+		let mut reader = pre_header_reader.clone();
+		let sum = calculate_checksum(src_addr, dest_addr, total_len, (0 .. total_len / 2).map(|_| reader.read_u16n().unwrap()), if total_len % 2 == 1 { Some(reader.read_u8().unwrap()) } else { None });
+		if sum != 0 {
+			log_error!("Incorrect checksum: 0x{:04x} != 0", sum);
+			return None;
+		}
+	}
+	pkt.truncate(total_len - 8);
+	Some(hdr)
+}
+/// Read a datagram's header (see `read_header`), returning the source and destination ports
+pub(crate) fn read_ports(src_addr: Address, dest_addr: Address, pkt: &mut crate::nic::PacketReader) -> Option<(u16, u16)>
+{
+	read_header(src_addr, dest_addr, pkt).map(|h| (h.source_port, h.dest_port))
+}
+
+/// Handle an ICMP error reported for a datagram sent from `local`
+pub(crate) fn handle_icmp_error(local_addr: Address, local_port: u16, remote_addr: Address, remote_port: u16, kind: crate::icmp::ErrorKind)
+{
+	let s = match Option::or( SOCKETS.get(&LocalPair::fixed(local_addr, local_port)), SOCKETS.get(&LocalPair::any(local_port)) )
+		{
+		Some(v) => v,
+		None => return,
+		};
+	if !s.remote.matches(&remote_addr, remote_port) {
+		return ;
+	}
+	log_debug!("{:?} from {:?}:{} for socket {:?}:{}", kind, remote_addr, remote_port, local_addr, local_port);
+	s.rx_queue.lock().error = Some(kind);
+	s.waiters.wake_all();
+}
+
+/// Calculate the UDP checksum (including the IP pseudo-header) over a sequence of native-endian words
+fn calculate_checksum(src_addr: Address, dest_addr: Address, total_len: usize, words: impl Iterator<Item=u16>, tail: Option<u8>) -> u16
+{
+	use crate::ipv4::calculate_checksum;
+	let sum_pseudo = crate::pseudo_header_checksum(src_addr, dest_addr, IPV4_PROTO_UDP, total_len);
+	let sum_whole = calculate_checksum(words);
+	// Final byte is decoded as if there was a zero after it (so as 0x??00)
+	let sum_partial = calculate_checksum(tail.map(|v| (v as u16) << 8).into_iter());
+	calculate_checksum([ !sum_pseudo, !sum_whole, !sum_partial ].iter().copied())
+}
+
+/// Build a UDP header (with checksum) for a datagram
+pub(crate) fn encode_header(src: (Address, u16), dst: (Address, u16), data: &[u8]) -> [u8; 8]
+{
+	let total_len = 8 + data.len();
+	let mut hdr = PktHeader {
+		source_port: src.1,
+		dest_port: dst.1,
+		length: total_len as u16,
+		checksum: 0,
+		};
+	hdr.checksum = {
+		let hdr_bytes = hdr.as_bytes();
+		let it = hdr_bytes.iter().chain(data.iter()).copied();
+		let words = it.clone().step_by(2).zip(it.skip(1).step_by(2)).map(|(a,b)| (a as u16) << 8 | b as u16);
+		let tail = if total_len % 2 == 1 { data.last().copied() } else { None };
+		match calculate_checksum(src.0, dst.0, total_len, words, tail)
+		{
+		// A calculated zero is sent as all ones (zero indicates no checksum)
+		0 => 0xFFFF,
+		v => v,
+		}
+		};
+	hdr.as_bytes()
+}
+
+#[derive(Copy,Clone,PartialEq,PartialOrd,Eq,Ord,Debug)]
+struct LocalPair(Option<Address>, u16);
+impl LocalPair
+{
+	fn any(port: u16) -> LocalPair {
+		LocalPair(None, port)
+	}
+	fn fixed(addr: Address, port: u16) -> LocalPair {
+		LocalPair(Some(addr), port)
+	}
+}
+
+#[derive(Debug)]
+struct PktHeader
+{
+	source_port: u16,
+	dest_port: u16,
+	/// Length of the header and data
+	length: u16,
+	checksum: u16,
+}
+impl PktHeader
+{
+	fn read(reader: &mut crate::nic::PacketReader) -> Result<Self, ()>
+	{
+		Ok(PktHeader {
+			source_port: reader.read_u16n()?,
+			dest_port: reader.read_u16n()?,
+			length: reader.read_u16n()?,
+			checksum: reader.read_u16n()?,
+			})
+	}
+	fn as_bytes(&self) -> [u8; 8]
+	{
+		[
+			(self.source_port >> 8) as u8,
+			(self.source_port >> 0) as u8,
+			(self.dest_port >> 8) as u8,
+			(self.dest_port >> 0) as u8,
+			(self.length >> 8) as u8,
+			(self.length >> 0) as u8,
+			(self.checksum >> 8) as u8,
+			(self.checksum >> 0) as u8,
+			]
+	}
+}
+
+/// Filter applied to the source of incoming datagrams
+#[derive(Copy,Clone,Debug)]
+pub struct RemoteFilter
+{
+	/// Remote address
+	pub addr: Address,
+	/// Number of leading bits of `addr` that must match (zero matches any address)
+	pub mask_bits: u8,
+	/// Remote port (zero matches any port)
+	pub port: u16,
+}
+impl RemoteFilter
+{
+	/// A filter that matches any remote
+	pub fn any(addr: Address) -> RemoteFilter
+	{
+		RemoteFilter { addr: addr, mask_bits: 0, port: 0 }
+	}
+	pub fn matches(&self, addr: &Address, port: u16) -> bool
+	{
+		if self.port != 0 && self.port != port {
+			return false;
+		}
+		if self.mask_bits == 0 {
+			return true;
+		}
+		match (self.addr, *addr)
+		{
+		(Address::Ipv4(f), Address::Ipv4(a)) => f.mask(self.mask_bits.min(32)) == a.mask(self.mask_bits.min(32)),
+		(Address::Ipv6(f), Address::Ipv6(a)) => f.mask(self.mask_bits.min(128)) == a.mask(self.mask_bits.min(128)),
+		_ => false,
+		}
+	}
+}
+
+struct Datagram
+{
+	source: (Address, u16),
+	data: Vec<u8>,
+}
+struct RxQueue
+{
+	/// Number of data bytes in `queue`
+	total_bytes: usize,
+	queue: ::kernel::lib::Queue<Datagram>,
+	/// ICMP error reported for a sent datagram (returned by the next receive)
+	error: Option<crate::icmp::ErrorKind>,
+}
+impl RxQueue
+{
+	fn has_event(&self) -> bool
+	{
+		!self.queue.is_empty() || self.error.is_some()
+	}
+}
+struct Socket
+{
+	remote: RemoteFilter,
+	/// Maximum number of queued data bytes, datagrams that would exceed this are dropped
+	rx_limit: usize,
+	rx_queue: Mutex<RxQueue>,
+	/// Userland waiters for a new datagram
+	waiters: ::kernel::user_async::Queue,
+}
+impl Socket
+{
+	fn push(&self, source: (Address, u16), data: Vec<u8>)
+	{
+		let mut lh = self.rx_queue.lock();
+		if lh.total_bytes + data.len() > self.rx_limit {
+			log_debug!("Dropping datagram from {:?}, receive queue full ({} + {} > {})",
+				source, lh.total_bytes, data.len(), self.rx_limit);
+			return ;
+		}
+		lh.total_bytes += data.len();
+		lh.queue.push(Datagram { source, data });
+		self.waiters.wake_all();
+	}
+}
+
+#[derive(Debug)]
+pub enum BindError
+{
+	/// The requested local address/port is already bound
+	SocketInUse,
+	/// No dynamic port was available
+	NoPortAvailable,
+}
+#[derive(Debug)]
+pub enum SendError
+{
+	/// No route to the destination
+	NoRoute,
+	/// The destination isn't allowed by the socket's remote filter
+	FilteredAddress,
+	/// The datagram is larger than the maximum UDP payload
+	TooLarge,
+}
+
+/// Handle to a bound UDP socket
+pub struct SocketHandle
+{
+	local: LocalPair,
+}
+impl SocketHandle
+{
+	/// Bind a socket to a local address (`None` for all addresses) and port (zero to allocate a dynamic port)
+	pub fn bind(local_addr: Option<Address>, port: u16, remote: RemoteFilter) -> Result<SocketHandle, BindError>
+	{
+		let port = if port == 0 {
+				allocate_port(&local_addr).ok_or(BindError::NoPortAvailable)?
+			}
+			else {
+				take_port(&local_addr, port).map_err(|_| BindError::SocketInUse)?;
+				port
+			};
+		let local = LocalPair(local_addr, port);
+		let sock = Socket {
+			remote: remote,
+			rx_limit: DEF_RX_QUEUE_LIMIT,
+			rx_queue: Mutex::new(RxQueue { total_bytes: 0, queue: ::kernel::lib::Queue::new(), error: None }),
+			waiters: ::kernel::user_async::Queue::new(),
+			};
+		if SOCKETS.insert(local, sock).is_err() {
+			release_port(&local_addr, port);
+			return Err(BindError::SocketInUse);
+		}
+		Ok(SocketHandle { local })
+	}
+
+	/// Local port number
+	pub fn local_port(&self) -> u16
+	{
+		self.local.1
+	}
+
+	/// Send a datagram to the specified remote
+	pub fn send_to(&self, addr: Address, port: u16, data: &[u8]) -> Result<usize, SendError>
+	{
+		// Limited by the IPv4 total length field (with a minimal header)
+		if data.len() > 0xFFFF - 20 - 8 {
+			return Err(SendError::TooLarge);
+		}
+		if !self.with_socket(|s| s.remote.matches(&addr, port)) {
+			return Err(SendError::FilteredAddress);
+		}
+		let local_addr = match self.local.0
+			{
+			Some(a) => a,
+			None => match addr
+				{
+				Address::Ipv4(a) => match crate::ipv4::route_lookup(crate::ipv4::Address::zero(), a)
+					{
+					Some((laddr, _, _)) => Address::Ipv4(laddr),
+					None => return Err(SendError::NoRoute),
+					},
+				Address::Ipv6(a) => match crate::ipv6::route_lookup(crate::ipv6::Address::zero(), a)
+					{
+					Some((laddr, _, _)) => Address::Ipv6(laddr),
+					None => return Err(SendError::NoRoute),
+					},
+				},
+			};
+		match (local_addr, addr)
+		{
+		(Address::Ipv4(_), Address::Ipv4(_)) | (Address::Ipv6(_), Address::Ipv6(_)) => {},
+		// Bound to an address of the other family
+		_ => return Err(SendError::NoRoute),
+		}
+		let hdr_bytes = encode_header((local_addr, self.local.1), (addr, port), data);
+		let data_pkt = SparsePacket::new_root(data);
+		let hdr_pkt = SparsePacket::new_chained(&hdr_bytes, &data_pkt);
+		match (local_addr, addr)
+		{
+		(Address::Ipv4(s), Address::Ipv4(d)) => ::kernel::futures::block_on(crate::ipv4::send_packet(s, d, IPV4_PROTO_UDP, hdr_pkt)),
+		(Address::Ipv6(s), Address::Ipv6(d)) => ::kernel::futures::block_on(crate::ipv6::send_packet(s, d, IPV6_NEXT_UDP, hdr_pkt)),
+		_ => unreachable!(),
+		}
+		Ok(data.len())
+	}
+
+	/// Receive a datagram (non-blocking), returning the data length and the source
+	///
+	/// If the buffer is smaller than the datagram, the excess is discarded. An ICMP error reported for an earlier
+	/// datagram is returned (once) before any further datagrams.
+	pub fn recv_from(&self, buf: &mut [u8]) -> Result<Option<(usize, Address, u16)>, crate::icmp::ErrorKind>
+	{
+		self.with_socket(|s| {
+			let mut lh = s.rx_queue.lock();
+			if let Some(e) = lh.error.take() {
+				return Err(e);
+			}
+			let dg = match lh.queue.pop()
+				{
+				Some(v) => v,
+				None => return Ok(None),
+				};
+			lh.total_bytes -= dg.data.len();
+			let len = usize::min(buf.len(), dg.data.len());
+			buf[..len].copy_from_slice(&dg.data[..len]);
+			Ok(Some( (len, dg.source.0, dg.source.1) ))
+			})
+	}
+
+	/// Register a sleep object to be woken when a datagram (or error) is available
+	pub fn bind_wait_recv(&self, obj: &mut ::kernel::threads::SleepObject)
+	{
+		self.with_socket(|s| {
+			s.waiters.wait_upon(obj);
+			if s.rx_queue.lock().has_event() {
+				obj.signal();
+			}
+			})
+	}
+	/// Unregister a sleep object, returning `true` if there's a datagram (or error) waiting
+	pub fn clear_wait_recv(&self, obj: &mut ::kernel::threads::SleepObject) -> bool
+	{
+		self.with_socket(|s| {
+			s.waiters.clear_wait(obj);
+			s.rx_queue.lock().has_event()
+			})
+	}
+
+	fn with_socket<R>(&self, f: impl FnOnce(&Socket)->R) -> R
+	{
+		match SOCKETS.get(&self.local)
+		{
+		None => panic!("Socket {:?} removed before handle dropped", self.local),
+		Some(v) => f(&v),
+		}
+	}
+}
+impl ::core::ops::Drop for SocketHandle
+{
+	fn drop(&mut self)
+	{
+		SOCKETS.take(&self.local);
+		release_port(&self.local.0, self.local.1);
+	}
+}
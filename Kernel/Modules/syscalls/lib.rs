@@ -136,6 +136,7 @@ pub mod native_exports {
 	pub use crate::objects::give_object;
 	pub use crate::objects::object_has_no_such_method_ref;
 	pub use crate::objects::object_has_no_such_method_val;
+	pub use crate::threads::set_credentials;
 }
 
 
@@ -205,6 +206,11 @@ fn invoke_int(call_id: u32, args: &mut Args) -> Result<u64,Error>
 		CORE_FUTEX_WAKE => {
 			todo!("FUTEX_SLEEP");
 			},
+		CORE_RANDOM_BYTES => {
+			let mut buf: FreezeMut<[u8]> = args.get()?;
+			::kernel::rand::get_bytes(&mut buf);
+			buf.len() as u64
+			},
 		// === 1: Window Manager / GUI
 		// - 1/0: New group (requires permission, has other restrictions)
 		GUI_NEWGROUP => {
@@ -297,6 +303,38 @@ fn invoke_int(call_id: u32, args: &mut Args) -> Result<u64,Error>
 			let remote: crate::values::MaskedSocketAddress = { let p: Freeze<_> = args.get()?; *p };
 			from_result(network_calls::new_free_socket(local, remote))
 			},
+		NET_ROUTE_ADD => {
+			let route: crate::values::NetworkRoute = { let p: Freeze<_> = args.get()?; *p };
+			from_result(network_calls::route_add(route))
+			},
+		NET_ROUTE_DEL => {
+			let route: crate::values::NetworkRoute = { let p: Freeze<_> = args.get()?; *p };
+			from_result(network_calls::route_del(route))
+			},
+		NET_ROUTE_GET => {
+			let index: usize = args.get()?;
+			let mut route: FreezeMut<crate::values::NetworkRoute> = args.get()?;
+			from_result(network_calls::route_get(index, &mut route))
+			},
+		// === 5: VFS management
+		VFS_MOUNT => {
+			let mountpoint: Freeze<str> = args.get()?;
+			let volume: Freeze<str> = args.get()?;
+			let options: Freeze<str> = args.get()?;
+			from_result(vfs::mount(&mountpoint, &volume, &options))
+			},
+		VFS_UNMOUNT => {
+			let mountpoint: Freeze<str> = args.get()?;
+			from_result(vfs::unmount(&mountpoint))
+			},
+		VFS_REMOUNT => {
+			let mountpoint: Freeze<str> = args.get()?;
+			let options: Freeze<str> = args.get()?;
+			from_result(vfs::remount(&mountpoint, &options))
+			},
+		VFS_SYNC => {
+			from_result(vfs::sync())
+			},
 		// === *: Default
 		_ => {
 			log_error!("Unknown syscall {:05x}", call_id);
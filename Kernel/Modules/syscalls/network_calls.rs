@@ -8,29 +8,120 @@ use kernel::memory::freeze::{Freeze,FreezeMut};
 
 unsafe impl crate::args::Pod for crate::values::SocketAddress { }
 unsafe impl crate::args::Pod for crate::values::MaskedSocketAddress { }
+unsafe impl crate::args::Pod for crate::values::NetworkRoute { }
 
 fn from_tcp_result(r: Result<usize, ::network::tcp::ConnError>) -> u64 {
 	crate::from_result::<_, crate::values::SocketError>(match r
 		{
 		Ok(v) => Ok(v as u32),
-		Err(e) => todo!("Convert error: {:?}", e),
+		Err(e) => Err(from_conn_error(e)),
 		})
 }
+fn from_conn_error(e: ::network::tcp::ConnError) -> crate::values::SocketError {
+	use ::network::tcp::ConnError;
+	use crate::values::SocketError;
+	match e
+	{
+	ConnError::NoRoute => SocketError::NoRoute,
+	ConnError::LocalClosed => SocketError::ConnectionClosed,
+	ConnError::RemoteRefused => SocketError::ConnectionRefused,
+	ConnError::RemoteClosed => SocketError::ConnectionClosed,
+	ConnError::RemoteReset => SocketError::ConnectionReset,
+	ConnError::TimedOut => SocketError::TimedOut,
+	ConnError::NoPortAvailable => SocketError::NoPortAvailable,
+	}
+}
+
+fn from_icmp_error(e: ::network::icmp::ErrorKind) -> crate::values::SocketError {
+	use ::network::icmp::ErrorKind;
+	use crate::values::SocketError;
+	match e
+	{
+	ErrorKind::ProtocolUnreachable | ErrorKind::PortUnreachable => SocketError::ConnectionRefused,
+	_ => SocketError::NoRoute,
+	}
+}
+
+/// Convert a userland socket address into a network address and port
+fn get_address(addr: &crate::values::SocketAddress) -> Result<(::network::Address, u16), crate::values::SocketError>
+{
+	match crate::values::SocketAddressType::try_from(addr.addr_ty)
+	{
+	Ok(crate::values::SocketAddressType::Ipv4) => {
+		let a = ::network::ipv4::Address::new(addr.addr[0], addr.addr[1], addr.addr[2], addr.addr[3]);
+		Ok( (::network::Address::Ipv4(a), addr.port) )
+		},
+	Ok(crate::values::SocketAddressType::Ipv6) => {
+		Ok( (::network::Address::Ipv6(::network::ipv6::Address(addr.addr)), addr.port) )
+		},
+	_ => Err(crate::values::SocketError::InvalidValue),
+	}
+}
+/// Convert a network address and port into the userland representation
+fn make_address(port_ty: crate::values::SocketPortType, addr: ::network::Address, port: u16) -> crate::values::SocketAddress
+{
+	match addr
+	{
+	::network::Address::Ipv4(a) => {
+		let mut rv = crate::values::SocketAddress {
+			port_ty: port_ty.into(),
+			addr_ty: crate::values::SocketAddressType::Ipv4.into(),
+			port: port,
+			addr: [0; 16],
+			};
+		rv.addr[..4].copy_from_slice(&a.0);
+		rv
+		},
+	::network::Address::Ipv6(a) => crate::values::SocketAddress {
+		port_ty: port_ty.into(),
+		addr_ty: crate::values::SocketAddressType::Ipv6.into(),
+		port: port,
+		addr: a.0,
+		},
+	}
+}
+fn check_port_type(addr: &crate::values::SocketAddress, exp: crate::values::SocketPortType) -> Result<(), crate::values::SocketError>
+{
+	let exp: u8 = exp.into();
+	if addr.port_ty != exp {
+		Err(crate::values::SocketError::InvalidValue)
+	}
+	else {
+		Ok( () )
+	}
+}
 
 pub fn new_server(local_address: crate::values::SocketAddress) -> Result<u32, crate::values::SocketError>
 {
+	check_port_type(&local_address, crate::values::SocketPortType::Tcp)?;
+	let (addr, port) = get_address(&local_address)?;
+	// TODO: Check that the current process is allowed to listen on this port
+	let is_any = addr.is_zero();
+	let rv = if is_any {
+			::network::tcp::ServerHandle::listen(port)
+		}
+		else {
+			::network::tcp::ServerHandle::listen_on(addr, port)
+		};
+	let inner = match rv
+		{
+		Ok(v) => v,
+		Err(::network::tcp::ListenError::SocketInUse) => return Err(crate::values::SocketError::AlreadyInUse),
+		};
 	Ok(crate::objects::new_object(ConnServer {
-		inner: todo!("new_server({:?}", local_address),
+		inner: inner,
 		}))
 }
 pub fn new_client(remote_address: crate::values::SocketAddress) -> Result<u32, crate::values::SocketError>
 {
+	check_port_type(&remote_address, crate::values::SocketPortType::Tcp)?;
+	let (addr, port) = get_address(&remote_address)?;
 	Ok(crate::objects::new_object(ConnSocket {
-		inner: todo!("new_client({:?}", remote_address),	//::network::tcp::ConnectionHandle::connect(remote_mask, remote_mask)?,
+		inner: ::network::tcp::ConnectionHandle::connect(addr, port).map_err(from_conn_error)?,
 		}))
 }
 
-/// Create a UDP socket
+/// Create a UDP or ICMP echo socket
 pub fn new_free_socket(local_address: crate::values::SocketAddress, remote_mask: crate::values::MaskedSocketAddress) -> Result<u32, crate::values::SocketError>
 {
 	if local_address.port_ty != remote_mask.addr.port_ty {
@@ -39,15 +130,106 @@ pub fn new_free_socket(local_address: crate::values::SocketAddress, remote_mask:
 	if local_address.addr_ty != remote_mask.addr.addr_ty {
 		return Err(crate::values::SocketError::InvalidValue);
 	}
+	if check_port_type(&local_address, crate::values::SocketPortType::IcmpEcho).is_ok() {
+		// TODO: Check that the current process is allowed to send raw echo requests
+		get_address(&local_address)?;
+		let inner = match ::network::icmp::PingHandle::open()
+			{
+			Ok(v) => v,
+			Err(::network::icmp::PingError::NoIdentifierAvailable) => return Err(crate::values::SocketError::NoPortAvailable),
+			};
+		return Ok(crate::objects::new_object(FreeSocket {
+			inner: FreeSocketInner::Ping(inner),
+			}));
+	}
+	check_port_type(&local_address, crate::values::SocketPortType::Udp)?;
 	// TODO: Check that the current process is allowed to use the specified combination of port/type
+	let (local_addr, local_port) = get_address(&local_address)?;
+	let (remote_addr, remote_port) = get_address(&remote_mask.addr)?;
+	let is_any = local_addr.is_zero();
+	let filter = ::network::udp::RemoteFilter {
+		addr: remote_addr,
+		mask_bits: remote_mask.mask,
+		port: remote_port,
+		};
+	let inner = match ::network::udp::SocketHandle::bind(if is_any { None } else { Some(local_addr) }, local_port, filter)
+		{
+		Ok(v) => v,
+		Err(::network::udp::BindError::SocketInUse) => return Err(crate::values::SocketError::AlreadyInUse),
+		Err(::network::udp::BindError::NoPortAvailable) => return Err(crate::values::SocketError::NoPortAvailable),
+		};
 	Ok(crate::objects::new_object(FreeSocket {
-		inner: todo!("new_free_socket"),
+		inner: FreeSocketInner::Udp(inner),
 		}))
 }
 
+/// Convert a userland route into the network stack's representation
+fn get_route(route: &crate::values::NetworkRoute) -> Result<::network::ipv4::Route, crate::values::SocketError>
+{
+	match crate::values::SocketAddressType::try_from(route.addr_ty)
+	{
+	Ok(crate::values::SocketAddressType::Ipv4) => Ok(::network::ipv4::Route {
+		network: ::network::ipv4::Address::new(route.network[0], route.network[1], route.network[2], route.network[3]),
+		mask: route.mask,
+		next_hop: ::network::ipv4::Address::new(route.next_hop[0], route.next_hop[1], route.next_hop[2], route.next_hop[3]),
+		}),
+	_ => Err(crate::values::SocketError::InvalidValue),
+	}
+}
+/// Changing the routing table affects every process, so is restricted to root
+fn check_route_permission() -> Result<(), crate::values::SocketError>
+{
+	if ::kernel::threads::get_credentials().is_root() {
+		Ok( () )
+	}
+	else {
+		Err(crate::values::SocketError::PermissionDenied)
+	}
+}
+
+pub fn route_add(route: crate::values::NetworkRoute) -> Result<u32, crate::values::SocketError>
+{
+	check_route_permission()?;
+	match ::network::ipv4::add_route(get_route(&route)?)
+	{
+	Ok( () ) => Ok(0),
+	Err(::network::ipv4::RouteError::Exists) => Err(crate::values::SocketError::AlreadyInUse),
+	Err(_) => Err(crate::values::SocketError::InvalidValue),
+	}
+}
+pub fn route_del(route: crate::values::NetworkRoute) -> Result<u32, crate::values::SocketError>
+{
+	check_route_permission()?;
+	let route = get_route(&route)?;
+	match ::network::ipv4::del_route(route.network, route.mask)
+	{
+	Ok(_) => Ok(0),
+	Err(::network::ipv4::RouteError::NotFound) => Err(crate::values::SocketError::NoRoute),
+	Err(_) => Err(crate::values::SocketError::InvalidValue),
+	}
+}
+pub fn route_get(index: usize, dst: &mut crate::values::NetworkRoute) -> Result<u32, crate::values::SocketError>
+{
+	match ::network::ipv4::get_route(index)
+	{
+	Some(r) => {
+		*dst = crate::values::NetworkRoute {
+			addr_ty: crate::values::SocketAddressType::Ipv4.into(),
+			mask: r.mask,
+			network: [0; 16],
+			next_hop: [0; 16],
+			};
+		dst.network[..4].copy_from_slice(&r.network.0);
+		dst.next_hop[..4].copy_from_slice(&r.next_hop.0);
+		Ok(0)
+		},
+	None => Err(crate::values::SocketError::NoData),
+	}
+}
+
 struct ConnServer
 {
-	inner: (),
+	inner: ::network::tcp::ServerHandle,
 }
 impl crate::objects::Object for ConnServer
 {
@@ -61,8 +243,16 @@ impl crate::objects::Object for ConnServer
 		match call
 		{
 		crate::values::NET_SERVER_ACCEPT => {
-			let addr_ptr: FreezeMut<crate::values::SocketAddress> = args.get()?;
-			todo!("NET_SERVER_ACCEPT({:p})", &*addr_ptr);
+			let mut addr_ptr: FreezeMut<crate::values::SocketAddress> = args.get()?;
+			Ok(crate::from_result(match self.inner.accept()
+				{
+				Some(conn) => {
+					let (addr, port) = conn.remote();
+					*addr_ptr = make_address(crate::values::SocketPortType::Tcp, addr, port);
+					Ok( crate::objects::new_object(ConnSocket { inner: conn }) )
+					},
+				None => Err(crate::values::SocketError::NoData),
+				}))
 			},
 		_ => crate::objects::object_has_no_such_method_ref("network_calls::ConnServer", call),
 		}
@@ -72,11 +262,22 @@ impl crate::objects::Object for ConnServer
 		let _ = unsafe { ::core::ptr::read(self) };
 		crate::objects::object_has_no_such_method_val("network_calls::ConnServer", call)
 	}
-	fn bind_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 {
-		0
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & crate::values::EV_NET_SERVER_ACCEPT != 0 {
+			self.inner.bind_wait_accept(obj);
+			ret += 1;
+		}
+		ret
 	}
-	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 {
-		0
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & crate::values::EV_NET_SERVER_ACCEPT != 0 {
+			if self.inner.clear_wait_accept(obj) {
+				ret += 1;
+			}
+		}
+		ret
 	}
 }
 
@@ -97,7 +298,12 @@ impl crate::objects::Object for ConnSocket
 		{
 		crate::values::NET_CONNSOCK_SHUTDOWN => {
 			let what = crate::values::SocketShutdownSide::try_from(args.get::<u8>()?).map_err(|_| crate::Error::BadValue)?;
-			todo!("NET_CONNSOCK_SHUTDOWN({:?})", what);
+			let side = match what
+				{
+				crate::values::SocketShutdownSide::Transmit => ::network::tcp::ShutdownSide::Transmit,
+				crate::values::SocketShutdownSide::Receive => ::network::tcp::ShutdownSide::Receive,
+				};
+			from_tcp_result(self.inner.shutdown(side).map(|_| 0))
 			},
 		crate::values::NET_CONNSOCK_SEND => {
 			let data: Freeze<[u8]> = args.get()?;
@@ -115,19 +321,82 @@ impl crate::objects::Object for ConnSocket
 		let _ = unsafe { ::core::ptr::read(self) };
 		crate::objects::object_has_no_such_method_val("network_calls::ConnSocket", call)
 	}
-	fn bind_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 {
-		0
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & crate::values::EV_NET_CONNSOCK_RECV != 0 {
+			self.inner.bind_wait_recv(obj);
+			ret += 1;
+		}
+		ret
 	}
-	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 {
-		0
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & crate::values::EV_NET_CONNSOCK_RECV != 0 {
+			if self.inner.clear_wait_recv(obj) {
+				ret += 1;
+			}
+		}
+		ret
 	}
 }
 
 struct FreeSocket
 {
-	inner: (),
+	inner: FreeSocketInner,
+}
+enum FreeSocketInner
+{
+	Udp(::network::udp::SocketHandle),
+	/// ICMP echo socket, the address "port" is the sequence number
+	Ping(::network::icmp::PingHandle),
 }
 
+impl FreeSocket
+{
+	fn send_to(&self, data: &[u8], remote: &crate::values::SocketAddress) -> Result<u32, crate::values::SocketError>
+	{
+		match self.inner
+		{
+		FreeSocketInner::Udp(ref h) => {
+			check_port_type(remote, crate::values::SocketPortType::Udp)?;
+			let (addr, port) = get_address(remote)?;
+			match h.send_to(addr, port, data)
+			{
+			Ok(len) => Ok(len as u32),
+			Err(::network::udp::SendError::NoRoute) => Err(crate::values::SocketError::NoRoute),
+			Err(::network::udp::SendError::FilteredAddress) => Err(crate::values::SocketError::InvalidValue),
+			Err(::network::udp::SendError::TooLarge) => Err(crate::values::SocketError::InvalidValue),
+			}
+			},
+		FreeSocketInner::Ping(ref h) => {
+			check_port_type(remote, crate::values::SocketPortType::IcmpEcho)?;
+			let (addr, sequence) = get_address(remote)?;
+			match h.send_echo(addr, sequence, data)
+			{
+			Ok(len) => Ok(len as u32),
+			Err(()) => Err(crate::values::SocketError::NoRoute),
+			}
+			},
+		}
+	}
+	fn recv_from(&self, data: &mut [u8], addr_ptr: &mut crate::values::SocketAddress) -> Result<u32, crate::values::SocketError>
+	{
+		let (port_ty, rv) = match self.inner
+			{
+			FreeSocketInner::Udp(ref h) => (crate::values::SocketPortType::Udp, h.recv_from(data)),
+			FreeSocketInner::Ping(ref h) => (crate::values::SocketPortType::IcmpEcho, h.recv_reply(data)),
+			};
+		match rv
+		{
+		Ok(Some( (len, addr, port) )) => {
+			*addr_ptr = make_address(port_ty, addr, port);
+			Ok(len as u32)
+			},
+		Ok(None) => Err(crate::values::SocketError::NoData),
+		Err(e) => Err(from_icmp_error(e)),
+		}
+	}
+}
 impl crate::objects::Object for FreeSocket
 {
 	fn class(&self) -> u16 { crate::values::CLASS_FREESOCKET }
@@ -141,11 +410,13 @@ impl crate::objects::Object for FreeSocket
 		{
 		crate::values::NET_FREESOCK_SEND => {
 			let data: Freeze<[u8]> = args.get()?;
-			todo!("NET_FREESOCK_SEND({:p})", &*data);
+			let remote: Freeze<crate::values::SocketAddress> = args.get()?;
+			Ok(crate::from_result(self.send_to(&data, &remote)))
 			},
 		crate::values::NET_FREESOCK_RECV => {
-			let data: FreezeMut<[u8]> = args.get()?;
-			todo!("NET_FREESOCK_RECV({:p})", &*data);
+			let mut data: FreezeMut<[u8]> = args.get()?;
+			let mut addr_ptr: FreezeMut<crate::values::SocketAddress> = args.get()?;
+			Ok(crate::from_result(self.recv_from(&mut data, &mut addr_ptr)))
 			},
 		_ => crate::objects::object_has_no_such_method_ref("network_calls::FreeSocket", call),
 		}
@@ -155,11 +426,31 @@ impl crate::objects::Object for FreeSocket
 		let _ = unsafe { ::core::ptr::read(self) };
 		crate::objects::object_has_no_such_method_val("network_calls::FreeSocket", call)
 	}
-	fn bind_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 {
-		0
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & crate::values::EV_NET_FREESOCK_RECV != 0 {
+			match self.inner
+			{
+			FreeSocketInner::Udp(ref h) => h.bind_wait_recv(obj),
+			FreeSocketInner::Ping(ref h) => h.bind_wait_recv(obj),
+			}
+			ret += 1;
+		}
+		ret
 	}
-	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 {
-		0
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & crate::values::EV_NET_FREESOCK_RECV != 0 {
+			let fired = match self.inner
+				{
+				FreeSocketInner::Udp(ref h) => h.clear_wait_recv(obj),
+				FreeSocketInner::Ping(ref h) => h.clear_wait_recv(obj),
+				};
+			if fired {
+				ret += 1;
+			}
+		}
+		ret
 	}
 }
 
@@ -328,6 +328,19 @@ pub fn clone_object(handle: u32) -> Result<u64, super::Error> {
 		})
 }
 
+/// Call `fcn` with another of the process's objects (for methods that take an object as an argument)
+///
+/// NOTE: Must not be the object currently being called (its slot is already locked)
+pub fn with_object_ref<T: Object+'static, R>(handle: u32, fcn: impl FnOnce(&T)->R) -> Result<R, super::Error> {
+	get_process_local::<ProcessObjects>().with_object(handle, |obj| {
+		match obj.as_any().downcast_ref::<T>()
+		{
+		Some(v) => Ok( fcn(v) ),
+		None => Err( super::Error::BadValue ),
+		}
+		})
+}
+
 pub fn wait_on_object(handle: u32, mask: u32, sleeper: &mut ::kernel::threads::SleepObject) -> Result<u32,super::Error> {
 	get_process_local::<ProcessObjects>().with_object(handle, |obj| {
 		Ok( obj.bind_wait(mask, sleeper) )
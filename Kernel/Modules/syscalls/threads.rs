@@ -97,6 +97,17 @@ pub fn wait(events: &mut [values::WaitItem], wake_time_mono: u64) -> Result<u32,
 		})
 }
 
+/// Set a new process's credentials, only root can do this
+pub fn set_credentials(process: &::kernel::threads::ProcessHandle, uid: u32, gid: u32) -> Result<u32,u32> {
+	if ::kernel::threads::get_credentials().is_root() {
+		process.set_credentials(::kernel::threads::Credentials { uid, gid });
+		Ok(0)
+	}
+	else {
+		Err(0)
+	}
+}
+
 pub struct ProtoProcess(::kernel::threads::ProcessHandle);
 impl crate::objects::Object for ProtoProcess
 {
@@ -115,6 +126,12 @@ impl crate::objects::Object for ProtoProcess
 			let handle: u32 = args.get()?;
 			crate::objects::give_object(&self.0, &tag, handle).map(|_| 0)
 			}
+		// Change the credentials the process will run with
+		values::CORE_PROTOPROCESS_SETCREDS => {
+			let uid: u32 = args.get()?;
+			let gid: u32 = args.get()?;
+			Ok( super::from_result(set_credentials(&self.0, uid, gid)) )
+			}
 		_ => crate::objects::object_has_no_such_method_ref("threads::ProtoProcess", call),
 		}
 	}
@@ -38,8 +38,26 @@ impl_from! {
 		Error::PermissionDenied => VFSError::PermissionDenied,
 		Error::Locked => VFSError::FileLocked,
 		Error::MalformedPath => VFSError::MalformedPath,
-		Error::Unknown(reason) => todo!("VFS Error Unknown - '{}'", reason),
-		_ => todo!("VFS Error - {:?}", v),
+		Error::AlreadyExists => VFSError::AlreadyExists,
+		Error::DirectoryNotEmpty => VFSError::DirectoryNotEmpty,
+		Error::InvalidParameter => VFSError::InvalidParameter,
+		Error::NonDirComponent => VFSError::NonDirComponent,
+		Error::ReadOnlyFilesystem => VFSError::ReadOnlyFilesystem,
+		Error::RecursionDepthExceeded => VFSError::RecursionDepthExceeded,
+		Error::CrossVolume => VFSError::CrossVolume,
+		Error::Unsupported => VFSError::Unsupported,
+		Error::BlockIoError(e) => {
+			log_notice!("VFS IO error: {:?}", e);
+			VFSError::IoError
+			},
+		Error::InconsistentFilesystem => VFSError::InconsistentFilesystem,
+		Error::OutOfSpace => VFSError::OutOfSpace,
+		Error::OutOfMemory => VFSError::OutOfMemory,
+		Error::TransientError => VFSError::TransientError,
+		Error::Unknown(reason) => {
+			log_notice!("VFS error: {}", reason);
+			VFSError::Unknown
+			},
 		}
 	}}
 	From<NodeClass>(v) for values::VFSNodeType {
@@ -74,6 +92,77 @@ fn to_result<T>(r: Result<T, ::vfs::Error>) -> Result<T, u32> {
 	r.map_err( |e| Into::into( <values::VFSError as From<_>>::from(e) ) )
 }
 
+impl_from! {
+	From<::vfs::mount::MountError>(v) for values::VFSMountError {{
+		use ::vfs::mount::MountError;
+		use crate::values::VFSMountError;
+		match v
+		{
+		MountError::UnknownFilesystem => VFSMountError::UnknownFilesystem,
+		MountError::NoHandler => VFSMountError::UnknownFilesystem,
+		MountError::InvalidMountpoint => VFSMountError::InvalidMountpoint,
+		MountError::MountpointUsed => VFSMountError::MountpointUsed,
+		MountError::CallFailed => VFSMountError::DriverError,
+		MountError::BadOption => VFSMountError::BadOption,
+		MountError::NotMounted => VFSMountError::NotMounted,
+		MountError::Busy => VFSMountError::Busy,
+		}
+	}}
+}
+
+/// Mount management is restricted to root
+fn check_mount_permission() -> Result<(), values::VFSMountError> {
+	if ::kernel::threads::get_credentials().is_root() {
+		Ok( () )
+	}
+	else {
+		Err(values::VFSMountError::PermissionDenied)
+	}
+}
+/// Split a comma-separated option list
+fn split_options(options: &str) -> Vec<&str> {
+	options.split(',').filter(|v| *v != "").collect()
+}
+
+/// VFS_MOUNT - `options` can include `fs=<driver>` to select the filesystem driver, `volume` is `none` for ramfs
+pub fn mount(mountpoint: &str, volume: &str, options: &str) -> Result<u32, values::VFSMountError> {
+	check_mount_permission()?;
+	let mut options = split_options(options);
+	let fs = match options.iter().position(|v| v.starts_with("fs="))
+		{
+		Some(i) => options.remove(i).split_at(3).1,
+		None => "",
+		};
+	let vh = match ::kernel::metadevs::storage::VolumeHandle::open_named(volume)
+		{
+		Ok(v) => v,
+		// Filesystems without a backing volume (i.e. ramfs)
+		Err(_) if volume == "none" => ::kernel::metadevs::storage::VolumeHandle::new_ramdisk(0),
+		Err(e) => {
+			log_notice!("VFS_MOUNT: Unable to open volume {:?}: {}", volume, e);
+			return Err(values::VFSMountError::VolumeNotFound);
+			},
+		};
+	::vfs::mount::mount(Path::new(mountpoint), vh, fs, &options)?;
+	Ok(0)
+}
+/// VFS_UNMOUNT
+pub fn unmount(mountpoint: &str) -> Result<u32, values::VFSMountError> {
+	check_mount_permission()?;
+	::vfs::mount::unmount(Path::new(mountpoint))?;
+	Ok(0)
+}
+/// VFS_REMOUNT
+pub fn remount(mountpoint: &str, options: &str) -> Result<u32, values::VFSMountError> {
+	check_mount_permission()?;
+	::vfs::mount::remount(Path::new(mountpoint), &split_options(options))?;
+	Ok(0)
+}
+/// VFS_SYNC
+pub fn sync() -> Result<u32, u32> {
+	to_result(::vfs::mount::sync_all()).map(|()| 0)
+}
+
 pub fn init_handles(init_handle: ::vfs::handle::File) {
 	// #1: Read-only root
 	objects::push_as_unclaimed("ro:/", objects::new_object(Dir::new( {
@@ -82,7 +171,7 @@ pub fn init_handles(init_handle: ::vfs::handle::File) {
 		root
 		})) );
 	// #2: Initial file handle
-	objects::new_object( File(init_handle) );
+	objects::new_object( File::new(init_handle) );
 
 	// - Read-write handle to /
 	objects::push_as_unclaimed("RwRoot", objects::new_object( Dir::new( handle::Dir::open(Path::new("/")).unwrap() ) ) );
@@ -101,7 +190,7 @@ impl objects::Object for Node
 	fn try_clone(&self) -> Option<u32> {
 		Some( objects::new_object( Node(self.0.clone()) ) )
 	}
-	fn handle_syscall_ref(&self, call: u16, _args: &mut Args) -> Result<u64,Error> {
+	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
 		match call
 		{
 		values::VFS_NODE_GETTYPE => {
@@ -109,6 +198,27 @@ impl objects::Object for Node
 			let v32: u32 = values::VFSNodeType::from( self.0.get_class() ).into();
 			Ok( v32 as u64 )
 			},
+		values::VFS_NODE_GETMETA => {
+			let mut dst: FreezeMut<values::VFSMetadata> = args.get()?;
+			log_debug!("VFS_NODE_GETMETA({:p})", &*dst);
+			let res = to_result(self.0.get_metadata())
+				.map(|m| {
+					let time = |t: Option<::vfs::node::Timestamp>| t.map(|v| v.secs).unwrap_or(values::VFS_TIME_UNKNOWN);
+					*dst = values::VFSMetadata {
+						size: m.size,
+						atime: time(m.atime),
+						mtime: time(m.mtime),
+						ctime: time(m.ctime),
+						crtime: time(m.crtime),
+						uid: m.uid,
+						gid: m.gid,
+						links: m.links,
+						mode: m.mode,
+						};
+					0u32
+					});
+			Ok( super::from_result(res) )
+			},
 		_ => objects::object_has_no_such_method_ref("vfs::Node", call),
 		}
 	}
@@ -129,7 +239,7 @@ impl objects::Object for Node
 			log_debug!("VFS_NODE_TOFILE({:?})", mode);
 
 			let objres = to_result(inner.into_file(mode.into()))
-				.map( |h| objects::new_object(File(h)) );
+				.map( |h| objects::new_object(File::new(h)) );
 			Ok( super::from_result(objres) )
 			},
 		values::VFS_NODE_TODIR => {
@@ -154,25 +264,37 @@ impl objects::Object for Node
 //
 // --------------------------------------------------------------------
 
-struct File(::vfs::handle::File);
+struct File {
+	handle: ::vfs::handle::File,
+	/// The last lock attempt that failed with `FileLocked` (checked by `EV_VFS_FILE_LOCK`)
+	blocked_lock: ::kernel::sync::Spinlock<Option<(u64, u64, handle::LockKind)>>,
+}
+impl File {
+	fn new(handle: ::vfs::handle::File) -> File {
+		File {
+			handle: handle,
+			blocked_lock: ::kernel::sync::Spinlock::new(None),
+		}
+	}
+}
 impl objects::Object for File
 {
 	fn class(&self) -> u16 { values::CLASS_VFS_FILE }
 	fn as_any(&self) -> &dyn Any { self }
 	fn try_clone(&self) -> Option<u32> {
-		Some( crate::objects::new_object( File(self.0.clone()) ) )
+		Some( crate::objects::new_object( File::new(self.handle.clone()) ) )
 	}
 	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
 		match call
 		{
 		values::VFS_FILE_GETSIZE => {
-			Ok( self.0.size() )
+			Ok( self.handle.size() )
 			},
 		values::VFS_FILE_READAT => {
 			let ofs: u64 = args.get()?;
 			let mut dest: FreezeMut<[u8]> = args.get()?;
 			log_debug!("File::readat({}, {:p}+{} bytes)", ofs, dest.as_ptr(), dest.len());
-			match self.0.read(ofs, &mut dest)
+			match self.handle.read(ofs, &mut dest)
 			{
 			Ok(count) => Ok(count as u64),
 			Err(e) => todo!("File::handle_syscall READAT Error {:?}", e),
@@ -182,7 +304,7 @@ impl objects::Object for File
 			let ofs: u64 = args.get()?;
 			let src: Freeze<[u8]> = args.get()?;
 			log_debug!("File::writeat({}, {:p}+{} bytes)", ofs, src.as_ptr(), src.len());
-			match self.0.write(ofs, &src)
+			match self.handle.write(ofs, &src)
 			{
 			Ok(count) => Ok(count as u64),
 			Err(e) => todo!("File::handle_syscall WRITEAT Error {:?}", e),
@@ -205,7 +327,7 @@ impl objects::Object for File
 				};
 			log_debug!("VFS_FILE_MEMMAP({:#x}, {:#x}+{}, {:?})", ofs, addr, size, mode);
 			
-			match self.0.memory_map(addr, ofs, size, mode)
+			match self.handle.memory_map(addr, ofs, size, mode)
 			{
 			Ok(h) => {
 				// TODO: I would like the map handle to be available, but I'd like the user to be able to "forget" it
@@ -218,18 +340,74 @@ impl objects::Object for File
 			Err(e) => todo!("File::handle_syscall MEMMAP Error {:?}", e),
 			}
 			},
+		values::VFS_FILE_LOCK => {
+			let ofs: u64 = args.get()?;
+			let len: u64 = args.get()?;
+			let kind = match args.get::<u8>()?
+				{
+				0 => handle::LockKind::Shared,
+				1 => handle::LockKind::Exclusive,
+				v @ _ => {
+					log_log!("VFS_FILE_LOCK - Bad lock flag {}", v);
+					return Err( Error::BadValue );
+					},
+				};
+			log_debug!("VFS_FILE_LOCK({:#x}+{:#x}, {:?})", ofs, len, kind);
+			let res = self.handle.try_lock(ofs, len, kind);
+			*self.blocked_lock.lock() = match res
+				{
+				Err(::vfs::Error::Locked) => Some( (ofs, len, kind) ),
+				_ => None,
+				};
+			Ok( super::from_result( to_result(res).map(|()| 0u32) ) )
+			},
+		values::VFS_FILE_UNLOCK => {
+			let ofs: u64 = args.get()?;
+			let len: u64 = args.get()?;
+			log_debug!("VFS_FILE_UNLOCK({:#x}+{:#x})", ofs, len);
+			Ok( super::from_result( to_result(self.handle.unlock(ofs, len)).map(|()| 0u32) ) )
+			},
+		values::VFS_FILE_SYNC => {
+			log_debug!("VFS_FILE_SYNC()");
+			Ok( super::from_result( to_result(self.handle.sync()).map(|()| 0u32) ) )
+			},
 		_ => crate::objects::object_has_no_such_method_ref("vfs::File", call),
 		}
 	}
-	fn bind_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
-	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_VFS_FILE_LOCK != 0 {
+			self.handle.bind_wait_lock(obj);
+			// The lock may have been released between the failed attempt and this wait
+			if let Some((ofs, len, kind)) = *self.blocked_lock.lock() {
+				if !self.handle.lock_would_block(ofs, len, kind) {
+					obj.signal();
+				}
+			}
+			ret += 1;
+		}
+		ret
+	}
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_VFS_FILE_LOCK != 0 {
+			self.handle.clear_wait_lock(obj);
+			// Only fires once the blocking lock has been released
+			if let Some((ofs, len, kind)) = *self.blocked_lock.lock() {
+				if !self.handle.lock_would_block(ofs, len, kind) {
+					ret += 1;
+				}
+			}
+		}
+		ret
+	}
 }
 
 #[cfg(feature="native")]
 /// Used by the native "kernel" to get a file object for `new_process`
 pub fn get_file_handle(obj: u32) -> Result<::vfs::handle::File, crate::Error> {
 	crate::objects::take_object::<crate::vfs::File>(obj)
-		.map(|f| f.0)
+		.map(|f| f.handle)
 }
 
 
@@ -282,6 +460,23 @@ impl objects::Object for Dir
 		values::VFS_DIR_ENUMERATE => {
 			objects::new_object( DirIter::new( self.handle.clone() ) ) as u64
 			},
+		values::VFS_DIR_RENAME => {
+			let old_name: Freeze<[u8]> = args.get()?;
+			let new_dir: u32 = args.get()?;
+			let new_name: Freeze<[u8]> = args.get()?;
+
+			let old_name = ::kernel::lib::byte_str::ByteStr::new(&*old_name);
+			let new_name = ::kernel::lib::byte_str::ByteStr::new(&*new_name);
+			log_debug!("VFS_DIR_RENAME({:?}, {}, {:?})", old_name, new_dir, new_name);
+			// Handle 0 (never a directory) refers to this directory, as this object can't be locked again
+			let res = if new_dir == 0 {
+					self.handle.rename(old_name, &self.handle, new_name)
+				}
+				else {
+					objects::with_object_ref(new_dir, |d: &Dir| self.handle.rename(old_name, &d.handle, new_name))?
+				};
+			super::from_result( to_result(res).map(|()| 0u32) )
+			},
 		_ => return crate::objects::object_has_no_such_method_ref("vfs::Dir", call),
 		})
 	}
@@ -7,7 +7,8 @@
 use ::kernel::prelude::*;
 use ::kernel::lib::byte_str::{ByteStr,ByteString};
 use ::kernel::PAGE_SIZE;
-use super::node::{NodeType};
+use super::node::{NodeType,Metadata,MetadataUpdate};
+use super::node::{ACCESS_READ,ACCESS_WRITE,ACCESS_EXEC};
 use super::node_cache::{CacheHandle};
 use super::Path;
 
@@ -16,11 +17,13 @@ use super::Path;
 pub struct Any {
 	node: CacheHandle,
 }
-#[derive(Debug,Clone)]
+#[derive(Debug)]
 /// Normal file
 pub struct File {
 	node: super::node_cache::CacheHandleFile,
 	mode: FileOpenMode,
+	/// Identifies this handle as the owner of advisory range locks
+	lock_owner: usize,
 }
 #[derive(Debug,Clone)]
 /// Directory (for enumeration)
@@ -69,6 +72,16 @@ pub enum FileOpenMode
 	Unsynch,
 }
 
+#[derive(Debug,Copy,Clone,PartialEq)]
+/// Advisory lock type for `File::lock`
+pub enum LockKind
+{
+	/// Multiple handles can hold a shared lock on a range
+	Shared,
+	/// Only one handle can hold a lock on the range
+	Exclusive,
+}
+
 #[derive(Debug)]
 pub enum MemoryMapMode
 {
@@ -96,6 +109,15 @@ impl Any
 		self.node.get_class()
 	}
 	
+	/// Read the node's metadata
+	pub fn get_metadata(&self) -> super::Result<Metadata> {
+		self.node.get_metadata()
+	}
+	/// Update the node's metadata (mode, ownership, times)
+	pub fn set_metadata(&self, changes: &MetadataUpdate) -> super::Result<()> {
+		self.node.set_metadata(changes)
+	}
+	
 	/// Upgrade the handle to a directory handle
 	pub fn into_dir(self) -> super::Result<Dir> {
 		Ok(Dir { node: self.node.into_dir()? })
@@ -136,25 +158,60 @@ impl File
 		match mode
 		{
 		FileOpenMode::NoDataAccess => {},
-		// TODO: Check permissions (must be readable in current context)
-		FileOpenMode::SharedRO => { node.file_lock_shared()?; },
-		// TODO: Check permissions (must be writable in current context)
-		FileOpenMode::Append => { node.file_lock_shared()?; },
-		// TODO: Check permissions (must be executable in current context)
-		FileOpenMode::Execute => { node.file_lock_shared()?; },
-		// TODO: Check permissions (must be writable in current context)
-		FileOpenMode::ExclRW => { node.file_lock_exclusive()?; }
-		FileOpenMode::Unsynch => { node.file_lock_unsynch()?; }
-		FileOpenMode::UniqueRW => todo!("UniqueRW - CoW"),
+		FileOpenMode::SharedRO => {
+			node.check_access(ACCESS_READ)?;
+			node.file_lock_shared()?;
+			},
+		FileOpenMode::Append => {
+			node.check_access(ACCESS_WRITE)?;
+			node.file_lock_shared()?;
+			},
+		FileOpenMode::Execute => {
+			node.check_access(ACCESS_EXEC)?;
+			node.file_lock_shared()?;
+			},
+		FileOpenMode::ExclRW => {
+			node.check_access(ACCESS_READ|ACCESS_WRITE)?;
+			node.file_lock_exclusive()?;
+			},
+		FileOpenMode::Unsynch => {
+			node.check_access(ACCESS_READ|ACCESS_WRITE)?;
+			node.file_lock_unsynch()?;
+			},
+		// TODO: Needs copy-on-write support
+		FileOpenMode::UniqueRW => return Err(super::Error::Unsupported),
 		}
-		Ok(File { node: node, mode: mode })
+		Ok(File { node: node, mode: mode, lock_owner: next_lock_owner() })
 	}
 	
 	pub fn size(&self) -> u64 {
 		self.node.get_valid_size()
 	}
-	pub fn truncate(&self) {
-		todo!("Handle::truncate()");
+	pub fn get_metadata(&self) -> super::Result<Metadata> {
+		self.node.get_metadata()
+	}
+	pub fn set_metadata(&self, changes: &MetadataUpdate) -> super::Result<()> {
+		self.node.set_metadata(changes)
+	}
+	/// Set the size of the file (zero-extending or truncating), returns the new size
+	pub fn truncate(&self, newsize: u64) -> super::Result<u64> {
+		match self.mode
+		{
+		FileOpenMode::ExclRW
+		|FileOpenMode::UniqueRW
+		|FileOpenMode::Unsynch => self.node.truncate(newsize),
+		_ => Err(super::Error::PermissionDenied),
+		}
+	}
+	/// Replace a range of the file with zeroes
+	pub fn clear(&self, ofs: u64, len: u64) -> super::Result<()> {
+		match self.mode
+		{
+		FileOpenMode::ExclRW
+		|FileOpenMode::UniqueRW
+		|FileOpenMode::Unsynch => self.node.clear(ofs, len),
+		_ => Err(super::Error::PermissionDenied),
+		}
 	}
 
 	/// Read data from the file at the specified offset
@@ -181,7 +238,58 @@ impl File
 		|FileOpenMode::Unsynch => self.node.write(ofs, src),
 		}
 	}
+	/// Write any cached data for this file back to the volume
+	pub fn sync(&self) -> super::Result<()> {
+		self.node.sync()
+	}
+
 
+	/// Take an advisory lock on `len` bytes from `ofs` (a length of zero locks to the end of the file), failing with
+	/// `Locked` if another handle holds a conflicting lock
+	///
+	/// Locking a range this handle already holds replaces the existing lock (e.g. to upgrade or downgrade it).
+	pub fn try_lock(&self, ofs: u64, len: u64, kind: LockKind) -> super::Result<()> {
+		match self.mode
+		{
+		FileOpenMode::NoDataAccess => Err(super::Error::PermissionDenied),
+		_ => self.node.range_lock(self.lock_owner, ofs, len, kind == LockKind::Exclusive),
+		}
+	}
+	/// Take an advisory lock, blocking until conflicting locks are released
+	pub fn lock(&self, ofs: u64, len: u64, kind: LockKind) -> super::Result<()> {
+		::kernel::threads::SleepObject::with_new("File::lock", |obj| {
+			loop
+			{
+				// Bind before trying, so a release between the attempt and the sleep isn't missed
+				self.node.range_lock_wait_upon(obj);
+				let rv = self.try_lock(ofs, len, kind);
+				if let Err(super::Error::Locked) = rv {
+					obj.wait();
+				}
+				self.node.range_lock_clear_wait(obj);
+				match rv
+				{
+				Err(super::Error::Locked) => {},
+				_ => return rv,
+				}
+			}
+			})
+	}
+	/// Release this handle's advisory locks on a range (locks are also released when the handle is dropped)
+	pub fn unlock(&self, ofs: u64, len: u64) -> super::Result<()> {
+		self.node.range_unlock(self.lock_owner, ofs, len)
+	}
+	/// Check if `try_lock` would currently fail
+	pub fn lock_would_block(&self, ofs: u64, len: u64, kind: LockKind) -> bool {
+		self.node.range_lock_conflicts(self.lock_owner, ofs, len, kind == LockKind::Exclusive)
+	}
+	/// Register to be woken when an advisory lock on this file is released
+	pub fn bind_wait_lock(&self, obj: &mut ::kernel::threads::SleepObject) {
+		self.node.range_lock_wait_upon(obj)
+	}
+	pub fn clear_wait_lock(&self, obj: &mut ::kernel::threads::SleepObject) {
+		self.node.range_lock_clear_wait(obj)
+	}
 	
 	/// Map a file into the address space
 	pub fn memory_map(&self, address: usize, ofs: u64, size: usize, mode: MemoryMapMode) -> super::Result<MemoryMapHandle> {
@@ -243,24 +351,14 @@ impl File
 				return Err( super::Error::Locked );
 				},
 			};
-		// - Obtain handles to each cached page, and map into the reservation
+		// - Share the file's cached pages where possible, otherwise map a copy of the data
 		for i in 0 .. page_count {
 			let page = ofs / PAGE_SIZE as u64 + i as u64;
-			// 1. Search the node for this particular page
-			//let lh = self.page_cache.read();
-			//  - If found, map over region
-			// 2. Drop lock, read data from file, and try again
-			//drop(lh)
-			self.node.read(page * PAGE_SIZE as u64, resv.get_mut_page(i))?;
-			// 3. Acquire write on lock, and attempt to insert a handle to this page
-			//let lh = self.page_cache.write();
-			//match lh.try_insert(pag, self.get_page_handle(i))
-			//{
-			//Ok(_) => {},	// Inserted correctly
-			//Err(h) => {	// Another handle made a page for this first
-			//	resv.map_at(i, h);	// - Map over our original attempt
-			//	},
-			//}
+			match self.node.get_page_frame(page)?
+			{
+			Some(frame) => resv.map_at(i, frame),
+			None => { self.node.read(page * PAGE_SIZE as u64, resv.get_mut_page(i))?; },
+			}
 		}
 		resv.finalise( match mode
 			{
@@ -278,9 +376,22 @@ impl File
 			})
 	}
 }
+/// Allocate a unique advisory lock owner for a new handle
+fn next_lock_owner() -> usize {
+	static S_NEXT_LOCK_OWNER: ::core::sync::atomic::AtomicUsize = ::core::sync::atomic::AtomicUsize::new(1);
+	S_NEXT_LOCK_OWNER.fetch_add(1, ::core::sync::atomic::Ordering::Relaxed)
+}
+/// NOTE: A cloned handle is a separate lock owner, advisory locks are not shared
+impl Clone for File
+{
+	fn clone(&self) -> File {
+		File { node: self.node.clone(), mode: self.mode.clone(), lock_owner: next_lock_owner() }
+	}
+}
 impl ::core::ops::Drop for File
 {
 	fn drop(&mut self) {
+		self.node.range_unlock_all(self.lock_owner);
 		match self.mode
 		{
 		FileOpenMode::NoDataAccess => {},
@@ -289,7 +400,8 @@ impl ::core::ops::Drop for File
 		| FileOpenMode::Execute => self.node.file_unlock_shared(),
 		FileOpenMode::ExclRW => self.node.file_unlock_exclusive(),
 		FileOpenMode::Unsynch => self.node.file_unlock_unsync(),
-		FileOpenMode::UniqueRW => todo!("File::drop() - mode={:?}", self.mode),
+		// Never opened (see `from_node`)
+		FileOpenMode::UniqueRW => {},
 		}
 	}
 }
@@ -332,34 +444,63 @@ impl Dir
 	
 	/// Create a new directory
 	pub fn mkdir(&self, name: impl AsRef<ByteStr>) -> super::Result<Dir> {
+		self.node.check_access(ACCESS_WRITE|ACCESS_EXEC)?;
 		let node = self.node.create(name.as_ref(), NodeType::Dir)?;
 		Ok( Dir { node: node.into_dir()? } )
 	}
 	/// Create a new symbolic link
 	pub fn symlink(&self, name: impl AsRef<ByteStr>, target: &Path) -> super::Result<()> {
+		self.node.check_access(ACCESS_WRITE|ACCESS_EXEC)?;
 		self.node.create(name.as_ref(), NodeType::Symlink(target))?;
 		Ok( () )
 	}
 	/// Create a new file (opened exclusively)
 	pub fn create_file(&self, name: impl AsRef<ByteStr>) -> super::Result<File> {
+		self.node.check_access(ACCESS_WRITE|ACCESS_EXEC)?;
 		let node = self.node.create(name.as_ref(), NodeType::File)?;
 		File::from_node(node.into_file()?, FileOpenMode::ExclRW)
 	}
 
 	/// Open a child of this node
 	pub fn open_child(&self, name: &ByteStr) -> super::Result<Any> {
+		self.node.check_access(ACCESS_EXEC)?;
 		let node = self.node.open_child(name)?;
 		Ok(Any { node: node })
 	}
 
+	/// Add a new name for an existing node
+	pub fn link(&self, name: impl AsRef<ByteStr>, node: &Any) -> super::Result<()> {
+		self.node.check_access(ACCESS_WRITE|ACCESS_EXEC)?;
+		self.node.link(name.as_ref(), &node.node)
+	}
+	/// Remove a name (directories must be empty)
+	pub fn unlink(&self, name: impl AsRef<ByteStr>) -> super::Result<()> {
+		self.node.check_access(ACCESS_WRITE|ACCESS_EXEC)?;
+		self.node.unlink(name.as_ref())
+	}
+	/// Move a name to `new_name` in `new_dir` (replacing an existing entry), both must be on the same volume
+	pub fn rename(&self, old_name: impl AsRef<ByteStr>, new_dir: &Dir, new_name: impl AsRef<ByteStr>) -> super::Result<()> {
+		self.node.check_access(ACCESS_WRITE|ACCESS_EXEC)?;
+		new_dir.node.check_access(ACCESS_WRITE|ACCESS_EXEC)?;
+		self.node.rename(old_name.as_ref(), &new_dir.node, new_name.as_ref())
+	}
+
 	pub fn open_child_path(&self, path: &Path) -> super::Result<Any> {
 		let node = CacheHandle::from_path_at_node(self.node.clone(), path)?;
 		Ok(Any{ node: node })
 	}
 
 
+	pub fn get_metadata(&self) -> super::Result<Metadata> {
+		self.node.get_metadata()
+	}
+	pub fn set_metadata(&self, changes: &MetadataUpdate) -> super::Result<()> {
+		self.node.set_metadata(changes)
+	}
+
 	/// RETURN: next position
 	pub fn read_ents(&self, pos: usize, ents: &mut super::node::ReadDirCallback) -> super::Result<usize> {
+		self.node.check_access(ACCESS_READ)?;
 		self.node.read_dir(pos, ents)
 	}
 }
@@ -377,7 +518,7 @@ impl<'a> ::core::iter::Iterator for DirIter<'a> {
 		if self.ofs == self.count
 		{
 			self.count = 0;
-			match self.handle.node.read_dir(self.pos, &mut |inode, name| {
+			match self.handle.read_ents(self.pos, &mut |inode, name| {
 				self.ents[self.count].0 = inode;
 				self.ents[self.count].1 = name.collect();
 				self.count += 1;
@@ -413,5 +554,11 @@ impl Symlink
 	pub fn get_target(&self) -> super::Result<ByteString> {
 		self.node.get_target()
 	}
+	pub fn get_metadata(&self) -> super::Result<Metadata> {
+		self.node.get_metadata()
+	}
+	pub fn set_metadata(&self, changes: &MetadataUpdate) -> super::Result<()> {
+		self.node.set_metadata(changes)
+	}
 }
 
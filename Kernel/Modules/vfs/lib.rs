@@ -27,6 +27,8 @@ pub enum Error
 	Locked,
 	/// The item already exists
 	AlreadyExists,
+	/// Directory still has entries (so can't be removed)
+	DirectoryNotEmpty,
 
 	/// Path was malformed (too long, not absolute, not normalised, ... depends)
 	MalformedPath,
@@ -38,6 +40,10 @@ pub enum Error
 	NonDirComponent,
 	/// Symbolic link recursion limit reached
 	RecursionDepthExceeded,
+	/// Operation would move a node between mounted volumes
+	CrossVolume,
+	/// Operation (or open mode) isn't supported
+	Unsupported,
 
 
 	/// Block-level IO Error
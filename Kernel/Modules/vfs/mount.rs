@@ -19,13 +19,101 @@ pub struct Handle(usize);
 
 /// Handle to a mounted filesystem held by the filesystem itself
 ///
-/// Allows access to the node cache
-pub struct SelfHandle(usize);
+/// Allows access to the node cache and the driver-specific mount options
+pub struct SelfHandle(usize, PermissionDefaults, Vec<String>);
+
+/// Ownership and permissions reported by filesystems that don't store them (e.g. FAT and NTFS)
+///
+/// Set using the `uid=`, `gid=` and `umask=` (octal) mount options
+#[derive(Debug,Copy,Clone)]
+pub struct PermissionDefaults
+{
+	pub uid: u32,
+	pub gid: u32,
+	pub umask: u16,
+}
+impl Default for PermissionDefaults {
+	fn default() -> Self {
+		PermissionDefaults { uid: 0, gid: 0, umask: 0o022 }
+	}
+}
+impl PermissionDefaults
+{
+	/// Mode for a node (without the write bits if it's flagged as read-only)
+	pub fn mode(&self, readonly: bool) -> u16 {
+		let mode = 0o777 & !self.umask;
+		if readonly { mode & !0o222 } else { mode }
+	}
+
+	/// Apply an ownership option, returns `Ok(false)` if the option isn't one of these
+	fn apply(&mut self, name: &str, value: &str) -> Result<bool,MountError> {
+		match name
+		{
+		"uid" => self.uid = value.parse().map_err(|_| MountError::BadOption)?,
+		"gid" => self.gid = value.parse().map_err(|_| MountError::BadOption)?,
+		"umask" => self.umask = u16::from_str_radix(value, 8).map_err(|_| MountError::BadOption)? & 0o777,
+		_ => return Ok(false),
+		}
+		Ok(true)
+	}
+}
+
+/// Per-mount behaviour, set by the `ro`/`rw` and `sync`/`async` mount options (and changable by `remount`)
+#[derive(Debug,Copy,Clone,Default)]
+pub struct MountFlags
+{
+	/// Modifications are rejected with `ReadOnlyFilesystem`
+	pub read_only: bool,
+	/// The filesystem is synced after every modification
+	pub sync: bool,
+}
+impl MountFlags
+{
+	/// Apply a flag option, returns `false` if the option isn't a flag
+	fn apply(&mut self, name: &str) -> bool {
+		match name
+		{
+		"ro" => self.read_only = true,
+		"rw" => self.read_only = false,
+		"sync" => self.sync = true,
+		"async" => self.sync = false,
+		_ => return false,
+		}
+		true
+	}
+}
+
+fn split_option(opt: &str) -> (&str, &str) {
+	match opt.find('=')
+	{
+	Some(p) => (&opt[..p], &opt[p+1..]),
+	None => (opt, ""),
+	}
+}
+/// Parse mount options, options not handled here are returned for the driver (see `SelfHandle::get_option`)
+fn parse_options(options: &[&str]) -> Result<(MountFlags,PermissionDefaults,Vec<String>),MountError> {
+	let mut flags = MountFlags::default();
+	let mut perms = PermissionDefaults::default();
+	let mut driver_options = Vec::new();
+	for opt in options
+	{
+		let (name, value) = split_option(opt);
+		if !flags.apply(name) && !perms.apply(name, value)? {
+			driver_options.push(String::from(*opt));
+		}
+	}
+	Ok( (flags, perms, driver_options) )
+}
 
 /// Internal representation of a mounted volume
 struct MountedVolume
 {
-	mountpoint_node: super::node_cache::CacheHandleDir,
+	/// Directory this volume is mounted on (`None` for the root volume)
+	mountpoint_node: Option<super::node_cache::CacheHandleDir>,
+	driver: &'static str,
+	flags: MountFlags,
+	/// The driver was mounted read-only (so can't be remounted read-write)
+	mounted_read_only: bool,
 	fs: Box<dyn Filesystem>,
 }
 
@@ -36,6 +124,13 @@ pub trait Filesystem:
 {
 	fn root_inode(&self) -> InodeId;
 	fn get_node_by_inode(&self, _: InodeId) -> Option<Node>;
+
+	/// Write any cached state back to the volume
+	///
+	/// Called after each modification on `sync` mounts, when remounting read-only, and before unmounting
+	fn sync(&self) -> super::Result<()> {
+		Ok( () )
+	}
 }
 
 struct NullFs;
@@ -55,8 +150,11 @@ pub trait Driver:
 
 	/// Mount the provided volume as this filesystem
 	///
+	/// If `read_only` is set (the `ro` option) the driver must not write to the volume, including marking it as
+	/// in use. Modifications are also rejected by the VFS.
+	///
 	/// NOTE: `handle` isn't actually usable until after this function returns
-	fn mount(&self, vol: VolumeHandle, handle: SelfHandle) -> super::Result<Box<dyn Filesystem>>;
+	fn mount(&self, vol: VolumeHandle, read_only: bool, handle: SelfHandle) -> super::Result<Box<dyn Filesystem>>;
 }
 
 pub struct DriverRegistration(&'static str);
@@ -64,9 +162,11 @@ pub struct DriverRegistration(&'static str);
 /// Known drivers
 static S_DRIVERS: LazyStatic<RwLock< VecMap<&'static str, &'static dyn Driver> >> = lazystatic_init!();
 /// Mounted volumes
+///
+/// NOTE: When both are needed, the node cache lock must be acquired before this
 static S_VOLUMES: LazyStatic<RwLock< SparseVec<MountedVolume> >> = lazystatic_init!();
 /// Root mount
-static S_ROOT_VOLUME: RwLock<Option<Box<dyn Filesystem>>> = RwLock::new(None);
+static S_ROOT_VOLUME: RwLock<Option<MountedVolume>> = RwLock::new(None);
 
 pub fn init()
 {
@@ -75,25 +175,25 @@ pub fn init()
 }
 
 /// Mount a volume at the provided location
-// TODO: Parse options
-pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) -> Result<(),MountError>
+pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, options: &[&str]) -> Result<(),MountError>
 {
+	let (flags, perms, driver_options) = parse_options(options)?;
 	let drivers = S_DRIVERS.read();
 	// 1. (maybe) detect filesystem
-	let driver = if fs == "" {
+	let (driver_name, driver) = if fs == "" {
 			match drivers.iter()
 				.filter_map(|(n,fs)| fs.detect(&vol).ok().map(|r| (r, n, fs)))
 				.max_by_key(|&(l,_,_)| l)
 			{
 			Some((0,_,_)) => return Err(MountError::NoHandler),
-			Some((_,_name,fs)) => fs,
+			Some((_,name,fs)) => (*name, *fs),
 			None => return Err(MountError::NoHandler),
 			}
 		}
 		else {
-			match drivers.get(fs)
+			match drivers.iter().find(|&(n,_)| *n == fs)
 			{
-			Some(d) => d,
+			Some((name,d)) => (*name, *d),
 			None => {
 				log_notice!("Filesystem '{}' not registered", fs);
 				return Err(MountError::UnknownFilesystem);
@@ -103,17 +203,20 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 	
 	if location == Path::new("/")
 	{
-		let fs: Box<_> = match driver.mount(vol, SelfHandle(0))
+		if S_ROOT_VOLUME.read().is_some() {
+			// Use `remount` to change the options
+			return Err(MountError::MountpointUsed);
+		}
+		let fs: Box<_> = match driver.mount(vol, flags.read_only, SelfHandle(0, perms, driver_options))
 			{
 			Ok(v) => v,
 			Err(_) => return Err(MountError::CallFailed),
 			};
 		let mut lh = S_ROOT_VOLUME.write();
 		if lh.is_some() {
-			log_warning!("TODO: Support remounting /");
 			return Err(MountError::MountpointUsed);
 		}
-		*lh = Some(fs);
+		*lh = Some(MountedVolume { mountpoint_node: None, driver: driver_name, flags, mounted_read_only: flags.read_only, fs });
 	}
 	else
 	{
@@ -130,28 +233,140 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 		
 		// 3. Reserve the mountpoint ID (using a placeholder instance)
 		// NOTE: Nothing should know of this index until after mount is completed
-		let vidx = S_VOLUMES.write().insert(MountedVolume { mountpoint_node: nh, fs: Box::new(NullFs) });
+		let vidx = S_VOLUMES.write().insert(MountedVolume { mountpoint_node: Some(nh), driver: driver_name, flags, mounted_read_only: flags.read_only, fs: Box::new(NullFs) });
 
 		// 4. Mount and register volume
-		let fs = match driver.mount(vol, SelfHandle(vidx))
+		let fs = match driver.mount(vol, flags.read_only, SelfHandle(vidx, perms, driver_options))
 			{
 			Ok(v) => v,
-			Err(_) => return Err(MountError::CallFailed),
+			Err(_) => {
+				// Release the placeholder outside the lock (dropping the mountpoint handle locks the node cache)
+				let placeholder = S_VOLUMES.write().remove(vidx);
+				drop(placeholder);
+				return Err(MountError::CallFailed);
+				},
 			};
 
 		// 5. Store and bind to mountpoint
-		{
+		let failed = {
 			let mut lh = S_VOLUMES.write();
 			lh[vidx].fs = fs;
-			if lh[vidx].mountpoint_node.mount(vidx + 1) == false {
-				lh.remove(vidx);
-				return Err(MountError::MountpointUsed);
+			if lh[vidx].mountpoint_node.as_ref().unwrap().mount(vidx + 1) == false {
+				lh.remove(vidx)
 			}
+			else {
+				None
+			}
+			};
+		if failed.is_some() {
+			return Err(MountError::MountpointUsed);
 		}
 	}
 
 	Ok( () )
 }
+
+/// Unmount the volume mounted at `location`
+///
+/// Fails with `MountError::Busy` if any node on the volume (or a volume mounted within it) is still in use
+pub fn unmount(location: &Path) -> Result<(),MountError>
+{
+	let id = find_volume(location)?;
+	if id == 0 {
+		log_notice!("Refusing to unmount /");
+		return Err(MountError::Busy);
+	}
+	// The node cache is locked while the volume is removed, so nothing can open a node on it in the meantime
+	let vol = match super::node_cache::with_volume_unused(id, || {
+			super::node_cache::invalidate_volume(id);
+			S_VOLUMES.write().remove(id - 1)
+			})
+		{
+		Some(v) => v.expect("Mounted volume vanished during unmount"),
+		None => return Err(MountError::Busy),
+		};
+	let mountpoint_node = vol.mountpoint_node.expect("Non-root volume without a mountpoint");
+	mountpoint_node.unmount(id);
+	log_log!("Unmounted {:?} ({})", location, vol.driver);
+	if let Err(e) = vol.fs.sync() {
+		log_error!("Unable to sync {:?} before unmount: {:?}", location, e);
+	}
+	// Dropping the filesystem releases the volume (drivers mark it as clean at this point)
+	drop(vol.fs);
+	Ok( () )
+}
+
+/// Change the flags (`ro`/`rw`/`sync`/`async`) of the volume mounted at `location`
+///
+/// Ownership options (`uid`/`gid`/`umask`) can only be set when mounting. Switching to read-only leaves open files
+/// open, but further writes are rejected. Volumes mounted read-only can't be switched to read-write (the driver
+/// hasn't prepared the volume for writing), they have to be unmounted and mounted again.
+pub fn remount(location: &Path, options: &[&str]) -> Result<(),MountError>
+{
+	let id = find_volume(location)?;
+	let mh = Handle::from_id(id).map_err(|_| MountError::NotMounted)?;
+	let mut flags = mh.flags().map_err(|_| MountError::NotMounted)?;
+	for opt in options
+	{
+		let (name, _) = split_option(opt);
+		if !flags.apply(name) {
+			log_notice!("Mount option {:?} can't be changed by remount", opt);
+			return Err(MountError::BadOption);
+		}
+	}
+	let was_ro = mh.with_volume_mut(|v| {
+			if v.mounted_read_only && !flags.read_only {
+				log_notice!("{:?} was mounted read-only, it must be mounted again to be writable", location);
+				return Err(MountError::BadOption);
+			}
+			Ok( ::core::mem::replace(&mut v.flags, flags).read_only )
+			})
+		.map_err(|_| MountError::NotMounted)??;
+	// Writes are rejected from here on, so flush anything written before the switch
+	if flags.read_only && !was_ro {
+		if let Err(e) = super::node_cache::sync_volume(id).and_then(|_| mh.sync()) {
+			log_error!("Unable to sync {:?} when remounting read-only: {:?}", location, e);
+			return Err(MountError::CallFailed);
+		}
+	}
+	Ok( () )
+}
+
+/// Write back all cached file data and sync every mounted volume
+pub fn sync_all() -> super::Result<()>
+{
+	let mut rv = super::node_cache::sync_all_files();
+	let mut ids = Vec::new();
+	if S_ROOT_VOLUME.read().is_some() {
+		ids.push(0);
+	}
+	{
+		let lh = S_VOLUMES.read();
+		ids.extend( (0 .. lh.len()).filter(|&i| lh.get(i).is_some()).map(|i| i + 1) );
+	}
+	for id in ids
+	{
+		if let Err(e) = Handle::from_id(id).and_then(|mh| mh.sync()) {
+			log_error!("Unable to sync volume {}: {:?}", id, e);
+			rv = Err(e);
+		}
+	}
+	rv
+}
+
+/// Get the mount ID of the volume whose root is at `location`
+fn find_volume(location: &Path) -> Result<usize,MountError>
+{
+	let nh = CacheHandle::from_path(location).map_err(|_| MountError::InvalidMountpoint)?;
+	let id = nh.get_mount_id();
+	// Mountpoints are redirected to the root of the mounted volume
+	match Handle::from_id(id).and_then(|mh| mh.root_inode())
+	{
+	Ok(inode) if inode == nh.get_inode() => Ok(id),
+	_ => Err(MountError::NotMounted),
+	}
+}
+
 #[derive(Debug)]
 pub enum MountError
 {
@@ -160,6 +375,9 @@ pub enum MountError
 	InvalidMountpoint,
 	MountpointUsed,
 	CallFailed,
+	BadOption,
+	NotMounted,
+	Busy,
 }
 impl_fmt! {
 	Display(self,f) for MountError {
@@ -170,6 +388,9 @@ impl_fmt! {
 			&MountError::InvalidMountpoint => "The specified mountpoint was invalid",
 			&MountError::MountpointUsed => "The specified mountpoint was already used",
 			&MountError::CallFailed => "Driver's mount call failed",
+			&MountError::BadOption => "A mount option had an invalid value",
+			&MountError::NotMounted => "Nothing is mounted at the specified location",
+			&MountError::Busy => "The volume is in use",
 			})
 	}
 }
@@ -191,41 +412,67 @@ impl DriverRegistration
 }
 impl Drop for DriverRegistration {
 	fn drop(&mut self) {
-		log_error!("TODO: De-register VFS driver {:?}", self.0);
+		// Existing mounts keep their instances, but nothing new can be mounted with this driver
+		let in_use = S_VOLUMES.read().iter().filter(|v| v.driver == self.0).count()
+			+ S_ROOT_VOLUME.read().iter().filter(|v| v.driver == self.0).count();
+		if in_use > 0 {
+			log_warning!("VFS driver {:?} de-registered while still mounted ({} volumes)", self.0, in_use);
+		}
+		S_DRIVERS.write().remove(&self.0);
 	}
 }
 
 impl Handle
 {
-	pub fn from_id(id: usize) -> Handle {
+	/// Obtain a handle to a mounted volume, fails with `NotFound` if the volume has been unmounted
+	pub fn from_id(id: usize) -> super::Result<Handle> {
 		if id == 0 {
-			Handle(0)
+			Ok( Handle(0) )
 		}
 		else {
 			if ! S_VOLUMES.read().get(id-1).is_some() {
-				panic!("Handle::from_id - ID {} not valid", id);
+				return Err(super::Error::NotFound);
 			}
-			Handle(id)
+			Ok( Handle(id) )
 		}
 	}
 	
 	pub fn id(&self) -> usize {
 		self.0
 	}
-	pub fn root_inode(&self) -> InodeId {
-		self.with_fs(|fs| fs.root_inode())
+	pub fn root_inode(&self) -> super::Result<InodeId> {
+		self.with_volume(|v| v.fs.root_inode())
 	}
 	
 	pub fn get_node(&self, id: InodeId) -> Option<Node> {
-		self.with_fs(|fs| fs.get_node_by_inode(id))
+		self.with_volume(|v| v.fs.get_node_by_inode(id)).ok()?
 	}
 
-	fn with_fs<R, F: FnOnce(&dyn Filesystem)->R>(&self, f: F) -> R {
+	pub fn flags(&self) -> super::Result<MountFlags> {
+		self.with_volume(|v| v.flags)
+	}
+	pub fn sync(&self) -> super::Result<()> {
+		self.with_volume(|v| v.fs.sync())?
+	}
+
+	fn with_volume<R, F: FnOnce(&MountedVolume)->R>(&self, f: F) -> super::Result<R> {
 		if self.0 == 0 {
-			f(&**S_ROOT_VOLUME.read().as_ref().unwrap())
+			S_ROOT_VOLUME.read().as_ref().map(f).ok_or(super::Error::NotFound)
 		}
 		else {
-			f(&*S_VOLUMES.read().get(self.0 - 1).unwrap().fs)
+			S_VOLUMES.read().get(self.0 - 1).map(f).ok_or(super::Error::NotFound)
+		}
+	}
+	fn with_volume_mut<R, F: FnOnce(&mut MountedVolume)->R>(&self, f: F) -> super::Result<R> {
+		if self.0 == 0 {
+			S_ROOT_VOLUME.write().as_mut().map(f).ok_or(super::Error::NotFound)
+		}
+		else {
+			let mut lh = S_VOLUMES.write();
+			if lh.get(self.0 - 1).is_none() {
+				return Err(super::Error::NotFound);
+			}
+			Ok( f(&mut lh[self.0 - 1]) )
 		}
 	}
 }
@@ -236,5 +483,15 @@ impl SelfHandle
 	pub fn get_node(&self, inode: InodeId) -> super::Result<CacheHandle> {
 		CacheHandle::from_ids(self.0, inode)
 	}
+	/// Ownership/permissions to report for nodes (for filesystems that don't store them)
+	pub fn permission_defaults(&self) -> PermissionDefaults {
+		self.1
+	}
+	/// Get the value of a driver-specific mount option (`Some("")` if it's present without a value)
+	pub fn get_option(&self, name: &str) -> Option<&str> {
+		self.2.iter()
+			.map(|v| split_option(v))
+			.find(|&(n,_)| n == name)
+			.map(|(_,v)| v)
+	}
 }
-
@@ -17,12 +17,117 @@ pub enum NodeType<'a> {
 	Symlink(&'a super::Path),
 }
 
+/// Point in time, relative to the UNIX epoch (1970-01-01 00:00 UTC)
+#[derive(Debug,Default,Copy,Clone,PartialEq,Eq,PartialOrd,Ord)]
+pub struct Timestamp {
+	pub secs: i64,
+	pub nsecs: u32,
+}
+impl Timestamp {
+	pub fn from_unix(secs: i64) -> Timestamp {
+		Timestamp { secs, nsecs: 0 }
+	}
+	/// Construct from a (proleptic Gregorian) calendar date and time of day
+	pub fn from_civil(year: i64, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Timestamp {
+		// Days since 1970-01-01 (algorithm from Howard Hinnant's `days_from_civil`)
+		let y = if month <= 2 { year - 1 } else { year };
+		let era = y.div_euclid(400);
+		let yoe = y.rem_euclid(400);
+		let m = month as i64;
+		let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+		let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+		let days = era * 146097 + doe - 719468;
+		Timestamp::from_unix(days * 86400 + hour as i64 * 3600 + minute as i64 * 60 + second as i64)
+	}
+	/// Split into a calendar date and time of day: `(year, month, day, hour, minute, second)`
+	pub fn to_civil(&self) -> (i64, u8, u8, u8, u8, u8) {
+		let days = self.secs.div_euclid(86400);
+		let tod = self.secs.rem_euclid(86400);
+		let z = days + 719468;
+		let era = z.div_euclid(146097);
+		let doe = z.rem_euclid(146097);
+		let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+		let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+		let mp = (5 * doy + 2) / 153;
+		let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+		let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u8;
+		let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+		(year, month, day, (tod / 3600) as u8, (tod / 60 % 60) as u8, (tod % 60) as u8)
+	}
+}
+
+/// Node metadata (as returned by `NodeBase::get_metadata`)
+///
+/// Fields that the filesystem doesn't track are left as their default (zero/`None`)
+#[derive(Debug,Default,Clone)]
+pub struct Metadata {
+	/// Size in bytes (zero for directories where the size isn't meaningful)
+	pub size: u64,
+	/// Number of names referring to this node
+	pub links: u32,
+	/// UNIX-style permission bits (`0o7777`)
+	pub mode: u16,
+	pub uid: u32,
+	pub gid: u32,
+	/// Last access time
+	pub atime: Option<Timestamp>,
+	/// Last modification time (data)
+	pub mtime: Option<Timestamp>,
+	/// Last change time (metadata)
+	pub ctime: Option<Timestamp>,
+	/// Creation time
+	pub crtime: Option<Timestamp>,
+}
+
+/// Access requested from `Metadata::allows` (same bit layout as each `rwx` triplet in the mode)
+pub const ACCESS_READ: u16 = 4;
+pub const ACCESS_WRITE: u16 = 2;
+pub const ACCESS_EXEC: u16 = 1;
+
+impl Metadata {
+	/// Check if a process with the given credentials may access this node
+	pub fn allows(&self, creds: &::kernel::threads::Credentials, access: u16) -> bool {
+		if creds.is_root() {
+			return true;
+		}
+		let bits = if creds.uid == self.uid {
+				self.mode >> 6
+			}
+			else if creds.gid == self.gid {
+				self.mode >> 3
+			}
+			else {
+				self.mode
+			};
+		bits & access == access
+	}
+}
+
+/// Set of changes to apply with `NodeBase::set_metadata`
+///
+/// Changes that can't be represented by the filesystem (e.g. ownership on FAT) are silently ignored
+#[derive(Debug,Default,Clone)]
+pub struct MetadataUpdate {
+	pub mode: Option<u16>,
+	pub uid: Option<u32>,
+	pub gid: Option<u32>,
+	pub atime: Option<Timestamp>,
+	pub mtime: Option<Timestamp>,
+}
+
 /// Base trait for a VFS node, defines common operation on nodes
 pub trait NodeBase: Send {
 	/// Return the volume's inode number
 	fn get_id(&self) -> InodeId;
 	/// Return an &Any associated with this node (not necessarily same as `self`, up to the driver)
 	fn get_any(&self) -> &dyn Any;
+	/// Read the node's metadata (sizes, times, permissions and ownership)
+	fn get_metadata(&self) -> Result<Metadata>;
+	/// Update the node's metadata (only for writable filesystems)
+	fn set_metadata(&self, changes: &MetadataUpdate) -> Result<()> {
+		let _ = changes;
+		Err(super::Error::ReadOnlyFilesystem)
+	}
 }
 /// Trait for "File" nodes
 pub trait File: NodeBase {
@@ -36,6 +141,17 @@ pub trait File: NodeBase {
 	fn read(&self, ofs: u64, buf: &mut [u8]) -> Result<usize>;
 	/// Write data to the file, can only grow the file if ofs==size
 	fn write(&self, ofs: u64, buf: &[u8]) -> Result<usize>;
+	/// Returns true if file data should be held in the VFS page cache (false if it's already in memory)
+	fn cacheable(&self) -> bool {
+		true
+	}
+	/// Get the frame holding a page of data, so memory mappings can share it (only used if the file isn't cacheable)
+	///
+	/// Returns `None` if the data isn't held in memory, or the page is past the end of the file
+	fn get_page_frame(&self, page: u64) -> Result<Option<::kernel::memory::phys::FrameHandle>> {
+		let _ = page;
+		Ok(None)
+	}
 }
 
 // TODO: Should this be &ByteStr instead of an iterator?
@@ -63,6 +179,10 @@ pub trait Dir: NodeBase {
 	fn link(&self, name: &ByteStr, inode: &dyn NodeBase) -> Result<()>;
 	/// Remove the specified name
 	fn unlink(&self, name: &ByteStr) -> Result<()>;
+	/// Move the name `old_name` to `new_name` in `new_dir` (a directory on the same filesystem, possibly this one)
+	///
+	/// An existing `new_name` is replaced (it must be the same type as the source, and empty if it's a directory)
+	fn rename(&self, old_name: &ByteStr, new_dir: &dyn NodeBase, new_name: &ByteStr) -> Result<()>;
 }
 /// Trait for symbolic link nodes.
 pub trait Symlink: NodeBase {
@@ -11,13 +11,166 @@ use ::kernel::lib::byte_str::{ByteStr,ByteString};
 use ::core::sync::atomic::{self,AtomicUsize};
 
 static S_NODE_CACHE: LazyMutex<::kernel::lib::VecMap<(usize,InodeId),Box<CachedNode>>> = lazymutex_init!();
+static S_DENTRY_CACHE: LazyMutex<DentryCache> = lazymutex_init!();
+
+/// Maximum number of symbolic links followed while resolving a single path
+const MAX_SYMLINK_DEPTH: usize = 16;
+/// Maximum number of names held in the directory entry cache
+const MAX_DENTRIES: usize = 1024;
 
 mod file;
 mod dir;
+mod pages;
 
 pub fn init()
 {
 	S_NODE_CACHE.init(|| Default::default());
+	S_DENTRY_CACHE.init(|| Default::default());
+	pages::init();
+}
+
+/// Call `f` with the cache locked, if no nodes from the given volume are cached (i.e. it can be unmounted)
+///
+/// Returns `None` if the volume is in use
+pub fn with_volume_unused<R>(mountpt: usize, f: impl FnOnce()->R) -> Option<R>
+{
+	let lh = S_NODE_CACHE.lock();
+	if lh.iter().any(|(k,_)| k.0 == mountpt) {
+		return None;
+	}
+	let rv = f();
+	drop(lh);
+	Some(rv)
+}
+
+/// Write back cached data for all open files on the given volume
+pub fn sync_volume(mountpt: usize) -> super::Result<()>
+{
+	sync_files(Some(mountpt))
+}
+/// Write back cached data for all open files
+pub fn sync_all_files() -> super::Result<()>
+{
+	sync_files(None)
+}
+fn sync_files(mountpt: Option<usize>) -> super::Result<()>
+{
+	// Take references to the files with the cache locked, then write them back without the lock
+	let files: Vec<CacheHandle> = {
+		let lh = S_NODE_CACHE.lock();
+		lh.iter()
+			.filter(|(k,_)| mountpt.map_or(true, |m| k.0 == m))
+			.filter(|(_,n)| match n.node { CacheNodeInfo::File(_) => true, _ => false })
+			.map(|(k,n)| {
+				n.refcount.fetch_add(1, atomic::Ordering::Relaxed);
+				CacheHandle { mountpt: k.0, inode: k.1, ptr: &**n }
+				})
+			.collect()
+		};
+	let mut rv = Ok( () );
+	for h in files
+	{
+		if let CacheNodeInfo::File(ref info) = *h.as_ref() {
+			if let Err(e) = info.flush() {
+				log_error!("Failed to write back {:?}: {:?}", h, e);
+				rv = Err(e);
+			}
+		}
+	}
+	rv
+}
+/// Call `f` with the page cache of each cached file until it returns false (used for reclaiming memory)
+///
+/// Does nothing if the node cache is currently locked
+fn with_file_pages_nonblocking(mut f: impl FnMut(&pages::FilePages)->bool)
+{
+	if let Some(lh) = S_NODE_CACHE.try_lock() {
+		for (_, n) in lh.iter()
+		{
+			if let CacheNodeInfo::File(ref info) = n.node {
+				if !f(&info.pages) {
+					break;
+				}
+			}
+		}
+	}
+}
+
+/// Cache of directory lookups (name to inode), keyed by mount ID and directory inode
+#[derive(Default)]
+struct DentryCache
+{
+	/// Incremented on every invalidation, so lookups that raced with a modification aren't cached
+	generation: u64,
+	count: usize,
+	dirs: ::kernel::lib::VecMap<(usize,InodeId), ::kernel::lib::VecMap<ByteString,InodeId>>,
+}
+impl DentryCache
+{
+	fn get(&self, mountpt: usize, dir: InodeId, name: &ByteStr) -> Option<InodeId> {
+		self.dirs.get( &(mountpt, dir) )?.get(name).cloned()
+	}
+	fn insert(&mut self, generation: u64, mountpt: usize, dir: InodeId, name: &ByteStr, inode: InodeId) {
+		if generation != self.generation {
+			return ;
+		}
+		if self.count >= MAX_DENTRIES {
+			log_debug!("Directory entry cache full, flushing");
+			self.dirs.clear();
+			self.count = 0;
+		}
+		if self.dirs.entry( (mountpt, dir) ).or_default().insert(name.into(), inode).is_none() {
+			self.count += 1;
+		}
+	}
+	fn invalidate(&mut self, mountpt: usize, dir: InodeId, name: &ByteStr) {
+		self.generation += 1;
+		if let Some(names) = self.dirs.get_mut( &(mountpt, dir) ) {
+			if names.remove(name).is_some() {
+				self.count -= 1;
+			}
+		}
+	}
+	fn invalidate_volume(&mut self, mountpt: usize) {
+		self.generation += 1;
+		let mut removed = 0;
+		self.dirs.retain(|k, names| if k.0 == mountpt { removed += names.len(); false } else { true });
+		self.count -= removed;
+	}
+}
+
+/// Look up `name` in a directory, using the directory entry cache
+///
+/// `.` and `..` always go to the filesystem, as `..` changes when a directory is moved (and is only handled by the
+/// filesystem when a walk starts at the directory)
+fn lookup_cached(dir: &CacheHandle, fsnode: &dyn super::node::Dir, name: &ByteStr) -> super::Result<InodeId>
+{
+	if name.as_bytes() == b"." || name.as_bytes() == b".." {
+		return fsnode.lookup(name);
+	}
+	let generation = {
+		let lh = S_DENTRY_CACHE.lock();
+		if let Some(inode) = lh.get(dir.mountpt, dir.inode, name) {
+			return Ok(inode);
+		}
+		lh.generation
+		};
+	// Filesystem lookup is done without the lock held (it may need to do IO)
+	let inode = fsnode.lookup(name)?;
+	S_DENTRY_CACHE.lock().insert(generation, dir.mountpt, dir.inode, name, inode);
+	Ok(inode)
+}
+
+/// Remove a name from the directory entry cache (called when a directory is modified)
+fn invalidate_dentry(dir: &CacheHandle, name: &ByteStr)
+{
+	S_DENTRY_CACHE.lock().invalidate(dir.mountpt, dir.inode, name);
+}
+
+/// Remove all cached directory entries for a volume (called when it's unmounted)
+pub fn invalidate_volume(mountpt: usize)
+{
+	S_DENTRY_CACHE.lock().invalidate_volume(mountpt);
 }
 
 #[derive(Debug,PartialEq)]
@@ -67,6 +220,28 @@ struct CachedNode
 pub struct CacheHandleFile(CacheHandle);
 #[derive(Debug,Clone)]
 pub struct CacheHandleDir(CacheHandle);
+impl CacheHandleFile {
+	pub fn check_access(&self, access: u16) -> super::Result<()> {
+		self.0.check_access(access)
+	}
+	pub fn get_metadata(&self) -> super::Result<super::node::Metadata> {
+		self.0.get_metadata()
+	}
+	pub fn set_metadata(&self, changes: &super::node::MetadataUpdate) -> super::Result<()> {
+		self.0.set_metadata(changes)
+	}
+}
+impl CacheHandleDir {
+	pub fn check_access(&self, access: u16) -> super::Result<()> {
+		self.0.check_access(access)
+	}
+	pub fn get_metadata(&self) -> super::Result<super::node::Metadata> {
+		self.0.get_metadata()
+	}
+	pub fn set_metadata(&self, changes: &super::node::MetadataUpdate) -> super::Result<()> {
+		self.0.set_metadata(changes)
+	}
+}
 //#[derive(Debug,Clone)]
 //pub struct CacheHandleSymlink(CacheHandle);
 //#[derive(Debug,Clone)]
@@ -104,6 +279,31 @@ impl Clone for CacheHandle
 	}
 }
 
+impl Drop for CacheHandle
+{
+	fn drop(&mut self) {
+		// Write back cached data before the last reference is released (so a re-open sees it on disk)
+		// SAFE: self.ptr is valid while this handle exists
+		if unsafe { (*self.ptr).refcount.load(atomic::Ordering::Relaxed) } == 1 {
+			if let CacheNodeInfo::File(ref info) = *self.as_ref() {
+				if let Err(e) = info.flush() {
+					log_error!("Failed to write back {:?}: {:?}", self, e);
+				}
+			}
+		}
+		let node = {
+			let mut lh = S_NODE_CACHE.lock();
+			// SAFE: self.ptr is valid until the count reaches zero, which is only checked with the lock held
+			if unsafe { (*self.ptr).refcount.fetch_sub(1, atomic::Ordering::Relaxed) } != 1 {
+				return ;
+			}
+			lh.remove( &(self.mountpt, self.inode) )
+			};
+		// Release the filesystem's node outside the lock (it may need to do IO)
+		drop(node);
+	}
+}
+
 impl CacheHandle
 {
 	/// Obtain a node handle using a mountpoint ID and inode number
@@ -119,7 +319,7 @@ impl CacheHandle
 				e.into_mut()
 				},
 			Entry::Vacant(e) =>
-				match super::mount::Handle::from_id(mountpoint).get_node(inode)
+				match super::mount::Handle::from_id(mountpoint)?.get_node(inode)
 				{
 				Some(node) => e.insert(Box::new(CachedNode { node: node.into(), refcount: AtomicUsize::new(1) })),
 				None => return Err( super::Error::NotFound ),
@@ -142,7 +342,7 @@ impl CacheHandle
 			let new_mountpoint = info.mountpoint.load(atomic::Ordering::Relaxed);
 			if new_mountpoint != 0 {
 				// Then recurse (hopefully only once) with the new mountpoint
-				let new_inode = super::mount::Handle::from_id(new_mountpoint).root_inode();
+				let new_inode = super::mount::Handle::from_id(new_mountpoint)?.root_inode()?;
 				log_trace!("CacheHandle::from_ids({},{}) => Mount {}, {}",
 					mountpoint, inode,  new_mountpoint, new_inode);
 				return CacheHandle::from_ids(new_mountpoint, new_inode);
@@ -157,7 +357,6 @@ impl CacheHandle
 	/// Obtain a node handle using a parent directory node and a relative path
 	pub fn from_path_at_node(node_h: CacheHandleDir, path: &Path) -> super::Result<CacheHandle>
 	{
-		let mut node_h = node_h.0;
 		log_function!("CacheHandle::from_path_at_node(node_h={:?}, {:?})", node_h, path);
 		let path = if path.is_absolute() {
 				path.split_off_first().ok_or(super::Error::MalformedPath)?.1
@@ -165,46 +364,66 @@ impl CacheHandle
 			else {
 				path
 			};
-		// Iterate path components
+		// The walk keeps handles to every directory traversed, so `..` can return to the previous directory
+		// (which might be on another volume) and relative symlinks can be resolved against their parent.
+		let mut stack = vec![node_h.0];
+		let mut links_followed = 0;
+		CacheHandle::walk(&mut stack, path, &mut links_followed)?;
+		let rv = stack.pop().expect("Path walk emptied the stack");
+		log_trace!("CacheHandle::from_path_at_node() {:?}", rv);
+		Ok( rv )
+	}
+
+	/// Obtain a node handle using a path
+	pub fn from_path(path: &Path) -> super::Result<CacheHandle>
+	{
+		log_function!("CacheHandle::from_path({:?})", path);
+		
+		// - Remove the leading / from the absolute path
+		//  > Also checks that it's actually abolsute
+		let (first_comp, path) = path.split_off_first().ok_or(super::Error::MalformedPath)?;
+		if first_comp.len() != 0 {
+			return Err(super::Error::MalformedPath);
+		}
+
+		CacheHandle::from_path_at_node(CacheHandle::root()?.into_dir()?, path)
+	}
+
+	/// Root of the filesystem tree
+	fn root() -> super::Result<CacheHandle>
+	{
+		let mph = super::mount::Handle::from_id(0)?;
+		CacheHandle::from_ids( mph.id(), mph.root_inode()? )
+	}
+
+	/// Walk `path` (relative to the top of `stack`), pushing the nodes traversed
+	///
+	/// The final component is not followed if it's a symbolic link
+	fn walk(stack: &mut Vec<CacheHandle>, path: &Path, links_followed: &mut usize) -> super::Result<()>
+	{
 		for seg in path
 		{
 			log_trace!("seg = {:?}", seg);
-			// Loop resolving symbolic links
-			loop
-			{
-				// TODO: Should symlinks be handled in this function? Or should the passed path be without symlinks?
-				node_h = if let CacheNodeInfo::Symlink { ref target, .. } = *node_h.as_ref() {
-					//log_debug!("- seg={:?} : SYMLINK {:?}", seg, name);
-					let linkpath = Path::new(&target);
-					if linkpath.is_absolute() {
-						CacheHandle::from_path(linkpath)?
-					}
-					else {
-						//TODO: To make this work (or any path-relative symlink), the current position in
-						//      `path` needs to be known.
-						// It can be hacked up though...
-						let parent_segs_len = seg.as_bytes().as_ptr() as usize - AsRef::<[u8]>::as_ref(path).as_ptr() as usize;
-						let parent = ByteStr::new( &AsRef::<[u8]>::as_ref(path)[..parent_segs_len] );
-
-						//let segs = [ &path[..pos], &link ];
-						//let p = PathChain::new(&segs);
-						//try!( CacheHandle::from_path(p) )
-						// Recurse with a special chained path type
-						// (that iterates but can't be sliced).
-						todo!("Relative symbolic links {:?} relative to {:?}", target, parent)
-					}
-				}
-				else {
-					break;
-				};
+			// Intermediate components must be directories, so follow any symbolic links
+			CacheHandle::follow_links(stack, links_followed)?;
+
+			if seg.as_bytes() == b"." {
+				continue ;
+			}
+			// NOTE: If the walk started at this directory, the filesystem handles `..` (if it can)
+			if seg.as_bytes() == b".." && stack.len() > 1 {
+				stack.pop();
+				continue ;
 			}
 
 			// Look up this component in the current node
-			node_h = match *node_h.as_ref()
+			let next = {
+				let node_h = stack.last().expect("Path walk emptied the stack");
+				match *node_h.as_ref()
 				{
 				CacheNodeInfo::Dir(ref info) => {
-					//log_debug!("- seg={:?} : DIR", seg);
-					let next_id = match info.fsnode.lookup(seg)
+					node_h.check_access(super::node::ACCESS_EXEC)?;
+					let next_id = match lookup_cached(node_h, &*info.fsnode, seg)
 						{
 						Ok(v) => v,
 						Err(_) => return Err(super::Error::NotFound),
@@ -212,32 +431,56 @@ impl CacheHandle
 					CacheHandle::from_ids( node_h.mountpt, next_id )?
 					},
 				_ => return Err(super::Error::NonDirComponent),
+				}
 				};
+			stack.push(next);
 		}
-		log_trace!("CacheHandle::from_path_at_node() {:?}", node_h);
-		Ok( node_h )
+		Ok( () )
 	}
 
-	/// Obtain a node handle using a path
-	pub fn from_path(path: &Path) -> super::Result<CacheHandle>
+	/// If the top of `stack` is a symbolic link, replace it with the link's target (repeating until it isn't a link)
+	fn follow_links(stack: &mut Vec<CacheHandle>, links_followed: &mut usize) -> super::Result<()>
 	{
-		log_function!("CacheHandle::from_path({:?})", path);
-		// TODO: Support path caching?
-		
-		// - Remove the leading / from the absolute path
-		//  > Also checks that it's actually abolsute
-		let (first_comp, path) = path.split_off_first().ok_or(super::Error::MalformedPath)?;
-		if first_comp.len() != 0 {
-			return Err(super::Error::MalformedPath);
-		}
-
-		// Acquire the root vnode
-		let mph = super::mount::Handle::from_id(0);
-		let node_h = CacheHandle::from_ids( mph.id(), mph.root_inode() )?;
+		loop
+		{
+			let target = match *stack.last().expect("Path walk emptied the stack").as_ref()
+				{
+				CacheNodeInfo::Symlink { ref target, .. } => target.clone(),
+				_ => return Ok( () ),
+				};
+			*links_followed += 1;
+			if *links_followed > MAX_SYMLINK_DEPTH {
+				log_debug!("Too many symbolic links ({}) while resolving path", *links_followed);
+				return Err(super::Error::RecursionDepthExceeded);
+			}
 
-		CacheHandle::from_path_at_node(node_h.into_dir()?, path)
+			// Relative targets are resolved against the directory containing the link
+			stack.pop();
+			let linkpath = Path::new(&target);
+			let linkpath = if linkpath.is_absolute() {
+					stack.clear();
+					stack.push( CacheHandle::root()? );
+					linkpath.split_off_first().ok_or(super::Error::MalformedPath)?.1
+				}
+				else {
+					linkpath
+				};
+			// A link opened directly (not found through a directory) has nothing to resolve against
+			if stack.is_empty() {
+				return Err(super::Error::NonDirComponent);
+			}
+			CacheHandle::walk(stack, linkpath, links_followed)?;
+		}
 	}
 	
+	/// ID of the mounted volume containing this node
+	pub fn get_mount_id(&self) -> usize {
+		self.mountpt
+	}
+	pub fn get_inode(&self) -> InodeId {
+		self.inode
+	}
+
 	pub fn get_class(&self) -> NodeClass {
 		match self.as_ref()
 		{
@@ -278,6 +521,77 @@ impl CacheHandle
 		&CacheNodeInfo::Symlink { ref fsnode, .. } => fsnode.get_any(),
 		}
 	}
+
+	/// Check that the current process has the requested access (`node::ACCESS_*` bits) to this node
+	pub fn check_access(&self, access: u16) -> super::Result<()> {
+		if access & super::node::ACCESS_WRITE != 0 && super::mount::Handle::from_id(self.mountpt)?.flags()?.read_only {
+			return Err(super::Error::ReadOnlyFilesystem);
+		}
+		let creds = ::kernel::threads::get_credentials();
+		// Fast path: root never needs the metadata
+		if creds.is_root() {
+			return Ok( () );
+		}
+		if self.get_metadata()?.allows(&creds, access) {
+			Ok( () )
+		}
+		else {
+			Err(super::Error::PermissionDenied)
+		}
+	}
+
+	pub fn get_metadata(&self) -> super::Result<super::node::Metadata> {
+		match self.as_ref()
+		{
+		&CacheNodeInfo::Dir(ref inner) => inner.fsnode.get_metadata(),
+		&CacheNodeInfo::File(ref inner) => {
+			let mut rv = inner.fsnode.get_metadata()?;
+			rv.size = inner.size();
+			Ok(rv)
+			},
+		&CacheNodeInfo::Special { ref fsnode, .. } => fsnode.get_metadata(),
+		&CacheNodeInfo::Symlink { ref fsnode, .. } => fsnode.get_metadata(),
+		}
+	}
+	/// Update metadata, only the owner (or root) can do this and only root can change the ownership
+	pub fn set_metadata(&self, changes: &super::node::MetadataUpdate) -> super::Result<()> {
+		let creds = ::kernel::threads::get_credentials();
+		if !creds.is_root() {
+			let meta = self.get_metadata()?;
+			if creds.uid != meta.uid {
+				return Err(super::Error::PermissionDenied);
+			}
+			if changes.uid.map_or(false, |v| v != meta.uid) || changes.gid.map_or(false, |v| v != meta.gid) {
+				return Err(super::Error::PermissionDenied);
+			}
+		}
+		self.modify(|| self.set_metadata_unchecked(changes))
+	}
+	/// Run an operation that modifies the volume (rejecting it if read-only, and syncing afterwards if mounted `sync`)
+	fn modify<R>(&self, f: impl FnOnce()->super::Result<R>) -> super::Result<R> {
+		let mh = super::mount::Handle::from_id(self.mountpt)?;
+		let flags = mh.flags()?;
+		if flags.read_only {
+			return Err(super::Error::ReadOnlyFilesystem);
+		}
+		let rv = f()?;
+		if flags.sync {
+			if let CacheNodeInfo::File(ref info) = *self.as_ref() {
+				info.flush()?;
+			}
+			mh.sync()?;
+		}
+		Ok(rv)
+	}
+	fn set_metadata_unchecked(&self, changes: &super::node::MetadataUpdate) -> super::Result<()> {
+		match self.as_ref()
+		{
+		&CacheNodeInfo::Dir(ref inner) => inner.fsnode.set_metadata(changes),
+		&CacheNodeInfo::File(ref inner) => inner.fsnode.set_metadata(changes),
+		&CacheNodeInfo::Special { ref fsnode, .. } => fsnode.set_metadata(changes),
+		&CacheNodeInfo::Symlink { ref fsnode, .. } => fsnode.set_metadata(changes),
+		}
+	}
 }
 
 
@@ -32,16 +32,83 @@ impl CacheHandleDir
 		}
 	}
 	pub fn create(&self, name: &ByteStr, ty: vfs::node::NodeType) -> vfs::Result<super::CacheHandle> {
-		let inode = self.get_info()?.fsnode.create(name, ty)?;
-		Ok( super::CacheHandle::from_ids(self.0.mountpt, inode)? )
+		let info = self.get_info()?;
+		let res = self.0.modify(|| Ok( info.fsnode.create(name, ty)? ));
+		super::invalidate_dentry(&self.0, name);
+		let inode = res?;
+		let rv = super::CacheHandle::from_ids(self.0.mountpt, inode)?;
+		// New nodes are owned by the creating process (filesystems create them as root)
+		let creds = ::kernel::threads::get_credentials();
+		if !creds.is_root() {
+			let owner = vfs::node::MetadataUpdate { uid: Some(creds.uid), gid: Some(creds.gid), ..Default::default() };
+			match rv.set_metadata_unchecked(&owner)
+			{
+			Ok(()) => {},
+			Err(vfs::Error::ReadOnlyFilesystem) => {},
+			Err(e) => return Err(e),
+			}
+		}
+		Ok( rv )
 	}
 	pub fn read_dir(&self, ofs: usize, items: &mut vfs::node::ReadDirCallback) -> vfs::Result<usize> {
 		Ok( self.get_info()?.fsnode.read(ofs, items)? )
 	}
 	pub fn open_child(&self, name: &ByteStr) -> vfs::Result<super::CacheHandle> {
-		let inode = self.get_info()?.fsnode.lookup(name)?;
+		let inode = super::lookup_cached(&self.0, &*self.get_info()?.fsnode, name)?;
 		Ok( super::CacheHandle::from_ids(self.0.mountpt, inode)? )
 	}
+	/// Add a new name for an existing node (must be on the same filesystem)
+	pub fn link(&self, name: &ByteStr, node: &super::CacheHandle) -> vfs::Result<()> {
+		if node.mountpt != self.0.mountpt {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let fsnode: &dyn vfs::node::NodeBase = match node.as_ref()
+			{
+			&super::CacheNodeInfo::File(ref i) => &*i.fsnode,
+			&super::CacheNodeInfo::Dir(ref i) => &*i.fsnode,
+			&super::CacheNodeInfo::Symlink { ref fsnode, .. } => &**fsnode,
+			&super::CacheNodeInfo::Special { ref fsnode, .. } => &**fsnode,
+			};
+		let info = self.get_info()?;
+		let rv = self.0.modify(|| info.fsnode.link(name, fsnode));
+		super::invalidate_dentry(&self.0, name);
+		rv
+	}
+	/// Remove a name from this directory
+	pub fn unlink(&self, name: &ByteStr) -> vfs::Result<()> {
+		let info = self.get_info()?;
+		// Refuse to remove a mountpoint
+		if let Ok(inode) = info.fsnode.lookup(name) {
+			let child = super::CacheHandle::from_ids(self.0.mountpt, inode)?;
+			if child.mountpt != self.0.mountpt || child.inode != inode {
+				return Err(vfs::Error::Locked);
+			}
+		}
+		let rv = self.0.modify(|| info.fsnode.unlink(name));
+		super::invalidate_dentry(&self.0, name);
+		rv
+	}
+	/// Move a name to `new_name` in `new_dir` (which must be on the same volume)
+	pub fn rename(&self, old_name: &ByteStr, new_dir: &CacheHandleDir, new_name: &ByteStr) -> vfs::Result<()> {
+		if new_dir.0.mountpt != self.0.mountpt {
+			return Err(vfs::Error::CrossVolume);
+		}
+		let info = self.get_info()?;
+		let new_info = new_dir.get_info()?;
+		// Refuse to move or replace a mountpoint
+		for &(dir, name) in &[(self, old_name), (new_dir, new_name)] {
+			if let Ok(inode) = dir.get_info()?.fsnode.lookup(name) {
+				let child = super::CacheHandle::from_ids(dir.0.mountpt, inode)?;
+				if child.mountpt != dir.0.mountpt || child.inode != inode {
+					return Err(vfs::Error::Locked);
+				}
+			}
+		}
+		let rv = self.0.modify(|| info.fsnode.rename(old_name, &*new_info.fsnode, new_name));
+		super::invalidate_dentry(&self.0, old_name);
+		super::invalidate_dentry(&new_dir.0, new_name);
+		rv
+	}
 }
 /// Directory methods (mountpoint)
 impl CacheHandleDir
@@ -63,4 +130,10 @@ impl CacheHandleDir
 		_ => false,
 		}
 	}
+	/// Remove the binding created by `mount`
+	pub fn unmount(&self, filesystem_id: usize) {
+		if let Ok(info) = self.get_info() {
+			let _ = info.mountpoint.compare_exchange(filesystem_id, 0, atomic::Ordering::Relaxed, atomic::Ordering::Relaxed);
+		}
+	}
 }
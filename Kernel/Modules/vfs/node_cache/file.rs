@@ -9,13 +9,56 @@ pub struct CacheNodeInfoFile
 	//mapped_pages: HashMap<u64,FrameHandle>,
 	lock_info: ::kernel::sync::Mutex<CacheNodeInfoFileLock>,
 	append_lock: ::kernel::sync::Mutex<()>,
+	/// Advisory byte-range locks held by open handles
+	range_locks: ::kernel::sync::Mutex<Vec<RangeLock>>,
+	/// Woken whenever a range lock is released or downgraded
+	range_lock_waiters: ::kernel::user_async::Queue,
+	/// Cached file data (only used if the filesystem node is cacheable)
+	pub pages: super::pages::FilePages,
 }
 impl CacheNodeInfoFile {
 	pub fn new(fsnode: Box<dyn vfs::node::File>) -> Self {
 		CacheNodeInfoFile {
 			fsnode,
 			lock_info: Default::default(),
-			append_lock: Default::default()
+			append_lock: Default::default(),
+			range_locks: Default::default(),
+			range_lock_waiters: Default::default(),
+			pages: Default::default(),
+		}
+	}
+	/// File size, including data that is only in the cache
+	pub fn size(&self) -> u64 {
+		if self.fsnode.cacheable() {
+			self.pages.size(&*self.fsnode)
+		}
+		else {
+			self.fsnode.size()
+		}
+	}
+	/// Write back any cached data
+	pub fn flush(&self) -> vfs::Result<()> {
+		if self.fsnode.cacheable() {
+			self.pages.flush(&*self.fsnode)
+		}
+		else {
+			Ok( () )
+		}
+	}
+	fn write(&self, ofs: u64, src: &[u8]) -> vfs::Result<usize> {
+		if self.fsnode.cacheable() {
+			self.pages.write(&*self.fsnode, ofs, src)
+		}
+		else {
+			Ok( self.fsnode.write(ofs, src)? )
+		}
+	}
+}
+impl Drop for CacheNodeInfoFile
+{
+	fn drop(&mut self) {
+		if let Err(e) = self.flush() {
+			log_error!("Failed to write back cached file data: {:?}", e);
 		}
 	}
 }
@@ -32,6 +75,53 @@ enum CacheNodeInfoFileLock {
 	Unsynch(usize),
 }
 
+/// An advisory lock on `start .. end` (an `end` of `!0` extends to the end of the file)
+struct RangeLock
+{
+	owner: usize,
+	start: u64,
+	end: u64,
+	exclusive: bool,
+}
+impl RangeLock
+{
+	fn overlaps(&self, start: u64, end: u64) -> bool {
+		self.start < end && start < self.end
+	}
+}
+/// Remove `start .. end` from all of `owner`'s locks (splitting locks that straddle the range)
+///
+/// Returns true if anything was released
+fn release_range(locks: &mut Vec<RangeLock>, owner: usize, start: u64, end: u64) -> bool {
+	let mut changed = false;
+	let mut i = 0;
+	while i < locks.len()
+	{
+		if locks[i].owner != owner || !locks[i].overlaps(start, end) {
+			i += 1;
+			continue ;
+		}
+		changed = true;
+		let l = locks.swap_remove(i);
+		if l.start < start {
+			locks.push(RangeLock { owner, start: l.start, end: start, exclusive: l.exclusive });
+		}
+		if end < l.end {
+			locks.push(RangeLock { owner, start: end, end: l.end, exclusive: l.exclusive });
+		}
+	}
+	changed
+}
+/// Convert an offset/length pair into a range, a length of zero means "to the end of the file"
+fn lock_range(ofs: u64, len: u64) -> (u64, u64) {
+	if len == 0 {
+		(ofs, !0)
+	}
+	else {
+		(ofs, ofs.saturating_add(len))
+	}
+}
+
 /// Normal file methods
 impl CacheHandleFile
 {
@@ -126,21 +216,120 @@ impl CacheHandleFile
 		}
 	}
 
+	/// Check if `owner` taking the specified lock would conflict with another owner's lock
+	pub fn range_lock_conflicts(&self, owner: usize, ofs: u64, len: u64, exclusive: bool) -> bool {
+		let info = match self.get_info() { Ok(v) => v, Err(_) => return false };
+		let (start, end) = lock_range(ofs, len);
+		info.range_locks.lock().iter().any(|l| l.owner != owner && l.overlaps(start, end) && (exclusive || l.exclusive))
+	}
+	/// Take an advisory lock on a range of the file (replacing any of `owner`'s existing locks in the range)
+	///
+	/// Returns `Locked` if another owner holds a conflicting lock
+	pub fn range_lock(&self, owner: usize, ofs: u64, len: u64, exclusive: bool) -> vfs::Result<()> {
+		let info = self.get_info()?;
+		let (start, end) = lock_range(ofs, len);
+		let mut lh = info.range_locks.lock();
+		if lh.iter().any(|l| l.owner != owner && l.overlaps(start, end) && (exclusive || l.exclusive)) {
+			return Err(vfs::Error::Locked);
+		}
+		// Replacing an exclusive lock with a shared one can unblock other shared lockers
+		let downgraded = lh.iter().any(|l| l.owner == owner && l.overlaps(start, end) && l.exclusive && !exclusive);
+		release_range(&mut lh, owner, start, end);
+		lh.push(RangeLock { owner, start, end, exclusive });
+		drop(lh);
+		if downgraded {
+			info.range_lock_waiters.wake_all();
+		}
+		Ok( () )
+	}
+	/// Release `owner`'s advisory locks on a range of the file
+	pub fn range_unlock(&self, owner: usize, ofs: u64, len: u64) -> vfs::Result<()> {
+		let info = self.get_info()?;
+		let (start, end) = lock_range(ofs, len);
+		if release_range(&mut info.range_locks.lock(), owner, start, end) {
+			info.range_lock_waiters.wake_all();
+		}
+		Ok( () )
+	}
+	/// Release all advisory locks held by `owner` (called when a handle is closed)
+	pub fn range_unlock_all(&self, owner: usize) {
+		if let Ok(info) = self.get_info() {
+			if release_range(&mut info.range_locks.lock(), owner, 0, !0) {
+				info.range_lock_waiters.wake_all();
+			}
+		}
+	}
+	/// Register a sleeper to be woken when any advisory lock is released
+	pub fn range_lock_wait_upon(&self, obj: &mut ::kernel::threads::SleepObject) {
+		if let Ok(info) = self.get_info() {
+			info.range_lock_waiters.wait_upon(obj);
+		}
+	}
+	pub fn range_lock_clear_wait(&self, obj: &mut ::kernel::threads::SleepObject) {
+		if let Ok(info) = self.get_info() {
+			info.range_lock_waiters.clear_wait(obj);
+		}
+	}
+
 	/// Valid size = maximum offset in the file
 	pub fn get_valid_size(&self) -> u64 {
-		self.get_info().map(|v| v.fsnode.size()).unwrap_or(0)
+		self.get_info().map(|v| v.size()).unwrap_or(0)
 	}
 	pub fn read(&self, ofs: u64, dst: &mut [u8]) -> vfs::Result<usize> {
-		Ok( self.get_info()?.fsnode.read(ofs, dst)? )
+		let info = self.get_info()?;
+		if info.fsnode.cacheable() {
+			info.pages.read(&*info.fsnode, ofs, dst)
+		}
+		else {
+			Ok( info.fsnode.read(ofs, dst)? )
+		}
 	}
 	pub fn write(&self, ofs: u64, src: &[u8]) -> vfs::Result<usize> {
-		// TODO: Ensure that the handle is writable?
-		Ok( self.get_info()?.fsnode.write(ofs, src)? )
+		let info = self.get_info()?;
+		self.0.modify(|| info.write(ofs, src))
+	}
+	/// Set the file size (zero-extending or truncating), returns the new size
+	pub fn truncate(&self, newsize: u64) -> vfs::Result<u64> {
+		let info = self.get_info()?;
+		let _lh = info.append_lock.lock();
+		self.0.modify(|| {
+			info.pages.truncate(newsize);
+			Ok( info.fsnode.truncate(newsize)? )
+			})
+	}
+	/// Replace a range of the file with zeroes
+	pub fn clear(&self, ofs: u64, len: u64) -> vfs::Result<()> {
+		let info = self.get_info()?;
+		self.0.modify(|| {
+			info.fsnode.clear(ofs, len)?;
+			info.pages.clear(ofs, len);
+			Ok( () )
+			})
 	}
 	pub fn append(&self, data: &[u8]) -> vfs::Result<usize> {
 		let info = self.get_info()?;
 		let _lh = info.append_lock.lock();
-		let ofs = info.fsnode.size();
-		Ok( info.fsnode.write(ofs, data)? )
+		self.0.modify(|| {
+			let ofs = info.size();
+			info.write(ofs, data)
+			})
+	}
+	/// Write back cached data and flush the volume
+	pub fn sync(&self) -> vfs::Result<()> {
+		self.get_info()?.flush()?;
+		vfs::mount::Handle::from_id(self.0.get_mount_id())?.sync()
+	}
+	/// Get the frame holding a page of the file's data (for sharing with a memory mapping)
+	///
+	/// Uses the page cache, or the filesystem's own frames for files held in memory. Returns `None` if the page is
+	/// past the end of the file, the cache is full, or the filesystem can't share its data.
+	pub fn get_page_frame(&self, page: u64) -> vfs::Result<Option<::kernel::memory::phys::FrameHandle>> {
+		let info = self.get_info()?;
+		if info.fsnode.cacheable() {
+			info.pages.get_frame(&*info.fsnode, page)
+		}
+		else {
+			Ok( info.fsnode.get_page_frame(page)? )
+		}
 	}
 }
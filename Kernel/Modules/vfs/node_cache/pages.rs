@@ -0,0 +1,339 @@
+//! File data page cache
+//!
+//! Each open file caches its data in pages from `kernel::memory::page_cache` (each cached page keeps its mapping).
+//! Writes only touch the cache (extensions are recorded and applied by writeback), dirty pages are written back by a
+//! periodic worker, on `sync`, when the cache is full, and when the last handle to the file is closed.
+//!
+//! Pages shared with memory mappings are never evicted, so the mapping and the cache always see the same data.
+use ::kernel::PAGE_SIZE;
+use ::kernel::lib::VecMap;
+use ::kernel::memory::page_cache::{S_PAGE_CACHE,CachedPage};
+use ::core::sync::atomic::{AtomicUsize,AtomicU64,Ordering};
+use crate as vfs;
+
+/// Limit on the number of pages held by all file caches
+///
+/// The page cache's mapping region is shared with the block cache, so this leaves room for metadata
+const MAX_CACHED_PAGES: usize = 512;
+/// Time between passes of the writeback worker (in ms)
+const WRITEBACK_INTERVAL_MS: usize = 5000;
+
+/// Total number of pages held by file caches
+static S_CACHED_PAGES: AtomicUsize = AtomicUsize::new(0);
+static S_RECLAIM: Reclaim = Reclaim;
+
+/// Cached pages for a single file
+#[derive(Default)]
+pub struct FilePages
+{
+	pages: ::kernel::sync::Mutex<VecMap<u64,CachedFilePage>>,
+	/// Size of the file including cached extensions (zero if the file hasn't been extended past its on-disk size)
+	///
+	/// Only changed with `pages` locked
+	extended_size: AtomicU64,
+}
+struct CachedFilePage
+{
+	data: CachedPage,
+	dirty: bool,
+	last_access: u64,
+}
+
+pub fn init()
+{
+	::kernel::memory::phys::register_reclaimable(&S_RECLAIM);
+	::core::mem::forget(::kernel::threads::WorkerThread::new("VFS Writeback", || {
+		loop
+		{
+			::kernel::futures::block_on(::kernel::futures::msleep(WRITEBACK_INTERVAL_MS));
+			if let Err(e) = super::sync_all_files() {
+				log_error!("Writeback failed: {:?}", e);
+			}
+		}
+		}));
+}
+
+impl FilePages
+{
+	/// Returns true if there are pages (or an extension) waiting to be written back
+	pub fn is_dirty(&self) -> bool {
+		let lh = self.pages.lock();
+		self.extended_size.load(Ordering::Relaxed) != 0 || lh.iter().any(|(_,p)| p.dirty)
+	}
+
+	/// Current file size (including data that has only been written to the cache)
+	pub fn size(&self, fsnode: &dyn vfs::node::File) -> u64 {
+		u64::max(fsnode.size(), self.extended_size.load(Ordering::Relaxed))
+	}
+
+	/// Read file data via the cache
+	pub fn read(&self, fsnode: &dyn vfs::node::File, ofs: u64, dst: &mut [u8]) -> vfs::Result<usize> {
+		let size = self.size(fsnode);
+		if ofs >= size {
+			return Ok(0);
+		}
+		let len = u64::min(dst.len() as u64, size - ofs) as usize;
+		let mut done = 0;
+		while done < len
+		{
+			let pos = ofs + done as u64;
+			let page_ofs = (pos % PAGE_SIZE as u64) as usize;
+			let dst = &mut dst[done..][..usize::min(PAGE_SIZE - page_ofs, len - done)];
+			let mut lh = self.pages.lock();
+			match get_page(&mut lh, &self.extended_size, fsnode, pos / PAGE_SIZE as u64, true)?
+			{
+			Some(page) => dst.copy_from_slice(&page.data.data()[page_ofs..][..dst.len()]),
+			// Cache is full, so go directly to the filesystem
+			None => { fsnode.read(pos, dst)?; },
+			}
+			done += dst.len();
+		}
+		Ok(done)
+	}
+	/// Write file data into the cache (recording the new size if the file grows)
+	pub fn write(&self, fsnode: &dyn vfs::node::File, ofs: u64, src: &[u8]) -> vfs::Result<usize> {
+		// Same restriction as `node::File::write`, the file can only grow from the end
+		if ofs > self.size(fsnode) {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let mut done = 0;
+		while done < src.len()
+		{
+			let pos = ofs + done as u64;
+			let page_ofs = (pos % PAGE_SIZE as u64) as usize;
+			let src = &src[done..][..usize::min(PAGE_SIZE - page_ofs, src.len() - done)];
+			let mut lh = self.pages.lock();
+			// Only read the existing data if the page isn't being completely overwritten
+			match get_page(&mut lh, &self.extended_size, fsnode, pos / PAGE_SIZE as u64, src.len() != PAGE_SIZE)?
+			{
+			Some(page) => {
+				page.data.data_mut()[page_ofs..][..src.len()].copy_from_slice(src);
+				page.dirty = true;
+				},
+			// Cache is full (and has been written back, so the file on disk is up to date)
+			None => {
+				if fsnode.write(pos, src)? < src.len() {
+					return Err(vfs::Error::OutOfSpace);
+				}
+				},
+			}
+			let end = pos + src.len() as u64;
+			if end > self.size(fsnode) {
+				self.extended_size.store(end, Ordering::Relaxed);
+			}
+			done += src.len();
+		}
+		Ok(done)
+	}
+	/// Update the cache for a size change (called before the filesystem truncates the file)
+	///
+	/// Pages past the end are dropped (discarding unwritten data) and the tail of the last page is zeroed, so
+	/// later extensions read as zero.
+	pub fn truncate(&self, newsize: u64) {
+		let mut lh = self.pages.lock();
+		// The filesystem applies the new size directly, pending extensions past it are discarded
+		self.extended_size.store(0, Ordering::Relaxed);
+		let first_removed = (newsize + PAGE_SIZE as u64 - 1) / PAGE_SIZE as u64;
+		let mut removed = 0;
+		lh.retain(|&idx, _| if idx >= first_removed { removed += 1; false } else { true });
+		S_CACHED_PAGES.fetch_sub(removed, Ordering::Relaxed);
+		let tail = (newsize % PAGE_SIZE as u64) as usize;
+		if tail != 0 {
+			if let Some(page) = lh.get_mut(&(newsize / PAGE_SIZE as u64)) {
+				zero(&mut page.data.data_mut()[tail..]);
+			}
+		}
+	}
+	/// Zero a range of cached data (called after the filesystem clears the range)
+	pub fn clear(&self, ofs: u64, len: u64) {
+		let mut lh = self.pages.lock();
+		let end = ofs.saturating_add(len);
+		for (&idx, page) in lh.iter_mut()
+		{
+			let page_start = idx * PAGE_SIZE as u64;
+			let page_end = page_start + PAGE_SIZE as u64;
+			if page_end <= ofs || end <= page_start {
+				continue ;
+			}
+			let start = (u64::max(ofs, page_start) - page_start) as usize;
+			let end = (u64::min(end, page_end) - page_start) as usize;
+			zero(&mut page.data.data_mut()[start..end]);
+		}
+	}
+	/// Write all dirty pages back to the filesystem
+	pub fn flush(&self, fsnode: &dyn vfs::node::File) -> vfs::Result<()> {
+		flush_locked(&mut self.pages.lock(), &self.extended_size, fsnode)
+	}
+	/// Get a handle to the frame backing a page (for sharing with a memory mapping)
+	///
+	/// Returns `None` if the page is past the end of the file, or the cache is full. The page stays cached while the
+	/// returned handle (or a mapping it was moved into) exists.
+	pub fn get_frame(&self, fsnode: &dyn vfs::node::File, page: u64) -> vfs::Result<Option<::kernel::memory::phys::FrameHandle>> {
+		let mut lh = self.pages.lock();
+		if page * PAGE_SIZE as u64 >= self.size(fsnode) {
+			return Ok(None);
+		}
+		Ok( get_page(&mut lh, &self.extended_size, fsnode, page, true)?.map(|p| p.data.get_frame_handle()) )
+	}
+}
+impl Drop for FilePages
+{
+	fn drop(&mut self) {
+		let lh = self.pages.get_mut();
+		if lh.iter().any(|(_,p)| p.dirty) {
+			log_error!("BUG: File page cache dropped with unwritten data");
+		}
+		S_CACHED_PAGES.fetch_sub(lh.len(), Ordering::Relaxed);
+	}
+}
+
+impl CachedFilePage
+{
+	/// Clean pages can be dropped, unless the frame is shared with a mapping (which would then see stale data)
+	fn can_evict(&self) -> bool {
+		!self.dirty && !self.data.is_shared()
+	}
+}
+
+fn zero(data: &mut [u8]) {
+	for b in data.iter_mut() {
+		*b = 0;
+	}
+}
+
+/// Write back all dirty pages of a file (only the part of the last page within the file is written)
+///
+/// Extensions are applied here, pages are written in order so the file grows from the end (as `node::File::write`
+/// requires)
+fn flush_locked(pages: &mut VecMap<u64,CachedFilePage>, extended_size: &AtomicU64, fsnode: &dyn vfs::node::File) -> vfs::Result<()>
+{
+	let size = u64::max(fsnode.size(), extended_size.load(Ordering::Relaxed));
+	for (&idx, page) in pages.iter_mut()
+	{
+		if !page.dirty {
+			continue ;
+		}
+		let start = idx * PAGE_SIZE as u64;
+		if start < size {
+			let len = u64::min(PAGE_SIZE as u64, size - start) as usize;
+			// Fill any gap before this page (which can only be zeroes)
+			if fsnode.size() < start && fsnode.truncate(start)? < start {
+				return Err(vfs::Error::OutOfSpace);
+			}
+			if fsnode.write(start, &page.data.data()[..len])? < len {
+				return Err(vfs::Error::OutOfSpace);
+			}
+		}
+		page.dirty = false;
+	}
+	// Apply any extension not covered by a dirty page (zero-filled by the filesystem)
+	if fsnode.size() < size && fsnode.truncate(size)? < size {
+		return Err(vfs::Error::OutOfSpace);
+	}
+	extended_size.store(0, Ordering::Relaxed);
+	Ok( () )
+}
+
+/// Get a cached page, loading it from the filesystem if `fill` is set (otherwise the new page is zeroed)
+///
+/// Returns `None` if the cache is full and nothing could be evicted
+fn get_page<'a>(pages: &'a mut VecMap<u64,CachedFilePage>, extended_size: &AtomicU64, fsnode: &dyn vfs::node::File, idx: u64, fill: bool) -> vfs::Result<Option<&'a mut CachedFilePage>>
+{
+	if pages.get(&idx).is_none()
+	{
+		if !reserve_page(pages, extended_size, fsnode)? {
+			return Ok(None);
+		}
+		let mut data = match S_PAGE_CACHE.create()
+			{
+			Ok(v) => v,
+			Err(_) => {
+				S_CACHED_PAGES.fetch_sub(1, Ordering::Relaxed);
+				return Err(vfs::Error::OutOfMemory);
+				},
+			};
+		let len = if fill {
+				match fsnode.read(idx * PAGE_SIZE as u64, data.data_mut())
+				{
+				Ok(v) => v,
+				Err(e) => {
+					S_CACHED_PAGES.fetch_sub(1, Ordering::Relaxed);
+					return Err(e);
+					},
+				}
+			}
+			else {
+				0
+			};
+		zero(&mut data.data_mut()[len..]);
+		pages.insert(idx, CachedFilePage { data, dirty: false, last_access: 0 });
+	}
+	let page = pages.get_mut(&idx).unwrap();
+	page.last_access = ::kernel::time::ticks();
+	Ok( Some(page) )
+}
+
+/// Account for a new page, evicting an existing one if the cache is full
+///
+/// Returns false if no space could be made
+fn reserve_page(pages: &mut VecMap<u64,CachedFilePage>, extended_size: &AtomicU64, fsnode: &dyn vfs::node::File) -> vfs::Result<bool>
+{
+	if S_CACHED_PAGES.fetch_add(1, Ordering::Relaxed) < MAX_CACHED_PAGES {
+		return Ok(true);
+	}
+	S_CACHED_PAGES.fetch_sub(1, Ordering::Relaxed);
+	// Full, try to replace one of this file's clean pages (the new page takes over its count)
+	if evict_oldest(pages) {
+		return Ok(true);
+	}
+	// Then pages from any other file
+	if reclaim_clean(1) > 0 {
+		S_CACHED_PAGES.fetch_add(1, Ordering::Relaxed);
+		return Ok(true);
+	}
+	// Finally write back this file's pages and replace one
+	flush_locked(pages, extended_size, fsnode)?;
+	Ok( evict_oldest(pages) )
+}
+
+/// Remove the least recently used clean page (without changing the page count)
+fn evict_oldest(pages: &mut VecMap<u64,CachedFilePage>) -> bool
+{
+	let oldest = pages.iter()
+		.filter(|(_,p)| p.can_evict())
+		.min_by_key(|(_,p)| p.last_access)
+		.map(|(&idx,_)| idx);
+	match oldest
+	{
+	Some(idx) => {
+		pages.remove(&idx);
+		true
+		},
+	None => false,
+	}
+}
+
+/// Drop up to `count` clean pages from any file, returning the number dropped
+///
+/// Never blocks, files that are currently in use are skipped
+fn reclaim_clean(count: usize) -> usize
+{
+	let mut released = 0;
+	super::with_file_pages_nonblocking(|file_pages| {
+		if let Some(mut lh) = file_pages.pages.try_lock() {
+			lh.retain(|_, p| if released < count && p.can_evict() { released += 1; false } else { true });
+		}
+		released < count
+		});
+	S_CACHED_PAGES.fetch_sub(released, Ordering::Relaxed);
+	released
+}
+
+/// Registration with the physical memory manager
+struct Reclaim;
+impl ::kernel::memory::phys::Reclaimable for Reclaim
+{
+	fn reclaim(&self, count: usize) -> usize {
+		reclaim_clean(count)
+	}
+}
@@ -10,13 +10,38 @@ use ::kernel::metadevs::storage::VolumeHandle;
 use ::kernel::lib::{VecMap,SparseVec};
 use ::kernel::lib::byte_str::{ByteStr,ByteString};
 use ::kernel::lib::mem::aref::{Aref,ArefInner,ArefBorrow};
+use ::kernel::memory::phys::FrameHandle;
+use ::kernel::memory::page_cache::S_PAGE_CACHE;
+use ::kernel::PAGE_SIZE;
+use ::core::sync::atomic::{AtomicUsize,Ordering};
+use ::core::mem::ManuallyDrop;
 
 pub struct Driver;
 pub static S_DRIVER: Driver = Driver;
 
+struct RamNode
+{
+	/// Metadata (the size is filled in when read)
+	meta: ::kernel::sync::Mutex<node::Metadata>,
+	file: RamFile,
+}
+impl RamNode {
+	fn new(file: RamFile) -> RamNode {
+		let mode = match file
+			{
+			RamFile::File(_) => 0o644,
+			RamFile::Dir(_) => 0o755,
+			RamFile::Symlink(_) => 0o777,
+			};
+		RamNode {
+			meta: ::kernel::sync::Mutex::new(node::Metadata { links: 1, mode, ..Default::default() }),
+			file,
+		}
+	}
+}
 enum RamFile
 {
-	//File(RamFileFile),
+	File(RamFileFile),
 	Dir(RamFileDir),
 	Symlink(RamFileSymlink),
 }
@@ -30,13 +55,22 @@ struct RamFileSymlink
 {
 	target: super::PathBuf,
 }
-//#[derive(Default)]
-//struct RamFileFile
-//{
-//	ofs: usize,
-//	size: usize,
-//}
-struct FileRef(ArefBorrow<RamFSInner>,ArefBorrow<RamFile>);
+#[derive(Default)]
+struct RamFileFile
+{
+	data: ::kernel::sync::RwLock<RamFileData>,
+}
+#[derive(Default)]
+struct RamFileData
+{
+	size: u64,
+	/// Allocated pages, indexed by page number (missing pages read as zero)
+	pages: VecMap<u64,FrameHandle>,
+}
+/// Handle to a node
+///
+/// The node borrow is released (and the node freed, if it's been unlinked) on drop
+struct FileRef(ArefBorrow<RamFSInner>,ManuallyDrop<ArefBorrow<RamNode>>,node::InodeId);
 
 struct RamFS
 {
@@ -47,7 +81,12 @@ struct RamFSInner
 	_vh: VolumeHandle,
 	// TODO: Store as much data (and metadata) as possible on the volume
 	// - Possibly by using an allocation pool backed onto the volume
-	nodes: ::kernel::sync::Mutex< SparseVec<Aref<RamFile>> >,
+	nodes: ::kernel::sync::Mutex< SparseVec<Aref<RamNode>> >,
+	/// Maximum number of data pages (from the `size=` mount option, zero for no limit)
+	page_limit: usize,
+	pages_used: AtomicUsize,
+	/// Held while renaming, so directories can't move while checking for loops
+	rename_lock: ::kernel::sync::Mutex<()>,
 }
 
 pub fn init()
@@ -62,15 +101,30 @@ impl mount::Driver for Driver
 		// RAMFS should never bind to an arbitrary volume
 		Ok(0)
 	}
-	fn mount(&self, vol: VolumeHandle, _: mount::SelfHandle) -> super::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _read_only: bool, handle: mount::SelfHandle) -> super::Result<Box<dyn mount::Filesystem>> {
+		let page_limit = match handle.get_option("size")
+			{
+			Some(v) => match parse_size(v)
+				{
+				Some(bytes) => ((bytes + PAGE_SIZE as u64 - 1) / PAGE_SIZE as u64) as usize,
+				None => {
+					log_notice!("ramfs: Invalid size {:?}", v);
+					return Err(vfs::Error::InvalidParameter);
+					},
+				},
+			None => 0,
+			};
 		let rv = Box::new(RamFS {
 			// SAFE: ArefInner must not change addresses, but because you can't move out of a boxed trait, we're good
 			inner: unsafe { ArefInner::new( RamFSInner {
 				_vh: vol,
 				nodes: Default::default(),
+				page_limit,
+				pages_used: AtomicUsize::new(0),
+				rename_lock: Default::default(),
 				}) },
 			});
-		let root_inode = rv.inner.nodes.lock().insert( Aref::new(RamFile::Dir(Default::default())) );
+		let root_inode = rv.inner.nodes.lock().insert( Aref::new(RamNode::new(RamFile::Dir(Default::default()))) );
 		assert_eq!(root_inode, 0);
 		Ok(rv)
 	}
@@ -84,35 +138,149 @@ impl mount::Filesystem for RamFS
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
 		log_trace!("RamFS::get_node_by_inode({})", id);
 		let nodes = self.inner.nodes.lock();
-		if id >= nodes.len() as node::InodeId {
-			log_log!("RamFile::get_node_by_inode - Inode {} out of range", id);
-			None
-		}
-		else {
-			let fr = Box::new(FileRef(
-				self.inner.borrow(),
-				nodes[id as usize].borrow()
-				));
-			match *nodes[id as usize]
+		let n = match nodes.get(id as usize)
+			{
+			// Unlinked nodes are only reachable through existing handles
+			Some(n) if n.meta.lock().links > 0 => n,
+			_ => {
+				log_log!("RamFile::get_node_by_inode - Inode {} out of range", id);
+				return None;
+				},
+			};
+		let fr = Box::new(FileRef(
+			self.inner.borrow(),
+			ManuallyDrop::new(n.borrow()),
+			id
+			));
+		match n.file
+		{
+		RamFile::File(_) => Some(node::Node::File(fr)),
+		RamFile::Dir(_) => Some(node::Node::Dir(fr)),
+		RamFile::Symlink(_) => Some(node::Node::Symlink(fr)),
+		}
+	}
+}
+
+/// Parse a size with an optional `k`/`m`/`g` suffix
+fn parse_size(v: &str) -> Option<u64> {
+	let (num, shift) = match v.as_bytes().last()
+		{
+		Some(b'k') | Some(b'K') => (&v[..v.len()-1], 10),
+		Some(b'm') | Some(b'M') => (&v[..v.len()-1], 20),
+		Some(b'g') | Some(b'G') => (&v[..v.len()-1], 30),
+		_ => (v, 0),
+		};
+	num.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+impl RamFSInner
+{
+	/// Allocate a zeroed data page, checking against the size limit
+	fn alloc_page(&self) -> vfs::Result<FrameHandle> {
+		let used = self.pages_used.fetch_add(1, Ordering::Relaxed);
+		if self.page_limit != 0 && used >= self.page_limit {
+			self.pages_used.fetch_sub(1, Ordering::Relaxed);
+			return Err(vfs::Error::OutOfSpace);
+		}
+		let mut page = match S_PAGE_CACHE.create()
+			{
+			Ok(v) => v,
+			Err(_) => {
+				self.pages_used.fetch_sub(1, Ordering::Relaxed);
+				return Err(vfs::Error::OutOfMemory);
+				},
+			};
+		for b in page.data_mut().iter_mut() {
+			*b = 0;
+		}
+		Ok( page.get_frame_handle() )
+	}
+	fn free_pages(&self, count: usize) {
+		self.pages_used.fetch_sub(count, Ordering::Relaxed);
+	}
+
+	/// Check if `inode` is the directory `dir` or somewhere below it
+	fn is_within(&self, inode: usize, dir: usize) -> bool {
+		if inode == dir {
+			return true;
+		}
+		let node = self.nodes.lock()[dir].borrow();
+		let children: Vec<usize> = match node.file
+			{
+			RamFile::Dir(ref d) => d.ents.read().iter().map(|(_,&i)| i).collect(),
+			_ => return false,
+			};
+		children.into_iter().any(|c| self.is_within(inode, c))
+	}
+
+	/// Free a node once it has no names and no open handles
+	fn release_if_orphaned(&self, inode: usize) {
+		let node = {
+			let mut nodes = self.nodes.lock();
+			match nodes.get(inode)
 			{
-			RamFile::Dir(_) => Some(node::Node::Dir(fr)),
-			RamFile::Symlink(_) => Some(node::Node::Symlink(fr)),
-			//RamFile::File(_) => todo!("normal files"),
+			Some(n) if n.meta.lock().links == 0 => {},
+			_ => return,
+			}
+			if Aref::get_mut(&mut nodes[inode]).is_none() {
+				// Still open, freed when the last handle is dropped
+				return ;
+			}
+			nodes.remove(inode)
+			};
+		if let Some(ref n) = node {
+			if let RamFile::File(ref f) = n.file {
+				self.free_pages(f.data.read().pages.iter().count());
 			}
 		}
 	}
 }
 
+impl RamFileData
+{
+	/// Zero part of a page (if it's allocated)
+	fn zero_partial(&self, page: u64, ofs: usize, len: usize) {
+		if let Some(frame) = self.pages.get(&page) {
+			let mut mapping = S_PAGE_CACHE.map(frame).expect("ramfs: Out of page cache mappings");
+			for b in mapping.data_mut()[ofs..][..len].iter_mut() {
+				*b = 0;
+			}
+		}
+	}
+	/// Remove all pages in the range `first..last` (returning the number freed)
+	fn free_range(&mut self, first: u64, last: u64) -> usize {
+		let to_free: Vec<u64> = self.pages.iter().map(|(&k,_)| k).filter(|&k| first <= k && k < last).collect();
+		for k in &to_free {
+			self.pages.remove(k);
+		}
+		to_free.len()
+	}
+}
+
+impl Drop for FileRef {
+	fn drop(&mut self) {
+		// SAFE: The borrow isn't used after this point
+		unsafe { ManuallyDrop::drop(&mut self.1) };
+		self.0.release_if_orphaned(self.2 as usize);
+	}
+}
 impl FileRef {
+	fn file(&self) -> &RamFileFile {
+		match &self.1.file
+		{
+		&RamFile::File(ref e) => e,
+		_ => panic!("Called FileRef::file() on non-file"),
+		}
+	}
 	fn dir(&self) -> &RamFileDir {
-		match &*self.1
+		match &self.1.file
 		{
 		&RamFile::Dir(ref e) => e,
 		_ => panic!("Called FileRef::dir() on non-dir"),
 		}
 	}
 	fn symlink(&self) -> &RamFileSymlink {
-		match &*self.1
+		match &self.1.file
 		{
 		&RamFile::Symlink(ref e) => e,
 		_ => panic!("Called FileRef::symlink() on non-symlink"),
@@ -121,11 +289,30 @@ impl FileRef {
 }
 impl node::NodeBase for FileRef {
 	fn get_id(&self) -> node::InodeId {
-		unimplemented!()
+		self.2
 	}
 	fn get_any(&self) -> &dyn (::core::any::Any) {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		let mut rv = self.1.meta.lock().clone();
+		rv.size = match self.1.file
+			{
+			RamFile::File(ref f) => f.data.read().size,
+			RamFile::Dir(ref d) => d.ents.read().len() as u64,
+			RamFile::Symlink(ref l) => ByteStr::new(&*l.target).len() as u64,
+			};
+		Ok(rv)
+	}
+	fn set_metadata(&self, changes: &node::MetadataUpdate) -> node::Result<()> {
+		let mut lh = self.1.meta.lock();
+		if let Some(v) = changes.mode { lh.mode = v & 0o7777; }
+		if let Some(v) = changes.uid { lh.uid = v; }
+		if let Some(v) = changes.gid { lh.gid = v; }
+		if let Some(v) = changes.atime { lh.atime = Some(v); }
+		if let Some(v) = changes.mtime { lh.mtime = Some(v); }
+		Ok( () )
+	}
 }
 impl node::Dir for FileRef {
 	fn lookup(&self, name: &ByteStr) -> vfs::Result<node::InodeId> {
@@ -161,22 +348,237 @@ impl node::Dir for FileRef {
 			let nn = match nodetype
 				{
 				node::NodeType::Dir  => RamFile::Dir (Default::default()),
-				//node::NodeType::File => RamFile::File(Default::default()),
-				node::NodeType::File => return Err(vfs::Error::Unknown("TODO: Files")),
+				node::NodeType::File => RamFile::File(Default::default()),
 				node::NodeType::Symlink(v) =>
 					RamFile::Symlink(RamFileSymlink{target: From::from(v)}),
 				};
-			let inode = self.0.nodes.lock().insert( Aref::new(nn) );
+			let inode = self.0.nodes.lock().insert( Aref::new(RamNode::new(nn)) );
 			e.insert(inode);
 			Ok(inode as node::InodeId)
 			},
 		}
 	}
 	fn link(&self, name: &ByteStr, node: &dyn node::NodeBase) -> vfs::Result<()> {
-		todo!("<FileRef as Dir>::link({:?}, inode={})", name, node.get_id())
+		use ::kernel::lib::vec_map::Entry;
+		// NOTE: The VFS only passes nodes from the same mount
+		let target = match node.get_any().downcast_ref::<FileRef>()
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::InvalidParameter),
+			};
+		// Directories can't be hard linked (it would form loops)
+		if let RamFile::Dir(_) = target.1.file {
+			return Err(vfs::Error::PermissionDenied);
+		}
+		let mut lh = self.dir().ents.write();
+		match lh.entry(From::from(name))
+		{
+		Entry::Occupied(_) => Err(vfs::Error::AlreadyExists),
+		Entry::Vacant(e) => {
+			e.insert(target.2 as usize);
+			target.1.meta.lock().links += 1;
+			Ok( () )
+			},
+		}
 	}
 	fn unlink(&self, name: &ByteStr) -> vfs::Result<()> {
-		todo!("<FileRef as Dir>::unlink({:?})", name)
+		let mut lh = self.dir().ents.write();
+		let inode = match lh.get(name)
+			{
+			Some(&v) => v,
+			None => return Err(vfs::Error::NotFound),
+			};
+		{
+			let nodes = self.0.nodes.lock();
+			let n = &nodes[inode];
+			if let RamFile::Dir(ref d) = n.file {
+				if d.ents.read().len() > 0 {
+					return Err(vfs::Error::DirectoryNotEmpty);
+				}
+			}
+			n.meta.lock().links -= 1;
+		}
+		lh.remove(name);
+		self.0.release_if_orphaned(inode);
+		Ok( () )
+	}
+	fn rename(&self, old_name: &ByteStr, new_dir: &dyn node::NodeBase, new_name: &ByteStr) -> vfs::Result<()> {
+		// NOTE: The VFS only passes nodes from the same mount
+		let new_dir = match new_dir.get_any().downcast_ref::<FileRef>()
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::InvalidParameter),
+			};
+		let _lh_rename = self.0.rename_lock.lock();
+		let inode = self.lookup(old_name)? as usize;
+		if self.2 == new_dir.2 && old_name == new_name {
+			return Ok( () );
+		}
+		let src = self.0.nodes.lock()[inode].borrow();
+		// A directory can't be moved into itself
+		if let RamFile::Dir(_) = src.file {
+			if self.0.is_within(new_dir.2 as usize, inode) {
+				return Err(vfs::Error::InvalidParameter);
+			}
+		}
+
+		// Add the new name (replacing an existing one), the source has an extra link until the old name is removed
+		let replaced = {
+			let mut lh = new_dir.dir().ents.write();
+			match lh.get_mut(new_name)
+			{
+			Some(slot) => {
+				let dst = *slot;
+				if dst == inode {
+					return Ok( () );
+				}
+				{
+					let nodes = self.0.nodes.lock();
+					match (&src.file, &nodes[dst].file)
+					{
+					(RamFile::Dir(_), RamFile::Dir(d)) => if d.ents.read().len() > 0 {
+						return Err(vfs::Error::DirectoryNotEmpty);
+						},
+					(RamFile::Dir(_), _) | (_, RamFile::Dir(_)) => return Err(vfs::Error::TypeMismatch),
+					_ => {},
+					}
+					nodes[dst].meta.lock().links -= 1;
+				}
+				src.meta.lock().links += 1;
+				*slot = inode;
+				Some(dst)
+				},
+			None => {
+				src.meta.lock().links += 1;
+				lh.insert(From::from(new_name), inode);
+				None
+				},
+			}
+			};
+
+		// Remove the old name
+		{
+			let mut lh = self.dir().ents.write();
+			if lh.get(old_name) == Some(&inode) {
+				lh.remove(old_name);
+				src.meta.lock().links -= 1;
+			}
+		}
+		drop(src);
+		if let Some(dst) = replaced {
+			self.0.release_if_orphaned(dst);
+		}
+		Ok( () )
+	}
+}
+impl node::File for FileRef {
+	fn size(&self) -> u64 {
+		self.file().data.read().size
+	}
+	fn truncate(&self, newsize: u64) -> vfs::Result<u64> {
+		let mut lh = self.file().data.write();
+		if newsize < lh.size {
+			// Free whole pages past the new end, and zero the tail of the last page (so growing again reads zeroes)
+			let freed = lh.free_range( (newsize + PAGE_SIZE as u64 - 1) / PAGE_SIZE as u64, !0 );
+			self.0.free_pages(freed);
+			let tail_ofs = (newsize % PAGE_SIZE as u64) as usize;
+			if tail_ofs != 0 {
+				lh.zero_partial(newsize / PAGE_SIZE as u64, tail_ofs, PAGE_SIZE - tail_ofs);
+			}
+		}
+		lh.size = newsize;
+		Ok(newsize)
+	}
+	fn clear(&self, ofs: u64, size: u64) -> vfs::Result<()> {
+		let mut lh = self.file().data.write();
+		let end = u64::min(ofs.saturating_add(size), lh.size);
+		let mut pos = ofs;
+		while pos < end
+		{
+			let page = pos / PAGE_SIZE as u64;
+			let page_ofs = (pos % PAGE_SIZE as u64) as usize;
+			let len = u64::min((PAGE_SIZE - page_ofs) as u64, end - pos) as usize;
+			// Pages shared with a memory mapping are zeroed in place (so the mapping sees the change)
+			if len == PAGE_SIZE && !lh.pages.get(&page).map(|f| f.is_shared()).unwrap_or(false) {
+				let freed = lh.free_range(page, page + 1);
+				self.0.free_pages(freed);
+			}
+			else {
+				lh.zero_partial(page, page_ofs, len);
+			}
+			pos += len as u64;
+		}
+		Ok( () )
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+		let lh = self.file().data.read();
+		if ofs >= lh.size {
+			return Ok(0);
+		}
+		let len = u64::min(buf.len() as u64, lh.size - ofs) as usize;
+		let mut done = 0;
+		while done < len
+		{
+			let pos = ofs + done as u64;
+			let page_ofs = (pos % PAGE_SIZE as u64) as usize;
+			let dst = &mut buf[done..len];
+			let dst = if dst.len() > PAGE_SIZE - page_ofs { &mut dst[..PAGE_SIZE - page_ofs] } else { dst };
+			match lh.pages.get(&(pos / PAGE_SIZE as u64))
+			{
+			Some(frame) => {
+				let mapping = S_PAGE_CACHE.map(frame).map_err(|_| vfs::Error::TransientError)?;
+				dst.copy_from_slice(&mapping.data()[page_ofs..][..dst.len()]);
+				},
+			None => for b in dst.iter_mut() { *b = 0; },
+			}
+			done += dst.len();
+		}
+		Ok(len)
+	}
+	fn write(&self, ofs: u64, buf: &[u8]) -> vfs::Result<usize> {
+		let mut lh = self.file().data.write();
+		if ofs > lh.size {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let mut done = 0;
+		while done < buf.len()
+		{
+			let pos = ofs + done as u64;
+			let page = pos / PAGE_SIZE as u64;
+			let page_ofs = (pos % PAGE_SIZE as u64) as usize;
+			let src = &buf[done..];
+			let src = if src.len() > PAGE_SIZE - page_ofs { &src[..PAGE_SIZE - page_ofs] } else { src };
+			if lh.pages.get(&page).is_none() {
+				match self.0.alloc_page()
+				{
+				Ok(frame) => { lh.pages.insert(page, frame); },
+				// Report a short write if some data was written
+				Err(_) if done > 0 => break,
+				Err(e) => return Err(e),
+				}
+			}
+			let mut mapping = S_PAGE_CACHE.map(lh.pages.get(&page).unwrap()).map_err(|_| vfs::Error::TransientError)?;
+			mapping.data_mut()[page_ofs..][..src.len()].copy_from_slice(src);
+			done += src.len();
+		}
+		lh.size = u64::max(lh.size, ofs + done as u64);
+		Ok(done)
+	}
+	fn cacheable(&self) -> bool {
+		// Data is already held in memory
+		false
+	}
+	fn get_page_frame(&self, page: u64) -> vfs::Result<Option<FrameHandle>> {
+		let mut lh = self.file().data.write();
+		if page * PAGE_SIZE as u64 >= lh.size {
+			return Ok(None);
+		}
+		// Holes are allocated, so writes through the mapping end up in the file
+		if lh.pages.get(&page).is_none() {
+			let frame = self.0.alloc_page()?;
+			lh.pages.insert(page, frame);
+		}
+		Ok( lh.pages.get(&page).cloned() )
 	}
 }
 impl node::Symlink for FileRef {
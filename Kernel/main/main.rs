@@ -46,6 +46,7 @@ pub extern "C" fn kmain()
 	::kernel::memory::heap::init();
 	::kernel::memory::page_cache::init();
 	::kernel::threads::init();
+	::kernel::rand::init();
 	
 	log_log!("Command line = {:?}", ::kernel::arch::boot::get_boot_string());
 	::kernel::config::init( ::kernel::arch::boot::get_boot_string() );
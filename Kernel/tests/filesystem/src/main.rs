@@ -27,6 +27,9 @@ fn main()
     modules::use_mods();
 
     let cmd_stream = ::std::io::stdin();
+    // Nodes kept open by `hold`
+    let mut held = Vec::new();
+    let mut locked = Vec::new();
     loop
     {
         let mut s = String::new();
@@ -71,6 +74,7 @@ fn main()
             let vh = match ::kernel::metadevs::storage::VolumeHandle::open_named(volume)
                 {
                 Ok(vh) => vh,
+                Err(_) if volume == "none" => ::kernel::metadevs::storage::VolumeHandle::new_ramdisk(0),
                 Err(e) => panic!("`mount`: Unable to open {}: {}", volume, e),
                 };
             match ::vfs::mount::mount(mountpt.as_ref(), vh, filesystem, &options)
@@ -79,6 +83,156 @@ fn main()
             Err(e) => panic!("`mount`: Unable to mount {} from {}: {:?}", mountpt, volume, e),
             }
             },
+        // Unmount a volume, optionally checking that it fails with the given error
+        "unmount" => {
+            let mountpt = args.next().expect("`unmount` mountpt");
+            let expected_error = args.next();
+            log_log!("COMMAND: unmount {mountpt:?} (expect {expected_error:?})");
+            match (::vfs::mount::unmount(mountpt.as_ref()), expected_error)
+            {
+            (Ok(()), None) => {},
+            (Ok(()), Some(exp)) => panic!("`unmount`: Unmounting {} succeeded, expected {}", mountpt, exp),
+            (Err(e), Some(exp)) if format!("{:?}", e) == exp => {},
+            (Err(e), _) => panic!("`unmount`: Unable to unmount {}: {:?}", mountpt, e),
+            }
+            },
+        // Change a volume's mount options, optionally checking that it fails with the given error
+        "remount" => {
+            let mountpt = args.next().expect("`remount` mountpt");
+            let options = args.next().expect("`remount` options").split(",").collect::<Vec<_>>();
+            let expected_error = args.next();
+            log_log!("COMMAND: remount {mountpt:?} options={options:?} (expect {expected_error:?})");
+            match (::vfs::mount::remount(mountpt.as_ref(), &options), expected_error)
+            {
+            (Ok(()), None) => {},
+            (Ok(()), Some(exp)) => panic!("`remount`: Remounting {} succeeded, expected {}", mountpt, exp),
+            (Err(e), Some(exp)) if format!("{:?}", e) == exp => {},
+            (Err(e), _) => panic!("`remount`: Unable to remount {}: {:?}", mountpt, e),
+            }
+            },
+        // Keep a node open until `release`
+        "hold" => {
+            let path: &::vfs::Path = args.next().expect("`hold` path").as_ref();
+            log_log!("COMMAND: hold {:?}", path);
+            match vfs_handle::Any::open(path)
+            {
+            Ok(h) => held.push(h),
+            Err(e) => panic!("`hold`: Cannot open {:?}: {:?}", path, e),
+            }
+            },
+        "release" => {
+            log_log!("COMMAND: release ({} nodes)", held.len());
+            held.clear();
+            },
+        // Take an advisory lock using a new handle (kept open until `release_locks`)
+        "lock" => {
+            let path: &::vfs::Path = args.next().expect("`lock` path").as_ref();
+            let ofs: u64 = args.next().expect("`lock` ofs").parse().expect("`lock` ofs invalid");
+            let len: u64 = args.next().expect("`lock` len").parse().expect("`lock` len invalid");
+            let kind = match args.next().expect("`lock` kind")
+                {
+                "shared" => vfs_handle::LockKind::Shared,
+                "exclusive" => vfs_handle::LockKind::Exclusive,
+                k => panic!("`lock`: Unknown lock kind {:?}", k),
+                };
+            let expected_error = args.next();
+            log_log!("COMMAND: lock {:?} {}+{} {:?} (expect {:?})", path, ofs, len, kind, expected_error);
+            let h = match vfs_handle::File::open(path, vfs_handle::FileOpenMode::SharedRO)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`lock`: Cannot open {:?}: {:?}", path, e),
+                };
+            match (h.try_lock(ofs, len, kind), expected_error)
+            {
+            (Ok(()), None) => locked.push(h),
+            (Ok(()), Some(exp)) => panic!("`lock`: Locking {:?} succeeded, expected {}", path, exp),
+            (Err(e), Some(exp)) if format!("{:?}", e) == exp => {},
+            (Err(e), _) => panic!("`lock`: Cannot lock {:?}: {:?}", path, e),
+            }
+            },
+        // Release part of the most recent lock
+        "unlock" => {
+            let ofs: u64 = args.next().expect("`unlock` ofs").parse().expect("`unlock` ofs invalid");
+            let len: u64 = args.next().expect("`unlock` len").parse().expect("`unlock` len invalid");
+            log_log!("COMMAND: unlock {}+{}", ofs, len);
+            let h = locked.last().expect("`unlock`: No locks held");
+            if let Err(e) = h.unlock(ofs, len) {
+                panic!("`unlock`: Cannot unlock {}+{}: {:?}", ofs, len, e);
+            }
+            },
+        // Try a lock using a clone of the most recent lock's handle (clones are separate lock owners)
+        "lock_clone" => {
+            let ofs: u64 = args.next().expect("`lock_clone` ofs").parse().expect("`lock_clone` ofs invalid");
+            let len: u64 = args.next().expect("`lock_clone` len").parse().expect("`lock_clone` len invalid");
+            let kind = match args.next().expect("`lock_clone` kind")
+                {
+                "shared" => vfs_handle::LockKind::Shared,
+                "exclusive" => vfs_handle::LockKind::Exclusive,
+                k => panic!("`lock_clone`: Unknown lock kind {:?}", k),
+                };
+            let expected_error = args.next();
+            log_log!("COMMAND: lock_clone {}+{} {:?} (expect {:?})", ofs, len, kind, expected_error);
+            let h = locked.last().expect("`lock_clone`: No locks held").clone();
+            match (h.try_lock(ofs, len, kind), expected_error)
+            {
+            (Ok(()), None) => locked.push(h),
+            (Ok(()), Some(exp)) => panic!("`lock_clone`: Locking succeeded, expected {}", exp),
+            (Err(e), Some(exp)) if format!("{:?}", e) == exp => {},
+            (Err(e), _) => panic!("`lock_clone`: Cannot lock: {:?}", e),
+            }
+            },
+        // Take a lock that conflicts with the most recent lock, waiting until that lock is released by `unlock`-ing
+        // all of it or `close`-ing its handle (checks that waiters are woken)
+        "lock_wait" => {
+            let path: &::vfs::Path = args.next().expect("`lock_wait` path").as_ref();
+            let ofs: u64 = args.next().expect("`lock_wait` ofs").parse().expect("`lock_wait` ofs invalid");
+            let len: u64 = args.next().expect("`lock_wait` len").parse().expect("`lock_wait` len invalid");
+            let kind = match args.next().expect("`lock_wait` kind")
+                {
+                "shared" => vfs_handle::LockKind::Shared,
+                "exclusive" => vfs_handle::LockKind::Exclusive,
+                k => panic!("`lock_wait`: Unknown lock kind {:?}", k),
+                };
+            let release = args.next().expect("`lock_wait` release");
+            log_log!("COMMAND: lock_wait {:?} {}+{} {:?} ({})", path, ofs, len, kind, release);
+            let h = match vfs_handle::File::open(path, vfs_handle::FileOpenMode::SharedRO)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`lock_wait`: Cannot open {:?}: {:?}", path, e),
+                };
+            match h.try_lock(ofs, len, kind)
+            {
+            Err(::vfs::Error::Locked) => {},
+            rv => panic!("`lock_wait`: Expected {:?} to be locked, got {:?}", path, rv),
+            }
+            assert!(h.lock_would_block(ofs, len, kind));
+            ::kernel::threads::SleepObject::with_new("lock_wait", |obj| {
+                h.bind_wait_lock(obj);
+                match release
+                {
+                "unlock" => {
+                    let other = locked.last().expect("`lock_wait`: No locks held");
+                    if let Err(e) = other.unlock(0, 0) {
+                        panic!("`lock_wait`: Cannot unlock: {:?}", e);
+                    }
+                    },
+                "close" => { locked.pop().expect("`lock_wait`: No locks held"); },
+                r => panic!("`lock_wait`: Unknown release method {:?}", r),
+                }
+                // Returns immediately if the release signalled the waiter (hangs otherwise)
+                obj.wait();
+                h.clear_wait_lock(obj);
+                });
+            if let Err(e) = h.try_lock(ofs, len, kind) {
+                panic!("`lock_wait`: Cannot lock {:?} after release: {:?}", path, e);
+            }
+            locked.push(h);
+            },
+        // Close all handles holding locks
+        "release_locks" => {
+            log_log!("COMMAND: release_locks ({} handles)", locked.len());
+            locked.clear();
+            },
         // List directory
         "ls" => {
             let dir = ::vfs::Path::new( args.next().expect("ls dir") );
@@ -120,6 +274,26 @@ fn main()
             Err(e) => log_error!("cannot create {:?} in '{:?}': {:?}", dirname, dir, e),
             }
             },
+        // Create a symbolic link, optionally checking that it fails with the given error
+        "symlink" => {
+            let path = ::vfs::Path::new( args.next().expect("`symlink` path") );
+            let target = ::vfs::Path::new( args.next().expect("`symlink` target") );
+            let expected_error = args.next();
+            let (dir,name) = path.split_off_last().expect("`symlink` path invalid");
+            log_log!("COMMAND: symlink {:?} {:?} -> {:?}", dir, name, target);
+            let h = match vfs_handle::Dir::open(dir)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`symlink`: {:?} cannot be opened: {:?}", dir, e),
+                };
+            match (h.symlink(name, target), expected_error)
+            {
+            (Ok(()), None) => {},
+            (Ok(()), Some(exp)) => panic!("`symlink`: Creating {:?} succeeded, expected {}", path, exp),
+            (Err(e), Some(exp)) if format!("{:?}", e) == exp => {},
+            (Err(e), _) => panic!("`symlink`: Cannot create {:?}: {:?}", path, e),
+            }
+            },
         // Copy a file from local to remote
         "store" => {
             let src: &::std::path::Path = args.next().expect("`store` src").as_ref();
@@ -144,7 +318,9 @@ fn main()
                     Ok(h) => match h.into_file(::vfs::handle::FileOpenMode::ExclRW)
                         {
                         Ok(h) => {
-                            h.truncate();
+                            if let Err(e) = h.truncate(0) {
+                                panic!("`store`: Cannot truncate existing {:?}: {:?}", dst, e);
+                            }
                             h
                             },
                         Err(e) => panic!("`store`: Cannot create {:?}: {:?}", dst, e),
@@ -214,6 +390,289 @@ fn main()
                 ofs += len_l as u64;
             }
             },
+        // Write a string into a file at the given offset, optionally checking that it fails with the given error
+        "write" => {
+            let remote: &::vfs::Path = args.next().expect("`write` remote").as_ref();
+            let ofs: u64 = args.next().expect("`write` ofs").parse().expect("`write` ofs invalid");
+            let data = args.next().expect("`write` data");
+            let expected_error = args.next();
+            log_log!("COMMAND: write {:?} @{} {:?} (expect {:?})", remote, ofs, data, expected_error);
+            let h = open_rw(remote);
+            match (h.write(ofs, data.as_bytes()), expected_error)
+            {
+            (Ok(l), None) if l == data.len() => {},
+            (Ok(l), None) => panic!("`write`: Short write to {:?}: {} != exp {}", remote, l, data.len()),
+            (Ok(_), Some(exp)) => panic!("`write`: Writing to {:?} succeeded, expected {}", remote, exp),
+            (Err(e), Some(exp)) if format!("{:?}", e) == exp => {},
+            (Err(e), _) => panic!("`write`: Failed to write to {:?}: {:?}", remote, e),
+            }
+            },
+        // Change a file's size
+        "truncate" => {
+            let remote: &::vfs::Path = args.next().expect("`truncate` remote").as_ref();
+            let size: u64 = args.next().expect("`truncate` size").parse().expect("`truncate` size invalid");
+            log_log!("COMMAND: truncate {:?} {}", remote, size);
+            match open_rw(remote).truncate(size)
+            {
+            Ok(v) => assert_eq!(v, size, "`truncate`: Size mismatch"),
+            Err(e) => panic!("`truncate`: Failed to truncate {:?}: {:?}", remote, e),
+            }
+            },
+        // Zero a range of a file
+        "clear" => {
+            let remote: &::vfs::Path = args.next().expect("`clear` remote").as_ref();
+            let ofs: u64 = args.next().expect("`clear` ofs").parse().expect("`clear` ofs invalid");
+            let len: u64 = args.next().expect("`clear` len").parse().expect("`clear` len invalid");
+            log_log!("COMMAND: clear {:?} {}+{}", remote, ofs, len);
+            if let Err(e) = open_rw(remote).clear(ofs, len) {
+                panic!("`clear`: Failed to clear {:?}: {:?}", remote, e);
+            }
+            },
+        // Write a file's cached data back to disk
+        "fsync" => {
+            let remote: &::vfs::Path = args.next().expect("`fsync` remote").as_ref();
+            log_log!("COMMAND: fsync {:?}", remote);
+            if let Err(e) = open_rw(remote).sync() {
+                panic!("`fsync`: Failed to sync {:?}: {:?}", remote, e);
+            }
+            },
+        // Write all cached data back to disk
+        "sync" => {
+            log_log!("COMMAND: sync");
+            if let Err(e) = ::vfs::mount::sync_all() {
+                panic!("`sync`: Failed: {:?}", e);
+            }
+            },
+        // Add a new name for a file
+        "link" => {
+            let existing: &::vfs::Path = args.next().expect("`link` existing").as_ref();
+            let new: &::vfs::Path = args.next().expect("`link` new").as_ref();
+            let expected_error = args.next();
+            let (new_dir,new_name) = new.split_off_last().expect("`link` new invalid");
+            log_log!("COMMAND: link {:?} {:?} (expect {:?})", existing, new, expected_error);
+            let node = match vfs_handle::Any::open(existing)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`link`: Cannot open {:?}: {:?}", existing, e),
+                };
+            let parent = match vfs_handle::Dir::open(new_dir)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`link`: Cannot open parent directory of {:?}: {:?}", new, e),
+                };
+            match (parent.link(new_name, &node), expected_error)
+            {
+            (Ok(()), None) => {},
+            (Ok(()), Some(exp)) => panic!("`link`: Linking {:?} succeeded, expected {}", new, exp),
+            (Err(e), Some(exp)) if format!("{:?}", e) == exp => {},
+            (Err(e), _) => panic!("`link`: Cannot link {:?} to {:?}: {:?}", existing, new, e),
+            }
+            },
+        // Remove a name, optionally checking that it fails with the given error
+        "unlink" => {
+            let path: &::vfs::Path = args.next().expect("`unlink` path").as_ref();
+            let expected_error = args.next();
+            let (dir,name) = path.split_off_last().expect("`unlink` path invalid");
+            log_log!("COMMAND: unlink {:?} {:?} (expect {:?})", dir, name, expected_error);
+            let h = match vfs_handle::Dir::open(dir)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`unlink`: Cannot open {:?}: {:?}", dir, e),
+                };
+            match (h.unlink(name), expected_error)
+            {
+            (Ok(()), None) => {},
+            (Ok(()), Some(exp)) => panic!("`unlink`: Removing {:?} succeeded, expected {}", path, exp),
+            (Err(e), Some(exp)) if format!("{:?}", e) == exp => {},
+            (Err(e), _) => panic!("`unlink`: Cannot remove {:?}: {:?}", path, e),
+            }
+            },
+        // Rename a node, optionally checking that it fails with the given error
+        "rename" => {
+            let old: &::vfs::Path = args.next().expect("`rename` old").as_ref();
+            let new: &::vfs::Path = args.next().expect("`rename` new").as_ref();
+            let expected_error = args.next();
+            let (old_dir,old_name) = old.split_off_last().expect("`rename` old invalid");
+            let (new_dir,new_name) = new.split_off_last().expect("`rename` new invalid");
+            log_log!("COMMAND: rename {:?} {:?} (expect {:?})", old, new, expected_error);
+            let src = match vfs_handle::Dir::open(old_dir)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`rename`: Cannot open {:?}: {:?}", old_dir, e),
+                };
+            let dst = match vfs_handle::Dir::open(new_dir)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`rename`: Cannot open {:?}: {:?}", new_dir, e),
+                };
+            match (src.rename(old_name, &dst, new_name), expected_error)
+            {
+            (Ok(()), None) => {},
+            (Ok(()), Some(exp)) => panic!("`rename`: Renaming {:?} to {:?} succeeded, expected {}", old, new, exp),
+            (Err(e), Some(exp)) if format!("{:?}", e) == exp => {},
+            (Err(e), _) => panic!("`rename`: Cannot rename {:?} to {:?}: {:?}", old, new, e),
+            }
+            },
+        // Check the size of a file
+        "assert_size" => {
+            let remote: &::vfs::Path = args.next().expect("`assert_size` remote").as_ref();
+            let size: u64 = args.next().expect("`assert_size` size").parse().expect("`assert_size` size invalid");
+            let h = match vfs_handle::File::open(remote, vfs_handle::FileOpenMode::SharedRO)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`assert_size`: Cannot open {:?}: {:?}", remote, e),
+                };
+            assert_eq!(h.size(), size, "`assert_size`: {:?}", remote);
+            },
+        // Check the contents of a file at an offset
+        "assert_bytes" => {
+            let remote: &::vfs::Path = args.next().expect("`assert_bytes` remote").as_ref();
+            let ofs: u64 = args.next().expect("`assert_bytes` ofs").parse().expect("`assert_bytes` ofs invalid");
+            let data = args.next().expect("`assert_bytes` data");
+            let h = match vfs_handle::File::open(remote, vfs_handle::FileOpenMode::SharedRO)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`assert_bytes`: Cannot open {:?}: {:?}", remote, e),
+                };
+            let mut buf = vec![0; data.len()];
+            match h.read(ofs, &mut buf)
+            {
+            Ok(l) => assert_eq!(&buf[..l], data.as_bytes(), "`assert_bytes`: {:?} @{}", remote, ofs),
+            Err(e) => panic!("`assert_bytes`: Cannot read {:?}: {:?}", remote, e),
+            }
+            },
+        // Check that a range of a file is zero
+        "assert_zero" => {
+            let remote: &::vfs::Path = args.next().expect("`assert_zero` remote").as_ref();
+            let ofs: u64 = args.next().expect("`assert_zero` ofs").parse().expect("`assert_zero` ofs invalid");
+            let len: usize = args.next().expect("`assert_zero` len").parse().expect("`assert_zero` len invalid");
+            let h = match vfs_handle::File::open(remote, vfs_handle::FileOpenMode::SharedRO)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`assert_zero`: Cannot open {:?}: {:?}", remote, e),
+                };
+            let mut buf = vec![0xFF; len];
+            match h.read(ofs, &mut buf)
+            {
+            Ok(l) => {
+                assert_eq!(l, len, "`assert_zero`: {:?} @{} short read", remote, ofs);
+                assert!(buf.iter().all(|&b| b == 0), "`assert_zero`: {:?} @{}+{} not zero", remote, ofs, len);
+                },
+            Err(e) => panic!("`assert_zero`: Cannot read {:?}: {:?}", remote, e),
+            }
+            },
+        // Check that a path doesn't exist
+        "assert_missing" => {
+            let remote: &::vfs::Path = args.next().expect("`assert_missing` remote").as_ref();
+            match vfs_handle::Any::open(remote)
+            {
+            Err(::vfs::Error::NotFound) => {},
+            Err(e) => panic!("`assert_missing`: Unexpected error opening {:?}: {:?}", remote, e),
+            Ok(_) => panic!("`assert_missing`: {:?} exists", remote),
+            }
+            },
+        // Check the target of a symbolic link
+        "assert_symlink" => {
+            let remote: &::vfs::Path = args.next().expect("`assert_symlink` remote").as_ref();
+            let target = args.next().expect("`assert_symlink` target");
+            let h = match vfs_handle::Symlink::open(remote)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`assert_symlink`: Cannot open {:?}: {:?}", remote, e),
+                };
+            match h.get_target()
+            {
+            Ok(v) => assert_eq!(v.as_bytes(), target.as_bytes(), "`assert_symlink`: {:?}", remote),
+            Err(e) => panic!("`assert_symlink`: Cannot read {:?}: {:?}", remote, e),
+            }
+            },
+        // Check a metadata field (times are seconds since 1970, or `none`)
+        "assert_meta" => {
+            let remote: &::vfs::Path = args.next().expect("`assert_meta` remote").as_ref();
+            let field = args.next().expect("`assert_meta` field");
+            let value = args.next().expect("`assert_meta` value");
+            let h = match vfs_handle::Any::open(remote)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`assert_meta`: Cannot open {:?}: {:?}", remote, e),
+                };
+            let m = match h.get_metadata()
+                {
+                Ok(m) => m,
+                Err(e) => panic!("`assert_meta`: Cannot get metadata for {:?}: {:?}", remote, e),
+                };
+            let time = |t: Option<::vfs::node::Timestamp>| t.map(|v| v.secs.to_string()).unwrap_or("none".to_owned());
+            let have = match field
+                {
+                "size" => m.size.to_string(),
+                "links" => m.links.to_string(),
+                "mode" => format!("{:o}", m.mode),
+                "uid" => m.uid.to_string(),
+                "gid" => m.gid.to_string(),
+                "atime" => time(m.atime),
+                "mtime" => time(m.mtime),
+                "ctime" => time(m.ctime),
+                "crtime" => time(m.crtime),
+                _ => panic!("`assert_meta`: Unknown field {:?}", field),
+                };
+            assert_eq!(have, value, "`assert_meta`: {:?} {}", remote, field);
+            },
+        // Update a metadata field (mode is in octal), optionally checking that it fails with the given error
+        "set_meta" => {
+            let remote: &::vfs::Path = args.next().expect("`set_meta` remote").as_ref();
+            let field = args.next().expect("`set_meta` field");
+            let value = args.next().expect("`set_meta` value");
+            let expected_error = args.next();
+            let mut changes = ::vfs::node::MetadataUpdate::default();
+            match field
+            {
+            "mode" => changes.mode = Some(u16::from_str_radix(value, 8).expect("`set_meta` mode invalid")),
+            "uid" => changes.uid = Some(value.parse().expect("`set_meta` uid invalid")),
+            "gid" => changes.gid = Some(value.parse().expect("`set_meta` gid invalid")),
+            "atime" => changes.atime = Some(::vfs::node::Timestamp::from_unix(value.parse().expect("`set_meta` atime invalid"))),
+            "mtime" => changes.mtime = Some(::vfs::node::Timestamp::from_unix(value.parse().expect("`set_meta` mtime invalid"))),
+            _ => panic!("`set_meta`: Unknown field {:?}", field),
+            }
+            let h = match vfs_handle::Any::open(remote)
+                {
+                Ok(h) => h,
+                Err(e) => panic!("`set_meta`: Cannot open {:?}: {:?}", remote, e),
+                };
+            match (h.set_metadata(&changes), expected_error)
+            {
+            (Ok(()), None) => {},
+            (Ok(()), Some(exp)) => panic!("`set_meta`: Updating {:?} succeeded, expected {}", remote, exp),
+            (Err(e), Some(exp)) if format!("{:?}", e) == exp => {},
+            (Err(e), _) => panic!("`set_meta`: Cannot update {:?}: {:?}", remote, e),
+            }
+            },
+        // Open a file (modes: ro, rw, exec, append), optionally checking that it fails with the given error
+        "open" => {
+            let remote: &::vfs::Path = args.next().expect("`open` remote").as_ref();
+            let mode = match args.next().expect("`open` mode")
+                {
+                "ro" => vfs_handle::FileOpenMode::SharedRO,
+                "rw" => vfs_handle::FileOpenMode::ExclRW,
+                "exec" => vfs_handle::FileOpenMode::Execute,
+                "append" => vfs_handle::FileOpenMode::Append,
+                m => panic!("`open`: Unknown mode {:?}", m),
+                };
+            let expected_error = args.next();
+            match (vfs_handle::File::open(remote, mode), expected_error)
+            {
+            (Ok(_), None) => {},
+            (Ok(_), Some(exp)) => panic!("`open`: Opening {:?} succeeded, expected {}", remote, exp),
+            (Err(e), Some(exp)) if format!("{:?}", e) == exp => {},
+            (Err(e), _) => panic!("`open`: Cannot open {:?}: {:?}", remote, e),
+            }
+            },
+        // Switch the user/group that following commands run as
+        "set_creds" => {
+            let uid: u32 = args.next().expect("`set_creds` uid").parse().expect("`set_creds` uid invalid");
+            let gid: u32 = args.next().expect("`set_creds` gid").parse().expect("`set_creds` gid invalid");
+            log_log!("COMMAND: set_creds {} {}", uid, gid);
+            ::kernel::threads::set_credentials(::kernel::threads::Credentials { uid, gid });
+            },
         "crc32" => {
             let remote: &::vfs::Path = args.next().expect("`crc32` remote").as_ref();
 
@@ -318,3 +777,13 @@ fn main()
     // TODO: Unmount all volumes
 }
 
+/// Open a file for read-write access (for the modification commands)
+fn open_rw(path: &::vfs::Path) -> vfs_handle::File
+{
+    match vfs_handle::File::open(path, vfs_handle::FileOpenMode::ExclRW)
+    {
+    Ok(h) => h,
+    Err(e) => panic!("Cannot open {:?} for writing: {:?}", path, e),
+    }
+}
+
@@ -125,6 +125,58 @@ impl ::core::ops::Drop for TcpConnection {
     }
 }
 
+/// A UDP socket bound to a local port
+pub struct Udp {
+    conn: *mut ::lwip_sys::netconn,
+}
+impl Udp {
+    /// Create a socket bound to [*]:<port> (receives don't block)
+    pub fn bind(port: u16) -> Result<Udp,crate::Error> {
+        let conn = unsafe { netconn_new_with_proto_and_callback(netconn_type_NETCONN_UDP, 0, None) };
+        if conn == ::core::ptr::null_mut() {
+            return Err(crate::Error(err_enum_t_ERR_MEM as _));
+        }
+        let rv = Udp { conn };
+        let addr = unsafe { ::core::mem::zeroed::<::lwip_sys::ip_addr>() };
+        crate::Error::check_unit(unsafe { netconn_bind(rv.conn, &addr, port) })?;
+        unsafe { (*rv.conn).flags |= NETCONN_FLAG_NON_BLOCKING as u8; }
+        Ok(rv)
+    }
+    /// Send a datagram to the given address
+    pub fn send_to(&self, addr: &ip_addr, port: u16, bytes: &[u8]) -> Result<(),crate::Error> {
+        unsafe {
+            let buf = netbuf_new();
+            if buf == ::core::ptr::null_mut() {
+                return Err(crate::Error(err_enum_t_ERR_MEM as _));
+            }
+            let buf = Netbuf(buf);
+            crate::Error::check_unit(netbuf_ref(buf.0, bytes.as_ptr() as *const _, bytes.len() as _))?;
+            crate::Error::check_unit(netconn_sendto(self.conn, buf.0, addr, port))
+        }
+    }
+    /// Receive a waiting datagram (`None` if nothing is waiting)
+    pub fn try_recv(&self) -> Result<Option<Netbuf>,crate::Error> {
+        unsafe {
+            let mut inbuf = ::core::ptr::null_mut();
+            match netconn_recv(self.conn, &mut inbuf)
+            {
+            v if v == err_enum_t_ERR_WOULDBLOCK as err_t => Ok(None),
+            v => {
+                crate::Error::check_unit(v)?;
+                Ok(Some(Netbuf(inbuf)))
+                },
+            }
+        }
+    }
+}
+impl ::core::ops::Drop for Udp {
+    fn drop(&mut self) {
+        unsafe {
+            crate::Error::check_unit(netconn_delete(self.conn)).expect("Error deleting a UDP connection");
+        }
+    }
+}
+
 /// Blob of data owned by the LWIP stack
 pub struct Netbuf(*mut ::lwip_sys::netbuf);
 impl Netbuf {
@@ -138,6 +190,15 @@ impl Netbuf {
             Ok( ::core::slice::from_raw_parts(buf_ptr, buflen as usize) )
         }
     }
+    /// Copy out all of the data (which may span several fragments)
+    pub fn to_vec(&self) -> Vec<u8> {
+        unsafe {
+            let p = (*self.0).p;
+            let mut rv = vec![0; (*p).tot_len as usize];
+            ::lwip_sys::pbuf_copy_partial(p, rv.as_mut_ptr() as *mut _, (*p).tot_len, 0);
+            rv
+        }
+    }
 }
 impl ::core::ops::Drop for Netbuf {
     fn drop(&mut self) {
@@ -30,12 +30,19 @@ pub fn init() {
 }
 
 pub fn create_interface(stream: Arc<::std::net::UdpSocket>, number: u32, mac: [u8; 6], addr: IpAddr) -> &'static mut ::network::nic::Registration<TestNic> {
-    let nic_handle = network::nic::register(mac, TestNic::new(number, stream));
 	// TODO: Make this a command instead
-    network::ipv4::add_interface(mac, addr, 24);
+    // - The address is added before registering, so DHCP isn't started (a zero address leaves it to DHCP)
+    if !addr.is_zero() {
+        network::ipv4::add_interface(mac, addr, 24);
+    }
+    let nic_handle = network::nic::register(mac, TestNic::new(number, stream));
     Box::leak( Box::new(nic_handle) )
 }
 
+pub fn ipv4_route_add(network: IpAddr, mask: u8, next_hop: IpAddr) {
+    ::network::ipv4::add_route(::network::ipv4::Route { network, mask, next_hop }).unwrap();
+}
+
 pub fn spawn_thread(f: impl FnOnce() + Send + 'static) {
     let h = ::kernel::threads::WorkerThread::new("Worker", f);
     ::core::mem::forget(h);
@@ -51,6 +58,20 @@ pub fn tcp_listen(port: u16) -> ::network::tcp::ServerHandle {
     ::network::tcp::ServerHandle::listen(port).unwrap()
 }
 
+pub fn udp_bind(port: u16) -> ::network::udp::SocketHandle {
+    let any = ::network::udp::RemoteFilter::any(::network::Address::Ipv4(IpAddr::zero()));
+    ::network::udp::SocketHandle::bind(None, port, any).unwrap()
+}
+pub fn udp_send(h: &::network::udp::SocketHandle, ip: IpAddr, port: u16, data: &[u8]) {
+    h.send_to(::network::Address::Ipv4(ip), port, data).unwrap();
+}
+pub fn udp_recv(h: &::network::udp::SocketHandle) -> Option<Vec<u8>> {
+    let mut buf = vec![0; 0x10000];
+    let (len, _addr, _port) = h.recv_from(&mut buf).expect("Unexpected ICMP error")?;
+    buf.truncate(len);
+    Some(buf)
+}
+
 
 pub struct TestNic
 {
@@ -1,5 +1,6 @@
 
 use ::std::sync::Arc;
+use ::std::sync::atomic::{AtomicPtr,Ordering};
 
 mod nic;
 mod client_socket;
@@ -34,8 +35,24 @@ pub fn init()
     b.wait();
 }
 
+/// Interface that receives the default route
+static DEFAULT_NETIF: AtomicPtr<::lwip::sys::netif> = AtomicPtr::new(::core::ptr::null_mut());
+
 pub fn create_interface(stream: Arc<::std::net::UdpSocket>, number: u32, mac: [u8; 6], addr: IpAddr) -> &'static TestNicHandle {
-    TestNicHandle::new( number, stream, mac, addr, 24 )
+    let rv = TestNicHandle::new( number, stream, mac, addr, 24 );
+    let _ = DEFAULT_NETIF.compare_exchange(::core::ptr::null_mut(), rv.netif_ptr(), Ordering::SeqCst, Ordering::SeqCst);
+    rv
+}
+
+/// lwip only has a gateway per interface, so only the default route (set as the first interface's gateway) is supported
+pub fn ipv4_route_add(network: IpAddr, mask: u8, next_hop: IpAddr) {
+    if mask != 0 {
+        println!("ipv4_route_add({:?}/{}) - Only default routes are supported by lwip, ignored", network.addr.to_le_bytes(), mask);
+        return ;
+    }
+    let netif = DEFAULT_NETIF.load(Ordering::SeqCst);
+    assert!(netif != ::core::ptr::null_mut(), "ipv4_route_add - No interface");
+    ::lwip::os_mode::callback(move || unsafe { ::lwip::sys::netif_set_gw(netif, &next_hop) });
 }
 
 pub fn spawn_thread(f: impl FnOnce() + Send + 'static) {
@@ -53,6 +70,23 @@ pub fn tcp_listen(port: u16) -> Server {
 }
 
 
+pub struct UdpSocket(::lwip::netconn::Udp);
+pub fn udp_bind(port: u16) -> UdpSocket {
+    UdpSocket( ::lwip::netconn::Udp::bind(port).unwrap() )
+}
+pub fn udp_send(h: &UdpSocket, ip: IpAddr, port: u16, data: &[u8]) {
+    let ip = ::lwip::sys::ip_addr {
+        type_: ::lwip::sys::lwip_ip_addr_type_IPADDR_TYPE_V4 as u8,
+        u_addr: ::lwip::sys::ip_addr__bindgen_ty_1 {
+            ip4: ip,
+        }
+    };
+    h.0.send_to(&ip, port, data).unwrap();
+}
+pub fn udp_recv(h: &UdpSocket) -> Option<Vec<u8>> {
+    Some( h.0.try_recv().unwrap()?.to_vec() )
+}
+
 pub struct Server(::lwip::netconn::TcpServer);
 impl Server
 {
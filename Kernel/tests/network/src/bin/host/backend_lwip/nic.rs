@@ -37,6 +37,10 @@ impl TestNicHandle
         Box::leak(rv)
     }
 
+    pub(super) fn netif_ptr(&self) -> *mut ::lwip::sys::netif {
+        self.netif.get()
+    }
+
     unsafe extern "C" fn init(netif_r: *mut ::lwip::sys::netif) -> ::lwip::sys::err_t {
         let netif = &mut *netif_r;
         let this = &*(netif.state as *const TestNicHandle);
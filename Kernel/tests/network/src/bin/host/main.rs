@@ -108,6 +108,7 @@ fn main()
 	// Monitor stdin for commands
 	let mut tcp_conn_handles = ::std::collections::HashMap::new();
 	let mut tcp_server_handles = ::std::collections::HashMap::new();
+	let mut udp_handles = ::std::collections::HashMap::new();
 	
     loop
     {
@@ -131,6 +132,14 @@ fn main()
 			},
 		"ipv4-add" => {
 			},
+		"ipv4-route-add" => {
+			let network = backend::parse_addr(it.next().expect("Missing network")).unwrap();
+			let mask: u8 = it.next().unwrap().parse().unwrap();
+			let next_hop = backend::parse_addr(it.next().expect("Missing next hop")).unwrap();
+			log_notice!("ipv4-route-add {:?}/{} via {:?}", network, mask, next_hop);
+			backend::ipv4_route_add(network, mask, next_hop);
+			println!("OK");
+			},
 		// Listen on a port/interface
 		"tcp-listen" => {
 			let index: usize = it.next().unwrap().parse().unwrap();
@@ -160,7 +169,10 @@ fn main()
 		// Close a TCP connection
 		"tcp-close" => {
 			let index: usize = it.next().unwrap().parse().unwrap();
-			todo!("tcp-close {}", index);
+			log_notice!("tcp-close {}", index);
+			// Dropping the handle closes the connection
+			drop(tcp_conn_handles.remove(&index).expect("BUG: Bad connection index"));
+			println!("OK");
 			},
 		"tcp-send" => {
 			let index: usize = it.next().unwrap().parse().unwrap();
@@ -184,6 +196,55 @@ fn main()
 			assert_eq!(&buf[..len], &exp_bytes[..]);
 			println!("OK");
 			},
+		// Bind a UDP socket to a local port
+		"udp-bind" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			let port : u16   = it.next().unwrap().parse().unwrap();
+			log_notice!("udp-bind {} = *:{}", index, port);
+			udp_handles.insert(index, backend::udp_bind(port));
+			println!("OK");
+			},
+		"udp-send" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			let ip = backend::parse_addr(it.next().expect("Missing IP")).unwrap();
+			let port: u16 = it.next().unwrap().parse().unwrap();
+			let bytes = parse_hex_bytes(it.next().unwrap()).unwrap();
+			log_notice!("udp-send {} {:?}:{} {:?}", index, ip, port, bytes);
+			let h = &udp_handles[&index];
+			backend::udp_send(h, ip, port, &bytes);
+			println!("OK");
+			},
+		// Send a datagram filled with an incrementing byte pattern (for datagrams too large for a command)
+		"udp-send-pattern" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			let ip = backend::parse_addr(it.next().expect("Missing IP")).unwrap();
+			let port: u16 = it.next().unwrap().parse().unwrap();
+			let len: usize = it.next().unwrap().parse().unwrap();
+			log_notice!("udp-send-pattern {} {:?}:{} {}", index, ip, port, len);
+			let bytes: Vec<u8> = (0 .. len).map(|i| i as u8).collect();
+			let h = &udp_handles[&index];
+			backend::udp_send(h, ip, port, &bytes);
+			println!("OK");
+			},
+		"udp-recv-assert" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			let exp_bytes = parse_hex_bytes(it.next().unwrap()).unwrap();
+			// NOTE: No wait
+			log_notice!("udp-recv-assert {} == {:?}", index, exp_bytes);
+			let h = &udp_handles[&index];
+			let data = backend::udp_recv(h).expect("No datagram waiting");
+			assert_eq!(&data[..], &exp_bytes[..]);
+			println!("OK");
+			},
+		"udp-recv-assert-none" => {
+			let index: usize = it.next().unwrap().parse().unwrap();
+			log_notice!("udp-recv-assert-none {}", index);
+			let h = &udp_handles[&index];
+			if let Some(data) = backend::udp_recv(h) {
+				panic!("Unexpected datagram: {:?}", data);
+			}
+			println!("OK");
+			},
 		_ => panic!("ERROR: Unknown command '{}'", cmd),
 		}
     }
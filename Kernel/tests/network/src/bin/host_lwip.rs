@@ -175,7 +175,10 @@ pub fn main()
         // Close a TCP connection
         "tcp-close" => {
             let index: usize = it.next().unwrap().parse().unwrap();
-            todo!("tcp-close {}", index);
+            println!("tcp-close {}", index);
+            // Dropping the handle closes the connection
+            drop(tcp_conn_handles.remove(&index).expect("BUG: Bad connection index"));
+            println!("OK");
             },
         "tcp-send" => {
             let index: usize = it.next().unwrap().parse().unwrap();
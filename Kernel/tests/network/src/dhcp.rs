@@ -0,0 +1,172 @@
+// "Tifflin" Kernel Tests (network)
+// - By John Hodge (Mutabah)
+//
+// tests/network/dhcp.rs
+//! DHCP tests and infrastructure (a minimal server stand-in)
+use crate::ipv4::Addr as IpAddr4;
+
+#[cfg(test)]
+mod tests;
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+pub const MSG_DISCOVER: u8 = 1;
+pub const MSG_OFFER: u8 = 2;
+pub const MSG_REQUEST: u8 = 3;
+pub const MSG_ACK: u8 = 5;
+pub const MSG_NAK: u8 = 6;
+
+pub const OPT_SUBNET_MASK: u8 = 1;
+pub const OPT_ROUTER: u8 = 3;
+pub const OPT_DNS_SERVERS: u8 = 6;
+pub const OPT_REQUESTED_ADDRESS: u8 = 50;
+pub const OPT_LEASE_TIME: u8 = 51;
+pub const OPT_MESSAGE_TYPE: u8 = 53;
+pub const OPT_SERVER_ID: u8 = 54;
+pub const OPT_RENEWAL_TIME: u8 = 58;
+pub const OPT_REBINDING_TIME: u8 = 59;
+
+/// A BOOTP/DHCP message (only the fields used by the tests)
+#[derive(Debug)]
+pub struct Message
+{
+    pub op: u8,
+    pub xid: u32,
+    pub flags: u16,
+    pub ciaddr: IpAddr4,
+    pub yiaddr: IpAddr4,
+    pub chaddr: [u8; 6],
+    pub options: Vec<(u8, Vec<u8>)>,
+}
+impl Message
+{
+    pub fn parse(buf: &[u8]) -> Self
+    {
+        assert!(buf.len() >= 240, "DHCP message too short ({} bytes)", buf.len());
+        assert_eq!(buf[236..240], MAGIC_COOKIE, "Bad DHCP magic cookie");
+        let mut options = Vec::new();
+        let mut opts = &buf[240..];
+        while let Some(&code) = opts.first()
+        {
+            match code
+            {
+            0 => { opts = &opts[1..]; continue },
+            255 => break,
+            _ => {},
+            }
+            let len = opts[1] as usize;
+            options.push( (code, opts[2..][..len].to_owned()) );
+            opts = &opts[2 + len..];
+        }
+        let rv = Message {
+            op: buf[0],
+            xid: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            flags: u16::from_be_bytes([buf[10], buf[11]]),
+            ciaddr: IpAddr4([buf[12], buf[13], buf[14], buf[15]]),
+            yiaddr: IpAddr4([buf[16], buf[17], buf[18], buf[19]]),
+            chaddr: [buf[28], buf[29], buf[30], buf[31], buf[32], buf[33]],
+            options,
+            };
+        println!("Message: {:?}", rv);
+        rv
+    }
+    pub fn encode(&self) -> Vec<u8>
+    {
+        let mut rv = vec![0; 240];
+        rv[0] = self.op;
+        rv[1] = 1;
+        rv[2] = 6;
+        rv[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        rv[10..12].copy_from_slice(&self.flags.to_be_bytes());
+        rv[12..16].copy_from_slice(&self.ciaddr.0);
+        rv[16..20].copy_from_slice(&self.yiaddr.0);
+        rv[28..34].copy_from_slice(&self.chaddr);
+        rv[236..240].copy_from_slice(&MAGIC_COOKIE);
+        for (code, value) in &self.options
+        {
+            rv.push(*code);
+            rv.push(value.len() as u8);
+            rv.extend_from_slice(value);
+        }
+        rv.push(255);
+        rv
+    }
+    pub fn get_option(&self, code: u8) -> Option<&[u8]>
+    {
+        self.options.iter().find(|(c,_)| *c == code).map(|(_,v)| &v[..])
+    }
+    pub fn message_type(&self) -> Option<u8>
+    {
+        self.get_option(OPT_MESSAGE_TYPE).map(|v| v[0])
+    }
+
+    /// Build a reply to this message
+    pub fn reply(&self, msg_type: u8, yiaddr: IpAddr4, server: IpAddr4, options: &[(u8, &[u8])]) -> Message
+    {
+        let mut o = vec![ (OPT_MESSAGE_TYPE, vec![msg_type]), (OPT_SERVER_ID, server.0.to_vec()) ];
+        o.extend( options.iter().map(|(c,v)| (*c, v.to_vec())) );
+        Message {
+            op: 2,
+            xid: self.xid,
+            flags: self.flags,
+            ciaddr: self.ciaddr,
+            yiaddr,
+            chaddr: self.chaddr,
+            options: o,
+        }
+    }
+}
+
+/// Wait for a broadcast message from the client, checking the addressing and message type
+#[track_caller]
+pub fn wait_client_message(fw: &crate::TestFramework, msg_type: u8) -> Message
+{
+    wait_client_message_to(fw, msg_type, None)
+}
+/// Wait for a message unicast from the client's address to the server (i.e. a renewal)
+#[track_caller]
+pub fn wait_client_unicast(fw: &crate::TestFramework, msg_type: u8, client: IpAddr4, server: IpAddr4) -> Message
+{
+    wait_client_message_to(fw, msg_type, Some((client, server)))
+}
+#[track_caller]
+fn wait_client_message_to(fw: &crate::TestFramework, msg_type: u8, unicast: Option<(IpAddr4, IpAddr4)>) -> Message
+{
+    let data_handle = match fw.wait_packet(std::time::Duration::from_millis(5000))
+        {
+        Some(v) => v,
+        None => panic!("No packet received"),
+        };
+    let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(&data_handle);
+    assert_eq!(ether_hdr.proto, 0x0800, "Incorrect ethernet protocol value: {:04x}", ether_hdr.proto);
+    let (ip_hdr, _ip_options, tail) = crate::ipv4::Header::parse(tail);
+    assert_eq!(ip_hdr.protocol, 17);
+    match unicast
+    {
+    None => {
+        assert_eq!(ether_hdr.dst, [0xFF; 6], "Not an ethernet broadcast");
+        assert_eq!(ip_hdr.dst_addr, [255; 4], "Not sent to the limited broadcast address");
+        },
+    Some((client, server)) => {
+        assert_ne!(ether_hdr.dst, [0xFF; 6], "Ethernet broadcast, expected unicast");
+        assert_eq!(IpAddr4(ip_hdr.src_addr), client, "Not sent from the client's address");
+        assert_eq!(IpAddr4(ip_hdr.dst_addr), server, "Not sent to the server");
+        },
+    }
+    let (udp_hdr, tail) = crate::udp::Header::parse(tail);
+    assert_eq!(udp_hdr.src_port, CLIENT_PORT);
+    assert_eq!(udp_hdr.dst_port, SERVER_PORT);
+    let rv = Message::parse(tail);
+    assert_eq!(rv.op, 1, "Not a BOOTREQUEST");
+    assert_eq!(rv.chaddr, ether_hdr.src, "Client hardware address doesn't match the sender");
+    assert_eq!(rv.message_type(), Some(msg_type), "Unexpected DHCP message type");
+    rv
+}
+
+/// Send a message from the server to the client (to the limited broadcast address)
+pub fn send_reply(fw: &crate::TestFramework, server: IpAddr4, msg: &Message)
+{
+    crate::udp::send_packet(fw, (server, SERVER_PORT), (IpAddr4([255; 4]), CLIENT_PORT), &msg.encode());
+}
@@ -0,0 +1,134 @@
+//! DHCP client tests
+use super::*;
+
+const SERVER_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+const OFFERED_ADDR: IpAddr4 = IpAddr4([192,168,1,50]);
+
+/// Create a framework with the server (also the router) answering ARP
+fn new_framework(name: &str) -> crate::TestFramework
+{
+    let mut fw = crate::TestFramework::new_dhcp(name);
+    fw.add_handler(crate::arp::ArpHandler::new(SERVER_ADDR));
+    fw
+}
+
+/// Run the DISCOVER/OFFER/REQUEST/ACK exchange, granting `OFFERED_ADDR` with the given options
+fn grant_lease(fw: &crate::TestFramework, options: &[(u8, &[u8])])
+{
+    let discover = wait_client_message(fw, MSG_DISCOVER);
+    assert_eq!(discover.ciaddr, IpAddr4([0; 4]));
+    send_reply(fw, SERVER_ADDR, &discover.reply(MSG_OFFER, OFFERED_ADDR, SERVER_ADDR, options));
+
+    let request = wait_client_message(fw, MSG_REQUEST);
+    assert_eq!(request.xid, discover.xid, "Transaction ID changed");
+    assert_eq!(request.get_option(OPT_REQUESTED_ADDRESS), Some(&OFFERED_ADDR.0[..]), "Requested address mismatch");
+    assert_eq!(request.get_option(OPT_SERVER_ID), Some(&SERVER_ADDR.0[..]), "Server identifier mismatch");
+    send_reply(fw, SERVER_ADDR, &request.reply(MSG_ACK, OFFERED_ADDR, SERVER_ADDR, options));
+}
+
+/// Send echo requests to the leased address, returning true if one is answered
+///
+/// The address is configured asynchronously, so a few attempts are made
+fn ping_leased_address(fw: &crate::TestFramework) -> bool
+{
+    for seq in 0 .. 5u16
+    {
+        let rest = [0x12,0x34, (seq >> 8) as u8, seq as u8];
+        crate::icmp::send_packet(fw, SERVER_ADDR, OFFERED_ADDR, 8, 0, rest, b"ping");
+        if let Some(p) = fw.wait_packet(std::time::Duration::from_millis(200))
+        {
+            let (_, tail) = crate::ethernet::EthernetHeader::parse(&p);
+            let (ip_hdr, _, tail) = crate::ipv4::Header::parse(tail);
+            assert_eq!(IpAddr4(ip_hdr.src_addr), OFFERED_ADDR, "Echo reply from the wrong address");
+            let (hdr, _) = crate::icmp::Header::parse(tail);
+            assert_eq!( (hdr.ty, hdr.code), (0, 0), "Not an echo reply");
+            return true;
+        }
+    }
+    false
+}
+
+/// The client obtains a lease, configures the address, and uses the router as the default route
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn obtain_lease()
+{
+    const DEST_ADDR: IpAddr4 = IpAddr4([10,1,2,3]);
+    let fw = new_framework("dhcp_obtain_lease");
+
+    let options: &[(u8, &[u8])] = &[
+        (OPT_SUBNET_MASK, &[255,255,255,0]),
+        (OPT_ROUTER, &SERVER_ADDR.0),
+        (OPT_DNS_SERVERS, &[192,168,1,2, 192,168,1,3]),
+        (OPT_LEASE_TIME, &3600u32.to_be_bytes()),
+        ];
+    grant_lease(&fw, options);
+    assert!(ping_leased_address(&fw), "No echo reply from the leased address");
+
+    // Off-link traffic goes via the router
+    fw.send_command("udp-bind 0 53");
+    let testblob = b"Routed";
+    fw.send_command( &format!("udp-send 0 {} 1234 {}", DEST_ADDR, crate::HexString(testblob)) );
+    crate::udp::wait_rx_check(&fw, (OFFERED_ADDR, 53), (DEST_ADDR, 1234), testblob);
+}
+
+/// At T1 the lease is renewed with the granting server (unicast), if that's unanswered the client rebinds
+/// (broadcast) at T2
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn renew_lease()
+{
+    let fw = new_framework("dhcp_renew_lease");
+    let options: &[(u8, &[u8])] = &[
+        (OPT_SUBNET_MASK, &[255,255,255,0]),
+        (OPT_LEASE_TIME, &10u32.to_be_bytes()),
+        (OPT_RENEWAL_TIME, &1u32.to_be_bytes()),
+        (OPT_REBINDING_TIME, &3u32.to_be_bytes()),
+        ];
+    grant_lease(&fw, options);
+    assert!(ping_leased_address(&fw), "No echo reply from the leased address");
+
+    // RENEWING: Sent from the leased address to the server, without the requested address/server options
+    let renew = wait_client_unicast(&fw, MSG_REQUEST, OFFERED_ADDR, SERVER_ADDR);
+    assert_eq!(renew.ciaddr, OFFERED_ADDR, "Renewal doesn't include the current address");
+    assert_eq!(renew.get_option(OPT_REQUESTED_ADDRESS), None, "Renewal includes a requested address");
+    assert_eq!(renew.get_option(OPT_SERVER_ID), None, "Renewal includes a server identifier");
+    send_reply(&fw, SERVER_ADDR, &renew.reply(MSG_ACK, OFFERED_ADDR, SERVER_ADDR, options));
+
+    // The renewed lease has the same timers, leave this renewal unanswered
+    let renew2 = wait_client_unicast(&fw, MSG_REQUEST, OFFERED_ADDR, SERVER_ADDR);
+    assert_ne!(renew2.xid, renew.xid, "Renewal reused a transaction ID");
+
+    // REBINDING: Broadcast (to any server) once T2 passes
+    let rebind = wait_client_message(&fw, MSG_REQUEST);
+    assert_eq!(rebind.ciaddr, OFFERED_ADDR, "Rebinding doesn't include the current address");
+    assert_eq!(rebind.get_option(OPT_REQUESTED_ADDRESS), None, "Rebinding includes a requested address");
+    send_reply(&fw, SERVER_ADDR, &rebind.reply(MSG_ACK, OFFERED_ADDR, SERVER_ADDR, options));
+
+    // The address stays configured throughout
+    assert!(ping_leased_address(&fw), "Leased address lost after rebinding");
+}
+
+/// A DHCPNAK in reply to a renewal removes the address and restarts discovery
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn renewal_refused()
+{
+    let fw = new_framework("dhcp_renewal_refused");
+    let options: &[(u8, &[u8])] = &[
+        (OPT_SUBNET_MASK, &[255,255,255,0]),
+        (OPT_LEASE_TIME, &10u32.to_be_bytes()),
+        (OPT_RENEWAL_TIME, &1u32.to_be_bytes()),
+        ];
+    grant_lease(&fw, options);
+    assert!(ping_leased_address(&fw), "No echo reply from the leased address");
+
+    let renew = wait_client_unicast(&fw, MSG_REQUEST, OFFERED_ADDR, SERVER_ADDR);
+    send_reply(&fw, SERVER_ADDR, &renew.reply(MSG_NAK, IpAddr4([0; 4]), SERVER_ADDR, &[]));
+
+    // Back to INIT, with no address
+    let discover = wait_client_message(&fw, MSG_DISCOVER);
+    assert_eq!(discover.ciaddr, IpAddr4([0; 4]), "Discovery after NAK still uses the old address");
+    assert_ne!(discover.xid, renew.xid, "Discovery reused the renewal's transaction ID");
+    assert!(!ping_leased_address(&fw), "Address still configured after NAK");
+}
@@ -0,0 +1,88 @@
+// "Tifflin" Kernel Tests (network)
+// - By John Hodge (Mutabah)
+//
+// tests/network/icmp.rs
+//! ICMP tests and infrastructure
+use crate::ipv4::Addr as IpAddr4;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Copy,Clone)]
+#[derive(Debug)]
+#[derive(serde_derive::Deserialize,serde_derive::Serialize)]
+pub struct Header
+{
+    pub ty: u8,
+    pub code: u8,
+    pub checksum: u16,
+    pub rest: [u8; 4],
+}
+impl Header
+{
+    /// Parse an ICMP header, checking the checksum and returning the data
+    pub fn parse(mut buf: &[u8]) -> (Self, &[u8]) {
+        assert_eq!(calculate_checksum(&[buf]), 0, "ICMP checksum mismatch");
+        let rv: Self = crate::des_be(&mut buf).expect("Failed to parse ICMP header");
+        println!("Header: {:?}", rv);
+        (rv, buf)
+    }
+    fn encode(&self) -> [u8; 8]
+    {
+        let mut rv = [0; 8];
+        {
+            let mut c = std::io::Cursor::new(&mut rv[..]);
+            crate::ser_be(&mut c, self);
+            assert_eq!(c.position(), 8, "Encoding of ICMP header failed?");
+        }
+        rv
+    }
+}
+
+/// Calculate the checksum over a set of buffers (each buffer must be an even length, except the last)
+fn calculate_checksum(bufs: &[&[u8]]) -> u16
+{
+    let it = bufs.iter().flat_map(|b| b.iter().copied());
+    let words = it.clone().step_by(2).zip(it.skip(1).step_by(2).map(Some).chain(std::iter::once(None)))
+        .map(|(a,b)| (a as u16) << 8 | b.unwrap_or(0) as u16);
+    crate::ipv4::calculate_ip_checksum(words)
+}
+
+pub fn send_packet(fw: &crate::TestFramework, src: IpAddr4, dst: IpAddr4, ty: u8, code: u8, rest: [u8; 4], data: &[u8])
+{
+    let mut header = Header { ty, code, checksum: 0, rest };
+    header.checksum = calculate_checksum(&[&header.encode(), data]);
+    let icmp_hdr = header.encode();
+    let ip_hdr = {
+        let mut h = crate::ipv4::Header::new_simple(src, dst, 1, icmp_hdr.len() + data.len());
+        h.set_checksum();
+        h.encode()
+        };
+    fw.send_ethernet_direct(0x0800, &[&ip_hdr, &icmp_hdr, data]);
+}
+
+/// Wait for an ICMP packet and check the addresses, type, and code (returning the rest of the header, and the data)
+#[track_caller]
+pub fn wait_rx_check(fw: &crate::TestFramework, src: IpAddr4, dst: IpAddr4, ty: u8, code: u8) -> ([u8; 4], Vec<u8>)
+{
+    let data_handle = match fw.wait_packet(std::time::Duration::from_millis(1000))
+        {
+        Some(v) => v,
+        None => panic!("No packet received"),
+        };
+    let tail = &data_handle[..];
+    // 1. Check the ethernet header
+    let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(tail);
+    assert_eq!(ether_hdr.proto, 0x0800, "Incorrect ethernet protocol value: {:04x}", ether_hdr.proto);
+    // 2. Check the IPv4 header
+    let (ip_hdr, _ip_options, tail) = crate::ipv4::Header::parse(tail);
+    assert_eq!(ip_hdr.protocol, 1);
+    assert_eq!(crate::ipv4::Addr(ip_hdr.src_addr), src);
+    assert_eq!(crate::ipv4::Addr(ip_hdr.dst_addr), dst);
+    let tail = &tail[..ip_hdr.total_length as usize - (ip_hdr.version_and_len & 0xF) as usize * 4];
+    // 3. Check the ICMP header
+    let (icmp_hdr, tail) = Header::parse(tail);
+    assert_eq!(icmp_hdr.ty, ty, "ICMP type mismatch");
+    assert_eq!(icmp_hdr.code, code, "ICMP code mismatch");
+    (icmp_hdr.rest, tail.to_owned())
+}
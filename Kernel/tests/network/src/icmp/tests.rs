@@ -0,0 +1,51 @@
+//! ICMP tests
+use crate::ipv4::Addr as IpAddr4;
+use super::*;
+
+const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+/// Echo requests are answered with the same identifier, sequence number, and data
+#[test]
+fn echo_request()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("icmp_echo_request");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+
+    // Odd length, to check checksum padding
+    let testblob = b"abcdefghijklmnopqrstuvwxyz0";
+    send_packet(&fw, LOCAL_ADDR, REMOTE_ADDR, 8, 0, [0x12,0x34, 0x00,0x01], testblob);
+    let (rest, data) = wait_rx_check(&fw, REMOTE_ADDR, LOCAL_ADDR, 0, 0);
+    assert_eq!(rest, [0x12,0x34, 0x00,0x01], "Identifier/sequence mismatch");
+    assert_eq!(&data[..], &testblob[..], "Echo data mismatch");
+}
+
+/// A packet with an unknown protocol gets a "protocol unreachable"
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn protocol_unreachable()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("icmp_protocol_unreachable");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+
+    let data = b"\x01\x02\x03\x04\x05\x06\x07\x08";
+    let ip_hdr = {
+        let mut h = crate::ipv4::Header::new_simple(LOCAL_ADDR, REMOTE_ADDR, 253, data.len());
+        h.set_checksum();
+        h.encode()
+        };
+    fw.send_ethernet_direct(0x0800, &[&ip_hdr, data]);
+    let (_rest, body) = wait_rx_check(&fw, REMOTE_ADDR, LOCAL_ADDR, 3, 2);
+    // Body contains the original header and the first 8 bytes of data
+    let (orig_hdr, _, orig_data) = crate::ipv4::Header::parse(&body);
+    assert_eq!(orig_hdr.protocol, 253);
+    assert_eq!(orig_hdr.src_addr, LOCAL_ADDR.0);
+    assert_eq!(orig_hdr.dst_addr, REMOTE_ADDR.0);
+    assert_eq!(orig_data, data);
+}
@@ -2,6 +2,9 @@
 use std::io::Cursor;
 use std::mem::size_of;
 
+#[cfg(test)]
+mod tests;
+
 #[derive(Copy,Clone,PartialEq)]
 pub struct Addr(pub [u8; 4]);
 impl ::core::fmt::Debug for Addr {
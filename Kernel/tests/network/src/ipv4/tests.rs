@@ -0,0 +1,122 @@
+//! IPv4 tests
+use super::*;
+
+const REMOTE_ADDR: Addr = Addr([192,168,1,1]);
+const LOCAL_ADDR: Addr = Addr([192,168,1,2]);
+
+/// Send a single fragment of a datagram
+fn send_fragment(fw: &crate::TestFramework, identification: u16, ofs: usize, more_fragments: bool, data: &[u8])
+{
+    assert!(ofs % 8 == 0);
+    let mut h = Header::new_simple(LOCAL_ADDR, REMOTE_ADDR, 17, data.len());
+    h.identification = identification;
+    h.fragment_info = (if more_fragments { 1 << 13 } else { 0 }) | (ofs / 8) as u16;
+    h.set_checksum();
+    fw.send_ethernet_direct(0x0800, &[&h.encode(), data]);
+}
+
+/// Build a UDP datagram (header and data) from the framework to the testee
+fn make_udp(src_port: u16, dst_port: u16, data: &[u8]) -> Vec<u8>
+{
+    let mut header = crate::udp::Header {
+        src_port,
+        dst_port,
+        length: (8 + data.len()) as u16,
+        checksum: 0,
+        };
+    header.checksum = header.calculate_checksum_v4(LOCAL_ADDR, REMOTE_ADDR, data);
+    let mut rv = Vec::new();
+    crate::ser_be(&mut rv, &header);
+    rv.extend_from_slice(data);
+    rv
+}
+
+/// A fragmented datagram (received out of order) is reassembled and delivered
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn rx_reassembly()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv4_rx_reassembly");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    fw.send_command("udp-bind 0 53");
+
+    let testblob: Vec<u8> = (0 .. 100u8).collect();
+    let dgram = make_udp(1234, 53, &testblob);
+    // Last fragment first, then the middle, then the head (overlapping the middle)
+    send_fragment(&fw, 0x1234, 64, false, &dgram[64..]);
+    send_fragment(&fw, 0x1234, 32, true, &dgram[32..64]);
+    fw.send_command("udp-recv-assert-none 0");
+    send_fragment(&fw, 0x1234, 0, true, &dgram[..40]);
+    fw.send_command( &format!("udp-recv-assert 0 {}", crate::HexString(&testblob)) );
+}
+
+/// A datagram larger than the MTU is sent as multiple fragments
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn tx_fragmentation()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv4_tx_fragmentation");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    fw.send_command("udp-bind 0 53");
+
+    const LEN: usize = 2000;
+    fw.send_command( &format!("udp-send-pattern 0 {} 1234 {}", LOCAL_ADDR, LEN) );
+
+    let mut dgram = Vec::new();
+    let mut identification = None;
+    loop
+    {
+        let pkt = fw.wait_packet(std::time::Duration::from_millis(1000)).expect("No packet received");
+        let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(&pkt);
+        assert_eq!(ether_hdr.proto, 0x0800);
+        let (ip_hdr, _options, tail) = Header::parse(tail);
+        assert_eq!(ip_hdr.protocol, 17);
+        assert_eq!(ip_hdr.calculate_checksum(), 0, "Bad header checksum");
+        assert!(ip_hdr.total_length as usize <= 1500, "Fragment exceeds the MTU");
+        if let Some(id) = identification {
+            assert_eq!(ip_hdr.identification, id, "Identification changed between fragments");
+        }
+        identification = Some(ip_hdr.identification);
+
+        let data = &tail[..ip_hdr.total_length as usize - 20];
+        let ofs = (ip_hdr.fragment_info & 0x1FFF) as usize * 8;
+        assert_eq!(ofs, dgram.len(), "Fragments out of order");
+        dgram.extend_from_slice(data);
+        if ip_hdr.fragment_info & (1 << 13) == 0 {
+            break;
+        }
+        assert!(data.len() % 8 == 0, "Non-final fragment isn't a multiple of 8 bytes");
+    }
+    assert_eq!(dgram.len(), 8 + LEN);
+    let (udp_hdr, data) = crate::udp::Header::parse(&dgram);
+    assert_eq!(udp_hdr.dst_port, 1234);
+    assert!(data.iter().enumerate().all(|(i,&b)| b == i as u8), "Data mismatch");
+}
+
+/// Off-link destinations are sent via the longest-prefix matching route's next hop
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn route_next_hop()
+{
+    const ROUTER_ADDR: Addr = Addr([192,168,1,253]);
+    const DEST_ADDR: Addr = Addr([10,1,2,3]);
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv4_route_next_hop");
+        // Only the more specific route's router responds to ARP
+        fw.add_handler(crate::arp::ArpHandler::new(ROUTER_ADDR));
+        fw
+        };
+    fw.send_command("ipv4-route-add 0.0.0.0 0 192.168.1.254");
+    fw.send_command( &format!("ipv4-route-add 10.1.0.0 16 {}", ROUTER_ADDR) );
+    fw.send_command("udp-bind 0 53");
+
+    let testblob = b"Routed";
+    fw.send_command( &format!("udp-send 0 {} 1234 {}", DEST_ADDR, crate::HexString(testblob)) );
+    crate::udp::wait_rx_check(&fw, (REMOTE_ADDR, 53), (DEST_ADDR, 1234), testblob);
+}
@@ -0,0 +1,191 @@
+// "Tifflin" Kernel Tests (network)
+// - By John Hodge (Mutabah)
+//
+// tests/network/ipv6.rs
+//! IPv6 tests and infrastructure (including ICMPv6 and neighbour discovery)
+
+#[cfg(test)]
+mod tests;
+
+pub const NEXT_ICMPV6: u8 = 58;
+pub const NEXT_UDP: u8 = 17;
+
+pub const ICMP_ECHO_REQUEST: u8 = 128;
+pub const ICMP_ECHO_REPLY: u8 = 129;
+pub const ICMP_NEIGHBOR_SOLICITATION: u8 = 135;
+pub const ICMP_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+#[derive(Copy,Clone,PartialEq,Eq)]
+pub struct Addr(pub [u8; 16]);
+impl ::std::fmt::Display for Addr
+{
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        ::std::net::Ipv6Addr::from(self.0).fmt(f)
+    }
+}
+impl ::std::fmt::Debug for Addr
+{
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        ::std::fmt::Display::fmt(self, f)
+    }
+}
+impl Addr
+{
+    /// Link-local address derived from a MAC address (modified EUI-64)
+    pub fn link_local_from_mac(mac: [u8; 6]) -> Addr {
+        Addr([
+            0xfe,0x80, 0,0, 0,0, 0,0,
+            mac[0] ^ 0x02, mac[1], mac[2], 0xFF, 0xFE, mac[3], mac[4], mac[5],
+            ])
+    }
+    /// Solicited-node multicast address for this address
+    pub fn solicited_node(&self) -> Addr {
+        Addr([0xff,0x02, 0,0, 0,0, 0,0, 0,0, 0,1, 0xff, self.0[13], self.0[14], self.0[15]])
+    }
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] == 0xff
+    }
+    /// Ethernet address used for a multicast address
+    pub fn multicast_mac(&self) -> [u8; 6] {
+        [0x33, 0x33, self.0[12], self.0[13], self.0[14], self.0[15]]
+    }
+}
+
+pub struct Header
+{
+    pub payload_length: u16,
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub src_addr: Addr,
+    pub dst_addr: Addr,
+}
+impl Header
+{
+    pub fn parse(buf: &[u8]) -> (Self, &[u8])
+    {
+        assert!(buf.len() >= 40, "IPv6 packet too short ({} bytes)", buf.len());
+        assert_eq!(buf[0] >> 4, 6, "Not an IPv6 packet");
+        let mut src = [0; 16]; src.copy_from_slice(&buf[8..24]);
+        let mut dst = [0; 16]; dst.copy_from_slice(&buf[24..40]);
+        let rv = Header {
+            payload_length: u16::from_be_bytes([buf[4], buf[5]]),
+            next_header: buf[6],
+            hop_limit: buf[7],
+            src_addr: Addr(src),
+            dst_addr: Addr(dst),
+            };
+        assert!(buf.len() >= 40 + rv.payload_length as usize, "IPv6 payload truncated");
+        (rv, &buf[40..][..rv.payload_length as usize])
+    }
+    pub fn encode(&self) -> [u8; 40]
+    {
+        let mut rv = [0; 40];
+        rv[0] = 6 << 4;
+        rv[4..6].copy_from_slice(&self.payload_length.to_be_bytes());
+        rv[6] = self.next_header;
+        rv[7] = self.hop_limit;
+        rv[8..24].copy_from_slice(&self.src_addr.0);
+        rv[24..40].copy_from_slice(&self.dst_addr.0);
+        rv
+    }
+}
+
+/// Checksum over the IPv6 pseudo-header and the upper-layer data
+pub fn calculate_checksum(src: Addr, dst: Addr, next_header: u8, buffers: &[&[u8]]) -> u16
+{
+    let len: usize = buffers.iter().map(|b| b.len()).sum();
+    let mut pseudo = Vec::with_capacity(40);
+    pseudo.extend_from_slice(&src.0);
+    pseudo.extend_from_slice(&dst.0);
+    pseudo.extend_from_slice(&(len as u32).to_be_bytes());
+    pseudo.extend_from_slice(&[0, 0, 0, next_header]);
+    let mut data: Vec<u8> = buffers.iter().flat_map(|b| b.iter().copied()).collect();
+    if data.len() % 2 == 1 {
+        data.push(0);
+    }
+    let words = pseudo.chunks(2).chain(data.chunks(2)).map(|v| (v[0] as u16) << 8 | v[1] as u16);
+    crate::ipv4::calculate_ip_checksum(words)
+}
+
+/// Send an IPv6 packet to the testee
+pub fn send_packet(fw: &crate::TestFramework, src: Addr, dst: Addr, next_header: u8, hop_limit: u8, buffers: &[&[u8]])
+{
+    let len: usize = buffers.iter().map(|b| b.len()).sum();
+    let hdr = Header { payload_length: len as u16, next_header, hop_limit, src_addr: src, dst_addr: dst, }.encode();
+    let mut bufs = vec![&hdr[..]];
+    bufs.extend_from_slice(buffers);
+    fw.send_ethernet_direct(0x86DD, &bufs);
+}
+
+/// Send an ICMPv6 message to the testee
+pub fn send_icmp(fw: &crate::TestFramework, src: Addr, dst: Addr, hop_limit: u8, ty: u8, code: u8, rest: [u8; 4], data: &[u8])
+{
+    let mut hdr = [ty, code, 0, 0, rest[0], rest[1], rest[2], rest[3]];
+    let sum = calculate_checksum(src, dst, NEXT_ICMPV6, &[&hdr, data]);
+    hdr[2..4].copy_from_slice(&sum.to_be_bytes());
+    send_packet(fw, src, dst, NEXT_ICMPV6, hop_limit, &[&hdr, data]);
+}
+
+/// Wait for an ICMPv6 message, checking the addressing and checksum. Returns the `rest` field and the data
+#[track_caller]
+pub fn wait_rx_icmp(fw: &crate::TestFramework, src: Addr, dst: Addr, ty: u8, code: u8) -> ([u8; 4], Vec<u8>)
+{
+    let data_handle = match fw.wait_packet(std::time::Duration::from_millis(1000))
+        {
+        Some(v) => v,
+        None => panic!("No packet received"),
+        };
+    let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(&data_handle);
+    assert_eq!(ether_hdr.proto, 0x86DD, "Incorrect ethernet protocol value: {:04x}", ether_hdr.proto);
+    let (hdr, tail) = Header::parse(tail);
+    assert_eq!(hdr.next_header, NEXT_ICMPV6, "Not an ICMPv6 packet");
+    assert_eq!(hdr.src_addr, src, "Source address mismatch");
+    assert_eq!(hdr.dst_addr, dst, "Destination address mismatch");
+    if dst.is_multicast() {
+        assert_eq!(ether_hdr.dst, dst.multicast_mac(), "Multicast to the wrong ethernet address");
+    }
+    assert!(tail.len() >= 8, "ICMPv6 message too short");
+    assert_eq!(calculate_checksum(src, dst, NEXT_ICMPV6, &[tail]), 0, "Bad ICMPv6 checksum");
+    assert_eq!( (tail[0], tail[1]), (ty, code), "Unexpected ICMPv6 type/code");
+    ([tail[4], tail[5], tail[6], tail[7]], tail[8..].to_owned())
+}
+
+/// Answers neighbour solicitations for a single address (like `ArpHandler`)
+pub struct NdpHandler
+{
+    my_ip: Addr,
+}
+impl NdpHandler {
+    pub fn new(my_ip: Addr) -> Self {
+        NdpHandler { my_ip }
+    }
+}
+impl super::PacketHandler for NdpHandler
+{
+    fn check_packet(&mut self, fw: &super::TestFramework, data: &[u8]) -> bool {
+        let (eh, data) = crate::ethernet::EthernetHeader::parse(data);
+        if eh.proto != 0x86DD {
+            return false;
+        }
+        let (hdr, data) = Header::parse(data);
+        if hdr.next_header != NEXT_ICMPV6 || data.len() < 24 || data[0] != ICMP_NEIGHBOR_SOLICITATION {
+            return false;
+        }
+        assert_eq!(hdr.hop_limit, 255, "Neighbour solicitation with a forwardable hop limit");
+        let target = { let mut a = [0; 16]; a.copy_from_slice(&data[8..24]); Addr(a) };
+        println!("NdpHandler: RECV solicitation for {} from {}", target, hdr.src_addr);
+        if target == self.my_ip {
+            let mut body = [0u8; 16 + 8];
+            body[..16].copy_from_slice(&target.0);
+            body[16] = 2;   // Target link-layer address
+            body[17] = 1;
+            body[18..].copy_from_slice(&crate::LOCAL_MAC);
+            // Solicited + Override
+            send_icmp(fw, self.my_ip, hdr.src_addr, 255, ICMP_NEIGHBOR_ADVERTISEMENT, 0, [0x60, 0, 0, 0], &body);
+        }
+        else {
+            println!("NdpHandler: Ignoring solicitation for {}", target);
+        }
+        true
+    }
+}
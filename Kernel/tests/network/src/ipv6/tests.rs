@@ -0,0 +1,78 @@
+//! IPv6 tests
+use super::*;
+
+fn remote_addr() -> Addr { Addr::link_local_from_mac(crate::REMOTE_MAC) }
+fn local_addr() -> Addr { Addr::link_local_from_mac(crate::LOCAL_MAC) }
+
+/// The link-local address is configured, and answers neighbour solicitations
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn neighbor_solicitation()
+{
+    let fw = crate::TestFramework::new("ipv6_neighbor_solicitation");
+
+    let mut body = [0u8; 16 + 8];
+    body[..16].copy_from_slice(&remote_addr().0);
+    body[16] = 1;   // Source link-layer address
+    body[17] = 1;
+    body[18..].copy_from_slice(&crate::LOCAL_MAC);
+    send_icmp(&fw, local_addr(), remote_addr().solicited_node(), 255, ICMP_NEIGHBOR_SOLICITATION, 0, [0; 4], &body);
+
+    let (rest, data) = wait_rx_icmp(&fw, remote_addr(), local_addr(), ICMP_NEIGHBOR_ADVERTISEMENT, 0);
+    assert_eq!(rest[0] & 0x40, 0x40, "Solicited flag not set");
+    assert_eq!(&data[..16], &remote_addr().0[..], "Advertisement for the wrong target");
+    assert_eq!(&data[16..18], &[2, 1], "No target link-layer address option");
+    assert_eq!(&data[18..24], &crate::REMOTE_MAC[..], "Incorrect target link-layer address");
+}
+
+/// Echo requests are answered (with the testee resolving the requester's address via NDP)
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn echo()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv6_echo");
+        fw.add_handler(NdpHandler::new(local_addr()));
+        fw
+        };
+
+    let rest = [0x12, 0x34, 0x00, 0x01];
+    send_icmp(&fw, local_addr(), remote_addr(), 64, ICMP_ECHO_REQUEST, 0, rest, b"ping6");
+    let (rx_rest, data) = wait_rx_icmp(&fw, remote_addr(), local_addr(), ICMP_ECHO_REPLY, 0);
+    assert_eq!(rx_rest, rest);
+    assert_eq!(&data[..], b"ping6");
+}
+
+/// UDP datagrams over IPv6 are delivered to a bound socket, and closed ports get an ICMPv6 error
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn udp()
+{
+    let fw = {
+        let mut fw = crate::TestFramework::new("ipv6_udp");
+        fw.add_handler(NdpHandler::new(local_addr()));
+        fw
+        };
+    fw.send_command("udp-bind 0 53");
+
+    let send_udp = |dst_port: u16, data: &[u8]| {
+        let mut hdr = [0u8; 8];
+        hdr[0..2].copy_from_slice(&1234u16.to_be_bytes());
+        hdr[2..4].copy_from_slice(&dst_port.to_be_bytes());
+        hdr[4..6].copy_from_slice(&(8 + data.len() as u16).to_be_bytes());
+        let sum = calculate_checksum(local_addr(), remote_addr(), NEXT_UDP, &[&hdr, data]);
+        hdr[6..8].copy_from_slice(&sum.to_be_bytes());
+        send_packet(&fw, local_addr(), remote_addr(), NEXT_UDP, 64, &[&hdr, data]);
+        };
+
+    let testblob = b"Query6\x00\x01";
+    send_udp(53, testblob);
+    fw.send_command( &format!("udp-recv-assert 0 {}", crate::HexString(testblob)) );
+
+    send_udp(54, b"Closed");
+    let (_, data) = wait_rx_icmp(&fw, remote_addr(), local_addr(), 1, 4);
+    let (orig_hdr, orig_data) = Header::parse(&data);
+    assert_eq!(orig_hdr.next_header, NEXT_UDP);
+    assert_eq!(orig_hdr.src_addr, local_addr());
+    assert_eq!(&orig_data[2..4], &54u16.to_be_bytes());
+}
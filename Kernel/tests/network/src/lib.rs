@@ -6,7 +6,11 @@ const REMOTE_MAC: [u8; 6] = *b"RSK\x12\x34\x56";
 const LOCAL_MAC: [u8; 6] = *b"RSK\xFE\xFE\xFE";
 
 pub mod tcp;
+pub mod udp;
+pub mod icmp;
+pub mod dhcp;
 pub mod ipv4;
+pub mod ipv6;
 pub mod ethernet;
 pub mod arp;
 pub mod pcap_writer;
@@ -48,6 +52,15 @@ struct Cache {
 impl TestFramework
 {
     pub fn new(name: &str) -> TestFramework
+    {
+        Self::new_inner(name, "192.168.1.1")
+    }
+    /// Create a framework where the testee's interface has no address (so is configured by DHCP)
+    pub fn new_dhcp(name: &str) -> TestFramework
+    {
+        Self::new_inner(name, "0.0.0.0")
+    }
+    fn new_inner(name: &str, remote_ip: &str) -> TestFramework
     {
         ::lazy_static::lazy_static! {
             static ref LOCK: ::std::sync::Mutex<()> = ::std::sync::Mutex::new( () );
@@ -61,7 +74,6 @@ impl TestFramework
         let socket = std::net::UdpSocket::bind( ("127.0.0.1", port) ).expect("Unable to bind socket");
         let socket_str = format!("127.0.0.1:{}", port);
         
-        let remote_ip = "192.168.1.1";
         let logfile: std::path::PathBuf = format!("{}.txt", name).into();
         let pcapfile: std::path::PathBuf = format!("{}.pcap", name).into();
 
@@ -328,6 +340,17 @@ impl<'a> ::std::fmt::Debug for HexDump<'a>
 }
 
 
+/// Helper to create a string of hex-encoded bytes
+pub struct HexString<'a>(pub &'a [u8]);
+impl ::std::fmt::Display for HexString<'_> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
 
 pub struct ArrayBuf<const N: usize> {
     len: usize,
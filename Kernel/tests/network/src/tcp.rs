@@ -52,8 +52,8 @@ impl Header
             (a as u16) << 8 | (b as u16)
         }
         fn iter_u16be<'a>(bytes: &'a [u8]) -> impl Iterator<Item=u16> + 'a {
-            assert!( bytes.len() % 2 == 0 );
-            bytes.chunks(2).map(|v| u16be(v[0], v[1]))
+            // A final odd byte is summed as if there was a zero after it
+            bytes.chunks(2).map(|v| u16be(v[0], v.get(1).copied().unwrap_or(0)))
         }
         let pseudo_enc = [
             u16be(src.0[0], src.0[1]), u16be(src.0[2], src.0[3]),
@@ -80,6 +80,63 @@ pub const TCP_RST: u8 = 0x04;
 pub const TCP_PSH: u8 = 0x08;
 pub const TCP_ACK: u8 = 0x10;
 
+pub const OPT_END: u8 = 0;
+pub const OPT_NOP: u8 = 1;
+pub const OPT_MSS: u8 = 2;
+pub const OPT_WINDOW_SCALE: u8 = 3;
+pub const OPT_SACK_PERMITTED: u8 = 4;
+pub const OPT_SACK: u8 = 5;
+pub const OPT_TIMESTAMP: u8 = 8;
+
+/// Encode TCP options (kind and value), padded to a multiple of four bytes
+pub fn encode_options(options: &[(u8, &[u8])]) -> Vec<u8>
+{
+    let mut rv = Vec::new();
+    for &(kind, value) in options {
+        rv.push(kind);
+        rv.push(2 + value.len() as u8);
+        rv.extend_from_slice(value);
+    }
+    while rv.len() % 4 != 0 {
+        rv.push(OPT_END);
+    }
+    rv
+}
+/// Encode a timestamp option's value
+pub fn timestamp_value(tsval: u32, tsecr: u32) -> [u8; 8]
+{
+    let mut rv = [0; 8];
+    rv[..4].copy_from_slice(&tsval.to_be_bytes());
+    rv[4..].copy_from_slice(&tsecr.to_be_bytes());
+    rv
+}
+/// Find an option in a received header, returning its value
+pub fn find_option(options: &[u8], kind: u8) -> Option<&[u8]>
+{
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+        OPT_END => break,
+        OPT_NOP => { i += 1; continue },
+        _ => {},
+        }
+        let len = *options.get(i+1).expect("Truncated TCP option") as usize;
+        assert!(len >= 2 && i + len <= options.len(), "Malformed TCP option: {:x?}", &options[i..]);
+        if options[i] == kind {
+            return Some(&options[i+2 .. i+len]);
+        }
+        i += len;
+    }
+    None
+}
+/// Get the timestamp option (TSval and TSecr) from a received header
+pub fn find_timestamp(options: &[u8]) -> Option<(u32, u32)>
+{
+    let v = find_option(options, OPT_TIMESTAMP)?;
+    assert_eq!(v.len(), 8, "Bad timestamp option length");
+    Some( (u32::from_be_bytes([v[0], v[1], v[2], v[3]]), u32::from_be_bytes([v[4], v[5], v[6], v[7]])) )
+}
+
 pub fn send_packet_raw(fw: &crate::TestFramework, src: IpAddr4, dst: IpAddr4, mut header: Header, options: &[u8], data: &[u8])
 {
     assert!(options.len() % 4 == 0);
@@ -130,10 +187,25 @@ impl TcpConn<'_>
     #[track_caller]
     pub fn wait_rx_check(&self, flags: u8, data: &[u8]) -> Header
     {
-        let data_handle = match self.fw.wait_packet(std::time::Duration::from_millis(1000))
+        self.wait_rx_check_within(std::time::Duration::from_millis(1000), flags, data)
+    }
+    /// Wait for a packet (with a custom timeout) and check the flags and data
+    #[track_caller]
+    pub fn wait_rx_check_within(&self, timeout: std::time::Duration, flags: u8, data: &[u8]) -> Header
+    {
+        let (tcp_hdr, _options, rx_data) = self.wait_rx_within(timeout);
+        assert!(tcp_hdr.flags == flags, "Header flags mismatch: Expected {:#x} got {:#x}", flags, tcp_hdr.flags);
+        assert_eq!(rx_data, data, "Data mismatch");
+        tcp_hdr
+    }
+    /// Wait for a packet on this connection, returning the header, options, and data
+    #[track_caller]
+    pub fn wait_rx_within(&self, timeout: std::time::Duration) -> (Header, Vec<u8>, Vec<u8>)
+    {
+        let data_handle = match self.fw.wait_packet(timeout)
             {
             Some(v) => v,
-            None => panic!("No packet received"),
+            None => panic!("No packet received within {:?}", timeout),
             };
         let tail = &data_handle[..];
         // 1. Check the ethernet header
@@ -145,16 +217,11 @@ impl TcpConn<'_>
         assert_eq!(crate::ipv4::Addr(ip_hdr.src_addr), self.addrs.1);
         assert_eq!(crate::ipv4::Addr(ip_hdr.dst_addr), self.addrs.0);
         assert_eq!(ip_options.len(), 0);
-        // 3. Check the TCP header (incl flags)
+        // 3. Check the TCP header
         let (tcp_hdr,tcp_options, tail) = Header::parse(tail);
         assert!(tcp_hdr.dst_port == self.local_port, "TCP destination port mismatch: Exp {} got {}", tcp_hdr.dst_port, self.local_port);
         assert!(tcp_hdr.src_port == self.remote_port, "TCP source port mismatch: Exp {} got {}", tcp_hdr.src_port, self.remote_port);
-        //assert!(tcp_options.len() == 0, "Expected no TCP options, got {:?}", tcp_options);
-        let _ = tcp_options;
-        assert!(tcp_hdr.flags == flags, "Header flags mismatch: Expected {:#x} got {:#x}", flags, tcp_hdr.flags);
-        // 4. Check the data
-        assert_eq!(tail, data, "Data mismatch");
-        tcp_hdr
+        (tcp_hdr, tcp_options.to_vec(), tail.to_vec())
     }
     #[track_caller]
     pub fn wait_rx_none(&self)
@@ -167,6 +234,11 @@ impl TcpConn<'_>
     }
 
     pub fn from_rx_conn(fw: &crate::TestFramework, lport: u16, laddr: crate::ipv4::Addr) -> TcpConn
+    {
+        Self::from_rx_conn_with_options(fw, lport, laddr).0
+    }
+    /// Wait for a SYN, returning the connection and the SYN's options
+    pub fn from_rx_conn_with_options(fw: &crate::TestFramework, lport: u16, laddr: crate::ipv4::Addr) -> (TcpConn, Vec<u8>)
     {
         let t = std::time::Instant::now();
         let data_handle = match fw.wait_packet(std::time::Duration::from_millis(1000))
@@ -186,13 +258,11 @@ impl TcpConn<'_>
         assert_eq!(ip_options.len(), 0);
         // 3. Check the TCP header (incl flags)
         let (tcp_hdr,tcp_options, tail) = Header::parse(tail);
-        //assert_eq!(tcp_options.len(), 0);
-        let _ = tcp_options;
         assert_eq!(tcp_hdr.flags, TCP_SYN);
         assert_eq!(tcp_hdr.dst_port, lport);
         // 4. Check the data
         assert_eq!(tail, &[], "Data mismatch");
-        TcpConn {
+        (TcpConn {
             fw: fw,
             addrs: (laddr, crate::ipv4::Addr(ip_hdr.src_addr)),
             remote_port: tcp_hdr.src_port, 
@@ -202,6 +272,6 @@ impl TcpConn<'_>
 
             local_seq: 0x10000,
             remote_seq: tcp_hdr.seq + 1,
-            }
+            }, tcp_options.to_vec())
     }
 }
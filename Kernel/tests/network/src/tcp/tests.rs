@@ -1,6 +1,7 @@
 //! TCP tests
 use crate::ipv4::Addr as IpAddr4;
 use super::*;
+use crate::HexString;
 
 /// TCP State CLOSED
 /// 
@@ -88,7 +89,7 @@ fn server()
     conn.local_seq = conn.local_seq.wrapping_add(1);
     let hdr = conn.wait_rx_check(TCP_SYN|TCP_ACK, &[]);
     assert_eq!(hdr.ack, conn.local_seq, "ACK number doesn't match expected");
-    conn.remote_seq = hdr.seq;//.wrapping_add(1);
+    conn.remote_seq = hdr.seq.wrapping_add(1);
 
     // >> STATE: SYN-RECEIVED
 
@@ -106,7 +107,7 @@ fn server()
     fw.send_command( &format!("tcp-recv-assert 0 {} {}", testblob.len(), HexString(testblob)) );
 
     fw.send_command( &format!("tcp-send 0 {}", HexString(testblob)) );
-    conn.wait_rx_check(TCP_ACK/*|TCP_PSH*/, testblob);
+    conn.wait_rx_check(TCP_ACK|TCP_PSH, testblob);
     conn.remote_seq += testblob.len() as u32;
 }
 
@@ -134,16 +135,432 @@ fn client()
     conn.wait_rx_check(TCP_ACK, &[]);
     // Get the client to send data
     fw.send_command("tcp-send 0 \"00 01 02 03\"");
-    conn.wait_rx_check(if cfg!(feature="lwip") { TCP_ACK|TCP_PSH } else { 0 }, &[0,1,2,3]);
+    let hdr = conn.wait_rx_check(TCP_ACK|TCP_PSH, &[0,1,2,3]);
+    assert_eq!(hdr.seq, conn.remote_seq, "First data byte should follow the SYN");
 }
 
-/// Helper to create a string of hex-encoded bytes
-struct HexString<'a>(&'a [u8]);
-impl ::std::fmt::Display for HexString<'_> {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        for b in self.0 {
-            write!(f, "{:02x}", b)?;
-        }
-        Ok(())
+/// Open a client connection from the testee to the framework
+fn connect_client<'a>(fw: &'a crate::TestFramework, my_ip: IpAddr4) -> TcpConn<'a>
+{
+    connect_client_with_options(fw, my_ip, &[]).0
+}
+/// Open a client connection with options in the SYN,ACK, returning the connection and the SYN's options
+fn connect_client_with_options<'a>(fw: &'a crate::TestFramework, my_ip: IpAddr4, options: &[u8]) -> (TcpConn<'a>, Vec<u8>)
+{
+    fw.send_command(&format!("tcp-connect 0 {my_ip} 80"));
+    let (mut conn, syn_options) = TcpConn::from_rx_conn_with_options(fw, 80, my_ip);
+    conn.raw_send_packet(TCP_SYN|TCP_ACK, options, &[]);
+    conn.local_seq += 1;
+    conn.wait_rx_check(TCP_ACK, &[]);
+    (conn, syn_options)
+}
+
+/// Unacknowledged data is retransmitted, with the timeout doubling each time
+#[test]
+fn retransmit_timeout()
+{
+    let my_ip = IpAddr4([192,168,1,2]);
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_retransmit_timeout");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+    let mut conn = connect_client(&fw, my_ip);
+
+    fw.send_command("tcp-send 0 \"00 01 02 03\"");
+    let first = conn.wait_rx_check(TCP_ACK|TCP_PSH, &[0,1,2,3]);
+
+    // Drop the segment (by not ACKing it), it should be re-sent after the retransmit timeout
+    let t = std::time::Instant::now();
+    let retx = conn.wait_rx_check_within(std::time::Duration::from_secs(5), TCP_ACK|TCP_PSH, &[0,1,2,3]);
+    let first_rto = t.elapsed();
+    assert_eq!(retx.seq, first.seq, "Retransmit has a different sequence number");
+
+    // Drop it again, the timeout should have backed off
+    let t = std::time::Instant::now();
+    let retx = conn.wait_rx_check_within(std::time::Duration::from_secs(10), TCP_ACK|TCP_PSH, &[0,1,2,3]);
+    let second_rto = t.elapsed();
+    assert_eq!(retx.seq, first.seq, "Retransmit has a different sequence number");
+    assert!(second_rto >= first_rto * 3 / 2, "Retransmit timeout didn't back off: {:?} then {:?}", first_rto, second_rto);
+
+    // ACK it, no more retransmits
+    conn.remote_seq = conn.remote_seq.wrapping_add(4);
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    conn.wait_rx_none();
+}
+
+/// Three duplicate ACKs trigger a retransmit without waiting for the timeout
+#[test]
+fn fast_retransmit()
+{
+    let my_ip = IpAddr4([192,168,1,2]);
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_fast_retransmit");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+    let mut conn = connect_client(&fw, my_ip);
+
+    fw.send_command("tcp-send 0 \"00 01 02 03\"");
+    let first = conn.wait_rx_check(TCP_ACK|TCP_PSH, &[0,1,2,3]);
+
+    // Pretend the segment was lost, and that three later segments arrived
+    for _ in 0 .. 3 {
+        conn.raw_send_packet(TCP_ACK, &[], &[]);
     }
-}
\ No newline at end of file
+    // The retransmit timeout is at least one second, so this must be the fast retransmit
+    let retx = conn.wait_rx_check_within(std::time::Duration::from_millis(500), TCP_ACK|TCP_PSH, &[0,1,2,3]);
+    assert_eq!(retx.seq, first.seq, "Retransmit has a different sequence number");
+
+    conn.remote_seq = conn.remote_seq.wrapping_add(4);
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    conn.wait_rx_none();
+}
+
+/// A lost segment: out-of-order data gets an immediate duplicate ACK, and filling the gap ACKs everything
+#[test]
+fn out_of_order()
+{
+    let my_ip = IpAddr4([192,168,1,2]);
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_out_of_order");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+    let mut conn = connect_client(&fw, my_ip);
+
+    // Second segment first
+    let base_seq = conn.local_seq;
+    conn.local_seq = base_seq.wrapping_add(4);
+    conn.raw_send_packet(TCP_ACK|TCP_PSH, &[], b"5678");
+    let hdr = conn.wait_rx_check(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, base_seq, "Out of order data should get a duplicate ACK");
+
+    // Then the "retransmitted" first segment
+    conn.local_seq = base_seq;
+    conn.raw_send_packet(TCP_ACK|TCP_PSH, &[], b"1234");
+    let hdr = conn.wait_rx_check(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, base_seq.wrapping_add(8), "Filling the gap should ACK both segments");
+    conn.local_seq = base_seq.wrapping_add(8);
+
+    fw.send_command( &format!("tcp-recv-assert 0 8 {}", HexString(b"12345678")) );
+}
+
+/// Received data is ACKed after a short delay, not immediately
+#[test]
+fn delayed_ack()
+{
+    let my_ip = IpAddr4([192,168,1,2]);
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_delayed_ack");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+    let mut conn = connect_client(&fw, my_ip);
+
+    conn.raw_send_packet(TCP_ACK|TCP_PSH, &[], b"Hello");
+    conn.local_seq += 5;
+    // lwIP's timer granularity means its ACK could be sent at any point in the first 250ms
+    if !cfg!(feature="lwip") {
+        conn.wait_rx_none();
+    }
+    let hdr = conn.wait_rx_check_within(std::time::Duration::from_millis(500), TCP_ACK, &[]);
+    assert_eq!(hdr.ack, conn.local_seq);
+}
+
+/// Active close: FIN_WAIT_1 -> FIN_WAIT_2 -> TIME_WAIT, with a retransmitted FIN re-ACKed in TIME_WAIT
+#[test]
+fn active_close()
+{
+    let my_ip = IpAddr4([192,168,1,2]);
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_active_close");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+    let mut conn = connect_client(&fw, my_ip);
+
+    fw.send_command("tcp-close 0");
+    let hdr = conn.wait_rx_check(TCP_FIN|TCP_ACK, &[]);
+    assert_eq!(hdr.seq, conn.remote_seq);
+    // The FIN consumes a sequence number
+    conn.remote_seq = conn.remote_seq.wrapping_add(1);
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    conn.wait_rx_none();
+
+    // >> FIN_WAIT_2
+    conn.raw_send_packet(TCP_FIN|TCP_ACK, &[], &[]);
+    let hdr = conn.wait_rx_check(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, conn.local_seq.wrapping_add(1), "FIN not ACKed");
+
+    // >> TIME_WAIT: A retransmit of the FIN (as if the ACK was lost) is ACKed again
+    conn.raw_send_packet(TCP_FIN|TCP_ACK, &[], &[]);
+    let hdr = conn.wait_rx_check(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, conn.local_seq.wrapping_add(1), "Retransmitted FIN not ACKed");
+}
+
+/// Passive close: the remote's FIN is ACKed, and the local close sends a FIN
+#[test]
+fn passive_close()
+{
+    let my_ip = IpAddr4([192,168,1,2]);
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_passive_close");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+    let mut conn = connect_client(&fw, my_ip);
+
+    conn.raw_send_packet(TCP_FIN|TCP_ACK, &[], &[]);
+    conn.local_seq += 1;
+    let hdr = conn.wait_rx_check(TCP_ACK, &[]);
+    assert_eq!(hdr.ack, conn.local_seq, "FIN not ACKed");
+
+    // >> CLOSE_WAIT
+    fw.send_command("tcp-close 0");
+    let hdr = conn.wait_rx_check(TCP_FIN|TCP_ACK, &[]);
+    assert_eq!(hdr.seq, conn.remote_seq);
+    conn.remote_seq = conn.remote_seq.wrapping_add(1);
+
+    // >> LAST_ACK
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    conn.wait_rx_none();
+}
+
+/// The SYN advertises an MSS (and the testee's other supported options)
+#[test]
+fn syn_options()
+{
+    let my_ip = IpAddr4([192,168,1,2]);
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_syn_options");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+    fw.send_command(&format!("tcp-connect 0 {my_ip} 80"));
+    let (_conn, options) = TcpConn::from_rx_conn_with_options(&fw, 80, my_ip);
+    let mss = find_option(&options, OPT_MSS).expect("No MSS option in SYN");
+    assert_eq!(mss.len(), 2);
+    assert!(u16::from_be_bytes([mss[0], mss[1]]) >= 536, "MSS too small: {:?}", mss);
+    if !cfg!(feature="lwip") {
+        assert!(find_option(&options, OPT_WINDOW_SCALE).is_some(), "No window scale option in SYN");
+        assert!(find_option(&options, OPT_SACK_PERMITTED).is_some(), "No SACK-permitted option in SYN");
+        assert!(find_timestamp(&options).is_some(), "No timestamp option in SYN");
+    }
+}
+
+/// The remote's MSS limits the size of sent segments
+#[test]
+fn mss_option()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_mss_option");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    fw.send_command("tcp-listen 0 80");
+
+    let mut conn = TcpConn {
+        fw: &fw,
+        addrs: (LOCAL_ADDR, REMOTE_ADDR),
+        remote_port: 80,
+        local_port: 11200,
+
+        rx_window: 0x1000,
+
+        local_seq: 0x1000,
+        remote_seq: 0x1000,
+        };
+    conn.raw_send_packet(TCP_SYN, &encode_options(&[(OPT_MSS, &100u16.to_be_bytes())]), &[]);
+    conn.local_seq += 1;
+    let (hdr, options, _) = conn.wait_rx_within(std::time::Duration::from_millis(1000));
+    assert_eq!(hdr.flags, TCP_SYN|TCP_ACK);
+    assert!(find_option(&options, OPT_MSS).is_some(), "No MSS option in SYN,ACK");
+    conn.remote_seq = hdr.seq.wrapping_add(1);
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    fw.send_command("tcp-accept 0 0");
+
+    let testblob: Vec<u8> = (0 .. 250).map(|v| v as u8).collect();
+    fw.send_command( &format!("tcp-send 0 {}", HexString(&testblob)) );
+    let mut received = Vec::new();
+    while received.len() < testblob.len() {
+        let (hdr, _, data) = conn.wait_rx_within(std::time::Duration::from_millis(1000));
+        assert_eq!(hdr.seq, conn.remote_seq, "Unexpected sequence number");
+        assert!(data.len() <= 100, "Segment of {} bytes exceeds the MSS", data.len());
+        received.extend_from_slice(&data);
+        conn.remote_seq = conn.remote_seq.wrapping_add(data.len() as u32);
+        conn.raw_send_packet(TCP_ACK, &[], &[]);
+    }
+    assert_eq!(received, testblob);
+}
+
+/// Windows after the SYN are scaled by the negotiated shift
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn window_scale()
+{
+    let my_ip = IpAddr4([192,168,1,2]);
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_window_scale");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+    let options = encode_options(&[(OPT_MSS, &100u16.to_be_bytes()), (OPT_WINDOW_SCALE, &[4])]);
+    let (mut conn, _) = connect_client_with_options(&fw, my_ip, &options);
+
+    // A window of 20 bytes if unscaled, 320 with the shift
+    conn.rx_window = 20;
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    conn.wait_rx_none();
+
+    let testblob: Vec<u8> = (0 .. 300).map(|v| v as u8).collect();
+    fw.send_command( &format!("tcp-send 0 {}", HexString(&testblob)) );
+    conn.wait_rx_check(TCP_ACK, &testblob[..100]);
+    conn.wait_rx_check(TCP_ACK, &testblob[100..200]);
+    conn.wait_rx_check(TCP_ACK|TCP_PSH, &testblob[200..]);
+}
+
+/// With SACK, loss recovery only resends the data that the remote reports as missing
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn sack_retransmit()
+{
+    let my_ip = IpAddr4([192,168,1,2]);
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_sack_retransmit");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+    let options = encode_options(&[(OPT_MSS, &100u16.to_be_bytes()), (OPT_SACK_PERMITTED, &[])]);
+    let (mut conn, _) = connect_client_with_options(&fw, my_ip, &options);
+
+    let testblob: Vec<u8> = (0 .. 400).map(|v| v as u8).collect();
+    fw.send_command( &format!("tcp-send 0 {}", HexString(&testblob)) );
+    let base = conn.remote_seq;
+    conn.wait_rx_check(TCP_ACK, &testblob[..100]);
+    conn.wait_rx_check(TCP_ACK, &testblob[100..200]);
+    conn.wait_rx_check(TCP_ACK, &testblob[200..300]);
+    conn.wait_rx_check(TCP_ACK|TCP_PSH, &testblob[300..]);
+
+    // The first and third segments were lost
+    let mut sack = Vec::new();
+    for (s, e) in [(100, 200), (300, 400)] {
+        sack.extend_from_slice(&base.wrapping_add(s).to_be_bytes());
+        sack.extend_from_slice(&base.wrapping_add(e).to_be_bytes());
+    }
+    let sack_options = encode_options(&[(OPT_SACK, &sack)]);
+    for _ in 0 .. 3 {
+        conn.raw_send_packet(TCP_ACK, &sack_options, &[]);
+    }
+    let hdr = conn.wait_rx_check_within(std::time::Duration::from_millis(500), TCP_ACK, &testblob[..100]);
+    assert_eq!(hdr.seq, base);
+    // The next duplicate fills the other hole, skipping the SACKed segment
+    conn.raw_send_packet(TCP_ACK, &sack_options, &[]);
+    let hdr = conn.wait_rx_check_within(std::time::Duration::from_millis(500), TCP_ACK, &testblob[200..300]);
+    assert_eq!(hdr.seq, base.wrapping_add(200));
+
+    conn.remote_seq = base.wrapping_add(400);
+    conn.raw_send_packet(TCP_ACK, &[], &[]);
+    conn.wait_rx_none();
+}
+
+/// Timestamps are echoed, and segments with an old timestamp are dropped (PAWS)
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn timestamps()
+{
+    let my_ip = IpAddr4([192,168,1,2]);
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_timestamps");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+    fw.send_command(&format!("tcp-connect 0 {my_ip} 80"));
+    let (mut conn, syn_options) = TcpConn::from_rx_conn_with_options(&fw, 80, my_ip);
+    let (syn_tsval, _) = find_timestamp(&syn_options).expect("No timestamp in SYN");
+    conn.raw_send_packet(TCP_SYN|TCP_ACK, &encode_options(&[(OPT_TIMESTAMP, &timestamp_value(1000, syn_tsval))]), &[]);
+    conn.local_seq += 1;
+    let (hdr, options, _) = conn.wait_rx_within(std::time::Duration::from_millis(1000));
+    assert_eq!(hdr.flags, TCP_ACK);
+    assert_eq!(find_timestamp(&options).map(|v| v.1), Some(1000), "SYN,ACK timestamp not echoed");
+
+    conn.raw_send_packet(TCP_ACK|TCP_PSH, &encode_options(&[(OPT_TIMESTAMP, &timestamp_value(1010, 0))]), b"abcd");
+    conn.local_seq += 4;
+    let (hdr, options, _) = conn.wait_rx_within(std::time::Duration::from_millis(500));
+    assert_eq!(hdr.ack, conn.local_seq);
+    assert_eq!(find_timestamp(&options).map(|v| v.1), Some(1010), "Data timestamp not echoed");
+
+    // An older timestamp: dropped, and the ACK doesn't move
+    conn.raw_send_packet(TCP_ACK|TCP_PSH, &encode_options(&[(OPT_TIMESTAMP, &timestamp_value(900, 0))]), b"efgh");
+    let (hdr, options, _) = conn.wait_rx_within(std::time::Duration::from_millis(500));
+    assert_eq!(hdr.ack, conn.local_seq, "Segment with an old timestamp was accepted");
+    assert_eq!(find_timestamp(&options).map(|v| v.1), Some(1010));
+    fw.send_command( &format!("tcp-recv-assert 0 8 {}", HexString(b"abcd")) );
+}
+
+/// Segments with options are accepted when their checksum covers the options (and odd-length data)
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn options_checksum()
+{
+    let my_ip = IpAddr4([192,168,1,2]);
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_options_checksum");
+        fw.add_handler(crate::arp::ArpHandler::new(my_ip));
+        fw
+        };
+    fw.send_command(&format!("tcp-connect 0 {my_ip} 80"));
+    let (mut conn, syn_options) = TcpConn::from_rx_conn_with_options(&fw, 80, my_ip);
+    let (syn_tsval, _) = find_timestamp(&syn_options).expect("No timestamp in SYN");
+    let options = encode_options(&[(OPT_MSS, &1000u16.to_be_bytes()), (OPT_TIMESTAMP, &timestamp_value(1000, syn_tsval))]);
+    conn.raw_send_packet(TCP_SYN|TCP_ACK, &options, &[]);
+    conn.local_seq += 1;
+    conn.wait_rx_check(TCP_ACK, &[]);
+
+    conn.raw_send_packet(TCP_ACK|TCP_PSH, &encode_options(&[(OPT_TIMESTAMP, &timestamp_value(1010, 0))]), b"Hello");
+    conn.local_seq += 5;
+    let hdr = conn.wait_rx_check_within(std::time::Duration::from_millis(500), TCP_ACK, &[]);
+    assert_eq!(hdr.ack, conn.local_seq, "Segment with options was dropped");
+    fw.send_command( &format!("tcp-recv-assert 0 5 {}", HexString(b"Hello")) );
+}
+
+/// Initial sequence numbers differ between connections (RFC 6528)
+#[test]
+#[cfg_attr(feature="lwip", ignore)]
+fn random_isn()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("tcp_random_isn");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    fw.send_command("tcp-listen 0 80");
+
+    let mut seqs = Vec::new();
+    for local_port in [11200, 11201, 11202]
+    {
+        let conn = TcpConn {
+            fw: &fw,
+            addrs: (LOCAL_ADDR, REMOTE_ADDR),
+            remote_port: 80,
+            local_port,
+
+            rx_window: 0x1000,
+
+            local_seq: 0x1000,
+            remote_seq: 0x1000,
+            };
+        conn.raw_send_packet(TCP_SYN, &[], &[]);
+        let hdr = conn.wait_rx_check(TCP_SYN|TCP_ACK, &[]);
+        seqs.push(hdr.seq);
+    }
+    assert!(seqs[0] != seqs[1] && seqs[1] != seqs[2] && seqs[0] != seqs[2], "Repeated ISN: {:x?}", seqs);
+    // A clock-only ISN would only differ by a few ticks
+    assert!(seqs.windows(2).any(|w| w[1].wrapping_sub(w[0]) > 0x100000), "ISNs look sequential: {:x?}", seqs);
+}
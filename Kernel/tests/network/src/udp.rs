@@ -0,0 +1,110 @@
+// "Tifflin" Kernel Tests (network)
+// - By John Hodge (Mutabah)
+//
+// tests/network/udp.rs
+//! UDP tests and infrastructure
+use crate::ipv4::Addr as IpAddr4;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Copy,Clone)]
+#[derive(Debug)]
+#[derive(serde_derive::Deserialize,serde_derive::Serialize)]
+pub struct Header
+{
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub length: u16,
+    pub checksum: u16,
+}
+impl Header
+{
+    /// Parse a UDP header, returning the data
+    pub fn parse(mut buf: &[u8]) -> (Self, &[u8]) {
+        let rv: Self = crate::des_be(&mut buf).expect("Failed to parse UDP header");
+        println!("Header: {:?}", rv);
+        assert!(rv.length as usize >= 8, "Bad UDP length");
+        assert!(rv.length as usize - 8 <= buf.len(), "UDP length larger than packet: 8+{} > 8+{}", rv.length - 8, buf.len());
+        (rv, &buf[..rv.length as usize - 8])
+    }
+    fn encode(&self) -> [u8; 8]
+    {
+        let mut rv = [0; 8];
+        {
+            let mut c = std::io::Cursor::new(&mut rv[..]);
+            crate::ser_be(&mut c, self);
+            assert_eq!(c.position(), 8, "Encoding of UDP header failed?");
+        }
+        rv
+    }
+    pub fn calculate_checksum_v4(&self, src: IpAddr4, dst: IpAddr4, data: &[u8]) -> u16
+    {
+        fn u16be(a: u8, b: u8) -> u16 {
+            (a as u16) << 8 | (b as u16)
+        }
+        let pseudo_enc = [
+            u16be(src.0[0], src.0[1]), u16be(src.0[2], src.0[3]),
+            u16be(dst.0[0], dst.0[1]), u16be(dst.0[2], dst.0[3]),
+            17, self.length,
+            ];
+        let hdr_enc = self.encode();
+        let it_pseudo = pseudo_enc.iter().copied();
+        let it_header = hdr_enc.chunks(2).map(|v| u16be(v[0], v[1]));
+        // Odd trailing bytes are padded with zero
+        let it_data = data.chunks(2).map(|v| u16be(v[0], *v.get(1).unwrap_or(&0)));
+
+        crate::ipv4::calculate_ip_checksum(it_pseudo.chain(it_header).chain(it_data))
+    }
+}
+
+pub fn send_packet(fw: &crate::TestFramework, src: (IpAddr4, u16), dst: (IpAddr4, u16), data: &[u8])
+{
+    let mut header = Header {
+        src_port: src.1,
+        dst_port: dst.1,
+        length: (8 + data.len()) as u16,
+        checksum: 0,
+        };
+    header.checksum = header.calculate_checksum_v4(src.0, dst.0, data);
+    let udp_hdr = header.encode();
+    let ip_hdr = {
+        let mut h = crate::ipv4::Header::new_simple(src.0, dst.0, 17, udp_hdr.len() + data.len());
+        h.set_checksum();
+        h.encode()
+        };
+    fw.send_ethernet_direct(0x0800, &[&ip_hdr, &udp_hdr, data]);
+}
+
+/// Wait for a UDP packet and check the addresses, ports and data
+#[track_caller]
+pub fn wait_rx_check(fw: &crate::TestFramework, src: (IpAddr4, u16), dst: (IpAddr4, u16), data: &[u8])
+{
+    let data_handle = match fw.wait_packet(std::time::Duration::from_millis(1000))
+        {
+        Some(v) => v,
+        None => panic!("No packet received"),
+        };
+    let tail = &data_handle[..];
+    // 1. Check the ethernet header
+    let (ether_hdr, tail) = crate::ethernet::EthernetHeader::parse(tail);
+    assert_eq!(ether_hdr.proto, 0x0800, "Incorrect ethernet protocol value: {:04x}", ether_hdr.proto);
+    // 2. Check the IPv4 header
+    let (ip_hdr,ip_options, tail) = crate::ipv4::Header::parse(tail);
+    assert_eq!(ip_hdr.protocol, 17);
+    assert_eq!(crate::ipv4::Addr(ip_hdr.src_addr), src.0);
+    assert_eq!(crate::ipv4::Addr(ip_hdr.dst_addr), dst.0);
+    assert_eq!(ip_options.len(), 0);
+    // 3. Check the UDP header
+    let (udp_hdr, tail) = Header::parse(tail);
+    assert_eq!(udp_hdr.src_port, src.1, "UDP source port mismatch");
+    assert_eq!(udp_hdr.dst_port, dst.1, "UDP destination port mismatch");
+    if udp_hdr.checksum != 0 {
+        let mut h = udp_hdr;
+        h.checksum = 0;
+        let exp = match h.calculate_checksum_v4(src.0, dst.0, tail) { 0 => 0xFFFF, v => v };
+        assert_eq!(udp_hdr.checksum, exp, "UDP checksum mismatch");
+    }
+    // 4. Check the data
+    assert_eq!(tail, data, "Data mismatch");
+}
@@ -0,0 +1,50 @@
+//! UDP tests
+use crate::ipv4::Addr as IpAddr4;
+use super::*;
+
+/// Datagrams in both directions on a bound socket
+#[test]
+fn bound_socket()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("udp_bound_socket");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    fw.send_command("udp-bind 0 53");
+
+    // Framework -> Testee
+    let testblob = b"Query\x00\x01\x02";
+    send_packet(&fw, (LOCAL_ADDR, 1234), (REMOTE_ADDR, 53), testblob);
+    fw.send_command( &format!("udp-recv-assert 0 {}", crate::HexString(testblob)) );
+
+    // Testee -> Framework (odd length, to check checksum padding)
+    let testblob = b"Response.";
+    fw.send_command( &format!("udp-send 0 {} 1234 {}", LOCAL_ADDR, crate::HexString(testblob)) );
+    wait_rx_check(&fw, (REMOTE_ADDR, 53), (LOCAL_ADDR, 1234), testblob);
+}
+
+/// Datagrams to a port with no socket get an ICMP "port unreachable"
+#[test]
+fn closed_port()
+{
+    const REMOTE_ADDR: IpAddr4 = IpAddr4([192,168,1,1]);
+    const LOCAL_ADDR: IpAddr4 = IpAddr4([192,168,1,2]);
+
+    let fw = {
+        let mut fw = crate::TestFramework::new("udp_closed_port");
+        fw.add_handler(crate::arp::ArpHandler::new(LOCAL_ADDR));
+        fw
+        };
+    send_packet(&fw, (LOCAL_ADDR, 1234), (REMOTE_ADDR, 53), b"Hello");
+    let (_rest, body) = crate::icmp::wait_rx_check(&fw, REMOTE_ADDR, LOCAL_ADDR, 3, 3);
+    // Body contains the original header and the start of the datagram
+    let (orig_hdr, _, orig_data) = crate::ipv4::Header::parse(&body);
+    assert_eq!(orig_hdr.protocol, 17);
+    let (udp_hdr, _) = Header::parse(orig_data);
+    assert_eq!(udp_hdr.src_port, 1234);
+    assert_eq!(udp_hdr.dst_port, 53);
+}
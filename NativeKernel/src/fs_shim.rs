@@ -37,7 +37,7 @@ impl mount::Driver for NativeFsDriver
 	fn detect(&self, _vol: &VolumeHandle) -> ::vfs::Result<usize> {
         Ok(0)
     }
-	fn mount(&self, _vol: VolumeHandle, _handle: mount::SelfHandle) -> ::vfs::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, _vol: VolumeHandle, _read_only: bool, _handle: mount::SelfHandle) -> ::vfs::Result<Box<dyn mount::Filesystem>> {
         // TODO: Can this get the path from the volume handle?
         let root_path: PathBuf = ".native_fs".into();
         let mut rv = NativeFs::default();
@@ -96,6 +96,9 @@ impl ::vfs::node::NodeBase for DirNodeRef
 	fn get_any(&self) -> &dyn ::std::any::Any {
         self
     }
+	fn get_metadata(&self) -> ::vfs::node::Result<::vfs::node::Metadata> {
+        get_metadata(&self.0.get_dir(self.1).path)
+    }
 }
 impl ::vfs::node::Dir for DirNodeRef
 {
@@ -197,6 +200,9 @@ impl vfs::node::NodeBase for FileNodeRef
 	fn get_any(&self) -> &dyn ::std::any::Any {
         self
     }
+	fn get_metadata(&self) -> vfs::node::Result<vfs::node::Metadata> {
+        get_metadata(&self.0.get_file(self.1).path)
+    }
 }
 impl vfs::node::File for FileNodeRef
 {
@@ -227,6 +233,25 @@ impl vfs::node::File for FileNodeRef
     }
 }
 
+/// Translate host metadata (only the read-only flag is portable, so that's all the mode reflects)
+fn get_metadata(path: &Path) -> vfs::node::Result<vfs::node::Metadata> {
+    let m = ::std::fs::metadata(path).map_err(map_err)?;
+    let time = |t: ::std::io::Result<::std::time::SystemTime>| t.ok()
+        .and_then(|t| t.duration_since(::std::time::UNIX_EPOCH).ok())
+        .map(|d| vfs::node::Timestamp { secs: d.as_secs() as i64, nsecs: d.subsec_nanos() });
+    Ok(vfs::node::Metadata {
+        size: if m.is_dir() { 0 } else { m.len() },
+        links: 1,
+        mode: if m.permissions().readonly() { 0o555 } else { 0o777 },
+        uid: 0,
+        gid: 0,
+        atime: time(m.accessed()),
+        mtime: time(m.modified()),
+        ctime: time(m.modified()),
+        crtime: time(m.created()),
+        })
+}
+
 fn map_err(e: ::std::io::Error) -> vfs::Error {
     todo!("Transform error {:?}", e)
 }
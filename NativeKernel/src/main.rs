@@ -506,6 +506,13 @@ fn start_process(gs: &GlobalStateRef, mut args: &[usize]) -> Result<u32, ::sysca
 				let lh = self.gs.lock().unwrap();
 				::syscalls::native_exports::give_object(&lh.process_handles[&self.pid], &tag, handle).map(|_| 0)
 				},
+			::syscalls::native_exports::values::CORE_PROTOPROCESS_SETCREDS => {
+				let uid: u32 = args.get()?;
+				let gid: u32 = args.get()?;
+				self.wait_until_tracked();
+				let lh = self.gs.lock().unwrap();
+				Ok( ::syscalls::native_exports::from_result(::syscalls::native_exports::set_credentials(&lh.process_handles[&self.pid], uid, gid)) )
+				},
 			_ => ::syscalls::native_exports::object_has_no_such_method_ref("ProtoProcess", call),
 			}
 		}
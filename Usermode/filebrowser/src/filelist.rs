@@ -16,19 +16,24 @@ pub struct FileList<'a>
 
 	cur_paths: RefCell<Vec<OsString>>,
 	
-	list: ListView<[&'static str; 2], FileEnt>,
+	list: ListView<[&'static str; 4], FileEnt>,
 }
 
 impl<'a> FileList<'a>
 {
 	pub fn new(root: &'a ::syscalls::vfs::Dir) -> FileList<'a>
 	{
+		let mut list = ListView::new(["T", "Size", "Modified", "Filename"]);
+		// Room for "1023.9M" and "YYYY-MM-DD HH:MM"
+		list.set_column_width(1, 7*8+3);
+		list.set_column_width(2, 16*8+3);
+		list.set_column_width(3, 40*8+3);
 		FileList {
 			root: root,
 			on_open: Box::new(|_,_,_|()),
 			on_chdir: Box::new(|_,_|()),
 			cur_paths: Default::default(),
-			list: ListView::new(["T", "Filename"]),
+			list: list,
 		}
 	}
 	
@@ -132,6 +137,8 @@ impl<'a> ::wtk::Element for FileList<'a>
 struct FileEnt
 {
 	ty_str: &'static str,
+	size_str: String,
+	mtime_str: String,
 	name: OsString,
 	display_name: Option<String>,
 }
@@ -139,8 +146,19 @@ impl FileEnt
 {
 	fn new(dir: &::syscalls::vfs::Dir, name: &[u8]) -> FileEnt {
 		let node = dir.open_child(name);
-		let node_ty = match node { Ok(n) => Some(n.class()), Err(_) => None };
+		let node_ty = match node { Ok(ref n) => Some(n.class()), Err(_) => None };
+		let meta = match node { Ok(ref n) => n.get_metadata().ok(), Err(_) => None };
 		FileEnt {
+			size_str: match (node_ty, &meta)
+				{
+				(Some(::syscalls::vfs::NodeType::File), &Some(ref m)) => format_size(m.size),
+				_ => String::new(),
+				},
+			mtime_str: match meta
+				{
+				Some(ref m) => format!("{}", ::syscalls::vfs::DisplayTime(m.mtime)),
+				None => String::new(),
+				},
 			ty_str: match node_ty
 				{
 				Some(::syscalls::vfs::NodeType::File) => "f",
@@ -159,15 +177,31 @@ impl FileEnt
 		}
 	}
 }
+/// Format a file size using binary suffixes (e.g. "12.5K")
+fn format_size(size: u64) -> String {
+	const SUFFIXES: [&str; 4] = ["K", "M", "G", "T"];
+	if size < 1024 {
+		return format!("{}", size);
+	}
+	let mut v = size;
+	let mut i = 0;
+	while v >= 1024*1024 && i < SUFFIXES.len()-1 {
+		v /= 1024;
+		i += 1;
+	}
+	format!("{}.{}{}", v / 1024, (v % 1024) * 10 / 1024, SUFFIXES[i])
+}
 impl ::listview::Row for FileEnt {
 	fn count(&self) -> usize {
-		2
+		4
 	}
 	fn value(&self, col: usize) -> &str {
 		match col
 		{
 		0 => self.ty_str,
-		1 => if let Some(ref dn) = self.display_name {
+		1 => &self.size_str,
+		2 => &self.mtime_str,
+		3 => if let Some(ref dn) = self.display_name {
 				dn
 			}
 			else {
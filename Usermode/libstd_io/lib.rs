@@ -44,6 +44,20 @@ impl ::core::fmt::Display for Error {
 		ErrorInner::VFS(::syscalls::vfs::Error::PermissionDenied) => f.write_str("Permission denied"),
 		ErrorInner::VFS(::syscalls::vfs::Error::FileLocked) => f.write_str("File is locked"),
 		ErrorInner::VFS(::syscalls::vfs::Error::MalformedPath) => f.write_str("Malformed path"),
+		ErrorInner::VFS(::syscalls::vfs::Error::ReadOnlyFilesystem) => f.write_str("Read-only filesystem"),
+		ErrorInner::VFS(::syscalls::vfs::Error::RecursionDepthExceeded) => f.write_str("Too many levels of symbolic links"),
+		ErrorInner::VFS(::syscalls::vfs::Error::CrossVolume) => f.write_str("Cannot move between volumes"),
+		ErrorInner::VFS(::syscalls::vfs::Error::IoError) => f.write_str("I/O error"),
+		ErrorInner::VFS(::syscalls::vfs::Error::InconsistentFilesystem) => f.write_str("Filesystem is corrupted"),
+		ErrorInner::VFS(::syscalls::vfs::Error::OutOfMemory) => f.write_str("Out of memory"),
+		ErrorInner::VFS(::syscalls::vfs::Error::TransientError) => f.write_str("Temporary failure, try again"),
+		ErrorInner::VFS(::syscalls::vfs::Error::Unknown) => f.write_str("Unknown filesystem error"),
+		ErrorInner::VFS(::syscalls::vfs::Error::AlreadyExists) => f.write_str("File exists"),
+		ErrorInner::VFS(::syscalls::vfs::Error::DirectoryNotEmpty) => f.write_str("Directory not empty"),
+		ErrorInner::VFS(::syscalls::vfs::Error::InvalidParameter) => f.write_str("Invalid argument"),
+		ErrorInner::VFS(::syscalls::vfs::Error::NonDirComponent) => f.write_str("Not a directory"),
+		ErrorInner::VFS(::syscalls::vfs::Error::Unsupported) => f.write_str("Operation not supported"),
+		ErrorInner::VFS(::syscalls::vfs::Error::OutOfSpace) => f.write_str("No space left on device"),
 		//ErrorInner::VFS(ref e) => write!(f, "Unknown VFS error {:?}", e),
 		}
 	}
@@ -191,6 +191,23 @@ impl ObjectHandle
 		{ return ::raw::syscall_4( self.call_value(call), (a1 & 0xFFFFFFFF) as usize, (a1 >> 32) as usize, a2, a3 ) }
 	}
 
+	#[allow(dead_code)]
+	#[inline]
+	unsafe fn call_2ll(&self, call: u16, a1: u64, a2: u64) -> u64 {
+		#[cfg(target_pointer_width="64")]
+		{ return ::raw::syscall_2( self.call_value(call), a1 as usize, a2 as usize ) }
+		#[cfg(target_pointer_width="32")]
+		{ return ::raw::syscall_4( self.call_value(call), (a1 & 0xFFFFFFFF) as usize, (a1 >> 32) as usize, (a2 & 0xFFFFFFFF) as usize, (a2 >> 32) as usize ) }
+	}
+	#[allow(dead_code)]
+	#[inline]
+	unsafe fn call_3ll(&self, call: u16, a1: u64, a2: u64, a3: usize) -> u64 {
+		#[cfg(target_pointer_width="64")]
+		{ return ::raw::syscall_3( self.call_value(call), a1 as usize, a2 as usize, a3 ) }
+		#[cfg(target_pointer_width="32")]
+		{ return ::raw::syscall_5( self.call_value(call), (a1 & 0xFFFFFFFF) as usize, (a1 >> 32) as usize, (a2 & 0xFFFFFFFF) as usize, (a2 >> 32) as usize, a3 ) }
+	}
+
 	#[allow(dead_code)]
 	#[inline]
 	unsafe fn call_4l(&self, call: u16, a1: u64, a2: usize, a3: usize, a4: usize) -> u64 {
@@ -342,5 +359,12 @@ pub fn get_text_info(unit: u32, id: u32, buf: &mut [u8]) -> &str {
 	::core::str::from_utf8(&buf[..len]).expect("TODO: get_text_info handle error")
 }
 
+#[inline]
+/// Fill a buffer with random bytes (from the kernel's entropy pool)
+pub fn get_random_bytes(buf: &mut [u8]) {
+	// SAFE: Syscall
+	unsafe { syscall!(CORE_RANDOM_BYTES, buf.as_mut_ptr() as usize, buf.len()); }
+}
+
 
 
@@ -8,6 +8,7 @@ pub use ::values::SocketError as Error;
 pub use ::values::SocketShutdownSide as ShutdownSide;
 pub use ::values::SocketAddress as SocketAddress;
 pub use ::values::MaskedSocketAddress;
+pub use ::values::NetworkRoute;
 
 /// Network connection server (allows waiting for an incoming connection)
 pub struct Server(::ObjectHandle);
@@ -35,8 +36,11 @@ impl ::Object for Server
 		&self.0
 	}
 
-	type Waits = ();
+	type Waits = ServerWaits;
 }
+define_waits!{ ServerWaits => (
+	accept:has_accept = ::values::EV_NET_SERVER_ACCEPT,
+)}
 impl Server
 {
 	pub fn open(addr: impl Into<SocketAddress>) -> Result<Server, Error> {
@@ -47,6 +51,10 @@ impl Server
 			.map(|v| Server(v))
 	}
 
+	pub fn wait_accept(&self) -> ::WaitItem {
+		::values::WaitItem { object: self.0 .0, flags: ::values::EV_NET_SERVER_ACCEPT }
+	}
+
 	pub fn accept(&self) -> Result<(ConnectedSocket, SocketAddress), Error> {
 		let mut sa = SocketAddress::default();
 		// SAFE: Syscall
@@ -70,8 +78,11 @@ impl ::Object for ConnectedSocket
 		&self.0
 	}
 
-	type Waits = ();
+	type Waits = ConnectedSocketWaits;
 }
+define_waits!{ ConnectedSocketWaits => (
+	recv:has_recv = ::values::EV_NET_CONNSOCK_RECV,
+)}
 impl ConnectedSocket
 {
 	pub fn connect(addr: impl Into<SocketAddress>) -> Result<ConnectedSocket, Error> {
@@ -109,6 +120,10 @@ impl ConnectedSocket
 			.map(|v| v as usize)
 	}
 
+	pub fn wait_recv(&self) -> ::WaitItem {
+		::values::WaitItem { object: self.0 .0, flags: ::values::EV_NET_CONNSOCK_RECV }
+	}
+
 	// TODO: Async IO using registered buffers (which minimises the problems with borrowing)
 }
 // --------------------------------------------------------------------
@@ -126,8 +141,11 @@ impl ::Object for FreeSocket
 		&self.0
 	}
 
-	type Waits = ();
+	type Waits = FreeSocketWaits;
 }
+define_waits!{ FreeSocketWaits => (
+	recv:has_recv = ::values::EV_NET_FREESOCK_RECV,
+)}
 impl FreeSocket
 {
 	/// Create a free socket using the specified local and remote addresses.
@@ -145,6 +163,10 @@ impl FreeSocket
 		to_result( unsafe { self.0.call_3(::values::NET_FREESOCK_SEND, data.as_ptr() as usize, data.len(), &remote as *const _ as usize) as usize } )
 			.map(|v| v as usize)
 	}
+	pub fn wait_recv(&self) -> ::WaitItem {
+		::values::WaitItem { object: self.0 .0, flags: ::values::EV_NET_FREESOCK_RECV }
+	}
+
 	pub fn recv_from(&mut self, data: &mut [u8]) -> Result<(usize, SocketAddress), Error> {
 		let mut sa = SocketAddress::default();
 		// SAFE: Syscall
@@ -153,3 +175,23 @@ impl FreeSocket
 	}
 }
 
+// --------------------------------------------------------------------
+/// Add a static route (requires privilege)
+pub fn route_add(route: &NetworkRoute) -> Result<(), Error> {
+	// SAFE: Syscall
+	to_result( unsafe { syscall!(NET_ROUTE_ADD, route as *const _ as usize) as usize } )
+		.map(|_| ())
+}
+/// Remove a static route (requires privilege)
+pub fn route_del(route: &NetworkRoute) -> Result<(), Error> {
+	// SAFE: Syscall
+	to_result( unsafe { syscall!(NET_ROUTE_DEL, route as *const _ as usize) as usize } )
+		.map(|_| ())
+}
+/// Get a static route by index (returns `Error::NoData` past the end)
+pub fn route_get(index: usize) -> Result<NetworkRoute, Error> {
+	let mut rv = NetworkRoute::default();
+	// SAFE: Syscall
+	to_result( unsafe { syscall!(NET_ROUTE_GET, index, &mut rv as *mut _ as usize) as usize } )
+		.map(|_| rv)
+}
@@ -132,6 +132,17 @@ impl ProtoProcess
 		// SAFE: Syscall
 		unsafe { self.0.call_2l(::values::CORE_PROTOPROCESS_SENDOBJ, ::values::FixedStr8::from(tag).into(), oh as usize); }
 	}
+
+	#[inline]
+	/// Set the user and group the process will run as (fails unless the caller is root)
+	pub fn set_credentials(&self, uid: u32, gid: u32) -> Result<(),()> {
+		// SAFE: Syscall
+		match ::to_result( unsafe { self.0.call_2(::values::CORE_PROTOPROCESS_SETCREDS, uid as usize, gid as usize) } as usize )
+		{
+		Ok(_) => Ok( () ),
+		Err(_) => Err( () ),
+		}
+	}
  
  	#[inline]
 	pub fn start(self, entry: usize, stack: usize) -> Process {
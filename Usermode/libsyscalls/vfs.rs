@@ -18,6 +18,19 @@ pub use ::values::VFSError as Error;
 pub use ::values::VFSNodeType as NodeType;
 pub use ::values::VFSFileOpenMode as FileOpenMode;
 pub use ::values::VFSMemoryMapMode as MemoryMapMode;
+pub use ::values::VFSMetadata as Metadata;
+pub use ::values::VFS_TIME_UNKNOWN as TIME_UNKNOWN;
+pub use ::values::VFSMountError as MountError;
+
+/// Advisory lock type for `File::lock`
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum LockKind
+{
+	/// Can be held by multiple handles at once
+	Shared,
+	/// Only one handle can lock the range
+	Exclusive,
+}
 
 pub fn root() -> &'static Dir {
 	use ::core::sync::atomic::{Ordering,AtomicBool};
@@ -33,6 +46,39 @@ pub fn root() -> &'static Dir {
 	}
 }
 
+/// Mount a volume (requires privilege)
+///
+/// `options` is a comma-separated list (e.g. `ro,sync`), `fs=<driver>` selects the filesystem driver
+pub fn mount(mountpoint: &str, volume: &str, options: &str) -> Result<(), MountError> {
+	// SAFE: Syscall
+	to_mount_result( unsafe { syscall!(VFS_MOUNT,
+		mountpoint.as_ptr() as usize, mountpoint.len(),
+		volume.as_ptr() as usize, volume.len(),
+		options.as_ptr() as usize, options.len()
+		) } as usize )
+}
+/// Unmount a volume (requires privilege, fails with `Busy` if anything on the volume is open)
+pub fn unmount(mountpoint: &str) -> Result<(), MountError> {
+	// SAFE: Syscall
+	to_mount_result( unsafe { syscall!(VFS_UNMOUNT, mountpoint.as_ptr() as usize, mountpoint.len()) } as usize )
+}
+/// Change the `ro`/`rw`/`sync`/`async` options of a mounted volume (requires privilege)
+pub fn remount(mountpoint: &str, options: &str) -> Result<(), MountError> {
+	// SAFE: Syscall
+	to_mount_result( unsafe { syscall!(VFS_REMOUNT,
+		mountpoint.as_ptr() as usize, mountpoint.len(),
+		options.as_ptr() as usize, options.len()
+		) } as usize )
+}
+/// Write all cached file data back to disk
+pub fn sync() -> Result<(), Error> {
+	// SAFE: Syscall
+	to_result( unsafe { syscall!(VFS_SYNC) } as usize ).map( |_| () )
+}
+#[inline]
+fn to_mount_result(val: usize) -> Result<(), MountError> {
+	super::to_result(val).map(|_| ()).map_err(|code| MountError::try_from(code).expect("Bad VFS Mount Error"))
+}
 
 #[inline]
 fn to_obj(val: usize) -> Result<super::ObjectHandle, Error> {
@@ -52,6 +98,15 @@ impl Node
 		NodeType::try_from( unsafe { self.0.call_0(::values::VFS_NODE_GETTYPE) } as u32 ).expect("Bad VFS Node Type")
 	}
 
+	/// Read the node's metadata (size, times, ownership and permissions)
+	#[inline]
+	pub fn get_metadata(&self) -> Result<Metadata,Error> {
+		let mut rv = Metadata::default();
+		// SAFE: Syscall writes to a valid `Metadata`
+		to_result( unsafe { self.0.call_1(::values::VFS_NODE_GETMETA, &mut rv as *mut _ as usize) } as usize )?;
+		Ok(rv)
+	}
+
 	/// Convert handle to a directory handle
 	#[inline]
 	pub fn into_dir(self) -> Result<Dir,Error> {
@@ -133,6 +188,46 @@ impl File
 		to_result( unsafe { self.0.call_4l(::values::VFS_FILE_MEMMAP, ofs, read_size, mem_addr as usize, mode as u8 as usize) } as usize )
 			.map( |_| () )
 	}
+
+	/// Try to take an advisory lock on `len` bytes from `ofs` (zero length locks to the end of the file)
+	///
+	/// Fails with `FileLocked` if another handle holds a conflicting lock, `wait_lock` fires once it's released.
+	#[inline]
+	pub fn try_lock(&self, ofs: u64, len: u64, kind: LockKind) -> Result<(),Error> {
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_3ll(::values::VFS_FILE_LOCK, ofs, len, (kind == LockKind::Exclusive) as usize) } as usize )
+			.map( |_| () )
+	}
+	/// Take an advisory lock, blocking until any conflicting locks are released
+	pub fn lock(&self, ofs: u64, len: u64, kind: LockKind) -> Result<(),Error> {
+		loop
+		{
+			match self.try_lock(ofs, len, kind)
+			{
+			Err(Error::FileLocked) => { ::threads::wait(&mut [self.wait_lock()], !0); },
+			rv @ _ => return rv,
+			}
+		}
+	}
+	/// Release advisory locks on a range (all locks are released when the handle is closed)
+	#[inline]
+	pub fn unlock(&self, ofs: u64, len: u64) -> Result<(),Error> {
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_2ll(::values::VFS_FILE_UNLOCK, ofs, len) } as usize )
+			.map( |_| () )
+	}
+	/// Write cached data for this file back to disk
+	#[inline]
+	pub fn sync(&self) -> Result<(),Error> {
+		// SAFE: Syscall
+		to_result( unsafe { self.0.call_0(::values::VFS_FILE_SYNC) } as usize )
+			.map( |_| () )
+	}
+	/// Wait item that fires when the last lock to fail with `FileLocked` can be retried
+	#[inline]
+	pub fn wait_lock(&self) -> ::values::WaitItem {
+		self.0.get_wait(::values::EV_VFS_FILE_LOCK)
+	}
 }
 impl ::Object for File {
 	const CLASS: u16 = ::values::CLASS_VFS_FILE;
@@ -182,6 +277,23 @@ impl Dir
 		Err(code) => Err( Error::try_from(code).expect("Bad VFS Error") ),
 		}
 	}
+
+	/// Move the entry `old_name` to `new_name` in `new_dir` (replacing any existing entry)
+	///
+	/// Both directories must be on the same volume
+	pub fn rename<P: ?Sized+AsRef<[u8]>, Q: ?Sized+AsRef<[u8]>>(&self, old_name: &P, new_dir: &Dir, new_name: &Q) -> Result<(), Error> {
+		let old_name = old_name.as_ref();
+		let new_name = new_name.as_ref();
+		// Handle 0 tells the kernel to use this directory (it can't look up the handle being called)
+		let dir_handle = if (new_dir.0).0 == (self.0).0 { 0 } else { (new_dir.0).0 };
+		// SAFE: Syscall
+		to_result(unsafe { self.0.call_5(::values::VFS_DIR_RENAME,
+			old_name.as_ptr() as usize, old_name.len(),
+			dir_handle as usize,
+			new_name.as_ptr() as usize, new_name.len()
+			) } as usize)?;
+		Ok( () )
+	}
 }
 impl ::Object for Dir {
 	const CLASS: u16 = ::values::CLASS_VFS_DIR;
@@ -252,3 +364,26 @@ impl ::Object for Symlink {
 
 	type Waits = ();
 }
+
+/// Formats a metadata time (seconds since 1970, UTC) as `YYYY-MM-DD HH:MM`
+pub struct DisplayTime(pub i64);
+impl ::core::fmt::Display for DisplayTime {
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		if self.0 == TIME_UNKNOWN {
+			return f.pad("-");
+		}
+		// Civil date from a day count (Howard Hinnant's `civil_from_days`)
+		let days = self.0.div_euclid(86400);
+		let tod = self.0.rem_euclid(86400);
+		let z = days + 719468;
+		let era = z.div_euclid(146097);
+		let doe = z.rem_euclid(146097);
+		let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+		let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+		let mp = (5 * doy + 2) / 153;
+		let day = doy - (153 * mp + 2) / 5 + 1;
+		let month = if mp < 10 { mp + 3 } else { mp - 9 };
+		let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+		write!(f, "{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, tod / 3600, tod / 60 % 60)
+	}
+}
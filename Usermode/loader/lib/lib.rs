@@ -51,6 +51,11 @@ impl ProtoProcess
 		self.0.send_obj( tag, obj );
 	}
 
+	/// Set the user and group the process will run as (the caller must be root)
+	pub fn set_credentials(&self, uid: u32, gid: u32) -> Result<(),()> {
+		self.0.set_credentials(uid, gid)
+	}
+
 	pub fn start(self) -> ::syscalls::threads::Process {
 		// SAFE: FFI into rust code
 		unsafe {
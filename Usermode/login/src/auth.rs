@@ -7,6 +7,8 @@ pub enum Error
 
 pub struct UserInfo
 {
+	uid: u32,
+	gid: u32,
 }
 
 pub fn try_login(username: &str, password: &str) -> Result<UserInfo, Error>
@@ -14,7 +16,7 @@ pub fn try_login(username: &str, password: &str) -> Result<UserInfo, Error>
 	// TODO: Use a proper auth infrastructure, something PAM-esque
 	if username == "root" && password == "password"
 	{
-		Ok(UserInfo {})
+		Ok(UserInfo { uid: 0, gid: 0 })
 	}
 	else if username == "guest"
 	{
@@ -29,6 +31,11 @@ pub fn try_login(username: &str, password: &str) -> Result<UserInfo, Error>
 
 impl UserInfo
 {
+	/// User and group IDs the session runs as
+	pub fn get_ids(&self) -> (u32, u32)
+	{
+		(self.uid, self.gid)
+	}
 	pub fn get_shell(&self) -> &str
 	{
 		"/sysroot/bin/shell"
@@ -186,8 +186,31 @@ impl ShellState
 			while let Some(v) = args.next() {
 				print!(term, "{} ", v);
 			},
+		// 'mount' - Mount a volume (or change options with `-o remount,...`)
+		Some("mount") => command_mount(term, &args.collect::<Vec<_>>()),
+		// 'umount' - Unmount a volume
+		Some("umount") =>
+			match args.next()
+			{
+			Some(path) => if let Err(e) = ::syscalls::vfs::unmount(path) {
+				print!(term, "Unable to unmount '{}': {:?}", path, e);
+				},
+			None => print!(term, "Usage: umount <path>"),
+			},
+		// 'mv' - Rename/move a node
+		Some("mv") =>
+			match (args.next(), args.next())
+			{
+			(Some(src), Some(dst)) => command_mv(term, &self.root_handle, src, dst),
+			_ => print!(term, "Usage: mv <src> <dst>"),
+			},
+		// 'sync' - Write cached file data to disk
+		Some("sync") =>
+			if let Err(e) = ::syscalls::vfs::sync() {
+				print!(term, "Unable to sync: {:?}", e);
+			},
 		Some("help") => {
-			print!(term, "Builtins: pwd, cd, ls, cat, help, echo");
+			print!(term, "Builtins: pwd, cd, ls, cat, help, echo, mount, umount, mv, sync");
 			},
 		Some(cmd @_) => {
 			print!(term, "Unkownn command '{}'", cmd);
@@ -232,20 +255,28 @@ fn command_ls<T: ::Terminal>(term: &T, root: &::syscalls::vfs::Dir, path: &str)
 
 		let name = ::std::str::from_utf8(name_bytes).expect("Filename not utf-8");
 
-		print!(term, "- {}", name);
-
 		let file_node = match handle.open_child(name)
 			{
 			Ok(v) => v,
 			Err(e) => {
-				print!(term, "(Error: {:?})\n", e);
+				print!(term, "- {} (Error: {:?})\n", name, e);
 				continue ;
 				},
 			};
+		let meta = file_node.get_metadata().ok();
+		match meta
+		{
+		Some(ref m) => print!(term, "- {:04o} {:16} {}", m.mode, ::syscalls::vfs::DisplayTime(m.mtime), name),
+		None => print!(term, "- ???? {:16} {}", "", name),
+		}
 		match file_node.class()
 		{
 		NodeType::File => {
-			let size = file_node.into_file(FileOpenMode::ReadOnly).and_then(|h| Ok(h.get_size())).unwrap_or(0);
+			let size = match meta
+				{
+				Some(m) => m.size,
+				None => file_node.into_file(FileOpenMode::ReadOnly).and_then(|h| Ok(h.get_size())).unwrap_or(0),
+				};
 			print!(term, " ({})", size);
 			},
 		NodeType::Dir => print!(term, "/"),
@@ -264,6 +295,62 @@ fn command_ls<T: ::Terminal>(term: &T, root: &::syscalls::vfs::Dir, path: &str)
 	}
 }
 
+/// Mount a volume: `mount [-o <options>] <volume> <path>` or `mount -o remount,<options> <path>`
+fn command_mount<T: ::Terminal>(term: &T, args: &[&str])
+{
+	let (options, args) = match args
+		{
+		["-o", options, rest @ ..] => (*options, rest),
+		_ => ("", args),
+		};
+	let rv = if options == "remount" || options.starts_with("remount,") {
+			match args
+			{
+			[path] => ::syscalls::vfs::remount(path, options.split_at("remount".len()).1.trim_start_matches(',')),
+			_ => { print!(term, "Usage: mount -o remount,<options> <path>"); return ; },
+			}
+		}
+		else {
+			match args
+			{
+			[volume, path] => ::syscalls::vfs::mount(path, volume, options),
+			_ => { print!(term, "Usage: mount [-o <options>] <volume> <path>"); return ; },
+			}
+		};
+	if let Err(e) = rv {
+		print!(term, "mount failed: {:?}", e);
+	}
+}
+
+/// Move a node: `mv <src> <dst>` (both paths are absolute)
+fn command_mv<T: ::Terminal>(term: &T, root: &::syscalls::vfs::Dir, src: &str, dst: &str)
+{
+	fn split(path: &str) -> (&str, &str) {
+		match path.rfind('/')
+		{
+		Some(0) => ("/", &path[1..]),
+		Some(i) => (&path[..i], &path[i+1..]),
+		None => ("/", path),
+		}
+	}
+	let open_dir = |path: &str| root.open_child_path(path).and_then(|v| v.into_dir());
+
+	let (src_dir, src_name) = split(src);
+	let (dst_dir, dst_name) = split(dst);
+	let src_h = match open_dir(src_dir)
+		{
+		Ok(v) => v,
+		Err(e) => { print!(term, "Unable to open '{}': {:?}", src_dir, e); return ; },
+		};
+	let dst_h = match open_dir(dst_dir)
+		{
+		Ok(v) => v,
+		Err(e) => { print!(term, "Unable to open '{}': {:?}", dst_dir, e); return ; },
+		};
+	if let Err(e) = src_h.rename(src_name, &dst_h, dst_name) {
+		print!(term, "mv failed: {:?}", e);
+	}
+}
 
 /// Trait to provde 'is_combining', used by render code
 pub trait UnicodeCombining
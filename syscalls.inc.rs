@@ -71,6 +71,8 @@ def_groups! {
 		=8: CORE_FUTEX_SLEEP,
 		/// Wake a number of sleepers on a futex
 		=9: CORE_FUTEX_WAKE,
+		/// Fill a buffer with random bytes from the kernel's entropy pool
+		=10: CORE_RANDOM_BYTES,
 	},
 	/// GUI System calls
 	=1: GROUP_GUI = {
@@ -102,6 +104,23 @@ def_groups! {
 		=1: NET_LISTEN,
 		/// Open a free-form datagram 'socket'
 		=2: NET_BIND,
+		/// Add a static route (privileged)
+		=3: NET_ROUTE_ADD,
+		/// Remove a static route (privileged)
+		=4: NET_ROUTE_DEL,
+		/// Get a static route by index
+		=5: NET_ROUTE_GET,
+	},
+	/// Filesystem management (privileged)
+	=5: GROUP_VFS = {
+		/// Mount a volume (mountpoint, volume name, comma-separated options - `fs=<driver>` selects the driver)
+		=0: VFS_MOUNT,
+		/// Unmount the volume at a mountpoint
+		=1: VFS_UNMOUNT,
+		/// Change the `ro`/`rw`/`sync`/`async` options of a mounted volume
+		=2: VFS_REMOUNT,
+		/// Write all cached file data back to disk (not privileged)
+		=3: VFS_SYNC,
 	}
 }
 
@@ -178,6 +197,8 @@ def_classes! {
 		/// Give the process one of this process's objects
 		/// This method blocks if the child process hasn't popped the previous object
 		=0: CORE_PROTOPROCESS_SENDOBJ,
+		/// Set the user/group the process runs as (requires the caller to be root)
+		=1: CORE_PROTOPROCESS_SETCREDS,
 		--
 		/// Start the process executing
 		=0: CORE_PROTOPROCESS_START,
@@ -202,6 +223,8 @@ def_classes! {
 	/// Opened node
 	=3: CLASS_VFS_NODE = {
 		=0: VFS_NODE_GETTYPE,
+		/// Read the node's metadata (into a `VFSMetadata`)
+		=1: VFS_NODE_GETMETA,
 		--
 		=0: VFS_NODE_TOFILE,
 		=1: VFS_NODE_TODIR,
@@ -218,8 +241,16 @@ def_classes! {
 		=2: VFS_FILE_WRITEAT,
 		/// Map part of the file into the current address space
 		=3: VFS_FILE_MEMMAP,
+		/// Try to take an advisory lock on a byte range (length 0 is to the end of the file, flag 1 is exclusive)
+		=4: VFS_FILE_LOCK,
+		/// Release advisory locks on a byte range
+		=5: VFS_FILE_UNLOCK,
+		/// Write cached data for the file back to disk
+		=6: VFS_FILE_SYNC,
 		--
 	}|{
+		/// Fires when the lock that last failed with `FileLocked` could be retried
+		=0: EV_VFS_FILE_LOCK,
 	},
 	/// Opened directory
 	=5: CLASS_VFS_DIR = {
@@ -229,6 +260,8 @@ def_classes! {
 		=1: VFS_DIR_OPENCHILD,
 		/// Open a sub-path
 		=2: VFS_DIR_OPENPATH,
+		/// Move an entry to a new name, possibly in another directory (handle 0 is this directory)
+		=3: VFS_DIR_RENAME,
 		--
 	}|{
 	},
@@ -325,6 +358,8 @@ def_classes! {
 		=0: NET_SERVER_ACCEPT,
 	--
 	}|{
+		/// Fires when there is a connection waiting to be accepted
+		=0: EV_NET_SERVER_ACCEPT,
 	},
 	/// Socket connection
 	=12: CLASS_SOCKET = {
@@ -332,10 +367,12 @@ def_classes! {
 		=0: NET_CONNSOCK_RECV,
 		/// Send data
 		=1: NET_CONNSOCK_SEND,
-		///
+		/// Shut down one side of the connection
 		=2: NET_CONNSOCK_SHUTDOWN,
 	--
 	}|{
+		/// Fires when there is data to read (or the connection has closed)
+		=0: EV_NET_CONNSOCK_RECV,
 	},
 	/// Free-bind socket
 	=13: CLASS_FREESOCKET = {
@@ -345,6 +382,8 @@ def_classes! {
 		=1: NET_FREESOCK_SEND,
 	--
 	}|{
+		/// Fires when there is a datagram waiting to be received
+		=0: EV_NET_FREESOCK_RECV,
 	},
 /*
 	/// A registered read/write buffer
@@ -404,6 +443,32 @@ enum_to_from!{ VFSError => u32:
 	PermissionDenied = 2,
 	FileLocked = 3,
 	MalformedPath = 4,
+	ReadOnlyFilesystem = 5,
+	RecursionDepthExceeded = 6,
+	CrossVolume = 7,
+	IoError = 8,
+	InconsistentFilesystem = 9,
+	OutOfMemory = 10,
+	TransientError = 11,
+	/// Miscellaneous error (details are logged by the kernel)
+	Unknown = 12,
+	AlreadyExists = 13,
+	DirectoryNotEmpty = 14,
+	InvalidParameter = 15,
+	NonDirComponent = 16,
+	Unsupported = 17,
+	OutOfSpace = 18,
+}
+enum_to_from!{ VFSMountError => u32:
+	PermissionDenied = 0,
+	VolumeNotFound = 1,
+	UnknownFilesystem = 2,
+	InvalidMountpoint = 3,
+	MountpointUsed = 4,
+	DriverError = 5,
+	BadOption = 6,
+	NotMounted = 7,
+	Busy = 8,
 }
 enum_to_from!{ VFSNodeType => u32:
 	File = 0,
@@ -431,6 +496,24 @@ enum_to_from!{ VFSMemoryMapMode => u8:
 	WriteBack = 3,
 }
 
+/// Node metadata (used by VFS_NODE_GETMETA)
+#[derive(Default,Copy,Clone,Debug)]
+#[repr(C)]
+pub struct VFSMetadata
+{
+	pub size: u64,
+	/// Times are seconds since 1970-01-01 00:00 UTC, `VFS_TIME_UNKNOWN` if not recorded
+	pub atime: i64,
+	pub mtime: i64,
+	pub ctime: i64,
+	pub crtime: i64,
+	pub uid: u32,
+	pub gid: u32,
+	pub links: u32,
+	/// UNIX-style permission bits (0o7777)
+	pub mode: u16,
+}
+pub const VFS_TIME_UNKNOWN: i64 = i64::MIN;
 
 enum_to_from!{ GuiWinFlag => u8:
 	Visible = 0,
@@ -552,6 +635,20 @@ enum_to_from!{ SocketError => u32:
 	InvalidValue = 1,
 	/// The specified address was already in use
 	AlreadyInUse = 2,
+	/// No route to the remote address
+	NoRoute = 3,
+	/// The remote end refused the connection
+	ConnectionRefused = 4,
+	/// The remote end reset the connection
+	ConnectionReset = 5,
+	/// The connection has been closed (locally or by the remote)
+	ConnectionClosed = 6,
+	/// No local port was available for the connection
+	NoPortAvailable = 7,
+	/// The caller isn't allowed to perform this operation
+	PermissionDenied = 8,
+	/// The remote stopped responding
+	TimedOut = 9,
 }
 enum_to_from!{ SocketShutdownSide => u8:
 	Transmit = 0,
@@ -575,6 +672,8 @@ enum_to_from!{ SocketPortType => u8:
 	Udp = 2,
 	/// Stream Control Transmission Protocol
 	Sctp = 3,
+	/// ICMP echo ("ping"), the port is the echo sequence number
+	IcmpEcho = 4,
 }
 #[derive(Default,Copy,Clone,Debug)]
 #[repr(C)]
@@ -592,4 +691,17 @@ pub struct MaskedSocketAddress
 	pub addr: SocketAddress,
 	pub mask: u8,
 }
+/// A static route (used by NET_ROUTE_*)
+#[derive(Default,Copy,Clone,Debug)]
+#[repr(C)]
+pub struct NetworkRoute
+{
+	/// Address type of `network` and `next_hop` (SocketAddressType)
+	pub addr_ty: u8,
+	/// Prefix length of `network`
+	pub mask: u8,
+	pub network: [u8; 16],
+	/// Router the traffic is sent to
+	pub next_hop: [u8; 16],
+}
 